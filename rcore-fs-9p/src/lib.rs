@@ -0,0 +1,660 @@
+//! A 9P2000.L server exporting a `FileSystem` over a byte transport
+//!
+//! Requests are handled one at a time and synchronously, as INode
+//! operations are. Only the transport is async, see `Server::serve()`.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+extern crate log;
+
+pub mod proto;
+#[cfg(test)]
+mod tests;
+
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use core::future::Future;
+use log::warn;
+use proto::*;
+use rcore_fs::file::File;
+use rcore_fs::vfs::{
+    is_same_inode, DirCursor, DirEntrySlot, FileSystem, FileType, FsCapabilities, FsError, INode,
+    InodeKey, Result, Timespec,
+};
+
+/// Largest message the server takes or sends, until `Tversion` asks for
+/// less
+pub const MAX_MSIZE: u32 = 128 * 1024;
+
+/// Most entries read from the fs at a time by `Treaddir`
+const READDIR_WINDOW: usize = 128;
+
+/// Byte stream carrying the messages, e.g. a virtio queue or a socket
+pub trait Transport {
+    /// Receive some bytes into `buf`, returning how many, 0 once closed
+    fn recv(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize>> + Send;
+
+    /// Send all of `buf`
+    fn send(&mut self, buf: &[u8]) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// A file of the client
+struct Fid {
+    inode: Arc<dyn INode>,
+    /// Dir and name it was reached by, for `Trename` and `Tremove`
+    parent: Option<(Arc<dyn INode>, Vec<u8>)>,
+    /// Set by `Tlopen` and `Tlcreate`
+    file: Option<File>,
+    /// Where the last `Treaddir` ended
+    cursor: DirCursor,
+}
+
+/// Error reply, by Linux errno
+struct Errno(u32);
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        Errno(err.to_errno() as u32)
+    }
+}
+
+type Reply = core::result::Result<Writer, Errno>;
+
+/// The server of one client, owning the fids of the client
+pub struct Server {
+    fs: Arc<dyn FileSystem>,
+    msize: u32,
+    fids: BTreeMap<u32, Fid>,
+    /// Qid paths of inodes of other file systems than the root one, e.g.
+    /// mounted below it
+    foreign: BTreeMap<InodeKey, u64>,
+}
+
+impl Server {
+    pub fn new(fs: Arc<dyn FileSystem>) -> Self {
+        Server {
+            fs,
+            msize: MAX_MSIZE,
+            fids: BTreeMap::new(),
+            foreign: BTreeMap::new(),
+        }
+    }
+
+    /// Serve the messages from `transport` until it is closed
+    ///
+    /// Fails if a message is malformed, as the stream cannot be followed
+    /// past it, or if the transport fails.
+    pub async fn serve<T: Transport>(&mut self, transport: &mut T) -> Result<()> {
+        let mut buf = vec![0; 4];
+        loop {
+            buf.resize(4, 0);
+            if !recv_exact(transport, &mut buf).await? {
+                return Ok(());
+            }
+            let size = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if size < HEADER_SIZE || size > self.msize as usize {
+                warn!("9p: message of {} bytes, msize is {}", size, self.msize);
+                return Err(FsError::InvalidParam);
+            }
+            buf.resize(size, 0);
+            if !recv_exact(transport, &mut buf[4..]).await? {
+                return Err(FsError::InvalidParam);
+            }
+            let reply = self.handle(&buf);
+            transport.send(&reply).await?;
+        }
+    }
+
+    /// Handle one message `request` and return the reply
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut reader = Reader::new(request);
+        let (type_, tag) = match read_header(&mut reader) {
+            Ok((size, type_, tag)) if size as usize == request.len() => (type_, tag),
+            _ => return error_reply(NOTAG, Errno::from(FsError::InvalidParam)),
+        };
+        let reply = match type_ {
+            TVERSION => self.version(tag, &mut reader),
+            TATTACH => self.attach(tag, &mut reader),
+            TWALK => self.walk(tag, &mut reader),
+            TCLUNK => self.clunk(tag, &mut reader),
+            TREMOVE => self.remove(tag, &mut reader),
+            TFLUSH => Ok(Writer::new(TFLUSH + 1, tag)),
+            TLOPEN => self.lopen(tag, &mut reader),
+            TLCREATE => self.lcreate(tag, &mut reader),
+            TREAD => self.read(tag, &mut reader),
+            TWRITE => self.write(tag, &mut reader),
+            TREADDIR => self.readdir(tag, &mut reader),
+            TGETATTR => self.getattr(tag, &mut reader),
+            TSETATTR => self.setattr(tag, &mut reader),
+            TSTATFS => self.statfs(tag, &mut reader),
+            TFSYNC => self.fsync(tag, &mut reader),
+            TMKDIR => self.mkdir(tag, &mut reader),
+            TSYMLINK => self.symlink(tag, &mut reader),
+            TREADLINK => self.readlink(tag, &mut reader),
+            TLINK => self.link(tag, &mut reader),
+            TRENAME => self.rename(tag, &mut reader),
+            TRENAMEAT => self.renameat(tag, &mut reader),
+            TUNLINKAT => self.unlinkat(tag, &mut reader),
+            _ => {
+                warn!("9p: unknown message type {}", type_);
+                Err(Errno(EOPNOTSUPP))
+            }
+        };
+        match reply {
+            Ok(writer) => writer.finish(),
+            Err(errno) => error_reply(tag, errno),
+        }
+    }
+
+    fn fid(&self, fid: u32) -> core::result::Result<&Fid, Errno> {
+        self.fids.get(&fid).ok_or(Errno(EBADF))
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> core::result::Result<&mut Fid, Errno> {
+        self.fids.get_mut(&fid).ok_or(Errno(EBADF))
+    }
+
+    fn new_fid(&mut self, fid: u32, value: Fid) -> core::result::Result<(), Errno> {
+        if self.fids.contains_key(&fid) {
+            return Err(Errno(EBADF));
+        }
+        self.fids.insert(fid, value);
+        Ok(())
+    }
+
+    /// Qid of `inode`, whose path is the inode number in the root fs and
+    /// version the low bits of its data version
+    fn qid(&mut self, inode: &Arc<dyn INode>) -> Result<Qid> {
+        let type_ = Qid::type_of(inode.metadata()?.type_);
+        let key = inode.ino_key();
+        let path = if key.fs == self.fs.instance_id() {
+            key.inode as u64
+        } else {
+            // numbered apart from the inodes of the root fs
+            let next = (1 << 63) | self.foreign.len() as u64;
+            *self.foreign.entry(key).or_insert(next)
+        };
+        Ok(Qid {
+            type_,
+            version: inode.data_version() as u32,
+            path,
+        })
+    }
+
+    fn iounit(&self) -> u32 {
+        self.msize - IO_HEADER_SIZE as u32
+    }
+
+    /// Point the fids reached by `old_name` in `old_dir` to its new place
+    fn renamed(
+        &mut self,
+        old_dir: &Arc<dyn INode>,
+        old_name: &[u8],
+        new_dir: &Arc<dyn INode>,
+        new_name: &[u8],
+    ) {
+        for fid in self.fids.values_mut() {
+            if let Some((dir, name)) = &mut fid.parent {
+                if name == old_name && is_same_inode(dir, old_dir) {
+                    *dir = new_dir.clone();
+                    *name = new_name.to_vec();
+                }
+            }
+        }
+    }
+
+    fn version(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let msize = reader.u32()?;
+        let version = reader.str()?;
+        if (msize as usize) < IO_HEADER_SIZE + 1 {
+            return Err(FsError::InvalidParam.into());
+        }
+        self.msize = msize.min(MAX_MSIZE);
+        self.fids.clear();
+        let mut writer = Writer::new(TVERSION + 1, tag);
+        writer.u32(self.msize);
+        match version.starts_with(VERSION) {
+            true => writer.str(VERSION),
+            false => writer.str("unknown"),
+        }
+        Ok(writer)
+    }
+
+    fn attach(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let _afid = reader.u32()?;
+        let _uname = reader.str()?;
+        let _aname = reader.str()?;
+        let _n_uname = reader.u32()?;
+        let root = self.fs.root_inode();
+        let qid = self.qid(&root)?;
+        self.new_fid(
+            fid,
+            Fid {
+                inode: root,
+                parent: None,
+                file: None,
+                cursor: DirCursor::default(),
+            },
+        )?;
+        let mut writer = Writer::new(TATTACH + 1, tag);
+        writer.qid(qid);
+        Ok(writer)
+    }
+
+    fn walk(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let new_fid = reader.u32()?;
+        let nwname = reader.u16()? as usize;
+        let names = (0..nwname)
+            .map(|_| reader.name())
+            .collect::<Result<Vec<_>>>()?;
+        let from = self.fid(fid)?;
+        if new_fid != fid && self.fids.contains_key(&new_fid) {
+            return Err(Errno(EBADF));
+        }
+        let mut inode = from.inode.clone();
+        let mut parent = from.parent.clone();
+        let mut qids = Vec::new();
+        for &name in names.iter() {
+            let next = match inode.find_bytes(name) {
+                Ok(next) => next,
+                Err(err) if qids.is_empty() => return Err(err.into()),
+                Err(_) => break,
+            };
+            qids.push(self.qid(&next)?);
+            parent = match name {
+                b"." => parent,
+                b".." => None,
+                _ => Some((inode, name.to_vec())),
+            };
+            inode = next;
+        }
+        // the fid is only made if all names were found
+        if qids.len() == names.len() {
+            let fid = Fid {
+                inode,
+                parent,
+                file: None,
+                cursor: DirCursor::default(),
+            };
+            self.fids.insert(new_fid, fid);
+        }
+        let mut writer = Writer::new(TWALK + 1, tag);
+        writer.u16(qids.len() as u16);
+        for qid in qids {
+            writer.qid(qid);
+        }
+        Ok(writer)
+    }
+
+    fn clunk(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        self.fids.remove(&fid).ok_or(Errno(EBADF))?;
+        Ok(Writer::new(TCLUNK + 1, tag))
+    }
+
+    fn remove(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        // the fid is clunked even if the remove fails
+        let fid = self.fids.remove(&fid).ok_or(Errno(EBADF))?;
+        let (dir, name) = fid.parent.ok_or(FsError::Busy)?;
+        dir.unlink_bytes(&name)?;
+        Ok(Writer::new(TREMOVE + 1, tag))
+    }
+
+    /// Open `inode` with the access mode of `flags`
+    fn open(inode: &Arc<dyn INode>, flags: u32) -> Result<File> {
+        let (readable, writable) = match flags & O_ACCMODE {
+            O_WRONLY => (false, true),
+            O_RDWR => (true, true),
+            _ => (true, false),
+        };
+        let file = File::open(inode.clone(), readable, writable, false)?;
+        if flags & O_TRUNC != 0 && writable {
+            inode.resize(0)?;
+        }
+        Ok(file)
+    }
+
+    fn lopen(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let flags = reader.u32()?;
+        let inode = self.fid(fid)?.inode.clone();
+        if self.fid(fid)?.file.is_some() {
+            return Err(Errno(EBADF));
+        }
+        let qid = self.qid(&inode)?;
+        let file = Self::open(&inode, flags)?;
+        self.fid_mut(fid)?.file = Some(file);
+        let mut writer = Writer::new(TLOPEN + 1, tag);
+        writer.qid(qid);
+        writer.u32(self.iounit());
+        Ok(writer)
+    }
+
+    fn lcreate(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let name = utf8(reader.name()?)?;
+        let flags = reader.u32()?;
+        let mode = reader.u32()?;
+        let _gid = reader.u32()?;
+        let dir = self.fid(fid)?.inode.clone();
+        let inode = dir.create(name, FileType::File, mode & 0o7777)?;
+        let qid = self.qid(&inode)?;
+        let file = Self::open(&inode, flags)?;
+        // the fid now stands for the new file
+        *self.fid_mut(fid)? = Fid {
+            inode,
+            parent: Some((dir, name.as_bytes().to_vec())),
+            file: Some(file),
+            cursor: DirCursor::default(),
+        };
+        let mut writer = Writer::new(TLCREATE + 1, tag);
+        writer.qid(qid);
+        writer.u32(self.iounit());
+        Ok(writer)
+    }
+
+    fn read(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let offset = reader.u64()?;
+        let count = reader.u32()?.min(self.iounit());
+        let file = match &mut self.fid_mut(fid)?.file {
+            Some(file) if file.readable() => file,
+            _ => return Err(Errno(EBADF)),
+        };
+        let mut buf = vec![0; count as usize];
+        file.seek(offset as usize);
+        let len = file.read(&mut buf)?;
+        let mut writer = Writer::new(TREAD + 1, tag);
+        writer.u32(len as u32);
+        writer.bytes(&buf[..len]);
+        Ok(writer)
+    }
+
+    fn write(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let offset = reader.u64()?;
+        let count = reader.u32()?;
+        let data = reader.bytes(count as usize)?;
+        let file = match &mut self.fid_mut(fid)?.file {
+            Some(file) if file.writable() => file,
+            _ => return Err(Errno(EBADF)),
+        };
+        file.seek(offset as usize);
+        let len = file.write(data)?;
+        let mut writer = Writer::new(TWRITE + 1, tag);
+        writer.u32(len as u32);
+        Ok(writer)
+    }
+
+    /// Entries from `offset` on, by the positions of `INode::next_entries()`.
+    /// The offset of each entry is the position of the next one.
+    ///
+    /// The fid keeps where the reply ended, so that the dir is read a window
+    /// at a time in order. If it changes meanwhile, the listing goes on from
+    /// there anyway, which may skip or repeat entries as POSIX allows.
+    fn readdir(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let offset = reader.u64()? as usize;
+        let count = reader.u32()?.min(self.iounit()) as usize;
+        let dir = self.fid(fid)?.inode.clone();
+        let mut cursor = self.fid(fid)?.cursor;
+        if cursor.next != offset {
+            cursor = DirCursor::at(offset);
+        }
+        // as many as the smallest entries fitting in `count`
+        let window = (count / (13 + 8 + 1 + str_size("x"))).clamp(1, READDIR_WINDOW);
+        let mut slots = vec![DirEntrySlot::default(); window];
+        let mut writer = Writer::new(TREADDIR + 1, tag);
+        writer.u32(0);
+        let start = writer.size();
+        'fill: loop {
+            let from = cursor.next;
+            let filled = match dir.next_entries(&mut cursor, &mut slots) {
+                Err(err) if *err.root_cause() == FsError::InvalidParam => {
+                    cursor = DirCursor::at(from);
+                    continue;
+                }
+                result => result?,
+            };
+            let mut position = from;
+            for slot in &slots[..filled] {
+                let size = 13 + 8 + 1 + str_size(slot.name_bytes());
+                if writer.size() - start + size > count {
+                    cursor.next = position;
+                    break 'fill;
+                }
+                // the qid has the data version, and crosses mount points
+                let child = dir.find_bytes(slot.name_bytes())?;
+                writer.qid(self.qid(&child)?);
+                writer.u64(slot.next as u64);
+                writer.u8(dirent_type_of(slot.type_));
+                writer.name(slot.name_bytes());
+                position = slot.next;
+            }
+            if filled < slots.len() {
+                break;
+            }
+        }
+        self.fid_mut(fid)?.cursor = cursor;
+        let len = writer.size() - start;
+        writer.patch_u32(HEADER_SIZE, len as u32);
+        Ok(writer)
+    }
+
+    fn getattr(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let _request_mask = reader.u64()?;
+        let inode = self.fid(fid)?.inode.clone();
+        let metadata = inode.metadata()?;
+        let qid = self.qid(&inode)?;
+        let has_btime = inode
+            .fs()
+            .is_ok_and(|fs| fs.capabilities().contains(FsCapabilities::BIRTH_TIME));
+        let mut writer = Writer::new(TGETATTR + 1, tag);
+        writer.u64(match has_btime {
+            true => GETATTR_BASIC | GETATTR_BTIME,
+            false => GETATTR_BASIC,
+        });
+        writer.qid(qid);
+        writer.u32(mode_of(metadata.type_) | metadata.mode as u32);
+        writer.u32(metadata.uid as u32);
+        writer.u32(metadata.gid as u32);
+        writer.u64(metadata.nlinks as u64);
+        writer.u64(metadata.rdev as u64);
+        writer.u64(metadata.size as u64);
+        writer.u64(metadata.blk_size as u64);
+        // in 512-byte units, as `st_blocks`
+        writer.u64((metadata.blocks * metadata.blk_size / 512) as u64);
+        for time in [metadata.atime, metadata.mtime, metadata.ctime].iter() {
+            writer.u64(time.sec as u64);
+            writer.u64(time.nsec as u64);
+        }
+        let btime = match has_btime {
+            true => metadata.btime,
+            false => Timespec { sec: 0, nsec: 0 },
+        };
+        writer.u64(btime.sec as u64);
+        writer.u64(btime.nsec as u64);
+        // gen and data_version are not basic
+        writer.u64(0);
+        writer.u64(0);
+        Ok(writer)
+    }
+
+    /// Times without the `_SET` bits are to be set to the time of the
+    /// server, which it does not have, so they are left as is
+    fn setattr(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let valid = reader.u32()?;
+        let mode = reader.u32()?;
+        let uid = reader.u32()?;
+        let gid = reader.u32()?;
+        let size = reader.u64()?;
+        let atime = Timespec {
+            sec: reader.u64()? as i64,
+            nsec: reader.u64()? as i32,
+        };
+        let mtime = Timespec {
+            sec: reader.u64()? as i64,
+            nsec: reader.u64()? as i32,
+        };
+        let inode = self.fid(fid)?.inode.clone();
+        if valid & SETATTR_SIZE != 0 {
+            inode.resize(size as usize)?;
+        }
+        let others = SETATTR_MODE | SETATTR_UID | SETATTR_GID | SETATTR_ATIME | SETATTR_MTIME;
+        if valid & others != 0 {
+            let mut metadata = inode.metadata()?;
+            if valid & SETATTR_MODE != 0 {
+                metadata.mode = (mode & 0o7777) as u16;
+            }
+            if valid & SETATTR_UID != 0 {
+                metadata.uid = uid as usize;
+            }
+            if valid & SETATTR_GID != 0 {
+                metadata.gid = gid as usize;
+            }
+            if valid & SETATTR_ATIME_SET != 0 {
+                metadata.atime = atime;
+            }
+            if valid & SETATTR_MTIME_SET != 0 {
+                metadata.mtime = mtime;
+            }
+            inode.set_metadata(&metadata)?;
+        }
+        Ok(Writer::new(TSETATTR + 1, tag))
+    }
+
+    fn statfs(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        // of the fs mounted there, if any, see `INode::fs_info()`
+        let info = self.fid(reader.u32()?)?.inode.fs_info()?;
+        let mut writer = Writer::new(TSTATFS + 1, tag);
+        writer.u32(V9FS_MAGIC);
+        writer.u32(info.bsize as u32);
+        writer.u64(info.blocks as u64);
+        writer.u64(info.bfree as u64);
+        writer.u64(info.bavail as u64);
+        writer.u64(info.files as u64);
+        writer.u64(info.ffree as u64);
+        writer.u64(self.fs.instance_id());
+        writer.u32(info.namemax as u32);
+        Ok(writer)
+    }
+
+    fn fsync(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let _datasync = reader.u32()?;
+        self.fid(fid)?.inode.sync_all()?;
+        Ok(Writer::new(TFSYNC + 1, tag))
+    }
+
+    fn mkdir(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let name = utf8(reader.name()?)?;
+        let mode = reader.u32()?;
+        let _gid = reader.u32()?;
+        let dir = self.fid(fid)?.inode.clone();
+        let inode = dir.create(name, FileType::Dir, mode & 0o7777)?;
+        let mut writer = Writer::new(TMKDIR + 1, tag);
+        writer.qid(self.qid(&inode)?);
+        Ok(writer)
+    }
+
+    fn symlink(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let name = utf8(reader.name()?)?;
+        let target = reader.str()?;
+        let _gid = reader.u32()?;
+        let dir = self.fid(fid)?.inode.clone();
+        let inode = dir.create(name, FileType::SymLink, 0o777)?;
+        inode.write_at(0, target.as_bytes())?;
+        let mut writer = Writer::new(TSYMLINK + 1, tag);
+        writer.qid(self.qid(&inode)?);
+        Ok(writer)
+    }
+
+    fn readlink(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let inode = &self.fid(reader.u32()?)?.inode;
+        let mut buf = vec![0; inode.metadata()?.size];
+        let len = inode.read_at(0, &mut buf)?;
+        let target = core::str::from_utf8(&buf[..len]).map_err(|_| FsError::InvalidParam)?;
+        let mut writer = Writer::new(TREADLINK + 1, tag);
+        writer.str(target);
+        Ok(writer)
+    }
+
+    fn link(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let dir = self.fid(reader.u32()?)?.inode.clone();
+        let inode = self.fid(reader.u32()?)?.inode.clone();
+        let name = utf8(reader.name()?)?;
+        dir.link(name, &inode)?;
+        Ok(Writer::new(TLINK + 1, tag))
+    }
+
+    fn rename(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let fid = reader.u32()?;
+        let new_dir = self.fid(reader.u32()?)?.inode.clone();
+        let new_name = utf8(reader.name()?)?;
+        let (old_dir, old_name) = self.fid(fid)?.parent.clone().ok_or(FsError::Busy)?;
+        old_dir.move_(utf8(&old_name)?, &new_dir, new_name)?;
+        self.renamed(&old_dir, &old_name, &new_dir, new_name.as_bytes());
+        Ok(Writer::new(TRENAME + 1, tag))
+    }
+
+    fn renameat(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let old_dir = self.fid(reader.u32()?)?.inode.clone();
+        let old_name = utf8(reader.name()?)?;
+        let new_dir = self.fid(reader.u32()?)?.inode.clone();
+        let new_name = utf8(reader.name()?)?;
+        old_dir.move_(old_name, &new_dir, new_name)?;
+        self.renamed(&old_dir, old_name.as_bytes(), &new_dir, new_name.as_bytes());
+        Ok(Writer::new(TRENAMEAT + 1, tag))
+    }
+
+    fn unlinkat(&mut self, tag: u16, reader: &mut Reader) -> Reply {
+        let dir = self.fid(reader.u32()?)?.inode.clone();
+        let name = reader.name()?;
+        let flags = reader.u32()?;
+        let is_dir = dir.find_bytes(name)?.metadata()?.type_ == FileType::Dir;
+        match (flags & AT_REMOVEDIR != 0, is_dir) {
+            (true, false) => return Err(FsError::NotDir.into()),
+            (false, true) => return Err(FsError::IsDir.into()),
+            _ => {}
+        }
+        dir.unlink_bytes(name)?;
+        Ok(Writer::new(TUNLINKAT + 1, tag))
+    }
+}
+
+/// A name for the `&str` API of the fs, `InvalidName` if not UTF-8
+fn utf8(name: &[u8]) -> Result<&str> {
+    core::str::from_utf8(name).map_err(|_| FsError::InvalidName)
+}
+
+/// `size[4] type[1] tag[2]`
+fn read_header(reader: &mut Reader) -> Result<(u32, u8, u16)> {
+    Ok((reader.u32()?, reader.u8()?, reader.u16()?))
+}
+
+fn error_reply(tag: u16, errno: Errno) -> Vec<u8> {
+    let mut writer = Writer::new(RLERROR, tag);
+    writer.u32(errno.0);
+    writer.finish()
+}
+
+/// Fill `buf` from `transport`, false if it was closed before the first
+/// byte. Closing in the middle is an error.
+async fn recv_exact<T: Transport>(transport: &mut T, buf: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match transport.recv(&mut buf[filled..]).await? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(FsError::InvalidParam),
+            len => filled += len,
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,247 @@
+//! Messages of 9P2000.L: numbers, and the encoding of their fields
+//!
+//! Ref: [https://github.com/chaos/diod/blob/master/protocol.md]
+
+use alloc::vec::Vec;
+use rcore_fs::vfs::{FileType, FsError, Result};
+
+/// Size of `size[4] type[1] tag[2]` leading each message
+pub const HEADER_SIZE: usize = 7;
+/// Room for the header and fields of `Rread` and `Twrite` around the data
+pub const IO_HEADER_SIZE: usize = 24;
+/// Tag of `Tversion`, which is sent before any other message
+pub const NOTAG: u16 = !0;
+/// `afid` of `Tattach` without authentication
+pub const NOFID: u32 = !0;
+pub const VERSION: &str = "9P2000.L";
+
+pub const RLERROR: u8 = 7;
+pub const TSTATFS: u8 = 8;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TSYMLINK: u8 = 16;
+pub const TRENAME: u8 = 20;
+pub const TREADLINK: u8 = 22;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TLINK: u8 = 70;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TATTACH: u8 = 104;
+pub const TFLUSH: u8 = 108;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+pub const TREMOVE: u8 = 122;
+
+/// `type` of `Rstatfs`, as of Linux
+pub const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// Bits of `Tgetattr` `request_mask` and `Rgetattr` `valid`
+pub const GETATTR_BASIC: u64 = 0x7ff;
+pub const GETATTR_BTIME: u64 = 0x800;
+
+/// Bits of `Tsetattr` `valid`
+pub const SETATTR_MODE: u32 = 0x1;
+pub const SETATTR_UID: u32 = 0x2;
+pub const SETATTR_GID: u32 = 0x4;
+pub const SETATTR_SIZE: u32 = 0x8;
+pub const SETATTR_ATIME: u32 = 0x10;
+pub const SETATTR_MTIME: u32 = 0x20;
+pub const SETATTR_ATIME_SET: u32 = 0x80;
+pub const SETATTR_MTIME_SET: u32 = 0x100;
+
+/// Bits of `Tlopen` and `Tlcreate` `flags`, as of Linux
+pub const O_ACCMODE: u32 = 0o3;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+
+/// Bit of `Tunlinkat` `flags` to remove a dir
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+/// Linux errno of unknown messages
+pub const EOPNOTSUPP: u32 = 95;
+/// Linux errno of unknown fids
+pub const EBADF: u32 = 9;
+
+/// Identity of a file on the server
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Qid {
+    pub type_: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub const DIR: u8 = 0x80;
+    pub const SYMLINK: u8 = 0x02;
+    pub const FILE: u8 = 0x00;
+
+    pub fn type_of(type_: FileType) -> u8 {
+        match type_ {
+            FileType::Dir => Qid::DIR,
+            FileType::SymLink => Qid::SYMLINK,
+            _ => Qid::FILE,
+        }
+    }
+}
+
+/// `S_IFMT` bits of `mode` in `Rgetattr`
+pub fn mode_of(type_: FileType) -> u32 {
+    match type_ {
+        FileType::File => 0o100000,
+        FileType::Dir => 0o040000,
+        FileType::SymLink => 0o120000,
+        FileType::CharDevice => 0o020000,
+        FileType::BlockDevice => 0o060000,
+        FileType::NamedPipe => 0o010000,
+        FileType::Socket => 0o140000,
+    }
+}
+
+/// `DT_*` type of entries in `Rreaddir`
+pub fn dirent_type_of(type_: FileType) -> u8 {
+    match type_ {
+        FileType::File => 8,
+        FileType::Dir => 4,
+        FileType::SymLink => 10,
+        FileType::CharDevice => 2,
+        FileType::BlockDevice => 6,
+        FileType::NamedPipe => 1,
+        FileType::Socket => 12,
+    }
+}
+
+/// Decoder of the fields of a message, failing with `InvalidParam` past
+/// its end
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.buf.len() {
+            return Err(FsError::InvalidParam);
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// `len[2] bytes[len]` in UTF-8
+    pub fn str(&mut self) -> Result<&'a str> {
+        core::str::from_utf8(self.name()?).map_err(|_| FsError::InvalidParam)
+    }
+
+    /// `len[2] bytes[len]` of a file name, in any bytes
+    pub fn name(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+}
+
+/// Encoder of a message, filling in its size when done
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Start a message of `type_` replying to `tag`
+    pub fn new(type_: u8, tag: u16) -> Self {
+        let mut writer = Writer { buf: Vec::new() };
+        writer.u32(0);
+        writer.u8(type_);
+        writer.u16(tag);
+        writer
+    }
+
+    /// Size of the message so far
+    pub fn size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// `len[2] bytes[len]`, names longer than that are not valid in 9P
+    pub fn str(&mut self, value: &str) {
+        self.name(value.as_bytes());
+    }
+
+    /// `len[2] bytes[len]` of a file name, in any bytes
+    pub fn name(&mut self, value: &[u8]) {
+        self.u16(value.len() as u16);
+        self.bytes(value);
+    }
+
+    pub fn qid(&mut self, qid: Qid) {
+        self.u8(qid.type_);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+
+    /// Overwrite the `u32` at `offset`, e.g. a count known at the end
+    pub fn patch_u32(&mut self, offset: usize, value: u32) {
+        self.buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.patch_u32(0, size);
+        self.buf
+    }
+}
+
+/// Encoded size of `value` by `Writer::str()` or `Writer::name()`
+pub fn str_size(value: impl AsRef<[u8]>) -> usize {
+    2 + value.as_ref().len()
+}
@@ -0,0 +1,384 @@
+use crate::*;
+use core::future::poll_fn;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use rcore_fs_sfs::SimpleFileSystem;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Bytes in flight between the client and the server
+#[derive(Default)]
+struct Pipe {
+    to_server: VecDeque<u8>,
+    to_client: VecDeque<u8>,
+    closed: bool,
+}
+
+/// The server end of a `Pipe`, handing out a few bytes at a time so that
+/// messages arrive in pieces
+struct Loopback(Arc<Mutex<Pipe>>);
+
+impl Transport for Loopback {
+    fn recv(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize>> + Send {
+        let pipe = self.0.clone();
+        poll_fn(move |_| {
+            let mut pipe = pipe.lock().unwrap();
+            if pipe.to_server.is_empty() {
+                return match pipe.closed {
+                    true => Poll::Ready(Ok(0)),
+                    false => Poll::Pending,
+                };
+            }
+            let len = buf.len().min(pipe.to_server.len()).min(5);
+            for byte in buf[..len].iter_mut() {
+                *byte = pipe.to_server.pop_front().unwrap();
+            }
+            Poll::Ready(Ok(len))
+        })
+    }
+
+    fn send(&mut self, buf: &[u8]) -> impl Future<Output = Result<()>> + Send {
+        self.0.lock().unwrap().to_client.extend(buf);
+        core::future::ready(Ok(()))
+    }
+}
+
+/// Hand-rolled client, sending each message and running the server until
+/// it waits for the next one
+struct Client {
+    pipe: Arc<Mutex<Pipe>>,
+    serve: Pin<Box<dyn Future<Output = Result<()>>>>,
+    tag: u16,
+}
+
+impl Client {
+    fn new(fs: Arc<dyn FileSystem>) -> Self {
+        let pipe = Arc::new(Mutex::new(Pipe::default()));
+        let mut transport = Loopback(pipe.clone());
+        let serve = Box::pin(async move { Server::new(fs).serve(&mut transport).await });
+        Client {
+            pipe,
+            serve,
+            tag: 0,
+        }
+    }
+
+    fn poll(&mut self) -> Poll<Result<()>> {
+        let mut cx = Context::from_waker(Waker::noop());
+        self.serve.as_mut().poll(&mut cx)
+    }
+
+    /// Send `body` as a message of `type_`, and return the type and body
+    /// of the reply
+    fn rpc(&mut self, type_: u8, body: &[u8]) -> (u8, Vec<u8>) {
+        self.tag += 1;
+        let tag = match type_ {
+            TVERSION => NOTAG,
+            _ => self.tag,
+        };
+        let size = (HEADER_SIZE + body.len()) as u32;
+        let request = [&size.to_le_bytes()[..], &[type_], &tag.to_le_bytes(), body].concat();
+        self.pipe.lock().unwrap().to_server.extend(request);
+        assert!(self.poll().is_pending());
+        let reply: Vec<u8> = self.pipe.lock().unwrap().to_client.drain(..).collect();
+        let size = u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]);
+        assert_eq!(size as usize, reply.len());
+        assert_eq!(u16::from_le_bytes([reply[5], reply[6]]), tag);
+        (reply[4], reply[HEADER_SIZE..].to_vec())
+    }
+
+    /// Like `rpc()`, expecting a reply of `type_ + 1`
+    fn call(&mut self, type_: u8, body: &[u8]) -> Vec<u8> {
+        let (reply_type, body) = self.rpc(type_, body);
+        assert_eq!(reply_type, type_ + 1, "error {:?}", body);
+        body
+    }
+
+    /// Like `rpc()`, expecting `Rlerror`, and return the errno
+    fn error(&mut self, type_: u8, body: &[u8]) -> u32 {
+        let (reply_type, body) = self.rpc(type_, body);
+        assert_eq!(reply_type, RLERROR);
+        u32::from_le_bytes([body[0], body[1], body[2], body[3]])
+    }
+}
+
+fn s(value: &str) -> Vec<u8> {
+    [&(value.len() as u16).to_le_bytes()[..], value.as_bytes()].concat()
+}
+
+fn qid(type_: u8, inode: &Arc<dyn INode>) -> Vec<u8> {
+    let path = inode.ino_key().inode as u64;
+    let version = inode.data_version() as u32;
+    [&[type_][..], &version.to_le_bytes(), &path.to_le_bytes()].concat()
+}
+
+fn dirent(qid: &[u8], offset: u64, type_: u8, name: &str) -> Vec<u8> {
+    [qid, &offset.to_le_bytes(), &[type_], &s(name)].concat()
+}
+
+fn sfs() -> Arc<SimpleFileSystem> {
+    let file = tempfile::tempfile().unwrap();
+    SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap()
+}
+
+/// Fid of the root after `attach()`
+const ROOT: u32 = 0;
+
+fn attach(client: &mut Client) {
+    let version = client.call(
+        TVERSION,
+        &[&8192u32.to_le_bytes()[..], &s("9P2000.L")].concat(),
+    );
+    assert_eq!(
+        version,
+        [&8192u32.to_le_bytes()[..], &s("9P2000.L")].concat()
+    );
+    let body = [
+        &ROOT.to_le_bytes()[..],
+        &NOFID.to_le_bytes(),
+        &s("root"),
+        &s(""),
+        &0u32.to_le_bytes(),
+    ]
+    .concat();
+    client.call(TATTACH, &body);
+}
+
+#[test]
+fn session() {
+    let sfs = sfs();
+    let root = sfs.root_inode();
+    let mut client = Client::new(sfs.clone());
+    attach(&mut client);
+    let root_qid = qid(Qid::DIR, &root);
+    let getattr = [&ROOT.to_le_bytes()[..], &GETATTR_BASIC.to_le_bytes()].concat();
+    assert_eq!(client.call(TGETATTR, &getattr)[8..21], root_qid[..]);
+
+    // create and write a file through a clone of the root fid
+    let walk = [
+        &ROOT.to_le_bytes()[..],
+        &1u32.to_le_bytes(),
+        &0u16.to_le_bytes(),
+    ]
+    .concat();
+    assert_eq!(client.call(TWALK, &walk), 0u16.to_le_bytes());
+    let lcreate = [
+        &1u32.to_le_bytes()[..],
+        &s("hello"),
+        &O_RDWR.to_le_bytes(),
+        &0o644u32.to_le_bytes(),
+        &0u32.to_le_bytes(),
+    ]
+    .concat();
+    let created = client.call(TLCREATE, &lcreate);
+    let hello_qid = qid(Qid::FILE, &root.find("hello").unwrap());
+    let iounit = 8192 - IO_HEADER_SIZE as u32;
+    assert_eq!(created, [&hello_qid[..], &iounit.to_le_bytes()].concat());
+    let data = b"hello, 9p";
+    let write = [
+        &1u32.to_le_bytes()[..],
+        &0u64.to_le_bytes(),
+        &(data.len() as u32).to_le_bytes(),
+        data,
+    ]
+    .concat();
+    assert_eq!(client.call(TWRITE, &write), 9u32.to_le_bytes());
+    // the qid has a new version
+    assert_eq!(root.find("hello").unwrap().data_version(), 1);
+    let hello_qid = qid(Qid::FILE, &root.find("hello").unwrap());
+    let read = [
+        &1u32.to_le_bytes()[..],
+        &7u64.to_le_bytes(),
+        &100u32.to_le_bytes(),
+    ]
+    .concat();
+    assert_eq!(
+        client.call(TREAD, &read),
+        [&2u32.to_le_bytes()[..], b"9p"].concat()
+    );
+
+    // readdir, by cookies
+    let walk = [
+        &ROOT.to_le_bytes()[..],
+        &2u32.to_le_bytes(),
+        &0u16.to_le_bytes(),
+    ]
+    .concat();
+    client.call(TWALK, &walk);
+    client.call(
+        TLOPEN,
+        &[&2u32.to_le_bytes()[..], &0u32.to_le_bytes()].concat(),
+    );
+    let entries = [
+        dirent(&root_qid, 1, 4, "."),
+        dirent(&root_qid, 2, 4, ".."),
+        dirent(&hello_qid, 3, 8, "hello"),
+    ];
+    let readdir = |offset: u64, count: u32| {
+        [
+            &2u32.to_le_bytes()[..],
+            &offset.to_le_bytes(),
+            &count.to_le_bytes(),
+        ]
+        .concat()
+    };
+    let all = entries.concat();
+    assert_eq!(
+        client.call(TREADDIR, &readdir(0, 1000)),
+        [&(all.len() as u32).to_le_bytes()[..], &all].concat()
+    );
+    let first = &entries[0];
+    let count = first.len() as u32 + 1;
+    assert_eq!(
+        client.call(TREADDIR, &readdir(0, count)),
+        [&(first.len() as u32).to_le_bytes()[..], first].concat()
+    );
+    let rest = entries[1..].concat();
+    assert_eq!(
+        client.call(TREADDIR, &readdir(1, 1000)),
+        [&(rest.len() as u32).to_le_bytes()[..], &rest].concat()
+    );
+    assert_eq!(client.call(TREADDIR, &readdir(3, 1000)), 0u32.to_le_bytes());
+
+    // rename the open file into a new dir, its qid stays
+    let mkdir = [
+        &ROOT.to_le_bytes()[..],
+        &s("dir"),
+        &0o755u32.to_le_bytes(),
+        &0u32.to_le_bytes(),
+    ]
+    .concat();
+    let dir_qid = client.call(TMKDIR, &mkdir);
+    assert_eq!(dir_qid, qid(Qid::DIR, &root.find("dir").unwrap()));
+    let walk = [
+        &ROOT.to_le_bytes()[..],
+        &3u32.to_le_bytes(),
+        &1u16.to_le_bytes(),
+        &s("dir"),
+    ]
+    .concat();
+    assert_eq!(
+        client.call(TWALK, &walk),
+        [&1u16.to_le_bytes()[..], &dir_qid].concat()
+    );
+    let rename = [&1u32.to_le_bytes()[..], &3u32.to_le_bytes(), &s("moved")].concat();
+    client.call(TRENAME, &rename);
+    let getattr = [&1u32.to_le_bytes()[..], &GETATTR_BASIC.to_le_bytes()].concat();
+    let attr = client.call(TGETATTR, &getattr);
+    assert_eq!(attr[..8], (GETATTR_BASIC | GETATTR_BTIME).to_le_bytes());
+    assert_eq!(attr[8..21], hello_qid[..]);
+    assert_eq!(attr[21..25], (0o100644u32).to_le_bytes());
+    assert_eq!(attr[49..57], 9u64.to_le_bytes());
+    let walk = [
+        &ROOT.to_le_bytes()[..],
+        &4u32.to_le_bytes(),
+        &2u16.to_le_bytes(),
+        &s("dir"),
+        &s("moved"),
+    ]
+    .concat();
+    assert_eq!(
+        client.call(TWALK, &walk),
+        [&2u16.to_le_bytes()[..], &dir_qid, &hello_qid].concat()
+    );
+    let read = [
+        &1u32.to_le_bytes()[..],
+        &0u64.to_le_bytes(),
+        &100u32.to_le_bytes(),
+    ]
+    .concat();
+    assert_eq!(
+        client.call(TREAD, &read),
+        [&9u32.to_le_bytes()[..], data].concat()
+    );
+    // the fid was moved along, so it can be renamed again
+    let rename = [&1u32.to_le_bytes()[..], &3u32.to_le_bytes(), &s("again")].concat();
+    client.call(TRENAME, &rename);
+    assert!(root.lookup("dir/again").is_ok());
+
+    // unlink
+    let unlinkat = |dir: u32, name: &str, flags: u32| {
+        [&dir.to_le_bytes()[..], &s(name), &flags.to_le_bytes()].concat()
+    };
+    client.call(TUNLINKAT, &unlinkat(3, "again", 0));
+    let walk = [
+        &ROOT.to_le_bytes()[..],
+        &5u32.to_le_bytes(),
+        &2u16.to_le_bytes(),
+        &s("dir"),
+        &s("again"),
+    ]
+    .concat();
+    assert_eq!(
+        client.call(TWALK, &walk),
+        [&1u16.to_le_bytes()[..], &dir_qid].concat()
+    );
+    let walk = [
+        &ROOT.to_le_bytes()[..],
+        &5u32.to_le_bytes(),
+        &1u16.to_le_bytes(),
+        &s("again"),
+    ]
+    .concat();
+    assert_eq!(client.error(TWALK, &walk), 2); // ENOENT
+    assert_eq!(client.error(TUNLINKAT, &unlinkat(ROOT, "dir", 0)), 21); // EISDIR
+    client.call(TUNLINKAT, &unlinkat(ROOT, "dir", AT_REMOVEDIR));
+    assert_eq!(root.list().unwrap(), [".", ".."]);
+
+    assert_eq!(client.error(TCLUNK, &99u32.to_le_bytes()), EBADF);
+    client.call(TCLUNK, &1u32.to_le_bytes());
+    assert_eq!(client.error(TREAD, &read), EBADF);
+    assert_eq!(client.error(0, &[]), EOPNOTSUPP);
+
+    client.pipe.lock().unwrap().closed = true;
+    assert!(matches!(client.poll(), Poll::Ready(Ok(()))));
+}
+
+/// Names not UTF-8 are looked up by their bytes, but not made
+#[test]
+fn names_not_utf8() {
+    let mut client = Client::new(sfs());
+    attach(&mut client);
+    let name = b"bad\xff";
+    let name = [&(name.len() as u16).to_le_bytes()[..], name].concat();
+    let walk = [
+        &ROOT.to_le_bytes()[..],
+        &1u32.to_le_bytes(),
+        &1u16.to_le_bytes(),
+        &name,
+    ]
+    .concat();
+    assert_eq!(client.error(TWALK, &walk), 2); // ENOENT
+    let unlinkat = [&ROOT.to_le_bytes()[..], &name, &0u32.to_le_bytes()].concat();
+    assert_eq!(client.error(TUNLINKAT, &unlinkat), 2);
+    let mkdir = [
+        &ROOT.to_le_bytes()[..],
+        &name,
+        &0o755u32.to_le_bytes(),
+        &0u32.to_le_bytes(),
+    ]
+    .concat();
+    assert_eq!(client.error(TMKDIR, &mkdir), 84); // EILSEQ
+    let lcreate = [
+        &ROOT.to_le_bytes()[..],
+        &name,
+        &O_RDWR.to_le_bytes(),
+        &0o644u32.to_le_bytes(),
+        &0u32.to_le_bytes(),
+    ]
+    .concat();
+    assert_eq!(client.error(TLCREATE, &lcreate), 84);
+}
+
+#[test]
+fn oversized_message() {
+    let mut client = Client::new(sfs());
+    attach(&mut client);
+    let size = 8192u32 + 1;
+    let request = [&size.to_le_bytes()[..], &[TCLUNK], &1u16.to_le_bytes()].concat();
+    client.pipe.lock().unwrap().to_server.extend(request);
+    assert!(matches!(
+        client.poll(),
+        Poll::Ready(Err(FsError::InvalidParam))
+    ));
+}
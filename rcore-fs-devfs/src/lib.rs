@@ -1,4 +1,16 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
+// a device going away must not take the kernel down
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::panic,
+        clippy::unimplemented,
+        clippy::unreachable,
+        clippy::todo
+    )
+)]
 
 extern crate alloc;
 
@@ -11,7 +23,14 @@ use core::any::Any;
 use rcore_fs::vfs::*;
 use spin::RwLock;
 
+mod manifest;
+mod numbered;
 pub mod special;
+#[cfg(test)]
+mod tests;
+
+pub use self::manifest::*;
+pub use self::numbered::*;
 
 /// Device file system
 ///
@@ -22,6 +41,7 @@ pub mod special;
 /// You can add or remove devices through `add()` and `remove()`.
 pub struct DevFS {
     root: Arc<DevINode>,
+    instance_id: u64,
 }
 
 impl FileSystem for DevFS {
@@ -43,14 +63,20 @@ impl FileSystem for DevFS {
             files: 0,
             ffree: 0,
             namemax: 0,
+            linkmax: 0,
         }
     }
+
+    fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
 }
 
 impl DevFS {
     pub fn new() -> Arc<Self> {
         let fs = Arc::new(Self {
             root: DevINode::new(),
+            instance_id: new_instance_id(),
         });
         *fs.root.fs.write() = Arc::downgrade(&fs);
         fs
@@ -73,6 +99,8 @@ pub struct DevINode {
     parent: Weak<DevINode>,
     fs: RwLock<Weak<DevFS>>,
     children: RwLock<BTreeMap<String, Arc<dyn INode>>>,
+    /// Driver keys of children added by `add_with_key()`
+    keys: RwLock<BTreeMap<String, String>>,
     inode_id: usize,
 }
 
@@ -83,6 +111,7 @@ impl DevINode {
             parent,
             fs: RwLock::new(Weak::default()),
             children: RwLock::new(BTreeMap::new()),
+            keys: RwLock::new(BTreeMap::new()),
             inode_id: DevFS::new_inode_id(),
         }
         .wrap()
@@ -117,6 +146,18 @@ impl DevINode {
         Ok(dir)
     }
 
+    /// Add a dir `name` of devices named by numbers, e.g. `pts`, see
+    /// `NumberedDir`
+    pub fn add_numbered_dir(&self, name: &str) -> Result<Arc<NumberedDir>> {
+        let mut children = self.children.write();
+        if children.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        let dir = NumberedDir::new(self.this.clone(), self.fs.read().clone());
+        children.insert(String::from(name), dir.clone());
+        Ok(dir)
+    }
+
     pub fn add(&self, name: &str, dev: Arc<dyn INode>) -> Result<()> {
         let mut children = self.children.write();
         if children.contains_key(name) {
@@ -126,11 +167,68 @@ impl DevINode {
         Ok(())
     }
 
+    /// Add device `dev` as `name` with permission `mode`.
+    /// Its device number is overridden by `rdev` if given.
+    pub fn add_with_perm(
+        &self,
+        name: &str,
+        dev: Arc<dyn INode>,
+        mode: u16,
+        rdev: Option<usize>,
+    ) -> Result<()> {
+        self.add(name, Arc::new(special::PermINode::new(dev, mode, rdev)))
+    }
+
+    /// Add device `dev` as `name`, recording the driver `key` which makes it
+    /// again from a manifest, see `DevFS::apply_manifest()`
+    pub fn add_with_key(&self, name: &str, dev: Arc<dyn INode>, key: &str) -> Result<()> {
+        self.add(name, dev)?;
+        self.keys
+            .write()
+            .insert(String::from(name), String::from(key));
+        Ok(())
+    }
+
+    /// Add a symlink `name` pointing to `target`
+    pub fn add_symlink(&self, name: &str, target: &str) -> Result<()> {
+        self.add(
+            name,
+            Arc::new(special::SymlinkINode::new(String::from(target))),
+        )
+    }
+
     pub fn remove(&self, name: &str) -> Result<()> {
         let mut children = self.children.write();
         children.remove(name).ok_or(FsError::EntryNotFound)?;
+        self.keys.write().remove(name);
         Ok(())
     }
+
+    fn metadata_of_size(&self, size: usize) -> Metadata {
+        dir_metadata(&self.fs.read(), self.inode_id, size)
+    }
+}
+
+/// Metadata of dir `inode_id` of `fs` with `size` entries
+fn dir_metadata(fs: &Weak<DevFS>, inode_id: usize, size: usize) -> Metadata {
+    Metadata {
+        dev: fs.upgrade().map_or(0, |fs| fs.instance_id as usize),
+        inode: inode_id,
+        size,
+        blk_size: 0,
+        blocks: 0,
+        atime: Timespec { sec: 0, nsec: 0 },
+        mtime: Timespec { sec: 0, nsec: 0 },
+        ctime: Timespec { sec: 0, nsec: 0 },
+        btime: Timespec { sec: 0, nsec: 0 },
+        type_: FileType::Dir,
+        mode: 0o755,
+        nlinks: 2,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: MetadataFlags::empty(),
+    }
 }
 
 impl INode for DevINode {
@@ -147,26 +245,23 @@ impl INode for DevINode {
     }
 
     fn metadata(&self) -> Result<Metadata> {
-        Ok(Metadata {
-            dev: 0,
-            inode: self.inode_id,
-            size: self.children.read().len(),
-            blk_size: 0,
-            blocks: 0,
-            atime: Timespec { sec: 0, nsec: 0 },
-            mtime: Timespec { sec: 0, nsec: 0 },
-            ctime: Timespec { sec: 0, nsec: 0 },
-            type_: FileType::Dir,
-            mode: 0o755,
-            nlinks: 2,
-            uid: 0,
-            gid: 0,
-            rdev: 0,
-        })
+        Ok(self.metadata_of_size(self.children.read().len()))
+    }
+
+    fn metadata_partial(&self, mask: MetadataMask) -> Result<PartialMetadata> {
+        // only the size needs to lock the children
+        let size = match mask.contains(MetadataMask::SIZE) {
+            true => self.children.read().len(),
+            false => 0,
+        };
+        Ok(PartialMetadata::from_metadata(
+            &self.metadata_of_size(size),
+            mask,
+        ))
     }
 
     fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
-        Err(FsError::NotSupported)
+        Err(FsError::Unsupported)
     }
 
     fn sync_all(&self) -> Result<()> {
@@ -181,20 +276,39 @@ impl INode for DevINode {
         Err(FsError::IsDir)
     }
 
+    // the tree is only changed by the kernel, through `add()` and `remove()`
+
     fn create(&self, _name: &str, _type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
-        Err(FsError::NotSupported)
+        Err(FsError::Unsupported)
+    }
+
+    /// The existing entry only, as `create()`
+    fn find_or_create(
+        &self,
+        name: &str,
+        type_: FileType,
+        _mode: u32,
+    ) -> Result<(Arc<dyn INode>, bool)> {
+        let inode = match self.find(name) {
+            Err(FsError::EntryNotFound) => return Err(FsError::Unsupported),
+            result => result?,
+        };
+        match inode.metadata()?.type_ == type_ {
+            true => Ok((inode, false)),
+            false => Err(FsError::EntryExist),
+        }
     }
 
     fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
-        Err(FsError::NotSupported)
+        Err(FsError::Unsupported)
     }
 
     fn unlink(&self, _name: &str) -> Result<()> {
-        Err(FsError::NotSupported)
+        Err(FsError::Unsupported)
     }
 
     fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> Result<()> {
-        Err(FsError::NotSupported)
+        Err(FsError::Unsupported)
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
@@ -232,8 +346,20 @@ impl INode for DevINode {
         Err(FsError::NotSupported)
     }
 
-    fn fs(&self) -> Arc<dyn FileSystem> {
-        self.fs.read().upgrade().unwrap()
+    /// `NotSupported` once the `DevFS` is dropped, the INode is then in no
+    /// file system, see `INode::fs()`
+    fn fs(&self) -> Result<Arc<dyn FileSystem>> {
+        match self.fs.read().upgrade() {
+            Some(fs) => Ok(fs),
+            None => Err(FsError::NotSupported),
+        }
+    }
+
+    fn ino_key(&self) -> InodeKey {
+        InodeKey {
+            fs: self.fs.read().upgrade().map_or(0, |fs| fs.instance_id),
+            inode: self.inode_id,
+        }
     }
 
     fn as_any_ref(&self) -> &dyn Any {
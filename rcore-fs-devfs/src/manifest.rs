@@ -0,0 +1,242 @@
+//! Manifests of the tree of a DevFS, to register the devices again at the
+//! same paths with the same permissions, e.g. after a reboot
+
+use super::*;
+use alloc::{format, vec, vec::Vec};
+use core::str;
+
+/// A dir, device or symlink in a DevFS
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DevManifestEntry {
+    /// Path from the root, like "input/event0"
+    pub path: String,
+    pub type_: FileType,
+    pub mode: u16,
+    pub uid: usize,
+    pub gid: usize,
+    /// Device number packed by `make_rdev()`
+    pub rdev: usize,
+    /// Driver key of a device given to `DevINode::add_with_key()`, empty if
+    /// added without one. Target of a symlink, empty for a dir.
+    pub key: String,
+}
+
+impl DevFS {
+    /// Entries of all dirs, devices and symlinks in the order of
+    /// enumeration, each dir before the entries in it
+    pub fn export_manifest(&self) -> Result<Vec<DevManifestEntry>> {
+        let mut entries = Vec::new();
+        self.root.export_to("", &mut entries)?;
+        Ok(entries)
+    }
+
+    /// Make `entries` of `export_manifest()` again, asking `resolver` for
+    /// the device of each driver key. Dirs already here are kept.
+    ///
+    /// Devices are added with the permission, owner and device number
+    /// recorded. Return the paths of devices whose key is not resolved,
+    /// they are skipped.
+    pub fn apply_manifest(
+        &self,
+        entries: &[DevManifestEntry],
+        resolver: &dyn Fn(&str) -> Option<Arc<dyn INode>>,
+    ) -> Result<Vec<String>> {
+        let mut unresolved = Vec::new();
+        for entry in entries {
+            let (dir, name) = match entry.path.rfind('/') {
+                Some(pos) => (
+                    self.root.find_dir(&entry.path[..pos])?,
+                    &entry.path[pos + 1..],
+                ),
+                None => (self.root(), entry.path.as_str()),
+            };
+            match entry.type_ {
+                FileType::Dir => {
+                    if dir.find_dir(name).is_err() {
+                        dir.add_dir(name)?;
+                    }
+                }
+                FileType::SymLink => dir.add_symlink(name, &entry.key)?,
+                _ => match resolver(&entry.key) {
+                    Some(dev) => {
+                        let dev = special::PermINode::new(dev, entry.mode, Some(entry.rdev))
+                            .with_owner(entry.uid, entry.gid);
+                        dir.add_with_key(name, Arc::new(dev), &entry.key)?;
+                    }
+                    None => unresolved.push(entry.path.clone()),
+                },
+            }
+        }
+        Ok(unresolved)
+    }
+}
+
+impl DevINode {
+    fn export_to(&self, prefix: &str, entries: &mut Vec<DevManifestEntry>) -> Result<()> {
+        for (name, child) in self.children.read().iter() {
+            let path = match prefix {
+                "" => name.clone(),
+                _ => format!("{}/{}", prefix, name),
+            };
+            let metadata = child.metadata()?;
+            let key = match metadata.type_ {
+                FileType::Dir => String::new(),
+                FileType::SymLink => {
+                    let mut target = vec![0; metadata.size];
+                    let len = child.read_at(0, &mut target)?;
+                    String::from(str::from_utf8(&target[..len]).map_err(|_| FsError::InvalidParam)?)
+                }
+                _ => self.keys.read().get(name).cloned().unwrap_or_default(),
+            };
+            entries.push(DevManifestEntry {
+                path: path.clone(),
+                type_: metadata.type_,
+                mode: metadata.mode,
+                uid: metadata.uid,
+                gid: metadata.gid,
+                rdev: metadata.rdev,
+                key,
+            });
+            if let Some(dir) = child.downcast_ref::<DevINode>() {
+                dir.export_to(&path, entries)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The dir at `path` under this dir
+    fn find_dir(&self, path: &str) -> Result<Arc<DevINode>> {
+        let mut dir = self.this.upgrade().ok_or(FsError::EntryNotFound)?;
+        for name in path.split('/') {
+            let child = dir.find(name)?;
+            dir = match child.downcast_ref::<DevINode>() {
+                Some(child) => child.this.upgrade().ok_or(FsError::EntryNotFound)?,
+                None => return Err(FsError::NotDir),
+            };
+        }
+        Ok(dir)
+    }
+}
+
+/// Magic at the start of an encoded manifest
+const MANIFEST_MAGIC: &[u8; 4] = b"DEVM";
+
+impl DevManifestEntry {
+    /// Encode `entries` to bytes: the magic and the number of entries, then
+    /// the fields of each entry, strings prefixed by their length. Integers
+    /// are little-endian.
+    pub fn encode(entries: &[Self]) -> Vec<u8> {
+        let mut buf = Vec::from(&MANIFEST_MAGIC[..]);
+        buf.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        for entry in entries {
+            put_str(&mut buf, &entry.path);
+            buf.push(type_to_u8(entry.type_));
+            buf.extend_from_slice(&entry.mode.to_le_bytes());
+            buf.extend_from_slice(&(entry.uid as u64).to_le_bytes());
+            buf.extend_from_slice(&(entry.gid as u64).to_le_bytes());
+            buf.extend_from_slice(&(entry.rdev as u64).to_le_bytes());
+            put_str(&mut buf, &entry.key);
+        }
+        buf
+    }
+
+    /// Decode the bytes of `encode()`, failing with `InvalidParam` if they
+    /// are malformed
+    pub fn decode(bytes: &[u8]) -> Result<Vec<Self>> {
+        let mut reader = Reader(bytes);
+        if reader.take(4)? != MANIFEST_MAGIC {
+            return Err(FsError::InvalidParam);
+        }
+        let count = reader.u32()? as usize;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push(DevManifestEntry {
+                path: reader.string()?,
+                type_: type_from_u8(reader.take(1)?[0])?,
+                mode: reader.u16()?,
+                uid: reader.u64()? as usize,
+                gid: reader.u64()? as usize,
+                rdev: reader.u64()? as usize,
+                key: reader.string()?,
+            });
+        }
+        if !reader.0.is_empty() {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(entries)
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// File types by their byte, see `type_to_u8()`
+const TYPES: [FileType; 7] = [
+    FileType::File,
+    FileType::Dir,
+    FileType::SymLink,
+    FileType::CharDevice,
+    FileType::BlockDevice,
+    FileType::NamedPipe,
+    FileType::Socket,
+];
+
+fn type_to_u8(type_: FileType) -> u8 {
+    match type_ {
+        FileType::File => 0,
+        FileType::Dir => 1,
+        FileType::SymLink => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::NamedPipe => 5,
+        FileType::Socket => 6,
+    }
+}
+
+fn type_from_u8(byte: u8) -> Result<FileType> {
+    TYPES
+        .get(byte as usize)
+        .copied()
+        .ok_or(FsError::InvalidParam)
+}
+
+/// Bytes not decoded yet
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(FsError::InvalidParam);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        let s = str::from_utf8(bytes).map_err(|_| FsError::InvalidParam)?;
+        Ok(String::from(s))
+    }
+}
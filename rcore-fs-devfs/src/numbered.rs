@@ -0,0 +1,160 @@
+//! A dir of devices named by numbers, made and removed by the kernel as
+//! they come and go, like `/dev/pts`
+
+use super::*;
+use alloc::format;
+
+/// Dir of devices named "0", "1", ..., listed in numeric order. Made by
+/// `DevINode::add_numbered_dir()`.
+pub struct NumberedDir {
+    this: Weak<NumberedDir>,
+    parent: Weak<DevINode>,
+    fs: Weak<DevFS>,
+    children: RwLock<BTreeMap<usize, Arc<dyn INode>>>,
+    inode_id: usize,
+}
+
+impl NumberedDir {
+    pub(crate) fn new(parent: Weak<DevINode>, fs: Weak<DevFS>) -> Arc<Self> {
+        Arc::new_cyclic(|this| NumberedDir {
+            this: this.clone(),
+            parent,
+            fs,
+            children: RwLock::new(BTreeMap::new()),
+            inode_id: DevFS::new_inode_id(),
+        })
+    }
+
+    /// Add the device made by `make_inode` as the lowest number not in use,
+    /// return the number with the device. Concurrent calls never get the
+    /// same number, `make_inode` is called with the dir locked.
+    pub fn allocate(
+        &self,
+        make_inode: &dyn Fn(usize) -> Arc<dyn INode>,
+    ) -> Result<(usize, Arc<dyn INode>)> {
+        let mut children = self.children.write();
+        // the first gap in the numbers, or the one after them
+        let n = children
+            .keys()
+            .enumerate()
+            .find(|&(i, &n)| i != n)
+            .map_or(children.len(), |(i, _)| i);
+        let inode = make_inode(n);
+        children.insert(n, inode.clone());
+        Ok((n, inode))
+    }
+
+    /// Remove the device numbered `n`, so that the number is used again
+    pub fn release(&self, n: usize) -> Result<()> {
+        self.children
+            .write()
+            .remove(&n)
+            .map(|_| ())
+            .ok_or(FsError::EntryNotFound)
+    }
+}
+
+impl INode for NumberedDir {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::IsDir)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Err(FsError::IsDir)
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(dir_metadata(
+            &self.fs,
+            self.inode_id,
+            self.children.read().len(),
+        ))
+    }
+
+    fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn resize(&self, _len: usize) -> Result<()> {
+        Err(FsError::IsDir)
+    }
+
+    // only changed by the kernel, through `allocate()` and `release()`
+
+    fn create(&self, _name: &str, _type_: FileType, _mode: u32) -> Result<Arc<dyn INode>> {
+        Err(FsError::Unsupported)
+    }
+
+    fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "." => Ok(self.this.upgrade().ok_or(FsError::EntryNotFound)?),
+            ".." => Ok(self.parent.upgrade().ok_or(FsError::EntryNotFound)?),
+            name => {
+                let n: usize = name.parse().map_err(|_| FsError::EntryNotFound)?;
+                // "07" or "+7" is not "7"
+                if format!("{}", n) != name {
+                    return Err(FsError::EntryNotFound);
+                }
+                self.children
+                    .read()
+                    .get(&n)
+                    .cloned()
+                    .ok_or(FsError::EntryNotFound)
+            }
+        }
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            i => match self.children.read().keys().nth(i - 2) {
+                Some(n) => Ok(n.to_string()),
+                None => Err(FsError::EntryNotFound),
+            },
+        }
+    }
+
+    /// `NotSupported` once the `DevFS` is dropped, see `DevINode::fs()`
+    fn fs(&self) -> Result<Arc<dyn FileSystem>> {
+        match self.fs.upgrade() {
+            Some(fs) => Ok(fs),
+            None => Err(FsError::NotSupported),
+        }
+    }
+
+    fn ino_key(&self) -> InodeKey {
+        InodeKey {
+            fs: self.fs.upgrade().map_or(0, |fs| fs.instance_id),
+            inode: self.inode_id,
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
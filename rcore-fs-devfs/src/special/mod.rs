@@ -1,4 +1,7 @@
 //! Built-in special device files
+//!
+//! They are not part of any DevFS and can be added to several, so their
+//! `Metadata::dev` is 0.
 
 use super::*;
 
@@ -34,15 +37,9 @@ macro_rules! impl_inode {
         fn get_entry(&self, _id: usize) -> Result<String> {
             Err(FsError::NotDir)
         }
-        fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
-            Err(FsError::NotSupported)
-        }
         fn mmap(&self, _area: MMapArea) -> Result<()> {
             Err(FsError::NotSupported)
         }
-        fn fs(&self) -> Arc<dyn FileSystem> {
-            unimplemented!()
-        }
         fn as_any_ref(&self) -> &dyn Any {
             self
         }
@@ -50,7 +47,13 @@ macro_rules! impl_inode {
 }
 
 mod null;
+mod perm;
+mod ptmx;
+mod symlink;
 mod zero;
 
 pub use self::null::*;
+pub use self::perm::*;
+pub use self::ptmx::*;
+pub use self::symlink::*;
 pub use self::zero::*;
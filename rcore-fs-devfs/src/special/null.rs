@@ -1,13 +1,29 @@
 use super::*;
+use rcore_fs::vfs::ioctl::{IoctlHandler, IoctlMemory, FIONBIO, FIONREAD};
 
 pub struct NullINode {
     inode_id: usize,
+    ioctl: IoctlHandler,
 }
 
 impl NullINode {
     pub fn new() -> Self {
+        Self::with_ioctl(IoctlHandler::new())
+    }
+
+    /// Create with `memory` to access the arguments of `io_control()`
+    pub fn with_memory(memory: Arc<dyn IoctlMemory>) -> Self {
+        Self::with_ioctl(IoctlHandler::new().with_memory(memory))
+    }
+
+    fn with_ioctl(ioctl: IoctlHandler) -> Self {
         Self {
             inode_id: DevFS::new_inode_id(),
+            ioctl: ioctl
+                // nothing to read, ever
+                .on_read(FIONREAD, || Ok(0i32.to_ne_bytes()))
+                // never blocks anyway
+                .on_write(FIONBIO, |_: &[u8; 4]| Ok(0)),
         }
     }
 }
@@ -33,7 +49,7 @@ impl INode for NullINode {
 
     fn metadata(&self) -> Result<Metadata> {
         Ok(Metadata {
-            dev: 1,
+            dev: 0,
             inode: self.inode_id,
             size: 0,
             blk_size: 0,
@@ -41,14 +57,20 @@ impl INode for NullINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            btime: Timespec { sec: 0, nsec: 0 },
             type_: FileType::CharDevice,
             mode: 0o666,
             nlinks: 1,
             uid: 0,
             gid: 0,
             rdev: make_rdev(1, 3),
+            flags: MetadataFlags::empty(),
         })
     }
 
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.ioctl.dispatch(cmd, data)
+    }
+
     impl_inode!();
 }
@@ -0,0 +1,124 @@
+use super::*;
+
+/// Wrapper of a device overriding its permission and device number.
+/// It also tracks the openers, so the device can be opened exclusively.
+pub struct PermINode {
+    inner: Arc<dyn INode>,
+    mode: u16,
+    rdev: Option<usize>,
+    /// (uid, gid), `None` to keep the ones of `inner`
+    owner: Option<(usize, usize)>,
+    openers: OpenTracker,
+}
+
+impl PermINode {
+    /// `rdev` is packed by `make_rdev()`, `None` to keep the one of `inner`
+    pub fn new(inner: Arc<dyn INode>, mode: u16, rdev: Option<usize>) -> Self {
+        Self {
+            inner,
+            mode,
+            rdev,
+            owner: None,
+            openers: OpenTracker::new(),
+        }
+    }
+
+    /// Override the owner of `inner` too
+    pub fn with_owner(mut self, uid: usize, gid: usize) -> Self {
+        self.owner = Some((uid, gid));
+        self
+    }
+}
+
+impl INode for PermINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.inner.write_at(offset, buf)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        self.inner.poll()
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let mut metadata = self.inner.metadata()?;
+        metadata.mode = self.mode;
+        if let Some(rdev) = self.rdev {
+            metadata.rdev = rdev;
+        }
+        if let Some((uid, gid)) = self.owner {
+            metadata.uid = uid;
+            metadata.gid = gid;
+        }
+        Ok(metadata)
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.inner.set_metadata(metadata)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        self.inner.sync_all()
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        self.inner.sync_data()
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        self.inner.resize(len)
+    }
+
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        self.inner.create2(name, type_, mode, data)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        self.inner.link(name, other)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.inner.unlink(name)
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        self.inner.move_(old_name, target, new_name)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        self.inner.find(name)
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.inner.get_entry(id)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.inner.io_control(cmd, data)
+    }
+
+    fn mmap(&self, area: MMapArea) -> Result<()> {
+        self.inner.mmap(area)
+    }
+
+    fn open_hook(&self, exclusive: bool) -> Result<OpenGuard> {
+        self.openers.open(exclusive)
+    }
+
+    fn wrapped(&self) -> Option<&Arc<dyn INode>> {
+        Some(&self.inner)
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self.inner.as_any_ref()
+    }
+}
@@ -0,0 +1,80 @@
+use super::*;
+use alloc::boxed::Box;
+
+/// Multiplexer like `/dev/ptmx`: each open makes a device in a
+/// `NumberedDir`, e.g. `/dev/pts/N`, removed once the open is closed. The
+/// guard of the open carries the number, see `OpenGuard::number()`.
+///
+/// Every open gets a device of its own, so `exclusive` opens are not
+/// tracked.
+pub struct PtmxLikeINode {
+    inode_id: usize,
+    dir: Arc<NumberedDir>,
+    make_inode: Box<dyn Fn(usize) -> Arc<dyn INode> + Send + Sync>,
+}
+
+impl PtmxLikeINode {
+    /// Make the device numbered `n` in `dir` by `make_inode(n)` on open
+    pub fn new(
+        dir: Arc<NumberedDir>,
+        make_inode: impl Fn(usize) -> Arc<dyn INode> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inode_id: DevFS::new_inode_id(),
+            dir,
+            make_inode: Box::new(make_inode),
+        }
+    }
+}
+
+impl INode for PtmxLikeINode {
+    /// Only the devices made on open are read
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Only the devices made on open are written
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: false,
+            write: false,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: self.inode_id,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            btime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::CharDevice,
+            mode: 0o666,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: make_rdev(5, 2),
+            flags: MetadataFlags::empty(),
+        })
+    }
+
+    fn open_hook(&self, _exclusive: bool) -> Result<OpenGuard> {
+        let (n, _) = self.dir.allocate(&*self.make_inode)?;
+        let dir = self.dir.clone();
+        Ok(OpenGuard::numbered(n, move || {
+            // only released here, so still there
+            let _ = dir.release(n);
+        }))
+    }
+
+    impl_inode!();
+}
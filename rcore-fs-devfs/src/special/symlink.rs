@@ -0,0 +1,63 @@
+use super::*;
+
+/// Symbolic link, e.g. `/dev/stdout -> /proc/self/fd/1`
+pub struct SymlinkINode {
+    inode_id: usize,
+    target: String,
+}
+
+impl SymlinkINode {
+    pub fn new(target: String) -> Self {
+        Self {
+            inode_id: DevFS::new_inode_id(),
+            target,
+        }
+    }
+}
+
+impl INode for SymlinkINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let target = self.target.as_bytes();
+        if offset >= target.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(target.len() - offset);
+        buf[..len].copy_from_slice(&target[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: false,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: self.inode_id,
+            size: self.target.len(),
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            btime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::SymLink,
+            mode: 0o777,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: MetadataFlags::empty(),
+        })
+    }
+
+    impl_inode!();
+}
@@ -36,7 +36,7 @@ impl INode for ZeroINode {
 
     fn metadata(&self) -> Result<Metadata> {
         Ok(Metadata {
-            dev: 1,
+            dev: 0,
             inode: self.inode_id,
             size: 0,
             blk_size: 0,
@@ -44,12 +44,14 @@ impl INode for ZeroINode {
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            btime: Timespec { sec: 0, nsec: 0 },
             type_: FileType::CharDevice,
             mode: 0o666,
             nlinks: 1,
             uid: 0,
             gid: 0,
             rdev: make_rdev(1, 5),
+            flags: MetadataFlags::empty(),
         })
     }
 
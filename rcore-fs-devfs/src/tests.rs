@@ -0,0 +1,351 @@
+use crate::*;
+use rcore_fs_mountfs::MountFS;
+use rcore_fs_ramfs::RamFS;
+
+#[test]
+fn symlink() {
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add_symlink("stdout", "/proc/self/fd/1")
+        .unwrap();
+    assert_eq!(
+        devfs.root().add_symlink("stdout", "/dev/null"),
+        Err(FsError::EntryExist)
+    );
+
+    let link = devfs.root_inode().find("stdout").unwrap();
+    let metadata = link.metadata().unwrap();
+    assert_eq!(metadata.type_, FileType::SymLink);
+    assert_eq!(metadata.size, "/proc/self/fd/1".len());
+    let mut buf = [0u8; 64];
+    let len = link.read_at(0, &mut buf).unwrap();
+    assert_eq!(&buf[..len], b"/proc/self/fd/1");
+    assert_eq!(link.read_at(6, &mut buf[..4]).unwrap(), 4);
+    assert_eq!(&buf[..4], b"self");
+    assert_eq!(link.write_at(0, b"x"), Err(FsError::NotSupported));
+    assert_eq!(link.resize(0), Err(FsError::NotSupported));
+
+    let (metadata, name) = devfs.root_inode().get_entry_with_metadata(2).unwrap();
+    assert_eq!(name, "stdout");
+    assert_eq!(metadata.type_, FileType::SymLink);
+}
+
+#[test]
+fn symlink_across_mount() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let dev = root.create("dev", FileType::Dir, 0o755).unwrap();
+    let proc = root.create("proc", FileType::Dir, 0o755).unwrap();
+
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add_symlink("stdout", "/proc/self/fd/1")
+        .unwrap();
+    dev.mount(devfs).unwrap();
+
+    // stub procfs
+    let procfs = RamFS::new();
+    let fd = procfs
+        .root_inode()
+        .create("self", FileType::Dir, 0o755)
+        .unwrap()
+        .create("fd", FileType::Dir, 0o755)
+        .unwrap();
+    let stdout = fd.create("1", FileType::File, 0o666).unwrap();
+    proc.mount(procfs).unwrap();
+
+    let root: Arc<dyn INode> = root;
+    let link = root.lookup("/dev/stdout").unwrap();
+    assert_eq!(link.metadata().unwrap().type_, FileType::SymLink);
+    let target = root.lookup_follow("/dev/stdout", 1).unwrap();
+    assert_eq!(
+        target.metadata().unwrap().inode,
+        stdout.metadata().unwrap().inode
+    );
+}
+
+#[test]
+fn exclusive_open() {
+    use rcore_fs::file::File;
+
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add_with_perm("tty", Arc::new(special::NullINode::new()), 0o620, None)
+        .unwrap();
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    root.create("dev", FileType::Dir, 0o777)
+        .unwrap()
+        .mount(devfs)
+        .unwrap();
+    let root: Arc<dyn INode> = root;
+    let tty = root.lookup("dev/tty").unwrap();
+    let open = |exclusive| File::open(tty.clone(), true, true, exclusive).map(|_| ());
+
+    // exclusive, then anything
+    let file = File::open(tty.clone(), true, true, true).unwrap();
+    assert_eq!(open(true), Err(FsError::Busy));
+    assert_eq!(open(false), Err(FsError::Busy));
+    drop(file);
+
+    // shared, then exclusive
+    let file1 = File::open(tty.clone(), true, false, false).unwrap();
+    let file2 = File::open(tty.clone(), false, true, false).unwrap();
+    assert_eq!(open(true), Err(FsError::Busy));
+    drop(file1);
+    assert_eq!(open(true), Err(FsError::Busy));
+    drop(file2);
+    assert_eq!(open(true), Ok(()));
+    assert_eq!(open(false), Ok(()));
+
+    // not tracked without the wrapper
+    let null = Arc::new(special::NullINode::new());
+    let _file = File::open(null.clone(), true, true, true).unwrap();
+    assert!(File::open(null, true, true, true).is_ok());
+}
+
+#[test]
+fn manifest_round_trip() {
+    let devfs = DevFS::new();
+    let root = devfs.root();
+    let null = || -> Arc<dyn INode> { Arc::new(special::NullINode::new()) };
+    let zero = || -> Arc<dyn INode> { Arc::new(special::ZeroINode::new()) };
+    let owned = |dev, mode, rdev| {
+        Arc::new(special::PermINode::new(dev, mode, Some(rdev)).with_owner(0, 5)) as Arc<dyn INode>
+    };
+    root.add_with_key("null", owned(null(), 0o666, make_rdev(1, 3)), "mem:null")
+        .unwrap();
+    root.add_with_perm("kmsg", null(), 0o600, None).unwrap();
+    root.add_symlink("stdout", "/proc/self/fd/1").unwrap();
+    let input = root.add_dir("input").unwrap();
+    input
+        .add_with_key("event0", owned(zero(), 0o640, make_rdev(13, 64)), "input:0")
+        .unwrap();
+    input
+        .add_with_key("event1", owned(zero(), 0o640, make_rdev(13, 65)), "input:1")
+        .unwrap();
+    input.add_dir("by-id").unwrap();
+
+    let manifest = devfs.export_manifest().unwrap();
+    let paths: Vec<&str> = manifest.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(
+        paths,
+        [
+            "input",
+            "input/by-id",
+            "input/event0",
+            "input/event1",
+            "kmsg",
+            "null",
+            "stdout"
+        ]
+    );
+    assert_eq!(manifest[0].type_, FileType::Dir);
+    assert_eq!(manifest[2].key, "input:0");
+    assert_eq!(manifest[2].mode, 0o640);
+    assert_eq!((manifest[2].uid, manifest[2].gid), (0, 5));
+    assert_eq!(manifest[2].rdev, make_rdev(13, 64));
+    assert_eq!(manifest[4].key, "");
+    assert_eq!(manifest[6].key, "/proc/self/fd/1");
+
+    let bytes = DevManifestEntry::encode(&manifest);
+    assert_eq!(DevManifestEntry::decode(&bytes).unwrap(), manifest);
+    for len in [0, 3, 8, bytes.len() - 1].iter() {
+        assert_eq!(
+            DevManifestEntry::decode(&bytes[..*len]),
+            Err(FsError::InvalidParam)
+        );
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(
+        DevManifestEntry::decode(&trailing),
+        Err(FsError::InvalidParam)
+    );
+
+    // "after a reboot", the driver of input:1 is gone
+    let devfs = DevFS::new();
+    devfs.root().add_dir("input").unwrap();
+    let resolver = |key: &str| match key {
+        "mem:null" | "" => Some(null()),
+        "input:0" => Some(zero()),
+        _ => None,
+    };
+    let unresolved = devfs.apply_manifest(&manifest, &resolver).unwrap();
+    assert_eq!(unresolved, ["input/event1"]);
+
+    let applied = devfs.export_manifest().unwrap();
+    let expected: Vec<_> = manifest
+        .iter()
+        .filter(|e| e.path != "input/event1")
+        .cloned()
+        .collect();
+    assert_eq!(applied, expected);
+
+    let root: Arc<dyn INode> = devfs.root_inode();
+    let event0 = root.lookup("input/event0").unwrap();
+    let metadata = event0.metadata().unwrap();
+    assert_eq!(metadata.mode, 0o640);
+    assert_eq!((metadata.uid, metadata.gid), (0, 5));
+    assert_eq!(metadata.rdev, make_rdev(13, 64));
+    let mut buf = [1u8; 4];
+    assert_eq!(event0.read_at(0, &mut buf).unwrap(), 4);
+    assert_eq!(buf, [0; 4]);
+    assert_eq!(
+        root.lookup("input/event1").err(),
+        Some(FsError::EntryNotFound)
+    );
+
+    // applying again finds the devices there
+    assert_eq!(
+        devfs.apply_manifest(&manifest, &resolver),
+        Err(FsError::EntryExist)
+    );
+}
+
+#[test]
+fn type_errors() {
+    let devfs = DevFS::new();
+    let root = devfs.root();
+    root.add("null", Arc::new(special::NullINode::new()))
+        .unwrap();
+    root.add_with_perm("tty", Arc::new(special::NullINode::new()), 0o620, None)
+        .unwrap();
+    root.add_symlink("stdout", "/proc/self/fd/1").unwrap();
+    root.add_dir("input")
+        .unwrap()
+        .add("zero", Arc::new(special::ZeroINode::new()))
+        .unwrap();
+    rcore_fs::conformance::check_type_errors(&devfs.root_inode());
+    // the same behind MountFS
+    let root: Arc<dyn INode> = MountFS::new(devfs).mountpoint_root_inode();
+    rcore_fs::conformance::check_type_errors(&root);
+}
+
+#[test]
+fn fs_of_inodes() {
+    let devfs = DevFS::new();
+    let null: Arc<dyn INode> = Arc::new(special::NullINode::new());
+    devfs.root().add("null", null.clone()).unwrap();
+    let dir = devfs.root_inode();
+    assert!(dir.fs().is_ok());
+    // a device is in no file system
+    assert_eq!(null.fs().err(), Some(FsError::NotSupported));
+    assert_eq!(
+        dir.find("null").unwrap().fs().err(),
+        Some(FsError::NotSupported)
+    );
+    assert!(null.fs_info().is_err());
+    drop(devfs);
+    assert_eq!(dir.fs().err(), Some(FsError::NotSupported));
+}
+
+#[test]
+fn io_contract() {
+    let devfs = DevFS::new();
+    let root = devfs.root();
+    root.add("null", Arc::new(special::NullINode::new()))
+        .unwrap();
+    root.add("zero", Arc::new(special::ZeroINode::new()))
+        .unwrap();
+    root.add_symlink("stdout", "/proc/self/fd/1").unwrap();
+    root.add_dir("input")
+        .unwrap()
+        .add_with_perm("zero", Arc::new(special::ZeroINode::new()), 0o600, None)
+        .unwrap();
+    rcore_fs::conformance::check_io(&devfs.root_inode());
+    // the same behind MountFS
+    let root: Arc<dyn INode> = MountFS::new(devfs).mountpoint_root_inode();
+    rcore_fs::conformance::check_io(&root);
+}
+
+#[test]
+fn find_or_create() {
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add_symlink("stdout", "/proc/self/fd/1")
+        .unwrap();
+    let root = devfs.root_inode();
+    let (link, created) = root
+        .find_or_create("stdout", FileType::SymLink, 0o777)
+        .unwrap();
+    assert!(!created);
+    assert_eq!(link.ino_key(), root.find("stdout").unwrap().ino_key());
+    assert_eq!(
+        root.find_or_create("stdout", FileType::File, 0o644).err(),
+        Some(FsError::EntryExist)
+    );
+    assert_eq!(
+        root.find_or_create("new", FileType::File, 0o644).err(),
+        Some(FsError::Unsupported)
+    );
+}
+
+#[test]
+fn numbered_dir() {
+    let devfs = DevFS::new();
+    let pts = devfs.root().add_numbered_dir("pts").unwrap();
+    let threads: Vec<_> = (0..100)
+        .map(|_| {
+            let pts = pts.clone();
+            std::thread::spawn(move || {
+                let make_inode = |_| -> Arc<dyn INode> {
+                    // let the others race for the number
+                    std::thread::yield_now();
+                    Arc::new(special::NullINode::new())
+                };
+                pts.allocate(&make_inode).unwrap().0
+            })
+        })
+        .collect();
+    let mut numbers: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    numbers.sort_unstable();
+    assert_eq!(numbers, (0..100).collect::<Vec<_>>());
+
+    // listed in numeric order, found by canonical names only
+    let dir = devfs.root_inode().find("pts").unwrap();
+    let names: Vec<_> = (0..100).map(|n| n.to_string()).collect();
+    assert_eq!(dir.list().unwrap()[2..], names[..]);
+    assert!(dir.find("42").is_ok());
+    assert_eq!(dir.find("042").err(), Some(FsError::EntryNotFound));
+    assert_eq!(dir.metadata().unwrap().size, 100);
+
+    // the lowest number free is used again
+    let null = |_| -> Arc<dyn INode> { Arc::new(special::NullINode::new()) };
+    pts.release(7).unwrap();
+    pts.release(9).unwrap();
+    assert_eq!(dir.find("7").err(), Some(FsError::EntryNotFound));
+    assert_eq!(pts.allocate(&null).unwrap().0, 7);
+    assert_eq!(pts.allocate(&null).unwrap().0, 9);
+    assert_eq!(pts.allocate(&null).unwrap().0, 100);
+    assert_eq!(pts.release(1000), Err(FsError::EntryNotFound));
+    assert_eq!(dir.unlink("7"), Err(FsError::Unsupported));
+}
+
+#[test]
+fn ptmx_like() {
+    let devfs = DevFS::new();
+    let pts = devfs.root().add_numbered_dir("pts").unwrap();
+    let ptmx = special::PtmxLikeINode::new(pts.clone(), |_| Arc::new(special::ZeroINode::new()));
+    devfs.root().add("ptmx", Arc::new(ptmx)).unwrap();
+    let root = devfs.root_inode();
+    let ptmx = root.lookup("ptmx").unwrap();
+
+    let first = ptmx.open_hook(false).unwrap();
+    let second = ptmx.open_hook(true).unwrap();
+    assert_eq!((first.number(), second.number()), (Some(0), Some(1)));
+    let pty = root.lookup("pts/1").unwrap();
+    assert_eq!(pty.metadata().unwrap().type_, FileType::CharDevice);
+    assert_eq!(root.lookup("pts/..").unwrap().ino_key(), root.ino_key());
+
+    // closed, the number is free again
+    drop(first);
+    assert_eq!(root.lookup("pts/0").err(), Some(FsError::EntryNotFound));
+    let third = ptmx.open_hook(false).unwrap();
+    assert_eq!(third.number(), Some(0));
+    assert_eq!(OpenGuard::default().number(), None);
+}
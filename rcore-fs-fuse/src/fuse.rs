@@ -1,25 +1,37 @@
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyStatfs, ReplyWrite, Request,
+    ReplyOpen, ReplyStatfs, ReplyWrite, Request,
 };
 use rcore_fs::vfs;
 use std::collections::btree_map::BTreeMap;
 use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::sync::Arc;
 use time::Timespec;
 
 const TTL: Timespec = Timespec { sec: 1, nsec: 0 }; // 1 second
 
+/// Entries read from the fs at a time by `readdir`
+const READDIR_WINDOW: usize = 128;
+
 pub struct VfsFuse {
     fs: Arc<dyn vfs::FileSystem>,
     inodes: BTreeMap<usize, Arc<dyn vfs::INode>>,
+    /// Where the last `readdir` of each open dir ended, by handle
+    dirs: BTreeMap<u64, vfs::DirCursor>,
+    next_fh: u64,
 }
 
 impl VfsFuse {
     pub fn new(fs: Arc<dyn vfs::FileSystem>) -> Self {
         let mut inodes = BTreeMap::new();
         inodes.insert(1, fs.root_inode());
-        VfsFuse { fs, inodes }
+        VfsFuse {
+            fs,
+            inodes,
+            dirs: BTreeMap::new(),
+            next_fh: 1,
+        }
     }
     fn trans_time(time: vfs::Timespec) -> Timespec {
         Timespec {
@@ -41,7 +53,7 @@ impl VfsFuse {
             atime: Self::trans_time(info.atime),
             mtime: Self::trans_time(info.mtime),
             ctime: Self::trans_time(info.ctime),
-            crtime: Timespec { sec: 0, nsec: 0 },
+            crtime: Self::trans_time(info.btime),
             kind: Self::trans_type(info.type_),
             perm: info.mode,
             nlink: info.nlinks as u32,
@@ -64,7 +76,7 @@ impl VfsFuse {
     }
     fn trans_error(err: vfs::FsError) -> i32 {
         use libc::*;
-        match err {
+        match err.root_cause() {
             vfs::FsError::NotSupported => ENOSYS,
             vfs::FsError::EntryNotFound => ENOENT,
             vfs::FsError::EntryExist => EEXIST,
@@ -77,9 +89,20 @@ impl VfsFuse {
             vfs::FsError::DirRemoved => ENOENT,
             vfs::FsError::DirNotEmpty => ENOTEMPTY,
             vfs::FsError::WrongFs => EINVAL,
+            vfs::FsError::ReadOnly => EROFS,
+            vfs::FsError::PermError => EPERM,
+            vfs::FsError::TooManyLinks => EMLINK,
+            vfs::FsError::Unsupported => EOPNOTSUPP,
+            vfs::FsError::PartialSync(_) => EIO,
+            vfs::FsError::Corrupted => EIO,
+            vfs::FsError::InvalidName => EILSEQ,
             _ => EINVAL,
         }
     }
+    /// A name for the `&str` API of the fs, `InvalidName` if not UTF-8
+    fn utf8(name: &OsStr) -> vfs::Result<&str> {
+        name.to_str().ok_or(vfs::FsError::InvalidName)
+    }
     fn get_inode(&self, ino: u64) -> vfs::Result<&Arc<dyn vfs::INode>> {
         self.inodes
             .get(&(ino as usize))
@@ -108,7 +131,7 @@ impl Filesystem for VfsFuse {
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let inode = try_vfs!(reply, self.get_inode(parent));
-        let target = try_vfs!(reply, inode.lookup(name.to_str().unwrap()));
+        let target = try_vfs!(reply, inode.find_bytes(name.as_bytes()));
         let info = try_vfs!(reply, target.metadata());
         self.inodes.insert(info.inode, target);
         let attr = Self::trans_attr(info);
@@ -173,7 +196,7 @@ impl Filesystem for VfsFuse {
         _rdev: u32,
         reply: ReplyEntry,
     ) {
-        let name = name.to_str().unwrap();
+        let name = try_vfs!(reply, Self::utf8(name));
         let inode = try_vfs!(reply, self.get_inode(parent));
         let target = try_vfs!(reply, inode.create(name, vfs::FileType::File, mode));
         let info = try_vfs!(reply, target.metadata());
@@ -183,7 +206,7 @@ impl Filesystem for VfsFuse {
     }
 
     fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
-        let name = name.to_str().unwrap();
+        let name = try_vfs!(reply, Self::utf8(name));
         let inode = try_vfs!(reply, self.get_inode(parent));
         let target = try_vfs!(reply, inode.create(name, vfs::FileType::Dir, mode));
         let info = try_vfs!(reply, target.metadata());
@@ -193,9 +216,8 @@ impl Filesystem for VfsFuse {
     }
 
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let name = name.to_str().unwrap();
         let parent = try_vfs!(reply, self.get_inode(parent));
-        try_vfs!(reply, parent.unlink(name));
+        try_vfs!(reply, parent.unlink_bytes(name.as_bytes()));
         reply.ok();
     }
 
@@ -212,8 +234,8 @@ impl Filesystem for VfsFuse {
         newname: &OsStr,
         reply: ReplyEmpty,
     ) {
-        let name = name.to_str().unwrap();
-        let newname = newname.to_str().unwrap();
+        let name = try_vfs!(reply, Self::utf8(name));
+        let newname = try_vfs!(reply, Self::utf8(newname));
         let parent = try_vfs!(reply, self.get_inode(parent));
         let newparent = try_vfs!(reply, self.get_inode(newparent));
         try_vfs!(reply, parent.move_(name, newparent, newname));
@@ -228,7 +250,7 @@ impl Filesystem for VfsFuse {
         newname: &OsStr,
         reply: ReplyEntry,
     ) {
-        let newname = newname.to_str().unwrap();
+        let newname = try_vfs!(reply, Self::utf8(newname));
         let inode = try_vfs!(reply, self.get_inode(ino));
         let newparent = try_vfs!(reply, self.get_inode(newparent));
         try_vfs!(reply, newparent.link(newname, inode));
@@ -284,34 +306,69 @@ impl Filesystem for VfsFuse {
         reply.ok();
     }
 
+    fn opendir(&mut self, _req: &Request, _ino: u64, flags: u32, reply: ReplyOpen) {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.dirs.insert(fh, vfs::DirCursor::default());
+        reply.opened(fh, flags);
+    }
+
+    /// Entries from `offset` on, by the positions of `INode::next_entries()`,
+    /// a window at a time from where the last call on `fh` ended. If the dir
+    /// changes meanwhile, the listing goes on from there anyway, which may
+    /// skip or repeat entries as POSIX allows.
     fn readdir(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let inode = try_vfs!(reply, self.get_inode(ino));
-        for i in offset as usize.. {
-            let name = match inode.get_entry(i) {
-                Ok(name) => name,
-                Err(vfs::FsError::EntryNotFound) => break,
-                e @ _ => try_vfs!(reply, e),
+        let inode = try_vfs!(reply, self.get_inode(ino)).clone();
+        let mut cursor = match self.dirs.get(&fh) {
+            Some(&cursor) if cursor.next == offset as usize => cursor,
+            _ => vfs::DirCursor::at(offset as usize),
+        };
+        let mut slots = vec![vfs::DirEntrySlot::default(); READDIR_WINDOW];
+        'fill: loop {
+            let from = cursor.next;
+            let filled = match inode.next_entries(&mut cursor, &mut slots) {
+                Err(err) if *err.root_cause() == vfs::FsError::InvalidParam => {
+                    cursor = vfs::DirCursor::at(from);
+                    continue;
+                }
+                result => try_vfs!(reply, result),
             };
-            let inode = try_vfs!(reply, inode.find(name.as_str()));
-            let info = try_vfs!(reply, inode.metadata());
-            let kind = Self::trans_type(info.type_);
-            let full = reply.add(info.inode as u64, i as i64 + 1, kind, name);
-            if full {
+            let mut position = from;
+            for slot in &slots[..filled] {
+                let kind = Self::trans_type(slot.type_);
+                let name = OsStr::from_bytes(slot.name_bytes());
+                if reply.add(slot.inode as u64, slot.next as i64, kind, name) {
+                    cursor.next = position;
+                    break 'fill;
+                }
+                position = slot.next;
+            }
+            if filled < slots.len() {
                 break;
             }
         }
+        self.dirs.insert(fh, cursor);
+        reply.ok();
+    }
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        self.dirs.remove(&fh);
         reply.ok();
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        let info = self.fs.info();
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        // of the fs mounted there, if any, see `INode::fs_info()`
+        let info = match self.get_inode(ino).and_then(|inode| inode.fs_info()) {
+            Ok(info) => info,
+            Err(_) => self.fs.info(),
+        };
         reply.statfs(
             info.blocks as u64,
             info.bfree as u64,
@@ -1,10 +1,16 @@
 use std::fs::OpenOptions;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use structopt::StructOpt;
 
 use rcore_fs::dev::std_impl::StdTimeProvider;
+use rcore_fs::dev::{DevError, Device, Result as DevResult, TimeProvider};
+use rcore_fs::metrics;
+use rcore_fs::sync_facade;
 use rcore_fs::vfs::FileSystem;
 #[cfg(feature = "use_fuse")]
 use rcore_fs_fuse::fuse::VfsFuse;
@@ -21,17 +27,58 @@ struct Opt {
     #[structopt(subcommand)]
     cmd: Cmd,
 
-    /// Image file
+    /// Image file, not used by `convert`
     #[structopt(parse(from_os_str))]
-    image: PathBuf,
+    image: Option<PathBuf>,
 
-    /// Target directory
+    /// Target directory, not used by `convert`
     #[structopt(parse(from_os_str))]
-    dir: PathBuf,
+    dir: Option<PathBuf>,
 
     /// File system: [sfs | sefs | ramfs]
     #[structopt(short = "f", long = "fs", default_value = "sfs")]
     fs: String,
+
+    /// Volume label of the new image (sfs only)
+    #[structopt(long = "label")]
+    label: Option<String>,
+
+    /// Volume UUID of the new image (sfs only), random if not given
+    #[structopt(long = "uuid", parse(try_from_str = parse_uuid))]
+    uuid: Option<[u8; 16]>,
+
+    /// Compress the files of the new image whose blocks it shrinks by at
+    /// least this percent (sfs zip only). The image is then sized to fit
+    /// and gets a random UUID.
+    #[structopt(long = "compress")]
+    compress: Option<usize>,
+
+    /// Rewrite this file with the metrics of the fs in the Prometheus text
+    /// format, every --metrics-interval seconds and at exit
+    #[structopt(long = "metrics-file", parse(from_os_str))]
+    metrics_file: Option<PathBuf>,
+
+    /// Seconds between two rewrites of --metrics-file
+    #[structopt(long = "metrics-interval", default_value = "10")]
+    metrics_interval: u64,
+
+    /// Print the metrics of the fs to stdout on SIGUSR1
+    #[structopt(long = "metrics-stdout-on-signal")]
+    metrics_on_signal: bool,
+}
+
+/// Parse UUID like `123e4567-e89b-12d3-a456-426614174000`
+fn parse_uuid(s: &str) -> Result<[u8; 16], String> {
+    let hex: Vec<u8> = s.bytes().filter(|&b| b != b'-').collect();
+    if hex.len() != 32 {
+        return Err(format!("invalid uuid: {}", s));
+    }
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        let digits = std::str::from_utf8(&hex[i * 2..i * 2 + 2]).unwrap_or("");
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| format!("invalid uuid: {}", s))?;
+    }
+    Ok(uuid)
 }
 
 #[derive(Debug, StructOpt)]
@@ -49,10 +96,190 @@ enum Cmd {
     #[structopt(name = "mount")]
     Mount,
 
+    /// Copy all files of the sfs image <from> to a new sfs image <to>
+    #[structopt(name = "convert")]
+    Convert {
+        /// Source image
+        #[structopt(long = "from", parse(from_os_str))]
+        from: PathBuf,
+
+        /// New image
+        #[structopt(long = "to", parse(from_os_str))]
+        to: PathBuf,
+
+        /// Block size of the new image, only 4096 is supported for now
+        #[structopt(long = "block-size", default_value = "4096")]
+        block_size: usize,
+    },
+
+    /// Print the differences from the sfs image <a> to <b>, comparing the
+    /// content of files by hash. Exits with 1 if there are any.
+    #[structopt(name = "sfsdiff")]
+    Diff {
+        /// Old image
+        #[structopt(parse(from_os_str))]
+        a: PathBuf,
+
+        /// New image
+        #[structopt(parse(from_os_str))]
+        b: PathBuf,
+
+        /// Print as JSON
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
     #[structopt(name = "git-version")]
     GitVersion,
 }
 
+/// Compare the images `a` and `b`, print the differences, exit with 1 if
+/// there are any
+fn diff(a: &Path, b: &Path, json: bool) {
+    let open = |path: &Path| -> Arc<dyn Device> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .expect("failed to open image");
+        Arc::new(Mutex::new(file))
+    };
+    let opts = sfs::DiffOpts { content_hash: true };
+    let diff = sync_facade::poll_once(pin!(sfs::diff_images(open(a), open(b), opts)))
+        .expect("failed to diff images");
+    match json {
+        true => println!("{}", diff.to_json()),
+        false => print!("{}", diff),
+    }
+    if !diff.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Export `from` and import the stream into a new image `to`
+fn convert(from: &Path, to: &Path, block_size: usize, opt: &Opt) {
+    if block_size != sfs::BLKSIZE {
+        panic!("unsupported block size {}", block_size);
+    }
+    let file = OpenOptions::new()
+        .read(true)
+        .open(from)
+        .expect("failed to open source image");
+    let source =
+        sfs::SimpleFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open sfs");
+    let mut stream = Vec::new();
+    let exported = sfs::export_stream(&source, &mut stream).expect("failed to export sfs");
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)
+        .expect("failed to create image");
+    const MAX_SPACE: usize = 0x1000 * 0x1000 * 1024; // 1G
+    let uuid = opt.uuid.unwrap_or_else(|| source.uuid());
+    let target =
+        sfs::SimpleFileSystem::create_with_uuid(Arc::new(Mutex::new(file)), MAX_SPACE, uuid)
+            .expect("failed to create sfs");
+    let label = opt.label.clone().unwrap_or_else(|| source.label());
+    target.set_label(&label).expect("invalid label");
+    let imported = sfs::import_stream(&target, &mut &stream[..]).expect("failed to import sfs");
+    assert_eq!(exported, imported);
+}
+
+/// Zip <dir> into a RamFS, then pack it into a new sfs <image> with files
+/// compressed
+fn zip_compressed(min_savings: usize, opt: &Opt) {
+    if opt.fs != "sfs" {
+        panic!("--compress is only supported by sfs");
+    }
+    let image = opt.image.as_ref().expect("<image> is required");
+    let dir = opt.dir.as_ref().expect("<dir> is required");
+    let ramfs = ramfs::RamFS::new();
+    zip_dir(dir, ramfs.root_inode()).expect("failed to zip fs");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image)
+        .expect("failed to create image");
+    let opts = sfs::PackOptions {
+        compress: Some(min_savings),
+        ..Default::default()
+    };
+    let sfs = sfs::pack_subtree(&ramfs.root_inode(), Arc::new(Mutex::new(file)), opts)
+        .expect("failed to pack sfs");
+    if let Some(label) = &opt.label {
+        sfs.set_label(label).expect("invalid label");
+    }
+    sfs.sync().expect("failed to sync sfs");
+}
+
+/// Image file opened without write access, so that SFS opens it read-only
+/// instead of failing to mark it in use
+struct ReadOnlyFile(Mutex<std::fs::File>);
+
+impl Device for ReadOnlyFile {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        self.0.read_at(offset, buf)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> DevResult<usize> {
+        Err(DevError::WriteProtected)
+    }
+    fn sync(&self) -> DevResult<()> {
+        Ok(())
+    }
+    fn is_read_only(&self) -> bool {
+        true
+    }
+}
+
+/// Write the metrics to `path` at once, by renaming a temporary file
+fn write_metrics(path: &Path) {
+    let tmp = path.with_extension("tmp");
+    let written =
+        std::fs::write(&tmp, metrics::render()).and_then(|()| std::fs::rename(&tmp, path));
+    if let Err(err) = written {
+        log::warn!("failed to write metrics to {}: {}", path.display(), err);
+    }
+}
+
+/// Set by SIGUSR1, see `--metrics-stdout-on-signal`
+static METRICS_WANTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_metrics_signal(_: libc::c_int) {
+    METRICS_WANTED.store(true, Ordering::SeqCst);
+}
+
+/// Start the thread writing metrics as asked by `opt`
+fn start_metrics(opt: &Opt) {
+    if opt.metrics_file.is_none() && !opt.metrics_on_signal {
+        return;
+    }
+    #[cfg(unix)]
+    if opt.metrics_on_signal {
+        let handler = on_metrics_signal as extern "C" fn(libc::c_int);
+        unsafe { libc::signal(libc::SIGUSR1, handler as libc::sighandler_t) };
+    }
+    let file = opt.metrics_file.clone();
+    let interval = Duration::from_secs(opt.metrics_interval.max(1));
+    std::thread::spawn(move || {
+        let mut next = Instant::now();
+        loop {
+            if METRICS_WANTED.swap(false, Ordering::SeqCst) {
+                print!("{}", metrics::render());
+            }
+            if let Some(file) = file.as_ref().filter(|_| Instant::now() >= next) {
+                write_metrics(file);
+                next += interval;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    });
+}
+
 fn main() {
     env_logger::init();
     let opt = Opt::from_args();
@@ -60,14 +287,37 @@ fn main() {
     // open or create
     let create = match opt.cmd {
         #[cfg(feature = "use_fuse")]
-        Cmd::Mount => !opt.image.is_dir() && !opt.image.is_file(),
-        Cmd::Zip => true,
+        Cmd::Mount => {
+            let image = opt.image.as_ref().expect("<image> is required");
+            !image.is_dir() && !image.is_file()
+        }
+        Cmd::Zip => {
+            if let Some(min_savings) = opt.compress {
+                zip_compressed(min_savings, &opt);
+                return;
+            }
+            true
+        }
         Cmd::Unzip => false,
+        Cmd::Convert {
+            ref from,
+            ref to,
+            block_size,
+        } => {
+            convert(from, to, block_size, &opt);
+            return;
+        }
+        Cmd::Diff { ref a, ref b, json } => {
+            diff(a, b, json);
+            return;
+        }
         Cmd::GitVersion => {
             println!("{}", git_version!());
             return;
         }
     };
+    let image = opt.image.as_ref().expect("<image> is required");
+    let dir = opt.dir.as_ref().expect("<dir> is required");
 
     let fs: Arc<dyn FileSystem> = match opt.fs.as_str() {
         "sfs" => {
@@ -76,19 +326,38 @@ fn main() {
                 .write(create)
                 .create(create)
                 .truncate(create)
-                .open(&opt.image)
+                .open(image)
                 .expect("failed to open image");
             let device = Mutex::new(file);
             const MAX_SPACE: usize = 0x1000 * 0x1000 * 1024; // 1G
-            match create {
-                true => sfs::SimpleFileSystem::create(Arc::new(device), MAX_SPACE)
-                    .expect("failed to create sfs"),
-                false => sfs::SimpleFileSystem::open(Arc::new(device)).expect("failed to open sfs"),
-            }
+            let sfs = match create {
+                true => {
+                    let device = Arc::new(device);
+                    let sfs = match opt.uuid {
+                        Some(uuid) => {
+                            sfs::SimpleFileSystem::create_with_uuid(device, MAX_SPACE, uuid)
+                        }
+                        None => {
+                            let now = StdTimeProvider.current_time();
+                            let seed = ((now.sec as u64) << 32) ^ now.nsec as u64;
+                            sfs::SimpleFileSystem::create_with_seed(device, MAX_SPACE, seed)
+                        }
+                    }
+                    .expect("failed to create sfs");
+                    if let Some(label) = &opt.label {
+                        sfs.set_label(label).expect("invalid label");
+                    }
+                    sfs
+                }
+                false => sfs::SimpleFileSystem::open(Arc::new(ReadOnlyFile(device)))
+                    .expect("failed to open sfs"),
+            };
+            metrics::register(&image.display().to_string(), sfs.clone());
+            sfs
         }
         "sefs" => {
-            std::fs::create_dir_all(&opt.image).unwrap();
-            let device = sefs::dev::StdStorage::new(&opt.image);
+            std::fs::create_dir_all(image).unwrap();
+            let device = sefs::dev::StdStorage::new(image);
             match create {
                 true => sefs::SEFS::create(Box::new(device), &StdTimeProvider)
                     .expect("failed to create sefs"),
@@ -99,18 +368,23 @@ fn main() {
         "ramfs" => ramfs::RamFS::new(),
         _ => panic!("unsupported file system"),
     };
+    start_metrics(&opt);
     match opt.cmd {
         #[cfg(feature = "use_fuse")]
         Cmd::Mount => {
-            fuse::mount(VfsFuse::new(fs), &opt.dir, &[]).expect("failed to mount fs");
+            fuse::mount(VfsFuse::new(fs.clone()), dir, &[]).expect("failed to mount fs");
         }
         Cmd::Zip => {
-            zip_dir(&opt.dir, fs.root_inode()).expect("failed to zip fs");
+            zip_dir(dir, fs.root_inode()).expect("failed to zip fs");
         }
         Cmd::Unzip => {
-            std::fs::create_dir(&opt.dir).expect("failed to create dir");
-            unzip_dir(&opt.dir, fs.root_inode()).expect("failed to unzip fs");
+            std::fs::create_dir(dir).expect("failed to create dir");
+            unzip_dir(dir, fs.root_inode()).expect("failed to unzip fs");
         }
-        Cmd::GitVersion => unreachable!(),
+        Cmd::Convert { .. } | Cmd::Diff { .. } | Cmd::GitVersion => unreachable!(),
+    }
+    fs.prepare_unmount().expect("failed to unmount fs");
+    if let Some(file) = &opt.metrics_file {
+        write_metrics(file);
     }
 }
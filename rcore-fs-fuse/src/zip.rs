@@ -1,49 +1,72 @@
 use std::error::Error;
 use std::fs;
 use std::io::{Read, Write};
-use std::mem::MaybeUninit;
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::str;
 use std::sync::Arc;
 
-use rcore_fs::vfs::{FileType, INode};
+use rcore_fs::vfs::{CreateSpec, FileType, INode};
 
 const DEFAULT_MODE: u32 = 0o664;
 const BUF_SIZE: usize = 0x1000;
 
 pub fn zip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error>> {
-    let dir = fs::read_dir(path)?;
-    for entry in dir {
+    // create all children of the directory at once
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path)? {
         let entry = entry?;
-        let name_ = entry.file_name();
-        let name = name_.to_str().unwrap();
+        let name = entry.file_name().to_str().unwrap().to_string();
         let type_ = entry.file_type()?;
-        if type_.is_file() {
-            let inode = inode.create(name, FileType::File, DEFAULT_MODE)?;
-            let mut file = fs::File::open(entry.path())?;
-            inode.resize(file.metadata()?.len() as usize)?;
-            let mut buf: [u8; BUF_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
-            let mut offset = 0usize;
-            let mut len = BUF_SIZE;
-            while len == BUF_SIZE {
-                len = file.read(&mut buf)?;
-                inode.write_at(offset, &buf[..len])?;
-                offset += len;
-            }
+        let type_ = if type_.is_file() {
+            FileType::File
         } else if type_.is_dir() {
-            let inode = inode.create(name, FileType::Dir, DEFAULT_MODE)?;
-            zip_dir(entry.path().as_path(), inode)?;
+            FileType::Dir
         } else if type_.is_symlink() {
-            let target = fs::read_link(entry.path())?;
-            let inode = inode.create(name, FileType::SymLink, DEFAULT_MODE)?;
-            #[cfg(unix)]
-            let data = target.as_os_str().as_bytes();
-            #[cfg(windows)]
-            let data = target.to_str().unwrap().as_bytes();
-            inode.resize(data.len())?;
-            inode.write_at(0, data)?;
+            FileType::SymLink
+        } else {
+            continue;
+        };
+        entries.push((name, type_, entry.path()));
+    }
+    let specs: Vec<_> = entries
+        .iter()
+        .map(|(name, type_, _)| CreateSpec {
+            name,
+            type_: *type_,
+            mode: DEFAULT_MODE,
+            data: 0,
+        })
+        .collect();
+    let inodes = inode.create_batch(&specs)?;
+
+    for ((_, type_, path), inode) in entries.into_iter().zip(inodes) {
+        match type_ {
+            FileType::File => {
+                let mut file = fs::File::open(&path)?;
+                inode.resize(file.metadata()?.len() as usize)?;
+                let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
+                let mut offset = 0usize;
+                let mut len = BUF_SIZE;
+                while len == BUF_SIZE {
+                    len = file.read(&mut buf)?;
+                    inode.write_at(offset, &buf[..len])?;
+                    offset += len;
+                }
+            }
+            FileType::Dir => {
+                zip_dir(path.as_path(), inode)?;
+            }
+            _ => {
+                let target = fs::read_link(&path)?;
+                #[cfg(unix)]
+                let data = target.as_os_str().as_bytes();
+                #[cfg(windows)]
+                let data = target.to_str().unwrap().as_bytes();
+                inode.resize(data.len())?;
+                inode.write_at(0, data)?;
+            }
         }
     }
     Ok(())
@@ -59,7 +82,7 @@ pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error
         match info.type_ {
             FileType::File => {
                 let mut file = fs::File::create(&path)?;
-                let mut buf: [u8; BUF_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
+                let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
                 let mut offset = 0usize;
                 let mut len = BUF_SIZE;
                 while len == BUF_SIZE {
@@ -73,7 +96,7 @@ pub fn unzip_dir(path: &Path, inode: Arc<dyn INode>) -> Result<(), Box<dyn Error
                 unzip_dir(path.as_path(), inode)?;
             }
             FileType::SymLink => {
-                let mut buf: [u8; BUF_SIZE] = unsafe { MaybeUninit::uninit().assume_init() };
+                let mut buf: [u8; BUF_SIZE] = [0; BUF_SIZE];
                 let len = inode.read_at(0, buf.as_mut())?;
                 #[cfg(unix)]
                 std::os::unix::fs::symlink(str::from_utf8(&buf[..len]).unwrap(), path)?;
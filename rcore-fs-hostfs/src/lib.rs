@@ -1,5 +1,3 @@
-#![feature(get_mut_unchecked)]
-
 use core::any::Any;
 use rcore_fs::vfs::*;
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -15,6 +13,7 @@ extern crate log;
 pub struct HostFS {
     path: PathBuf,
     self_ref: Weak<HostFS>,
+    instance_id: u64,
 }
 
 /// INode for `HostFS`
@@ -41,6 +40,10 @@ impl FileSystem for HostFS {
     fn info(&self) -> FsInfo {
         unimplemented!()
     }
+
+    fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
 }
 
 impl HostFS {
@@ -49,6 +52,7 @@ impl HostFS {
         HostFS {
             path: path.as_ref().to_path_buf(),
             self_ref: Weak::default(),
+            instance_id: new_instance_id(),
         }
         .wrap()
     }
@@ -57,11 +61,13 @@ impl HostFS {
     /// Used in constructors
     fn wrap(self) -> Arc<Self> {
         // Create an Arc, make a Weak from it, then put it into the struct.
-        let mut fs = Arc::new(self);
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
         unsafe {
-            Arc::get_mut_unchecked(&mut fs).self_ref = Arc::downgrade(&fs);
+            (*ptr).self_ref = weak;
         }
-        fs
+        unsafe { Arc::from_raw(ptr) }
     }
 }
 
@@ -197,8 +203,17 @@ impl INode for HNode {
         Err(FsError::NotSupported)
     }
 
-    fn fs(&self) -> Arc<dyn FileSystem> {
-        self.fs.clone()
+    fn fs(&self) -> Result<Arc<dyn FileSystem>> {
+        Ok(self.fs.clone())
+    }
+
+    /// An HNode is made on each lookup, so use the inode number on the
+    /// host, 0 if it can not be read
+    fn ino_key(&self) -> InodeKey {
+        InodeKey {
+            fs: self.fs.instance_id,
+            inode: self.metadata().map_or(0, |metadata| metadata.inode),
+        }
     }
 
     fn as_any_ref(&self) -> &dyn Any {
@@ -0,0 +1,940 @@
+//! A log-structured file system for flash-like devices
+//!
+//! Every change appends a record to the log, nothing on device is updated in
+//! place except the header of a segment being reused. The index (metadata,
+//! directory entries and the extents of file content) is kept in memory.
+//! `sync()` writes a checkpoint of the whole index at the start of a fresh
+//! segment, so that `open()` only replays the records after it. The space of
+//! overwritten data is reclaimed by `gc()`.
+//!
+//! Hard links are not supported, `link()` returns `Unsupported`.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+extern crate alloc;
+#[macro_use]
+extern crate log;
+
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec,
+    vec::Vec,
+};
+use core::any::Any;
+use spin::Mutex;
+
+use rcore_fs::dev::Device;
+use rcore_fs::vfs::{self, FileSystem, FileType, FsError, INode, Metadata, PollStatus};
+
+pub use self::structs::*;
+
+mod structs;
+#[cfg(test)]
+mod tests;
+
+/// Free segments that only `sync()` and `gc()` can take
+const RESERVED_SEGMENTS: usize = 2;
+
+trait DeviceExt: Device {
+    fn read_exact(&self, pos: LogPos, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.read_at(pos as usize, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => Err(err.into()),
+        }
+    }
+    fn write_all(&self, pos: LogPos, buf: &[u8]) -> vfs::Result<()> {
+        match self.write_at(pos as usize, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!("logfs: cannot write {} bytes at {:#x}", buf.len(), pos);
+                Err(err.into())
+            }
+        }
+    }
+}
+
+impl DeviceExt for dyn Device {}
+
+/// Log-structured file system
+pub struct LogFS {
+    /// on-disk superblock
+    super_block: SuperBlock,
+    /// index and log position, all operations hold it
+    state: Mutex<State>,
+    /// device
+    device: Arc<dyn Device>,
+    /// Pointer to self, used by INodes
+    self_ptr: Weak<LogFS>,
+    /// see `FileSystem::instance_id()`
+    instance_id: u64,
+}
+
+/// In-memory index, and where to append the log
+struct State {
+    next_id: INodeId,
+    inodes: BTreeMap<INodeId, InodeImage>,
+    /// sequence number of each segment in use, `None` if it is free
+    segments: Vec<Option<u64>>,
+    /// segment being appended
+    head: usize,
+    /// where to append the next record
+    head_pos: LogPos,
+    next_seq: u64,
+    /// segments older than it are free, since the last checkpoint
+    tail_seq: u64,
+    /// records are appended since the last checkpoint
+    dirty: bool,
+}
+
+impl State {
+    fn get(&self, id: INodeId) -> vfs::Result<&InodeImage> {
+        self.inodes.get(&id).ok_or(FsError::EntryNotFound)
+    }
+    fn get_dir(&self, id: INodeId) -> vfs::Result<&InodeImage> {
+        let dir = self.get(id)?;
+        if dir.meta.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(dir)
+    }
+    fn free_segments(&self) -> usize {
+        self.segments.iter().filter(|seq| seq.is_none()).count()
+    }
+    fn head_seq(&self) -> u64 {
+        self.segments[self.head].unwrap()
+    }
+    /// Apply `record` at `pos` to the index.
+    /// Return `None` if it does not match the index.
+    fn apply(&mut self, pos: LogPos, record: &Record) -> Option<()> {
+        match record {
+            Record::Create {
+                dir,
+                name,
+                id,
+                type_,
+                mode,
+                rdev,
+            } => {
+                let parent = if *type_ == FileType::Dir { *dir } else { 0 };
+                self.inodes.get_mut(dir)?.entries.insert(name.clone(), *id);
+                let image = InodeImage {
+                    meta: InodeMeta::new(*type_, *mode, *rdev, parent),
+                    entries: BTreeMap::new(),
+                    extents: BTreeMap::new(),
+                };
+                self.inodes.insert(*id, image);
+                self.next_id = self.next_id.max(id + 1);
+            }
+            Record::Unlink { dir, name } => {
+                let child = self.inodes.get_mut(dir)?.entries.remove(name)?;
+                let meta = &mut self.inodes.get_mut(&child)?.meta;
+                meta.nlinks -= 1;
+                if meta.nlinks == 0 {
+                    self.inodes.remove(&child);
+                }
+            }
+            Record::Move {
+                dir,
+                name,
+                target,
+                new_name,
+            } => {
+                let child = self.inodes.get_mut(dir)?.entries.remove(name)?;
+                self.inodes
+                    .get_mut(target)?
+                    .entries
+                    .insert(new_name.clone(), child);
+                let meta = &mut self.inodes.get_mut(&child)?.meta;
+                if meta.type_ == FileType::Dir {
+                    meta.parent = *target;
+                }
+            }
+            Record::Write {
+                id,
+                offset,
+                size,
+                len,
+            } => {
+                let image = self.inodes.get_mut(id)?;
+                let pos = pos + (RECORD_HEADER_SIZE + WRITE_FIELDS_SIZE) as LogPos;
+                insert_extent(&mut image.extents, *offset, Extent { len: *len, pos });
+                image.meta.size = *size;
+            }
+            Record::SetAttr { id, attr } => {
+                let image = self.inodes.get_mut(id)?;
+                if attr.size < image.meta.size {
+                    truncate_extents(&mut image.extents, attr.size);
+                }
+                let meta = &mut image.meta;
+                meta.size = attr.size;
+                meta.mode = attr.mode;
+                meta.uid = attr.uid;
+                meta.gid = attr.gid;
+                meta.atime = attr.atime;
+                meta.mtime = attr.mtime;
+                meta.ctime = attr.ctime;
+            }
+            // loaded by `open()`
+            Record::Checkpoint { .. } => {}
+        }
+        Some(())
+    }
+}
+
+/// Put `extent` at `offset`, cutting the extents it overlaps
+fn insert_extent(extents: &mut BTreeMap<u64, Extent>, offset: u64, extent: Extent) {
+    if extent.len == 0 {
+        return;
+    }
+    let end = offset + extent.len;
+    let overlapped: Vec<u64> = extents
+        .range(..end)
+        .rev()
+        .take_while(|(&start, old)| start + old.len > offset)
+        .map(|(&start, _)| start)
+        .collect();
+    for start in overlapped {
+        let old = extents.remove(&start).unwrap();
+        if start < offset {
+            let len = offset - start;
+            extents.insert(start, Extent { len, pos: old.pos });
+        }
+        let old_end = start + old.len;
+        if old_end > end {
+            let pos = old.pos + (end - start);
+            extents.insert(
+                end,
+                Extent {
+                    len: old_end - end,
+                    pos,
+                },
+            );
+        }
+    }
+    extents.insert(offset, extent);
+}
+
+/// Drop content beyond `size`
+fn truncate_extents(extents: &mut BTreeMap<u64, Extent>, size: u64) {
+    let beyond: Vec<u64> = extents
+        .iter()
+        .rev()
+        .take_while(|(&start, extent)| start + extent.len > size)
+        .map(|(&start, _)| start)
+        .collect();
+    for start in beyond {
+        let extent = extents.remove(&start).unwrap();
+        if start < size {
+            let len = size - start;
+            extents.insert(
+                start,
+                Extent {
+                    len,
+                    pos: extent.pos,
+                },
+            );
+        }
+    }
+}
+
+impl LogFS {
+    /// Load LogFS from device
+    pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        let mut buf = vec![0u8; BLKSIZE];
+        device.read_exact(0, &mut buf)?;
+        let super_block = SuperBlock::decode(&buf, device.size()).ok_or(FsError::WrongFs)?;
+        let segment_size = super_block.segment_size as usize;
+
+        // valid segments in order of age
+        let mut order = Vec::new();
+        for id in 0..super_block.segments as usize {
+            let mut header = [0u8; SEGMENT_HEADER_SIZE];
+            device.read_exact(super_block.segment_pos(id), &mut header)?;
+            if let Some(header) = SegmentHeader::decode(&header) {
+                order.push((header.seq, id));
+            }
+        }
+        order.sort_unstable();
+
+        let mut fs = LogFS {
+            state: Mutex::new(State {
+                next_id: ROOT_ID + 1,
+                inodes: BTreeMap::new(),
+                segments: vec![None; super_block.segments as usize],
+                head: 0,
+                head_pos: 0,
+                next_seq: order.last().map_or(1, |&(seq, _)| seq + 1),
+                tail_seq: 0,
+                dirty: false,
+            }),
+            super_block,
+            device,
+            self_ptr: Weak::default(),
+            instance_id: vfs::new_instance_id(),
+        };
+
+        // start from the latest checkpoint
+        let mut checkpoint = None;
+        for &(seq, id) in order.iter().rev() {
+            let pos = fs.super_block.segment_pos(id) + SEGMENT_HEADER_SIZE as LogPos;
+            if let Some((record @ Record::Checkpoint { .. }, len)) = fs.read_record(pos, seq)? {
+                checkpoint = Some((seq, pos + len as LogPos, record));
+                break;
+            }
+        }
+        let (checkpoint_seq, mut pos, record) = checkpoint.ok_or(FsError::WrongFs)?;
+        let state = fs.state.get_mut();
+        if let Record::Checkpoint {
+            next_id,
+            tail_seq,
+            inodes,
+        } = record
+        {
+            state.next_id = next_id;
+            state.tail_seq = tail_seq;
+            state.inodes = inodes;
+        }
+        let tail_seq = state.tail_seq;
+        for &(seq, id) in order.iter().filter(|&&(seq, _)| seq >= tail_seq) {
+            state.segments[id] = Some(seq);
+        }
+
+        // then replay the records after it
+        for &(seq, id) in order.iter().filter(|&&(seq, _)| seq >= checkpoint_seq) {
+            if seq != checkpoint_seq {
+                pos = fs.super_block.segment_pos(id) + SEGMENT_HEADER_SIZE as LogPos;
+            }
+            let end = fs.super_block.segment_pos(id) + segment_size as LogPos;
+            while pos < end {
+                let (record, len) = match fs.read_record(pos, seq)? {
+                    Some(record) => record,
+                    // the end, or a torn record of an interrupted write
+                    None => break,
+                };
+                let state = fs.state.get_mut();
+                state.apply(pos, &record).ok_or(FsError::WrongFs)?;
+                state.dirty = true;
+                pos += len as LogPos;
+            }
+            let state = fs.state.get_mut();
+            state.head = id;
+            state.head_pos = pos;
+        }
+        Ok(fs.wrap())
+    }
+
+    /// Create a new LogFS on blank disk, with the default segment size
+    pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
+        Self::create_with_segment_size(device, space, DEFAULT_SEGMENT_SIZE)
+    }
+
+    /// Create a new LogFS on blank disk.
+    ///
+    /// `segment_size` is a multiple of `BLKSIZE`, ideally the erase block of
+    /// device. A file is written in records no larger than a segment. Fail
+    /// with `InvalidParam` if it is not, or `space` holds too few segments.
+    pub fn create_with_segment_size(
+        device: Arc<dyn Device>,
+        space: usize,
+        segment_size: usize,
+    ) -> vfs::Result<Arc<Self>> {
+        if segment_size < BLKSIZE || segment_size & (BLKSIZE - 1) != 0 {
+            error!(
+                "logfs: segment size {} is not a multiple of {}",
+                segment_size, BLKSIZE
+            );
+            return Err(FsError::InvalidParam);
+        }
+        let segments = space.saturating_sub(BLKSIZE) / segment_size;
+        if segments <= RESERVED_SEGMENTS + 1 || segments > u32::MAX as usize {
+            error!(
+                "logfs: {} bytes do not make a fs of {}-byte segments",
+                space, segment_size
+            );
+            return Err(FsError::InvalidParam);
+        }
+        let super_block = SuperBlock {
+            segment_size: segment_size as u32,
+            segments: segments as u32,
+        };
+        device.write_all(0, &super_block.encode())?;
+        // forget segments of whatever was on device
+        for id in 0..segments {
+            device.write_all(super_block.segment_pos(id), &[0; SEGMENT_HEADER_SIZE])?;
+        }
+
+        let mut inodes = BTreeMap::new();
+        let root = InodeImage {
+            meta: InodeMeta::new(FileType::Dir, 0o777, 0, ROOT_ID),
+            entries: BTreeMap::new(),
+            extents: BTreeMap::new(),
+        };
+        inodes.insert(ROOT_ID, root);
+        let fs = LogFS {
+            super_block,
+            state: Mutex::new(State {
+                next_id: ROOT_ID + 1,
+                inodes,
+                segments: vec![None; segments],
+                head: 0,
+                head_pos: 0,
+                next_seq: 1,
+                tail_seq: 0,
+                dirty: true,
+            }),
+            device,
+            self_ptr: Weak::default(),
+            instance_id: vfs::new_instance_id(),
+        }
+        .wrap();
+        fs.sync()?;
+        Ok(fs)
+    }
+
+    /// Wrap pure LogFS with Arc
+    /// Used in constructors
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ptr = weak;
+        }
+        unsafe { Arc::from_raw(ptr) }
+    }
+
+    /// Number of free segments
+    pub fn free_segments(&self) -> usize {
+        self.state.lock().free_segments()
+    }
+
+    /// Reclaim the oldest segments until `target_free_segments` are free,
+    /// or no more can be reclaimed. Return the number of free segments.
+    ///
+    /// Live data in them is copied to the head of log, then a checkpoint
+    /// is written, after which they are trimmed from the tail.
+    pub fn gc(&self, target_free_segments: usize) -> vfs::Result<usize> {
+        let mut state = self.state.lock();
+        if state.free_segments() >= target_free_segments {
+            return Ok(state.free_segments());
+        }
+        let mut candidates: Vec<(u64, usize)> = state
+            .segments
+            .iter()
+            .enumerate()
+            .filter(|&(id, _)| id != state.head)
+            .filter_map(|(id, seq)| seq.map(|seq| (seq, id)))
+            .collect();
+        candidates.sort_unstable();
+        let mut victims = Vec::new();
+        for (seq, id) in candidates {
+            // the checkpoint takes one more
+            if state.free_segments() + victims.len() > target_free_segments {
+                break;
+            }
+            self.copy_live_data(&mut state, id)?;
+            victims.push((seq, id));
+        }
+        let (last_seq, _) = match victims.last() {
+            Some(&victim) => victim,
+            None => return Ok(state.free_segments()),
+        };
+        state.tail_seq = last_seq + 1;
+        self.checkpoint(&mut state)?;
+        for (_, id) in victims {
+            debug!("logfs: segment {} is reclaimed", id);
+            state.segments[id] = None;
+        }
+        Ok(state.free_segments())
+    }
+
+    /// Copy data in segment `id`, still in use, to the head of log
+    fn copy_live_data(&self, state: &mut State, id: usize) -> vfs::Result<()> {
+        let mut live = Vec::new();
+        for (&inode, image) in state.inodes.iter() {
+            for (&offset, extent) in image.extents.iter() {
+                if self.super_block.segment_of(extent.pos) == id {
+                    live.push((inode, offset, *extent, image.meta.size));
+                }
+            }
+        }
+        for (inode, offset, extent, size) in live {
+            let mut data = vec![0u8; extent.len as usize];
+            self.device.read_exact(extent.pos, &mut data)?;
+            let record = Record::Write {
+                id: inode,
+                offset,
+                size,
+                len: extent.len,
+            };
+            // keep one for the checkpoint
+            self.append(state, &record, &data, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Max length of data in a `Write` record
+    fn max_write_len(&self) -> usize {
+        let len = self.super_block.segment_size as usize
+            - SEGMENT_HEADER_SIZE
+            - RECORD_HEADER_SIZE
+            - WRITE_FIELDS_SIZE;
+        len & !7
+    }
+
+    /// Start a new segment as the head, leaving at least `reserved` free
+    fn open_segment(&self, state: &mut State, reserved: usize) -> vfs::Result<()> {
+        if state.free_segments() <= reserved {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let id = state.segments.iter().position(Option::is_none).unwrap();
+        let seq = state.next_seq;
+        let pos = self.super_block.segment_pos(id);
+        self.device
+            .write_all(pos, &SegmentHeader { seq }.encode())?;
+        state.next_seq += 1;
+        state.segments[id] = Some(seq);
+        state.head = id;
+        state.head_pos = pos + SEGMENT_HEADER_SIZE as LogPos;
+        Ok(())
+    }
+
+    /// Append `record` with `data` to the log and apply it to the index.
+    /// New segments leave at least `reserved` free.
+    fn append(
+        &self,
+        state: &mut State,
+        record: &Record,
+        data: &[u8],
+        reserved: usize,
+    ) -> vfs::Result<()> {
+        let mut buf = record.encode(state.head_seq(), data);
+        let end =
+            self.super_block.segment_pos(state.head) + self.super_block.segment_size as LogPos;
+        if state.head_pos + align(buf.len()) as LogPos > end {
+            self.open_segment(state, reserved)?;
+            buf = record.encode(state.head_seq(), data);
+        }
+        let pos = state.head_pos;
+        self.device.write_all(pos, &buf)?;
+        state.head_pos += align(buf.len()) as LogPos;
+        state.dirty = true;
+        state
+            .apply(pos, record)
+            .expect("record is checked before appending");
+        Ok(())
+    }
+
+    /// Write the index at the start of a new segment
+    fn checkpoint(&self, state: &mut State) -> vfs::Result<()> {
+        let record = Record::Checkpoint {
+            next_id: state.next_id,
+            tail_seq: state.tail_seq,
+            inodes: state.inodes.clone(),
+        };
+        let len = align(record.encode(0, &[]).len());
+        if len > self.super_block.segment_size as usize - SEGMENT_HEADER_SIZE {
+            warn!(
+                "logfs: checkpoint of {} bytes is larger than a segment",
+                len
+            );
+            return Err(FsError::NoDeviceSpace);
+        }
+        self.open_segment(state, 0)?;
+        let buf = record.encode(state.head_seq(), &[]);
+        self.device.write_all(state.head_pos, &buf)?;
+        state.head_pos += align(buf.len()) as LogPos;
+        state.dirty = false;
+        Ok(())
+    }
+
+    /// Read the record at `pos` in segment `seq`, return it with its length.
+    /// Return `None` if there is no valid record.
+    fn read_record(&self, pos: LogPos, seq: u64) -> vfs::Result<Option<(Record, usize)>> {
+        let id = self.super_block.segment_of(pos);
+        let end = self.super_block.segment_pos(id) + self.super_block.segment_size as LogPos;
+        if pos + RECORD_HEADER_SIZE as LogPos > end {
+            return Ok(None);
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        self.device.read_exact(pos, &mut header)?;
+        let len = match Record::decode_header(&header, seq) {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let total = align(RECORD_HEADER_SIZE + len);
+        if pos + total as LogPos > end {
+            return Ok(None);
+        }
+        let mut payload = vec![0u8; len];
+        self.device
+            .read_exact(pos + RECORD_HEADER_SIZE as LogPos, &mut payload)?;
+        Ok(Record::decode(&header, &payload).map(|record| (record, total)))
+    }
+
+    /// Read content of `extents` at `offset`, holes are filled with 0
+    fn read_extents(
+        &self,
+        extents: &BTreeMap<u64, Extent>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> vfs::Result<()> {
+        buf.fill(0);
+        let end = offset + buf.len() as u64;
+        for (&start, extent) in extents
+            .range(..end)
+            .rev()
+            .take_while(|(&start, extent)| start + extent.len > offset)
+        {
+            let begin = start.max(offset);
+            let stop = (start + extent.len).min(end);
+            let target = &mut buf[(begin - offset) as usize..(stop - offset) as usize];
+            self.device
+                .read_exact(extent.pos + (begin - start), target)?;
+        }
+        Ok(())
+    }
+
+    fn get_inode(&self, id: INodeId) -> Arc<LogINode> {
+        Arc::new(LogINode {
+            id,
+            fs: self.self_ptr.upgrade().unwrap(),
+        })
+    }
+}
+
+impl FileSystem for LogFS {
+    /// Write a checkpoint if anything changed
+    fn sync(&self) -> vfs::Result<()> {
+        let mut state = self.state.lock();
+        if state.dirty {
+            self.checkpoint(&mut state)?;
+        }
+        self.device.sync()?;
+        Ok(())
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        self.get_inode(ROOT_ID)
+    }
+
+    fn info(&self) -> vfs::FsInfo {
+        let state = self.state.lock();
+        let segment_blocks = self.super_block.segment_size as usize / BLKSIZE;
+        let free = state.free_segments();
+        vfs::FsInfo {
+            bsize: BLKSIZE,
+            frsize: BLKSIZE,
+            blocks: self.super_block.segments as usize * segment_blocks,
+            bfree: free * segment_blocks,
+            bavail: free.saturating_sub(RESERVED_SEGMENTS) * segment_blocks,
+            files: state.inodes.len(),
+            ffree: free * segment_blocks, // inaccurate
+            namemax: MAX_NAME_LEN,
+            linkmax: 1, // no hard links
+        }
+    }
+
+    fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+
+    /// Holes are not in any extent, so they take no space
+    fn capabilities(&self) -> vfs::FsCapabilities {
+        use vfs::FsCapabilities as Caps;
+        Caps::SYMLINK | Caps::SPARSE | Caps::DEVICE_NODES | Caps::RENAME
+    }
+}
+
+/// INode for LogFS.
+///
+/// It only holds the inode id, after the inode is removed all operations
+/// return `EntryNotFound`.
+pub struct LogINode {
+    id: INodeId,
+    fs: Arc<LogFS>,
+}
+
+fn check_name(name: &str) -> vfs::Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('/') {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+impl LogINode {
+    /// Append a `SetAttr` record changing `f` of current attributes
+    fn set_attr(&self, f: impl FnOnce(&mut Attr)) -> vfs::Result<()> {
+        let mut state = self.fs.state.lock();
+        let meta = &state.get(self.id)?.meta;
+        let mut attr = Attr {
+            size: meta.size,
+            mode: meta.mode,
+            uid: meta.uid,
+            gid: meta.gid,
+            atime: meta.atime,
+            mtime: meta.mtime,
+            ctime: meta.ctime,
+        };
+        f(&mut attr);
+        let record = Record::SetAttr { id: self.id, attr };
+        self.fs.append(&mut state, &record, &[], RESERVED_SEGMENTS)
+    }
+}
+
+impl INode for LogINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let state = self.fs.state.lock();
+        let image = state.get(self.id)?;
+        if image.meta.type_ == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        let size = image.meta.size as usize;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        self.fs
+            .read_extents(&image.extents, offset as u64, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        let mut state = self.fs.state.lock();
+        if state.get(self.id)?.meta.type_ == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        let mut written = 0;
+        for chunk in buf.chunks(self.fs.max_write_len()) {
+            let offset = (offset + written) as u64;
+            let end = offset + chunk.len() as u64;
+            let record = Record::Write {
+                id: self.id,
+                offset,
+                size: state.get(self.id)?.meta.size.max(end),
+                len: chunk.len() as u64,
+            };
+            match self
+                .fs
+                .append(&mut state, &record, chunk, RESERVED_SEGMENTS)
+            {
+                Ok(()) => written += chunk.len(),
+                // the chunks before are written
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+
+    fn poll(&self) -> vfs::Result<PollStatus> {
+        let state = self.fs.state.lock();
+        if state.get(self.id)?.meta.type_ == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        let state = self.fs.state.lock();
+        let image = state.get(self.id)?;
+        let meta = &image.meta;
+        let size = match meta.type_ {
+            FileType::Dir => image.entries.len() + 2,
+            _ => meta.size as usize,
+        };
+        let rdev = match meta.type_ {
+            FileType::CharDevice | FileType::BlockDevice => meta.rdev as usize,
+            _ => 0,
+        };
+        Ok(Metadata {
+            dev: 0,
+            inode: self.id as usize,
+            size,
+            blk_size: BLKSIZE,
+            blocks: size / BLKSIZE + (size % BLKSIZE != 0) as usize,
+            atime: meta.atime,
+            mtime: meta.mtime,
+            ctime: meta.ctime,
+            btime: vfs::Timespec { sec: 0, nsec: 0 },
+            type_: meta.type_,
+            mode: meta.mode,
+            nlinks: meta.nlinks as usize,
+            uid: meta.uid as usize,
+            gid: meta.gid as usize,
+            rdev,
+            flags: vfs::MetadataFlags::empty(),
+        })
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> vfs::Result<()> {
+        self.set_attr(|attr| {
+            attr.mode = metadata.mode;
+            attr.uid = metadata.uid as u32;
+            attr.gid = metadata.gid as u32;
+            attr.atime = metadata.atime;
+            attr.mtime = metadata.mtime;
+            attr.ctime = metadata.ctime;
+        })
+    }
+
+    fn sync_all(&self) -> vfs::Result<()> {
+        self.fs.sync()
+    }
+
+    fn sync_data(&self) -> vfs::Result<()> {
+        self.fs.sync()
+    }
+
+    fn resize(&self, len: usize) -> vfs::Result<()> {
+        let type_ = self.fs.state.lock().get(self.id)?.meta.type_;
+        match type_ {
+            FileType::File | FileType::SymLink => {}
+            FileType::Dir => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        }
+        self.set_attr(|attr| attr.size = len as u64)
+    }
+
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> vfs::Result<Arc<dyn INode>> {
+        let mut state = self.fs.state.lock();
+        let dir = state.get_dir(self.id)?;
+        if name == "." || name == ".." || dir.entries.contains_key(name) {
+            return Err(FsError::EntryExist);
+        }
+        check_name(name)?;
+        let id = state.next_id;
+        let record = Record::Create {
+            dir: self.id,
+            name: String::from(name),
+            id,
+            type_,
+            mode: mode as u16,
+            rdev: data as u64,
+        };
+        self.fs
+            .append(&mut state, &record, &[], RESERVED_SEGMENTS)?;
+        Ok(self.fs.get_inode(id))
+    }
+
+    /// No hard links, but only a dir could have one
+    fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> vfs::Result<()> {
+        self.fs.state.lock().get_dir(self.id)?;
+        Err(FsError::Unsupported)
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        if name == "." || name == ".." {
+            return Err(FsError::DirNotEmpty);
+        }
+        let mut state = self.fs.state.lock();
+        let dir = state.get_dir(self.id)?;
+        let child = *dir.entries.get(name).ok_or(FsError::EntryNotFound)?;
+        if !state.get(child)?.entries.is_empty() {
+            return Err(FsError::DirNotEmpty);
+        }
+        let record = Record::Unlink {
+            dir: self.id,
+            name: String::from(name),
+        };
+        self.fs.append(&mut state, &record, &[], RESERVED_SEGMENTS)
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        let target = target
+            .downcast_ref::<LogINode>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        for name in [old_name, new_name].iter() {
+            if *name == "." || *name == ".." {
+                return Err(FsError::InvalidParam);
+            }
+        }
+        check_name(new_name)?;
+        let mut state = self.fs.state.lock();
+        let child = *state
+            .get_dir(self.id)?
+            .entries
+            .get(old_name)
+            .ok_or(FsError::EntryNotFound)?;
+        if state.get_dir(target.id)?.entries.contains_key(new_name) {
+            return Err(FsError::EntryExist);
+        }
+        // a directory can not be moved into itself
+        if state.get(child)?.meta.type_ == FileType::Dir {
+            let mut dir = target.id;
+            while dir != ROOT_ID {
+                if dir == child {
+                    return Err(FsError::InvalidParam);
+                }
+                dir = state.get(dir)?.meta.parent;
+            }
+        }
+        let record = Record::Move {
+            dir: self.id,
+            name: String::from(old_name),
+            target: target.id,
+            new_name: String::from(new_name),
+        };
+        self.fs.append(&mut state, &record, &[], RESERVED_SEGMENTS)
+    }
+
+    fn find(&self, name: &str) -> vfs::Result<Arc<dyn INode>> {
+        let state = self.fs.state.lock();
+        let dir = state.get_dir(self.id)?;
+        let id = match name {
+            "." => self.id,
+            ".." => dir.meta.parent,
+            _ => *dir.entries.get(name).ok_or(FsError::EntryNotFound)?,
+        };
+        Ok(self.fs.get_inode(id))
+    }
+
+    fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        let state = self.fs.state.lock();
+        let dir = state.get_dir(self.id)?;
+        match id {
+            0 => Ok(String::from(".")),
+            1 => Ok(String::from("..")),
+            i => dir
+                .entries
+                .keys()
+                .nth(i - 2)
+                .cloned()
+                .ok_or(FsError::EntryNotFound),
+        }
+    }
+
+    fn fs(&self) -> vfs::Result<Arc<dyn FileSystem>> {
+        Ok(self.fs.clone())
+    }
+
+    fn ino_key(&self) -> vfs::InodeKey {
+        vfs::InodeKey {
+            fs: self.fs.instance_id,
+            inode: self.id as usize,
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,602 @@
+//! On-disk structures in LogFS
+//!
+//! Layout of device:
+//!
+//! | superblock (1 block) | segment 0 | segment 1 | ... |
+//!
+//! Each segment begins with a `SegmentHeader`, followed by records. All
+//! integers are little endian. Records are 8-byte aligned, each protected
+//! by a CRC that also covers the sequence number of its segment, so
+//! records left over from a previous use of the segment are never valid.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::str;
+use rcore_fs::vfs::{FileType, Timespec};
+
+pub type INodeId = u64;
+/// Byte offset on device
+pub type LogPos = u64;
+
+/// magic number of superblock, "LOGF"
+pub const MAGIC: u32 = 0x4c4f_4746;
+/// magic number of segment header, "LSEG"
+pub const SEGMENT_MAGIC: u32 = 0x4c53_4547;
+/// magic number of record header
+pub const RECORD_MAGIC: u16 = 0x4c52;
+/// on-disk format version
+pub const VERSION: u32 = 1;
+/// size of a block, the superblock takes one
+pub const BLKSIZE: usize = 4096;
+/// default size of a segment
+pub const DEFAULT_SEGMENT_SIZE: usize = 64 * 1024;
+pub const SEGMENT_HEADER_SIZE: usize = 24;
+pub const RECORD_HEADER_SIZE: usize = 24;
+/// size of fields of `Record::Write` before the data
+pub const WRITE_FIELDS_SIZE: usize = 24;
+/// inode id of root
+pub const ROOT_ID: INodeId = 1;
+/// max length of file name
+pub const MAX_NAME_LEN: usize = 255;
+
+const KIND_CREATE: u8 = 1;
+const KIND_UNLINK: u8 = 2;
+const KIND_MOVE: u8 = 3;
+const KIND_WRITE: u8 = 4;
+const KIND_SET_ATTR: u8 = 5;
+const KIND_CHECKPOINT: u8 = 6;
+
+/// On-disk superblock, written once when the fs is created
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SuperBlock {
+    pub segment_size: u32,
+    pub segments: u32,
+}
+
+impl SuperBlock {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        e.u32(MAGIC);
+        e.u32(VERSION);
+        e.u32(self.segment_size);
+        e.u32(self.segments);
+        e.seal()
+    }
+    /// The superblock in `buf`, if its segments are whole blocks and fit a
+    /// device of `device_size` bytes, when known
+    pub fn decode(buf: &[u8], device_size: Option<usize>) -> Option<Self> {
+        let mut d = Decoder::sealed(buf, 16)?;
+        if d.u32()? != MAGIC || d.u32()? != VERSION {
+            return None;
+        }
+        let super_block = SuperBlock {
+            segment_size: d.u32()?,
+            segments: d.u32()?,
+        };
+        let (segment_size, segments) = (
+            super_block.segment_size as usize,
+            super_block.segments as usize,
+        );
+        if segment_size < BLKSIZE || segment_size % BLKSIZE != 0 || segments == 0 {
+            return None;
+        }
+        let end = segments.checked_mul(segment_size)?.checked_add(BLKSIZE)?;
+        match device_size {
+            Some(size) if end > size => None,
+            _ => Some(super_block),
+        }
+    }
+    /// Position of segment `id` on device
+    pub fn segment_pos(&self, id: usize) -> LogPos {
+        (BLKSIZE + id * self.segment_size as usize) as LogPos
+    }
+    /// Segment holding position `pos`
+    pub fn segment_of(&self, pos: LogPos) -> usize {
+        (pos as usize - BLKSIZE) / self.segment_size as usize
+    }
+}
+
+/// Header of a segment in use. Segments are filled in order of `seq`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SegmentHeader {
+    pub seq: u64,
+}
+
+impl SegmentHeader {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut e = Encoder::default();
+        e.u32(SEGMENT_MAGIC);
+        e.u32(0);
+        e.u64(self.seq);
+        let mut buf = e.seal();
+        buf.resize(SEGMENT_HEADER_SIZE, 0);
+        buf
+    }
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut d = Decoder::sealed(buf, 16)?;
+        if d.u32()? != SEGMENT_MAGIC {
+            return None;
+        }
+        d.u32()?;
+        Some(SegmentHeader { seq: d.u64()? })
+    }
+}
+
+/// Metadata of an inode
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InodeMeta {
+    pub type_: FileType,
+    pub mode: u16,
+    pub nlinks: u32,
+    pub size: u64,
+    pub rdev: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+    /// parent directory, only for directories
+    pub parent: INodeId,
+}
+
+impl InodeMeta {
+    pub fn new(type_: FileType, mode: u16, rdev: u64, parent: INodeId) -> Self {
+        let zero = Timespec { sec: 0, nsec: 0 };
+        InodeMeta {
+            type_,
+            mode,
+            nlinks: 1,
+            size: 0,
+            rdev,
+            uid: 0,
+            gid: 0,
+            atime: zero,
+            mtime: zero,
+            ctime: zero,
+            parent,
+        }
+    }
+}
+
+/// Part of file content, stored at `pos` in the log
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Extent {
+    pub len: u64,
+    pub pos: LogPos,
+}
+
+/// Everything about an inode, as stored in a checkpoint
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InodeImage {
+    pub meta: InodeMeta,
+    /// name -> inode, only for directories
+    pub entries: BTreeMap<String, INodeId>,
+    /// file offset -> extent, not overlapping
+    pub extents: BTreeMap<u64, Extent>,
+}
+
+/// Attributes changed by `Record::SetAttr`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Attr {
+    pub size: u64,
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+}
+
+/// A change to the fs, appended to the log
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Record {
+    Create {
+        dir: INodeId,
+        name: String,
+        id: INodeId,
+        type_: FileType,
+        mode: u16,
+        rdev: u64,
+    },
+    Unlink {
+        dir: INodeId,
+        name: String,
+    },
+    Move {
+        dir: INodeId,
+        name: String,
+        target: INodeId,
+        new_name: String,
+    },
+    /// `len` bytes of data follow the fields, `size` is the file size after it
+    Write {
+        id: INodeId,
+        offset: u64,
+        size: u64,
+        len: u64,
+    },
+    SetAttr {
+        id: INodeId,
+        attr: Attr,
+    },
+    /// The whole index. Segments older than `tail_seq` are free.
+    Checkpoint {
+        next_id: INodeId,
+        tail_seq: u64,
+        inodes: BTreeMap<INodeId, InodeImage>,
+    },
+}
+
+impl Record {
+    /// Encode the record in segment `seq` with its header, not padded.
+    /// `data` follows the fields, only for `Write`.
+    pub fn encode(&self, seq: u64, data: &[u8]) -> Vec<u8> {
+        let mut e = Encoder::default();
+        let kind = match self {
+            Record::Create {
+                dir,
+                name,
+                id,
+                type_,
+                mode,
+                rdev,
+            } => {
+                e.u64(*dir);
+                e.str(name);
+                e.u64(*id);
+                e.u8(encode_type(*type_));
+                e.u16(*mode);
+                e.u64(*rdev);
+                KIND_CREATE
+            }
+            Record::Unlink { dir, name } => {
+                e.u64(*dir);
+                e.str(name);
+                KIND_UNLINK
+            }
+            Record::Move {
+                dir,
+                name,
+                target,
+                new_name,
+            } => {
+                e.u64(*dir);
+                e.str(name);
+                e.u64(*target);
+                e.str(new_name);
+                KIND_MOVE
+            }
+            Record::Write {
+                id, offset, size, ..
+            } => {
+                e.u64(*id);
+                e.u64(*offset);
+                e.u64(*size);
+                e.buf.extend_from_slice(data);
+                KIND_WRITE
+            }
+            Record::SetAttr { id, attr } => {
+                e.u64(*id);
+                e.attr(attr);
+                KIND_SET_ATTR
+            }
+            Record::Checkpoint {
+                next_id,
+                tail_seq,
+                inodes,
+            } => {
+                e.u64(*next_id);
+                e.u64(*tail_seq);
+                e.u32(inodes.len() as u32);
+                for (&id, image) in inodes.iter() {
+                    e.u64(id);
+                    e.image(image);
+                }
+                KIND_CHECKPOINT
+            }
+        };
+        let payload = e.buf;
+        let mut header = Encoder::default();
+        header.u16(RECORD_MAGIC);
+        header.u8(kind);
+        header.u8(0);
+        header.u32(payload.len() as u32);
+        header.u64(seq);
+        let crc = crc32(crc32(0, &header.buf), &payload);
+        header.u32(crc);
+        header.u32(0);
+        let mut buf = header.buf;
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    /// Decode the header of a record in segment `seq`, return length of payload
+    pub fn decode_header(buf: &[u8], seq: u64) -> Option<usize> {
+        let mut d = Decoder::new(buf);
+        if d.u16()? != RECORD_MAGIC {
+            return None;
+        }
+        d.u8()?;
+        d.u8()?;
+        let len = d.u32()? as usize;
+        if d.u64()? != seq {
+            return None;
+        }
+        Some(len)
+    }
+
+    /// Decode a record from its header and payload, `None` if it is broken
+    pub fn decode(header: &[u8], payload: &[u8]) -> Option<Self> {
+        let mut d = Decoder::new(header);
+        d.u16()?;
+        let kind = d.u8()?;
+        d.u8()?;
+        d.u32()?;
+        d.u64()?;
+        let crc = d.u32()?;
+        if crc32(crc32(0, &header[..16]), payload) != crc {
+            return None;
+        }
+        let mut d = Decoder::new(payload);
+        let record = match kind {
+            KIND_CREATE => Record::Create {
+                dir: d.u64()?,
+                name: d.str()?,
+                id: d.u64()?,
+                type_: decode_type(d.u8()?)?,
+                mode: d.u16()?,
+                rdev: d.u64()?,
+            },
+            KIND_UNLINK => Record::Unlink {
+                dir: d.u64()?,
+                name: d.str()?,
+            },
+            KIND_MOVE => Record::Move {
+                dir: d.u64()?,
+                name: d.str()?,
+                target: d.u64()?,
+                new_name: d.str()?,
+            },
+            KIND_WRITE => Record::Write {
+                id: d.u64()?,
+                offset: d.u64()?,
+                size: d.u64()?,
+                len: (payload.len() - WRITE_FIELDS_SIZE) as u64,
+            },
+            KIND_SET_ATTR => Record::SetAttr {
+                id: d.u64()?,
+                attr: d.attr()?,
+            },
+            KIND_CHECKPOINT => {
+                let next_id = d.u64()?;
+                let tail_seq = d.u64()?;
+                let mut inodes = BTreeMap::new();
+                for _ in 0..d.u32()? {
+                    inodes.insert(d.u64()?, d.image()?);
+                }
+                Record::Checkpoint {
+                    next_id,
+                    tail_seq,
+                    inodes,
+                }
+            }
+            _ => return None,
+        };
+        Some(record)
+    }
+}
+
+/// Round `len` up to the alignment of records
+pub const fn align(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+fn encode_type(type_: FileType) -> u8 {
+    match type_ {
+        FileType::File => 1,
+        FileType::Dir => 2,
+        FileType::SymLink => 3,
+        FileType::CharDevice => 4,
+        FileType::BlockDevice => 5,
+        FileType::NamedPipe => 6,
+        FileType::Socket => 7,
+    }
+}
+
+fn decode_type(type_: u8) -> Option<FileType> {
+    Some(match type_ {
+        1 => FileType::File,
+        2 => FileType::Dir,
+        3 => FileType::SymLink,
+        4 => FileType::CharDevice,
+        5 => FileType::BlockDevice,
+        6 => FileType::NamedPipe,
+        7 => FileType::Socket,
+        _ => return None,
+    })
+}
+
+/// CRC-32 (IEEE), continuing from `crc`
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & 0u32.wrapping_sub(crc & 1));
+        }
+    }
+    !crc
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+    fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    fn str(&mut self, s: &str) {
+        self.u16(s.len() as u16);
+        self.buf.extend_from_slice(s.as_bytes());
+    }
+    fn time(&mut self, t: Timespec) {
+        self.u64(t.sec as u64);
+        self.u32(t.nsec as u32);
+    }
+    fn attr(&mut self, attr: &Attr) {
+        self.u64(attr.size);
+        self.u16(attr.mode);
+        self.u32(attr.uid);
+        self.u32(attr.gid);
+        self.time(attr.atime);
+        self.time(attr.mtime);
+        self.time(attr.ctime);
+    }
+    fn image(&mut self, image: &InodeImage) {
+        let meta = &image.meta;
+        self.u8(encode_type(meta.type_));
+        self.u32(meta.nlinks);
+        self.u64(meta.rdev);
+        self.u64(meta.parent);
+        self.attr(&Attr {
+            size: meta.size,
+            mode: meta.mode,
+            uid: meta.uid,
+            gid: meta.gid,
+            atime: meta.atime,
+            mtime: meta.mtime,
+            ctime: meta.ctime,
+        });
+        self.u32(image.entries.len() as u32);
+        for (name, &id) in image.entries.iter() {
+            self.str(name);
+            self.u64(id);
+        }
+        self.u32(image.extents.len() as u32);
+        for (&offset, extent) in image.extents.iter() {
+            self.u64(offset);
+            self.u64(extent.len);
+            self.u64(extent.pos);
+        }
+    }
+    /// Append CRC of content, for fixed size structures
+    fn seal(mut self) -> Vec<u8> {
+        let crc = crc32(0, &self.buf);
+        self.u32(crc);
+        self.buf
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Decoder { buf }
+    }
+    /// Check the CRC after the first `len` bytes
+    fn sealed(buf: &'a [u8], len: usize) -> Option<Self> {
+        let crc = Decoder::new(buf.get(len..)?).u32()?;
+        if crc32(0, &buf[..len]) != crc {
+            return None;
+        }
+        Some(Decoder::new(&buf[..len]))
+    }
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < len {
+            return None;
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(head)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Some(u16::from_le_bytes(bytes))
+    }
+    fn u32(&mut self) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(bytes))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(bytes))
+    }
+    fn str(&mut self) -> Option<String> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        Some(String::from(str::from_utf8(bytes).ok()?))
+    }
+    fn time(&mut self) -> Option<Timespec> {
+        Some(Timespec {
+            sec: self.u64()? as i64,
+            nsec: self.u32()? as i32,
+        })
+    }
+    fn attr(&mut self) -> Option<Attr> {
+        Some(Attr {
+            size: self.u64()?,
+            mode: self.u16()?,
+            uid: self.u32()?,
+            gid: self.u32()?,
+            atime: self.time()?,
+            mtime: self.time()?,
+            ctime: self.time()?,
+        })
+    }
+    fn image(&mut self) -> Option<InodeImage> {
+        let type_ = decode_type(self.u8()?)?;
+        let nlinks = self.u32()?;
+        let rdev = self.u64()?;
+        let parent = self.u64()?;
+        let attr = self.attr()?;
+        let mut entries = BTreeMap::new();
+        for _ in 0..self.u32()? {
+            let name = self.str()?;
+            entries.insert(name, self.u64()?);
+        }
+        let mut extents = BTreeMap::new();
+        for _ in 0..self.u32()? {
+            let offset = self.u64()?;
+            let extent = Extent {
+                len: self.u64()?,
+                pos: self.u64()?,
+            };
+            extents.insert(offset, extent);
+        }
+        Some(InodeImage {
+            meta: InodeMeta {
+                type_,
+                mode: attr.mode,
+                nlinks,
+                size: attr.size,
+                rdev,
+                uid: attr.uid,
+                gid: attr.gid,
+                atime: attr.atime,
+                mtime: attr.mtime,
+                ctime: attr.ctime,
+                parent,
+            },
+            entries,
+            extents,
+        })
+    }
+}
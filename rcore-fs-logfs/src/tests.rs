@@ -0,0 +1,379 @@
+extern crate std;
+
+use crate::*;
+use rcore_fs::dev::{DevError, Result as DevResult};
+use rcore_fs::vfs::Result;
+use std::collections::BTreeMap;
+use std::format;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Device in memory, recording every write so that a crash can be
+/// simulated by replaying a prefix of them
+struct JournalDevice {
+    data: Mutex<Vec<u8>>,
+    journal: Mutex<Vec<(usize, Vec<u8>)>>,
+    bytes_read: AtomicUsize,
+}
+
+impl JournalDevice {
+    fn new(data: Vec<u8>) -> Arc<Self> {
+        Arc::new(JournalDevice {
+            data: Mutex::new(data),
+            journal: Mutex::new(Vec::new()),
+            bytes_read: AtomicUsize::new(0),
+        })
+    }
+    fn snapshot(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+    /// Content after the first `n` writes to `base`, and at most `torn` bytes
+    /// of the next one
+    fn crash_image(&self, base: &[u8], n: usize, torn: usize) -> Vec<u8> {
+        let mut data = base.to_vec();
+        let journal = self.journal.lock().unwrap();
+        let writes = journal[..n].iter().map(|(offset, buf)| (*offset, &buf[..]));
+        let torn = journal
+            .get(n)
+            .map(|(offset, buf)| (*offset, &buf[..torn.min(buf.len() - 1)]));
+        for (offset, buf) in writes.chain(torn) {
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+        }
+        data
+    }
+}
+
+impl Device for JournalDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let data = self.data.lock().unwrap();
+        if offset + buf.len() > data.len() {
+            return Err(DevError::OutOfRange);
+        }
+        buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        self.bytes_read.fetch_add(buf.len(), Ordering::Relaxed);
+        Ok(buf.len())
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        let mut data = self.data.lock().unwrap();
+        if offset + buf.len() > data.len() {
+            return Err(DevError::OutOfRange);
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        self.journal.lock().unwrap().push((offset, buf.to_vec()));
+        Ok(buf.len())
+    }
+    fn sync(&self) -> DevResult<()> {
+        Ok(())
+    }
+    fn size(&self) -> Option<usize> {
+        Some(self.data.lock().unwrap().len())
+    }
+}
+
+const SEGMENT_SIZE: usize = 4 * BLKSIZE;
+
+fn create_fs(segments: usize) -> (Arc<JournalDevice>, Arc<LogFS>) {
+    let space = BLKSIZE + segments * SEGMENT_SIZE;
+    let device = JournalDevice::new(vec![0xcc; space]);
+    let fs = LogFS::create_with_segment_size(device.clone(), space, SEGMENT_SIZE).unwrap();
+    (device, fs)
+}
+
+/// Path -> content of all files, `None` for directories
+fn tree(fs: &Arc<LogFS>) -> Result<BTreeMap<String, Option<Vec<u8>>>> {
+    fn walk(
+        dir: &Arc<dyn INode>,
+        path: &str,
+        tree: &mut BTreeMap<String, Option<Vec<u8>>>,
+    ) -> Result<()> {
+        for name in dir.list()?.into_iter().skip(2) {
+            let inode = dir.find(&name)?;
+            let path = format!("{}/{}", path, name);
+            if inode.metadata()?.type_ == FileType::Dir {
+                tree.insert(path.clone(), None);
+                walk(&inode, &path, tree)?;
+            } else {
+                tree.insert(path, Some(inode.read_as_vec()?));
+            }
+        }
+        Ok(())
+    }
+    let mut tree = BTreeMap::new();
+    walk(&fs.root_inode(), "", &mut tree)?;
+    Ok(tree)
+}
+
+trait ReadAsVec {
+    fn read_as_vec(&self) -> Result<Vec<u8>>;
+}
+
+impl ReadAsVec for Arc<dyn INode> {
+    fn read_as_vec(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0; self.metadata()?.size];
+        let len = self.read_at(0, &mut buf)?;
+        assert_eq!(len, buf.len());
+        Ok(buf)
+    }
+}
+
+fn pattern(seed: u8, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+        .collect()
+}
+
+#[test]
+fn create_and_reopen() -> Result<()> {
+    let (device, fs) = create_fs(16);
+    let root = fs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    let file = dir.create("file", FileType::File, 0o644)?;
+    // larger than a segment, with a hole
+    let data = pattern(1, 3 * SEGMENT_SIZE);
+    file.write_at(100, &data)?;
+    file.write_at(5000, b"overwrite")?;
+    file.resize(20000)?;
+    root.create("a", FileType::File, 0o644)?;
+    root.move_("a", &dir, "b")?;
+    assert_eq!(root.list()?, [".", "..", "dir"]);
+    assert_eq!(dir.list()?, [".", "..", "b", "file"]);
+    assert_eq!(root.lookup("dir/..")?.metadata()?.inode, ROOT_ID as usize);
+    assert_eq!(root.unlink("dir"), Err(FsError::DirNotEmpty));
+    assert_eq!(dir.move_("b", &dir.find("..")?, "b"), Ok(()));
+    assert_eq!(root.move_("dir", &dir, "self"), Err(FsError::InvalidParam));
+
+    let mut expected = vec![0u8; 100];
+    expected.extend_from_slice(&data);
+    expected[5000..5009].copy_from_slice(b"overwrite");
+    expected.truncate(20000);
+    assert_eq!(file.read_as_vec()?, expected);
+    let before = tree(&fs)?;
+
+    // replay without a checkpoint
+    drop((root, dir, file));
+    drop(fs);
+    let fs = LogFS::open(device.clone())?;
+    assert_eq!(tree(&fs)?, before);
+    let meta = fs.root_inode().lookup("dir/file")?.metadata()?;
+    assert_eq!((meta.size, meta.mode), (20000, 0o644));
+
+    // and from a checkpoint
+    fs.sync()?;
+    drop(fs);
+    let fs = LogFS::open(device)?;
+    assert_eq!(tree(&fs)?, before);
+    Ok(())
+}
+
+#[test]
+fn open_starts_from_checkpoint() -> Result<()> {
+    let (device, fs) = create_fs(32);
+    let root = fs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    for i in 0..40 {
+        file.write_at(0, &pattern(i, 2000))?;
+    }
+    drop((root, file));
+    let open = || -> Result<usize> {
+        device.bytes_read.store(0, Ordering::Relaxed);
+        LogFS::open(device.clone())?;
+        Ok(device.bytes_read.load(Ordering::Relaxed))
+    };
+    let replayed = open()?;
+    fs.sync()?;
+    let checkpointed = open()?;
+    assert!(
+        checkpointed * 4 < replayed,
+        "{} bytes read from checkpoint, {} without",
+        checkpointed,
+        replayed
+    );
+    Ok(())
+}
+
+#[test]
+fn bad_geometry() -> Result<()> {
+    let (device, fs) = create_fs(8);
+    drop(fs);
+    let image = device.snapshot();
+    let segment_size = SEGMENT_SIZE as u32;
+    for (segment_size, segments) in [
+        (0, 8),
+        (100, 8),
+        (segment_size + 1, 8),
+        (segment_size, 0),
+        (segment_size, 9),
+        (segment_size, u32::MAX),
+    ] {
+        let mut data = image.clone();
+        let super_block = SuperBlock {
+            segment_size,
+            segments,
+        }
+        .encode();
+        data[..super_block.len()].copy_from_slice(&super_block);
+        assert_eq!(
+            LogFS::open(JournalDevice::new(data)).err(),
+            Some(FsError::WrongFs),
+            "{} segments of {} bytes",
+            segments,
+            segment_size
+        );
+    }
+    LogFS::open(JournalDevice::new(image))?;
+
+    let space = BLKSIZE + 8 * SEGMENT_SIZE;
+    let device = JournalDevice::new(vec![0; space]);
+    let create = |space, segment_size| {
+        LogFS::create_with_segment_size(device.clone(), space, segment_size).err()
+    };
+    assert_eq!(create(space, 100), Some(FsError::InvalidParam));
+    assert_eq!(create(space, 0), Some(FsError::InvalidParam));
+    assert_eq!(
+        create(2 * SEGMENT_SIZE, SEGMENT_SIZE),
+        Some(FsError::InvalidParam)
+    );
+    Ok(())
+}
+
+#[test]
+fn crash_replay() -> Result<()> {
+    let (device, fs) = create_fs(32);
+    let base = device.snapshot();
+    device.journal.lock().unwrap().clear();
+
+    // each step appends a single record, except `sync()`
+    let root = fs.root_inode();
+    let mut states = vec![(0, tree(&fs)?)];
+    let mut step = |f: &dyn Fn() -> Result<()>| -> Result<()> {
+        f()?;
+        states.push((device.journal.lock().unwrap().len(), tree(&fs)?));
+        Ok(())
+    };
+    step(&|| root.create("d", FileType::Dir, 0o755).map(|_| ()))?;
+    for i in 0..12u8 {
+        let name = format!("f{}", i % 5);
+        if i < 5 {
+            step(&|| {
+                let dir = root.find("d")?;
+                dir.create(&name, FileType::File, 0o644).map(|_| ())
+            })?;
+        }
+        step(&|| {
+            let file = root.find("d")?.find(&name)?;
+            let data = pattern(i, 1000 + i as usize * 500);
+            file.write_at(i as usize * 300, &data).map(|_| ())
+        })?;
+        if i == 5 {
+            step(&|| fs.sync())?;
+        }
+    }
+    step(&|| root.lookup("d/f1")?.resize(700))?;
+    step(&|| root.move_("d", &root, "e"))?;
+    step(&|| root.lookup("e")?.unlink("f2"))?;
+    step(&|| root.create("g", FileType::Dir, 0o755).map(|_| ()))?;
+    step(&|| root.lookup("e")?.move_("f3", &root.lookup("g")?, "h"))?;
+
+    let writes = device.journal.lock().unwrap().len();
+    for n in 0..=writes {
+        let committed = states.iter().rev().find(|(end, _)| *end <= n).unwrap();
+        for &torn in &[0, 13, 100] {
+            let image = device.crash_image(&base, n, torn);
+            let fs = LogFS::open(JournalDevice::new(image))?;
+            assert_eq!(
+                tree(&fs)?,
+                committed.1,
+                "crash after {} writes, {} torn",
+                n,
+                torn
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn gc_reclaims_space() -> Result<()> {
+    let (device, fs) = create_fs(12);
+    let root = fs.root_inode();
+    let files: Vec<_> = (0..3)
+        .map(|i| root.create(&format!("f{}", i), FileType::File, 0o644))
+        .collect::<Result<_>>()?;
+    let mut contents = vec![Vec::new(); files.len()];
+    let mut round = 0u8;
+    let mut write_round = |contents: &mut Vec<Vec<u8>>| -> Result<()> {
+        round += 1;
+        let i = round as usize % files.len();
+        let data = pattern(round, 3000);
+        files[i].write_at(0, &data)?;
+        contents[i] = data;
+        Ok(())
+    };
+    // overwrite until the space is used up
+    loop {
+        match write_round(&mut contents) {
+            Ok(()) => {}
+            Err(FsError::NoDeviceSpace) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    assert!(fs.free_segments() <= RESERVED_SEGMENTS);
+
+    assert!(fs.gc(6)? >= 6);
+    assert!(fs.free_segments() >= 6);
+    for (file, content) in files.iter().zip(&contents) {
+        assert_eq!(&file.read_as_vec()?, content);
+    }
+
+    // reclaimed space can be written again, and survives reopen
+    for _ in 0..12 {
+        write_round(&mut contents)?;
+    }
+    fs.sync()?;
+    let free = fs.free_segments();
+    drop((files, root));
+    drop(fs);
+    let fs = LogFS::open(device)?;
+    assert_eq!(fs.free_segments(), free);
+    let root = fs.root_inode();
+    for (i, content) in contents.iter().enumerate() {
+        assert_eq!(&root.find(&format!("f{}", i))?.read_as_vec()?, content);
+    }
+    Ok(())
+}
+
+#[test]
+fn conformance() -> Result<()> {
+    let (device, fs) = create_fs(64);
+    let caps = fs.capabilities();
+    assert!(!caps.contains(vfs::FsCapabilities::HARDLINK));
+    assert!(!caps.contains(vfs::FsCapabilities::BIRTH_TIME));
+    let root = fs.root_inode();
+    root.create("empty", FileType::File, 0o644)?;
+    root.create("file", FileType::File, 0o644)?
+        .write_at(0, &pattern(1, 5000))?;
+    root.symlink("link", "file")?;
+    root.create2("tty", FileType::CharDevice, 0o620, 0x0501)?;
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    dir.create("sparse", FileType::File, 0o644)?
+        .write_at(3000, b"end")?;
+    dir.create("sub", FileType::Dir, 0o755)?;
+    let file = root.find("file")?;
+    assert_eq!(root.link("hard", &file).err(), Some(FsError::Unsupported));
+
+    let check = |root: &Arc<dyn INode>| {
+        rcore_fs::conformance::check_type_errors(root);
+        rcore_fs::conformance::check_io(root);
+        rcore_fs::conformance::check_btime(root);
+    };
+    check(&root);
+    // the same once replayed from the log
+    let before = tree(&fs)?;
+    fs.sync()?;
+    drop((file, dir, root));
+    drop(fs);
+    let fs = LogFS::open(device)?;
+    assert_eq!(tree(&fs)?, before);
+    check(&fs.root_inode());
+    Ok(())
+}
@@ -1,36 +1,108 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
+// a fs mounted here must not take the kernel down by a bug of the mount tree
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::panic,
+        clippy::unimplemented,
+        clippy::unreachable,
+        clippy::todo
+    )
+)]
 
 extern crate alloc;
 #[macro_use]
 extern crate log;
 
 use alloc::{
+    borrow::Cow,
     boxed::Box,
     collections::BTreeMap,
     string::String,
     sync::{Arc, Weak},
+    vec,
+    vec::Vec,
 };
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{any::Any, future::Future, pin::Pin};
+use rcore_fs::dev::CacheHint;
 use rcore_fs::vfs::*;
-use spin::RwLock;
+use rcore_fs::{fs_instrument, fs_span, fs_try};
+use spin::{Mutex, RwLock};
 
+use self::negative::NegativeCache;
+pub use self::watch::*;
+
+mod negative;
 #[cfg(test)]
 mod tests;
+mod watch;
 
 /// The filesystem on which all the other filesystems are mounted
 pub struct MountFS {
     /// The inner file system
     inner: Arc<dyn FileSystem>,
     /// All mounted children file systems
-    mountpoints: RwLock<BTreeMap<INodeId, Arc<MountFS>>>,
+    mountpoints: RwLock<BTreeMap<InodeKey, Arc<MountFS>>>,
     /// The mount point of this file system
     self_mountpoint: Option<Arc<MNode>>,
     /// Weak reference to self
     self_ref: Weak<MountFS>,
+    /// Change notification registry for INodes in this file system
+    watcher: Watcher,
+    /// Generation of directories, bumped when their entries change
+    dir_generations: RwLock<BTreeMap<INodeId, u16>>,
+    /// Names found missing, see `set_negative_cache()`
+    negative: Mutex<NegativeCache>,
+    /// Where the next `sync_partial()` starts among the inner fs and the
+    /// mounted ones
+    sync_cursor: AtomicUsize,
+    /// see `FileSystem::instance_id()`
+    instance_id: u64,
 }
 
 type INodeId = usize;
 
+/// Position in a directory, see `MNode::readdir()`
+pub type DirCookie = u64;
+
+/// Number of entries read at a time by `MNode::readdir_all()`
+const READDIR_BATCH: usize = 64;
+
+/// Entry returned by `MNode::readdir()`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    /// Position of this entry. "." and ".." are always at 0 and 1.
+    pub cookie: DirCookie,
+    pub name: String,
+}
+
+/// A file system mounted on `MountFS`
+#[derive(Debug, Clone)]
+pub struct MountInfo {
+    /// INode id of the mount point
+    pub inode_id: INodeId,
+    /// Identity of the mounted volume
+    pub volume: Option<VolumeInfo>,
+    /// Features of the mounted fs
+    pub capabilities: FsCapabilities,
+    /// `FileSystem::instance_id()` of the mount, as named by
+    /// `FsError::PartialSync`
+    pub instance_id: u64,
+}
+
+/// Outcome of `MountFS::sync_report()`
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Number of file systems synced, this one and the mounted ones
+    pub synced: usize,
+    /// `FileSystem::instance_id()` of the mount of each fs failing to sync,
+    /// with its error
+    pub failed: Vec<(u64, FsError)>,
+}
+
 /// INode for `MountFS`
 pub struct MNode {
     /// The inner INode
@@ -49,101 +121,498 @@ impl MountFS {
             mountpoints: RwLock::new(BTreeMap::new()),
             self_mountpoint: None,
             self_ref: Weak::default(),
+            watcher: Watcher::new(),
+            dir_generations: RwLock::new(BTreeMap::new()),
+            negative: Mutex::new(NegativeCache::default()),
+            sync_cursor: AtomicUsize::new(0),
+            instance_id: new_instance_id(),
         }
         .wrap()
     }
 
     /// Wrap pure `MountFS` with `Arc<..>`.
     /// Used in constructors.
-    fn wrap(self) -> Arc<Self> {
-        // Create an Arc, make a Weak from it, then put it into the struct.
-        // It's a little tricky.
-        let fs = Arc::new(self);
-        let weak = Arc::downgrade(&fs);
-        let ptr = Arc::into_raw(fs) as *mut Self;
-        unsafe {
-            (*ptr).self_ref = weak;
-            Arc::from_raw(ptr)
-        }
+    fn wrap(mut self) -> Arc<Self> {
+        Arc::new_cyclic(|weak| {
+            self.self_ref = weak.clone();
+            self
+        })
+    }
+
+    /// The `Arc` this fs is in
+    #[allow(clippy::unwrap_used)]
+    fn this(&self) -> Arc<Self> {
+        // only made by `wrap()` in an `Arc`, alive while `self` is borrowed
+        self.self_ref.upgrade().unwrap()
     }
 
     /// Strong type version of `root_inode`
     pub fn mountpoint_root_inode(&self) -> Arc<MNode> {
         MNode {
             inode: self.inner.root_inode(),
-            vfs: self.self_ref.upgrade().unwrap(),
+            vfs: self.this(),
             self_ref: Weak::default(),
         }
         .wrap()
     }
+
+    /// List the file systems mounted directly on this one
+    pub fn mounts(&self) -> Vec<MountInfo> {
+        self.mountpoints
+            .read()
+            .iter()
+            .map(|(key, fs)| MountInfo {
+                inode_id: key.inode,
+                volume: fs.volume_info(),
+                capabilities: fs.capabilities(),
+                instance_id: fs.instance_id,
+            })
+            .collect()
+    }
+
+    /// Sync this fs and all those mounted on it. A failing one does not
+    /// stop the others from being synced, unlike with most errors.
+    pub fn sync_report(&self) -> SyncReport {
+        let mut report = SyncReport::default();
+        self.sync_into(&mut report);
+        report
+    }
+
+    fn sync_into(&self, report: &mut SyncReport) {
+        match self.inner.sync() {
+            Ok(()) => report.synced += 1,
+            Err(err) => report.failed.push((self.instance_id, err)),
+        }
+        // not locked while syncing, which may be long
+        let children: Vec<_> = self.mountpoints.read().values().cloned().collect();
+        for child in children {
+            child.sync_into(report);
+        }
+    }
+
+    /// Watch INode `inode_id` of this file system for events in `mask`
+    pub fn watch(&self, inode_id: usize, mask: EventMask) -> WatchId {
+        self.watcher.watch(inode_id, mask)
+    }
+
+    /// Remove a watch
+    pub fn unwatch(&self, watch: WatchId) -> Result<()> {
+        if self.watcher.unwatch(watch) {
+            Ok(())
+        } else {
+            Err(FsError::InvalidParam)
+        }
+    }
+
+    /// Take at most `max` pending events in order
+    pub fn take_events(&self, max: usize) -> Vec<FsEvent> {
+        self.watcher.take_events(max)
+    }
+
+    /// Set the max number of queued events
+    pub fn set_event_queue_capacity(&self, capacity: usize) {
+        self.watcher.set_capacity(capacity)
+    }
+
+    /// Print the tree of file systems mounted from this one, each with the
+    /// path of its mount point and its `FsInfo`. Mounts under the same fs
+    /// are sorted by path.
+    #[cfg(any(test, feature = "debug-dump"))]
+    pub fn dump_mounts(&self, out: &mut dyn core::fmt::Write) -> Result<()> {
+        let path = match &self.self_mountpoint {
+            Some(mountpoint) => mountpoint.path(),
+            None => Ok(String::from("/")),
+        };
+        self.dump_mounts_at(out, path, 0)
+            .map_err(|_| FsError::InvalidParam)
+    }
+
+    #[cfg(any(test, feature = "debug-dump"))]
+    fn dump_mounts_at(
+        &self,
+        out: &mut dyn core::fmt::Write,
+        path: Result<String>,
+        depth: usize,
+    ) -> core::fmt::Result {
+        let indent = depth * 2;
+        match path {
+            Ok(path) => write!(out, "{:indent$}{}", "", path)?,
+            Err(err) => write!(out, "{:indent$}? ({:?})", "", err)?,
+        }
+        let info = self.inner.info();
+        writeln!(
+            out,
+            " (bsize {}, blocks {}, bfree {}, files {}, ffree {}, namemax {})",
+            info.bsize, info.blocks, info.bfree, info.files, info.ffree, info.namemax
+        )?;
+        let mut mounts: Vec<_> = self
+            .mountpoints
+            .read()
+            .values()
+            // only the root fs, which is mounted nowhere, has no mount point
+            .filter_map(|fs| Some((fs.self_mountpoint.as_ref()?.path(), fs.clone())))
+            .collect();
+        mounts.sort_by(|(a, _), (b, _)| a.as_ref().ok().cmp(&b.as_ref().ok()));
+        for (path, fs) in mounts {
+            fs.dump_mounts_at(out, path, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    /// `FsInfo` of the fs containing `path`, from the root of this one,
+    /// see `MNode::fs_info()`. Symlinks are not followed.
+    pub fn info_at(&self, path: &str) -> Result<FsInfo> {
+        Ok(self.mountpoint_root_inode().lookup(path)?.fs_info())
+    }
+
+    /// `FsInfo` of this fs and all those mounted on it, summed, e.g. for a
+    /// dashboard of the whole tree.
+    ///
+    /// Block counts of fs of different block sizes do not add up, so they
+    /// are converted to bytes and then to blocks of the smallest `frsize`
+    /// in the tree, which `bsize` and `frsize` report. `namemax` and
+    /// `linkmax` are the smallest ones not 0. Those of fs without blocks,
+    /// e.g. in memory, are 0, as is their `frsize`.
+    pub fn total_info(&self) -> FsInfo {
+        let mut all = Vec::new();
+        self.collect_info(&mut all);
+        let unit = all
+            .iter()
+            .map(|info| info.frsize)
+            .filter(|&frsize| frsize != 0)
+            .min()
+            .unwrap_or(1);
+        let blocks = |info: &FsInfo, count: usize| {
+            (count as u128 * info.frsize as u128 / unit as u128) as usize
+        };
+        let mut total = FsInfo {
+            bsize: unit,
+            frsize: unit,
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            namemax: 0,
+            linkmax: 0,
+        };
+        let min_known = |a: usize, b: usize| match (a, b) {
+            (0, b) => b,
+            (a, 0) => a,
+            (a, b) => a.min(b),
+        };
+        for info in all.iter() {
+            total.blocks = total.blocks.saturating_add(blocks(info, info.blocks));
+            total.bfree = total.bfree.saturating_add(blocks(info, info.bfree));
+            total.bavail = total.bavail.saturating_add(blocks(info, info.bavail));
+            total.files = total.files.saturating_add(info.files);
+            total.ffree = total.ffree.saturating_add(info.ffree);
+            total.namemax = min_known(total.namemax, info.namemax);
+            total.linkmax = min_known(total.linkmax, info.linkmax);
+        }
+        total
+    }
+
+    fn collect_info(&self, all: &mut Vec<FsInfo>) {
+        all.push(self.inner.info());
+        let children: Vec<_> = self.mountpoints.read().values().cloned().collect();
+        for child in children {
+            child.collect_info(all);
+        }
+    }
+
+    fn dir_generation(&self, inode_id: INodeId) -> u16 {
+        self.dir_generations
+            .read()
+            .get(&inode_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Remember up to `capacity` names `find()` missed in the dirs of this
+    /// fs, so that looking them up again does not reach the inner fs.
+    /// 0, the default, disables it. File systems mounted later start with
+    /// the same capacity.
+    ///
+    /// A miss is forgotten when the entries of its dir change through this
+    /// `MountFS`, or when its dir shows another `INode::change_cookie()`.
+    /// Changes made by other means, e.g. directly on an inner fs not
+    /// counting them, are not seen: call `invalidate_negative()` for them.
+    pub fn set_negative_cache(&self, capacity: usize) {
+        self.negative.lock().set_capacity(capacity);
+    }
+
+    /// Forget the names found missing in `dir`, an INode of this fs
+    pub fn invalidate_negative(&self, dir: &Arc<dyn INode>) {
+        self.negative.lock().invalidate(dir.ino_key());
+    }
+
+    /// Is a fs mounted at `key`, or is it the root of a mounted fs? Then it
+    /// can not be linked, moved or removed.
+    fn is_mount_busy(&self, key: InodeKey) -> bool {
+        let mountpoints = self.mountpoints.read();
+        mountpoints.contains_key(&key)
+            || mountpoints
+                .values()
+                .any(|fs| fs.inner.root_inode().ino_key() == key)
+            || (self.self_mountpoint.is_some() && self.inner.root_inode().ino_key() == key)
+    }
+
+    /// Fail with `Busy` if `name` in `dir`, of this fs, is a mount point
+    fn check_not_mountpoint(&self, dir: &dyn INode, name: &str) -> Result<()> {
+        match dir.unwrap_inner().find(name) {
+            Ok(inode) if self.mountpoints.read().contains_key(&inode.ino_key()) => {
+                Err(FsError::Busy)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Invalidate cookies of directory `inode_id`
+    fn dir_changed(&self, inode_id: INodeId) {
+        let mut generations = self.dir_generations.write();
+        let generation = generations.entry(inode_id).or_insert(0);
+        *generation = generation.wrapping_add(1);
+    }
 }
 
 impl MNode {
     /// Wrap pure `INode` with `Arc<..>`.
     /// Used in constructors.
-    fn wrap(self) -> Arc<Self> {
-        // Create an Arc, make a Weak from it, then put it into the struct.
-        // It's a little tricky.
-        let inode = Arc::new(self);
-        let weak = Arc::downgrade(&inode);
-        let ptr = Arc::into_raw(inode) as *mut Self;
-        unsafe {
-            (*ptr).self_ref = weak;
-            Arc::from_raw(ptr)
+    fn wrap(mut self) -> Arc<Self> {
+        Arc::new_cyclic(|weak| {
+            self.self_ref = weak.clone();
+            self
+        })
+    }
+
+    /// The `Arc` this INode is in
+    #[allow(clippy::unwrap_used)]
+    fn this(&self) -> Arc<Self> {
+        // only made by `wrap()` in an `Arc`, alive while `self` is borrowed
+        self.self_ref.upgrade().unwrap()
+    }
+
+    /// Unmount the fs mounted at this INode and return it, after its
+    /// `FileSystem::prepare_unmount()`, which may fail, e.g. with a broken
+    /// device, and only leaves the storage not cleanly unmounted. Files open
+    /// in it are left working on it. `Busy` if fs are mounted on it in turn.
+    pub fn umount(&self) -> Result<Arc<MountFS>> {
+        fs_span!("umount", fs = self.vfs.instance_id);
+        let key = self.inode.ino_key();
+        let mut mountpoints = self.vfs.mountpoints.write();
+        match mountpoints.get(&key) {
+            None => return Err(FsError::InvalidParam),
+            Some(fs) if !fs.mountpoints.read().is_empty() => return Err(FsError::Busy),
+            Some(fs) => {
+                if let Err(err) = fs.inner.prepare_unmount() {
+                    warn!("mountfs: failed to prepare unmount: {:?}", err);
+                }
+            }
         }
+        self.vfs.negative.lock().invalidate(key);
+        mountpoints.remove(&key).ok_or(FsError::InvalidParam)
     }
 
     /// Mount file system `fs` at this INode
     pub fn mount(&self, fs: Arc<dyn FileSystem>) -> Result<Arc<MountFS>> {
+        fs_span!(
+            "mount",
+            fs = self.vfs.instance_id,
+            mounted = fs.instance_id()
+        );
         let metadata = self.inode.metadata()?;
         if metadata.type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
+        if metadata.nlinks == 0 {
+            return Err(FsError::DirRemoved);
+        }
         let new_fs = MountFS {
             inner: fs,
             mountpoints: RwLock::new(BTreeMap::new()),
-            self_mountpoint: Some(self.self_ref.upgrade().unwrap()),
+            self_mountpoint: Some(self.this()),
             self_ref: Weak::default(),
+            watcher: Watcher::new(),
+            dir_generations: RwLock::new(BTreeMap::new()),
+            negative: Mutex::new(NegativeCache::default()),
+            sync_cursor: AtomicUsize::new(0),
+            instance_id: new_instance_id(),
         }
         .wrap();
-        self.vfs
-            .mountpoints
-            .write()
-            .insert(metadata.inode, new_fs.clone());
+        let capacity = self.vfs.negative.lock().capacity();
+        new_fs.set_negative_cache(capacity);
+        let key = self.inode.ino_key();
+        self.vfs.negative.lock().invalidate(key);
+        self.vfs.mountpoints.write().insert(key, new_fs.clone());
         Ok(new_fs)
     }
 
     /// Get the root INode of the mounted fs at here.
     /// Return self if no mounted fs.
+    ///
+    /// The result belongs to the `MountFS` of the mounted fs, and fs mounted
+    /// on its root are resolved as well.
     fn overlaid_inode(&self) -> Arc<MNode> {
-        let inode_id = self.metadata().unwrap().inode;
-        if let Some(sub_vfs) = self.vfs.mountpoints.read().get(&inode_id) {
-            sub_vfs.mountpoint_root_inode()
-        } else {
-            self.self_ref.upgrade().unwrap()
+        let mut inode = self.this();
+        loop {
+            let key = inode.inode.ino_key();
+            let sub_vfs = inode.vfs.mountpoints.read().get(&key).cloned();
+            match sub_vfs {
+                Some(sub_vfs) => inode = sub_vfs.mountpoint_root_inode(),
+                None => return inode,
+            }
         }
     }
 
     /// Is the root INode of its FS?
     fn is_mountpoint_root(&self) -> bool {
-        self.inode.fs().root_inode().metadata().unwrap().inode
-            == self.inode.metadata().unwrap().inode
+        match self.inode.fs() {
+            Ok(fs) => is_same_inode(&fs.root_inode(), &self.inode),
+            Err(_) => false,
+        }
     }
 
-    /// Strong type version of `create()`
+    /// `target` of a move from this dir, or the root of the fs mounted at
+    /// it, if any
+    fn overlaid_target(&self, target: &Arc<dyn INode>) -> Arc<dyn INode> {
+        let mounted = self.vfs.mountpoints.read().get(&target.ino_key()).cloned();
+        match mounted {
+            Some(fs) => fs.mountpoint_root_inode().overlaid_inode(),
+            None => target.clone(),
+        }
+    }
+
+    /// Strong type version of `create()`.
+    ///
+    /// Like the other changes of entries, it goes to the root of the fs
+    /// mounted at this INode, if any, never to the dir hidden under it.
     pub fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<Self>> {
-        Ok(MNode {
-            inode: self.inode.create(name, type_, mode)?,
+        self.create2(name, type_, mode, 0)
+    }
+
+    /// Strong type version of `create2()`
+    pub fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<Self>> {
+        let dir = self.overlaid_inode();
+        let inode = fs_try!(
+            dir.inode.create2(name, type_, mode, data),
+            ErrorContext::new("create").name(name)
+        );
+        Ok(dir.created(name, inode))
+    }
+
+    /// Strong type version of `create3()`
+    pub fn create3(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+        ctx: &CreateContext,
+    ) -> Result<Arc<Self>> {
+        let dir = self.overlaid_inode();
+        let inode = fs_try!(
+            dir.inode.create3(name, type_, mode, data, ctx),
+            ErrorContext::new("create").name(name)
+        );
+        Ok(dir.created(name, inode))
+    }
+
+    /// Strong type version of `symlink()`
+    pub fn symlink(&self, name: &str, target: &str) -> Result<Arc<Self>> {
+        let dir = self.overlaid_inode();
+        let inode = fs_try!(
+            dir.inode.symlink(name, target),
+            ErrorContext::new("symlink").name(name)
+        );
+        Ok(dir.created(name, inode))
+    }
+
+    /// Strong type version of `find_or_create()`
+    ///
+    /// An existing INode is overlaid as by `find()`.
+    pub fn find_or_create(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+    ) -> Result<(Arc<Self>, bool)> {
+        if matches!(name, "" | "." | "..") {
+            let dir = self.find(false, name)?;
+            return match dir.inode.metadata()?.type_ == type_ {
+                true => Ok((dir, false)),
+                false => Err(FsError::EntryExist),
+            };
+        }
+        let dir = self.overlaid_inode();
+        let (inode, created) = fs_try!(
+            dir.inode.find_or_create(name, type_, mode),
+            ErrorContext::new("find_or_create").name(name)
+        );
+        if created {
+            return Ok((dir.created(name, inode), true));
+        }
+        let inode = MNode {
+            inode,
+            vfs: dir.vfs.clone(),
+            self_ref: Weak::default(),
+        }
+        .wrap()
+        .overlaid_inode();
+        Ok((inode, false))
+    }
+
+    /// Wrap `inode` just created as `name` in this dir
+    fn created(&self, name: &str, inode: Arc<dyn INode>) -> Arc<Self> {
+        self.dir_changed();
+        self.notify(EventKind::Created, Some(name), 0);
+        MNode {
+            inode,
             vfs: self.vfs.clone(),
             self_ref: Weak::default(),
         }
-        .wrap())
+        .wrap()
+    }
+
+    /// Watch this INode for events in `mask`.
+    /// Events are queued on the `MountFS` this INode belongs to.
+    pub fn watch(&self, mask: EventMask) -> Result<WatchId> {
+        let inode_id = self.inode.metadata()?.inode;
+        Ok(self.vfs.watch(inode_id, mask))
+    }
+
+    /// Invalidate cookies and misses of this directory
+    fn dir_changed(&self) {
+        self.vfs.negative.lock().invalidate(self.inode.ino_key());
+        if let Ok(metadata) = self.inode.metadata() {
+            self.vfs.dir_changed(metadata.inode);
+        }
+    }
+
+    /// Queue an event for watches on this INode
+    fn notify(&self, kind: EventKind, name: Option<&str>, cookie: u32) {
+        if let Ok(metadata) = self.inode.metadata() {
+            self.vfs.watcher.notify(metadata.inode, kind, name, cookie);
+        }
     }
 
     /// Strong type version of `find()`
+    ///
+    /// The result is always overlaid, i.e. a mount point is resolved to the
+    /// root of the fs mounted there, owned by the `MountFS` of that fs.
     pub fn find(&self, root: bool, name: &str) -> Result<Arc<Self>> {
+        // "." and ".." are answered here, but only by dirs
+        if matches!(name, "" | "." | "..") && self.inode.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
         match name {
-            "" | "." => Ok(self.self_ref.upgrade().unwrap()),
+            "" | "." => Ok(self.this()),
             ".." => {
                 // Going Up
                 // We need to check these things:
@@ -152,30 +621,38 @@ impl MNode {
                 //    thus requires falling back to parent of original_mountpoint?
                 // TODO: check going up.
                 if root {
-                    Ok(self.self_ref.upgrade().unwrap())
+                    Ok(self.this())
                 } else if self.is_mountpoint_root() {
                     // Here is mountpoint.
                     match &self.vfs.self_mountpoint {
-                        Some(inode) => inode.find(root, ".."),
+                        // the landing INode may be a mount point as well
+                        Some(inode) => Ok(inode.find(root, "..")?.overlaid_inode()),
                         // root fs
-                        None => Ok(self.self_ref.upgrade().unwrap()),
+                        None => Ok(self.this()),
                     }
                 } else {
                     // Not trespassing filesystem border. Parent and myself in the same filesystem.
+                    // Going up is handled by the filesystem. A better API?
+                    let inode =
+                        fs_try!(self.inode.find(name), ErrorContext::new("find").name(name));
                     Ok(MNode {
-                        inode: self.inode.find(name)?, // Going up is handled by the filesystem. A better API?
+                        inode,
                         vfs: self.vfs.clone(),
                         self_ref: Weak::default(),
                     }
-                    .wrap())
+                    .wrap()
+                    .overlaid_inode())
                 }
             }
             _ => {
                 // Going down may trespass the filesystem border.
-                // An INode replacement is required here.
+                // An INode replacement is required here, and the child
+                // belongs to the fs of the replacement.
+                let dir = self.overlaid_inode();
+                let inode = fs_try!(dir.find_inner(name), ErrorContext::new("find").name(name));
                 Ok(MNode {
-                    inode: self.overlaid_inode().inode.find(name)?,
-                    vfs: self.vfs.clone(),
+                    inode,
+                    vfs: dir.vfs.clone(),
                     self_ref: Weak::default(),
                 }
                 .wrap()
@@ -184,18 +661,250 @@ impl MNode {
         }
     }
 
+    /// Find `name` in the inner INode, unless known missing, see
+    /// `MountFS::set_negative_cache()`. Misses are kept by the name as the
+    /// inner fs compares it, see `FileSystem::name_ops()`.
+    fn find_inner(&self, name: &str) -> Result<Arc<dyn INode>> {
+        let key = self.inode.ino_key();
+        let name_ops = self.vfs.inner.name_ops();
+        let normalized = match name_ops.as_ref() {
+            Some(name_ops) if name != "." && name != ".." => name_ops.normalize(name),
+            _ => Cow::Borrowed(name),
+        };
+        // read before the lookup, so that a change during it is seen later
+        let cookie = self.inode.change_cookie();
+        let generation = {
+            let mut negative = self.vfs.negative.lock();
+            if negative.contains(key, &normalized, cookie) {
+                return Err(FsError::EntryNotFound);
+            }
+            negative.generation()
+        };
+        let result = self.inode.find(name);
+        if let Err(FsError::EntryNotFound) = result.as_ref().map_err(FsError::root_cause) {
+            self.vfs
+                .negative
+                .lock()
+                .insert(key, &normalized, generation, cookie);
+        }
+        result
+    }
+
+    /// Read at most `max` entries from position `cookie`.
+    /// Use `cookie + 1` of the last entry to continue.
+    ///
+    /// "." and ".." always come first at 0 and 1, whether or not the inner
+    /// fs lists them. Entries are never skipped silently: if this directory
+    /// has changed since `cookie` was returned, the read fails with
+    /// `InvalidParam` and should be restarted from 0.
+    pub fn readdir(&self, cookie: DirCookie, max: usize) -> Result<Vec<DirEntry>> {
+        let metadata = self.inode.metadata()?;
+        if metadata.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        // Changes through this MountFS bump the generation, the others
+        // the change cookie of the inner fs, or if it does not count them
+        // (e.g. `DevINode::add()`) are caught by the size of directory.
+        let generation = self.vfs.dir_generation(metadata.inode) as u64;
+        let generation = generation.wrapping_add(self.inode.change_cookie());
+        let stamp = (generation as u32) << 16 | metadata.size as u16 as u32;
+        let mut index = cookie as u32 as usize;
+        // 2 is the first entry after dots, valid for any state
+        if cookie > 2 && (cookie >> 32) as u32 != stamp {
+            return Err(FsError::InvalidParam);
+        }
+        let inner_dots = matches!(self.inode.get_entry(0), Ok(name) if name == ".")
+            && matches!(self.inode.get_entry(1), Ok(name) if name == "..");
+        let mut entries = Vec::new();
+        while entries.len() < max {
+            let position = index;
+            index += 1;
+            let name = match position {
+                0 => String::from("."),
+                1 => String::from(".."),
+                _ => {
+                    let inner_index = if inner_dots { position } else { position - 2 };
+                    match self.inode.get_entry(inner_index) {
+                        Ok(name) => name,
+                        Err(FsError::EntryNotFound) => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+            };
+            let cookie = match position {
+                0 | 1 => position as u64,
+                // the inner fs lists dots in another place
+                _ if name == "." || name == ".." => continue,
+                _ => (stamp as u64) << 32 | position as u64,
+            };
+            entries.push(DirEntry { cookie, name });
+        }
+        Ok(entries)
+    }
+
+    /// Read names of all entries, restarting if the directory changes meanwhile
+    pub fn readdir_all(&self) -> Result<Vec<String>> {
+        'restart: loop {
+            let mut names = Vec::new();
+            let mut cookie = 0;
+            loop {
+                let entries = match self.readdir(cookie, READDIR_BATCH) {
+                    Ok(entries) => entries,
+                    Err(FsError::InvalidParam) if cookie != 0 => continue 'restart,
+                    Err(err) => return Err(err),
+                };
+                match entries.last() {
+                    Some(last) => cookie = last.cookie + 1,
+                    None => return Ok(names),
+                }
+                names.extend(entries.into_iter().map(|entry| entry.name));
+            }
+        }
+    }
+
+    /// Sync this INode and those below it, with the file systems mounted
+    /// below it, but not the rest of its fs.
+    ///
+    /// The dirty INodes below it are found by `dirty_inodes_under()` of the
+    /// inner fs, or if it can not tell, every INode below it is synced. The
+    /// file systems mounted below it are synced whole, as is this one if
+    /// this is its root. As with `MountFS::sync_report()`, a failing fs does
+    /// not stop the others.
+    pub fn sync_subtree_report(&self) -> SyncReport {
+        let mut report = SyncReport::default();
+        let this = self.overlaid_inode();
+        if this.is_mountpoint_root() {
+            this.vfs.sync_into(&mut report);
+            return report;
+        }
+        let dirty = this
+            .inode
+            .metadata()
+            .and_then(|metadata| this.vfs.inner.dirty_inodes_under(metadata.inode));
+        let result = match dirty {
+            // all are tried, the first error is kept
+            Ok(inodes) => inodes
+                .iter()
+                .map(|inode| inode.sync_all())
+                .fold(Ok(()), Result::and),
+            Err(FsError::Unsupported) => this.sync_inner_below(),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => report.synced += 1,
+            Err(err) => report.failed.push((this.vfs.instance_id, err)),
+        }
+        // not locked while syncing, which may be long
+        let children: Vec<_> = this.vfs.mountpoints.read().values().cloned().collect();
+        for child in children {
+            let mountpoint = match child.self_mountpoint.as_ref() {
+                Some(mountpoint) => mountpoint,
+                // mounted on this fs, so at a mount point
+                None => continue,
+            };
+            if this.is_above(&mountpoint.inode) {
+                child.sync_into(&mut report);
+            }
+        }
+        report
+    }
+
+    /// `sync_all()` the inner INode and all below it, not crossing mount
+    /// points. All are tried, the first error is returned.
+    fn sync_inner_below(&self) -> Result<()> {
+        let mut result = self.inode.sync_all();
+        let mut dirs = vec![self.inode.clone()];
+        while let Some(dir) = dirs.pop() {
+            if dir.metadata()?.type_ != FileType::Dir {
+                continue;
+            }
+            for index in 0.. {
+                let name = match dir.get_entry(index) {
+                    Ok(name) => name,
+                    Err(err) if *err.root_cause() == FsError::EntryNotFound => break,
+                    Err(err) => return Err(err),
+                };
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let inode = dir.find(&name)?;
+                result = result.and(inode.sync_all());
+                dirs.push(inode);
+            }
+        }
+        result
+    }
+
+    /// Whether `inode` of the inner fs is below this dir
+    fn is_above(&self, inode: &Arc<dyn INode>) -> bool {
+        let mut inode = inode.clone();
+        while let Ok(parent) = inode.find("..") {
+            if is_same_inode(&parent, &self.inode) {
+                return true;
+            }
+            // ".." of the root is itself
+            if is_same_inode(&parent, &inode) {
+                return false;
+            }
+            inode = parent;
+        }
+        false
+    }
+
+    /// Info of the fs containing this INode: that of the fs mounted here if
+    /// any, else that of its own, not of the root of the mount tree
+    pub fn fs_info(&self) -> FsInfo {
+        self.overlaid_inode().vfs.inner.info()
+    }
+
+    /// Strong type version of `lookup()`, without following symlinks
+    pub fn lookup(&self, path: &str) -> Result<Arc<Self>> {
+        let mut inode = self.this();
+        for name in path.split('/') {
+            inode = inode.find(false, name)?;
+        }
+        Ok(inode)
+    }
+
+    /// Path from the root of the whole tree, like "/mnt/usb"
+    #[cfg(any(test, feature = "debug-dump"))]
+    fn path(&self) -> Result<String> {
+        let mut names = Vec::new();
+        let mut inode = self.overlaid_inode();
+        loop {
+            let parent = inode.find(false, "..")?;
+            if Arc::ptr_eq(&parent.vfs, &inode.vfs) && is_same_inode(&parent.inode, &inode.inode) {
+                break;
+            }
+            names.push(parent.find_name_by_child(&inode)?);
+            inode = parent;
+        }
+        let mut path = String::new();
+        for name in names.iter().rev() {
+            path.push('/');
+            path.push_str(name);
+        }
+        if path.is_empty() {
+            path.push('/');
+        }
+        Ok(path)
+    }
+
     /// If `child` is a child of `self`, return its name.
     pub fn find_name_by_child(&self, child: &Arc<MNode>) -> Result<String> {
+        let dir = self.overlaid_inode();
+        let child = child.overlaid_inode();
         for index in 0.. {
-            let name = self.inode.get_entry(index)?;
+            let name = dir.inode.get_entry(index)?;
             match name.as_ref() {
                 "." | ".." => {}
                 _ => {
-                    let queryback = self.find(false, &name)?.overlaid_inode();
-                    // TODO: mountpoint check!
-                    debug!("checking name {}", name);
+                    let queryback = dir.find(false, &name)?;
+                    trace!("checking name {}", name);
+                    // the same fs may be mounted twice, the id of the inner
+                    // fs alone can not tell the mounts apart
                     if Arc::ptr_eq(&queryback.vfs, &child.vfs)
-                        && queryback.inode.metadata()?.inode == child.inode.metadata()?.inode
+                        && is_same_inode(&queryback.inode, &child.inode)
                     {
                         return Ok(name);
                     }
@@ -207,12 +916,14 @@ impl MNode {
 }
 
 impl FileSystem for MountFS {
+    /// Sync all the fs, see `sync_report()`. `PartialSync` if any failed.
     fn sync(&self) -> Result<()> {
-        self.inner.sync()?;
-        for mount_fs in self.mountpoints.read().values() {
-            mount_fs.sync()?;
+        let report = self.sync_report();
+        if report.failed.is_empty() {
+            Ok(())
+        } else {
+            Err(FsError::PartialSync(report.failed))
         }
-        Ok(())
     }
 
     fn root_inode(&self) -> Arc<dyn INode> {
@@ -225,6 +936,77 @@ impl FileSystem for MountFS {
     fn info(&self) -> FsInfo {
         self.inner.info()
     }
+
+    fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+
+    fn volume_info(&self) -> Option<VolumeInfo> {
+        self.inner.volume_info()
+    }
+
+    /// Features of the fs at the root, see `mounts()` for the others
+    fn capabilities(&self) -> FsCapabilities {
+        self.inner.capabilities()
+    }
+
+    /// Names of the fs at the root
+    fn name_ops(&self) -> Option<Arc<dyn NameOps>> {
+        self.inner.name_ops()
+    }
+
+    /// Share `max_blocks` among the inner fs and the mounted ones, in turn
+    /// from a different one each call so that none is starved. As with
+    /// `sync()`, a failing fs does not stop the others, `PartialSync` then
+    /// names all those which failed.
+    fn sync_partial(&self, max_blocks: usize) -> Result<SyncProgress> {
+        let mut all: Vec<(u64, Arc<dyn FileSystem>)> = Vec::new();
+        all.push((self.instance_id, self.inner.clone()));
+        for mount_fs in self.mountpoints.read().values() {
+            all.push((mount_fs.instance_id, mount_fs.clone()));
+        }
+        let start = self.sync_cursor.fetch_add(1, Ordering::Relaxed) % all.len();
+        let mut progress = SyncProgress {
+            completed: true,
+            ..SyncProgress::default()
+        };
+        let mut failed = Vec::new();
+        for (id, fs) in all[start..].iter().chain(all[..start].iter()) {
+            let budget = max_blocks.saturating_sub(progress.written_blocks);
+            if budget == 0 {
+                // not synced in this call, may have been in the last ones
+                progress.completed = false;
+                continue;
+            }
+            let fs_progress = match fs.sync_partial(budget) {
+                Ok(fs_progress) => fs_progress,
+                Err(FsError::PartialSync(errors)) => {
+                    failed.extend(errors);
+                    continue;
+                }
+                Err(err) => {
+                    failed.push((*id, err));
+                    continue;
+                }
+            };
+            progress.written_blocks += fs_progress.written_blocks;
+            progress.remaining_dirty_blocks += fs_progress.remaining_dirty_blocks;
+            progress.completed &= fs_progress.completed;
+        }
+        if !failed.is_empty() {
+            return Err(FsError::PartialSync(failed));
+        }
+        Ok(progress)
+    }
+
+    /// Prepare the mounted fs, then the inner one
+    fn prepare_unmount(&self) -> Result<()> {
+        let mounted: Vec<_> = self.mountpoints.read().values().cloned().collect();
+        for fs in mounted {
+            fs.prepare_unmount()?;
+        }
+        self.inner.prepare_unmount()
+    }
 }
 
 // unwrap `MNode` and forward methods to inner except `find()`
@@ -234,7 +1016,35 @@ impl INode for MNode {
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
-        self.inode.write_at(offset, buf)
+        let len = self.inode.write_at(offset, buf)?;
+        if len > 0 {
+            self.notify(EventKind::Modified, None, 0);
+        }
+        Ok(len)
+    }
+
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inode.read_at_direct(offset, buf)
+    }
+
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let len = self.inode.write_at_direct(offset, buf)?;
+        if len > 0 {
+            self.notify(EventKind::Modified, None, 0);
+        }
+        Ok(len)
+    }
+
+    fn read_at_with(&self, offset: usize, buf: &mut [u8], ctx: &TaskContext) -> Result<usize> {
+        self.inode.read_at_with(offset, buf, ctx)
+    }
+
+    fn write_at_with(&self, offset: usize, buf: &[u8], ctx: &TaskContext) -> Result<usize> {
+        let len = self.inode.write_at_with(offset, buf, ctx)?;
+        if len > 0 {
+            self.notify(EventKind::Modified, None, 0);
+        }
+        Ok(len)
     }
 
     fn poll(&self) -> Result<PollStatus> {
@@ -245,15 +1055,25 @@ impl INode for MNode {
     fn async_poll<'a>(
         &'a self,
     ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
-        self.inode.async_poll()
+        fs_instrument!(
+            self.inode.async_poll(),
+            "async_poll",
+            fs = self.vfs.instance_id
+        )
     }
 
     fn metadata(&self) -> Result<Metadata> {
         self.inode.metadata()
     }
 
+    fn metadata_partial(&self, mask: MetadataMask) -> Result<PartialMetadata> {
+        self.inode.metadata_partial(mask)
+    }
+
     fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
-        self.inode.set_metadata(metadata)
+        self.inode.set_metadata(metadata)?;
+        self.notify(EventKind::AttrChanged, None, 0);
+        Ok(())
     }
 
     fn sync_all(&self) -> Result<()> {
@@ -264,43 +1084,231 @@ impl INode for MNode {
         self.inode.sync_data()
     }
 
+    /// See `sync_subtree_report()`, `PartialSync` if any fs failed
+    fn sync_subtree(&self) -> Result<()> {
+        let report = self.sync_subtree_report();
+        if report.failed.is_empty() {
+            Ok(())
+        } else {
+            Err(FsError::PartialSync(report.failed))
+        }
+    }
+
     fn resize(&self, len: usize) -> Result<()> {
-        self.inode.resize(len)
+        self.inode.resize(len)?;
+        self.notify(EventKind::Modified, None, 0);
+        Ok(())
+    }
+
+    fn resize_with(&self, len: usize, ctx: &TaskContext) -> Result<()> {
+        self.inode.resize_with(len, ctx)?;
+        self.notify(EventKind::Modified, None, 0);
+        Ok(())
+    }
+
+    fn fallocate(&self, offset: usize, len: usize, mode: FallocateMode) -> Result<()> {
+        self.inode.fallocate(offset, len, mode)?;
+        self.notify(EventKind::Modified, None, 0);
+        Ok(())
+    }
+
+    fn get_flags(&self) -> Result<InodeFlags> {
+        self.inode.get_flags()
+    }
+
+    fn set_flags(&self, flags: InodeFlags) -> Result<()> {
+        self.inode.set_flags(flags)?;
+        self.notify(EventKind::AttrChanged, None, 0);
+        Ok(())
     }
 
     fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
         Ok(self.create(name, type_, mode)?)
     }
 
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        Ok(self.create2(name, type_, mode, data)?)
+    }
+
+    fn create3(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+        ctx: &CreateContext,
+    ) -> Result<Arc<dyn INode>> {
+        Ok(self.create3(name, type_, mode, data, ctx)?)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>> {
+        Ok(self.symlink(name, target)?)
+    }
+
+    fn create_batch(&self, entries: &[CreateSpec]) -> Result<Vec<Arc<dyn INode>>> {
+        let dir = self.overlaid_inode();
+        let inodes = dir.inode.create_batch(entries)?;
+        dir.dir_changed();
+        for entry in entries {
+            dir.notify(EventKind::Created, Some(entry.name), 0);
+        }
+        Ok(inodes
+            .into_iter()
+            .map(|inode| {
+                MNode {
+                    inode,
+                    vfs: dir.vfs.clone(),
+                    self_ref: Weak::default(),
+                }
+                .wrap() as Arc<dyn INode>
+            })
+            .collect())
+    }
+
+    fn find_or_create(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+    ) -> Result<(Arc<dyn INode>, bool)> {
+        let (inode, created) = self.find_or_create(name, type_, mode)?;
+        Ok((inode, created))
+    }
+
+    /// Into the fs mounted here, if any. `Busy` to link a mount point or
+    /// the root of a mounted fs.
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
-        self.inode.link(name, other)
+        let dir = self.overlaid_inode();
+        let key = other.ino_key();
+        if self.vfs.is_mount_busy(key) || dir.vfs.is_mount_busy(key) {
+            return Err(FsError::Busy);
+        }
+        dir.inode.link(name, other)?;
+        dir.dir_changed();
+        Ok(())
     }
 
+    /// From the fs mounted here, if any. `Busy` if `name` is a mount point.
     fn unlink(&self, name: &str) -> Result<()> {
-        let inode_id = self.inode.find(name)?.metadata()?.inode;
+        self.unlink_bytes(name.as_bytes())
+    }
+
+    /// Watchers see the name escaped by `escape_name()`
+    fn unlink_bytes(&self, name: &[u8]) -> Result<()> {
+        let dir = self.overlaid_inode();
+        let escaped = escape_name(name);
+        let inode = fs_try!(
+            dir.inode.find_bytes(name),
+            ErrorContext::new("unlink").name(&escaped)
+        );
         // target INode is being mounted
-        if self.vfs.mountpoints.read().contains_key(&inode_id) {
+        if dir.vfs.mountpoints.read().contains_key(&inode.ino_key()) {
             return Err(FsError::Busy);
         }
-        self.inode.unlink(name)
+        let inode_id = inode.metadata()?.inode;
+        fs_try!(
+            dir.inode.unlink_bytes(name),
+            ErrorContext::new("unlink").name(&escaped)
+        );
+        dir.dir_changed();
+        dir.notify(EventKind::Deleted, Some(&escaped), 0);
+        // the INode itself is gone: report it and drop its watches
+        if inode.metadata().map_or(true, |m| m.nlinks == 0) {
+            let watcher = &dir.vfs.watcher;
+            watcher.notify(inode_id, EventKind::Deleted, None, 0);
+            watcher.unwatch_inode(inode_id);
+            dir.vfs.dir_generations.write().remove(&inode_id);
+        }
+        Ok(())
     }
 
+    /// Between the fs mounted at either dir, if any. `Busy` if `old_name`
+    /// or `new_name` is a mount point.
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
-        self.inode.move_(old_name, target, new_name)
+        let dir = self.overlaid_inode();
+        let target = self.overlaid_target(target);
+        dir.vfs.check_not_mountpoint(&*dir.inode, old_name)?;
+        dir.vfs.check_not_mountpoint(&*target, new_name)?;
+        // a removed dir is never a destination, whatever the fs below does
+        let target_metadata = target.metadata()?;
+        if target_metadata.type_ == FileType::Dir && target_metadata.nlinks == 0 {
+            return Err(FsError::DirRemoved);
+        }
+        dir.inode.move_(old_name, &target, new_name)?;
+        dir.dir_changed();
+        let cookie = dir.vfs.watcher.new_cookie();
+        dir.notify(EventKind::MovedFrom, Some(old_name), cookie);
+        dir.vfs.negative.lock().invalidate(target.ino_key());
+        if let Ok(metadata) = target.metadata() {
+            dir.vfs.dir_changed(metadata.inode);
+            let watcher = &dir.vfs.watcher;
+            watcher.notify(metadata.inode, EventKind::MovedTo, Some(new_name), cookie);
+        }
+        Ok(())
     }
 
     fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
         Ok(self.find(false, name)?)
     }
 
+    /// Names not UTF-8 are never dots, nor kept by the negative cache
+    fn find_bytes(&self, name: &[u8]) -> Result<Arc<dyn INode>> {
+        let name = match core::str::from_utf8(name) {
+            Ok(name) => return Ok(self.find(false, name)?),
+            Err(_) => name,
+        };
+        let dir = self.overlaid_inode();
+        let inode = fs_try!(
+            dir.inode.find_bytes(name),
+            ErrorContext::new("find").name(&escape_name(name))
+        );
+        Ok(MNode {
+            inode,
+            vfs: dir.vfs.clone(),
+            self_ref: Weak::default(),
+        }
+        .wrap()
+        .overlaid_inode())
+    }
+
+    fn change_cookie(&self) -> u64 {
+        self.inode.change_cookie()
+    }
+
+    fn data_version(&self) -> u64 {
+        self.inode.data_version()
+    }
+
     fn get_entry(&self, id: usize) -> Result<String> {
         self.inode.get_entry(id)
     }
 
+    fn get_entry_bytes(&self, id: usize) -> Result<Vec<u8>> {
+        self.inode.get_entry_bytes(id)
+    }
+
     fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
         self.inode.get_entry_with_metadata(id)
     }
 
+    fn get_entry_with_metadata_partial(
+        &self,
+        id: usize,
+        mask: MetadataMask,
+    ) -> Result<(PartialMetadata, String)> {
+        self.inode.get_entry_with_metadata_partial(id, mask)
+    }
+
+    fn next_entries(&self, cursor: &mut DirCursor, out: &mut [DirEntrySlot]) -> Result<usize> {
+        self.inode.next_entries(cursor, out)
+    }
+
     fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
         self.inode.io_control(cmd, data)
     }
@@ -309,8 +1317,28 @@ impl INode for MNode {
         self.inode.mmap(area)
     }
 
-    fn fs(&self) -> Arc<dyn FileSystem> {
-        self.vfs.clone()
+    fn open_hook(&self, exclusive: bool) -> Result<OpenGuard> {
+        self.inode.open_hook(exclusive)
+    }
+
+    fn pin(&self) -> Result<PinGuard> {
+        self.inode.pin()
+    }
+
+    fn set_cache_hint(&self, hint: CacheHint) {
+        self.inode.set_cache_hint(hint)
+    }
+
+    fn fs(&self) -> Result<Arc<dyn FileSystem>> {
+        Ok(self.vfs.clone())
+    }
+
+    fn fs_info(&self) -> Result<FsInfo> {
+        Ok(MNode::fs_info(self))
+    }
+
+    fn wrapped(&self) -> Option<&Arc<dyn INode>> {
+        Some(&self.inode)
     }
 
     fn as_any_ref(&self) -> &dyn Any {
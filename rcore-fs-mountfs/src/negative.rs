@@ -0,0 +1,122 @@
+//! Cache of names known to be missing, see `MountFS::set_negative_cache()`
+
+use alloc::{collections::BTreeMap, string::String};
+use rcore_fs::vfs::InodeKey;
+
+/// Misses of `MNode::find()` by dir and name, evicted least recently used
+/// first. Each miss is kept with the `change_cookie()` of its dir, and is
+/// dropped once the dir shows another one.
+struct Miss {
+    last_use: u64,
+    /// `change_cookie()` of the dir when found missing
+    cookie: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct NegativeCache {
+    /// Max number of misses kept, 0 to disable the cache
+    capacity: usize,
+    /// Last use and cookie of each miss, by dir then name
+    misses: BTreeMap<InodeKey, BTreeMap<String, Miss>>,
+    /// Each miss by its last use, oldest first
+    lru: BTreeMap<u64, (InodeKey, String)>,
+    /// Source of the last use times
+    clock: u64,
+    /// Bumped by each invalidation, so that a miss found before one is
+    /// never recorded after it
+    generation: u64,
+}
+
+impl NegativeCache {
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Keep at most `capacity` misses, evicting the oldest
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.lru.len() > capacity {
+            self.evict_oldest();
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Whether `name` is known missing from `dir`, whose cookie is now
+    /// `cookie`. A hit counts as a use.
+    pub fn contains(&mut self, dir: InodeKey, name: &str, cookie: u64) -> bool {
+        let now = self.clock + 1;
+        let miss = match self
+            .misses
+            .get_mut(&dir)
+            .and_then(|names| names.get_mut(name))
+        {
+            Some(miss) => miss,
+            None => return false,
+        };
+        if miss.cookie != cookie {
+            // changed by other means than this cache sees
+            let last_use = miss.last_use;
+            self.remove(last_use);
+            return false;
+        }
+        let last_use = core::mem::replace(&mut miss.last_use, now);
+        self.clock = now;
+        if let Some(miss) = self.lru.remove(&last_use) {
+            self.lru.insert(now, miss);
+        }
+        true
+    }
+
+    /// Record `name` missing from `dir`, as found by a lookup started at
+    /// `generation` with the dir at `cookie`. Ignored if the cache was
+    /// invalidated since.
+    pub fn insert(&mut self, dir: InodeKey, name: &str, generation: u64, cookie: u64) {
+        if self.capacity == 0 || generation != self.generation || self.contains(dir, name, cookie) {
+            return;
+        }
+        if self.lru.len() == self.capacity {
+            self.evict_oldest();
+        }
+        self.clock += 1;
+        let names = self.misses.entry(dir).or_default();
+        let miss = Miss {
+            last_use: self.clock,
+            cookie,
+        };
+        names.insert(String::from(name), miss);
+        self.lru.insert(self.clock, (dir, String::from(name)));
+    }
+
+    /// Forget the misses of `dir`, whose entries have changed
+    pub fn invalidate(&mut self, dir: InodeKey) {
+        self.generation += 1;
+        if let Some(names) = self.misses.remove(&dir) {
+            for miss in names.values() {
+                self.lru.remove(&miss.last_use);
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((&last_use, _)) = self.lru.first_key_value() {
+            self.remove(last_use);
+        }
+    }
+
+    /// Forget the miss last used at `last_use`
+    fn remove(&mut self, last_use: u64) {
+        let (dir, name) = match self.lru.remove(&last_use) {
+            Some(miss) => miss,
+            None => return,
+        };
+        if let Some(names) = self.misses.get_mut(&dir) {
+            names.remove(&name);
+            if names.is_empty() {
+                self.misses.remove(&dir);
+            }
+        }
+    }
+}
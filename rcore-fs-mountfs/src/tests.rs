@@ -16,6 +16,35 @@ fn mount() {
     assert!((root as Arc<dyn INode>).lookup("mnt/file").is_ok());
 }
 
+#[test]
+fn find_or_create_mount_point() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let (mnt, created) = root.find_or_create("mnt", FileType::Dir, 0o777).unwrap();
+    assert!(created);
+    let ramfs = RamFS::new();
+    ramfs
+        .root_inode()
+        .create("file", FileType::File, 0o777)
+        .unwrap();
+    mnt.mount(ramfs).unwrap();
+
+    // the fs mounted there, as by `find()`
+    let (mnt, created) = root.find_or_create("mnt", FileType::Dir, 0o777).unwrap();
+    assert!(!created);
+    assert!(mnt.find(false, "file").is_ok());
+    let (_, created) = mnt.find_or_create("new", FileType::File, 0o644).unwrap();
+    assert!(created);
+    let found = (root as Arc<dyn INode>).lookup("mnt/new").unwrap();
+    let (file, created) = mnt.find_or_create("new", FileType::File, 0o644).unwrap();
+    assert!(!created);
+    assert_eq!(file.ino_key(), found.ino_key());
+    assert_eq!(
+        mnt.find_or_create("file", FileType::Dir, 0o777).err(),
+        Some(FsError::EntryExist)
+    );
+}
+
 #[test]
 fn remove_busy() {
     let rootfs = MountFS::new(RamFS::new());
@@ -25,3 +54,1707 @@ fn remove_busy() {
     mnt.mount(ramfs).unwrap();
     assert_eq!(root.unlink("mnt"), Err(FsError::Busy));
 }
+
+#[test]
+fn watch_events() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let dir_watch = root.watch(EventMask::ALL).unwrap();
+
+    let file = root.create("a", FileType::File, 0o777).unwrap();
+    let file_watch = file.watch(EventMask::MODIFY | EventMask::DELETE).unwrap();
+    file.write_at(0, b"hello").unwrap();
+    let root_dyn: Arc<dyn INode> = root.clone();
+    root.move_("a", &root_dyn, "b").unwrap();
+    root.unlink("b").unwrap();
+
+    let events = rootfs.take_events(usize::MAX);
+    let kinds: Vec<_> = events
+        .iter()
+        .map(|e| (e.watch, e.kind, e.name.as_deref()))
+        .collect();
+    assert_eq!(
+        kinds,
+        [
+            (dir_watch, EventKind::Created, Some("a")),
+            (file_watch, EventKind::Modified, None),
+            (dir_watch, EventKind::MovedFrom, Some("a")),
+            (dir_watch, EventKind::MovedTo, Some("b")),
+            (dir_watch, EventKind::Deleted, Some("b")),
+            (file_watch, EventKind::Deleted, None),
+        ]
+    );
+    assert_ne!(events[2].cookie, 0);
+    assert_eq!(events[2].cookie, events[3].cookie);
+
+    // watches on the removed file are dropped
+    assert_eq!(rootfs.unwatch(file_watch), Err(FsError::InvalidParam));
+    assert!(rootfs.unwatch(dir_watch).is_ok());
+    assert!(rootfs.take_events(usize::MAX).is_empty());
+}
+
+#[test]
+fn watch_overflow() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    root.watch(EventMask::CREATE).unwrap();
+    rootfs.set_event_queue_capacity(2);
+    for name in &["1", "2", "3", "4"] {
+        root.create(name, FileType::File, 0o777).unwrap();
+    }
+    let kinds: Vec<_> = rootfs
+        .take_events(usize::MAX)
+        .iter()
+        .map(|e| e.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        [EventKind::Created, EventKind::Created, EventKind::Overflow]
+    );
+}
+
+/// Read `dir` one entry at a time, removing `b` after the first real entry.
+/// Return the names read before and after the removal.
+fn readdir_with_unlink(dir: &MNode, remove_b: impl FnOnce()) -> (Vec<String>, Vec<String>) {
+    let mut names = Vec::new();
+    let mut cookie = 0;
+    for _ in 0..3 {
+        let entries = dir.readdir(cookie, 1).unwrap();
+        assert_eq!(entries.len(), 1);
+        names.push(entries[0].name.clone());
+        cookie = entries[0].cookie + 1;
+    }
+    remove_b();
+    // the cookie is stale now
+    assert_eq!(dir.readdir(cookie, 1), Err(FsError::InvalidParam));
+    (names, dir.readdir_all().unwrap())
+}
+
+#[test]
+fn readdir_cookies() {
+    use rcore_fs_devfs::{special::NullINode, DevFS};
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap();
+    let sfs = MountFS::new(sfs);
+    let sfs_root = sfs.mountpoint_root_inode();
+    for name in &["a", "b", "c"] {
+        sfs_root.create(name, FileType::File, 0o777).unwrap();
+    }
+    let sfs_result = readdir_with_unlink(&sfs_root, || sfs_root.unlink("b").unwrap());
+
+    let devfs = DevFS::new();
+    for name in &["a", "b", "c"] {
+        devfs.root().add(name, Arc::new(NullINode::new())).unwrap();
+    }
+    let devfs_root = MountFS::new(devfs.clone()).mountpoint_root_inode();
+    let devfs_result = readdir_with_unlink(&devfs_root, || devfs.root().remove("b").unwrap());
+
+    assert_eq!(sfs_result.0, [".", "..", "a"]);
+    assert_eq!(sfs_result.1, [".", "..", "a", "c"]);
+    assert_eq!(sfs_result, devfs_result);
+
+    // dots keep their cookies whatever happens to the directory
+    let dots = sfs_root.readdir(0, 2).unwrap();
+    assert_eq!((dots[0].cookie, dots[1].cookie), (0, 1));
+    assert_eq!(sfs_root.readdir(1, 1).unwrap()[0].name, "..");
+}
+
+#[test]
+fn device_numbers() {
+    use rcore_fs_devfs::{special::NullINode, DevFS};
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let rdev = make_rdev(4, 64);
+    let file = tempfile::tempfile().unwrap();
+    let device = Arc::new(Mutex::new(file.try_clone().unwrap()));
+    let sfs = SimpleFileSystem::create(device, 32 * 4096).unwrap();
+    sfs.root_inode()
+        .create2("tty", FileType::CharDevice, 0o666, rdev)
+        .unwrap();
+    sfs.sync().unwrap();
+    drop(sfs);
+
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let sfs = SimpleFileSystem::open(Arc::new(Mutex::new(file))).unwrap();
+    root.create("sfs", FileType::Dir, 0o777)
+        .unwrap()
+        .mount(sfs)
+        .unwrap();
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add_with_perm("tty", Arc::new(NullINode::new()), 0o620, Some(rdev))
+        .unwrap();
+    root.create("dev", FileType::Dir, 0o777)
+        .unwrap()
+        .mount(devfs)
+        .unwrap();
+
+    let root: Arc<dyn INode> = root;
+    for path in &["sfs/tty", "dev/tty"] {
+        let metadata = root.lookup(path).unwrap().metadata().unwrap();
+        assert_eq!(metadata.type_, FileType::CharDevice);
+        assert_eq!(unpack_rdev(metadata.rdev), (4, 64), "{}", path);
+    }
+    assert_eq!(
+        root.lookup("dev/tty").unwrap().metadata().unwrap().mode,
+        0o620
+    );
+    // not a device
+    assert_eq!(root.lookup("sfs").unwrap().metadata().unwrap().rdev, 0);
+}
+
+#[test]
+fn find_across_nested_mounts() {
+    // R: /m/r_file, A mounted on /m: /x/a_file, B mounted on /m/x: /b_file
+    let r = MountFS::new(RamFS::new());
+    let r_root = r.mountpoint_root_inode();
+    let m = r_root.create("m", FileType::Dir, 0o777).unwrap();
+    m.create("r_file", FileType::File, 0o777).unwrap();
+    let a = m.mount(RamFS::new()).unwrap();
+    let a_root = a.mountpoint_root_inode();
+    let x = a_root.create("x", FileType::Dir, 0o777).unwrap();
+    a_root.create("a_file", FileType::File, 0o777).unwrap();
+    let b = x.mount(RamFS::new()).unwrap();
+    let b_root = b.mountpoint_root_inode();
+    b_root.create("b_file", FileType::File, 0o777).unwrap();
+
+    let id = |node: &Arc<MNode>| node.metadata().unwrap().inode;
+    let a_file = a_root.find(false, "a_file").unwrap();
+    let b_file = b_root.find(false, "b_file").unwrap();
+    // m and x are mount points, as returned by `create()`
+    let starts = [
+        ("r", &r_root),
+        ("m", &m),
+        ("a", &a_root),
+        ("x", &x),
+        ("b", &b_root),
+    ];
+    let cases: &[(&str, &str, &Arc<MNode>, &Arc<MountFS>)] = &[
+        ("r", "m", &a_root, &a),
+        ("r", "m/x", &b_root, &b),
+        ("r", "m/x/b_file", &b_file, &b),
+        ("r", "m/x/..", &a_root, &a),
+        ("r", "m/x/../..", &r_root, &r),
+        ("r", "m/x/../../m/x", &b_root, &b),
+        ("r", "m/./x/./b_file", &b_file, &b),
+        ("m", "x", &b_root, &b),
+        ("m", "a_file", &a_file, &a),
+        ("m", "x/../../m/x", &b_root, &b),
+        ("m", "x/../a_file", &a_file, &a),
+        ("m", "..", &r_root, &r),
+        ("a", "x/b_file", &b_file, &b),
+        ("a", "..", &r_root, &r),
+        ("x", "b_file", &b_file, &b),
+        ("x", ".", &x, &a),
+        ("x", "../a_file", &a_file, &a),
+        ("x", "../../m/x/..", &a_root, &a),
+        ("b", "..", &a_root, &a),
+        ("b", "../..", &r_root, &r),
+        ("b", "../../m/x/b_file", &b_file, &b),
+    ];
+    for &(start, path, inode, fs) in cases {
+        let (_, start_node) = starts.iter().find(|(name, _)| *name == start).unwrap();
+        let found = start_node.lookup(path).unwrap();
+        assert_eq!(id(&found), id(inode), "{}: {}", start, path);
+        assert!(Arc::ptr_eq(&found.vfs, fs), "{}: {}", start, path);
+    }
+
+    assert_eq!(r_root.find_name_by_child(&m).unwrap(), "m");
+    assert_eq!(m.find_name_by_child(&x).unwrap(), "x");
+    assert_eq!(a_root.find_name_by_child(&b_root).unwrap(), "x");
+}
+
+/// Caller memory in a vector, addressed by offset
+struct VecMemory(spin::Mutex<Vec<u8>>);
+
+impl ioctl::IoctlMemory for VecMemory {
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        let memory = self.0.lock();
+        let src = memory
+            .get(addr..addr + buf.len())
+            .ok_or(FsError::InvalidParam)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+    fn write(&self, addr: usize, buf: &[u8]) -> Result<()> {
+        let mut memory = self.0.lock();
+        let dst = memory
+            .get_mut(addr..addr + buf.len())
+            .ok_or(FsError::InvalidParam)?;
+        dst.copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+/// Device with a `u32` register, set and read by ioctl
+struct RegisterDevice {
+    ioctl: ioctl::IoctlHandler,
+}
+
+const SET_REGISTER: u32 = ioctl::iow(b'r', 1, 4);
+const GET_REGISTER: u32 = ioctl::ior(b'r', 2, 8);
+
+impl RegisterDevice {
+    fn new(memory: Arc<VecMemory>) -> Self {
+        let register = Arc::new(spin::Mutex::new(0u32));
+        let register1 = register.clone();
+        let ioctl = ioctl::IoctlHandler::new()
+            .with_memory(memory)
+            .on_write(SET_REGISTER, move |value: &[u8; 4]| {
+                *register1.lock() = u32::from_ne_bytes(*value);
+                Ok(1)
+            })
+            .on_read(GET_REGISTER, move || {
+                // as u64
+                Ok((*register.lock() as u64).to_ne_bytes())
+            });
+        RegisterDevice { ioctl }
+    }
+}
+
+impl INode for RegisterDevice {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus::default())
+    }
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.ioctl.dispatch(cmd, data)
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[test]
+fn io_control_through_sfs() {
+    use rcore_fs_devfs::{special::NullINode, DevFS};
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let memory = Arc::new(VecMemory(spin::Mutex::new(vec![0; 16])));
+    let rdev = make_rdev(10, 1);
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap();
+    sfs.root_inode()
+        .create2("reg", FileType::CharDevice, 0o666, rdev)
+        .unwrap();
+    sfs.root_inode()
+        .create("file", FileType::File, 0o666)
+        .unwrap();
+    sfs.new_device_inode(rdev, Arc::new(RegisterDevice::new(memory.clone())));
+
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    root.create("sfs", FileType::Dir, 0o777)
+        .unwrap()
+        .mount(sfs)
+        .unwrap();
+    let devfs = DevFS::new();
+    let null = NullINode::with_memory(memory.clone());
+    devfs.root().add("null", Arc::new(null)).unwrap();
+    root.create("dev", FileType::Dir, 0o777)
+        .unwrap()
+        .mount(devfs)
+        .unwrap();
+    let root: Arc<dyn INode> = root;
+
+    let reg = root.lookup("sfs/reg").unwrap();
+    memory.0.lock()[4..8].copy_from_slice(&0x1234u32.to_ne_bytes());
+    assert_eq!(reg.io_control(SET_REGISTER, 4), Ok(1));
+    assert_eq!(reg.io_control(GET_REGISTER, 8), Ok(0));
+    assert_eq!(memory.0.lock()[8..16], 0x1234u64.to_ne_bytes());
+    // the accessor checks the range
+    assert_eq!(reg.io_control(GET_REGISTER, 12), Err(FsError::InvalidParam));
+
+    // known command with another size or direction
+    let get_u32 = ioctl::ior(b'r', 2, 4);
+    assert_eq!(reg.io_control(get_u32, 0), Err(FsError::InvalidParam));
+    let set_read = ioctl::ior(b'r', 1, 4);
+    assert_eq!(reg.io_control(set_read, 0), Err(FsError::InvalidParam));
+    // unknown command
+    assert_eq!(
+        reg.io_control(ioctl::io(b'r', 3), 0),
+        Err(FsError::IOCTLError)
+    );
+    assert_eq!(reg.io_control(ioctl::FIONREAD, 0), Err(FsError::IOCTLError));
+    // not a device at all
+    let file = root.lookup("sfs/file").unwrap();
+    assert_eq!(file.io_control(GET_REGISTER, 8), Err(FsError::NotSupported));
+
+    let null = root.lookup("dev/null").unwrap();
+    memory.0.lock()[0..4].copy_from_slice(&[0xff; 4]);
+    assert_eq!(null.io_control(ioctl::FIONREAD, 0), Ok(0));
+    assert_eq!(memory.0.lock()[0..4], 0i32.to_ne_bytes());
+    assert_eq!(null.io_control(ioctl::FIONBIO, 0), Ok(0));
+    assert_eq!(
+        null.io_control(ioctl::TIOCGWINSZ, 0),
+        Err(FsError::IOCTLError)
+    );
+}
+
+#[test]
+fn removed_dir_through_sfs() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap();
+    let rootfs = MountFS::new(sfs);
+    let root = rootfs.mountpoint_root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777).unwrap();
+    root.create("file", FileType::File, 0o777).unwrap();
+    root.unlink("dir").unwrap();
+
+    assert_eq!(dir.metadata().unwrap().nlinks, 0);
+    let dir_dyn: Arc<dyn INode> = dir.clone();
+    assert_eq!(dir_dyn.list().unwrap(), ["."]);
+    assert!(dir.find(false, ".").is_ok());
+    assert_eq!(dir.find(false, "..").err(), Some(FsError::DirRemoved));
+    assert_eq!(
+        dir.create("new", FileType::File, 0o777).err(),
+        Some(FsError::DirRemoved)
+    );
+    let file = root.find(false, "file").unwrap() as Arc<dyn INode>;
+    assert_eq!(dir.link("new", &file), Err(FsError::DirRemoved));
+    assert_eq!(
+        root.move_("file", &dir_dyn, "file"),
+        Err(FsError::DirRemoved)
+    );
+    assert!(root.find(false, "file").is_ok());
+    assert_eq!(dir.mount(RamFS::new()).err(), Some(FsError::DirRemoved));
+    assert!(rootfs.mounts().is_empty());
+}
+
+#[test]
+fn scoped_dir_confinement() {
+    use rcore_fs::vfs::scoped::{AbsolutePaths, ScopedDir};
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 1024 * 4096).unwrap();
+    let rootfs = MountFS::new(sfs);
+    let root = rootfs.mountpoint_root_inode();
+    let write = |dir: &dyn INode, name: &str, type_: FileType, data: &str| {
+        let inode = dir.create(name, type_, 0o777).unwrap();
+        inode.write_at(0, data.as_bytes()).unwrap();
+    };
+    write(&*root, "outside", FileType::File, "outside");
+    let etc = root.create("etc", FileType::Dir, 0o777).unwrap();
+    write(&*etc, "passwd", FileType::File, "host passwd");
+
+    let jail = root.create("jail", FileType::Dir, 0o777).unwrap();
+    let a = jail.create("a", FileType::Dir, 0o777).unwrap();
+    let b = a.create("b", FileType::Dir, 0o777).unwrap();
+    write(&*b, "file", FileType::File, "inside");
+    write(&*a, "uplink", FileType::SymLink, "../../etc");
+    let jail_etc = jail.create("etc", FileType::Dir, 0o777).unwrap();
+    write(&*jail_etc, "passwd", FileType::File, "jail passwd");
+    write(&*jail, "abs", FileType::SymLink, "/etc/passwd");
+    write(&*jail, "up", FileType::SymLink, "../outside");
+    write(&*jail, "deep", FileType::SymLink, "a/b/../../../outside");
+    write(&*jail, "chain1", FileType::SymLink, "chain2");
+    write(&*jail, "chain2", FileType::SymLink, "a/uplink/passwd");
+    write(&*jail, "slash", FileType::SymLink, "/");
+    let ramfs = RamFS::new();
+    write(&*ramfs.root_inode(), "ram", FileType::File, "ram");
+    let mnt = jail.create("mnt", FileType::Dir, 0o777).unwrap();
+    mnt.mount(ramfs).unwrap();
+
+    let read = |inode: Result<Arc<dyn INode>>| {
+        let mut buf = [0u8; 32];
+        let len = inode?.read_at(0, &mut buf)?;
+        Ok(String::from(core::str::from_utf8(&buf[..len]).unwrap()))
+    };
+    let scope: Arc<dyn INode> = ScopedDir::new(jail.clone() as Arc<dyn INode>);
+    for path in &[
+        "..",
+        "../outside",
+        "./../outside",
+        "a/../../outside",
+        "a/b/../../../outside",
+        "mnt/../../outside",
+        "/../outside",
+        "up",
+        "deep",
+        "chain1",
+        "a/uplink/passwd",
+        "slash/../outside",
+    ] {
+        assert_eq!(
+            read(scope.lookup_follow(path, 8)).err(),
+            Some(FsError::PermError),
+            "{}",
+            path
+        );
+    }
+    assert_eq!(
+        scope.fs().unwrap().root_inode().find("..").err(),
+        Some(FsError::PermError)
+    );
+    // the entry ".." of the root shows the root itself
+    let (metadata, name) = scope.get_entry_with_metadata(1).unwrap();
+    assert_eq!(name, "..");
+    assert_eq!(metadata.inode, jail.metadata().unwrap().inode);
+
+    // absolute targets start from the root of the scope
+    assert_eq!(
+        read(scope.lookup_follow("abs", 8)),
+        Ok("jail passwd".into())
+    );
+    assert_eq!(
+        read(scope.lookup_follow("/etc/passwd", 8)),
+        Ok("jail passwd".into())
+    );
+    assert_eq!(
+        read(scope.lookup_follow("slash/a/b/file", 8)),
+        Ok("inside".into())
+    );
+    let strict: Arc<dyn INode> =
+        ScopedDir::with_absolute_paths(jail.clone() as Arc<dyn INode>, AbsolutePaths::Reject);
+    assert_eq!(
+        strict.lookup_follow("abs", 8).err(),
+        Some(FsError::PermError)
+    );
+    assert_eq!(strict.lookup("/etc").err(), Some(FsError::PermError));
+    assert_eq!(
+        read(strict.lookup_follow("a/b/file", 8)),
+        Ok("inside".into())
+    );
+
+    // normal access, ".." inside, and through a mountpoint
+    let b_scoped = scope.lookup("a/b").unwrap();
+    assert_eq!(b_scoped.downcast_ref::<ScopedDir>().unwrap().depth(), 2);
+    assert_eq!(read(b_scoped.lookup("../b/file")), Ok("inside".into()));
+    assert_eq!(read(scope.lookup("mnt/ram")), Ok("ram".into()));
+    assert_eq!(read(scope.lookup("mnt/../a/b/file")), Ok("inside".into()));
+
+    // rename and move within the scope
+    let a_scoped = scope.find("a").unwrap();
+    b_scoped.move_("file", &b_scoped, "renamed").unwrap();
+    b_scoped.move_("renamed", &a_scoped, "moved").unwrap();
+    assert_eq!(read(scope.lookup("a/moved")), Ok("inside".into()));
+    let created = b_scoped.create("new", FileType::File, 0o777).unwrap();
+    assert!(created.downcast_ref::<ScopedDir>().is_some());
+    b_scoped.unlink("new").unwrap();
+
+    // nothing moves or links out of the scope
+    let moved = a_scoped.find("moved").unwrap();
+    assert_eq!(
+        a_scoped.move_("moved", &(root.clone() as Arc<dyn INode>), "moved"),
+        Err(FsError::PermError)
+    );
+    assert_eq!(
+        a_scoped.move_("moved", &(etc as Arc<dyn INode>), "moved"),
+        Err(FsError::PermError)
+    );
+    assert_eq!(
+        a_scoped.link(
+            "other",
+            &(root.clone() as Arc<dyn INode>).find("outside").unwrap()
+        ),
+        Err(FsError::PermError)
+    );
+    let other_scope: Arc<dyn INode> = ScopedDir::new(jail.clone() as Arc<dyn INode>);
+    assert_eq!(
+        a_scoped.move_("moved", &other_scope, "moved"),
+        Err(FsError::PermError)
+    );
+    a_scoped.link("linked", &moved).unwrap();
+    assert_eq!(
+        read((jail as Arc<dyn INode>).lookup("a/linked")),
+        Ok("inside".into())
+    );
+}
+
+#[test]
+fn create_with_context_through_sfs() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap();
+    let rootfs = MountFS::new(sfs);
+    let root = rootfs.mountpoint_root_inode();
+    let ctx = CreateContext {
+        uid: 1000,
+        gid: 100,
+        umask: 0o022,
+    };
+    let dir = root
+        .create3(
+            "shared",
+            FileType::Dir,
+            0o2777,
+            0,
+            &CreateContext::default(),
+        )
+        .unwrap();
+    let file = dir.create3("file", FileType::File, 0o666, 0, &ctx).unwrap();
+    let metadata = file.metadata().unwrap();
+    assert_eq!(
+        (metadata.mode, metadata.uid, metadata.gid),
+        (0o644, 1000, 0)
+    );
+    let dir: Arc<dyn INode> = dir;
+    let sub = dir.create3("sub", FileType::Dir, 0o777, 0, &ctx).unwrap();
+    let metadata = sub.metadata().unwrap();
+    assert_eq!(
+        (metadata.mode, metadata.uid, metadata.gid),
+        (0o2755, 1000, 0)
+    );
+}
+
+/// Check that `fs` does what its `capabilities()` advertise, and fails with
+/// `Unsupported` where it lacks the feature. `file` is named "file" in `dir`.
+fn check_capabilities(caps: FsCapabilities, info: FsInfo, dir: &Arc<dyn INode>) {
+    fn expect<T>(caps: FsCapabilities, cap: FsCapabilities, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(ret) => {
+                assert!(caps.contains(cap), "{:?} not in {:?}", cap, caps);
+                Some(ret)
+            }
+            Err(err) => {
+                assert!(!caps.contains(cap), "{:?} of {:?}: {:?}", cap, caps, err);
+                assert_eq!(err, FsError::Unsupported);
+                None
+            }
+        }
+    }
+    let file = dir.find("file").unwrap();
+    let id = file.metadata().unwrap().inode;
+
+    if expect(caps, FsCapabilities::HARDLINK, dir.link("link", &file)).is_some() {
+        assert_eq!(dir.find("link").unwrap().metadata().unwrap().inode, id);
+        dir.unlink("link").unwrap();
+    }
+
+    let symlink = dir.create("symlink", FileType::SymLink, 0o777);
+    if let Some(symlink) = expect(caps, FsCapabilities::SYMLINK, symlink) {
+        symlink.write_at(0, b"file").unwrap();
+        let target = dir.lookup_follow("symlink", 1).unwrap();
+        assert_eq!(target.metadata().unwrap().inode, id);
+        dir.unlink("symlink").unwrap();
+    }
+
+    let rdev = make_rdev(1, 3);
+    let dev = dir.create2("null", FileType::CharDevice, 0o666, rdev);
+    if let Some(dev) = expect(caps, FsCapabilities::DEVICE_NODES, dev) {
+        let metadata = dev.metadata().unwrap();
+        assert_eq!(
+            (metadata.type_, metadata.rdev),
+            (FileType::CharDevice, rdev)
+        );
+        dir.unlink("null").unwrap();
+    }
+
+    let moved = dir.move_("file", dir, "moved");
+    if expect(caps, FsCapabilities::RENAME, moved).is_some() {
+        assert_eq!(dir.find("moved").unwrap().metadata().unwrap().inode, id);
+        dir.move_("moved", dir, "file").unwrap();
+    }
+
+    let flags = file.set_flags(InodeFlags::APPEND_ONLY);
+    if expect(caps, FsCapabilities::INODE_FLAGS, flags).is_some() {
+        assert_eq!(file.get_flags(), Ok(InodeFlags::APPEND_ONLY));
+        file.set_flags(InodeFlags::empty()).unwrap();
+    }
+
+    let size = file.metadata().unwrap().size;
+    let reserved = file.fallocate(size, 1, FallocateMode::KEEP_SIZE);
+    if expect(caps, FsCapabilities::PREALLOC, reserved).is_some() {
+        assert_eq!(file.metadata().unwrap().size, size);
+        // drop the reservation
+        file.resize(size).unwrap();
+    }
+
+    match dir.find("FILE") {
+        Ok(found) => {
+            assert!(caps.contains(FsCapabilities::CASE_INSENSITIVE));
+            assert_eq!(found.metadata().unwrap().inode, id);
+        }
+        Err(err) => {
+            assert!(!caps.contains(FsCapabilities::CASE_INSENSITIVE));
+            assert_eq!(err, FsError::EntryNotFound);
+        }
+    }
+
+    // only fs counting their blocks show if holes take space
+    if info.blocks > 0 {
+        let bfree = dir.fs().unwrap().info().bfree;
+        file.resize(64 * info.bsize).unwrap();
+        let sparse = dir.fs().unwrap().info().bfree == bfree;
+        assert_eq!(sparse, caps.contains(FsCapabilities::SPARSE));
+        file.resize(0).unwrap();
+    }
+
+    rcore_fs::conformance::check_btime(dir);
+}
+
+#[test]
+fn capabilities() {
+    use rcore_fs_devfs::{special::NullINode, DevFS};
+    use rcore_fs_logfs::LogFS;
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let device = || Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let ramfs = RamFS::new();
+    let sfs = SimpleFileSystem::create(device(), 1024 * 4096).unwrap();
+    let logfs = LogFS::create(device(), 1024 * 4096).unwrap();
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add("file", Arc::new(NullINode::new()))
+        .unwrap();
+    let all: [(Arc<dyn FileSystem>, FsCapabilities); 4] = [
+        (
+            ramfs,
+            FsCapabilities::HARDLINK
+                | FsCapabilities::SYMLINK
+                | FsCapabilities::DEVICE_NODES
+                | FsCapabilities::RENAME,
+        ),
+        (
+            sfs,
+            FsCapabilities::HARDLINK
+                | FsCapabilities::SYMLINK
+                | FsCapabilities::DEVICE_NODES
+                | FsCapabilities::RENAME
+                | FsCapabilities::INODE_FLAGS
+                | FsCapabilities::PREALLOC
+                | FsCapabilities::BIRTH_TIME,
+        ),
+        (
+            logfs,
+            FsCapabilities::SYMLINK
+                | FsCapabilities::SPARSE
+                | FsCapabilities::DEVICE_NODES
+                | FsCapabilities::RENAME,
+        ),
+        (devfs, FsCapabilities::empty()),
+    ];
+    for (fs, caps) in all.iter() {
+        assert_eq!(fs.capabilities(), *caps);
+        let root = fs.root_inode();
+        if root.find("file").is_err() {
+            root.create("file", FileType::File, 0o666).unwrap();
+        }
+        check_capabilities(*caps, fs.info(), &root);
+    }
+
+    // the same through mounts
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    root.create("file", FileType::File, 0o666).unwrap();
+    assert_eq!(rootfs.capabilities(), all[0].1);
+    let root_dyn: Arc<dyn INode> = root.clone();
+    check_capabilities(rootfs.capabilities(), rootfs.info(), &root_dyn);
+    let mut mnts = Vec::new();
+    for (fs, _) in all.iter().skip(1) {
+        let mnt = root
+            .create(&format!("mnt{}", mnts.len()), FileType::Dir, 0o777)
+            .unwrap();
+        let inode_id = mnt.metadata().unwrap().inode;
+        let mounted = mnt.mount(fs.clone()).unwrap();
+        mnts.push((inode_id, mounted.mountpoint_root_inode()));
+    }
+    let mounts = rootfs.mounts();
+    assert_eq!(mounts.len(), 3);
+    for ((fs, caps), (inode_id, mnt)) in all.iter().skip(1).zip(mnts) {
+        let mount = mounts
+            .iter()
+            .find(|mount| mount.inode_id == inode_id)
+            .unwrap();
+        assert_eq!(mount.capabilities, *caps);
+        check_capabilities(mount.capabilities, fs.info(), &(mnt as Arc<dyn INode>));
+    }
+}
+
+#[test]
+fn sync_partial_round_robin() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mut sfs = Vec::new();
+    let mut files = Vec::new();
+    for name in ["a", "b"].iter() {
+        let device = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+        let fs = SimpleFileSystem::create(device, 1024 * 4096).unwrap();
+        let mnt = root.create(name, FileType::Dir, 0o777).unwrap();
+        let dir = mnt.mount(fs.clone()).unwrap().mountpoint_root_inode();
+        for i in 0..40 {
+            let file = dir
+                .create(&format!("f{}", i), FileType::File, 0o644)
+                .unwrap();
+            files.push(file);
+        }
+        fs.sync().unwrap();
+        sfs.push(fs);
+    }
+    for file in files.iter() {
+        file.write_at(0, b"data").unwrap();
+    }
+    let remaining = |fs: &Arc<SimpleFileSystem>| fs.sync_partial(0).unwrap().remaining_dirty_blocks;
+    let before: Vec<_> = sfs.iter().map(remaining).collect();
+    assert!(before.iter().all(|&n| n >= 40));
+
+    // the inner fs and the two mounted ones each start a call
+    for _ in 0..3 {
+        let progress = rootfs.sync_partial(16).unwrap();
+        assert!(progress.written_blocks <= 16);
+        assert!(!progress.completed);
+    }
+    for (fs, before) in sfs.iter().zip(before) {
+        assert!(remaining(fs) < before);
+    }
+    while !rootfs.sync_partial(16).unwrap().completed {}
+    assert!(sfs.iter().all(|fs| remaining(fs) == 0));
+}
+
+#[test]
+fn inode_identity() {
+    use rcore_fs::vfs::scoped::ScopedDir;
+    use rcore_fs_devfs::special::PermINode;
+    use rcore_fs_sfs::{INodeImpl, SimpleFileSystem};
+    use std::sync::Mutex;
+
+    let device = || Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let sfs = SimpleFileSystem::create(device(), 1024 * 4096).unwrap();
+    let file = sfs
+        .root_inode()
+        .create("file", FileType::File, 0o644)
+        .unwrap();
+
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let a = root.create("a", FileType::Dir, 0o777).unwrap();
+    let b = root.create("b", FileType::Dir, 0o777).unwrap();
+    a.mount(sfs.clone()).unwrap();
+    // the same fs again, like a bind mount
+    b.mount(sfs.clone()).unwrap();
+    let via_a: Arc<dyn INode> = root.lookup("a/file").unwrap();
+    let via_b: Arc<dyn INode> = root.lookup("b/file").unwrap();
+    let perm: Arc<dyn INode> = Arc::new(PermINode::new(via_a.clone(), 0o600, None));
+    let scoped = ScopedDir::new(root.clone()).find("b").unwrap();
+    let scoped = scoped.find("file").unwrap();
+    for inode in [&via_a, &via_b, &perm, &scoped].iter() {
+        assert_eq!(inode.ino_key(), file.ino_key());
+        assert!(is_same_inode(inode, &file));
+        assert!(inode.unwrap_inner().downcast_ref::<INodeImpl>().is_some());
+    }
+    assert_eq!(file.ino_key().fs, sfs.instance_id());
+
+    // root INodes of two SFS have the same number, not the same key
+    let other = SimpleFileSystem::create(device(), 1024 * 4096).unwrap();
+    let (root1, root2) = (sfs.root_inode(), other.root_inode());
+    assert_eq!(
+        root1.metadata().unwrap().inode,
+        root2.metadata().unwrap().inode
+    );
+    assert!(!is_same_inode(&root1, &root2));
+    let dir_a: Arc<dyn INode> = root.lookup("a").unwrap();
+    assert!(!is_same_inode(&via_a, &dir_a));
+}
+
+#[test]
+fn dump_mounts() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let b = mnt.create("b", FileType::Dir, 0o777).unwrap();
+    let a = mnt.create("a", FileType::Dir, 0o777).unwrap();
+    let b_fs = b.mount(RamFS::new()).unwrap();
+    a.mount(RamFS::new()).unwrap();
+    let sub = b_fs
+        .mountpoint_root_inode()
+        .create("sub", FileType::Dir, 0o777)
+        .unwrap();
+    let sub_fs = sub.mount(RamFS::new()).unwrap();
+
+    let ramfs = " (bsize 0, blocks 0, bfree 0, files 0, ffree 0, namemax 0)";
+    let mut dump = String::new();
+    rootfs.dump_mounts(&mut dump).unwrap();
+    let expected: Vec<_> = ["/", "  /mnt/a", "  /mnt/b", "    /mnt/b/sub"]
+        .iter()
+        .map(|path| format!("{}{}\n", path, ramfs))
+        .collect();
+    assert_eq!(dump, expected.concat());
+
+    let mut dump = String::new();
+    sub_fs.dump_mounts(&mut dump).unwrap();
+    assert_eq!(dump, format!("/mnt/b/sub{}\n", ramfs));
+}
+
+#[test]
+fn metadata_dev() {
+    use rcore_fs_devfs::{special::NullINode, DevFS};
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let new_sfs = || {
+        let file = tempfile::tempfile().unwrap();
+        SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap()
+    };
+    let sfs = new_sfs();
+    let rootfs = MountFS::new(sfs.clone());
+    let root = rootfs.mountpoint_root_inode();
+    let file = root.create("file", FileType::File, 0o644).unwrap();
+    root.link("hard", &(file.clone() as Arc<dyn INode>))
+        .unwrap();
+    let a = root.create("a", FileType::Dir, 0o755).unwrap();
+    let a_fs = a.mount(new_sfs()).unwrap();
+    a_fs.mountpoint_root_inode()
+        .create("b", FileType::Dir, 0o755)
+        .unwrap()
+        .mount(new_sfs())
+        .unwrap();
+    let devfs = DevFS::new();
+    devfs
+        .root()
+        .add("null", Arc::new(NullINode::new()))
+        .unwrap();
+    let dev_dir = root.create("dev", FileType::Dir, 0o755).unwrap();
+    dev_dir.mount(devfs.clone()).unwrap();
+    let root: Arc<dyn INode> = root;
+
+    let dev_of = |path: &str| root.lookup(path).unwrap().metadata().unwrap().dev;
+    // the same file through MountFS and the raw fs
+    let raw = sfs.root_inode().find("file").unwrap().metadata().unwrap();
+    assert_eq!(dev_of("file"), raw.dev);
+    assert_eq!(raw.dev, sfs.instance_id() as usize);
+    // hard links
+    let hard = root.lookup("hard").unwrap().metadata().unwrap();
+    assert_eq!((hard.dev, hard.inode), (raw.dev, raw.inode));
+    // one per fs
+    let devs = [dev_of("/"), dev_of("a"), dev_of("a/b"), dev_of("dev")];
+    let unique: std::collections::BTreeSet<_> = devs.iter().collect();
+    assert_eq!(unique.len(), 4);
+    assert_eq!(dev_of("dev"), devfs.instance_id() as usize);
+
+    // like `find -xdev`, do not descend into dirs of another dev
+    fn walk(dir: &Arc<dyn INode>, dev: usize, path: &str, found: &mut Vec<String>) {
+        for name in dir.list().unwrap() {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let child = dir.find(&name).unwrap();
+            let metadata = child.metadata().unwrap();
+            let path = format!("{}/{}", path, name);
+            found.push(path.clone());
+            if metadata.type_ == FileType::Dir && metadata.dev == dev {
+                walk(&child, dev, &path, found);
+            }
+        }
+    }
+    let mut found = Vec::new();
+    walk(&root, dev_of("/"), "", &mut found);
+    found.sort();
+    assert_eq!(found, ["/a", "/dev", "/file", "/hard"]);
+    let a: Arc<dyn INode> = root.lookup("a").unwrap();
+    let mut found = Vec::new();
+    walk(&a, dev_of("a"), "/a", &mut found);
+    assert_eq!(found, ["/a/b"]);
+}
+
+#[test]
+fn type_errors() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap();
+    let root: Arc<dyn INode> = MountFS::new(sfs).mountpoint_root_inode();
+    root.create("file", FileType::File, 0o644).unwrap();
+    root.create("dir", FileType::Dir, 0o755)
+        .unwrap()
+        .create("empty", FileType::File, 0o644)
+        .unwrap();
+    root.create("link", FileType::SymLink, 0o777)
+        .unwrap()
+        .write_at(0, b"file")
+        .unwrap();
+    rcore_fs::conformance::check_type_errors(&root);
+}
+
+#[test]
+fn io_contract() {
+    use rcore_fs_logfs::LogFS;
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let device = || Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+    let all: [Arc<dyn FileSystem>; 3] = [
+        RamFS::new(),
+        SimpleFileSystem::create(device(), 1024 * 4096).unwrap(),
+        LogFS::create(device(), 1024 * 4096).unwrap(),
+    ];
+    for fs in all {
+        let root: Arc<dyn INode> = MountFS::new(fs.clone()).mountpoint_root_inode();
+        root.create("file", FileType::File, 0o644)
+            .unwrap()
+            .write_at(0, &[1; 5000])
+            .unwrap();
+        root.create("dir", FileType::Dir, 0o755)
+            .unwrap()
+            .create("empty", FileType::File, 0o644)
+            .unwrap();
+        rcore_fs::conformance::check_io(&fs.root_inode());
+        rcore_fs::conformance::check_io(&root);
+    }
+}
+
+/// A device counting its syncs, which fail while `failing` is set, and
+/// recording where it is written
+struct SyncCountingDevice {
+    file: std::sync::Mutex<std::fs::File>,
+    syncs: AtomicUsize,
+    failing: core::sync::atomic::AtomicBool,
+    writes: std::sync::Mutex<Vec<usize>>,
+}
+
+impl SyncCountingDevice {
+    fn new() -> Arc<Self> {
+        Arc::new(SyncCountingDevice {
+            file: std::sync::Mutex::new(tempfile::tempfile().unwrap()),
+            syncs: AtomicUsize::new(0),
+            failing: core::sync::atomic::AtomicBool::new(false),
+            writes: std::sync::Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl rcore_fs::dev::Device for SyncCountingDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> rcore_fs::dev::Result<usize> {
+        self.file.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> rcore_fs::dev::Result<usize> {
+        self.writes.lock().unwrap().push(offset);
+        self.file.write_at(offset, buf)
+    }
+    fn sync(&self) -> rcore_fs::dev::Result<()> {
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        if self.failing.load(Ordering::Relaxed) {
+            return Err(rcore_fs::dev::DevError::IoError);
+        }
+        self.file.sync()
+    }
+}
+
+#[test]
+fn sync_despite_failed_mount() {
+    use rcore_fs_sfs::SimpleFileSystem;
+
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mut devices = Vec::new();
+    let mut mounts = Vec::new();
+    for name in ["a", "b", "c"].iter() {
+        let device = SyncCountingDevice::new();
+        let fs = SimpleFileSystem::create(device.clone(), 64 * 4096).unwrap();
+        let mnt = root.create(name, FileType::Dir, 0o777).unwrap();
+        let mounted = mnt.mount(fs).unwrap();
+        mounted
+            .mountpoint_root_inode()
+            .create("file", FileType::File, 0o644)
+            .unwrap();
+        devices.push(device);
+        mounts.push((mnt, mounted));
+    }
+    devices[1].failing.store(true, Ordering::Relaxed);
+    let syncs = |device: &SyncCountingDevice| device.syncs.load(Ordering::Relaxed);
+    let before: Vec<_> = devices.iter().map(|device| syncs(device)).collect();
+
+    let report = rootfs.sync_report();
+    assert_eq!(report.synced, 3);
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, mounts[1].1.instance_id());
+    assert_eq!(report.failed[0].1.root_cause(), &FsError::DeviceError);
+    for (device, before) in devices.iter().zip(before.iter()) {
+        assert_eq!(syncs(device), before + 1);
+    }
+    let info = rootfs.mounts();
+    assert!(info
+        .iter()
+        .any(|m| m.instance_id == mounts[1].1.instance_id()));
+
+    match rootfs.sync() {
+        Err(FsError::PartialSync(failed)) => {
+            let ids: Vec<_> = failed.iter().map(|(id, _)| *id).collect();
+            assert_eq!(ids, [mounts[1].1.instance_id()]);
+        }
+        other => panic!("sync gave {:?}", other),
+    }
+    match rootfs.sync_partial(usize::MAX) {
+        Err(FsError::PartialSync(failed)) => assert_eq!(failed.len(), 1),
+        other => panic!("sync_partial gave {:?}", other),
+    }
+    assert_eq!(syncs(&devices[2]), before[2] + 3);
+
+    // the failed one is unmounted, still open files keep working on it
+    let file = mounts[1]
+        .1
+        .mountpoint_root_inode()
+        .find(false, "file")
+        .unwrap();
+    let failed = mounts[1].0.umount().unwrap();
+    assert_eq!(mounts[1].0.umount().err(), Some(FsError::InvalidParam));
+    assert_eq!(rootfs.sync(), Ok(()));
+    assert_eq!(file.write_at(0, b"data"), Ok(4));
+    // dropping it syncs again
+    devices[1].failing.store(false, Ordering::Relaxed);
+    drop(file);
+    drop(failed);
+    drop(mounts);
+}
+
+#[test]
+fn umount_busy() {
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    let child = mnt.mount(RamFS::new()).unwrap();
+    child
+        .mountpoint_root_inode()
+        .create("nested", FileType::Dir, 0o777)
+        .unwrap()
+        .mount(RamFS::new())
+        .unwrap();
+    assert_eq!(mnt.umount().err(), Some(FsError::Busy));
+    assert_eq!(
+        root.create("other", FileType::Dir, 0o777)
+            .unwrap()
+            .umount()
+            .err(),
+        Some(FsError::InvalidParam)
+    );
+}
+
+#[test]
+fn umount_marks_sfs_clean() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let device = Arc::new(Mutex::new(file.try_clone().unwrap()));
+    drop(SimpleFileSystem::create(device, 32 * 4096).unwrap());
+
+    let rootfs = MountFS::new(RamFS::new());
+    let mnt = rootfs
+        .mountpoint_root_inode()
+        .create("mnt", FileType::Dir, 0o777)
+        .unwrap();
+    let open = || SimpleFileSystem::open(Arc::new(Mutex::new(file.try_clone().unwrap())));
+    let sfs = open().unwrap();
+    assert!(sfs.opened_dirty());
+    mnt.mount(sfs).unwrap();
+    mnt.umount().unwrap();
+    assert!(!open().unwrap().opened_dirty());
+}
+
+/// Wraps the INodes of a fs, counting the `find()` calls reaching them
+struct CountingINode {
+    inode: Arc<dyn INode>,
+    finds: Arc<AtomicUsize>,
+}
+
+impl CountingINode {
+    fn wrap(inode: Arc<dyn INode>, finds: &Arc<AtomicUsize>) -> Arc<dyn INode> {
+        Arc::new(CountingINode {
+            inode,
+            finds: finds.clone(),
+        })
+    }
+}
+
+impl INode for CountingINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inode.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.inode.write_at(offset, buf)
+    }
+    fn poll(&self) -> Result<PollStatus> {
+        self.inode.poll()
+    }
+    fn metadata(&self) -> Result<Metadata> {
+        self.inode.metadata()
+    }
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        let inode = self.inode.create2(name, type_, mode, data)?;
+        Ok(Self::wrap(inode, &self.finds))
+    }
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.inode.unlink(name)
+    }
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        self.finds.fetch_add(1, Ordering::Relaxed);
+        Ok(Self::wrap(self.inode.find(name)?, &self.finds))
+    }
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.inode.get_entry(id)
+    }
+    fn fs(&self) -> Result<Arc<dyn FileSystem>> {
+        self.inode.fs()
+    }
+    fn wrapped(&self) -> Option<&Arc<dyn INode>> {
+        Some(&self.inode)
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A fs whose INodes are `CountingINode`s
+struct CountingFS {
+    inner: Arc<dyn FileSystem>,
+    finds: Arc<AtomicUsize>,
+}
+
+impl FileSystem for CountingFS {
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+    fn root_inode(&self) -> Arc<dyn INode> {
+        CountingINode::wrap(self.inner.root_inode(), &self.finds)
+    }
+    fn info(&self) -> FsInfo {
+        self.inner.info()
+    }
+    fn instance_id(&self) -> u64 {
+        self.inner.instance_id()
+    }
+    fn name_ops(&self) -> Option<Arc<dyn NameOps>> {
+        self.inner.name_ops()
+    }
+}
+
+/// A `MountFS` on a `CountingFS`, with the counter of finds
+fn counting_mountfs() -> (Arc<MountFS>, Arc<AtomicUsize>) {
+    let finds = Arc::new(AtomicUsize::new(0));
+    let fs = Arc::new(CountingFS {
+        inner: RamFS::new(),
+        finds: finds.clone(),
+    });
+    (MountFS::new(fs), finds)
+}
+
+#[test]
+fn negative_cache() {
+    let (fs, finds) = counting_mountfs();
+    fs.set_negative_cache(16);
+    let root = fs.mountpoint_root_inode();
+    for _ in 0..10 {
+        assert_eq!(root.find(false, "foo").err(), Some(FsError::EntryNotFound));
+    }
+    assert_eq!(finds.load(Ordering::Relaxed), 1);
+
+    // no stale miss after a create through the MountFS
+    root.create("foo", FileType::File, 0o644).unwrap();
+    root.find(false, "foo").unwrap();
+    root.unlink("foo").unwrap();
+    assert_eq!(root.find(false, "foo").err(), Some(FsError::EntryNotFound));
+
+    // the inner fs changed behind the MountFS
+    let before = finds.load(Ordering::Relaxed);
+    assert!(root.find(false, "bar").is_err());
+    fs.root_inode()
+        .wrapped()
+        .unwrap()
+        .create("bar", FileType::File, 0o644)
+        .unwrap();
+    assert!(root.find(false, "bar").is_err());
+    fs.invalidate_negative(&fs.root_inode());
+    root.find(false, "bar").unwrap();
+    assert_eq!(finds.load(Ordering::Relaxed), before + 2);
+}
+
+#[test]
+fn change_cookie_of_inner_fs() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap();
+    let inner = sfs.root_inode();
+    inner.create("a", FileType::File, 0o644).unwrap();
+    inner.create("b", FileType::File, 0o644).unwrap();
+    let fs = MountFS::new(sfs);
+    fs.set_negative_cache(16);
+    let root = fs.mountpoint_root_inode();
+
+    // a miss is dropped once the inner dir changed behind the MountFS
+    assert!(root.find(false, "bar").is_err());
+    assert!(root.find(false, "bar").is_err());
+    inner.create("bar", FileType::File, 0o644).unwrap();
+    root.find(false, "bar").unwrap();
+
+    // a rename in place keeps the size, the cookie still changes
+    let entries = root.readdir(0, 3).unwrap();
+    let cookie = entries.last().unwrap().cookie;
+    inner.move_("a", &inner, "c").unwrap();
+    assert_eq!(
+        root.readdir(cookie + 1, 8).err(),
+        Some(FsError::InvalidParam)
+    );
+}
+
+#[test]
+fn negative_cache_eviction() {
+    let (fs, finds) = counting_mountfs();
+    fs.set_negative_cache(4);
+    let root = fs.mountpoint_root_inode();
+    let miss = |name: &str| {
+        let before = finds.load(Ordering::Relaxed);
+        assert!(root.find(false, name).is_err());
+        finds.load(Ordering::Relaxed) - before
+    };
+    for name in ["a", "b", "c", "d"].iter() {
+        assert_eq!(miss(name), 1);
+    }
+    // "a" is used again, so "b" is the least recently used
+    assert_eq!(miss("a"), 0);
+    assert_eq!(miss("e"), 1);
+    assert_eq!(miss("b"), 1);
+    assert_eq!(miss("a"), 0);
+    assert_eq!(miss("e"), 0);
+    // shrinking evicts the oldest
+    fs.set_negative_cache(1);
+    assert_eq!(miss("e"), 0);
+    assert_eq!(miss("b"), 1);
+    assert_eq!(miss("e"), 1);
+
+    // disabled, every lookup reaches the inner fs
+    fs.set_negative_cache(0);
+    assert_eq!(miss("a"), 1);
+    assert_eq!(miss("a"), 1);
+}
+
+/// Names equal once their trailing dots are dropped, as in Windows
+struct TrailingDots;
+
+impl NameOps for TrailingDots {
+    fn normalize<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(name.trim_end_matches('.'))
+    }
+}
+
+#[test]
+fn negative_cache_by_name_policy() {
+    use rcore_fs_sfs::{CreateOptions, NamePolicy, SimpleFileSystem, NAME_POLICY_CUSTOM};
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let opts = CreateOptions {
+        names: Some(NamePolicy {
+            id: NAME_POLICY_CUSTOM,
+            ops: Arc::new(TrailingDots),
+        }),
+        ..CreateOptions::default()
+    };
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create_with_options(device, 32 * 4096, [1; 16], opts).unwrap();
+    let finds = Arc::new(AtomicUsize::new(0));
+    let fs = MountFS::new(Arc::new(CountingFS {
+        inner: sfs,
+        finds: finds.clone(),
+    }));
+    assert!(fs.name_ops().is_some());
+    fs.set_negative_cache(16);
+    let root = fs.mountpoint_root_inode();
+    let miss = |name: &str| {
+        let before = finds.load(Ordering::Relaxed);
+        assert_eq!(root.find(false, name).err(), Some(FsError::EntryNotFound));
+        finds.load(Ordering::Relaxed) - before
+    };
+
+    // one miss for all spellings of a name
+    assert_eq!(miss("foo."), 1);
+    assert_eq!(miss("foo"), 0);
+    assert_eq!(miss("foo.."), 0);
+    assert_eq!(miss("bar"), 1);
+    assert_eq!(miss("bar."), 0);
+
+    // nor a stale one for any of them once it exists
+    root.create("foo", FileType::File, 0o644).unwrap();
+    for name in ["foo", "foo.", "foo.."] {
+        root.find(false, name).unwrap();
+    }
+    assert_eq!(miss("foo.bar"), 1);
+}
+
+#[test]
+fn data_version_of_inner_fs() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap();
+    let inner = sfs.root_inode().create("a", FileType::File, 0o644).unwrap();
+    let fs = MountFS::new(sfs);
+    let outer = fs.mountpoint_root_inode().find(false, "a").unwrap();
+    outer.write_at(0, b"data").unwrap();
+    inner.resize(1).unwrap();
+    assert_eq!(inner.data_version(), 2);
+    assert_eq!(outer.data_version(), 2);
+}
+
+#[test]
+fn changes_at_mount_point() {
+    let ramfs = RamFS::new();
+    let rootfs = MountFS::new(ramfs.clone());
+    let root = rootfs.mountpoint_root_inode();
+    let a = root.create("a", FileType::Dir, 0o777).unwrap();
+    let b = a.create("b", FileType::Dir, 0o777).unwrap();
+    let other = a.create("other", FileType::File, 0o644).unwrap();
+    let fs_b = RamFS::new();
+    b.mount(fs_b.clone()).unwrap();
+    let shadowed = ramfs.root_inode().lookup("a/b").unwrap();
+    let hidden = || shadowed.list().unwrap().len();
+
+    // through the INode of the mount point, into the fs mounted there
+    let file = b.create("f", FileType::File, 0o644).unwrap();
+    b.symlink("l", "f").unwrap();
+    b.link("f2", &(file.clone() as Arc<dyn INode>)).unwrap();
+    b.move_("f2", &(b.clone() as Arc<dyn INode>), "f3").unwrap();
+    b.unlink("f3").unwrap();
+    assert!(fs_b.root_inode().find("f").is_ok());
+    assert!(fs_b.root_inode().find("l").is_ok());
+    assert_eq!(hidden(), 2);
+
+    // the mount point can not be renamed, replaced or linked
+    let mounted = root.lookup("a/b").unwrap() as Arc<dyn INode>;
+    let a = a as Arc<dyn INode>;
+    assert_eq!(a.move_("b", &a, "c").err(), Some(FsError::Busy));
+    assert_eq!(a.move_("other", &a, "b").err(), Some(FsError::Busy));
+    assert_eq!(a.link("c", &mounted).err(), Some(FsError::Busy));
+    assert_eq!(
+        a.link("c", &(b.clone() as Arc<dyn INode>)).err(),
+        Some(FsError::Busy)
+    );
+    assert!(a.find("other").is_ok());
+    assert_eq!(a.find("c").err(), Some(FsError::EntryNotFound));
+    drop(other);
+
+    b.umount().unwrap();
+    assert_eq!(hidden(), 2);
+    assert_eq!(b.find(false, "f").err(), Some(FsError::EntryNotFound));
+}
+
+#[test]
+fn sync_subtree_only() {
+    use rcore_fs_sfs::SimpleFileSystem;
+
+    let device = SyncCountingDevice::new();
+    let rootfs = MountFS::new(SimpleFileSystem::create(device.clone(), 64 * 4096).unwrap());
+    let root = rootfs.mountpoint_root_inode();
+    let sub = root.create("sub", FileType::Dir, 0o777).unwrap();
+    let inside = sub.create("inside", FileType::File, 0o644).unwrap();
+    let outside = root.create("outside", FileType::File, 0o644).unwrap();
+    let mnt = sub.create("mnt", FileType::Dir, 0o777).unwrap();
+    let nested_device = SyncCountingDevice::new();
+    let nested = SimpleFileSystem::create(nested_device.clone(), 64 * 4096).unwrap();
+    let nested = mnt.mount(nested).unwrap();
+    let nested_file = nested
+        .mountpoint_root_inode()
+        .create("file", FileType::File, 0o644)
+        .unwrap();
+    rootfs.sync().unwrap();
+
+    let write_all = || {
+        for file in [&inside, &outside, &nested_file].iter() {
+            let size = file.metadata().unwrap().size;
+            file.write_at(size, b"more").unwrap();
+        }
+        device.writes.lock().unwrap().clear();
+    };
+    let inode_offset = |inode: &Arc<MNode>| inode.metadata().unwrap().inode * 4096;
+    let syncs = nested_device.syncs.load(Ordering::Relaxed);
+    write_all();
+    sub.sync_subtree().unwrap();
+    // the inode of the file below, none of the rest of the fs
+    assert_eq!(*device.writes.lock().unwrap(), [inode_offset(&inside)]);
+    assert_eq!(nested_device.syncs.load(Ordering::Relaxed), syncs + 1);
+    assert_eq!(nested_file.metadata().unwrap().size, 4);
+    assert!(nested_device
+        .writes
+        .lock()
+        .unwrap()
+        .contains(&inode_offset(&nested_file)));
+
+    // a failing mount below does not stop the rest
+    nested_device.failing.store(true, Ordering::Relaxed);
+    write_all();
+    match sub.sync_subtree() {
+        Err(FsError::PartialSync(failed)) => {
+            let ids: Vec<_> = failed.iter().map(|(id, _)| *id).collect();
+            assert_eq!(ids, [nested.instance_id()]);
+        }
+        other => panic!("sync_subtree gave {:?}", other),
+    }
+    assert_eq!(*device.writes.lock().unwrap(), [inode_offset(&inside)]);
+    nested_device.failing.store(false, Ordering::Relaxed);
+
+    // the root of a mount syncs it whole
+    let report = nested.mountpoint_root_inode().sync_subtree_report();
+    assert_eq!((report.synced, report.failed.len()), (1, 0));
+    assert!(outside.metadata().unwrap().size > 0);
+}
+
+/// Name, fields and index of the parent of a span
+type SpanRecord = (&'static str, BTreeMap<&'static str, String>, Option<usize>);
+
+/// Subscriber keeping the spans made, and when they are entered and exited
+#[derive(Default)]
+struct SpanRecorder {
+    /// the id of a span being its index + 1
+    spans: std::sync::Mutex<Vec<SpanRecord>>,
+    /// spans entered, innermost last
+    stack: std::sync::Mutex<Vec<usize>>,
+    /// names of the spans entered, or exited with a `-`
+    log: std::sync::Mutex<Vec<String>>,
+}
+
+struct Fields<'a>(&'a mut BTreeMap<&'static str, String>);
+
+impl tracing::field::Visit for Fields<'_> {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        self.0.insert(field.name(), String::from(value));
+    }
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn core::fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+impl tracing::Subscriber for SpanRecorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::Id {
+        let mut fields = BTreeMap::new();
+        attrs.record(&mut Fields(&mut fields));
+        let parent = match attrs.parent() {
+            Some(id) => Some(id.into_u64() as usize - 1),
+            None if attrs.is_contextual() => self.stack.lock().unwrap().last().copied(),
+            None => None,
+        };
+        let mut spans = self.spans.lock().unwrap();
+        spans.push((attrs.metadata().name(), fields, parent));
+        tracing::Id::from_u64(spans.len() as u64)
+    }
+    fn record(&self, _: &tracing::Id, _: &tracing::span::Record<'_>) {}
+    fn record_follows_from(&self, _: &tracing::Id, _: &tracing::Id) {}
+    fn event(&self, _: &tracing::Event<'_>) {}
+    fn enter(&self, id: &tracing::Id) {
+        let i = id.into_u64() as usize - 1;
+        self.stack.lock().unwrap().push(i);
+        let name = self.spans.lock().unwrap()[i].0;
+        self.log.lock().unwrap().push(String::from(name));
+    }
+    fn exit(&self, id: &tracing::Id) {
+        let i = id.into_u64() as usize - 1;
+        assert_eq!(self.stack.lock().unwrap().pop(), Some(i));
+        let name = self.spans.lock().unwrap()[i].0;
+        self.log.lock().unwrap().push(format!("-{}", name));
+    }
+}
+
+#[test]
+fn tracing_spans() {
+    use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let dispatch = tracing::Dispatch::new(SpanRecorder::default());
+    let recorder = dispatch.downcast_ref::<SpanRecorder>().unwrap();
+    let (sfs, file, root_id, mnt_id) = tracing::dispatcher::with_default(&dispatch, || {
+        let rootfs = MountFS::new(RamFS::new());
+        let mnt = rootfs
+            .mountpoint_root_inode()
+            .create("mnt", FileType::Dir, 0o777)
+            .unwrap();
+        let device = Arc::new(Mutex::new(tempfile::tempfile().unwrap()));
+        let sfs = SimpleFileSystem::create(device, 32 * 4096).unwrap();
+        let root_id = sfs.root_inode().metadata().unwrap().inode;
+        let sfs_root = mnt.mount(sfs.clone()).unwrap().mountpoint_root_inode();
+        let file = sfs_root.create("file", FileType::File, 0o644).unwrap();
+        file.write_at(10, &[1; 5000]).unwrap();
+        sfs.sync().unwrap();
+
+        // entered each time polled, not only while made
+        recorder.log.lock().unwrap().clear();
+        let mut future = file.async_poll();
+        assert!(recorder.log.lock().unwrap().is_empty());
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RAW, |_| {}, |_| {}, |_| {});
+        const RAW: RawWaker = RawWaker::new(core::ptr::null(), &VTABLE);
+        let waker = unsafe { Waker::from_raw(RAW) };
+        let poll = future.as_mut().poll(&mut Context::from_waker(&waker));
+        assert!(matches!(poll, Poll::Ready(Ok(_))));
+        assert_eq!(*recorder.log.lock().unwrap(), ["async_poll", "-async_poll"]);
+        drop(future);
+
+        mnt.umount().unwrap();
+        (sfs, file, root_id, rootfs.instance_id())
+    });
+    assert!(recorder.stack.lock().unwrap().is_empty());
+
+    let spans = recorder.spans.lock().unwrap();
+    let find = |name: &str| {
+        let found: Vec<_> = spans.iter().filter(|span| span.0 == name).collect();
+        assert_eq!(found.len(), 1, "spans named {}", name);
+        found[0]
+    };
+    let fields = |pairs: &[(&'static str, String)]| pairs.iter().cloned().collect();
+    let fs = sfs.instance_id().to_string();
+    assert_eq!(
+        find("create_fs").1,
+        fields(&[("space", (32 * 4096).to_string())])
+    );
+    let mount = find("mount");
+    assert_eq!(
+        mount.1,
+        fields(&[("fs", mnt_id.to_string()), ("mounted", fs.clone())])
+    );
+    let create = find("create");
+    assert_eq!(
+        create.1,
+        fields(&[
+            ("fs", fs.clone()),
+            ("inode", root_id.to_string()),
+            ("name", "file".into()),
+            ("type_", "File".into()),
+        ])
+    );
+    let write = find("write_at");
+    assert_eq!(
+        write.1,
+        fields(&[
+            ("fs", fs.clone()),
+            ("inode", file.metadata().unwrap().inode.to_string()),
+            ("offset", "10".into()),
+            ("len", "5000".into()),
+        ])
+    );
+    // each made by the workload itself, nothing of it nested in another
+    for span in [mount, create, write] {
+        assert_eq!(span.2, None);
+    }
+    let syncs: Vec<_> = spans.iter().filter(|span| span.0 == "sync").collect();
+    assert!(syncs
+        .iter()
+        .any(|span| span.2.is_none() && span.1 == fields(&[("fs", fs.clone())])));
+    assert_eq!(find("umount").1, fields(&[("fs", mnt_id.to_string())]));
+}
+
+#[test]
+fn pin_through_mount() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let file = tempfile::tempfile().unwrap();
+    let sfs = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096).unwrap();
+    let rootfs = MountFS::new(RamFS::new());
+    let root = rootfs.mountpoint_root_inode();
+    let ram_file = root.create("file", FileType::File, 0o644).unwrap();
+    assert_eq!(ram_file.pin().err(), Some(FsError::NotSupported));
+
+    let mnt = root.create("mnt", FileType::Dir, 0o777).unwrap();
+    mnt.mount(sfs.clone()).unwrap();
+    let file = root
+        .lookup("mnt")
+        .unwrap()
+        .create("file", FileType::File, 0o644)
+        .unwrap();
+    let pin = file.pin().unwrap();
+    let id = file.metadata().unwrap().inode;
+    assert_eq!(sfs.pinned_inodes(), [id]);
+    let dir = root.lookup("mnt").unwrap();
+    assert_eq!(dir.unlink("file"), Err(FsError::Busy));
+    drop(pin);
+    assert!(sfs.pinned_inodes().is_empty());
+    dir.unlink("file").unwrap();
+}
+
+#[test]
+fn fs_info_by_path() {
+    use rcore_fs_sfs::SimpleFileSystem;
+    use std::sync::Mutex;
+
+    let new_sfs = |blocks: usize| {
+        let file = tempfile::tempfile().unwrap();
+        SimpleFileSystem::create(Arc::new(Mutex::new(file)), blocks * 4096).unwrap()
+    };
+    let rootfs = MountFS::new(new_sfs(64));
+    let root = rootfs.mountpoint_root_inode();
+    let etc = root.create("etc", FileType::Dir, 0o755).unwrap();
+    let data = root.create("data", FileType::Dir, 0o755).unwrap();
+    root.create("tmp", FileType::Dir, 0o755)
+        .unwrap()
+        .mount(RamFS::new())
+        .unwrap();
+    // nearly full
+    let big = etc.create("big", FileType::File, 0o644).unwrap();
+    let bavail = rootfs.info().bavail;
+    big.resize((bavail - 2) * 4096).unwrap();
+    let root_info = rootfs.info();
+    assert!(root_info.bavail <= 2);
+    data.mount(new_sfs(256)).unwrap();
+    let file = data.create("file", FileType::File, 0o644).unwrap();
+
+    let empty_info = rootfs.info_at("/data").unwrap();
+    assert_eq!(empty_info.blocks, 256);
+    assert!(empty_info.bfree > 200);
+    for info in [
+        file.fs_info(),
+        (file.clone() as Arc<dyn INode>).fs_info().unwrap(),
+        rootfs.info_at("/data/file").unwrap(),
+        // the mount point itself
+        data.fs_info(),
+    ] {
+        assert_eq!(
+            (info.blocks, info.bfree),
+            (empty_info.blocks, empty_info.bfree)
+        );
+    }
+    for info in [
+        etc.fs_info(),
+        big.fs_info(),
+        rootfs.info_at("/etc/big").unwrap(),
+        rootfs.info_at("/").unwrap(),
+    ] {
+        assert_eq!(
+            (info.blocks, info.bfree),
+            (root_info.blocks, root_info.bfree)
+        );
+    }
+    assert_eq!(rootfs.info_at("/tmp").unwrap().blocks, 0);
+    assert_eq!(rootfs.info_at("/none").err(), Some(FsError::EntryNotFound));
+
+    // the ramfs at /tmp has no blocks
+    let total = rootfs.total_info();
+    assert_eq!((total.bsize, total.frsize), (4096, 4096));
+    assert_eq!(total.blocks, root_info.blocks + empty_info.blocks);
+    assert_eq!(total.bfree, root_info.bfree + empty_info.bfree);
+    assert_eq!(total.bavail, root_info.bavail + empty_info.bavail);
+    assert_eq!(total.files, root_info.files + empty_info.files);
+    assert_eq!(total.namemax, root_info.namemax);
+}
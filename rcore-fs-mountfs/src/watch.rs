@@ -0,0 +1,222 @@
+//! Inotify-style change notification for `MountFS`
+
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec::Vec,
+};
+use spin::Mutex;
+
+/// Identifier of a registered watch
+pub type WatchId = usize;
+
+/// Default capacity of the event queue
+pub const DEFAULT_EVENT_QUEUE_CAPACITY: usize = 256;
+
+/// Set of event kinds a watch is interested in
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EventMask(pub u32);
+
+impl EventMask {
+    pub const CREATE: EventMask = EventMask(1 << 0);
+    pub const DELETE: EventMask = EventMask(1 << 1);
+    pub const MODIFY: EventMask = EventMask(1 << 2);
+    pub const MOVED_FROM: EventMask = EventMask(1 << 3);
+    pub const MOVED_TO: EventMask = EventMask(1 << 4);
+    pub const ATTRIB: EventMask = EventMask(1 << 5);
+    pub const MOVE: EventMask = EventMask(Self::MOVED_FROM.0 | Self::MOVED_TO.0);
+    pub const ALL: EventMask = EventMask(0x3f);
+
+    pub fn contains(&self, other: EventMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for EventMask {
+    type Output = EventMask;
+
+    fn bitor(self, rhs: EventMask) -> EventMask {
+        EventMask(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EventKind {
+    Created,
+    Deleted,
+    Modified,
+    MovedFrom,
+    MovedTo,
+    AttrChanged,
+    /// Events were dropped because the queue was full
+    Overflow,
+}
+
+impl EventKind {
+    fn mask(&self) -> EventMask {
+        match self {
+            EventKind::Created => EventMask::CREATE,
+            EventKind::Deleted => EventMask::DELETE,
+            EventKind::Modified => EventMask::MODIFY,
+            EventKind::MovedFrom => EventMask::MOVED_FROM,
+            EventKind::MovedTo => EventMask::MOVED_TO,
+            EventKind::AttrChanged => EventMask::ATTRIB,
+            EventKind::Overflow => EventMask(0),
+        }
+    }
+}
+
+/// A change notification
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FsEvent {
+    /// The watch this event is delivered to, 0 for `Overflow`
+    pub watch: WatchId,
+    pub kind: EventKind,
+    /// Name of the entry inside a watched directory.
+    /// `None` if the event is about the watched INode itself.
+    pub name: Option<String>,
+    /// Links `MovedFrom` with its `MovedTo`, 0 for other events
+    pub cookie: u32,
+}
+
+/// Registry of watches and the bounded queue of pending events
+pub struct Watcher {
+    inner: Mutex<WatcherInner>,
+}
+
+struct WatcherInner {
+    next_id: WatchId,
+    next_cookie: u32,
+    capacity: usize,
+    /// watch id -> (inode id, mask)
+    watches: BTreeMap<WatchId, (usize, EventMask)>,
+    /// inode id -> watch ids
+    by_inode: BTreeMap<usize, Vec<WatchId>>,
+    queue: VecDeque<FsEvent>,
+}
+
+impl Watcher {
+    pub fn new() -> Self {
+        Watcher {
+            inner: Mutex::new(WatcherInner {
+                next_id: 1,
+                next_cookie: 1,
+                capacity: DEFAULT_EVENT_QUEUE_CAPACITY,
+                watches: BTreeMap::new(),
+                by_inode: BTreeMap::new(),
+                queue: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Watch INode `inode_id` for events in `mask`
+    pub fn watch(&self, inode_id: usize, mask: EventMask) -> WatchId {
+        let mut inner = self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.watches.insert(id, (inode_id, mask));
+        inner.by_inode.entry(inode_id).or_default().push(id);
+        id
+    }
+
+    /// Remove a watch. Return false if it does not exist.
+    pub fn unwatch(&self, watch: WatchId) -> bool {
+        let mut inner = self.inner.lock();
+        match inner.watches.remove(&watch) {
+            Some((inode_id, _)) => {
+                inner.remove_from_inode(inode_id, watch);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove all watches on INode `inode_id`
+    pub fn unwatch_inode(&self, inode_id: usize) {
+        let mut inner = self.inner.lock();
+        if let Some(ids) = inner.by_inode.remove(&inode_id) {
+            for id in ids {
+                inner.watches.remove(&id);
+            }
+        }
+    }
+
+    /// Number of registered watches
+    pub fn watch_count(&self) -> usize {
+        self.inner.lock().watches.len()
+    }
+
+    /// Set the max number of queued events.
+    /// When it is full, a single `Overflow` event is appended and further events are dropped.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.inner.lock().capacity = capacity;
+    }
+
+    /// Take at most `max` pending events in order
+    pub fn take_events(&self, max: usize) -> Vec<FsEvent> {
+        let mut inner = self.inner.lock();
+        let n = max.min(inner.queue.len());
+        inner.queue.drain(..n).collect()
+    }
+
+    /// Allocate a cookie to pair `MovedFrom` and `MovedTo`
+    pub(crate) fn new_cookie(&self) -> u32 {
+        let mut inner = self.inner.lock();
+        let cookie = inner.next_cookie;
+        inner.next_cookie = inner.next_cookie.wrapping_add(1).max(1);
+        cookie
+    }
+
+    /// Queue `kind` for all watches on `inode_id` interested in it
+    pub(crate) fn notify(&self, inode_id: usize, kind: EventKind, name: Option<&str>, cookie: u32) {
+        let mut inner = self.inner.lock();
+        let ids = match inner.by_inode.get(&inode_id) {
+            Some(ids) => ids.clone(),
+            None => return,
+        };
+        for id in ids {
+            let mask = inner.watches[&id].1;
+            if mask.contains(kind.mask()) {
+                inner.push(FsEvent {
+                    watch: id,
+                    kind,
+                    name: name.map(String::from),
+                    cookie,
+                });
+            }
+        }
+    }
+}
+
+impl Default for Watcher {
+    fn default() -> Self {
+        Watcher::new()
+    }
+}
+
+impl WatcherInner {
+    fn remove_from_inode(&mut self, inode_id: usize, watch: WatchId) {
+        if let Some(ids) = self.by_inode.get_mut(&inode_id) {
+            ids.retain(|&id| id != watch);
+            if ids.is_empty() {
+                self.by_inode.remove(&inode_id);
+            }
+        }
+    }
+
+    fn push(&mut self, event: FsEvent) {
+        if self.queue.len() < self.capacity {
+            self.queue.push_back(event);
+            return;
+        }
+        let overflowed = matches!(self.queue.back(), Some(e) if e.kind == EventKind::Overflow);
+        if !overflowed {
+            self.queue.push_back(FsEvent {
+                watch: 0,
+                kind: EventKind::Overflow,
+                name: None,
+                cookie: 0,
+            });
+        }
+    }
+}
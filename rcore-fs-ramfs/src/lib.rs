@@ -15,6 +15,7 @@ use spin::{RwLock, RwLockWriteGuard};
 
 pub struct RamFS {
     root: Arc<LockedINode>,
+    instance_id: u64,
 }
 
 impl FileSystem for RamFS {
@@ -36,8 +37,20 @@ impl FileSystem for RamFS {
             files: 0,
             ffree: 0,
             namemax: 0,
+            linkmax: 0,
         }
     }
+
+    fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+
+    fn capabilities(&self) -> FsCapabilities {
+        FsCapabilities::HARDLINK
+            | FsCapabilities::SYMLINK
+            | FsCapabilities::DEVICE_NODES
+            | FsCapabilities::RENAME
+    }
 }
 
 impl RamFS {
@@ -56,16 +69,21 @@ impl RamFS {
                 atime: Timespec { sec: 0, nsec: 0 },
                 mtime: Timespec { sec: 0, nsec: 0 },
                 ctime: Timespec { sec: 0, nsec: 0 },
+                btime: Timespec { sec: 0, nsec: 0 },
                 type_: FileType::Dir,
                 mode: 0o777,
                 nlinks: 1,
                 uid: 0,
                 gid: 0,
                 rdev: 0,
+                flags: MetadataFlags::empty(),
             },
             fs: Weak::default(),
         })));
-        let fs = Arc::new(RamFS { root });
+        let fs = Arc::new(RamFS {
+            root,
+            instance_id: new_instance_id(),
+        });
         let mut root = fs.root.0.write();
         root.parent = Arc::downgrade(&fs.root);
         root.this = Arc::downgrade(&fs.root);
@@ -112,6 +130,10 @@ impl INode for LockedINode {
         if file.extra.type_ == FileType::Dir {
             return Err(FsError::IsDir);
         }
+        // nothing written, not even a hole
+        if buf.is_empty() {
+            return Ok(0);
+        }
         let content = &mut file.content;
         if offset + buf.len() > content.len() {
             content.resize(offset + buf.len(), 0);
@@ -198,12 +220,14 @@ impl INode for LockedINode {
                     atime: Timespec { sec: 0, nsec: 0 },
                     mtime: Timespec { sec: 0, nsec: 0 },
                     ctime: Timespec { sec: 0, nsec: 0 },
+                    btime: Timespec { sec: 0, nsec: 0 },
                     type_,
                     mode: mode as u16,
                     nlinks: 1,
                     uid: 0,
                     gid: 0,
                     rdev: data,
+                    flags: MetadataFlags::empty(),
                 },
                 fs: Weak::clone(&file.fs),
             })));
@@ -313,8 +337,20 @@ impl INode for LockedINode {
         Err(FsError::NotSupported)
     }
 
-    fn fs(&self) -> Arc<dyn FileSystem> {
-        Weak::upgrade(&self.0.read().fs).unwrap()
+    /// `NotSupported` once the `RamFS` is dropped
+    fn fs(&self) -> Result<Arc<dyn FileSystem>> {
+        match Weak::upgrade(&self.0.read().fs) {
+            Some(fs) => Ok(fs),
+            None => Err(FsError::NotSupported),
+        }
+    }
+
+    fn ino_key(&self) -> InodeKey {
+        let file = self.0.read();
+        InodeKey {
+            fs: file.fs.upgrade().map_or(0, |fs| fs.instance_id),
+            inode: file.extra.inode,
+        }
     }
 
     fn as_any_ref(&self) -> &dyn Any {
@@ -11,12 +11,11 @@ use alloc::{
 };
 use core::any::Any;
 use core::fmt::{Debug, Error, Formatter};
-use core::mem::MaybeUninit;
 
 use bitvec::prelude::*;
 use rcore_fs::dev::TimeProvider;
 use rcore_fs::dirty::Dirty;
-use rcore_fs::vfs::{self, FileSystem, FsError, INode, MMapArea, Timespec};
+use rcore_fs::vfs::{self, FileSystem, FsError, INode, MMapArea, MetadataFlags, Timespec};
 use spin::RwLock;
 
 use self::dev::*;
@@ -36,7 +35,7 @@ impl dyn File {
         self.write_all_at(buf, id * BLKSIZE)
     }
     fn read_direntry(&self, id: usize) -> DevResult<DiskEntry> {
-        let mut direntry: DiskEntry = unsafe { MaybeUninit::uninit().assume_init() };
+        let mut direntry = DiskEntry::zeroed();
         self.read_exact_at(direntry.as_buf_mut(), DIRENT_SIZE * id)?;
         Ok(direntry)
     }
@@ -45,7 +44,7 @@ impl dyn File {
     }
     /// Load struct `T` from given block in device
     fn load_struct<T: AsBuf>(&self, id: BlockId) -> DevResult<T> {
-        let mut s: T = unsafe { MaybeUninit::uninit().assume_init() };
+        let mut s = T::zeroed();
         self.read_block(id, s.as_buf_mut())?;
         Ok(s)
     }
@@ -153,6 +152,10 @@ impl vfs::INode for INodeImpl {
         if type_ != FileType::File && type_ != FileType::SymLink {
             return Err(FsError::NotFile);
         }
+        // nothing written, not even a hole
+        if buf.is_empty() {
+            return Ok(0);
+        }
         let end_offset = offset + buf.len();
         if (size as usize) < end_offset {
             self.resize(end_offset)?;
@@ -193,11 +196,13 @@ impl vfs::INode for INodeImpl {
                 sec: disk_inode.ctime as i64,
                 nsec: 0,
             },
+            btime: Timespec { sec: 0, nsec: 0 },
             nlinks: disk_inode.nlinks as usize,
             uid: disk_inode.uid as usize,
             gid: disk_inode.gid as usize,
             blk_size: 0x1000,
             rdev: 0,
+            flags: MetadataFlags::empty(),
         })
     }
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
@@ -255,7 +260,7 @@ impl vfs::INode for INodeImpl {
         }
 
         // Ensure the name is not exist
-        if !self.get_file_inode_id(name).is_none() {
+        if self.get_file_inode_id(name).is_some() {
             return Err(FsError::EntryExist);
         }
 
@@ -324,7 +329,7 @@ impl vfs::INode for INodeImpl {
         if info.nlinks <= 0 {
             return Err(FsError::DirRemoved);
         }
-        if !self.get_file_inode_id(name).is_none() {
+        if self.get_file_inode_id(name).is_some() {
             return Err(FsError::EntryExist);
         }
         let child = other
@@ -429,8 +434,14 @@ impl vfs::INode for INodeImpl {
     fn mmap(&self, _area: MMapArea) -> vfs::Result<()> {
         Err(FsError::NotSupported)
     }
-    fn fs(&self) -> Arc<dyn vfs::FileSystem> {
-        self.fs.clone()
+    fn fs(&self) -> vfs::Result<Arc<dyn vfs::FileSystem>> {
+        Ok(self.fs.clone())
+    }
+    fn ino_key(&self) -> vfs::InodeKey {
+        vfs::InodeKey {
+            fs: self.fs.instance_id,
+            inode: self.id,
+        }
     }
     fn as_any_ref(&self) -> &dyn Any {
         self
@@ -466,6 +477,8 @@ pub struct SEFS {
     time_provider: &'static dyn TimeProvider,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SEFS>,
+    /// see `FileSystem::instance_id()`
+    instance_id: u64,
 }
 
 impl SEFS {
@@ -501,6 +514,7 @@ impl SEFS {
             meta_file,
             time_provider,
             self_ptr: Weak::default(),
+            instance_id: vfs::new_instance_id(),
         }
         .wrap())
     }
@@ -536,6 +550,7 @@ impl SEFS {
             meta_file,
             time_provider,
             self_ptr: Weak::default(),
+            instance_id: vfs::new_instance_id(),
         }
         .wrap();
 
@@ -708,8 +723,13 @@ impl vfs::FileSystem for SEFS {
             files: sb.blocks as usize,        // inaccurate
             ffree: sb.unused_blocks as usize, // inaccurate
             namemax: MAX_FNAME_LEN,
+            linkmax: u16::MAX as usize,
         }
     }
+
+    fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
 }
 
 impl Drop for SEFS {
@@ -2,7 +2,7 @@
 
 use alloc::str;
 use core::fmt::{Debug, Error, Formatter};
-use core::mem::{size_of, size_of_val};
+use core::mem::{size_of, size_of_val, MaybeUninit};
 use core::slice;
 use static_assertions::const_assert;
 
@@ -84,6 +84,14 @@ impl SuperBlock {
 
 /// Convert structs to [u8] slice
 pub trait AsBuf {
+    /// All zero, to read into by `as_buf_mut()`. Implemented for plain data
+    /// only, for which zero is a valid value of every field.
+    fn zeroed() -> Self
+    where
+        Self: Sized,
+    {
+        unsafe { MaybeUninit::zeroed().assume_init() }
+    }
     fn as_buf(&self) -> &[u8] {
         unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of_val(self)) }
     }
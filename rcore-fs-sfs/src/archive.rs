@@ -0,0 +1,336 @@
+//! Export and import of a whole SFS tree as a self-describing stream, to
+//! migrate files between images of different layouts.
+//!
+//! The stream is a magic and version, then one entry per inode in pre-order:
+//!
+//! | field        | size     |
+//! |--------------|----------|
+//! | kind         | 1        |
+//! | path length  | 2        |
+//! | path         | variable |
+//! | mode         | 4        |
+//! | uid, gid     | 4 + 4    |
+//! | a/m/ctime    | 3 * 12   |
+//! | btime        | 12       |
+//! | rdev         | 8        |
+//! | data length  | 8        |
+//! | data         | variable |
+//!
+//! All integers are little-endian. Paths are relative to the root, which is
+//! the first entry with an empty path. The data is the content of a file, the
+//! target of a symlink, or the path of an already exported inode for a hard
+//! link. The stream ends with a kind of 0.
+//!
+//! Streams of version 1 have no btime, the inodes imported from them have a
+//! zero one.
+
+use crate::{INodeImpl, SimpleFileSystem, BLKSIZE};
+use alloc::{collections::BTreeMap, string::String, sync::Arc, vec, vec::Vec};
+use rcore_fs::vfs::{
+    self, FileSystem, FileType, FsError, INode, Metadata, MetadataFlags, Timespec,
+};
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 4] = b"SFSX";
+const STREAM_VERSION: u32 = 2;
+/// first version with the creation time of inodes
+const STREAM_VERSION_BTIME: u32 = 2;
+
+const KIND_END: u8 = 0;
+const KIND_FILE: u8 = 1;
+const KIND_DIR: u8 = 2;
+const KIND_SYMLINK: u8 = 3;
+const KIND_CHAR_DEVICE: u8 = 4;
+const KIND_BLOCK_DEVICE: u8 = 5;
+const KIND_LINK: u8 = 6;
+
+/// Number of entries of each kind in a stream
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct StreamSummary {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    pub devices: usize,
+    /// Hard links to an inode earlier in the stream
+    pub links: usize,
+    /// Bytes of file content
+    pub bytes: u64,
+}
+
+pub type ExportSummary = StreamSummary;
+pub type ImportSummary = StreamSummary;
+
+impl StreamSummary {
+    fn count(&mut self, kind: u8, len: u64) {
+        match kind {
+            KIND_FILE => {
+                self.files += 1;
+                self.bytes += len;
+            }
+            KIND_DIR => self.dirs += 1,
+            KIND_SYMLINK => self.symlinks += 1,
+            KIND_CHAR_DEVICE | KIND_BLOCK_DEVICE => self.devices += 1,
+            _ => self.links += 1,
+        }
+    }
+}
+
+struct Header {
+    kind: u8,
+    path: String,
+    meta: Metadata,
+    len: u64,
+}
+
+/// Write the whole tree of `fs` to `out`.
+///
+/// Non-directory inodes with several names are written once, the other names
+/// become link entries.
+pub fn export_stream(
+    fs: &Arc<SimpleFileSystem>,
+    out: &mut dyn Write,
+) -> vfs::Result<ExportSummary> {
+    out.write_all(MAGIC)?;
+    out.write_all(&STREAM_VERSION.to_le_bytes())?;
+    let mut exporter = Exporter {
+        out,
+        exported: BTreeMap::new(),
+        summary: ExportSummary::default(),
+    };
+    exporter.export(&fs.root_inode(), String::new())?;
+    exporter.out.write_all(&[KIND_END])?;
+    exporter.out.flush()?;
+    Ok(exporter.summary)
+}
+
+struct Exporter<'a> {
+    out: &'a mut dyn Write,
+    /// path of the inodes with several links, by (dev, inode)
+    exported: BTreeMap<(usize, usize), String>,
+    summary: ExportSummary,
+}
+
+impl Exporter<'_> {
+    fn export(&mut self, inode: &Arc<dyn INode>, path: String) -> vfs::Result<()> {
+        let meta = inode.metadata()?;
+        if meta.type_ != FileType::Dir && meta.nlinks > 1 {
+            let key = (meta.dev, meta.inode);
+            if let Some(target) = self.exported.get(&key) {
+                let target = target.clone().into_bytes();
+                self.header(KIND_LINK, &path, &meta, target.len() as u64)?;
+                return Ok(self.out.write_all(&target)?);
+            }
+            self.exported.insert(key, path.clone());
+        }
+        let kind = match meta.type_ {
+            FileType::File => KIND_FILE,
+            FileType::Dir => KIND_DIR,
+            FileType::SymLink => KIND_SYMLINK,
+            FileType::CharDevice => KIND_CHAR_DEVICE,
+            FileType::BlockDevice => KIND_BLOCK_DEVICE,
+            _ => return Err(FsError::NotSupported),
+        };
+        let len = match kind {
+            KIND_FILE | KIND_SYMLINK => meta.size as u64,
+            _ => 0,
+        };
+        self.header(kind, &path, &meta, len)?;
+        let mut buf = vec![0u8; BLKSIZE];
+        let mut offset = 0;
+        while offset < meta.size && len != 0 {
+            let chunk = (meta.size - offset).min(BLKSIZE);
+            let read = inode.read_at(offset, &mut buf[..chunk])?;
+            if read != chunk {
+                return Err(FsError::WrongFs);
+            }
+            self.out.write_all(&buf[..chunk])?;
+            offset += chunk;
+        }
+        if kind == KIND_DIR {
+            for name in inode.list()?.into_iter().skip(2) {
+                let child = inode.find(&name)?;
+                let child_path = match path.is_empty() {
+                    true => name,
+                    false => path.clone() + "/" + &name,
+                };
+                self.export(&child, child_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, kind: u8, path: &str, meta: &Metadata, len: u64) -> vfs::Result<()> {
+        if path.len() > u16::MAX as usize {
+            return Err(FsError::InvalidParam);
+        }
+        let mut buf = Vec::with_capacity(79 + path.len());
+        buf.push(kind);
+        buf.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        buf.extend_from_slice(path.as_bytes());
+        buf.extend_from_slice(&(meta.mode as u32).to_le_bytes());
+        buf.extend_from_slice(&(meta.uid as u32).to_le_bytes());
+        buf.extend_from_slice(&(meta.gid as u32).to_le_bytes());
+        for time in &[meta.atime, meta.mtime, meta.ctime, meta.btime] {
+            buf.extend_from_slice(&time.sec.to_le_bytes());
+            buf.extend_from_slice(&time.nsec.to_le_bytes());
+        }
+        buf.extend_from_slice(&(meta.rdev as u64).to_le_bytes());
+        buf.extend_from_slice(&len.to_le_bytes());
+        self.out.write_all(&buf)?;
+        self.summary.count(kind, len);
+        Ok(())
+    }
+}
+
+/// Recreate the tree written by `export_stream()` in `fs`.
+///
+/// The root entry only updates the times of the root, other entries must not
+/// exist yet. Times are restored once the whole stream is imported, creation
+/// times included.
+pub fn import_stream(
+    fs: &Arc<SimpleFileSystem>,
+    input: &mut dyn Read,
+) -> vfs::Result<ImportSummary> {
+    let mut magic = [0u8; 4];
+    input.read_exact(&mut magic)?;
+    let version = u32::from_le_bytes(read_array(input)?);
+    if &magic != MAGIC || !(1..=STREAM_VERSION).contains(&version) {
+        return Err(FsError::InvalidParam);
+    }
+    let root = fs.root_inode();
+    let mut imported: BTreeMap<String, Arc<dyn INode>> = BTreeMap::new();
+    // (inode, times) to restore at the end
+    let mut times = Vec::new();
+    let mut summary = ImportSummary::default();
+    while let Some(header) = read_header(input, version)? {
+        let (parent, name) = match header.path.rfind('/') {
+            Some(pos) => (&header.path[..pos], &header.path[pos + 1..]),
+            None => ("", header.path.as_str()),
+        };
+        let dir = match parent {
+            "" => root.clone(),
+            _ => imported.get(parent).ok_or(FsError::EntryNotFound)?.clone(),
+        };
+        let inode = match header.kind {
+            KIND_DIR if header.path.is_empty() => root.clone(),
+            KIND_LINK => {
+                let target = String::from_utf8(read_data(input, header.len)?)
+                    .map_err(|_| FsError::InvalidParam)?;
+                let target = imported.get(&target).ok_or(FsError::EntryNotFound)?;
+                dir.link(name, target)?;
+                summary.count(header.kind, header.len);
+                continue;
+            }
+            kind => {
+                let type_ = match kind {
+                    KIND_FILE => FileType::File,
+                    KIND_DIR => FileType::Dir,
+                    KIND_SYMLINK => FileType::SymLink,
+                    KIND_CHAR_DEVICE => FileType::CharDevice,
+                    KIND_BLOCK_DEVICE => FileType::BlockDevice,
+                    _ => return Err(FsError::InvalidParam),
+                };
+                let inode = dir.create2(name, type_, header.meta.mode as u32, header.meta.rdev)?;
+                if kind == KIND_FILE || kind == KIND_SYMLINK {
+                    copy_data(input, &inode, header.len)?;
+                }
+                inode
+            }
+        };
+        times.push((inode.clone(), header.meta));
+        imported.insert(header.path, inode);
+        summary.count(header.kind, header.len);
+    }
+    for (inode, meta) in times {
+        let mut new_meta = inode.metadata()?;
+        new_meta.atime = meta.atime;
+        new_meta.mtime = meta.mtime;
+        new_meta.ctime = meta.ctime;
+        inode.set_metadata(&new_meta)?;
+        if let Some(inode) = inode.downcast_ref::<INodeImpl>() {
+            inode.set_btime(meta.btime);
+        }
+    }
+    fs.sync()?;
+    Ok(summary)
+}
+
+fn read_header(input: &mut dyn Read, version: u32) -> vfs::Result<Option<Header>> {
+    let mut kind = [0u8; 1];
+    input.read_exact(&mut kind)?;
+    if kind[0] == KIND_END {
+        return Ok(None);
+    }
+    let path_len = u16::from_le_bytes(read_array(input)?) as u64;
+    let path = String::from_utf8(read_data(input, path_len)?).map_err(|_| FsError::InvalidParam)?;
+    let mode = u32::from_le_bytes(read_array(input)?);
+    let uid = u32::from_le_bytes(read_array(input)?);
+    let gid = u32::from_le_bytes(read_array(input)?);
+    let mut time = || -> vfs::Result<Timespec> {
+        Ok(Timespec {
+            sec: i64::from_le_bytes(read_array(input)?),
+            nsec: i32::from_le_bytes(read_array(input)?),
+        })
+    };
+    let (atime, mtime, ctime) = (time()?, time()?, time()?);
+    let btime = match version >= STREAM_VERSION_BTIME {
+        true => time()?,
+        false => Timespec { sec: 0, nsec: 0 },
+    };
+    let rdev = u64::from_le_bytes(read_array(input)?);
+    let len = u64::from_le_bytes(read_array(input)?);
+    Ok(Some(Header {
+        kind: kind[0],
+        path,
+        meta: Metadata {
+            dev: 0,
+            inode: 0,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime,
+            mtime,
+            ctime,
+            btime,
+            type_: FileType::File,
+            mode: mode as u16,
+            nlinks: 0,
+            uid: uid as usize,
+            gid: gid as usize,
+            rdev: rdev as usize,
+            flags: MetadataFlags::empty(),
+        },
+        len,
+    }))
+}
+
+fn read_array<const N: usize>(input: &mut dyn Read) -> vfs::Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read a short field, like a path
+fn read_data(input: &mut dyn Read, len: u64) -> vfs::Result<Vec<u8>> {
+    if len > u16::MAX as u64 {
+        return Err(FsError::InvalidParam);
+    }
+    let mut buf = vec![0u8; len as usize];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Copy `len` bytes of `input` to the start of `inode`
+fn copy_data(input: &mut dyn Read, inode: &Arc<dyn INode>, len: u64) -> vfs::Result<()> {
+    let len = len as usize;
+    inode.resize(len)?;
+    let mut buf = vec![0u8; BLKSIZE];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = (len - offset).min(BLKSIZE);
+        input.read_exact(&mut buf[..chunk])?;
+        inode.write_at(offset, &buf[..chunk])?;
+        offset += chunk;
+    }
+    Ok(())
+}
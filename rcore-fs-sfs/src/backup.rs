@@ -0,0 +1,200 @@
+//! Image backup of a live SFS, see `SimpleFileSystem::backup_stream()`
+//!
+//! The backup is of the fs as it was at one moment, its consistency point,
+//! while the fs stays writable. At that point all is written back with
+//! allocation locked, and the blocks in use are noted from the freemap. From
+//! then on, the first write to a noted block the backup has not streamed
+//! yet first keeps a copy of the block as it was, see `BackupCopies`. The
+//! backup streams that copy instead, and drops it. So writers only wait for
+//! the consistency point, and for the copy of the blocks they write.
+
+use super::*;
+use rcore_fs::dev::timed::BoxFuture;
+use rcore_fs::dev::{DevError, Result as DevResult};
+
+/// Where `SimpleFileSystem::backup_stream()` writes an image.
+///
+/// The image is `blocks` long, with the blocks given and zeros elsewhere,
+/// where blocks are free in its freemap. It is a valid SFS as is.
+pub trait AsyncBlockSink: Send {
+    /// Start of the image: its number of blocks, the content of its block
+    /// `BLKN_SUPER`, and that of its freemap, from block `BLKN_FREEMAP`
+    fn begin<'a>(
+        &'a mut self,
+        blocks: usize,
+        super_block: &'a [u8],
+        free_map: &'a [u8],
+    ) -> BoxFuture<'a, vfs::Result<()>>;
+    /// Block `id` of the image, called after `begin()` in increasing order
+    /// of `id`
+    fn write_block<'a>(&'a mut self, id: BlockId, data: &'a [u8])
+        -> BoxFuture<'a, vfs::Result<()>>;
+}
+
+/// What `SimpleFileSystem::backup_stream()` wrote
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct BackupSummary {
+    /// Blocks of the image, in use at the consistency point
+    pub blocks: usize,
+    /// Blocks given to `AsyncBlockSink::write_block()`, all those in use but
+    /// the superblock and the freemap
+    pub streamed: usize,
+    /// Blocks written during the backup, streamed from a copy kept before
+    pub copied_up: usize,
+}
+
+/// Blocks of a backup in progress, as they were at its consistency point
+pub(crate) struct BackupCopies {
+    inner: spin::Mutex<Copies>,
+}
+
+struct Copies {
+    /// blocks in use at the consistency point, not streamed yet
+    pending: BitVec<Lsb0, u8>,
+    /// content of pending blocks written since the consistency point
+    saved: BTreeMap<BlockId, Vec<u8>>,
+    copied_up: usize,
+}
+
+impl BackupCopies {
+    fn new(pending: BitVec<Lsb0, u8>) -> Self {
+        BackupCopies {
+            inner: spin::Mutex::new(Copies {
+                pending,
+                saved: BTreeMap::new(),
+                copied_up: 0,
+            }),
+        }
+    }
+
+    /// Keep the pending blocks among the `len` bytes from `offset` of
+    /// `device`, about to be written
+    pub(crate) fn copy_up(&self, device: &dyn Device, offset: usize, len: usize) -> DevResult<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let mut copies = self.inner.lock();
+        for id in offset / BLKSIZE..=(offset + len - 1) / BLKSIZE {
+            let pending = copies.pending.get(id).is_some_and(|pending| *pending);
+            if !pending || copies.saved.contains_key(&id) {
+                continue;
+            }
+            let mut data = vec![0u8; BLKSIZE];
+            read_whole_block(device, id, &mut data)?;
+            copies.saved.insert(id, data);
+            copies.copied_up += 1;
+        }
+        Ok(())
+    }
+
+    /// Take block `id` as it was at the consistency point, `None` if it was
+    /// not in use then or is already taken
+    pub(crate) fn take(&self, device: &dyn Device, id: BlockId) -> DevResult<Option<Vec<u8>>> {
+        let mut copies = self.inner.lock();
+        if !copies.pending.get(id).is_some_and(|pending| *pending) {
+            return Ok(None);
+        }
+        copies.pending.set(id, false);
+        if let Some(data) = copies.saved.remove(&id) {
+            return Ok(Some(data));
+        }
+        // still locked, so that no write copies it up in between
+        let mut data = vec![0u8; BLKSIZE];
+        read_whole_block(device, id, &mut data)?;
+        Ok(Some(data))
+    }
+
+    pub(crate) fn copied_up(&self) -> usize {
+        self.inner.lock().copied_up
+    }
+}
+
+fn read_whole_block(device: &dyn Device, id: BlockId, buf: &mut [u8]) -> DevResult<()> {
+    match device.read_at(id * BLKSIZE, buf)? {
+        len if len == buf.len() => Ok(()),
+        _ => Err(DevError::IoError),
+    }
+}
+
+/// Ends the backup of `TracingDevice` on drop, also when the future of
+/// `backup_stream()` is dropped
+struct BackupGuard<'a>(&'a TracingDevice);
+
+impl Drop for BackupGuard<'_> {
+    fn drop(&mut self) {
+        self.0.end_backup();
+    }
+}
+
+impl SimpleFileSystem {
+    /// Stream an image of the fs as it was when called to `sink`, while the
+    /// fs stays writable: the superblock and freemap, then every other block
+    /// in use, in order of block id.
+    ///
+    /// The fs is written back with allocation locked for the consistency
+    /// point, like `sync()`, then writes go on: a block written before the
+    /// backup streamed it is first copied, see the module doc. Writes which
+    /// are under way at the consistency point are in the image, or not, as
+    /// after a crash. The image is marked cleanly unmounted.
+    ///
+    /// Only one backup may run on a fs at a time, another fails with
+    /// `Busy`.
+    pub async fn backup_stream(&self, sink: &mut dyn AsyncBlockSink) -> vfs::Result<BackupSummary> {
+        fs_span!("backup_stream", fs = self.instance_id);
+        let (blocks, super_block, free_map) = self.freeze()?;
+        let guard = BackupGuard(&self.tracer);
+        sink.begin(blocks, &super_block, &free_map).await?;
+        let mut summary = BackupSummary {
+            blocks: 1 + free_map.len() / BLKSIZE,
+            ..BackupSummary::default()
+        };
+        for id in 0..blocks {
+            let data = fs_try!(
+                self.tracer.backup_block(id).map_err(FsError::from),
+                vfs::ErrorContext::new("backup_stream").block(id)
+            );
+            if let Some(data) = data {
+                sink.write_block(id, &data).await?;
+                summary.streamed += 1;
+            }
+        }
+        core::mem::forget(guard);
+        summary.blocks += summary.streamed;
+        summary.copied_up = self.tracer.end_backup();
+        Ok(summary)
+    }
+
+    /// Write all back and start a backup of the blocks in use, return the
+    /// number of blocks with the superblock and freemap to stream
+    fn freeze(&self) -> vfs::Result<(usize, Vec<u8>, Vec<u8>)> {
+        // declared first to be dropped last, see `sync()`
+        let mut inodes = Vec::new();
+        // in lock order, see `SimpleFileSystem`, no block is allocated or
+        // freed until the backup started
+        let mut free_map = self.free_map.write();
+        let mut super_block = self.super_block.write();
+        self.write_back(&mut free_map, &mut super_block, &mut inodes)?;
+
+        let mut frozen = SuperBlock::zeroed();
+        frozen.as_buf_mut().copy_from_slice(super_block.as_buf());
+        if frozen.version >= VERSION_STATE {
+            frozen.state = STATE_CLEAN;
+        }
+        let mut head = frozen.to_disk().into_owned();
+        head.resize(BLKSIZE, 0);
+        let free_map_buf = free_map.as_buf().to_vec();
+
+        let blocks = (super_block.blocks as usize).min(free_map.len());
+        let freemap_blocks = BLKN_FREEMAP..BLKN_FREEMAP + super_block.freemap_blocks as usize;
+        let mut pending = BitVec::<Lsb0, u8>::repeat(false, blocks);
+        for id in 0..blocks {
+            if !free_map[id] && id != BLKN_SUPER && !freemap_blocks.contains(&id) {
+                pending.set(id, true);
+            }
+        }
+        self.tracer.start_backup(BackupCopies::new(pending))?;
+        drop(super_block);
+        drop(free_map);
+        Ok((blocks, head, free_map_buf))
+    }
+}
@@ -0,0 +1,388 @@
+//! Read-only compressed files, see `INODE_COMPRESSED`
+//!
+//! The content of a compressed file starts with its chunk table: a header of
+//! 4 words (`CHUNK_TABLE_MAGIC`, `COMPRESS_CHUNK_SIZE`, number of chunks, 0)
+//! then 2 words for each chunk, the block of the content where it starts and
+//! its length as stored. All words are little-endian. A chunk stored with its
+//! full length is raw, a shorter one is compressed in the LZ4 block format.
+//! Chunks start on a block boundary past the table, in order.
+//!
+//! Compressed files are made by `INodeImpl::compress_from()`, as
+//! `pack_subtree()` does, and can not be written or resized. Reading one
+//! decompresses a chunk at a time into a buffer of the inode, so the memory
+//! used is bounded by `COMPRESS_CHUNK_SIZE`.
+
+use crate::structs::*;
+use crate::{DeviceExt, INodeImpl};
+use alloc::{vec, vec::Vec};
+use rcore_fs::util::BlockIter;
+use rcore_fs::vfs::{self, FsError, INode, InodeFlags};
+
+/// bytes of the header of a chunk table
+const HEADER_SIZE: usize = 16;
+/// bytes of an entry of a chunk table
+const ENTRY_SIZE: usize = 8;
+
+/// The chunk of a compressed file last read, see `INodeImpl::chunk_cache`
+#[derive(Default)]
+pub(crate) struct ChunkCache {
+    /// index of the chunk in `data`, `None` if it holds none
+    index: Option<usize>,
+    data: Vec<u8>,
+}
+
+/// Where a chunk is stored, an entry of the chunk table
+#[derive(Debug, Clone, Copy)]
+struct ChunkEntry {
+    /// first block, counted in the content of the file
+    block: usize,
+    /// bytes stored
+    len: usize,
+}
+
+/// Blocks of the chunk table of a file of `size` bytes uncompressed
+fn table_blocks(size: usize) -> usize {
+    (HEADER_SIZE + size.div_ceil(COMPRESS_CHUNK_SIZE) * ENTRY_SIZE).div_ceil(BLKSIZE)
+}
+
+/// Bytes of chunk `i` uncompressed
+fn chunk_len(i: usize, size: usize) -> usize {
+    (size - i * COMPRESS_CHUNK_SIZE).min(COMPRESS_CHUNK_SIZE)
+}
+
+fn word(bytes: &[u8], i: usize) -> usize {
+    u32::from_le_bytes([
+        bytes[i * 4],
+        bytes[i * 4 + 1],
+        bytes[i * 4 + 2],
+        bytes[i * 4 + 3],
+    ]) as usize
+}
+
+impl INodeImpl {
+    /// Fill this empty file with `size` bytes of `src`, compressed, if that
+    /// saves at least `min_savings` percent of its blocks. Return whether it
+    /// did, the file is left empty if not.
+    ///
+    /// Chunks that do not shrink are stored raw. The file can only be read
+    /// from then on, see the module doc.
+    pub fn compress_from(
+        &self,
+        src: &dyn INode,
+        size: usize,
+        min_savings: usize,
+    ) -> vfs::Result<bool> {
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
+        if self.fs.super_block.read().version < VERSION_COMPRESSED {
+            return Err(FsError::Unsupported);
+        }
+        {
+            let disk_inode = self.disk_inode.read();
+            if disk_inode.type_ != FileType::File || disk_inode.size != 0 {
+                return Err(FsError::InvalidParam);
+            }
+        }
+        if size > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        let budget = Self::blocks_for(size) as usize * (100 - min_savings.min(100)) / 100;
+        if table_blocks(size) >= budget {
+            return Ok(false);
+        }
+        let result = self.write_chunks(src, size, budget);
+        match result {
+            Ok(true) => {
+                let mut disk_inode = self.disk_inode.write();
+                disk_inode.size = size as u32;
+                disk_inode.flags |= INODE_COMPRESSED;
+                Ok(true)
+            }
+            _ => {
+                self._resize(0)?;
+                result
+            }
+        }
+    }
+    /// Write the chunk table and chunks of `size` bytes of `src` as the
+    /// content, false if it would take more than `budget` blocks
+    fn write_chunks(&self, src: &dyn INode, size: usize, budget: usize) -> vfs::Result<bool> {
+        let nchunks = size.div_ceil(COMPRESS_CHUNK_SIZE);
+        let mut table = Vec::with_capacity(HEADER_SIZE + nchunks * ENTRY_SIZE);
+        for word in [
+            CHUNK_TABLE_MAGIC,
+            COMPRESS_CHUNK_SIZE as u32,
+            nchunks as u32,
+            0,
+        ] {
+            table.extend_from_slice(&word.to_le_bytes());
+        }
+        let mut chunk = vec![0; COMPRESS_CHUNK_SIZE];
+        let mut packed = Vec::new();
+        let mut block = table_blocks(size);
+        for i in 0..nchunks {
+            let chunk = &mut chunk[..chunk_len(i, size)];
+            let mut done = 0;
+            while done < chunk.len() {
+                match src.read_at(i * COMPRESS_CHUNK_SIZE + done, &mut chunk[done..])? {
+                    0 => return Err(FsError::WrongFs),
+                    len => done += len,
+                }
+            }
+            packed.clear();
+            lz4_compress(chunk, &mut packed);
+            let stored = match packed.len() < chunk.len() {
+                true => &packed[..],
+                false => &chunk[..],
+            };
+            let end = block * BLKSIZE + stored.len();
+            if end.div_ceil(BLKSIZE) > budget {
+                return Ok(false);
+            }
+            self._resize(end)?;
+            if self._write_at(block * BLKSIZE, stored)? != stored.len() {
+                return Err(FsError::DeviceError);
+            }
+            table.extend_from_slice(&(block as u32).to_le_bytes());
+            table.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            block = end.div_ceil(BLKSIZE);
+        }
+        if self._write_at(0, &table)? != table.len() {
+            return Err(FsError::DeviceError);
+        }
+        Ok(true)
+    }
+    /// Read the content of a compressed file like `_read_at()`, None if it
+    /// is not
+    pub(crate) fn read_compressed(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Option<vfs::Result<usize>> {
+        let size = {
+            let disk_inode = self.disk_inode.read();
+            if !disk_inode.is_compressed() {
+                return None;
+            }
+            disk_inode.size as usize
+        };
+        let (begin, end) = (offset.min(size), (offset + buf.len()).min(size));
+        let mut cache = self.chunk_cache.lock();
+        let mut pos = begin;
+        while pos < end {
+            let i = pos / COMPRESS_CHUNK_SIZE;
+            if cache.index != Some(i) {
+                cache.index = None;
+                if let Err(err) = self.load_chunk(i, size, &mut cache.data) {
+                    warn!("sfs: chunk {} of inode {}: {:?}", i, self.id, err);
+                    return Some(Err(err));
+                }
+                cache.index = Some(i);
+            }
+            let from = pos - i * COMPRESS_CHUNK_SIZE;
+            let len = (end - pos).min(cache.data.len() - from);
+            buf[pos - begin..pos - begin + len].copy_from_slice(&cache.data[from..from + len]);
+            pos += len;
+        }
+        Some(Ok(end - begin))
+    }
+    /// Read and decompress chunk `i` of a compressed file of `size` bytes
+    /// into `data`
+    fn load_chunk(&self, i: usize, size: usize, data: &mut Vec<u8>) -> vfs::Result<()> {
+        let entry = self.chunk_entry(i, size)?;
+        data.resize(chunk_len(i, size), 0);
+        if entry.len == data.len() {
+            return self.read_stored(entry.block * BLKSIZE, data);
+        }
+        let mut packed = vec![0; entry.len];
+        self.read_stored(entry.block * BLKSIZE, &mut packed)?;
+        lz4_decompress(&packed, data).ok_or(FsError::Corrupted)
+    }
+    /// Entry of chunk `i` in the chunk table of a compressed file of `size`
+    /// bytes, checked to be within its blocks
+    fn chunk_entry(&self, i: usize, size: usize) -> vfs::Result<ChunkEntry> {
+        let mut header = [0; HEADER_SIZE];
+        self.read_stored(0, &mut header)?;
+        let nchunks = size.div_ceil(COMPRESS_CHUNK_SIZE);
+        if word(&header, 0) != CHUNK_TABLE_MAGIC as usize
+            || word(&header, 1) != COMPRESS_CHUNK_SIZE
+            || word(&header, 2) != nchunks
+        {
+            return Err(FsError::Corrupted);
+        }
+        let mut bytes = [0; ENTRY_SIZE];
+        self.read_stored(HEADER_SIZE + i * ENTRY_SIZE, &mut bytes)?;
+        let entry = ChunkEntry {
+            block: word(&bytes, 0),
+            len: word(&bytes, 1),
+        };
+        let blocks = self.disk_inode.read().blocks as usize;
+        if entry.len == 0
+            || entry.len > chunk_len(i, size)
+            || entry.block < table_blocks(size)
+            || entry.block * BLKSIZE + entry.len > blocks * BLKSIZE
+        {
+            return Err(FsError::Corrupted);
+        }
+        Ok(entry)
+    }
+    /// Read the content as stored, up to the end of its blocks
+    fn read_stored(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        let blocks = self.disk_inode.read().blocks as usize;
+        if offset + buf.len() > blocks * BLKSIZE {
+            return Err(FsError::Corrupted);
+        }
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        let mut done = 0;
+        for range in iter {
+            let block = self.get_disk_block_id(range.block)?;
+            let buf = &mut buf[done..done + range.len()];
+            self.fs.device.read_block(block, range.begin, buf)?;
+            done += range.len();
+        }
+        Ok(())
+    }
+    /// Whether the chunk table of this compressed file is consistent, and
+    /// every chunk decompresses to its length
+    pub(crate) fn verify_chunks(&self) -> vfs::Result<bool> {
+        let size = self.disk_inode.read().size as usize;
+        let mut next = table_blocks(size);
+        let mut data = Vec::new();
+        for i in 0..size.div_ceil(COMPRESS_CHUNK_SIZE) {
+            let entry = match self.chunk_entry(i, size) {
+                Ok(entry) => entry,
+                Err(FsError::Corrupted) => return Ok(false),
+                Err(err) => return Err(err),
+            };
+            // chunks are in order and do not overlap
+            if entry.block < next {
+                return Ok(false);
+            }
+            next = (entry.block * BLKSIZE + entry.len).div_ceil(BLKSIZE);
+            match self.load_chunk(i, size, &mut data) {
+                Ok(()) => {}
+                Err(FsError::Corrupted) => return Ok(false),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// shortest match encoded
+const MIN_MATCH: usize = 4;
+/// the last bytes of a block are always literals
+const LAST_LITERALS: usize = 5;
+/// no match starts in the last bytes of a block
+const MATCH_LIMIT: usize = 12;
+/// log2 of the number of slots of the hash table of the compressor
+const HASH_LOG2: u32 = 12;
+
+/// Append `src` compressed in the LZ4 block format to `out`
+pub(crate) fn lz4_compress(src: &[u8], out: &mut Vec<u8>) {
+    let read_u32 = |i: usize| u32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]]);
+    // last position + 1 of each hash of 4 bytes, 0 if none
+    let mut table = vec![0usize; 1 << HASH_LOG2];
+    let mut anchor = 0;
+    let mut i = 0;
+    while i + MATCH_LIMIT < src.len() {
+        let seq = read_u32(i);
+        let hash = (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG2)) as usize;
+        let candidate = table[hash];
+        table[hash] = i + 1;
+        if candidate == 0 || i + 1 - candidate > u16::MAX as usize || read_u32(candidate - 1) != seq
+        {
+            i += 1;
+            continue;
+        }
+        let from = candidate - 1;
+        let max = src.len() - LAST_LITERALS - i;
+        let mut len = MIN_MATCH;
+        while len < max && src[from + len] == src[i + len] {
+            len += 1;
+        }
+        lz4_sequence(out, &src[anchor..i], Some((i - from, len)));
+        i += len;
+        anchor = i;
+    }
+    lz4_sequence(out, &src[anchor..], None);
+}
+
+/// Append a sequence of `literals` then the match of (offset, length)
+fn lz4_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((literals.len().min(15) << 4 | match_len.min(15)) as u8);
+    if literals.len() >= 15 {
+        lz4_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            lz4_length(out, match_len - 15);
+        }
+    }
+}
+
+fn lz4_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+/// Decompress `src` in the LZ4 block format into `dst`, which it must fill
+/// exactly. None if `src` is malformed.
+pub(crate) fn lz4_decompress(src: &[u8], dst: &mut [u8]) -> Option<()> {
+    let (mut i, mut o) = (0usize, 0usize);
+    let read_length = |i: &mut usize| {
+        let mut len = 0usize;
+        loop {
+            let byte = *src.get(*i)?;
+            *i += 1;
+            len = len.checked_add(byte as usize)?;
+            if byte != 255 {
+                return Some(len);
+            }
+        }
+    };
+    loop {
+        let token = *src.get(i)?;
+        i += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(&mut i)?;
+        }
+        let from = src.get(i..i.checked_add(literals)?)?;
+        dst.get_mut(o..o.checked_add(literals)?)?
+            .copy_from_slice(from);
+        i += literals;
+        o += literals;
+        if i == src.len() {
+            return (o == dst.len()).then_some(());
+        }
+        let offset = u16::from_le_bytes([*src.get(i)?, *src.get(i + 1)?]) as usize;
+        i += 2;
+        if offset == 0 || offset > o {
+            return None;
+        }
+        let mut len = (token & 15) as usize;
+        if len == 15 {
+            len += read_length(&mut i)?;
+        }
+        len += MIN_MATCH;
+        if len > dst.len() - o {
+            return None;
+        }
+        // byte by byte, the match may overlap what it copies
+        for k in o..o + len {
+            dst[k] = dst[k - offset];
+        }
+        o += len;
+    }
+}
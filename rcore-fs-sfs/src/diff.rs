@@ -0,0 +1,487 @@
+//! Offline comparison of two SFS images, to tell what changed between two
+//! builds of an image without mounting them.
+
+use crate::{SimpleFileSystem, BLKSIZE};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use core::fmt::{self, Display, Write};
+use core::sync::atomic::Ordering;
+use rcore_fs::dev::{self, DevError, Device};
+use rcore_fs::vfs::{self, FileSystem, FileType, FsError, INode, Metadata, MetadataMask};
+
+/// What `diff_images()` compares besides the tree and metadata
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DiffOpts {
+    /// Hash the content of files and symlinks of the same size in both
+    /// images, to tell whether it changed
+    pub content_hash: bool,
+}
+
+/// Differences from image `a` to image `b`, paths are absolute and sorted
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImageDiff {
+    pub super_block: SuperBlockDiff,
+    /// Paths only in `b`, only the root of an added subtree is listed
+    pub added: Vec<DiffEntry>,
+    /// Paths only in `a`, only the root of a removed subtree is listed
+    pub removed: Vec<DiffEntry>,
+    /// Paths of the same type in both images which differ. A path whose
+    /// type changed is removed and added instead.
+    pub modified: Vec<Modified>,
+}
+
+/// Fields of the superblock which differ, as (a, b)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SuperBlockDiff {
+    pub label: Option<(String, String)>,
+    pub version: Option<(u32, u32)>,
+    pub blocks: Option<(u32, u32)>,
+    pub unused_blocks: Option<(u32, u32)>,
+}
+
+/// A path only in one of the images
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffEntry {
+    pub path: String,
+    pub type_: FileType,
+    /// Another path of the same inode in that image, for a hard link
+    pub link_of: Option<String>,
+}
+
+/// A path in both images which differs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Modified {
+    pub path: String,
+    pub a: Metadata,
+    pub b: Metadata,
+    /// Which of `SIZE`, `MODE`, `OWNER`, `TIMES` and `RDEV` differ. Only
+    /// mtime counts as times, and only mode and owner count for dirs, whose
+    /// other fields follow their entries.
+    pub changed: MetadataMask,
+    /// Whether the content differs, `None` if not compared: for dirs and
+    /// devices, or for files and symlinks of the same size without
+    /// `DiffOpts::content_hash`
+    pub content_changed: Option<bool>,
+}
+
+impl ImageDiff {
+    /// Whether the images are the same, as far as compared
+    pub fn is_empty(&self) -> bool {
+        self.super_block == SuperBlockDiff::default()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+    }
+
+    /// Render as a JSON object with the same fields, leaving out those of
+    /// the superblock and of the metadata which did not change
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        // writing to a `String` never fails
+        let _ = self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) -> fmt::Result {
+        let sb = &self.super_block;
+        let mut fields = Vec::new();
+        if let Some((a, b)) = &sb.label {
+            fields.push(format!("\"label\":[{},{}]", JsonStr(a), JsonStr(b)));
+        }
+        for (name, value) in [
+            ("version", sb.version),
+            ("blocks", sb.blocks),
+            ("unused_blocks", sb.unused_blocks),
+        ] {
+            if let Some((a, b)) = value {
+                fields.push(format!("\"{}\":[{},{}]", name, a, b));
+            }
+        }
+        write!(out, "{{\"super_block\":{{{}}}", fields.join(","))?;
+        for (name, entries) in [("added", &self.added), ("removed", &self.removed)] {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|entry| {
+                    let link_of = match &entry.link_of {
+                        Some(path) => JsonStr(path).to_string(),
+                        None => String::from("null"),
+                    };
+                    format!(
+                        "{{\"path\":{},\"type\":\"{}\",\"link_of\":{}}}",
+                        JsonStr(&entry.path),
+                        type_name(entry.type_),
+                        link_of
+                    )
+                })
+                .collect();
+            write!(out, ",\"{}\":[{}]", name, entries.join(","))?;
+        }
+        let modified: Vec<String> = self.modified.iter().map(Modified::to_json).collect();
+        write!(out, ",\"modified\":[{}]}}", modified.join(","))
+    }
+}
+
+impl Modified {
+    fn to_json(&self) -> String {
+        let (a, b) = (&self.a, &self.b);
+        let mut fields = vec![
+            format!("\"path\":{}", JsonStr(&self.path)),
+            format!("\"type\":\"{}\"", type_name(b.type_)),
+        ];
+        if self.changed.contains(MetadataMask::SIZE) {
+            fields.push(format!("\"size\":[{},{}]", a.size, b.size));
+        }
+        if self.changed.contains(MetadataMask::MODE) {
+            fields.push(format!("\"mode\":[{},{}]", a.mode, b.mode));
+        }
+        if self.changed.contains(MetadataMask::OWNER) {
+            fields.push(format!("\"uid\":[{},{}]", a.uid, b.uid));
+            fields.push(format!("\"gid\":[{},{}]", a.gid, b.gid));
+        }
+        if self.changed.contains(MetadataMask::TIMES) {
+            fields.push(format!(
+                "\"mtime\":[[{},{}],[{},{}]]",
+                a.mtime.sec, a.mtime.nsec, b.mtime.sec, b.mtime.nsec
+            ));
+        }
+        if self.changed.contains(MetadataMask::RDEV) {
+            fields.push(format!("\"rdev\":[{},{}]", a.rdev, b.rdev));
+        }
+        let content = match self.content_changed {
+            Some(changed) => changed.to_string(),
+            None => String::from("null"),
+        };
+        fields.push(format!("\"content_changed\":{}", content));
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+/// One line per difference, like `diff`: superblock fields first, then `+`
+/// for added, `-` for removed and `M` for modified paths
+impl Display for ImageDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sb = &self.super_block;
+        if let Some((a, b)) = &sb.label {
+            writeln!(f, "label: {:?} -> {:?}", a, b)?;
+        }
+        for (name, value) in [
+            ("version", sb.version),
+            ("blocks", sb.blocks),
+            ("unused blocks", sb.unused_blocks),
+        ] {
+            if let Some((a, b)) = value {
+                writeln!(f, "{}: {} -> {}", name, a, b)?;
+            }
+        }
+        for (sign, entries) in [('+', &self.added), ('-', &self.removed)] {
+            for entry in entries {
+                write!(f, "{} {} ({}", sign, entry.path, type_name(entry.type_))?;
+                if let Some(path) = &entry.link_of {
+                    write!(f, ", link of {}", path)?;
+                }
+                writeln!(f, ")")?;
+            }
+        }
+        for modified in &self.modified {
+            writeln!(f, "M {}: {}", modified.path, modified)?;
+        }
+        Ok(())
+    }
+}
+
+/// The differences, comma separated
+impl Display for Modified {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (a, b) = (&self.a, &self.b);
+        let mut details = Vec::new();
+        if self.changed.contains(MetadataMask::SIZE) {
+            details.push(format!("size {} -> {}", a.size, b.size));
+        }
+        if self.changed.contains(MetadataMask::MODE) {
+            details.push(format!("mode {:o} -> {:o}", a.mode, b.mode));
+        }
+        if self.changed.contains(MetadataMask::OWNER) {
+            details.push(format!("owner {}:{} -> {}:{}", a.uid, a.gid, b.uid, b.gid));
+        }
+        if self.changed.contains(MetadataMask::TIMES) {
+            details.push(format!(
+                "mtime {}.{:09} -> {}.{:09}",
+                a.mtime.sec, a.mtime.nsec, b.mtime.sec, b.mtime.nsec
+            ));
+        }
+        if self.changed.contains(MetadataMask::RDEV) {
+            details.push(format!("rdev {:#x} -> {:#x}", a.rdev, b.rdev));
+        }
+        match self.content_changed {
+            Some(true) => details.push(String::from("content changed")),
+            Some(false) => details.push(String::from("content unchanged")),
+            None => {}
+        }
+        f.write_str(&details.join(", "))
+    }
+}
+
+/// Compare the images on devices `a` and `b`, which are opened read-only.
+///
+/// Both trees are walked in lockstep by name. The other names of a hard
+/// link are ignored, so a new link is an added path with `link_of` set,
+/// and not a change of the linked file. This completes at once over
+/// synchronous devices.
+pub async fn diff_images(
+    a: Arc<dyn Device>,
+    b: Arc<dyn Device>,
+    opts: DiffOpts,
+) -> vfs::Result<ImageDiff> {
+    let a = SimpleFileSystem::open(Arc::new(ReadOnly(a)))?;
+    let b = SimpleFileSystem::open(Arc::new(ReadOnly(b)))?;
+    let mut differ = Differ {
+        opts,
+        diff: ImageDiff {
+            super_block: SuperBlockDiff::new(&a, &b),
+            ..ImageDiff::default()
+        },
+        links: [BTreeMap::new(), BTreeMap::new()],
+    };
+    differ.dir(&a.root_inode(), &b.root_inode(), "/")?;
+    let Differ {
+        mut diff, links, ..
+    } = differ;
+    for (entries, links) in [(&mut diff.removed, &links[0]), (&mut diff.added, &links[1])] {
+        for entry in entries.iter_mut() {
+            let paths = links.values().find(|paths| paths.contains(&entry.path));
+            entry.link_of = paths
+                .and_then(|paths| paths.iter().find(|&path| *path != entry.path))
+                .cloned();
+        }
+    }
+    Ok(diff)
+}
+
+impl SuperBlockDiff {
+    fn new(a: &SimpleFileSystem, b: &SimpleFileSystem) -> Self {
+        fn changed<T: PartialEq>(a: T, b: T) -> Option<(T, T)> {
+            if a != b {
+                Some((a, b))
+            } else {
+                None
+            }
+        }
+        let (sb_a, sb_b) = (a.super_block.read(), b.super_block.read());
+        SuperBlockDiff {
+            label: changed(a.label(), b.label()),
+            version: changed(sb_a.version, sb_b.version),
+            blocks: changed(sb_a.blocks, sb_b.blocks),
+            unused_blocks: changed(
+                a.unused_blocks.load(Ordering::Relaxed),
+                b.unused_blocks.load(Ordering::Relaxed),
+            ),
+        }
+    }
+}
+
+struct Differ {
+    opts: DiffOpts,
+    diff: ImageDiff,
+    /// paths of the non-dir inodes with several links, by inode, of a and b
+    links: [BTreeMap<usize, Vec<String>>; 2],
+}
+
+impl Differ {
+    fn dir(&mut self, a: &Arc<dyn INode>, b: &Arc<dyn INode>, path: &str) -> vfs::Result<()> {
+        let names_a = sorted_entries(a)?;
+        let names_b = sorted_entries(b)?;
+        let (mut i, mut j) = (0, 0);
+        while i < names_a.len() || j < names_b.len() {
+            let name = match (names_a.get(i), names_b.get(j)) {
+                (Some(name_a), Some(name_b)) => name_a.min(name_b),
+                (Some(name), None) | (None, Some(name)) => name,
+                (None, None) => break,
+            };
+            let child_path = match path {
+                "/" => format!("/{}", name),
+                _ => format!("{}/{}", path, name),
+            };
+            let in_a = names_a.get(i) == Some(name);
+            let in_b = names_b.get(j) == Some(name);
+            match (in_a, in_b) {
+                (true, true) => self.both(&a.find(name)?, &b.find(name)?, child_path)?,
+                (true, false) => {
+                    let entry = self.only(0, &a.find(name)?, child_path)?;
+                    self.diff.removed.push(entry);
+                }
+                _ => {
+                    let entry = self.only(1, &b.find(name)?, child_path)?;
+                    self.diff.added.push(entry);
+                }
+            }
+            i += in_a as usize;
+            j += in_b as usize;
+        }
+        Ok(())
+    }
+
+    fn both(&mut self, a: &Arc<dyn INode>, b: &Arc<dyn INode>, path: String) -> vfs::Result<()> {
+        let (meta_a, meta_b) = (a.metadata()?, b.metadata()?);
+        if meta_a.type_ != meta_b.type_ {
+            let removed = self.only(0, a, path.clone())?;
+            self.diff.removed.push(removed);
+            let added = self.only(1, b, path)?;
+            self.diff.added.push(added);
+            return Ok(());
+        }
+        self.note_link(0, &meta_a, &path);
+        self.note_link(1, &meta_b, &path);
+        let mut changed = MetadataMask::empty();
+        if meta_a.mode != meta_b.mode {
+            changed = changed | MetadataMask::MODE;
+        }
+        if (meta_a.uid, meta_a.gid) != (meta_b.uid, meta_b.gid) {
+            changed = changed | MetadataMask::OWNER;
+        }
+        let mut content_changed = None;
+        if meta_a.type_ == FileType::Dir {
+            self.dir(a, b, &path)?;
+        } else {
+            if meta_a.size != meta_b.size {
+                changed = changed | MetadataMask::SIZE;
+            }
+            if meta_a.mtime != meta_b.mtime {
+                changed = changed | MetadataMask::TIMES;
+            }
+            if meta_a.rdev != meta_b.rdev {
+                changed = changed | MetadataMask::RDEV;
+            }
+            if matches!(meta_a.type_, FileType::File | FileType::SymLink) {
+                content_changed = if meta_a.size != meta_b.size {
+                    Some(true)
+                } else if self.opts.content_hash {
+                    Some(content_hash(a, meta_a.size)? != content_hash(b, meta_b.size)?)
+                } else {
+                    None
+                };
+            }
+        }
+        if changed != MetadataMask::empty() || content_changed == Some(true) {
+            self.diff.modified.push(Modified {
+                path,
+                a: meta_a,
+                b: meta_b,
+                changed,
+                content_changed,
+            });
+        }
+        Ok(())
+    }
+
+    /// `inode` only in image `side`, walk its subtree for the links in it
+    fn only(
+        &mut self,
+        side: usize,
+        inode: &Arc<dyn INode>,
+        path: String,
+    ) -> vfs::Result<DiffEntry> {
+        let meta = inode.metadata()?;
+        self.note_link(side, &meta, &path);
+        if meta.type_ == FileType::Dir {
+            for name in sorted_entries(inode)? {
+                self.only(side, &inode.find(&name)?, format!("{}/{}", path, name))?;
+            }
+        }
+        Ok(DiffEntry {
+            path,
+            type_: meta.type_,
+            link_of: None,
+        })
+    }
+
+    fn note_link(&mut self, side: usize, meta: &Metadata, path: &str) {
+        if meta.type_ != FileType::Dir && meta.nlinks > 1 {
+            let paths = self.links[side].entry(meta.inode).or_default();
+            paths.push(String::from(path));
+        }
+    }
+}
+
+/// Names in `dir` but "." and "..", sorted
+fn sorted_entries(dir: &Arc<dyn INode>) -> vfs::Result<Vec<String>> {
+    let mut names: Vec<String> = dir.list()?.into_iter().skip(2).collect();
+    names.sort();
+    Ok(names)
+}
+
+/// 64-bit FNV-1a of the first `size` bytes of `inode`, read a block at a
+/// time
+fn content_hash(inode: &Arc<dyn INode>, size: usize) -> vfs::Result<u64> {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut buf = vec![0u8; BLKSIZE];
+    let mut offset = 0;
+    while offset < size {
+        let chunk = (size - offset).min(BLKSIZE);
+        if inode.read_at(offset, &mut buf[..chunk])? != chunk {
+            return Err(FsError::WrongFs);
+        }
+        for &byte in &buf[..chunk] {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+        offset += chunk;
+    }
+    Ok(hash)
+}
+
+fn type_name(type_: FileType) -> &'static str {
+    match type_ {
+        FileType::File => "file",
+        FileType::Dir => "dir",
+        FileType::SymLink => "symlink",
+        FileType::CharDevice => "char device",
+        FileType::BlockDevice => "block device",
+        FileType::NamedPipe => "pipe",
+        FileType::Socket => "socket",
+    }
+}
+
+/// A JSON string literal
+struct JsonStr<'a>(&'a str);
+
+impl Display for JsonStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// `Device` refusing writes, so that an image is opened read-only
+struct ReadOnly(Arc<dyn Device>);
+
+impl Device for ReadOnly {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> dev::Result<usize> {
+        self.0.read_at(offset, buf)
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> dev::Result<usize> {
+        Err(DevError::WriteProtected)
+    }
+    fn sync(&self) -> dev::Result<()> {
+        Ok(())
+    }
+    fn is_read_only(&self) -> bool {
+        true
+    }
+    fn size(&self) -> Option<usize> {
+        self.0.size()
+    }
+}
@@ -0,0 +1,381 @@
+//! Hashed index of large dirs, so that a lookup reads a few blocks instead of
+//! all entries.
+//!
+//! A dir gets an index once it has `INDEX_THRESHOLD` entries. The root block
+//! (`DirIndexRoot`) lists the bucket blocks, and a bucket (`DirIndexBucket`)
+//! holds the name hash and entry id of each entry hashing to it. A full bucket
+//! makes the index grow. With the max number of buckets it is marked
+//! overflowed instead, and lookups in it fall back to the linear scan.
+//!
+//! The index only duplicates the entries: it is dropped whenever it can not
+//! be updated, and it can be checked and rebuilt by
+//! `SimpleFileSystem::check_dir_indexes()`.
+
+use crate::structs::*;
+use crate::{DeviceExt, DirSlot, INodeImpl};
+use alloc::{vec, vec::Vec};
+use rcore_fs::vfs::{self, FsError};
+
+impl DirIndexBucket {
+    const fn empty() -> Self {
+        DirIndexBucket {
+            count: 0,
+            overflow: 0,
+            slots: [[0; 2]; INDEX_BUCKET_SLOTS],
+        }
+    }
+    fn used(&self) -> vfs::Result<&[[u32; 2]]> {
+        self.slots
+            .get(..self.count as usize)
+            .ok_or(FsError::WrongFs)
+    }
+    /// Position of (`hash`, `id`) in the bucket
+    fn position(&self, hash: u32, id: usize) -> vfs::Result<Option<usize>> {
+        let slot = [hash, id as u32];
+        let pos = self.used()?.iter().position(|s| *s == slot);
+        if pos.is_none() && self.overflow == 0 {
+            // every entry of a bucket which did not overflow is recorded
+            return Err(FsError::WrongFs);
+        }
+        Ok(pos)
+    }
+}
+
+impl INodeImpl {
+    pub(crate) fn has_index(&self) -> bool {
+        self.disk_inode.read().index != 0
+    }
+    /// Only for Dir
+    /// Find `name` by the index. `None` if the dir has no index, or the
+    /// bucket of `name` overflowed and the entries have to be scanned.
+    pub(crate) fn index_find(&self, name: &str) -> vfs::Result<Option<DirSlot>> {
+        let root = self.disk_inode.read().index as BlockId;
+        if root == 0 {
+            return Ok(None);
+        }
+        let hash = self.fs.name_hash(name);
+        let (_, _, block) = self.index_bucket_of(root, hash)?;
+        let count = self.disk_inode.read().size as usize / DIRENT_SIZE;
+        if block != 0 {
+            let bucket = self.fs.device.load_struct::<DirIndexBucket>(block)?;
+            if bucket.overflow != 0 {
+                return Ok(None);
+            }
+            for &[slot_hash, id] in bucket.used()? {
+                let id = id as usize;
+                if slot_hash != hash {
+                    continue;
+                }
+                if id < 2 || id >= count {
+                    return Err(FsError::WrongFs);
+                }
+                let entry = self.read_direntry(id)?;
+                if self.fs.is_name(&entry.name, name) {
+                    return Ok(Some(DirSlot::Exist(entry.id as INodeId, id)));
+                }
+            }
+        }
+        Ok(Some(DirSlot::Free(count)))
+    }
+    /// Only for Dir
+    /// Record `entries`, just written from entry id `first`, in the index.
+    /// Build the index instead if the dir has none but is large enough.
+    pub(crate) fn index_insert(&self, first: usize, entries: &[DiskEntry]) {
+        let root = self.disk_inode.read().index as BlockId;
+        if root == 0 {
+            self.index_build_if_large();
+            return;
+        }
+        let result = self.index_add_all(root, first, entries);
+        self.index_check(result);
+    }
+    /// Only for Dir
+    /// Forget `removed` at entry id `id`, which now holds `last` moved from
+    /// the end at `last_id`. Drop the index once the dir gets small again.
+    pub(crate) fn index_remove(
+        &self,
+        id: usize,
+        removed: &DiskEntry,
+        last_id: usize,
+        last: &DiskEntry,
+    ) {
+        let root = self.disk_inode.read().index as BlockId;
+        if root == 0 {
+            return;
+        }
+        let result = self.index_move(root, self.fs.entry_hash(&removed.name), id, None);
+        let result = result.and_then(|_| match last_id == id {
+            true => Ok(()),
+            false => self.index_move(root, self.fs.entry_hash(&last.name), last_id, Some(id)),
+        });
+        self.index_check(result);
+        // half of the threshold, not to rebuild it on every create and remove
+        if last_id - 2 < INDEX_THRESHOLD / 2 {
+            self.index_discard();
+        }
+    }
+    /// Only for Dir
+    /// Entry `id` was renamed from `old_name` to `entry`
+    pub(crate) fn index_rename(&self, id: usize, old_name: &[u8], entry: &DiskEntry) {
+        let root = self.disk_inode.read().index as BlockId;
+        if root == 0 {
+            return;
+        }
+        let result = self
+            .index_move(root, self.fs.bytes_hash(old_name), id, None)
+            .and_then(|_| self.index_add_all(root, id, core::slice::from_ref(entry)));
+        self.index_check(result);
+    }
+    /// Only for Dir
+    /// Check the index against the entries. Return false if it is broken.
+    pub(crate) fn index_verify(&self) -> vfs::Result<bool> {
+        let root = self.disk_inode.read().index as BlockId;
+        if root == 0 {
+            return Ok(true);
+        }
+        let root = self.fs.device.load_struct::<DirIndexRoot>(root)?;
+        let nbuckets = root.nbuckets as usize;
+        if nbuckets == 0 || nbuckets > INDEX_MAX_BUCKETS {
+            return Ok(false);
+        }
+        let mut expected = vec![Vec::new(); nbuckets];
+        self.scan_direntry(|id, entry| {
+            if id >= 2 {
+                let hash = self.fs.entry_hash(&entry.name);
+                expected[hash as usize % nbuckets].push([hash, id as u32]);
+            }
+            None::<()>
+        })?;
+        for (block, mut expected) in root.buckets.iter().zip(expected) {
+            let mut found = match *block {
+                0 => Vec::new(),
+                block if self.fs.check_block_id(block).is_err() => return Ok(false),
+                block => {
+                    let bucket = self
+                        .fs
+                        .device
+                        .load_struct::<DirIndexBucket>(block as BlockId)?;
+                    let found = match bucket.used() {
+                        Ok(found) => found.to_vec(),
+                        Err(_) => return Ok(false),
+                    };
+                    if bucket.overflow != 0 {
+                        // only part of the entries are recorded
+                        if found.iter().any(|slot| !expected.contains(slot)) {
+                            return Ok(false);
+                        }
+                        continue;
+                    }
+                    found
+                }
+            };
+            found.sort_unstable();
+            expected.sort_unstable();
+            if found != expected {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+    /// Only for Dir
+    /// Build the index from all entries, replacing the old one
+    pub(crate) fn index_rebuild(&self) -> vfs::Result<()> {
+        let count = (self.disk_inode.read().size as usize / DIRENT_SIZE).saturating_sub(2);
+        self.index_build(count * 2 / INDEX_BUCKET_SLOTS + 1)
+    }
+    /// Only for Dir
+    /// Drop the index and free its blocks
+    pub(crate) fn index_discard(&self) {
+        let root = core::mem::take(&mut self.disk_inode.write().index) as BlockId;
+        if root == 0 {
+            return;
+        }
+        let index = match self.fs.device.load_struct::<DirIndexRoot>(root) {
+            Ok(index) if index.nbuckets as usize <= INDEX_MAX_BUCKETS => index,
+            // the blocks it points to can not be trusted
+            result => {
+                let err = result.err().unwrap_or(FsError::WrongFs);
+                warn!("sfs: leak the index of dir {}: {:?}", self.id, err);
+                return;
+            }
+        };
+        let blocks = index.buckets[..index.nbuckets as usize].to_vec();
+        let blocks = blocks.into_iter().filter(|&block| block != 0);
+        for block in blocks.map(|block| block as BlockId).chain(Some(root)) {
+            let in_use = self.fs.free_map.read().get(block).map(|bit| !*bit);
+            if in_use != Some(true) {
+                // a corrupt index, do not free a block twice
+                warn!("sfs: index of dir {} has bad block {:#x}", self.id, block);
+                continue;
+            }
+            self.fs.free_block(block);
+        }
+    }
+    /// Drop the index if updating it failed, the entries are still right
+    fn index_check(&self, result: vfs::Result<()>) {
+        if let Err(err) = result {
+            warn!("sfs: drop the index of dir {}: {:?}", self.id, err);
+            self.index_discard();
+        }
+    }
+    pub(crate) fn index_build_if_large(&self) {
+        let count = (self.disk_inode.read().size as usize / DIRENT_SIZE).saturating_sub(2);
+        if count < INDEX_THRESHOLD || self.fs.super_block.read().version < VERSION_INDEX {
+            return;
+        }
+        if let Err(err) = self.index_rebuild() {
+            warn!("sfs: cannot build the index of dir {}: {:?}", self.id, err);
+        }
+    }
+    /// Build the index with at least `nbuckets` buckets
+    fn index_build(&self, nbuckets: usize) -> vfs::Result<()> {
+        self.index_discard();
+        let mut slots = Vec::new();
+        self.scan_direntry(|id, entry| {
+            if id >= 2 {
+                slots.push([self.fs.entry_hash(&entry.name), id as u32]);
+            }
+            None::<()>
+        })?;
+        let mut nbuckets = nbuckets.clamp(1, INDEX_MAX_BUCKETS);
+        let buckets = loop {
+            let mut buckets = vec![Vec::new(); nbuckets];
+            for slot in slots.iter() {
+                buckets[slot[0] as usize % nbuckets].push(*slot);
+            }
+            let fit = buckets.iter().all(|b| b.len() <= INDEX_BUCKET_SLOTS);
+            if fit || nbuckets == INDEX_MAX_BUCKETS {
+                break buckets;
+            }
+            nbuckets = (nbuckets * 2).min(INDEX_MAX_BUCKETS);
+        };
+        let mut allocated = Vec::new();
+        match self.index_write(&buckets, &mut allocated) {
+            Ok(root) => {
+                self.disk_inode.write().index = root as u32;
+                Ok(())
+            }
+            Err(err) => {
+                for block in allocated {
+                    self.fs.free_block(block);
+                }
+                Err(err)
+            }
+        }
+    }
+    /// Write a new index with `buckets`, return its root block
+    fn index_write(
+        &self,
+        buckets: &[Vec<[u32; 2]>],
+        allocated: &mut Vec<BlockId>,
+    ) -> vfs::Result<BlockId> {
+        let mut alloc = || {
+            let block = self
+                .alloc_block(Some(self.id))
+                .ok_or(FsError::NoDeviceSpace)?;
+            allocated.push(block);
+            Ok::<_, FsError>(block)
+        };
+        let root_block = alloc()?;
+        let mut root = DirIndexRoot {
+            nbuckets: buckets.len() as u32,
+            buckets: [0; INDEX_MAX_BUCKETS],
+        };
+        let mut bucket = DirIndexBucket::empty();
+        for (i, slots) in buckets.iter().enumerate() {
+            if slots.is_empty() {
+                continue;
+            }
+            let count = slots.len().min(INDEX_BUCKET_SLOTS);
+            bucket.count = count as u32;
+            bucket.overflow = (slots.len() > count) as u32;
+            bucket.slots[..count].copy_from_slice(&slots[..count]);
+            let block = alloc()?;
+            self.fs.device.write_block(block, 0, &bucket.to_disk())?;
+            root.buckets[i] = block as u32;
+        }
+        self.fs.device.write_block(root_block, 0, &root.to_disk())?;
+        Ok(root_block)
+    }
+    /// Number of buckets, index and block of the bucket of `hash`
+    fn index_bucket_of(&self, root: BlockId, hash: u32) -> vfs::Result<(usize, usize, BlockId)> {
+        let nbuckets = self.fs.device.read_entry(root, 0)? as usize;
+        if nbuckets == 0 || nbuckets > INDEX_MAX_BUCKETS {
+            return Err(FsError::WrongFs);
+        }
+        let i = hash as usize % nbuckets;
+        let block = match self.fs.device.read_entry(root, ENTRY_SIZE * (i + 1))? {
+            0 => 0,
+            block => self.fs.check_block_id(block)?,
+        };
+        Ok((nbuckets, i, block))
+    }
+    /// Add `entries` from entry id `first` to their buckets.
+    /// If a bucket is full, grow the index instead, which takes all entries.
+    fn index_add_all(&self, root: BlockId, first: usize, entries: &[DiskEntry]) -> vfs::Result<()> {
+        for (i, entry) in entries.iter().enumerate() {
+            let hash = self.fs.entry_hash(&entry.name);
+            let (nbuckets, bucket_id, block) = self.index_bucket_of(root, hash)?;
+            let mut bucket = match block {
+                0 => DirIndexBucket::empty(),
+                _ => self.fs.device.load_struct::<DirIndexBucket>(block)?,
+            };
+            let count = bucket.used()?.len();
+            if count == INDEX_BUCKET_SLOTS {
+                if nbuckets < INDEX_MAX_BUCKETS {
+                    return self.index_build(nbuckets * 2);
+                }
+                if bucket.overflow == 0 {
+                    bucket.overflow = 1;
+                    self.fs.device.write_block(block, 0, &bucket.to_disk())?;
+                }
+                continue;
+            }
+            bucket.slots[count] = [hash, (first + i) as u32];
+            bucket.count += 1;
+            match block {
+                0 => {
+                    let block = self
+                        .alloc_block(Some(self.id))
+                        .ok_or(FsError::NoDeviceSpace)?;
+                    if let Err(err) = self.fs.device.write_block(block, 0, &bucket.to_disk()) {
+                        self.fs.free_block(block);
+                        return Err(err);
+                    }
+                    let offset = ENTRY_SIZE * (bucket_id + 1);
+                    self.fs.device.write_entry(root, offset, block as u32)?;
+                }
+                _ => self.fs.device.write_block(block, 0, &bucket.to_disk())?,
+            }
+        }
+        Ok(())
+    }
+    /// Change the entry id of the name hashed to `hash` from `from` to `to`,
+    /// or remove it if `to` is `None`
+    fn index_move(
+        &self,
+        root: BlockId,
+        hash: u32,
+        from: usize,
+        to: Option<usize>,
+    ) -> vfs::Result<()> {
+        let (_, _, block) = self.index_bucket_of(root, hash)?;
+        if block == 0 {
+            return Err(FsError::WrongFs);
+        }
+        let mut bucket = self.fs.device.load_struct::<DirIndexBucket>(block)?;
+        let pos = match bucket.position(hash, from)? {
+            Some(pos) => pos,
+            // not recorded in an overflowed bucket
+            None => return Ok(()),
+        };
+        match to {
+            Some(to) => bucket.slots[pos][1] = to as u32,
+            None => {
+                bucket.count -= 1;
+                bucket.slots[pos] = bucket.slots[bucket.count as usize];
+            }
+        }
+        self.fs.device.write_block(block, 0, &bucket.to_disk())
+    }
+}
@@ -0,0 +1,345 @@
+//! Human readable dump of a live SFS, for debugging corruption or
+//! unexpected `NoDeviceSpace`
+
+use super::*;
+use core::fmt::{self, Write};
+
+/// What `SimpleFileSystem::dump_tree()` prints
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DumpOpts {
+    /// Print the disk blocks of each inode, including index blocks
+    pub block_map: bool,
+}
+
+impl SimpleFileSystem {
+    /// Print the superblock, freemap statistics, then the tree from root
+    /// with the id, type, size, links and blocks of each inode, and the
+    /// pins of pinned ones.
+    ///
+    /// Entries of a dir are sorted by name, so the output only depends on
+    /// the content. Problems found on the way, like an entry pointing to a
+    /// free block, are printed inline where they are found. Only fails if
+    /// writing to `out` does.
+    pub fn dump_tree(&self, out: &mut dyn Write, opts: DumpOpts) -> vfs::Result<()> {
+        self.dump(out, opts).map_err(|_| FsError::InvalidParam)
+    }
+
+    fn dump(&self, out: &mut dyn Write, opts: DumpOpts) -> fmt::Result {
+        self.dump_super_block(out)?;
+        let root = match self.get_inode(self.root_id) {
+            Ok(root) => root,
+            Err(err) => return writeln!(out, "/ ! {:?}", err),
+        };
+        writeln!(out, "/ {}", Summary(&root))?;
+        if opts.block_map {
+            self.dump_blocks(out, &root, 1)?;
+        }
+        let mut visited = BTreeSet::new();
+        visited.insert(self.root_id);
+        self.dump_dir(out, &root, 1, opts, &mut visited)
+    }
+
+    fn dump_super_block(&self, out: &mut dyn Write) -> fmt::Result {
+        // before the locks below, it takes them too
+        let opts = self.options();
+        // in lock order, see `SimpleFileSystem`
+        let free_map = self.free_map.read();
+        let super_block = self.super_block.read();
+        writeln!(
+            out,
+            "superblock: {}, version {}, label {:?}",
+            if super_block.check() {
+                "valid"
+            } else {
+                "INVALID"
+            },
+            super_block.version,
+            super_block.info.as_ref()
+        )?;
+        let unused = self.unused_blocks.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "  blocks {}, unused {}, freemap blocks {}",
+            super_block.blocks, unused, super_block.freemap_blocks
+        )?;
+        let reserved = super_block.reserved_blocks as usize;
+        if self.root_id != BLKN_ROOT || reserved != 0 {
+            writeln!(
+                out,
+                "  root block {}, reserved blocks {}",
+                self.root_id, reserved
+            )?;
+        }
+        if self.names.id != NAME_POLICY_EXACT {
+            writeln!(out, "  name policy {:#x}", self.names.id)?;
+        }
+        if self.recovery_blocks != 0 {
+            writeln!(out, "  recovery blocks {}", self.recovery_blocks)?;
+        }
+        writeln!(
+            out,
+            "options: {}, inode cache {}, scratch buffers {}, dir readahead {}, io burst {}",
+            if opts.read_only {
+                "read-only"
+            } else {
+                "read-write"
+            },
+            opts.inode_cache_size,
+            opts.scratch_pool_size,
+            opts.dir_readahead,
+            opts.io_burst
+        )?;
+        writeln!(
+            out,
+            "  alloc groups {}, silly rename {}, deterministic {}, clock {}",
+            opts.alloc_groups,
+            opts.silly_rename,
+            opts.deterministic,
+            opts.time_provider.is_some()
+        )?;
+        // runs of free blocks by power of 2 of their length
+        let mut runs = [0usize; usize::BITS as usize];
+        let (mut free, mut run) = (0usize, 0usize);
+        let blocks = (super_block.blocks as usize).min(free_map.len());
+        for id in 0..=blocks {
+            if id < blocks && free_map[id] {
+                free += 1;
+                run += 1;
+            } else if run != 0 {
+                runs[(usize::BITS - 1 - run.leading_zeros()) as usize] += 1;
+                run = 0;
+            }
+        }
+        writeln!(
+            out,
+            "freemap: {} free blocks in {} runs",
+            free,
+            runs.iter().sum::<usize>()
+        )?;
+        if free != unused as usize {
+            writeln!(out, "  ! superblock counts {} unused", unused)?;
+        }
+        for (i, &count) in runs.iter().enumerate().filter(|&(_, &count)| count != 0) {
+            writeln!(
+                out,
+                "  runs of {}-{}: {}",
+                1usize << i,
+                (2usize << i) - 1,
+                count
+            )?;
+        }
+        Ok(())
+    }
+
+    fn dump_dir(
+        &self,
+        out: &mut dyn Write,
+        dir: &INodeImpl,
+        depth: usize,
+        opts: DumpOpts,
+        visited: &mut BTreeSet<INodeId>,
+    ) -> fmt::Result {
+        let indent = depth * 2;
+        let mut entries = Vec::new();
+        let scanned = dir.scan_direntry(|id, entry| {
+            if id >= 2 {
+                entries.push((String::from(entry.name.as_ref()), entry.id as INodeId));
+            }
+            None::<()>
+        });
+        if let Err(err) = scanned {
+            writeln!(out, "{:indent$}! cannot read entries: {:?}", "", err)?;
+        }
+        entries.sort();
+        for (name, id) in entries {
+            if let Some(problem) = self.check_used_block(id) {
+                writeln!(out, "{:indent$}{} (inode {}) ! {}", "", name, id, problem)?;
+                continue;
+            }
+            let inode = match self.get_inode(id) {
+                Ok(inode) => inode,
+                Err(err) => {
+                    writeln!(out, "{:indent$}{} (inode {}) ! {:?}", "", name, id, err)?;
+                    continue;
+                }
+            };
+            let is_dir = inode.disk_inode.read().type_ == FileType::Dir;
+            let slash = if is_dir { "/" } else { "" };
+            writeln!(out, "{:indent$}{}{} {}", "", name, slash, Summary(&inode))?;
+            if opts.block_map {
+                self.dump_blocks(out, &inode, depth + 1)?;
+            }
+            if !is_dir {
+                continue;
+            }
+            let inner = indent + 2;
+            if !visited.insert(id) {
+                writeln!(out, "{:inner$}! dir listed before, a loop", "")?;
+                continue;
+            }
+            match inode.read_direntry(1) {
+                Ok(dotdot) if dotdot.id as INodeId != dir.id => {
+                    writeln!(out, "{:inner$}! \"..\" is inode {}", "", dotdot.id)?;
+                }
+                Err(err) => writeln!(out, "{:inner$}! cannot read \"..\": {:?}", "", err)?,
+                Ok(_) => {}
+            }
+            self.dump_dir(out, &inode, depth + 1, opts, visited)?;
+        }
+        Ok(())
+    }
+
+    /// Print the data and index blocks of `inode`
+    fn dump_blocks(&self, out: &mut dyn Write, inode: &INodeImpl, depth: usize) -> fmt::Result {
+        let indent = depth * 2;
+        if inode.disk_inode.read().is_inline() {
+            return writeln!(out, "{:indent$}data: inline", "");
+        }
+        let (blocks, direct, indirect, db_indirect, index) = {
+            let disk_inode = inode.disk_inode.read();
+            (
+                disk_inode.blocks as usize,
+                disk_inode.direct,
+                disk_inode.indirect as BlockId,
+                disk_inode.db_indirect as BlockId,
+                disk_inode.index as BlockId,
+            )
+        };
+        let mut data: Vec<BlockId> = direct[..blocks.min(NDIRECT)]
+            .iter()
+            .map(|&id| id as BlockId)
+            .collect();
+        let mut meta = Vec::new();
+        if blocks > NDIRECT {
+            meta.push(indirect);
+            writeln!(out, "{:indent$}indirect: {}", "", indirect)?;
+            let len = blocks.min(MAX_NBLOCK_INDIRECT) - NDIRECT;
+            self.dump_table(out, indirect, len, &mut data, indent)?;
+        }
+        if blocks > MAX_NBLOCK_INDIRECT {
+            meta.push(db_indirect);
+            let len = blocks - MAX_NBLOCK_INDIRECT;
+            let mut tables = Vec::new();
+            self.dump_table(
+                out,
+                db_indirect,
+                len.div_ceil(BLK_NENTRY),
+                &mut tables,
+                indent,
+            )?;
+            writeln!(
+                out,
+                "{:indent$}db_indirect: {} -> {}",
+                "",
+                db_indirect,
+                Runs(&tables)
+            )?;
+            for (i, &table) in tables.iter().enumerate() {
+                let len = (len - i * BLK_NENTRY).min(BLK_NENTRY);
+                self.dump_table(out, table, len, &mut data, indent)?;
+            }
+            meta.extend(tables);
+        }
+        writeln!(out, "{:indent$}data: {}", "", Runs(&data))?;
+        if index != 0 {
+            meta.push(index);
+            match self.device.load_struct::<DirIndexRoot>(index) {
+                Ok(root) => {
+                    let nbuckets = (root.nbuckets as usize).min(INDEX_MAX_BUCKETS);
+                    let buckets: Vec<BlockId> = root.buckets[..nbuckets]
+                        .iter()
+                        .filter(|&&id| id != 0)
+                        .map(|&id| id as BlockId)
+                        .collect();
+                    writeln!(out, "{:indent$}index: {} -> {}", "", index, Runs(&buckets))?;
+                    meta.extend(buckets);
+                }
+                Err(err) => writeln!(
+                    out,
+                    "{:indent$}! cannot read index {}: {:?}",
+                    "", index, err
+                )?,
+            }
+        }
+        for id in data.into_iter().chain(meta) {
+            if let Some(problem) = self.check_used_block(id) {
+                writeln!(out, "{:indent$}! block {}: {}", "", id, problem)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the first `len` entries of indirect block `id` into `ids`
+    fn dump_table(
+        &self,
+        out: &mut dyn Write,
+        id: BlockId,
+        len: usize,
+        ids: &mut Vec<BlockId>,
+        indent: usize,
+    ) -> fmt::Result {
+        if let Some(problem) = self.check_used_block(id) {
+            return writeln!(out, "{:indent$}! index block {}: {}", "", id, problem);
+        }
+        match self.device.load_struct::<IndirectBlock>(id) {
+            Ok(table) => ids.extend(table.entries[..len].iter().map(|&id| id as BlockId)),
+            Err(err) => writeln!(out, "{:indent$}! cannot read block {}: {:?}", "", id, err)?,
+        }
+        Ok(())
+    }
+
+    /// What is wrong if block `id` is not in use by the fs
+    fn check_used_block(&self, id: BlockId) -> Option<&'static str> {
+        if id != self.root_id && !self.data_blocks.contains(&id) {
+            return Some("out of the fs");
+        }
+        match self.free_map.read().get(id).map(|free| *free) {
+            Some(true) => Some("marked free"),
+            Some(false) => None,
+            None => Some("out of the freemap"),
+        }
+    }
+}
+
+/// Id, type, size, links and blocks of an inode
+struct Summary<'a>(&'a INodeImpl);
+
+impl Display for Summary<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let disk_inode = self.0.disk_inode.read();
+        write!(
+            f,
+            "(inode {}, {:?}, size {}, nlinks {}, blocks {})",
+            self.0.id, disk_inode.type_, disk_inode.size, disk_inode.nlinks, disk_inode.blocks
+        )?;
+        match self.0.pins.load(Ordering::Relaxed) {
+            0 => Ok(()),
+            pins => write!(f, " pinned {}", pins),
+        }
+    }
+}
+
+/// Block ids, adjacent ones joined like "20-23"
+struct Runs<'a>(&'a [BlockId]);
+
+impl Display for Runs<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut i = 0;
+        while i < self.0.len() {
+            let start = self.0[i];
+            let mut len = 1;
+            while i + len < self.0.len() && self.0[i + len] == start + len {
+                len += 1;
+            }
+            if i != 0 {
+                write!(f, " ")?;
+            }
+            match len {
+                1 => write!(f, "{}", start)?,
+                _ => write!(f, "{}-{}", start, start + len - 1)?,
+            }
+            i += len;
+        }
+        Ok(())
+    }
+}
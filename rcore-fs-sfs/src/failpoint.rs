@@ -0,0 +1,156 @@
+//! Points inside SFS where tests can make it fail or crash, between two
+//! changes that a device fault cannot split, e.g. when both land in the
+//! same block or one is only in memory
+//!
+//! Each point is a `failpoint!()` in the code, named in `POINTS`. A test
+//! arms a point of one fs by `arm()`, so that the other fs of tests running
+//! meanwhile are not affected.
+
+// only built for tests, where a crash is a panic
+#![allow(clippy::panic)]
+
+use super::*;
+use spin::Mutex;
+
+/// Every failpoint, so that a test can go through all of them
+pub const POINTS: &[&str] = &[
+    // `create()`, after allocating the inode
+    "create_after_alloc_inode",
+    // `create()`, after writing the entry, before counting the links
+    "create_after_dirent",
+    // `symlink()`, after writing the target, before the entry
+    "symlink_after_target",
+    // `create_batch()`, after growing the dir, before writing the entries
+    "create_batch_after_resize",
+    // adding an entry at the end of a dir, after growing it, before writing
+    // the entry
+    "insert_dirent_after_grow",
+    // a new dir, after growing it, before writing "." and ".."
+    "init_dirent_after_grow",
+    // removing an entry, before writing the last one in its slot
+    "remove_dirent_before_swap",
+    // `link()`, after writing the entry, before counting the link
+    "link_after_dirent",
+    // `unlink()`, after dropping the links, before removing the entry
+    "unlink_after_nlinks_dec",
+    // `move_()` to another dir, after adding the entry there, before
+    // removing the old one
+    "move_after_append",
+    // `move_()` over an existing name, after taking over its entry, before
+    // removing the old one
+    "move_after_replace",
+    // growing past the direct blocks, after allocating the indirect ones
+    "resize_grow_after_indirect",
+    // growing, after allocating the data blocks, before zeroing them
+    "resize_grow_after_alloc",
+    // shrinking, after collecting the blocks to free
+    "resize_shrink_before_free",
+    // content stored inline growing, after allocating blocks for it
+    "uninline_after_grow",
+    // writing past the end, after growing, before writing
+    "write_after_grow",
+    // `TxnGuard::commit()`, before switching each dir to its new entries
+    "txn_commit_before_switch",
+    // `sync()`, after writing the superblock, before the freemap
+    "sync_after_super_block",
+    // `sync()`, after writing the freemap, before the inodes
+    "sync_after_freemap",
+    // `sync_partial()`, after writing the inodes, before the freemap
+    "sync_partial_after_inodes",
+    // dropping a removed inode, after freeing its content, before itself
+    "drop_after_resize0",
+    // `find()`, after reading the entry, before loading its inode
+    "find_after_entry",
+];
+
+/// What an armed failpoint does when hit
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FailAction {
+    /// Fail with `DeviceError`, as the device would
+    Error,
+    /// Panic, as a crash of the kernel would stop everything. Writes of
+    /// the fs while unwinding are not part of the crash, the device of the
+    /// test has to drop them.
+    Panic,
+    /// Wait there until `resume()`, so that a test can run others meanwhile
+    Pause,
+}
+
+struct Armed {
+    action: FailAction,
+    /// The hit it fires at, only once
+    nth: usize,
+    hits: usize,
+    /// see `resume()`
+    resumed: bool,
+}
+
+/// Armed points, by instance id of their fs
+static ARMED: Mutex<BTreeMap<(u64, &'static str), Armed>> = Mutex::new(BTreeMap::new());
+
+/// Make failpoint `name` of `fs` do `action` on its `nth` hit from now,
+/// counting from 1
+pub fn arm(fs: &SimpleFileSystem, name: &'static str, nth: usize, action: FailAction) {
+    assert!(POINTS.contains(&name), "unknown failpoint {}", name);
+    assert!(nth > 0);
+    let armed = Armed {
+        action,
+        nth,
+        hits: 0,
+        resumed: false,
+    };
+    ARMED.lock().insert((fs.instance_id, name), armed);
+}
+
+/// Disarm all failpoints of `fs`
+pub fn disarm_all(fs: &SimpleFileSystem) {
+    ARMED.lock().retain(|&(id, _), _| id != fs.instance_id);
+}
+
+/// Hits of failpoint `name` of `fs` since armed, 0 if not armed
+pub fn hits(fs: &SimpleFileSystem, name: &'static str) -> usize {
+    ARMED
+        .lock()
+        .get(&(fs.instance_id, name))
+        .map_or(0, |armed| armed.hits)
+}
+
+/// Let the thread waiting at failpoint `name` of `fs` go on, see
+/// `FailAction::Pause`
+pub fn resume(fs: &SimpleFileSystem, name: &'static str) {
+    if let Some(armed) = ARMED.lock().get_mut(&(fs.instance_id, name)) {
+        armed.resumed = true;
+    }
+}
+
+/// Called by `failpoint!()`
+pub(crate) fn hit(instance_id: u64, name: &'static str) -> vfs::Result<()> {
+    debug_assert!(POINTS.contains(&name), "unknown failpoint {}", name);
+    let action = {
+        let mut armed = ARMED.lock();
+        let armed = match armed.get_mut(&(instance_id, name)) {
+            Some(armed) => armed,
+            None => return Ok(()),
+        };
+        armed.hits += 1;
+        if armed.hits != armed.nth {
+            return Ok(());
+        }
+        armed.action
+    };
+    match action {
+        FailAction::Error => Err(FsError::DeviceError),
+        FailAction::Panic => panic!("failpoint {}", name),
+        FailAction::Pause => {
+            // also gone on when disarmed
+            while ARMED
+                .lock()
+                .get(&(instance_id, name))
+                .is_some_and(|armed| !armed.resumed)
+            {
+                core::hint::spin_loop();
+            }
+            Ok(())
+        }
+    }
+}
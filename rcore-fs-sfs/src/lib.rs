@@ -1,55 +1,354 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
+// a broken image or a failing device must not take the kernel down
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::panic,
+        clippy::unimplemented,
+        clippy::unreachable,
+        clippy::todo
+    )
+)]
 
 extern crate alloc;
 #[macro_use]
 extern crate log;
 
 use alloc::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
+    format,
     string::String,
     sync::{Arc, Weak},
     vec,
     vec::Vec,
 };
 use core::any::Any;
-use core::fmt::{Debug, Error, Formatter};
-use core::mem::MaybeUninit;
+use core::convert::TryFrom;
+use core::fmt::{Debug, Display, Error, Formatter};
+use core::mem::size_of;
+use core::ops::{ControlFlow, Range};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
 use bitvec::prelude::*;
 use spin::RwLock;
 
-use rcore_fs::dev::Device;
+use rcore_fs::dev::{CacheHint, Device, WindowedDevice};
 use rcore_fs::dirty::Dirty;
 use rcore_fs::util::*;
-use rcore_fs::vfs::{self, FileSystem, FsError, INode, MMapArea, Metadata};
+use rcore_fs::vfs::{
+    self, escape_name, AsciiCaseFoldOps, CreateContext, CreateSpec, ExactNameOps, FallocateMode,
+    FileSystem, FsError, INode, InodeFlags, MMapArea, Metadata, MetadataFlags, NameOps, Timespec,
+};
+use rcore_fs::{fs_span, fs_try};
+
+/// Failpoint `$name` of `$fs`, see `failpoint`: return `DeviceError` or
+/// panic here if armed. With `result` the error is the value instead of
+/// being returned. In functions that cannot fail, `crash` turns an armed
+/// error into a panic too.
+macro_rules! failpoint {
+    ($fs:expr, $name:expr) => {
+        #[cfg(any(test, feature = "failpoints"))]
+        $crate::failpoint::hit($fs.instance_id, $name)?;
+    };
+    (result $fs:expr, $name:expr) => {{
+        #[cfg(any(test, feature = "failpoints"))]
+        let result = $crate::failpoint::hit($fs.instance_id, $name);
+        #[cfg(not(any(test, feature = "failpoints")))]
+        let result: vfs::Result<()> = Ok(());
+        result
+    }};
+    (crash $fs:expr, $name:expr) => {
+        // a crash of the tests, see `failpoint`
+        #[cfg(any(test, feature = "failpoints"))]
+        #[allow(clippy::panic)]
+        if $crate::failpoint::hit($fs.instance_id, $name).is_err() {
+            panic!("failpoint {}", $name);
+        }
+    };
+}
 
+#[cfg(any(test, feature = "std"))]
+pub use self::archive::*;
+use self::backup::BackupCopies;
+pub use self::backup::*;
+#[cfg(any(test, feature = "std"))]
+pub use self::diff::*;
+#[cfg(any(test, feature = "debug-dump"))]
+pub use self::dump::*;
+pub use self::options::*;
+pub use self::pack::*;
+use self::pool::{ScratchPool, DEFAULT_SCRATCH_POOL_SIZE};
+use self::prefetch::TracingDevice;
+pub use self::prefetch::*;
+use self::rescue::Rescue;
+pub use self::rescue::*;
+pub use self::scrub::*;
 pub use self::structs::*;
+pub use self::txn::*;
 
+#[cfg(any(test, feature = "std"))]
+mod archive;
+mod backup;
+mod compress;
+#[cfg(any(test, feature = "std"))]
+mod diff;
+mod dir_index;
+#[cfg(any(test, feature = "debug-dump"))]
+mod dump;
+#[cfg(any(test, feature = "failpoints"))]
+pub mod failpoint;
+mod options;
+mod pack;
+mod pool;
+mod prefetch;
+mod rescue;
+mod scrub;
 mod structs;
 #[cfg(test)]
 mod tests;
+mod txn;
 
 trait DeviceExt: Device {
     fn read_block(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
         debug_assert!(offset + buf.len() <= BLKSIZE);
-        match self.read_at(id * BLKSIZE + offset, buf) {
+        let result = match self.read_at(id * BLKSIZE + offset, buf) {
             Ok(len) if len == buf.len() => Ok(()),
-            _ => panic!("cannot read block {} offset {} from device", id, offset),
-        }
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!(
+                    "cannot read block {} offset {} from device: {:?}",
+                    id, offset, err
+                );
+                Err(err.into())
+            }
+        };
+        fs_try!(result, vfs::ErrorContext::new("read_block").block(id));
+        Ok(())
     }
     fn write_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
         debug_assert!(offset + buf.len() <= BLKSIZE);
-        match self.write_at(id * BLKSIZE + offset, buf) {
+        let result = match self.write_at(id * BLKSIZE + offset, buf) {
             Ok(len) if len == buf.len() => Ok(()),
-            _ => panic!("cannot write block {} offset {} to device", id, offset),
-        }
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!(
+                    "cannot write block {} offset {} to device: {:?}",
+                    id, offset, err
+                );
+                Err(err.into())
+            }
+        };
+        fs_try!(result, vfs::ErrorContext::new("write_block").block(id));
+        Ok(())
+    }
+    /// Read like `read_block()` used as `hint` says, if the device takes
+    /// hints
+    fn read_block_hinted(
+        &self,
+        id: BlockId,
+        offset: usize,
+        buf: &mut [u8],
+        hint: CacheHint,
+    ) -> vfs::Result<()> {
+        let hinted = match (self.hinted(), hint) {
+            (Some(hinted), hint) if hint != CacheHint::Normal => hinted,
+            _ => return self.read_block(id, offset, buf),
+        };
+        debug_assert!(offset + buf.len() <= BLKSIZE);
+        let result = match hinted.read_at_hinted(id * BLKSIZE + offset, buf, hint) {
+            Ok(len) if len == buf.len() => Ok(()),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!(
+                    "cannot read block {} offset {} from device: {:?}",
+                    id, offset, err
+                );
+                Err(err.into())
+            }
+        };
+        fs_try!(result, vfs::ErrorContext::new("read_block").block(id));
+        Ok(())
+    }
+    /// Write like `write_block()` used as `hint` says, if the device takes
+    /// hints
+    fn write_block_hinted(
+        &self,
+        id: BlockId,
+        offset: usize,
+        buf: &[u8],
+        hint: CacheHint,
+    ) -> vfs::Result<()> {
+        let hinted = match (self.hinted(), hint) {
+            (Some(hinted), hint) if hint != CacheHint::Normal => hinted,
+            _ => return self.write_block(id, offset, buf),
+        };
+        debug_assert!(offset + buf.len() <= BLKSIZE);
+        let result = match hinted.write_at_hinted(id * BLKSIZE + offset, buf, hint) {
+            Ok(len) if len == buf.len() => Ok(()),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!(
+                    "cannot write block {} offset {} to device: {:?}",
+                    id, offset, err
+                );
+                Err(err.into())
+            }
+        };
+        fs_try!(result, vfs::ErrorContext::new("write_block").block(id));
+        Ok(())
+    }
+    /// Read like `read_block()` as latency-sensitive metadata, which the
+    /// device may serve before bulk data, or keep cached longer if it takes
+    /// hints
+    fn read_block_prio(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> vfs::Result<()> {
+        debug_assert!(offset + buf.len() <= BLKSIZE);
+        let offset_at = id * BLKSIZE + offset;
+        let result = match self.hinted() {
+            Some(hinted) => hinted.read_at_hinted(offset_at, buf, CacheHint::Metadata),
+            None => self.read_at_prio(offset_at, buf),
+        };
+        let result = match result {
+            Ok(len) if len == buf.len() => Ok(()),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!(
+                    "cannot read block {} offset {} from device: {:?}",
+                    id, offset, err
+                );
+                Err(err.into())
+            }
+        };
+        fs_try!(result, vfs::ErrorContext::new("read_block").block(id));
+        Ok(())
+    }
+    /// Read whole block `id` bypassing any cache
+    fn read_block_direct(&self, id: BlockId, buf: &mut [u8]) -> vfs::Result<()> {
+        debug_assert_eq!(buf.len(), BLKSIZE);
+        let result = match self.read_at_direct(id * BLKSIZE, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!("cannot read block {} directly from device: {:?}", id, err);
+                Err(err.into())
+            }
+        };
+        fs_try!(result, vfs::ErrorContext::new("read_block").block(id));
+        Ok(())
+    }
+    /// Write whole block `id` bypassing any cache
+    fn write_block_direct(&self, id: BlockId, buf: &[u8]) -> vfs::Result<()> {
+        debug_assert_eq!(buf.len(), BLKSIZE);
+        let result = match self.write_at_direct(id * BLKSIZE, buf) {
+            Ok(len) if len == buf.len() => Ok(()),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!("cannot write block {} directly to device: {:?}", id, err);
+                Err(err.into())
+            }
+        };
+        fs_try!(result, vfs::ErrorContext::new("write_block").block(id));
+        Ok(())
+    }
+    /// Fill `len` bytes from byte `offset` with zeros
+    fn zero_range(&self, offset: usize, len: usize) -> vfs::Result<()> {
+        let result = match self.write_zeros(offset, len) {
+            Ok(written) if written == len => Ok(()),
+            Ok(_) => Err(FsError::DeviceError),
+            Err(err) => {
+                warn!("cannot zero {} bytes at offset {} on device", len, offset);
+                Err(err.into())
+            }
+        };
+        fs_try!(
+            result,
+            vfs::ErrorContext::new("write_zeros").block(offset / BLKSIZE)
+        );
+        Ok(())
     }
-    /// Load struct `T` from given block in device
+    /// Load struct `T` from given block in device, as metadata
     fn load_struct<T: AsBuf>(&self, id: BlockId) -> vfs::Result<T> {
-        let mut s: T = unsafe { MaybeUninit::uninit().assume_init() };
-        self.read_block(id, 0, s.as_buf_mut())?;
+        let mut s = T::zeroed();
+        self.read_block_prio(id, 0, s.as_buf_mut())?;
+        s.convert_le();
         Ok(s)
     }
+    /// Load the inode in block `id`, failing with `Corrupted` if its type
+    /// is unknown
+    fn load_inode(&self, id: BlockId) -> vfs::Result<DiskINode> {
+        let mut buf = [0u8; size_of::<DiskINode>()];
+        self.read_block_prio(id, 0, &mut buf)?;
+        DiskINode::from_bytes(&buf).ok_or(FsError::Corrupted)
+    }
+    /// Read the block id at byte `offset` of block `id`, a table of them
+    fn read_entry(&self, id: BlockId, offset: usize) -> vfs::Result<u32> {
+        let mut entry: u32 = 0;
+        self.read_block(id, offset, entry.as_buf_mut())?;
+        Ok(u32::from_le(entry))
+    }
+    /// Write block id `entry` at byte `offset` of block `id`
+    fn write_entry(&self, id: BlockId, offset: usize, entry: u32) -> vfs::Result<()> {
+        self.write_block(id, offset, entry.to_le().as_buf())
+    }
+    /// Number of whole blocks in device, found by probing reads
+    fn probe_blocks(&self) -> usize {
+        let readable = |id: usize| {
+            let mut byte = [0u8; 1];
+            matches!(self.read_at(id * BLKSIZE + BLKSIZE - 1, &mut byte), Ok(1))
+        };
+        if !readable(0) {
+            return 0;
+        }
+        // readable(lo) && !readable(hi)
+        let (mut lo, mut hi) = (0, 1);
+        while readable(hi) {
+            lo = hi;
+            if hi > u32::MAX as usize {
+                return hi + 1;
+            }
+            hi *= 2;
+        }
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if readable(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        hi
+    }
+    /// Load a valid backup superblock, looking for it from the end of device
+    fn load_backup_super_block(&self) -> Option<(BlockId, SuperBlock)> {
+        let blocks = self.probe_blocks();
+        if blocks < 16 {
+            return None;
+        }
+        for &id in backup_super_blocks(blocks).iter().rev() {
+            let id = id as BlockId;
+            let mut s = SuperBlock::zeroed();
+            let read = self.read_at(id * BLKSIZE, s.as_buf_mut());
+            s.convert_le();
+            match read {
+                Ok(len) if len == s.as_buf().len() && s.check_backup(id) => return Some((id, s)),
+                _ => warn!("sfs: no valid backup superblock at block {}", id),
+            }
+        }
+        None
+    }
+    /// Load the freemap described by `super_block`
+    fn load_free_map(&self, super_block: &SuperBlock) -> vfs::Result<BitVec<Lsb0, u8>> {
+        let mut freemap_disk = vec![0u8; BLKSIZE * super_block.freemap_blocks as usize];
+        for i in 0..super_block.freemap_blocks as usize {
+            self.read_block(
+                BLKN_FREEMAP + i,
+                0,
+                &mut freemap_disk[i * BLKSIZE..(i + 1) * BLKSIZE],
+            )?;
+        }
+        Ok(BitVec::from_vec(freemap_disk))
+    }
 }
 
 impl DeviceExt for dyn Device {}
@@ -59,25 +358,157 @@ pub struct INodeImpl {
     /// INode number
     id: INodeId,
     /// On-disk INode
-    disk_inode: RwLock<Dirty<DiskINode>>,
+    disk_inode: RankedRwLock<Dirty<DiskINode>>,
     /// Reference to SFS, used by almost all operations
     fs: Arc<SimpleFileSystem>,
-    /// Char/block device id (major, minor)
+    /// Char/block device number, packed by `make_rdev()`
     /// e.g. crw-rw-rw- 1 root wheel 3, 2 May 13 16:40 /dev/null
-    device_inode_id: usize,
+    rdev: usize,
+    /// "." or ".." was found wrong on disk, rewrite them on sync
+    dots_stale: AtomicBool,
+    /// children loaded ahead by the last listing, see `set_dir_readahead()`
+    readahead: RwLock<Vec<Arc<INodeImpl>>>,
+    /// held while changing the entries of a dir, from looking a name up to
+    /// writing it, so that concurrent changes never both see it free
+    dir_lock: RankedRwLock<()>,
+    /// see `change_cookie()`. Taken from `SimpleFileSystem::change_clock`,
+    /// so that it never goes back when the inode is reloaded.
+    change_counter: AtomicU64,
+    /// the chunk last read of a compressed file
+    chunk_cache: spin::Mutex<compress::ChunkCache>,
+    /// blocks of this inode may be taken from the recovery reserve, see
+    /// `with_recovery()`
+    recovery: AtomicBool,
+    /// guards of `pin()` alive
+    pins: AtomicUsize,
+    /// see `set_cache_hint()`, for the blocks of content
+    cache_hint: RwLock<CacheHint>,
+}
+
+/// A held `INodeImpl::dir_lock`
+type DirGuard<'a> = RankedGuard<spin::RwLockWriteGuard<'a, ()>>;
+
+/// Where an entry is in a directory
+enum DirSlot {
+    /// The entry exists: (inode id, entry id)
+    Exist(INodeId, usize),
+    /// The entry does not exist, insert it at this entry id
+    Free(usize),
 }
 
 impl Debug for INodeImpl {
+    /// Never blocks, the fields of the disk inode are left out while it is
+    /// being written
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(
-            f,
-            "INode {{ id: {}, disk: {:?} }}",
-            self.id, self.disk_inode
-        )
+        match self.disk_inode.try_read() {
+            Some(disk_inode) => write!(
+                f,
+                "INode {{ id: {}, type: {:?}, size: {}, blocks: {}, nlinks: {}, dirty: {} }}",
+                self.id,
+                disk_inode.type_,
+                disk_inode.size,
+                disk_inode.blocks,
+                disk_inode.nlinks,
+                disk_inode.dirty()
+            ),
+            None => write!(f, "INode {{ id: {}, .. }}", self.id),
+        }
     }
 }
 
 impl INodeImpl {
+    /// Fail if the fs is read-only, else mark the image in use again if
+    /// `unmount()` marked it clean
+    fn check_writable(&self) -> vfs::Result<()> {
+        if self.fs.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.fs.remount()
+    }
+    /// Write the inode back if dirty, with its dots first if found wrong
+    fn write_inode(&self) -> vfs::Result<()> {
+        if self.dots_stale.load(Ordering::Relaxed) && !self.fs.read_only {
+            warn!("repair \".\" and \"..\" of inode {}", self.id);
+            self.write_dots(self.read_direntry(1)?.id as INodeId)?;
+            self.dots_stale.store(false, Ordering::Relaxed);
+        }
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.dirty() {
+            let generation = disk_inode.generation();
+            fs_try!(
+                self.fs
+                    .device
+                    .write_block(self.id, 0, &disk_inode.to_disk()),
+                vfs::ErrorContext::new("write_inode").inode(self.id)
+            );
+            disk_inode.sync_at(generation);
+        }
+        Ok(())
+    }
+    /// Only for Dir
+    /// Write back the inodes in memory which the entries refer to. The
+    /// caller holds `dir_lock`.
+    fn write_children(&self) -> vfs::Result<()> {
+        let mut ids = BTreeSet::new();
+        self.scan_direntry(|id, entry| {
+            if id >= 2 {
+                ids.insert(entry.id as INodeId);
+            }
+            None::<()>
+        })?;
+        for id in ids {
+            let child = self.fs.inodes.read().get(&id).and_then(INodeSlot::upgrade);
+            if let Some(child) = child {
+                child.write_inode()?;
+            }
+        }
+        Ok(())
+    }
+    /// Blocks `write_inode()` would write
+    fn dirty_blocks(&self) -> usize {
+        let dots = self.dots_stale.load(Ordering::Relaxed) && !self.fs.read_only;
+        self.disk_inode.read().dirty() as usize + dots as usize
+    }
+    fn flags(&self) -> InodeFlags {
+        InodeFlags(self.disk_inode.read().flags & InodeFlags::ALL.0)
+    }
+    /// Fail with `PermError` if any of `flags` is set
+    fn check_flags(&self, flags: InodeFlags) -> vfs::Result<()> {
+        if self.flags().intersects(flags) {
+            return Err(FsError::PermError);
+        }
+        Ok(())
+    }
+    fn is_pinned(&self) -> bool {
+        self.pins.load(Ordering::Relaxed) != 0
+    }
+    /// Fail with `Busy` if unlinking a link of this inode, pinned, would
+    /// unlink the last one
+    fn check_unpinned_unlink(&self) -> vfs::Result<()> {
+        let disk_inode = self.disk_inode.read();
+        if self.is_pinned() && (disk_inode.type_ == FileType::Dir || disk_inode.nlinks <= 1) {
+            return Err(FsError::Busy);
+        }
+        Ok(())
+    }
+    /// Fail unless `resize(len)` is allowed
+    fn check_resizable(&self, len: usize) -> vfs::Result<()> {
+        let disk_inode = self.disk_inode.read();
+        match disk_inode.type_ {
+            FileType::File | FileType::SymLink => {}
+            FileType::Dir => return Err(FsError::IsDir),
+            _ => return Err(FsError::NotFile),
+        }
+        if disk_inode.is_compressed() {
+            return Err(FsError::Unsupported);
+        }
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE)?;
+        if len < disk_inode.size as usize {
+            self.check_flags(InodeFlags::APPEND_ONLY)?;
+        }
+        Ok(())
+    }
     /// Map file block id to disk block id
     fn get_disk_block_id(&self, file_block_id: BlockId) -> vfs::Result<BlockId> {
         let disk_inode = self.disk_inode.read();
@@ -85,34 +516,35 @@ impl INodeImpl {
             id if id >= disk_inode.blocks as BlockId => Err(FsError::InvalidParam),
             id if id < MAX_NBLOCK_DIRECT => Ok(disk_inode.direct[id] as BlockId),
             id if id < MAX_NBLOCK_INDIRECT => {
-                let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    disk_inode.indirect as usize,
-                    ENTRY_SIZE * (id - NDIRECT),
-                    disk_block_id.as_buf_mut(),
-                )?;
-                Ok(disk_block_id as BlockId)
+                let disk_block_id = fs_try!(
+                    self.fs
+                        .device
+                        .read_entry(disk_inode.indirect as usize, ENTRY_SIZE * (id - NDIRECT),),
+                    vfs::ErrorContext::new("get_disk_block_id").inode(self.id)
+                );
+                self.fs.check_block_id(disk_block_id)
             }
             id if id < MAX_NBLOCK_DOUBLE_INDIRECT => {
                 // double indirect
                 let indirect_id = id - MAX_NBLOCK_INDIRECT;
-                let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    disk_inode.db_indirect as usize,
-                    ENTRY_SIZE * (indirect_id / BLK_NENTRY),
-                    indirect_block_id.as_buf_mut(),
-                )?;
-                assert!(indirect_block_id > 0);
-                let mut disk_block_id: u32 = 0;
-                self.fs.device.read_block(
-                    indirect_block_id as usize,
-                    ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
-                    disk_block_id.as_buf_mut(),
-                )?;
-                assert!(disk_block_id > 0);
-                Ok(disk_block_id as BlockId)
+                let indirect_block_id = fs_try!(
+                    self.fs.device.read_entry(
+                        disk_inode.db_indirect as usize,
+                        ENTRY_SIZE * (indirect_id / BLK_NENTRY),
+                    ),
+                    vfs::ErrorContext::new("get_disk_block_id").inode(self.id)
+                );
+                let disk_block_id = fs_try!(
+                    self.fs.device.read_entry(
+                        self.fs.check_block_id(indirect_block_id)?,
+                        ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
+                    ),
+                    vfs::ErrorContext::new("get_disk_block_id").inode(self.id)
+                );
+                self.fs.check_block_id(disk_block_id)
             }
-            _ => unimplemented!("triple indirect blocks is not supported"),
+            // more blocks than that fail `check_disk_inode()`
+            _ => Err(FsError::Corrupted),
         }
     }
     fn set_disk_block_id(&self, file_block_id: BlockId, disk_block_id: BlockId) -> vfs::Result<()> {
@@ -123,263 +555,1198 @@ impl INodeImpl {
                 Ok(())
             }
             id if id < MAX_NBLOCK_INDIRECT => {
-                let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
+                self.fs.device.write_entry(
                     self.disk_inode.read().indirect as usize,
                     ENTRY_SIZE * (id - NDIRECT),
-                    disk_block_id.as_buf(),
+                    disk_block_id as u32,
                 )?;
                 Ok(())
             }
             id if id < MAX_NBLOCK_DOUBLE_INDIRECT => {
                 // double indirect
                 let indirect_id = id - MAX_NBLOCK_INDIRECT;
-                let mut indirect_block_id: u32 = 0;
-                self.fs.device.read_block(
+                let indirect_block_id = self.fs.device.read_entry(
                     self.disk_inode.read().db_indirect as usize,
                     ENTRY_SIZE * (indirect_id / BLK_NENTRY),
-                    indirect_block_id.as_buf_mut(),
                 )?;
-                assert!(indirect_block_id > 0);
-                let disk_block_id = disk_block_id as u32;
-                self.fs.device.write_block(
-                    indirect_block_id as usize,
+                self.fs.device.write_entry(
+                    self.fs.check_block_id(indirect_block_id)?,
                     ENTRY_SIZE * (indirect_id as usize % BLK_NENTRY),
-                    disk_block_id.as_buf(),
+                    disk_block_id as u32,
                 )?;
                 Ok(())
             }
-            _ => unimplemented!("triple indirect blocks is not supported"),
+            // files never grow past double indirect blocks
+            _ => Err(FsError::InvalidParam),
+        }
+    }
+    /// Only for Dir
+    fn get_file_inode_and_entry_id(&self, name: &str) -> vfs::Result<Option<(INodeId, usize)>> {
+        let slot = fs_try!(
+            self.find_entry_or_insert_slot(name),
+            vfs::ErrorContext::new("find_entry")
+                .inode(self.id)
+                .name(name)
+        );
+        match slot {
+            DirSlot::Exist(inode_id, entry_id) => Ok(Some((inode_id, entry_id))),
+            DirSlot::Free(_) => Ok(None),
+        }
+    }
+    /// Only for Dir
+    /// `get_file_inode_and_entry_id()` by the bytes of the name. Names not
+    /// UTF-8 are only matched byte by byte, by a scan of the entries.
+    fn get_file_inode_and_entry_id_bytes(
+        &self,
+        name: &[u8],
+    ) -> vfs::Result<Option<(INodeId, usize)>> {
+        if let Ok(name) = core::str::from_utf8(name) {
+            return self.get_file_inode_and_entry_id(name);
         }
+        self.scan_direntry(|id, entry| {
+            (id >= 2 && entry.name.as_bytes() == name).then_some((entry.id as INodeId, id))
+        })
+    }
+    /// Only for Dir
+    /// Find entry `name`, or the slot to insert it if not exist, in a single pass
+    fn find_entry_or_insert_slot(&self, name: &str) -> vfs::Result<DirSlot> {
+        match name {
+            "." => return Ok(DirSlot::Exist(self.id, 0)),
+            ".." => return Ok(DirSlot::Exist(self.parent_id()?, 1)),
+            _ => {}
+        }
+        match self.index_find(name) {
+            Ok(Some(slot)) => return Ok(slot),
+            Ok(None) => {}
+            // `check_dir_indexes()` rebuilds it
+            Err(err) => warn!("sfs: index of dir {} is broken: {:?}", self.id, err),
+        }
+        let found = self.scan_direntry(|id, entry| {
+            if id >= 2 && self.fs.is_name(&entry.name, name) {
+                Some(DirSlot::Exist(entry.id as INodeId, id))
+            } else {
+                None
+            }
+        })?;
+        let count = self.disk_inode.read().size as usize / DIRENT_SIZE;
+        Ok(found.unwrap_or(DirSlot::Free(count)))
+    }
+    /// Only for Dir
+    /// Visit entries in order until `f` returns `Some`.
+    /// Content is read a whole block at a time.
+    fn scan_direntry<T>(
+        &self,
+        f: impl FnMut(usize, &DiskEntry) -> Option<T>,
+    ) -> vfs::Result<Option<T>> {
+        self.scan_direntry_from(0, f)
     }
     /// Only for Dir
-    fn get_file_inode_and_entry_id(&self, name: &str) -> Option<(INodeId, usize)> {
-        (0..self.disk_inode.read().size as usize / DIRENT_SIZE)
-            .map(|i| (self.read_direntry(i as usize).unwrap(), i))
-            .find(|(entry, _)| entry.name.as_ref() == name)
-            .map(|(entry, id)| (entry.id as INodeId, id as usize))
+    /// `scan_direntry()` from entry `start` on
+    fn scan_direntry_from<T>(
+        &self,
+        start: usize,
+        mut f: impl FnMut(usize, &DiskEntry) -> Option<T>,
+    ) -> vfs::Result<Option<T>> {
+        let count = self.disk_inode.read().size as usize / DIRENT_SIZE;
+        // an entry may cross the block boundary, keep its head in buf
+        let mut buf = self.fs.scratch.acquire();
+        let mut buf_len = 0;
+        // from the start of the block of entry `start`
+        let mut offset = start * DIRENT_SIZE / BLKSIZE * BLKSIZE;
+        let mut skip = start * DIRENT_SIZE - offset;
+        let mut entry = DiskEntry {
+            id: 0,
+            name: Str256([0; 256]),
+        };
+        let mut id = start;
+        while id < count {
+            let len = self._read_entries_at(offset, &mut buf[buf_len..buf_len + BLKSIZE])?;
+            if len == 0 {
+                break;
+            }
+            offset += len;
+            buf_len += len;
+            let mut pos = core::mem::take(&mut skip);
+            while pos + DIRENT_SIZE <= buf_len && id < count {
+                entry
+                    .as_buf_mut()
+                    .copy_from_slice(&buf[pos..pos + DIRENT_SIZE]);
+                entry.convert_le();
+                if let Some(ret) = f(id, &entry) {
+                    return Ok(Some(ret));
+                }
+                pos += DIRENT_SIZE;
+                id += 1;
+            }
+            buf.copy_within(pos..buf_len, 0);
+            buf_len -= pos;
+        }
+        Ok(None)
     }
-    fn get_file_inode_id(&self, name: &str) -> Option<INodeId> {
-        self.get_file_inode_and_entry_id(name)
-            .map(|(inode_id, _)| inode_id)
+    fn get_file_inode_id(&self, name: &str) -> vfs::Result<Option<INodeId>> {
+        Ok(self
+            .get_file_inode_and_entry_id(name)?
+            .map(|(inode_id, _)| inode_id))
     }
     /// Init dir content. Insert 2 init entries.
     /// This do not init nlinks, please modify the nlinks in the invoker.
     fn init_direntry(&self, parent: INodeId) -> vfs::Result<()> {
         // Insert entries: '.' '..'
-        self._resize(DIRENT_SIZE * 2)?;
-        self.write_direntry(
-            0,
-            &DiskEntry {
-                id: self.id as u32,
-                name: Str256::from("."),
-            },
-        )?;
-        self.write_direntry(
-            1,
-            &DiskEntry {
-                id: parent as u32,
-                name: Str256::from(".."),
-            },
-        )?;
+        self.grow_entries(0, DIRENT_SIZE * 2, || {
+            failpoint!(result self.fs, "init_dirent_after_grow")
+                .and_then(|()| self.write_dots(parent))
+        })
+    }
+    /// Only for Dir
+    /// Write "." and "..", pointing to self and `parent`.
+    /// They are pinned at entry 0 and 1, no other method writes there.
+    fn write_dots(&self, parent: INodeId) -> vfs::Result<()> {
+        let dots = [
+            DiskEntry::new(self.id as u32, Str256::from("."), FileType::Dir),
+            DiskEntry::new(parent as u32, Str256::from(".."), FileType::Dir),
+        ];
+        for (id, entry) in dots.iter().enumerate() {
+            if self._write_at(DIRENT_SIZE * id, &entry.to_disk())? != DIRENT_SIZE {
+                return Err(FsError::DeviceError);
+            }
+        }
+        Ok(())
+    }
+    /// Only for Dir
+    /// Id of the parent dir, from "..".
+    /// If "." or ".." is found wrong, they are repaired on the next sync.
+    fn parent_id(&self) -> vfs::Result<INodeId> {
+        let dot = self.read_direntry(0)?;
+        let dotdot = self.read_direntry(1)?;
+        if dot.id as INodeId != self.id || dot.name != *"." || dotdot.name != *".." {
+            warn!("\".\" or \"..\" of inode {} is corrupted", self.id);
+            self.dots_stale.store(true, Ordering::Relaxed);
+        }
+        Ok(dotdot.id as INodeId)
+    }
+    /// Only for Dir
+    /// Inodes with a hidden entry of `silly_rename()` in this dir
+    fn hidden_entries(&self) -> BTreeSet<INodeId> {
+        self.fs
+            .silly_renamed
+            .read()
+            .iter()
+            .filter(|&(_, &dir)| dir == self.id)
+            .map(|(&inode, _)| inode)
+            .collect()
+    }
+    /// Only for Dir
+    /// Entry id of the `id`th listed entry, skipping hidden entries of
+    /// `silly_rename()` and those lost in rescue mode
+    fn listed_entry_id(&self, id: usize) -> vfs::Result<usize> {
+        let hidden = self.hidden_entries();
+        if (hidden.is_empty() && self.fs.rescue.is_none()) || id < 2 {
+            return Ok(id);
+        }
+        let mut listed = 2;
+        self.scan_direntry(|entry_id, entry| {
+            if entry_id < 2 || !self.is_listed(entry_id, entry, &hidden) {
+                return None;
+            }
+            listed += 1;
+            (listed > id).then_some(entry_id)
+        })?
+        .ok_or(FsError::EntryNotFound)
+    }
+    /// Only for Dir
+    /// Whether entry `entry_id` is listed: it is neither a hidden entry of
+    /// the inodes in `hidden` nor lost in rescue mode
+    fn is_listed(&self, entry_id: usize, entry: &DiskEntry, hidden: &BTreeSet<INodeId>) -> bool {
+        let inode_id = entry.id as INodeId;
+        if hidden.contains(&inode_id) && entry.name == *silly_name(inode_id).as_str() {
+            return false;
+        }
+        !self.fs.is_lost(self.id, entry_id, entry)
+    }
+    /// Only for Dir
+    /// Hold `dir_lock` while changing the entries
+    fn lock_dir(&self) -> DirGuard<'_> {
+        self.dir_lock.write()
+    }
+    /// Only for Dirs
+    /// Lock this dir and `other`, which may be the same, in order of inode id
+    fn lock_dirs<'a>(&'a self, other: &'a INodeImpl) -> (DirGuard<'a>, Option<DirGuard<'a>>) {
+        if self.id == other.id {
+            return (self.lock_dir(), None);
+        }
+        let (first, second) = match self.id < other.id {
+            true => (self, other),
+            false => (other, self),
+        };
+        let first = first.lock_dir();
+        (first, Some(second.lock_dir()))
+    }
+    /// Only for Dir
+    /// Keep `inode` which is open under a hidden name instead of unlinking
+    /// entry `entry_id` of it, see `set_silly_rename()`
+    fn silly_rename(&self, entry_id: usize, name: &[u8], inode: &INodeImpl) -> vfs::Result<()> {
+        let hidden = silly_name(inode.id);
+        if let DirSlot::Exist(..) = self.find_entry_or_insert_slot(&hidden)? {
+            return Err(FsError::EntryExist);
+        }
+        let type_ = inode.disk_inode.read().type_;
+        let entry = DiskEntry::new(inode.id as u32, Str256::new(&hidden)?, type_);
+        self.write_direntry(entry_id, &entry)?;
+        self.with_recovery(|| self.index_rename(entry_id, name, &entry));
+        self.fs.silly_renamed.write().insert(inode.id, self.id);
+        Ok(())
+    }
+    /// Unlink the hidden name of `silly_rename()` on the last close
+    fn remove_silly_name(&self) -> vfs::Result<()> {
+        // not held while loading the dir, which may wait for it to drop
+        let dir = self.fs.silly_renamed.write().remove(&self.id);
+        let dir = match dir {
+            Some(dir) => self.fs.get_inode(dir)?,
+            None => return Ok(()),
+        };
+        let _dir = dir.lock_dir();
+        // it may have been moved away by now
+        if let Some((inode_id, entry_id)) = dir.get_file_inode_and_entry_id(&silly_name(self.id))? {
+            if inode_id == self.id {
+                dir.remove_direntry(entry_id)?;
+                self.nlinks_dec()?;
+            }
+        }
+        Ok(())
+    }
+    /// Only for Dir
+    /// Inode `inode_id` of entry `id`. If it is not in memory, load the
+    /// inodes of the entries after it too, see `set_dir_readahead()`.
+    fn child_inode(&self, id: usize, inode_id: INodeId) -> vfs::Result<Arc<INodeImpl>> {
+        let count = self.fs.dir_readahead.load(Ordering::Relaxed);
+        if id >= 2 && count > 1 && !self.fs.is_resident(inode_id) {
+            let end = (self.disk_inode.read().size as usize / DIRENT_SIZE).min(id + count);
+            let mut buf = vec![0u8; end.saturating_sub(id) * DIRENT_SIZE];
+            let len = self
+                ._read_entries_at(id * DIRENT_SIZE, &mut buf)
+                .unwrap_or(0);
+            let ids = buf[..len]
+                .chunks_exact(DIRENT_SIZE)
+                .map(|entry| {
+                    u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) as INodeId
+                })
+                .collect();
+            let loaded = self.fs.load_inodes(ids);
+            *self.readahead.write() = loaded;
+        }
+        self.fs.get_inode(inode_id)
+    }
+    /// Only for Dir
+    /// Load with `load` the inode of `first`, an entry read by `read` when
+    /// `change_cookie()` was `cookie`. Entries are read without `dir_lock`,
+    /// so the entry may be unlinked meanwhile, its inode reclaimed and its
+    /// block even reused by a new inode. So if the entries changed, it is
+    /// read again, until it points to the inode loaded.
+    fn load_entry_inode<T>(
+        &self,
+        mut cookie: u64,
+        first: (INodeId, T),
+        mut read: impl FnMut() -> vfs::Result<(INodeId, T)>,
+        mut load: impl FnMut(INodeId, &T) -> vfs::Result<Arc<INodeImpl>>,
+    ) -> vfs::Result<(Arc<INodeImpl>, T)> {
+        let (mut inode_id, mut entry) = first;
+        loop {
+            let inode = load(inode_id, &entry);
+            let now = self.change_cookie();
+            if now == cookie {
+                return Ok((inode?, entry));
+            }
+            cookie = now;
+            let (again, again_entry) = read()?;
+            match inode {
+                Ok(inode) if again == inode_id => return Ok((inode, again_entry)),
+                _ => (inode_id, entry) = (again, again_entry),
+            }
+        }
+    }
+    /// Only for Dir
+    /// Drop inode `id` from the readahead of this dir
+    fn forget_readahead(&self, id: INodeId) {
+        self.readahead.write().retain(|inode| inode.id != id);
+    }
+    /// Fail unless entry `id` can be listed, a removed dir only lists "."
+    fn check_entry_id(&self, id: usize) -> vfs::Result<()> {
+        let disk_inode = self.disk_inode.read();
+        if disk_inode.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let count = match disk_inode.nlinks {
+            0 => 1,
+            _ => disk_inode.size as usize / DIRENT_SIZE,
+        };
+        if id >= count {
+            return Err(FsError::EntryNotFound);
+        }
         Ok(())
     }
+    /// Only for Dir
+    /// Inode id and name of entry `id`.
+    /// Names of "." and ".." are not read from disk, nor is the id of ".".
+    fn entry_at(&self, id: usize) -> vfs::Result<(INodeId, String)> {
+        match id {
+            0 | 1 => {
+                let parent = self.parent_id()?;
+                if id == 0 {
+                    Ok((self.id, String::from(".")))
+                } else {
+                    Ok((parent, String::from("..")))
+                }
+            }
+            _ => {
+                let entry = self.read_direntry(id)?;
+                let name = escape_name(entry.name.as_bytes()).into_owned();
+                Ok((entry.id as INodeId, name))
+            }
+        }
+    }
     fn read_direntry(&self, id: usize) -> vfs::Result<DiskEntry> {
-        let mut direntry: DiskEntry = unsafe { MaybeUninit::uninit().assume_init() };
-        self._read_at(DIRENT_SIZE * id, direntry.as_buf_mut())?;
+        let mut direntry = DiskEntry::zeroed();
+        match self._read_entries_at(DIRENT_SIZE * id, direntry.as_buf_mut())? {
+            DIRENT_SIZE => {}
+            // removed since looked up, as entries are read without `dir_lock`
+            0 => return Err(FsError::EntryNotFound),
+            _ => return Err(FsError::DeviceError),
+        }
+        direntry.convert_le();
+        if direntry.id == 0 {
+            // appended, but not written yet
+            return Err(FsError::EntryNotFound);
+        }
         Ok(direntry)
     }
     fn write_direntry(&self, id: usize, direntry: &DiskEntry) -> vfs::Result<()> {
-        self._write_at(DIRENT_SIZE * id, direntry.as_buf())?;
+        Self::check_user_slot(id)?;
+        if self._write_at(DIRENT_SIZE * id, &direntry.to_disk())? != DIRENT_SIZE {
+            return Err(FsError::DeviceError);
+        }
+        self.entries_changed();
+        Ok(())
+    }
+    /// Only for Dir
+    /// Bump `change_counter`, once the change can be found
+    fn entries_changed(&self) {
+        let now = self.fs.change_clock.fetch_add(1, Ordering::SeqCst) + 1;
+        self.change_counter.fetch_max(now, Ordering::SeqCst);
+    }
+    /// Fail for entry 0 and 1, which are "." and ".."
+    fn check_user_slot(id: usize) -> vfs::Result<()> {
+        debug_assert!(id >= 2, "entry {} is reserved for dots", id);
+        if id < 2 {
+            return Err(FsError::InvalidParam);
+        }
         Ok(())
     }
     fn append_direntry(&self, direntry: &DiskEntry) -> vfs::Result<()> {
         let size = self.disk_inode.read().size as usize;
         let dirent_count = size / DIRENT_SIZE;
-        self._resize(size + DIRENT_SIZE)?;
-        self.write_direntry(dirent_count, direntry)?;
+        self.insert_direntry(dirent_count, direntry)
+    }
+    /// Write a new entry to the slot from `find_entry_or_insert_slot()`
+    fn insert_direntry(&self, id: usize, direntry: &DiskEntry) -> vfs::Result<()> {
+        Self::check_user_slot(id)?;
+        let size = self.disk_inode.read().size as usize;
+        if id == size / DIRENT_SIZE {
+            self.grow_entries(size, size + DIRENT_SIZE, || {
+                failpoint!(result self.fs, "insert_dirent_after_grow")
+                    .and_then(|()| self.write_direntry(id, direntry))
+            })?;
+        } else {
+            self.write_direntry(id, direntry)?;
+        }
+        self.index_insert(id, core::slice::from_ref(direntry));
         Ok(())
     }
+    /// Only for Dir
+    /// Grow the entries from `size` to `len` bytes, then write the new ones
+    /// by `write`. If it fails, what it may have written is zeroed and the
+    /// dir shrunk back, so that no slot is counted before it is written.
+    fn grow_entries(
+        &self,
+        size: usize,
+        len: usize,
+        write: impl FnOnce() -> vfs::Result<()>,
+    ) -> vfs::Result<()> {
+        self._resize(len)?;
+        let err = match write() {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        if let Err(clean_err) = self._clean_at(size, len) {
+            // shrunk anyway, and a slot past the end is not read
+            warn!(
+                "sfs: cannot zero failed entries of dir {}: {:?}",
+                self.id, clean_err
+            );
+        }
+        self._resize(size)?;
+        Err(err)
+    }
     /// remove a direntry in middle of file and insert the last one here, useful for direntry remove
     /// should be only used in unlink
     fn remove_direntry(&self, id: usize) -> vfs::Result<()> {
         let size = self.disk_inode.read().size as usize;
         let dirent_count = size / DIRENT_SIZE;
+        // "." and ".." are pinned at 0 and 1, readers rely on it
+        Self::check_user_slot(id)?;
         debug_assert!(id < dirent_count);
         let last_dirent = self.read_direntry(dirent_count - 1)?;
+        let removed = match self.has_index() {
+            true => Some(self.read_direntry(id)?),
+            false => None,
+        };
+        let len = size - DIRENT_SIZE;
+        // everything that can fail is done before the inode is changed,
+        // and the swap-in is written while the old size is still in effect
+        let blocks = Self::blocks_for(len);
+        let freed = self.blocks_to_free(blocks)?;
+        failpoint!(self.fs, "remove_dirent_before_swap");
         self.write_direntry(id, &last_dirent)?;
-        self._resize(size - DIRENT_SIZE)?;
+        // past the end is zeroed, so that an entry appended there is not
+        // found before it is written, see `read_direntry()`
+        if let Err(err) = self._clean_at(len, size) {
+            // only concurrent readers may find it, the entry is gone anyway
+            warn!(
+                "sfs: cannot zero removed entry of dir {}: {:?}",
+                self.id, err
+            );
+        }
+        self._shrink(len, blocks, freed);
+        // the last entry may be the one removed, gone only now
+        self.entries_changed();
+        if let Some(removed) = removed {
+            self.index_remove(id, &removed, dirent_count - 1, &last_dirent);
+        }
         Ok(())
     }
+    /// Number of blocks for content of `len` bytes
+    fn blocks_for(len: usize) -> u32 {
+        len.div_ceil(BLKSIZE) as u32
+    }
     /// Resize content size, no matter what type it is.
+    ///
+    /// Growing keeps the blocks reserved past the end by `fallocate()`,
+    /// shrinking frees them. Content stored inline moves to blocks once
+    /// larger than `MAX_INLINE_SIZE`, and stays there when shrunk.
     fn _resize(&self, len: usize) -> vfs::Result<()> {
         if len > MAX_FILE_SIZE {
             return Err(FsError::InvalidParam);
         }
-        let blocks = ((len + BLKSIZE - 1) / BLKSIZE) as u32;
+        let blocks = Self::blocks_for(len);
         if blocks > MAX_NBLOCK_DOUBLE_INDIRECT as u32 {
             return Err(FsError::InvalidParam);
         }
-        use core::cmp::Ordering;
-        let old_blocks = self.disk_inode.read().blocks;
-        match blocks.cmp(&old_blocks) {
-            Ordering::Equal => {
-                self.disk_inode.write().size = len as u32;
-            }
-            Ordering::Greater => {
-                let mut disk_inode = self.disk_inode.write();
-                disk_inode.blocks = blocks;
-                // allocate indirect block if needed
-                if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
-                    disk_inode.indirect = self.fs.alloc_block().expect("no space") as u32;
-                }
-                // allocate double indirect block if needed
-                if blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    if disk_inode.db_indirect == 0 {
-                        disk_inode.db_indirect = self.fs.alloc_block().expect("no space") as u32;
-                    }
-                    let indirect_begin = {
-                        if (old_blocks as usize) < MAX_NBLOCK_INDIRECT {
-                            0
-                        } else {
-                            (old_blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
-                        }
-                    };
-                    let indirect_end = (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let indirect = self.fs.alloc_block().expect("no space") as u32;
-                        self.fs.device.write_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf(),
-                        )?;
-                    }
-                }
-                drop(disk_inode);
-                // allocate extra blocks
-                for i in old_blocks..blocks {
-                    let disk_block_id = self.fs.alloc_block().expect("no space");
-                    self.set_disk_block_id(i as usize, disk_block_id)?;
-                }
-                // clean up
-                let mut disk_inode = self.disk_inode.write();
-                let old_size = disk_inode.size as usize;
-                disk_inode.size = len as u32;
-                drop(disk_inode);
-                self._clean_at(old_size, len)?;
-            }
-            Ordering::Less => {
-                // free extra blocks
-                for i in blocks..old_blocks {
-                    let disk_block_id = self.get_disk_block_id(i as usize)?;
-                    self.fs.free_block(disk_block_id);
-                }
-                let mut disk_inode = self.disk_inode.write();
-                // free indirect block if needed
-                if blocks < MAX_NBLOCK_DIRECT as u32
-                    && disk_inode.blocks >= MAX_NBLOCK_DIRECT as u32
-                {
-                    self.fs.free_block(disk_inode.indirect as usize);
-                    disk_inode.indirect = 0;
-                }
-                // free double indirect block if needed
-                if disk_inode.blocks >= MAX_NBLOCK_INDIRECT as u32 {
-                    let indirect_begin = {
-                        if (blocks as usize) < MAX_NBLOCK_INDIRECT {
-                            0
-                        } else {
-                            (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
-                        }
-                    };
-                    let indirect_end =
-                        (disk_inode.blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
-                    for i in indirect_begin..indirect_end {
-                        let mut indirect: u32 = 0;
-                        self.fs.device.read_block(
-                            disk_inode.db_indirect as usize,
-                            ENTRY_SIZE * i,
-                            indirect.as_buf_mut(),
-                        )?;
-                        assert!(indirect > 0);
-                        self.fs.free_block(indirect as usize);
-                    }
-                    if blocks < MAX_NBLOCK_INDIRECT as u32 {
-                        assert!(disk_inode.db_indirect > 0);
-                        self.fs.free_block(disk_inode.db_indirect as usize);
-                        disk_inode.db_indirect = 0;
-                    }
-                }
-                disk_inode.blocks = blocks;
-                disk_inode.size = len as u32;
+        let mut disk_inode = self.disk_inode.write();
+        if disk_inode.is_inline() {
+            if len <= MAX_INLINE_SIZE {
+                disk_inode.resize_inline(len);
+                return Ok(());
+            }
+            let old_size = disk_inode.size as usize;
+            drop(disk_inode);
+            self._uninline()?;
+            let result = self._resize(len);
+            if result.is_err() {
+                self._reinline(old_size)?;
+            }
+            return result;
+        }
+        drop(disk_inode);
+        let (old_size, old_blocks) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.size as usize, disk_inode.blocks)
+        };
+        if blocks > old_blocks {
+            self._grow_blocks(old_blocks, blocks, len)
+        } else if len > old_size {
+            // reserved blocks are zeroed already
+            self.disk_inode.write().size = len as u32;
+            Ok(())
+        } else {
+            self._truncate(len, blocks)
+        }
+    }
+    /// Grow content to `len` with `blocks` blocks like `_grow()`, rolling
+    /// back on error, so that no change is published if the device fails
+    fn _grow_blocks(&self, old_blocks: u32, blocks: u32, len: usize) -> vfs::Result<()> {
+        let backup = {
+            let disk_inode = self.disk_inode.read();
+            (
+                disk_inode.size,
+                disk_inode.blocks,
+                disk_inode.direct,
+                disk_inode.indirect,
+                disk_inode.db_indirect,
+            )
+        };
+        let mut allocated = Vec::new();
+        if let Err(err) = self._grow(old_blocks, blocks, len, &mut allocated) {
+            let mut disk_inode = self.disk_inode.write();
+            disk_inode.size = backup.0;
+            disk_inode.blocks = backup.1;
+            disk_inode.direct = backup.2;
+            disk_inode.indirect = backup.3;
+            disk_inode.db_indirect = backup.4;
+            drop(disk_inode);
+            for block_id in allocated {
+                self.fs.free_block(block_id);
             }
+            return Err(err);
         }
         Ok(())
     }
-    // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success
-    /// Read/Write content, no matter what type it is
-    fn _io_at<F>(&self, begin: usize, end: usize, mut f: F) -> vfs::Result<usize>
-    where
-        F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
-    {
-        let size = self.disk_inode.read().size as usize;
-        let iter = BlockIter {
-            begin: size.min(begin),
-            end: size.min(end),
-            block_size_log2: BLKSIZE_LOG2,
+    /// Move the content stored inline into blocks, if it is. It is left
+    /// inline on error.
+    fn _uninline(&self) -> vfs::Result<()> {
+        let (size, data) = {
+            let mut disk_inode = self.disk_inode.write();
+            if !disk_inode.is_inline() {
+                return Ok(());
+            }
+            let data = disk_inode.inline_data();
+            disk_inode.set_inline_data(&[0; MAX_INLINE_SIZE]);
+            disk_inode.flags &= !INODE_INLINE;
+            (disk_inode.size as usize, data)
+        };
+        let result = self
+            ._grow_blocks(0, Self::blocks_for(size), size)
+            .and_then(|()| {
+                failpoint!(self.fs, "uninline_after_grow");
+                match self._write_at(0, &data[..size])? {
+                    len if len == size => Ok(()),
+                    _ => Err(FsError::DeviceError),
+                }
+            });
+        if let Err(err) = result {
+            self._truncate(size, 0)?;
+            let mut disk_inode = self.disk_inode.write();
+            disk_inode.set_inline_data(&data);
+            disk_inode.flags |= INODE_INLINE;
+            return Err(err);
+        }
+        Ok(())
+    }
+    /// Store the first `len` bytes of content inline again and free the
+    /// blocks, to roll back growing content once inline
+    fn _reinline(&self, len: usize) -> vfs::Result<()> {
+        let mut data = [0; MAX_INLINE_SIZE];
+        if self._read_at(0, &mut data[..len])? != len {
+            return Err(FsError::DeviceError);
+        }
+        self._truncate(len, 0)?;
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.set_inline_data(&data);
+        disk_inode.flags |= INODE_INLINE;
+        Ok(())
+    }
+    /// Set the size to `len`, freeing the blocks after the first `blocks`
+    fn _truncate(&self, len: usize, blocks: u32) -> vfs::Result<()> {
+        {
+            let mut disk_inode = self.disk_inode.write();
+            if disk_inode.is_inline() {
+                disk_inode.resize_inline(len);
+                return Ok(());
+            }
+        }
+        let freed = self.blocks_to_free(blocks)?;
+        failpoint!(self.fs, "resize_shrink_before_free");
+        self._shrink(len, blocks, freed);
+        Ok(())
+    }
+    /// Collect the blocks to free when shrinking to `blocks` blocks,
+    /// including the indirect blocks no longer needed.
+    ///
+    /// It only reads, so a device error leaves the inode intact
+    /// and a retry never frees a block twice.
+    fn blocks_to_free(&self, blocks: u32) -> vfs::Result<Vec<BlockId>> {
+        let (old_blocks, indirect, db_indirect) = {
+            let disk_inode = self.disk_inode.read();
+            (
+                disk_inode.blocks,
+                disk_inode.indirect,
+                disk_inode.db_indirect,
+            )
+        };
+        let mut freed = Vec::new();
+        for i in blocks..old_blocks {
+            freed.push(self.get_disk_block_id(i as usize)?);
+        }
+        // indirect block
+        if blocks < MAX_NBLOCK_DIRECT as u32 && old_blocks >= MAX_NBLOCK_DIRECT as u32 {
+            freed.push(indirect as usize);
+        }
+        // double indirect block and the indirect blocks in it
+        if old_blocks >= MAX_NBLOCK_INDIRECT as u32 {
+            let indirect_begin = {
+                if (blocks as usize) < MAX_NBLOCK_INDIRECT {
+                    0
+                } else {
+                    (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
+                }
+            };
+            let indirect_end = (old_blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
+            for i in indirect_begin..indirect_end {
+                let indirect = self
+                    .fs
+                    .device
+                    .read_entry(db_indirect as usize, ENTRY_SIZE * i)?;
+                freed.push(self.fs.check_block_id(indirect)?);
+            }
+            if blocks < MAX_NBLOCK_INDIRECT as u32 {
+                freed.push(db_indirect as usize);
+            }
+        }
+        Ok(freed)
+    }
+    /// Shrink content to `len` in `blocks` blocks, then free `freed` from
+    /// `blocks_to_free()`
+    fn _shrink(&self, len: usize, blocks: u32, freed: Vec<BlockId>) {
+        let mut disk_inode = self.disk_inode.write();
+        if blocks < MAX_NBLOCK_DIRECT as u32 {
+            disk_inode.indirect = 0;
+        }
+        if blocks < MAX_NBLOCK_INDIRECT as u32 {
+            disk_inode.db_indirect = 0;
+        }
+        disk_inode.blocks = blocks;
+        disk_inode.size = len as u32;
+        drop(disk_inode);
+        for block_id in freed {
+            self.fs.free_block(block_id);
+        }
+    }
+    /// Grow content to `len` with `blocks` blocks, record newly allocated blocks in `allocated`.
+    /// Blocks past `len` are zeroed, as they are reserved by `fallocate()`.
+    fn _grow(
+        &self,
+        old_blocks: u32,
+        blocks: u32,
+        len: usize,
+        allocated: &mut Vec<BlockId>,
+    ) -> vfs::Result<()> {
+        // blocks are allocated without the inode locked, see "Lock order"
+        // of `SimpleFileSystem`
+        let (mut indirect, mut db_indirect) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.indirect, disk_inode.db_indirect)
+        };
+        // each block is allocated after the one before, the first one after
+        // the inode, see `set_alloc_groups()`
+        let mut goal = match old_blocks {
+            0 => self.id,
+            _ => self.get_disk_block_id(old_blocks as usize - 1)?,
+        };
+        // allocate indirect block if needed
+        if old_blocks < MAX_NBLOCK_DIRECT as u32 && blocks >= MAX_NBLOCK_DIRECT as u32 {
+            indirect = self.alloc_for_grow(allocated, &mut goal)? as u32;
+            failpoint!(self.fs, "resize_grow_after_indirect");
+        }
+        // allocate double indirect block if needed
+        if blocks >= MAX_NBLOCK_INDIRECT as u32 {
+            if db_indirect == 0 {
+                db_indirect = self.alloc_for_grow(allocated, &mut goal)? as u32;
+            }
+            let indirect_begin = {
+                if (old_blocks as usize) < MAX_NBLOCK_INDIRECT {
+                    0
+                } else {
+                    (old_blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1
+                }
+            };
+            let indirect_end = (blocks as usize - MAX_NBLOCK_INDIRECT) / BLK_NENTRY + 1;
+            for i in indirect_begin..indirect_end {
+                let indirect = self.alloc_for_grow(allocated, &mut goal)? as u32;
+                self.fs
+                    .device
+                    .write_entry(db_indirect as usize, ENTRY_SIZE * i, indirect)?;
+            }
+        }
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.blocks = blocks;
+        disk_inode.indirect = indirect;
+        disk_inode.db_indirect = db_indirect;
+        drop(disk_inode);
+        // allocate extra blocks
+        for i in old_blocks..blocks {
+            let disk_block_id = self.alloc_for_grow(allocated, &mut goal)?;
+            self.set_disk_block_id(i as usize, disk_block_id)?;
+        }
+        failpoint!(self.fs, "resize_grow_after_alloc");
+        // clean up, the new blocks of dirs before the size is published, as
+        // their entries are read without `dir_lock`
+        let dir = self.disk_inode.read().type_ == FileType::Dir;
+        if dir {
+            self._zero_blocks(old_blocks, blocks)?;
+        }
+        let mut disk_inode = self.disk_inode.write();
+        let old_size = disk_inode.size as usize;
+        disk_inode.size = len as u32;
+        drop(disk_inode);
+        if dir {
+            self._clean_at(old_size, len.min(old_blocks as usize * BLKSIZE))?;
+        } else {
+            self._clean_at(old_size, len)?;
+            self._zero_blocks(old_blocks.max(Self::blocks_for(len)), blocks)?;
+        }
+        Ok(())
+    }
+    /// Zero blocks `begin..end` of the content, which may be past the end
+    fn _zero_blocks(&self, begin: u32, end: u32) -> vfs::Result<()> {
+        // (first block, count) on device not zeroed yet
+        let mut run = (0, 0);
+        for i in begin..end {
+            let block_id = self.get_disk_block_id(i as usize)?;
+            if run.1 != 0 && run.0 + run.1 == block_id {
+                run.1 += 1;
+                continue;
+            }
+            if run.1 != 0 {
+                self.fs
+                    .device
+                    .zero_range(run.0 * BLKSIZE, run.1 * BLKSIZE)?;
+            }
+            run = (block_id, 1);
+        }
+        if run.1 != 0 {
+            self.fs
+                .device
+                .zero_range(run.0 * BLKSIZE, run.1 * BLKSIZE)?;
+        }
+        Ok(())
+    }
+    /// Allocate a block near `goal`, which becomes the block
+    fn alloc_for_grow(
+        &self,
+        allocated: &mut Vec<BlockId>,
+        goal: &mut BlockId,
+    ) -> vfs::Result<BlockId> {
+        let block_id = self
+            .alloc_block(Some(*goal))
+            .ok_or(FsError::NoDeviceSpace)?;
+        allocated.push(block_id);
+        *goal = block_id;
+        Ok(block_id)
+    }
+    /// Allocate a block for this inode, from the recovery reserve too if
+    /// allowed by `with_recovery()`
+    fn alloc_block(&self, goal: Option<BlockId>) -> Option<usize> {
+        match self.recovery.load(Ordering::Relaxed) {
+            true => self.fs.alloc_block_reserved(goal),
+            false => self.fs.alloc_block(goal),
+        }
+    }
+    /// Run `f`, which deletes or repairs, letting it allocate blocks of this
+    /// inode from the recovery reserve. Only for what holds the inode from
+    /// other changes meanwhile, e.g. `dir_lock` of a dir.
+    fn with_recovery<T>(&self, f: impl FnOnce() -> T) -> T {
+        let was = self.recovery.swap(true, Ordering::Relaxed);
+        let ret = f();
+        self.recovery.store(was, Ordering::Relaxed);
+        ret
+    }
+    // Note: the _\w*_at method always return begin>size?0:begin<end?0:(min(size,end)-begin) when success,
+    // except that _read_at and _write_at return less if an error follows some progress
+    /// Read/Write content, no matter what type it is
+    fn _io_at<F>(&self, begin: usize, end: usize, f: F) -> vfs::Result<usize>
+    where
+        F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
+    {
+        self._io_at_with(begin, end, None, f)
+    }
+    /// `_io_at()` calling `ctx.checkpoint()` before each burst of blocks,
+    /// see `SimpleFileSystem::set_io_burst()`
+    fn _io_at_with<F>(
+        &self,
+        begin: usize,
+        end: usize,
+        ctx: Option<&vfs::TaskContext>,
+        mut f: F,
+    ) -> vfs::Result<usize>
+    where
+        F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
+    {
+        let size = self.disk_inode.read().size as usize;
+        let iter = BlockIter {
+            begin: size.min(begin),
+            end: size.min(end),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        let burst = match ctx {
+            Some(ctx) => self.fs.io_burst().min(ctx.interval()),
+            None => usize::MAX,
         };
 
         // For each block
         let mut buf_offset = 0usize;
-        for mut range in iter {
+        for (i, mut range) in iter.enumerate() {
+            if let Some(ctx) = ctx.filter(|_| i % burst == 0) {
+                ctx.checkpoint()?;
+            }
             range.block = self.get_disk_block_id(range.block)?;
             f(&self.fs.device, &range, buf_offset)?;
             buf_offset += range.len();
         }
         Ok(buf_offset)
     }
+    /// Read or write content with `f` like `_io_at()`, ending short if some
+    /// blocks were transferred before an error
+    fn _transfer_at<F>(
+        &self,
+        op: &'static str,
+        begin: usize,
+        end: usize,
+        ctx: Option<&vfs::TaskContext>,
+        mut f: F,
+    ) -> vfs::Result<usize>
+    where
+        F: FnMut(&Arc<dyn Device>, &BlockRange, usize) -> vfs::Result<()>,
+    {
+        let mut done = 0;
+        let result = self._io_at_with(begin, end, ctx, |device, range, offset| {
+            f(device, range, offset)?;
+            done = offset + range.len();
+            Ok(())
+        });
+        match result {
+            Err(err) if done > 0 => {
+                warn!(
+                    "{} of inode {} ends short at {}: {:?}",
+                    op,
+                    self.id,
+                    begin + done,
+                    err
+                );
+                Ok(done)
+            }
+            result => Ok(fs_try!(result, vfs::ErrorContext::new(op).inode(self.id))),
+        }
+    }
+    /// Read the content stored inline like `_read_at()`, None if it is not
+    fn read_inline(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let disk_inode = self.disk_inode.read();
+        if !disk_inode.is_inline() {
+            return None;
+        }
+        let size = disk_inode.size as usize;
+        let (begin, end) = (offset.min(size), (offset + buf.len()).min(size));
+        buf[..end - begin].copy_from_slice(&disk_inode.inline_data()[begin..end]);
+        Some(end - begin)
+    }
+    /// Write the content stored inline like `_write_at()`, None if it is not
+    fn write_inline(&self, offset: usize, buf: &[u8]) -> Option<usize> {
+        let mut disk_inode = self.disk_inode.write();
+        if !disk_inode.is_inline() {
+            return None;
+        }
+        let size = disk_inode.size as usize;
+        let (begin, end) = (offset.min(size), (offset + buf.len()).min(size));
+        let mut data = disk_inode.inline_data();
+        data[begin..end].copy_from_slice(&buf[..end - begin]);
+        disk_inode.set_inline_data(&data);
+        Some(end - begin)
+    }
     /// Read content, no matter what type it is
     fn _read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.read_block(
-                range.block,
-                range.begin,
-                &mut buf[offset..offset + range.len()],
-            )
-        })
+        self._read_at_with(offset, buf, None)
+    }
+    /// `_read_at()` in bursts of blocks with `ctx`, ending short if
+    /// interrupted after some
+    fn _read_at_with(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        ctx: Option<&vfs::TaskContext>,
+    ) -> vfs::Result<usize> {
+        if let Some(len) = self.read_inline(offset, buf) {
+            return Ok(len);
+        }
+        if let Some(result) = self.read_compressed(offset, buf) {
+            return result;
+        }
+        if self.fs.rescue.is_some() {
+            return self._rescue_read_at(offset, buf);
+        }
+        let hint = *self.cache_hint.read();
+        self._transfer_at(
+            "read_at",
+            offset,
+            offset + buf.len(),
+            ctx,
+            |device, range, offset| {
+                device.read_block_hinted(
+                    range.block,
+                    range.begin,
+                    &mut buf[offset..offset + range.len()],
+                    hint,
+                )
+            },
+        )
+    }
+    /// Read the entries of a dir, served before bulk data
+    fn _read_entries_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        if self.fs.rescue.is_some() {
+            return self._rescue_read_at(offset, buf);
+        }
+        self._transfer_at(
+            "read_at",
+            offset,
+            offset + buf.len(),
+            None,
+            |device, range, offset| {
+                device.read_block_prio(
+                    range.block,
+                    range.begin,
+                    &mut buf[offset..offset + range.len()],
+                )
+            },
+        )
     }
     /// Write content, no matter what type it is
     fn _write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        self._io_at(offset, offset + buf.len(), |device, range, offset| {
-            device.write_block(range.block, range.begin, &buf[offset..offset + range.len()])
-        })
+        self._write_at_with(offset, buf, None)
+    }
+    /// `_write_at()` in bursts of blocks with `ctx`, ending short if
+    /// interrupted after some
+    fn _write_at_with(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        ctx: Option<&vfs::TaskContext>,
+    ) -> vfs::Result<usize> {
+        if let Some(len) = self.write_inline(offset, buf) {
+            return Ok(len);
+        }
+        let hint = *self.cache_hint.read();
+        self._transfer_at(
+            "write_at",
+            offset,
+            offset + buf.len(),
+            ctx,
+            |device, range, offset| {
+                device.write_block_hinted(
+                    range.block,
+                    range.begin,
+                    &buf[offset..offset + range.len()],
+                    hint,
+                )
+            },
+        )
+    }
+    /// Read whole blocks bypassing caches, `offset` is aligned to blocks
+    fn _read_at_direct(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        if let Some(len) = self.read_inline(offset, buf) {
+            return Ok(len);
+        }
+        if let Some(result) = self.read_compressed(offset, buf) {
+            return result;
+        }
+        let end = offset + buf.len();
+        self._transfer_at(
+            "read_at_direct",
+            offset,
+            end,
+            None,
+            |device, range, offset| {
+                let buf = &mut buf[offset..offset + range.len()];
+                if range.is_full() {
+                    return device.read_block_direct(range.block, buf);
+                }
+                // the last block of the file
+                let mut block = vec![0; BLKSIZE];
+                device.read_block_direct(range.block, &mut block)?;
+                buf.copy_from_slice(&block[..range.len()]);
+                Ok(())
+            },
+        )
+    }
+    /// Write whole blocks bypassing caches, `offset` and `buf.len()` are
+    /// aligned to blocks
+    fn _write_at_direct(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        let end = offset + buf.len();
+        self._transfer_at(
+            "write_at_direct",
+            offset,
+            end,
+            None,
+            |device, range, offset| {
+                device.write_block_direct(range.block, &buf[offset..offset + range.len()])
+            },
+        )
+    }
+    /// Fail with `InvalidParam` unless I/O of `len` bytes at `offset` is
+    /// aligned to blocks, as direct I/O requires
+    fn check_direct(offset: usize, len: usize) -> vfs::Result<()> {
+        if (offset | len) & (BLKSIZE - 1) != 0 {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(())
+    }
+    /// Write a file or symlink, bypassing caches if `direct`, else in
+    /// bursts with `ctx` if any
+    fn write_file(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        direct: bool,
+        ctx: Option<&vfs::TaskContext>,
+    ) -> vfs::Result<usize> {
+        let (size, compressed) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.size as usize, disk_inode.is_compressed())
+        };
+        if compressed {
+            return Err(FsError::Unsupported);
+        }
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE)?;
+        if offset < size {
+            self.check_flags(InodeFlags::APPEND_ONLY)?;
+        }
+        // nothing written, not even a hole
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let end_offset = offset + buf.len();
+        let grow = size < end_offset;
+        let (blocks, was_inline) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.blocks, disk_inode.is_inline())
+        };
+        if grow {
+            self._resize(end_offset)?;
+        }
+        let grown = match grow {
+            true => failpoint!(result self.fs, "write_after_grow"),
+            false => Ok(()),
+        };
+        let ret = grown.and_then(|()| match direct {
+            true => self._write_at_direct(offset, buf),
+            false => self._write_at_with(offset, buf, ctx),
+        });
+        if grow {
+            // do not publish the new size past the data written, keeping
+            // the blocks reserved before
+            let written = match ret {
+                Ok(len) if len < buf.len() => Some((offset + len).max(size)),
+                Err(_) => Some(size),
+                Ok(_) => None,
+            };
+            match written {
+                Some(len) if was_inline && len <= MAX_INLINE_SIZE => self._reinline(len)?,
+                Some(len) => self._truncate(len, blocks.max(Self::blocks_for(len)))?,
+                None => {}
+            }
+        }
+        if matches!(ret, Ok(len) if len > 0) {
+            self.content_changed();
+        }
+        if let (Ok(len), Some(hook)) = (&ret, self.fs.device.wear_hook()) {
+            hook.note_logical_write(*len);
+        }
+        ret
     }
-    /// Clean content, no matter what type it is
+    /// Clean content, no matter what type it is.
+    /// Contiguous blocks on disk are zeroed by one `write_zeros()`, of at
+    /// most `SimpleFileSystem::set_io_burst()` blocks.
     fn _clean_at(&self, begin: usize, end: usize) -> vfs::Result<usize> {
-        static ZEROS: [u8; BLKSIZE] = [0; BLKSIZE];
-        self._io_at(begin, end, |device, range, _| {
-            device.write_block(range.block, range.begin, &ZEROS[..range.len()])
-        })
+        let zeros = [0; MAX_INLINE_SIZE];
+        let len = end.saturating_sub(begin).min(MAX_INLINE_SIZE);
+        if let Some(len) = self.write_inline(begin, &zeros[..len]) {
+            return Ok(len);
+        }
+        // (offset, len) on device not zeroed yet
+        let mut run = (0, 0);
+        let burst = self.fs.io_burst() * BLKSIZE;
+        let len = self._io_at(begin, end, |device, range, _| {
+            let offset = range.block * BLKSIZE + range.begin;
+            if run.0 + run.1 == offset && run.1 + range.len() <= burst {
+                run.1 += range.len();
+                return Ok(());
+            }
+            if run.1 != 0 {
+                device.zero_range(run.0, run.1)?;
+            }
+            run = (offset, range.len());
+            Ok(())
+        })?;
+        if run.1 != 0 {
+            self.fs.device.zero_range(run.0, run.1)?;
+        }
+        Ok(len)
+    }
+    /// Whether the last link is gone, the inode living on while referenced.
+    ///
+    /// A removed dir can not get new entries, be moved to or linked to. It
+    /// only lists and finds ".", other names fail with `DirRemoved`.
+    fn is_removed(&self) -> bool {
+        self.disk_inode.read().nlinks == 0
+    }
+    /// Count a change of the content in `data_version`
+    fn content_changed(&self) {
+        self.disk_inode.write().data_version += 1;
+    }
+    /// Give the inode the creation time `btime`, e.g. of the file it is a
+    /// copy of, as `set_metadata()` does not. Images before VERSION_BTIME
+    /// keep none.
+    pub(crate) fn set_btime(&self, btime: Timespec) {
+        if self.fs.super_block.read().version >= VERSION_BTIME {
+            self.disk_inode.write().btime = btime;
+        }
+    }
+    /// Set the mode and owner of `inode` just created in this dir by `ctx`
+    fn init_owner(&self, inode: &INodeImpl, type_: vfs::FileType, mode: u32, ctx: &CreateContext) {
+        if self.fs.super_block.read().version < VERSION_OWNER {
+            return;
+        }
+        let (dir_mode, dir_gid) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.mode, disk_inode.gid as usize)
+        };
+        let (mode, uid, gid) = ctx.apply(type_, mode, dir_mode, dir_gid);
+        let mut disk_inode = inode.disk_inode.write();
+        disk_inode.mode = mode;
+        disk_inode.uid = uid as u32;
+        disk_inode.gid = gid as u32;
     }
-    fn nlinks_inc(&self) {
+    /// Check `more` links can be added without exceeding `LINK_MAX`
+    fn check_nlinks(&self, more: usize) -> vfs::Result<()> {
+        if self.disk_inode.read().nlinks as usize + more > LINK_MAX {
+            return Err(FsError::TooManyLinks);
+        }
+        Ok(())
+    }
+    fn nlinks_inc(&self) -> vfs::Result<()> {
+        self.check_nlinks(1)?;
         self.disk_inode.write().nlinks += 1;
+        Ok(())
     }
-    fn nlinks_dec(&self) {
+    fn nlinks_dec(&self) -> vfs::Result<()> {
         let mut disk_inode = self.disk_inode.write();
-        assert!(disk_inode.nlinks > 0);
-        disk_inode.nlinks -= 1;
+        disk_inode.nlinks = disk_inode.nlinks.checked_sub(1).ok_or_else(|| {
+            error!("nlinks of inode {} underflows", self.id);
+            FsError::WrongFs
+        })?;
+        Ok(())
     }
 
     pub fn link_inodeimpl(&self, name: &str, other: &Arc<INodeImpl>) -> vfs::Result<()> {
+        let _txn = self.fs.hold_against_txn(&[self.id])?;
+        let _dir = self.lock_dir();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if info.nlinks <= 0 {
+        if self.is_removed() {
             return Err(FsError::DirRemoved);
         }
-        if !self.get_file_inode_id(name).is_none() {
-            return Err(FsError::EntryExist);
-        }
+        let slot = match self.find_entry_or_insert_slot(name)? {
+            DirSlot::Exist(..) => return Err(FsError::EntryExist),
+            DirSlot::Free(slot) => slot,
+        };
         let child = other;
         if !Arc::ptr_eq(&self.fs, &child.fs) {
             return Err(FsError::NotSameFs);
@@ -387,56 +1754,224 @@ impl INodeImpl {
         if child.metadata()?.type_ == vfs::FileType::Dir {
             return Err(FsError::IsDir);
         }
-        let entry = DiskEntry {
-            id: child.id as u32,
-            name: Str256::from(name),
+        child.check_nlinks(1)?;
+        let type_ = child.disk_inode.read().type_;
+        let entry = DiskEntry::new(child.id as u32, Str256::new(name)?, type_);
+        self.insert_direntry(slot, &entry)?;
+        child.nlinks_inc()
+    }
+    /// `create3()`, or with `find` also give the INode `name` if it exists,
+    /// looking up and creating under the lock of the dir. Also return
+    /// whether it was created.
+    fn create_or_find(
+        &self,
+        name: &str,
+        type_: vfs::FileType,
+        mode: u32,
+        data: usize,
+        ctx: &CreateContext,
+        find: bool,
+    ) -> vfs::Result<(Arc<INodeImpl>, bool)> {
+        // finding works on a read-only fs
+        if !find {
+            self.check_writable()?;
+            self.check_flags(InodeFlags::IMMUTABLE)?;
+        }
+        let _txn = self.fs.hold_against_txn(&[self.id])?;
+        let _dir = self.lock_dir();
+        let info = self.metadata()?;
+        if info.type_ != vfs::FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if self.is_removed() {
+            return Err(FsError::DirRemoved);
+        }
+
+        let entry_name = Str256::new(name)?;
+        let slot = match self.find_entry_or_insert_slot(name)? {
+            DirSlot::Exist(id, _) if find => {
+                let inode = self.fs.get_inode(id)?;
+                return match inode.metadata()?.type_ == type_ {
+                    true => Ok((inode, false)),
+                    false => Err(FsError::EntryExist),
+                };
+            }
+            DirSlot::Exist(..) => return Err(FsError::EntryExist),
+            DirSlot::Free(slot) => slot,
         };
-        let disk_inode = self.disk_inode.write();
-        let old_size = disk_inode.size as usize;
-        self._resize(old_size + BLKSIZE)?;
-        self._write_at(old_size, entry.as_buf()).unwrap();
-        child.nlinks_inc();
+        if find {
+            self.check_writable()?;
+            self.check_flags(InodeFlags::IMMUTABLE)?;
+        }
+        if type_ == vfs::FileType::Dir {
+            self.check_nlinks(1)?;
+        }
+
+        // Create new INode
+        let inode = self.fs.new_inode_in(self.id, type_, data)?;
+        self.init_owner(&inode, type_, mode, ctx);
+        failpoint!(self.fs, "create_after_alloc_inode");
+
+        self.insert_new_inode(slot, entry_name, &inode)?;
+        Ok((inode, true))
+    }
+    /// Create a symlink to `target`, written before the entry is added, so
+    /// that it is never seen empty
+    fn create_symlink(&self, name: &str, target: &[u8]) -> vfs::Result<Arc<INodeImpl>> {
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE)?;
+        let _txn = self.fs.hold_against_txn(&[self.id])?;
+        let _dir = self.lock_dir();
+        let info = self.metadata()?;
+        if info.type_ != vfs::FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if self.is_removed() {
+            return Err(FsError::DirRemoved);
+        }
+
+        let entry_name = Str256::new(name)?;
+        let slot = match self.find_entry_or_insert_slot(name)? {
+            DirSlot::Exist(..) => return Err(FsError::EntryExist),
+            DirSlot::Free(slot) => slot,
+        };
+
+        // freed on drop without links, with its content
+        let inode = self.fs.new_inode_in(self.id, vfs::FileType::SymLink, 0)?;
+        let ctx = CreateContext::default();
+        self.init_owner(&inode, vfs::FileType::SymLink, 0o777, &ctx);
+        inode.write_file(0, target, false, None)?;
+        failpoint!(self.fs, "symlink_after_target");
+
+        self.insert_new_inode(slot, entry_name, &inode)?;
+        Ok(inode)
+    }
+    /// Add the entry of the new `inode` at `slot` and count its links, or
+    /// remove the entry again. The caller holds `dir_lock`.
+    fn insert_new_inode(
+        &self,
+        slot: usize,
+        entry_name: Str256,
+        inode: &Arc<INodeImpl>,
+    ) -> vfs::Result<()> {
+        let inode_type = inode.disk_inode.read().type_;
+        self.insert_direntry(
+            slot,
+            &DiskEntry::new(inode.id as u32, entry_name, inode_type),
+        )?;
+        let linked = failpoint!(result self.fs, "create_after_dirent").and_then(|()| {
+            inode.nlinks_inc()?;
+            if inode_type == FileType::Dir {
+                inode.nlinks_inc()?; //for .
+                self.nlinks_inc()?; //for ..
+            }
+            Ok(())
+        });
+        if let Err(err) = linked {
+            // freed on drop without links
+            inode.disk_inode.write().nlinks = 0;
+            self.remove_direntry(slot)?;
+            return Err(err);
+        }
         Ok(())
     }
 }
 
 impl vfs::INode for INodeImpl {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        fs_span!(
+            "read_at",
+            fs = self.fs.instance_id,
+            inode = self.id,
+            offset,
+            len = buf.len()
+        );
         match self.disk_inode.read().type_ {
             FileType::File => self._read_at(offset, buf),
             FileType::SymLink => self._read_at(offset, buf),
-            FileType::CharDevice => {
+            FileType::CharDevice | FileType::BlockDevice => {
                 let device_inodes = self.fs.device_inodes.read();
-                let device_inode = device_inodes.get(&self.device_inode_id);
+                let device_inode = device_inodes.get(&self.rdev);
                 match device_inode {
                     Some(device) => device.read_at(offset, buf),
                     None => Err(FsError::DeviceError),
                 }
             }
+            FileType::Dir => Err(FsError::IsDir),
             _ => Err(FsError::NotFile),
         }
     }
     fn write_at(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
-        let DiskINode { type_, size, .. } = **self.disk_inode.read();
+        fs_span!(
+            "write_at",
+            fs = self.fs.instance_id,
+            inode = self.id,
+            offset,
+            len = buf.len()
+        );
+        let type_ = self.disk_inode.read().type_;
         match type_ {
-            FileType::File | FileType::SymLink => {
-                let end_offset = offset + buf.len();
-                if (size as usize) < end_offset {
-                    self._resize(end_offset)?;
-                }
-                self._write_at(offset, buf)
-            }
-            FileType::CharDevice => {
+            FileType::File | FileType::SymLink => self.write_file(offset, buf, false, None),
+            FileType::CharDevice | FileType::BlockDevice => {
                 let device_inodes = self.fs.device_inodes.write();
-                let device_inode = device_inodes.get(&self.device_inode_id);
+                let device_inode = device_inodes.get(&self.rdev);
                 match device_inode {
                     Some(device) => device.write_at(offset, buf),
                     None => Err(FsError::DeviceError),
                 }
             }
+            FileType::Dir => Err(FsError::IsDir),
             _ => Err(FsError::NotFile),
         }
     }
+    /// Files and symlinks are read in bursts, see
+    /// `SimpleFileSystem::set_io_burst()`
+    fn read_at_with(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+        ctx: &vfs::TaskContext,
+    ) -> vfs::Result<usize> {
+        let type_ = self.disk_inode.read().type_;
+        match type_ {
+            FileType::File | FileType::SymLink => self._read_at_with(offset, buf, Some(ctx)),
+            _ => ctx.checkpoint().and_then(|()| self.read_at(offset, buf)),
+        }
+    }
+    /// Files and symlinks are written in bursts, see
+    /// `SimpleFileSystem::set_io_burst()`. When interrupted, the size of a
+    /// grown file ends at the bytes written.
+    fn write_at_with(
+        &self,
+        offset: usize,
+        buf: &[u8],
+        ctx: &vfs::TaskContext,
+    ) -> vfs::Result<usize> {
+        let type_ = self.disk_inode.read().type_;
+        match type_ {
+            FileType::File | FileType::SymLink => self.write_file(offset, buf, false, Some(ctx)),
+            _ => ctx.checkpoint().and_then(|()| self.write_at(offset, buf)),
+        }
+    }
+    /// Only for files and symlinks, whose `offset` and `buf.len()` must be
+    /// aligned to `BLKSIZE`
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let type_ = self.disk_inode.read().type_;
+        if type_ != FileType::File && type_ != FileType::SymLink {
+            return self.read_at(offset, buf);
+        }
+        Self::check_direct(offset, buf.len())?;
+        self._read_at_direct(offset, buf)
+    }
+    /// Same as `read_at_direct()`
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> vfs::Result<usize> {
+        let type_ = self.disk_inode.read().type_;
+        if type_ != FileType::File && type_ != FileType::SymLink {
+            return self.write_at(offset, buf);
+        }
+        Self::check_direct(offset, buf.len())?;
+        self.write_file(offset, buf, true, None)
+    }
     fn poll(&self) -> vfs::Result<vfs::PollStatus> {
         Ok(vfs::PollStatus {
             read: true,
@@ -446,112 +1981,346 @@ impl vfs::INode for INodeImpl {
     }
     /// the size returned here is logical size(entry num for directory), not the disk space used.
     fn metadata(&self) -> vfs::Result<vfs::Metadata> {
+        let flags = self.fs.metadata_flags(self.id);
         let disk_inode = self.disk_inode.read();
+        if disk_inode.type_ == FileType::Invalid {
+            error!("inode {} has no type", self.id);
+            return Err(FsError::WrongFs);
+        }
         Ok(vfs::Metadata {
-            dev: 0,
+            dev: self.fs.instance_id as usize,
             inode: self.id,
             size: match disk_inode.type_ {
-                FileType::File | FileType::SymLink => disk_inode.size as usize,
-                FileType::Dir => disk_inode.size as usize,
-                FileType::CharDevice => 0,
-                FileType::BlockDevice => 0,
-                _ => panic!("Unknown file type"),
+                FileType::CharDevice | FileType::BlockDevice => 0,
+                _ => disk_inode.size as usize,
             },
-            mode: 0o777,
+            mode: disk_inode.mode,
             type_: vfs::FileType::from(disk_inode.type_.clone()),
             blocks: disk_inode.blocks as usize,
             atime: disk_inode.atime,
             mtime: disk_inode.mtime,
             ctime: disk_inode.ctime,
+            btime: disk_inode.btime,
             nlinks: disk_inode.nlinks as usize,
-            uid: 0,
-            gid: 0,
+            uid: disk_inode.uid as usize,
+            gid: disk_inode.gid as usize,
             blk_size: BLKSIZE,
-            rdev: self.device_inode_id,
+            rdev: match disk_inode.type_ {
+                FileType::CharDevice | FileType::BlockDevice => self.rdev,
+                _ => 0,
+            },
+            flags,
         })
     }
+    /// `btime` is kept, whatever `metadata` has
     fn set_metadata(&self, metadata: &vfs::Metadata) -> vfs::Result<()> {
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE)?;
+        let uid = u32::try_from(metadata.uid).map_err(|_| FsError::InvalidParam)?;
+        let gid = u32::try_from(metadata.gid).map_err(|_| FsError::InvalidParam)?;
+        let has_owner = self.fs.super_block.read().version >= VERSION_OWNER;
         let mut disk_inode = self.disk_inode.write();
         disk_inode.atime = metadata.atime;
         disk_inode.mtime = metadata.mtime;
         disk_inode.ctime = metadata.ctime;
+        if has_owner {
+            disk_inode.mode = metadata.mode & 0o7777;
+            disk_inode.uid = uid;
+            disk_inode.gid = gid;
+        }
         Ok(())
     }
+    /// Write the inode back, after the freemap, then sync the device: what
+    /// was written before is durable before anything written after.
+    ///
+    /// For a dir, all its entries changed before are durable, and so are
+    /// the inodes of its children in memory, written before it. So after a
+    /// crash a name never refers to an inode not written yet, and replacing
+    /// a file atomically takes: write a temporary file, `sync_all()` it,
+    /// `move_()` it over the file, then `sync_all()` the dir. The name
+    /// then refers to the whole old content or the whole new one.
     fn sync_all(&self) -> vfs::Result<()> {
-        let mut disk_inode = self.disk_inode.write();
-        if disk_inode.dirty() {
-            self.fs
-                .device
-                .write_block(self.id, 0, disk_inode.as_buf())?;
-            disk_inode.sync();
+        fs_span!("sync_all", fs = self.fs.instance_id, inode = self.id);
+        if self.fs.read_only {
+            return self.write_inode();
         }
+        let _dir = self.lock_dir();
+        self.fs.write_free_map_changed()?;
+        if self.disk_inode.read().type_ == FileType::Dir {
+            self.write_children()?;
+        }
+        self.write_inode()?;
+        fs_try!(
+            self.fs.device.sync().map_err(FsError::from),
+            vfs::ErrorContext::new("sync_all").inode(self.id)
+        );
         Ok(())
     }
     fn sync_data(&self) -> vfs::Result<()> {
         self.sync_all()
     }
     fn resize(&self, len: usize) -> vfs::Result<()> {
-        if self.disk_inode.read().type_ != FileType::File
-            && self.disk_inode.read().type_ != FileType::SymLink
-        {
-            return Err(FsError::NotFile);
+        fs_span!("resize", fs = self.fs.instance_id, inode = self.id, len);
+        self.check_resizable(len)?;
+        let size = self.disk_inode.read().size as usize;
+        self._resize(len)?;
+        if len != size {
+            self.content_changed();
+        }
+        Ok(())
+    }
+    /// Resize by steps of `ctx.interval()` blocks. When interrupted, a grown
+    /// file is back to its old size, a shrunk one keeps the size reached.
+    fn resize_with(&self, len: usize, ctx: &vfs::TaskContext) -> vfs::Result<()> {
+        self.check_resizable(len)?;
+        let (old_size, old_blocks, was_inline) = {
+            let disk_inode = self.disk_inode.read();
+            (
+                disk_inode.size as usize,
+                disk_inode.blocks,
+                disk_inode.is_inline(),
+            )
+        };
+        let step = ctx.interval() * BLKSIZE;
+        let mut size = old_size;
+        loop {
+            let result = ctx.checkpoint().and_then(|()| {
+                size = match len > size {
+                    true => len.min(size + step),
+                    false => len.max(size.saturating_sub(step)),
+                };
+                self._resize(size)
+            });
+            match result {
+                Ok(()) if size == len => {
+                    if len != old_size {
+                        self.content_changed();
+                    }
+                    return Ok(());
+                }
+                Ok(()) => {}
+                Err(err) => {
+                    if size > old_size && was_inline {
+                        self._reinline(old_size)?;
+                    } else if size > old_size {
+                        self._truncate(old_size, old_blocks.max(Self::blocks_for(old_size)))?;
+                    } else if self.disk_inode.read().size as usize != old_size {
+                        // shrunk so far
+                        self.content_changed();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+    }
+    /// SFS has no holes: a hole punched within the size is zeroed, only
+    /// the blocks reserved past the end are freed, if the hole reaches the
+    /// last of them.
+    fn fallocate(&self, offset: usize, len: usize, mode: FallocateMode) -> vfs::Result<()> {
+        let keep_size = mode.contains(FallocateMode::KEEP_SIZE);
+        let punch = mode.contains(FallocateMode::PUNCH_HOLE);
+        if len == 0 || !FallocateMode::ALL.contains(mode) || (punch && !keep_size) {
+            return Err(FsError::InvalidParam);
+        }
+        let end = offset.checked_add(len).ok_or(FsError::InvalidParam)?;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        self.check_resizable(end)?;
+        if !punch {
+            // reserved blocks are only for content in blocks
+            self._uninline()?;
+        }
+        let (size, blocks) = {
+            let disk_inode = self.disk_inode.read();
+            (disk_inode.size as usize, disk_inode.blocks)
+        };
+        if punch {
+            self.check_flags(InodeFlags::APPEND_ONLY)?;
+            if offset < size {
+                self._clean_at(offset, end.min(size))?;
+                self.content_changed();
+            }
+            if end >= blocks as usize * BLKSIZE {
+                let keep = Self::blocks_for(size).max(Self::blocks_for(offset));
+                if keep < blocks {
+                    self._truncate(size, keep)?;
+                }
+            }
+            return Ok(());
+        }
+        let new_blocks = Self::blocks_for(end);
+        if !keep_size && end > size {
+            self._resize(end)?;
+            self.content_changed();
+            Ok(())
+        } else if new_blocks > blocks {
+            self._grow_blocks(blocks, new_blocks, size)
+        } else {
+            Ok(())
+        }
+    }
+    fn get_flags(&self) -> vfs::Result<InodeFlags> {
+        Ok(self.flags())
+    }
+    fn set_flags(&self, flags: InodeFlags) -> vfs::Result<()> {
+        self.check_writable()?;
+        if self.fs.super_block.read().version < VERSION_FLAGS {
+            return Err(FsError::Unsupported);
+        }
+        if !InodeFlags::ALL.contains(flags) {
+            return Err(FsError::InvalidParam);
         }
-        self._resize(len)
+        let mut disk_inode = self.disk_inode.write();
+        disk_inode.flags = flags.0 | (disk_inode.flags & (INODE_INLINE | INODE_COMPRESSED));
+        Ok(())
     }
     fn create2(
         &self,
         name: &str,
         type_: vfs::FileType,
-        _mode: u32,
+        mode: u32,
         data: usize,
     ) -> vfs::Result<Arc<dyn vfs::INode>> {
-        let info = self.metadata()?;
-        if info.type_ != vfs::FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-        if info.nlinks <= 0 {
-            return Err(FsError::DirRemoved);
+        self.create3(name, type_, mode, data, &CreateContext::default())
+    }
+    fn create3(
+        &self,
+        name: &str,
+        type_: vfs::FileType,
+        mode: u32,
+        data: usize,
+        ctx: &CreateContext,
+    ) -> vfs::Result<Arc<dyn vfs::INode>> {
+        fs_span!("create", fs = self.fs.instance_id, inode = self.id, name, type_ = ?type_);
+        let (inode, _) = self.create_or_find(name, type_, mode, data, ctx, false)?;
+        Ok(inode)
+    }
+
+    fn find_or_create(
+        &self,
+        name: &str,
+        type_: vfs::FileType,
+        mode: u32,
+    ) -> vfs::Result<(Arc<dyn INode>, bool)> {
+        let ctx = CreateContext::default();
+        let (inode, created) = self.create_or_find(name, type_, mode, 0, &ctx, true)?;
+        Ok((inode, created))
+    }
+
+    fn create_batch(&self, entries: &[CreateSpec]) -> vfs::Result<Vec<Arc<dyn INode>>> {
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE)?;
+        let _txn = self.fs.hold_against_txn(&[self.id])?;
+        let _dir = self.lock_dir();
+        let info = self.metadata()?;
+        if info.type_ != vfs::FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if self.is_removed() {
+            return Err(FsError::DirRemoved);
         }
 
-        // Ensure the name is not exist
-        if !self.get_file_inode_id(name).is_none() {
+        // Validate all entries before any change
+        let mut names = BTreeSet::new();
+        let mut entry_names = Vec::with_capacity(entries.len());
+        for entry in entries {
+            entry_names.push(Str256::new(entry.name)?);
+            if !names.insert(self.fs.names.ops.normalize(entry.name)) {
+                return Err(FsError::EntryExist);
+            }
+            if entry.type_ == vfs::FileType::Socket || entry.type_ == vfs::FileType::NamedPipe {
+                return Err(FsError::InvalidParam);
+            }
+        }
+        let exist = self.scan_direntry(|id, entry| {
+            if id >= 2 && names.contains(&self.fs.names.ops.normalize(entry.name.as_ref())) {
+                Some(())
+            } else {
+                None
+            }
+        })?;
+        let dots = entries
+            .iter()
+            .any(|entry| entry.name == "." || entry.name == "..");
+        if exist.is_some() || dots {
             return Err(FsError::EntryExist);
         }
+        let dirs = entries
+            .iter()
+            .filter(|entry| entry.type_ == vfs::FileType::Dir)
+            .count();
+        self.check_nlinks(dirs)?;
 
-        // Create new INode
-        let inode = match type_ {
-            vfs::FileType::File => self.fs.new_inode_file()?,
-            vfs::FileType::SymLink => self.fs.new_inode_symlink()?,
-            vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
-            vfs::FileType::CharDevice => self.fs.new_inode_chardevice(data)?,
-            _ => return Err(vfs::FsError::InvalidParam),
-        };
-
-        // Write new entry
-        self.append_direntry(&DiskEntry {
-            id: inode.id as u32,
-            name: Str256::from(name),
+        // Create new INodes, they are freed on drop if anything fails
+        let mut inodes = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let inode = self.fs.new_inode_in(self.id, entry.type_, entry.data)?;
+            self.init_owner(&inode, entry.type_, entry.mode, &CreateContext::default());
+            inodes.push(inode);
+        }
+
+        // Write all new entries at once
+        let size = self.disk_inode.read().size as usize;
+        let mut buf = Vec::with_capacity(entries.len() * DIRENT_SIZE);
+        let new_entries: Vec<_> = inodes
+            .iter()
+            .zip(entry_names)
+            .map(|(inode, name)| {
+                DiskEntry::new(inode.id as u32, name, inode.disk_inode.read().type_)
+            })
+            .collect();
+        for entry in new_entries.iter() {
+            buf.extend_from_slice(&entry.to_disk());
+        }
+        self.grow_entries(size, size + buf.len(), || {
+            let written = failpoint!(result self.fs, "create_batch_after_resize")
+                .and_then(|()| self._write_at(size, &buf))?;
+            match written == buf.len() {
+                true => Ok(()),
+                false => Err(FsError::DeviceError),
+            }
         })?;
-        inode.nlinks_inc();
-        if type_ == vfs::FileType::Dir {
-            inode.nlinks_inc(); //for .
-            self.nlinks_inc(); //for ..
+        self.index_insert(size / DIRENT_SIZE, &new_entries);
+        self.entries_changed();
+
+        for inode in inodes.iter() {
+            inode.nlinks_inc()?;
+            if inode.disk_inode.read().type_ == FileType::Dir {
+                inode.nlinks_inc()?; //for .
+            }
         }
+        self.disk_inode.write().nlinks += dirs as u16; //for ..
+        Ok(inodes
+            .into_iter()
+            .map(|inode| inode as Arc<dyn INode>)
+            .collect())
+    }
 
-        Ok(inode)
+    /// Atomic: the target is written before the entry is added
+    fn symlink(&self, name: &str, target: &str) -> vfs::Result<Arc<dyn INode>> {
+        if target.is_empty() || target.len() > MAX_FILE_SIZE {
+            return Err(FsError::InvalidParam);
+        }
+        Ok(self.create_symlink(name, target.as_bytes())?)
     }
 
     fn link(&self, name: &str, other: &Arc<dyn INode>) -> vfs::Result<()> {
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE)?;
+        let _txn = self.fs.hold_against_txn(&[self.id])?;
+        let _dir = self.lock_dir();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if info.nlinks <= 0 {
+        if self.is_removed() {
             return Err(FsError::DirRemoved);
         }
-        if !self.get_file_inode_id(name).is_none() {
-            return Err(FsError::EntryExist);
-        }
+        let slot = match self.find_entry_or_insert_slot(name)? {
+            DirSlot::Exist(..) => return Err(FsError::EntryExist),
+            DirSlot::Free(slot) => slot,
+        };
         let child = other
             .downcast_ref::<INodeImpl>()
             .ok_or(FsError::NotSameFs)?;
@@ -561,32 +2330,53 @@ impl vfs::INode for INodeImpl {
         if child.metadata()?.type_ == vfs::FileType::Dir {
             return Err(FsError::IsDir);
         }
-        self.append_direntry(&DiskEntry {
-            id: child.id as u32,
-            name: Str256::from(name),
-        })?;
-        child.nlinks_inc();
+        child.check_flags(InodeFlags::IMMUTABLE)?;
+        child.check_nlinks(1)?;
+        let type_ = child.disk_inode.read().type_;
+        self.insert_direntry(
+            slot,
+            &DiskEntry::new(child.id as u32, Str256::new(name)?, type_),
+        )?;
+        let linked =
+            failpoint!(result self.fs, "link_after_dirent").and_then(|()| child.nlinks_inc());
+        if let Err(err) = linked {
+            self.remove_direntry(slot)?;
+            return Err(err);
+        }
         Ok(())
     }
     fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.unlink_bytes(name.as_bytes())
+    }
+    /// Any name on disk, UTF-8 or not
+    fn unlink_bytes(&self, name: &[u8]) -> vfs::Result<()> {
+        fs_span!("unlink", fs = self.fs.instance_id, inode = self.id, name = %escape_name(name));
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
+        let _txn = self.fs.hold_against_txn(&[self.id])?;
+        let _dir = self.lock_dir();
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if info.nlinks <= 0 {
+        if self.is_removed() {
             return Err(FsError::DirRemoved);
         }
-        if name == "." {
+        if name == b"." {
             return Err(FsError::IsDir);
         }
-        if name == ".." {
+        if name == b".." {
             return Err(FsError::IsDir);
         }
 
         let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(name)
+            .get_file_inode_and_entry_id_bytes(name)?
             .ok_or(FsError::EntryNotFound)?;
-        let inode = self.fs.get_inode(inode_id);
+        let inode = self.fs.get_inode(inode_id)?;
+        inode.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
+        inode.check_unpinned_unlink()?;
+        // readahead is not a user either
+        self.forget_readahead(inode_id);
 
         let type_ = inode.disk_inode.read().type_;
         if type_ == FileType::Dir {
@@ -595,21 +2385,55 @@ impl vfs::INode for INodeImpl {
                 return Err(FsError::DirNotEmpty);
             }
         }
-        inode.nlinks_dec();
+        if self.fs.silly_rename.load(Ordering::Relaxed)
+            && type_ != FileType::Dir
+            && inode.disk_inode.read().nlinks == 1
+            && name != silly_name(inode_id).as_bytes()
+        {
+            // the strong cache is not a user
+            self.fs.uncache_inode(inode_id);
+            if Arc::strong_count(&inode) > 1 {
+                return self.silly_rename(entry_id, name, &inode);
+            }
+        }
+        inode.nlinks_dec()?;
         if type_ == FileType::Dir {
-            inode.nlinks_dec(); //for .
-            self.nlinks_dec(); //for ..
+            inode.nlinks_dec()?; //for .
+            self.nlinks_dec()?; //for ..
+        }
+        let removed = failpoint!(result self.fs, "unlink_after_nlinks_dec")
+            .and_then(|()| self.remove_direntry(entry_id));
+        if let Err(err) = removed {
+            // the entry is still there, so are its links
+            inode.disk_inode.write().nlinks += 1;
+            if type_ == FileType::Dir {
+                inode.disk_inode.write().nlinks += 1;
+                self.disk_inode.write().nlinks += 1;
+            }
+            return Err(err);
+        }
+        if inode.is_removed() {
+            // let it be freed as soon as the last user drops it
+            self.fs.uncache_inode(inode_id);
         }
-        self.remove_direntry(entry_id)?;
 
         Ok(())
     }
     fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> vfs::Result<()> {
+        fs_span!(
+            "move",
+            fs = self.fs.instance_id,
+            inode = self.id,
+            old_name,
+            new_name
+        );
+        self.check_writable()?;
+        self.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if info.nlinks <= 0 {
+        if self.is_removed() {
             return Err(FsError::DirRemoved);
         }
         if old_name == "." {
@@ -618,6 +2442,9 @@ impl vfs::INode for INodeImpl {
         if old_name == ".." {
             return Err(FsError::IsDir);
         }
+        if new_name == "." || new_name == ".." {
+            return Err(FsError::IsDir);
+        }
 
         let dest = target
             .downcast_ref::<INodeImpl>()
@@ -629,93 +2456,367 @@ impl vfs::INode for INodeImpl {
         if dest_info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if dest_info.nlinks <= 0 {
+        if dest.is_removed() {
             return Err(FsError::DirRemoved);
         }
-        if let Some((_, id)) = dest.get_file_inode_and_entry_id(new_name) {
-            dest.remove_direntry(id)?;
+        dest.check_flags(InodeFlags::IMMUTABLE)?;
+        let _txn = self.fs.hold_against_txn(&[self.id, dest.id])?;
+        let _dirs = self.lock_dirs(dest);
+        let new_entry_name = Str256::new(new_name)?;
+        let source_id = self
+            .get_file_inode_id(old_name)?
+            .ok_or(FsError::EntryNotFound)?;
+        // another spelling of the same name only changes the entry
+        let respell = info.inode == dest_info.inode
+            && old_name != new_name
+            && self.fs.same_name(old_name, new_name);
+        if !respell && dest.get_file_inode_id(new_name)? == Some(source_id) {
+            // both names are links to the same inode: POSIX says do nothing
+            return Ok(());
+        }
+        let source = self.fs.get_inode(source_id)?;
+        source.check_flags(InodeFlags::IMMUTABLE)?;
+        if info.inode != dest_info.inode && source.disk_inode.read().type_ == FileType::Dir {
+            // for .. of the moved dir
+            dest.check_nlinks(1)?;
         }
+        let replaced = match (respell, dest.find_entry_or_insert_slot(new_name)?) {
+            (false, DirSlot::Exist(replaced_id, id)) => {
+                // the replaced one is unlinked
+                let replaced = self.fs.get_inode(replaced_id)?;
+                replaced.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
+                replaced.check_unpinned_unlink()?;
+                Some((id, dest.read_direntry(id)?, replaced))
+            }
+            _ => None,
+        };
 
         let (inode_id, entry_id) = self
-            .get_file_inode_and_entry_id(old_name)
+            .get_file_inode_and_entry_id(old_name)?
             .ok_or(FsError::EntryNotFound)?;
-        if info.inode == dest_info.inode {
+        let source_type = source.disk_inode.read().type_;
+        self.forget_readahead(inode_id);
+        let entry = DiskEntry::new(inode_id as u32, new_entry_name, source_type);
+        if let Some((id, old_entry, replaced)) = replaced {
+            // the entry of the replaced one is taken over by a single write,
+            // so that a crash never leaves the new name missing, then the
+            // old name is removed
+            dest.write_direntry(id, &entry)?;
+            dest.index_rename(id, old_entry.name.as_bytes(), &entry);
+            let removed = failpoint!(result self.fs, "move_after_replace")
+                .and_then(|()| self.remove_direntry(entry_id));
+            if let Err(err) = removed {
+                dest.write_direntry(id, &old_entry)?;
+                dest.index_rename(id, entry.name.as_bytes(), &old_entry);
+                return Err(err);
+            }
+            // freed on drop once it has no links left
+            replaced.nlinks_dec()?;
+            if replaced.disk_inode.read().type_ == FileType::Dir {
+                replaced.nlinks_dec()?; //for .
+                dest.nlinks_dec()?; //for ..
+            }
+        } else if info.inode == dest_info.inode {
             // rename: in place modify name
-            self.write_direntry(
-                entry_id,
-                &DiskEntry {
-                    id: inode_id as u32,
-                    name: Str256::from(new_name),
-                },
-            )?;
+            self.write_direntry(entry_id, &entry)?;
+            self.index_rename(entry_id, old_name.as_bytes(), &entry);
         } else {
             // move
-            dest.append_direntry(&DiskEntry {
-                id: inode_id as u32,
-                name: Str256::from(new_name),
-            })?;
-            self.remove_direntry(entry_id)?;
-
-            let inode = self.fs.get_inode(inode_id);
+            dest.append_direntry(&entry)?;
+            let removed = failpoint!(result self.fs, "move_after_append")
+                .and_then(|()| self.remove_direntry(entry_id));
+            if let Err(err) = removed {
+                // drop the entry just appended
+                let last = dest.disk_inode.read().size as usize / DIRENT_SIZE - 1;
+                dest.remove_direntry(last)?;
+                return Err(err);
+            }
+        }
+        if info.inode != dest_info.inode {
+            let inode = self.fs.get_inode(inode_id)?;
             if inode.metadata()?.type_ == vfs::FileType::Dir {
-                self.nlinks_dec();
-                dest.nlinks_inc();
+                inode.write_dots(dest.id)?;
+                self.nlinks_dec()?;
+                dest.nlinks_inc()?;
             }
         }
         Ok(())
     }
     fn find(&self, name: &str) -> vfs::Result<Arc<dyn vfs::INode>> {
+        fs_span!("find", fs = self.fs.instance_id, inode = self.id, name);
         let info = self.metadata()?;
         if info.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        let inode_id = self.get_file_inode_id(name).ok_or(FsError::EntryNotFound)?;
-        Ok(self.fs.get_inode(inode_id))
+        if self.is_removed() {
+            return match name {
+                "." => Ok(self.fs.get_inode(self.id)?),
+                _ => Err(FsError::DirRemoved),
+            };
+        }
+        let cookie = self.change_cookie();
+        let inode_id = fs_try!(
+            self.get_file_inode_id(name),
+            vfs::ErrorContext::new("find").inode(self.id).name(name)
+        )
+        .ok_or(FsError::EntryNotFound)?;
+        failpoint!(self.fs, "find_after_entry");
+        let read = || {
+            let inode_id = self.get_file_inode_id(name)?;
+            Ok((inode_id.ok_or(FsError::EntryNotFound)?, ()))
+        };
+        let (inode, ()) =
+            self.load_entry_inode(cookie, (inode_id, ()), read, |id, _| self.fs.get_inode(id))?;
+        Ok(inode)
     }
-    fn get_entry(&self, id: usize) -> vfs::Result<String> {
-        if self.disk_inode.read().type_ != FileType::Dir {
+    /// Any name on disk, UTF-8 or not
+    fn find_bytes(&self, name: &[u8]) -> vfs::Result<Arc<dyn vfs::INode>> {
+        if let Ok(name) = core::str::from_utf8(name) {
+            return self.find(name);
+        }
+        fs_span!("find", fs = self.fs.instance_id, inode = self.id, name = %escape_name(name));
+        if self.metadata()?.type_ != vfs::FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
-            return Err(FsError::EntryNotFound);
+        if self.is_removed() {
+            return Err(FsError::DirRemoved);
+        }
+        let cookie = self.change_cookie();
+        let read = || {
+            let found = self.get_file_inode_and_entry_id_bytes(name)?;
+            Ok((found.ok_or(FsError::EntryNotFound)?.0, ()))
         };
-        let entry = self.read_direntry(id)?;
-        Ok(String::from(entry.name.as_ref()))
+        let first = read()?;
+        let (inode, ()) =
+            self.load_entry_inode(cookie, first, read, |id, _| self.fs.get_inode(id))?;
+        Ok(inode)
+    }
+    fn change_cookie(&self) -> u64 {
+        self.change_counter.load(Ordering::SeqCst)
+    }
+    /// Kept in the inode on disk since VERSION_DATA_VERSION, counted from 0
+    /// in each mount of older images
+    fn data_version(&self) -> u64 {
+        self.disk_inode.read().data_version
+    }
+    fn get_entry(&self, id: usize) -> vfs::Result<String> {
+        self.check_entry_id(id)?;
+        let id = self.listed_entry_id(id)?;
+        Ok(self.entry_at(id)?.1)
+    }
+
+    fn get_entry_bytes(&self, id: usize) -> vfs::Result<Vec<u8>> {
+        self.check_entry_id(id)?;
+        match self.listed_entry_id(id)? {
+            id @ (0 | 1) => Ok(self.entry_at(id)?.1.into_bytes()),
+            id => Ok(self.read_direntry(id)?.name.as_bytes().to_vec()),
+        }
     }
 
     fn get_entry_with_metadata(&self, id: usize) -> vfs::Result<(Metadata, String)> {
-        if self.disk_inode.read().type_ != FileType::Dir {
-            return Err(FsError::NotDir);
+        self.check_entry_id(id)?;
+        let cookie = self.change_cookie();
+        let read = || {
+            let id = self.listed_entry_id(id)?;
+            let (inode_id, name) = self.entry_at(id)?;
+            Ok((inode_id, (id, name)))
+        };
+        let first = read()?;
+        let (inode, (_, name)) =
+            self.load_entry_inode(cookie, first, read, |inode_id, entry| {
+                self.child_inode(entry.0, inode_id)
+            })?;
+        Ok((inode.metadata()?, name))
+    }
+
+    fn get_entry_with_metadata_partial(
+        &self,
+        id: usize,
+        mask: vfs::MetadataMask,
+    ) -> vfs::Result<(vfs::PartialMetadata, String)> {
+        self.check_entry_id(id)?;
+        let cookie = self.change_cookie();
+        let read = || {
+            let id = self.listed_entry_id(id)?;
+            Ok(match id {
+                0 | 1 => {
+                    let (inode_id, name) = self.entry_at(id)?;
+                    (inode_id, (id, name, Some(FileType::Dir)))
+                }
+                _ => {
+                    let entry = self.read_direntry(id)?;
+                    let name = escape_name(entry.name.as_bytes()).into_owned();
+                    (entry.id as INodeId, (id, name, entry.type_hint()))
+                }
+            })
+        };
+        let (inode_id, (id, name, type_)) = read()?;
+        // answer from the entry without loading the inode if possible
+        let cheap = vfs::MetadataMask::INODE | vfs::MetadataMask::TYPE;
+        if let Some(type_) = type_.filter(|_| cheap.contains(mask)) {
+            let metadata = vfs::PartialMetadata {
+                inode: mask.contains(vfs::MetadataMask::INODE).then_some(inode_id),
+                type_: mask
+                    .contains(vfs::MetadataMask::TYPE)
+                    .then_some(type_.into()),
+                ..Default::default()
+            };
+            return Ok((metadata, name));
         }
-        if id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
-            return Err(FsError::EntryNotFound);
+        let first = (inode_id, (id, name, type_));
+        let (inode, (_, name, _)) =
+            self.load_entry_inode(cookie, first, read, |inode_id, entry| {
+                self.child_inode(entry.0, inode_id)
+            })?;
+        Ok((inode.metadata_partial(mask)?, name))
+    }
+
+    /// Positions are the slots of the entries on disk, so a window only
+    /// reads the blocks of its entries, and a child is only loaded if its
+    /// entry lacks the type.
+    fn next_entries(
+        &self,
+        cursor: &mut vfs::DirCursor,
+        out: &mut [vfs::DirEntrySlot],
+    ) -> vfs::Result<usize> {
+        self.check_entry_id(0)?;
+        let cookie = self.change_cookie();
+        if *cursor.cookie.get_or_insert(cookie) != cookie {
+            return Err(FsError::InvalidParam);
+        }
+        let count = {
+            let disk_inode = self.disk_inode.read();
+            match disk_inode.nlinks {
+                0 => 1,
+                _ => disk_inode.size as usize / DIRENT_SIZE,
+            }
+        };
+        let hidden = self.hidden_entries();
+        let mut next = cursor.next;
+        let mut filled = 0;
+        let fill = |slot: &mut vfs::DirEntrySlot, inode, type_: FileType, name: &[u8], next| {
+            slot.inode = inode;
+            slot.type_ = type_.into();
+            slot.name.clear();
+            slot.name.push_str(&escape_name(name));
+            slot.raw_name = core::str::from_utf8(name).is_err().then(|| name.to_vec());
+            slot.next = next;
         };
-        let entry = self.read_direntry(id)?;
-        Ok((
-            self.fs.get_inode(entry.id as usize).metadata()?,
-            String::from(entry.name.as_ref()),
-        ))
+        while filled < out.len() && next < count.min(2) {
+            let (inode_id, name) = self.entry_at(next)?;
+            next += 1;
+            fill(
+                &mut out[filled],
+                inode_id,
+                FileType::Dir,
+                name.as_bytes(),
+                next,
+            );
+            filled += 1;
+        }
+        let mut failed = None;
+        if filled < out.len() && next < count {
+            self.scan_direntry_from(next, |id, entry| {
+                // past the end, or appended but not written yet, which
+                // is only a lost entry in rescue mode
+                if id >= count || (entry.id == 0 && self.fs.rescue.is_none()) {
+                    return Some(());
+                }
+                next = id + 1;
+                if !self.is_listed(id, entry, &hidden) {
+                    return None;
+                }
+                let inode_id = entry.id as INodeId;
+                let type_ = match entry.type_hint() {
+                    Some(type_) => type_,
+                    None => match self.child_inode(id, inode_id) {
+                        Ok(inode) => inode.disk_inode.read().type_,
+                        Err(err) => {
+                            failed = Some(err);
+                            return Some(());
+                        }
+                    },
+                };
+                fill(
+                    &mut out[filled],
+                    inode_id,
+                    type_,
+                    entry.name.as_bytes(),
+                    next,
+                );
+                filled += 1;
+                (filled == out.len()).then_some(())
+            })?;
+        }
+        // a child failing to load may be unlinked since its entry was read
+        if self.change_cookie() != cookie {
+            return Err(FsError::InvalidParam);
+        }
+        if let Some(err) = failed {
+            return Err(err);
+        }
+        cursor.next = next;
+        Ok(filled)
     }
 
-    fn io_control(&self, _cmd: u32, _data: usize) -> vfs::Result<usize> {
-        if self.metadata().unwrap().type_ != vfs::FileType::CharDevice {
-            return Err(FsError::IOCTLError);
+    fn io_control(&self, cmd: u32, data: usize) -> vfs::Result<usize> {
+        let type_ = self.disk_inode.read().type_;
+        if type_ != FileType::CharDevice && type_ != FileType::BlockDevice {
+            return Err(FsError::NotSupported);
         }
-        let device_inodes = self.fs.device_inodes.read();
-        let device_inode = device_inodes.get(&self.device_inode_id);
+        // commands are decoded by the device inode, untouched here
+        let device_inode = self.fs.device_inodes.read().get(&self.rdev).cloned();
         match device_inode {
-            Some(x) => x.io_control(_cmd, _data),
+            Some(x) => x.io_control(cmd, data),
             None => {
-                warn!("cannot find corresponding device inode in call_inoctl");
-                Err(FsError::IOCTLError)
+                warn!("cannot find corresponding device inode in io_control");
+                Err(FsError::NoDevice)
             }
         }
     }
     fn mmap(&self, _area: MMapArea) -> vfs::Result<()> {
         Err(FsError::NotSupported)
     }
-    fn fs(&self) -> Arc<dyn vfs::FileSystem> {
-        self.fs.clone()
+    fn open_hook(&self, exclusive: bool) -> vfs::Result<vfs::OpenGuard> {
+        let type_ = self.disk_inode.read().type_;
+        if type_ != FileType::CharDevice && type_ != FileType::BlockDevice {
+            return Ok(vfs::OpenGuard::default());
+        }
+        // the device decides, as for io_control
+        let device_inode = self.fs.device_inodes.read().get(&self.rdev).cloned();
+        match device_inode {
+            Some(x) => x.open_hook(exclusive),
+            None => Ok(vfs::OpenGuard::default()),
+        }
+    }
+    /// The inode is held by the guard, and never evicted from the strong
+    /// cache. SFS never moves blocks of files, so they stay put anyway.
+    fn pin(&self) -> vfs::Result<vfs::PinGuard> {
+        let inode = self.fs.get_inode(self.id)?;
+        if inode.is_removed() {
+            return Err(FsError::EntryNotFound);
+        }
+        inode.pins.fetch_add(1, Ordering::Relaxed);
+        Ok(vfs::PinGuard::new(move || {
+            if inode.pins.fetch_sub(1, Ordering::Relaxed) == 1 {
+                // evictable again
+                inode.fs.shrink_inode_cache();
+            }
+        }))
+    }
+    /// Passed to the device with the blocks of content, if it takes hints,
+    /// see `rcore_fs::dev::HintedDevice`. Kept while the inode is in memory.
+    fn set_cache_hint(&self, hint: CacheHint) {
+        *self.cache_hint.write() = hint;
+    }
+    fn fs(&self) -> vfs::Result<Arc<dyn vfs::FileSystem>> {
+        Ok(self.fs.clone())
+    }
+    fn ino_key(&self) -> vfs::InodeKey {
+        vfs::InodeKey {
+            fs: self.fs.instance_id,
+            inode: self.id,
+        }
     }
     fn as_any_ref(&self) -> &dyn Any {
         self
@@ -725,13 +2826,63 @@ impl vfs::INode for INodeImpl {
 impl Drop for INodeImpl {
     /// Auto sync when drop
     fn drop(&mut self) {
-        self.sync_all()
-            .expect("Failed to sync when dropping the SimpleFileSystem Inode");
-        if self.disk_inode.read().nlinks <= 0 {
-            self._resize(0).unwrap();
+        // not loaded again once it is to be reclaimed, see `find()`
+        let dying = self.is_removed() || self.fs.silly_renamed.read().contains_key(&self.id);
+        if dying {
+            self.fs.set_dying(self);
+        }
+        if let Err(err) = self.write_inode() {
+            if !self.fs.hold_super_block.load(Ordering::Relaxed) {
+                error!(
+                    "sfs: inode {} is lost, cannot write it back: {:?}",
+                    self.id, err
+                );
+            }
+            self.disk_inode.write().sync();
+        }
+        if let Err(err) = self.remove_silly_name() {
+            // reclaimed by the next `set_silly_rename()`
+            warn!(
+                "sfs: cannot remove hidden entry of inode {}: {:?}",
+                self.id, err
+            );
+        }
+        if self.is_removed() {
+            self.index_discard();
+            if let Err(err) = self._resize(0) {
+                // its blocks leak, for fsck or `scrub()` to reclaim
+                error!("sfs: cannot free removed inode {}: {:?}", self.id, err);
+                self.disk_inode.write().sync();
+                self.fs.forget_inode(self, dying);
+                return;
+            }
             self.disk_inode.write().sync();
+            failpoint!(crash self.fs, "drop_after_resize0");
             self.fs.free_block(self.id);
         }
+        self.fs.forget_inode(self, dying);
+    }
+}
+
+/// An entry of `SimpleFileSystem::inodes`
+enum INodeSlot {
+    /// the inode in memory, or being dropped if it does not upgrade: it is
+    /// written back and then takes its entry out, see `forget_inode()`
+    Loaded(Weak<INodeImpl>),
+    /// the inode is being read from disk, so that it is read once, and not
+    /// while it changes, see `reserve_inode()`
+    Loading,
+    /// the inode is removed and being reclaimed, so its block is about to be
+    /// freed and it must not be loaded again, see `set_dying()`
+    Dying,
+}
+
+impl INodeSlot {
+    fn upgrade(&self) -> Option<Arc<INodeImpl>> {
+        match self {
+            INodeSlot::Loaded(inode) => inode.upgrade(),
+            INodeSlot::Loading | INodeSlot::Dying => None,
+        }
     }
 }
 
@@ -741,273 +2892,2181 @@ impl Drop for INodeImpl {
 /// 为了方便协调外部及INode对SFS的访问，并为日后并行化做准备，
 /// 将SFS设置为内部可变，即对外接口全部是&self，struct的全部field用RwLock包起来
 /// 这样其内部各field均可独立访问
+///
+/// ## Lock order
+/// A thread holding some of the locks below only takes those after them,
+/// see `RankedRwLock`, which checks it in debug builds:
+///
+/// 1. `INodeImpl::dir_lock`, of at most two dirs, in order of inode id
+/// 2. `free_map`, which alone guards allocation, `free_map_changed` and
+///    `unused_blocks` only change under it
+/// 3. `super_block`
+/// 4. `inodes`
+/// 5. `INodeImpl::disk_inode`, of any number of inodes
+///
+/// So blocks are never allocated or freed with an inode locked.
 pub struct SimpleFileSystem {
-    /// on-disk superblock
-    super_block: RwLock<Dirty<SuperBlock>>,
+    /// on-disk superblock, its `unused_blocks` is the one last written
+    super_block: RankedRwLock<Dirty<SuperBlock>>,
     /// blocks in use are mared 0
-    free_map: RwLock<Dirty<BitVec<Lsb0, u8>>>,
+    free_map: RankedRwLock<Dirty<BitVec<Lsb0, u8>>>,
+    /// blocks of the freemap changed since written, counted from `BLKN_FREEMAP`
+    free_map_changed: RwLock<BTreeSet<usize>>,
+    /// free blocks, written to the superblock on sync if changed
+    unused_blocks: AtomicU32,
+    /// see `recovery_blocks()`
+    recovery_blocks: u32,
+    /// blocks after the freemap and the reserved blocks, where inodes and
+    /// content are
+    data_blocks: Range<BlockId>,
+    /// block of the root inode, see `CreateOptions::root_block`
+    root_id: INodeId,
+    /// how names are compared, see `is_name()`
+    names: NamePolicy,
+    /// backup superblocks need to be rewritten on next sync
+    backups_stale: AtomicBool,
+    /// set while `pack_subtree()` builds the image: the superblock is not
+    /// written, and errors writing back on drop are ignored, so that a
+    /// failed pack leaves no valid image
+    hold_super_block: AtomicBool,
     /// inode list
-    inodes: RwLock<BTreeMap<INodeId, Weak<INodeImpl>>>,
-    /// device
+    inodes: RankedRwLock<BTreeMap<INodeId, INodeSlot>>,
+    /// strong LRU cache of recently used inodes
+    inode_cache: RwLock<INodeCache>,
+    /// scratch buffers for directory scans
+    scratch: ScratchPool,
+    /// device, `tracer` over the one given
     device: Arc<dyn Device>,
+    /// see `start_access_trace()`
+    tracer: Arc<TracingDevice>,
     /// Pointer to self, used by INodes
     self_ptr: Weak<SimpleFileSystem>,
     /// device inode
     device_inodes: RwLock<BTreeMap<usize, Arc<DeviceINode>>>,
+    /// reject all modifications, set if the device is read-only
+    read_only: bool,
+    /// the options it was opened or created with, see `options()`
+    options: SfsOptions,
+    /// see `FileSystem::instance_id()`
+    instance_id: u64,
+    /// see `set_silly_rename()`
+    silly_rename: AtomicBool,
+    /// inodes unlinked while open and renamed to `silly_name()`, with the
+    /// dir they are in
+    silly_renamed: RwLock<BTreeMap<INodeId, INodeId>>,
+    /// dirs held by a `TxnGuard`
+    txn_dirs: RwLock<BTreeSet<INodeId>>,
+    /// source of `INodeImpl::change_counter`, bumped by every change of
+    /// entries in the fs
+    change_clock: AtomicU64,
+    /// see `set_dir_readahead()`
+    dir_readahead: AtomicUsize,
+    /// see `set_alloc_groups()`
+    alloc_groups: AtomicBool,
+    /// see `set_io_burst()`
+    io_burst: AtomicUsize,
+    /// the image was not cleanly unmounted when opened, or is too old to tell
+    opened_dirty: bool,
+    /// `unmount()` marked the image clean, see `remount()`
+    unmounted: AtomicBool,
+    /// blocks allocated or freed while `scrub()` runs, `None` if it does not
+    scrub_touched: RwLock<Option<BTreeSet<BlockId>>>,
+    /// see `open_rescue()`, `None` if not opened by it
+    rescue: Option<RwLock<Rescue>>,
+}
+
+/// What `SimpleFileSystem::open_with_options()` does with an image not
+/// cleanly unmounted
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DirtyPolicy {
+    /// Open it as is
+    Proceed,
+    /// Open it if `quick_scan()` finds nothing wrong
+    QuickScan,
+    /// Fail with `Corrupted`, for a full fsck to run first
+    RequireFsck,
+}
+
+/// How `SimpleFileSystem::open_with_options()` opens an image
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    /// What to do if the image was not cleanly unmounted
+    pub on_dirty: DirtyPolicy,
+    /// Custom name policy the image may be made with, the built-in ones
+    /// are always known. An image of another one fails with `WrongFs`.
+    pub names: Option<NamePolicy>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            on_dirty: DirtyPolicy::Proceed,
+            names: None,
+        }
+    }
+}
+
+/// Layout of the image made by `SimpleFileSystem::create_with_options()`.
+/// The default one is that of images before VERSION_LAYOUT, with a
+/// recovery reserve of `DEFAULT_RECOVERY_BLOCKS`.
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    /// Number of blocks right after the freemap never used by the fs, e.g.
+    /// for a bootloader, see `SimpleFileSystem::reserved_range()`
+    pub reserved_blocks: usize,
+    /// Block of the root inode instead of `BLKN_ROOT`, after the reserved
+    /// blocks, so that a bootloader finds it without reading the freemap
+    pub root_block: Option<BlockId>,
+    /// How names are compared, `NamePolicy::exact()` if `None`
+    pub names: Option<NamePolicy>,
+    /// Free blocks kept to delete and repair once the fs is full, see
+    /// `SimpleFileSystem::recovery_blocks()`
+    pub reserve_for_recovery: usize,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            reserved_blocks: 0,
+            root_block: None,
+            names: None,
+            reserve_for_recovery: DEFAULT_RECOVERY_BLOCKS,
+        }
+    }
+}
+
+/// How names are compared in an image, recorded in it by `id`.
+///
+/// An image made with a custom policy is only opened with the same one in
+/// `OpenOptions::names`: the hashed indexes of its dirs depend on it.
+#[derive(Clone)]
+pub struct NamePolicy {
+    /// `NAME_POLICY_EXACT`, `NAME_POLICY_ASCII_CASE_FOLD`, or from
+    /// `NAME_POLICY_CUSTOM` on for a custom one
+    pub id: u32,
+    pub ops: Arc<dyn NameOps>,
+}
+
+impl NamePolicy {
+    /// Names compared byte by byte, as in images before VERSION_NAMES
+    pub fn exact() -> Self {
+        NamePolicy {
+            id: NAME_POLICY_EXACT,
+            ops: Arc::new(ExactNameOps),
+        }
+    }
+
+    /// Names compared ignoring the case of ASCII letters
+    pub fn ascii_case_fold() -> Self {
+        NamePolicy {
+            id: NAME_POLICY_ASCII_CASE_FOLD,
+            ops: Arc::new(AsciiCaseFoldOps),
+        }
+    }
+
+    /// The built-in policy `id`, if any
+    fn built_in(id: u32) -> Option<Self> {
+        match id {
+            NAME_POLICY_EXACT => Some(Self::exact()),
+            NAME_POLICY_ASCII_CASE_FOLD => Some(Self::ascii_case_fold()),
+            _ => None,
+        }
+    }
+}
+
+impl Debug for NamePolicy {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        f.debug_struct("NamePolicy").field("id", &self.id).finish()
+    }
 }
 
 impl SimpleFileSystem {
     /// Load SFS from device
     pub fn open(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
-        let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
-        if !super_block.check() {
+        Self::open_with_options(device, OpenOptions::default())
+    }
+    /// Load SFS from the `len` bytes of `device` from `offset`, see
+    /// `WindowedDevice`. Fail with `InvalidParam` before touching the image
+    /// if its size is not the one of the window, which is likely the wrong
+    /// one.
+    pub fn open_in_window(
+        device: Arc<dyn Device>,
+        offset: usize,
+        len: usize,
+    ) -> vfs::Result<Arc<Self>> {
+        let window: Arc<dyn Device> = Arc::new(WindowedDevice::new(device, offset, len));
+        let super_block = match window.load_struct::<SuperBlock>(BLKN_SUPER)? {
+            super_block if super_block.check() => Some(super_block),
+            _ => window.load_backup_super_block().map(|(_, backup)| backup),
+        };
+        // one not found fails `open()`
+        if let Some(super_block) = super_block {
+            if super_block.blocks as usize != len / BLKSIZE {
+                error!(
+                    "sfs: image of {} blocks in a window of {} bytes at {:#x}",
+                    super_block.blocks, len, offset
+                );
+                return Err(FsError::InvalidParam);
+            }
+        }
+        Self::open(window)
+    }
+    /// Load SFS from device, with `opts`, see `open_with()`
+    pub fn open_with_options(device: Arc<dyn Device>, opts: OpenOptions) -> vfs::Result<Arc<Self>> {
+        let opts = SfsOptions {
+            on_dirty: opts.on_dirty,
+            names: opts.names,
+            ..SfsOptions::default()
+        };
+        Self::open_with(device, &opts)
+    }
+    /// Load SFS from device, with `opts`.
+    ///
+    /// Unless read-only, the image is marked in use on disk before this
+    /// returns, and stays so until `unmount()`. Images older than
+    /// VERSION_STATE have no mark, and are opened as if always clean,
+    /// except for `set_silly_rename()`.
+    ///
+    /// Fail as `SfsOptions::validate()` does, and with `InvalidParam` if
+    /// `opts` ask for other settings than those recorded in the image, see
+    /// `SfsOptions::from_superblock()`. Settings only used on create are
+    /// ignored.
+    pub fn open_with(device: Arc<dyn Device>, opts: &SfsOptions) -> vfs::Result<Arc<Self>> {
+        fs_span!("open");
+        opts.validate()?;
+        let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+        if super_block.is_byte_swapped() {
+            // its backups are byte-swapped as well
+            error!("sfs: image is big-endian, SFS images are little-endian");
             return Err(FsError::WrongFs);
         }
-        let mut freemap_disk = vec![0u8; BLKSIZE * super_block.freemap_blocks as usize];
-        for i in 0..super_block.freemap_blocks as usize {
-            device.read_block(
-                BLKN_FREEMAP + i,
-                0,
-                &mut freemap_disk[i * BLKSIZE..(i + 1) * BLKSIZE],
-            )?;
+        let mut restored = false;
+        if !super_block.check() {
+            let (id, backup) = device.load_backup_super_block().ok_or(FsError::WrongFs)?;
+            warn!(
+                "sfs: primary superblock is broken, use the backup at block {}",
+                id
+            );
+            super_block = backup;
+            restored = true;
+        }
+        if super_block.version < VERSION_UUID {
+            // the field is not initialized in old images
+            super_block.uuid = [0; 16];
+        }
+        if super_block.version < VERSION_BACKUP {
+            super_block.backup_blocks = [0; 2];
+        }
+        if super_block.version < VERSION_LAYOUT {
+            super_block.root_block = BLKN_ROOT as u32;
+            super_block.reserved_blocks = 0;
+        }
+        if super_block.version < VERSION_NAMES {
+            super_block.name_policy = NAME_POLICY_EXACT;
+        }
+        if super_block.version < VERSION_RECOVERY {
+            super_block.recovery_blocks = 0;
+        }
+        opts.check_persisted(&SfsOptions::from_superblock(&super_block))?;
+        let names = match opts.names.clone() {
+            Some(names) if names.id == super_block.name_policy => names,
+            _ => match NamePolicy::built_in(super_block.name_policy) {
+                Some(names) => names,
+                None => {
+                    error!(
+                        "sfs: unknown name policy {:#x}, open with it in OpenOptions::names",
+                        super_block.name_policy
+                    );
+                    return Err(FsError::WrongFs);
+                }
+            },
+        };
+        let opened_dirty = super_block.version < VERSION_STATE || super_block.state != STATE_CLEAN;
+        if super_block.version < VERSION_STATE {
+            super_block.state = STATE_DIRTY;
+        } else if opened_dirty {
+            warn!(
+                "sfs: volume {} was not cleanly unmounted",
+                Uuid(&super_block.uuid)
+            );
+            if opts.on_dirty == DirtyPolicy::RequireFsck {
+                error!("sfs: run fsck on it first");
+                return Err(FsError::Corrupted);
+            }
+        }
+        let mut read_only = opts.read_only || device.is_read_only();
+        if read_only && !opts.read_only {
+            info!("sfs: device is read-only, open in read-only mode");
+        }
+        let (data_blocks, root_id) = check_geometry(&super_block)?;
+        match device.size() {
+            Some(size) if (BLKN_FREEMAP + super_block.freemap_blocks as usize) * BLKSIZE > size => {
+                error!(
+                    "sfs: device has {} bytes, less than the {} blocks of freemap",
+                    size, super_block.freemap_blocks
+                );
+                return Err(FsError::Corrupted);
+            }
+            Some(size) if super_block.blocks as usize * BLKSIZE > size => {
+                warn!(
+                    "sfs: device has {} bytes, less than {} blocks, open in read-only mode",
+                    size, super_block.blocks
+                );
+                read_only = true;
+            }
+            _ => {}
+        }
+        info!(
+            "sfs: open volume {} label {:?}",
+            Uuid(&super_block.uuid),
+            super_block.info.as_ref()
+        );
+        let free_map = device.load_free_map(&super_block)?;
+        if restored {
+            // the backup may lag behind, the freemap is up to date
+            super_block.unused_blocks = free_map.count_ones() as u32;
         }
 
-        Ok(SimpleFileSystem {
-            super_block: RwLock::new(Dirty::new(super_block)),
-            free_map: RwLock::new(Dirty::new(BitVec::from_vec(freemap_disk))),
-            inodes: RwLock::new(BTreeMap::new()),
-            device,
+        let super_block = match restored {
+            // rewrite the primary superblock on next sync
+            true => Dirty::new_dirty(super_block),
+            false => Dirty::new(super_block),
+        };
+        let tracer = Arc::new(TracingDevice::new(device, opts.max_inflight_io));
+        let sfs = SimpleFileSystem {
+            unused_blocks: AtomicU32::new(super_block.unused_blocks),
+            recovery_blocks: super_block.recovery_blocks,
+            data_blocks,
+            root_id,
+            names,
+            super_block: RankedRwLock::new(RANK_SUPER_BLOCK, super_block),
+            free_map: RankedRwLock::new(RANK_FREE_MAP, Dirty::new(free_map)),
+            free_map_changed: RwLock::new(BTreeSet::new()),
+            backups_stale: AtomicBool::new(restored),
+            hold_super_block: AtomicBool::new(false),
+            inodes: RankedRwLock::new(RANK_INODES, BTreeMap::new()),
+            inode_cache: RwLock::new(INodeCache::default()),
+            scratch: ScratchPool::new(DEFAULT_SCRATCH_POOL_SIZE),
+            device: tracer.clone(),
+            tracer,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            read_only,
+            options: opts.clone(),
+            instance_id: vfs::new_instance_id(),
+            silly_rename: AtomicBool::new(false),
+            silly_renamed: RwLock::new(BTreeMap::new()),
+            txn_dirs: RwLock::new(BTreeSet::new()),
+            change_clock: AtomicU64::new(0),
+            dir_readahead: AtomicUsize::new(DEFAULT_DIR_READAHEAD),
+            alloc_groups: AtomicBool::new(true),
+            io_burst: AtomicUsize::new(DEFAULT_IO_BURST),
+            opened_dirty,
+            unmounted: AtomicBool::new(false),
+            scrub_touched: RwLock::new(None),
+            rescue: opts.rescue.then(|| RwLock::new(Rescue::default())),
+        }
+        .wrap();
+        let on_dirty = opts.on_dirty;
+        let check = || -> vfs::Result<()> {
+            // the other inodes are checked as they are reached from it
+            sfs.get_inode(sfs.root_id)?;
+            if opened_dirty && on_dirty == DirtyPolicy::QuickScan {
+                sfs.quick_scan()?;
+            }
+            sfs.mark_in_use()
+        };
+        if let Err(err) = check() {
+            // leave the image as it was
+            sfs.hold_super_block.store(true, Ordering::Relaxed);
+            return Err(err);
+        }
+        sfs.apply_options(opts)?;
+        Ok(sfs)
+    }
+    /// Restore a broken primary superblock from its backup copies,
+    /// without opening the fs. Do nothing if the primary is valid.
+    pub fn restore_superblock(device: Arc<dyn Device>) -> vfs::Result<()> {
+        if device.load_struct::<SuperBlock>(BLKN_SUPER)?.check() {
+            return Ok(());
         }
-        .wrap())
+        let (id, mut super_block) = device.load_backup_super_block().ok_or(FsError::WrongFs)?;
+        check_geometry(&super_block)?;
+        info!("sfs: restore primary superblock from block {}", id);
+        super_block.unused_blocks = device.load_free_map(&super_block)?.count_ones() as u32;
+        device.write_block(BLKN_SUPER, 0, &super_block.to_disk())?;
+        device.sync()?;
+        Ok(())
     }
     /// Create a new SFS on blank disk
+    ///
+    /// The UUID is derived from the system clock and a per-process counter.
+    /// Without the `std` feature there is no clock, and it is derived from
+    /// the counter and the device, which may give the same UUID after a
+    /// reboot: use `create_with()` with `SfsOptions::time_provider` or
+    /// `uuid` for UUIDs unique across boots.
     pub fn create(device: Arc<dyn Device>, space: usize) -> vfs::Result<Arc<Self>> {
-        let blocks = (space + BLKSIZE - 1) / BLKSIZE;
-        let freemap_blocks = (space + BLKBITS * BLKSIZE - 1) / BLKBITS / BLKSIZE;
-        assert!(blocks >= 16, "space too small");
+        Self::create_with(device, space, &SfsOptions::default())
+    }
+    /// Create a new SFS filling the `len` bytes of `device` from `offset`,
+    /// see `WindowedDevice` and `open_in_window()`
+    pub fn create_in_window(
+        device: Arc<dyn Device>,
+        offset: usize,
+        len: usize,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::create(Arc::new(WindowedDevice::new(device, offset, len)), len)
+    }
+    /// Create a new SFS on blank disk, with UUID generated from `seed`
+    pub fn create_with_seed(
+        device: Arc<dyn Device>,
+        space: usize,
+        seed: u64,
+    ) -> vfs::Result<Arc<Self>> {
+        Self::create_with_uuid(device, space, uuid_from_seed(seed))
+    }
+    /// Create a new SFS on blank disk with the given UUID
+    pub fn create_with_uuid(
+        device: Arc<dyn Device>,
+        space: usize,
+        uuid: [u8; 16],
+    ) -> vfs::Result<Arc<Self>> {
+        Self::create_with_options(device, space, uuid, CreateOptions::default())
+    }
+    /// Create a new SFS on blank disk with the given UUID and layout, see
+    /// `create_with()`
+    pub fn create_with_options(
+        device: Arc<dyn Device>,
+        space: usize,
+        uuid: [u8; 16],
+        opts: CreateOptions,
+    ) -> vfs::Result<Arc<Self>> {
+        let opts = SfsOptions {
+            uuid: Some(uuid),
+            reserved_blocks: opts.reserved_blocks,
+            root_block: opts.root_block,
+            names: opts.names,
+            reserve_for_recovery: opts.reserve_for_recovery,
+            ..SfsOptions::default()
+        };
+        Self::create_with(device, space, &opts)
+    }
+    /// Create a new SFS on blank disk with `opts`.
+    ///
+    /// Fail as `SfsOptions::validate()` does, and with `InvalidParam` if
+    /// read-only, `space` is less than 16 blocks or more than the device
+    /// has, the reserved blocks reach the backup superblock in the middle
+    /// of the fs, the root block is not after them, the name policy has an
+    /// id below `NAME_POLICY_CUSTOM` not built in, or the recovery reserve
+    /// is more than the free blocks.
+    pub fn create_with(
+        device: Arc<dyn Device>,
+        space: usize,
+        opts: &SfsOptions,
+    ) -> vfs::Result<Arc<Self>> {
+        static NEXT_SEED: AtomicU64 = AtomicU64::new(0);
+        fs_span!("create_fs", space);
+        opts.validate()?;
+        if opts.read_only {
+            error!("sfs: can not create a read-only fs");
+            return Err(FsError::InvalidParam);
+        }
+        let uuid = match opts.uuid {
+            Some(uuid) => uuid,
+            None if opts.deterministic => uuid_from_seed(0),
+            None => {
+                let count = NEXT_SEED.fetch_add(1, Ordering::Relaxed);
+                let now = match &opts.time_provider {
+                    Some(time) => Some(time.current_time()),
+                    None => system_time(),
+                };
+                let seed = match now {
+                    Some(now) => uuid_seed(now, count),
+                    None => {
+                        warn!("sfs: no clock to make a UUID from, it may repeat after a reboot");
+                        uuid_seed_without_clock(&device, space, count)
+                    }
+                };
+                uuid_from_seed(seed)
+            }
+        };
+        // a partial block at the end is never used
+        let blocks = space / BLKSIZE;
+        let freemap_blocks = space.div_ceil(BLKBITS * BLKSIZE);
+        if blocks < 16 {
+            error!("sfs: {} bytes are too small for a fs", space);
+            return Err(FsError::InvalidParam);
+        }
+        if let Some(size) = device.size() {
+            if blocks * BLKSIZE > size {
+                error!("sfs: {} bytes are more than the device has", space);
+                return Err(FsError::InvalidParam);
+            }
+        }
+
+        let backup_blocks = backup_super_blocks(blocks);
+        let data_blocks = (BLKN_FREEMAP + freemap_blocks + opts.reserved_blocks)..blocks;
+        if data_blocks.start > backup_blocks[0] as usize {
+            error!(
+                "sfs: {} reserved blocks reach the backup superblock",
+                opts.reserved_blocks
+            );
+            return Err(FsError::InvalidParam);
+        }
+        let names = match (opts.names.clone(), opts.case_insensitive) {
+            (Some(names), _) => names,
+            (None, Some(true)) => NamePolicy::ascii_case_fold(),
+            (None, _) => NamePolicy::exact(),
+        };
+        if names.id < NAME_POLICY_CUSTOM && NamePolicy::built_in(names.id).is_none() {
+            error!("sfs: name policy id {:#x} is reserved", names.id);
+            return Err(FsError::InvalidParam);
+        }
+        let root_id = opts.root_block.unwrap_or(BLKN_ROOT);
+        let root_moved = root_id != BLKN_ROOT;
+        if root_moved
+            && (!data_blocks.contains(&root_id) || backup_blocks.contains(&(root_id as u32)))
+        {
+            error!("sfs: root inode can not be at block {}", root_id);
+            return Err(FsError::InvalidParam);
+        }
 
+        let unused_blocks = data_blocks.len() - backup_blocks.len() - root_moved as usize;
+        if opts.reserve_for_recovery > unused_blocks {
+            error!(
+                "sfs: recovery reserve of {} blocks is more than the {} free",
+                opts.reserve_for_recovery, unused_blocks
+            );
+            return Err(FsError::InvalidParam);
+        }
         let super_block = SuperBlock {
             magic: MAGIC,
             blocks: blocks as u32,
-            unused_blocks: (blocks - BLKN_FREEMAP - freemap_blocks) as u32,
+            unused_blocks: unused_blocks as u32,
             info: Str32::from(DEFAULT_INFO),
             freemap_blocks: freemap_blocks as u32,
+            version: VERSION,
+            uuid,
+            backup_blocks,
+            state: STATE_DIRTY,
+            root_block: root_id as u32,
+            reserved_blocks: opts.reserved_blocks as u32,
+            name_policy: names.id,
+            recovery_blocks: opts.reserve_for_recovery as u32,
         };
         let free_map = {
             let mut bitset = BitVec::with_capacity(freemap_blocks * BLKBITS);
             bitset.extend(core::iter::repeat(false).take(freemap_blocks * BLKBITS));
-            for i in (BLKN_FREEMAP + freemap_blocks)..blocks {
+            for i in data_blocks.clone() {
                 bitset.set(i, true);
             }
+            for &id in backup_blocks.iter() {
+                bitset.set(id as usize, false);
+            }
+            bitset.set(root_id, false);
             bitset
         };
 
+        let tracer = Arc::new(TracingDevice::new(device, opts.max_inflight_io));
         let sfs = SimpleFileSystem {
-            super_block: RwLock::new(Dirty::new_dirty(super_block)),
-            free_map: RwLock::new(Dirty::new_dirty(free_map)),
-            inodes: RwLock::new(BTreeMap::new()),
-            device,
+            unused_blocks: AtomicU32::new(super_block.unused_blocks),
+            recovery_blocks: super_block.recovery_blocks,
+            data_blocks,
+            root_id,
+            names,
+            super_block: RankedRwLock::new(RANK_SUPER_BLOCK, Dirty::new_dirty(super_block)),
+            free_map: RankedRwLock::new(RANK_FREE_MAP, Dirty::new_dirty(free_map)),
+            free_map_changed: RwLock::new((0..freemap_blocks).collect()),
+            backups_stale: AtomicBool::new(true),
+            hold_super_block: AtomicBool::new(false),
+            inodes: RankedRwLock::new(RANK_INODES, BTreeMap::new()),
+            inode_cache: RwLock::new(INodeCache::default()),
+            scratch: ScratchPool::new(DEFAULT_SCRATCH_POOL_SIZE),
+            device: tracer.clone(),
+            tracer,
             self_ptr: Weak::default(),
             device_inodes: RwLock::new(BTreeMap::new()),
+            read_only: false,
+            options: opts.clone(),
+            instance_id: vfs::new_instance_id(),
+            silly_rename: AtomicBool::new(false),
+            silly_renamed: RwLock::new(BTreeMap::new()),
+            txn_dirs: RwLock::new(BTreeSet::new()),
+            change_clock: AtomicU64::new(0),
+            dir_readahead: AtomicUsize::new(DEFAULT_DIR_READAHEAD),
+            alloc_groups: AtomicBool::new(true),
+            io_burst: AtomicUsize::new(DEFAULT_IO_BURST),
+            opened_dirty: false,
+            unmounted: AtomicBool::new(false),
+            scrub_touched: RwLock::new(None),
+            rescue: None,
         }
         .wrap();
 
         // Init root INode
-        let root = sfs._new_inode(BLKN_ROOT, Dirty::new_dirty(DiskINode::new_dir()));
-        root.init_direntry(BLKN_ROOT)?;
-        root.nlinks_inc(); //for .
-        root.nlinks_inc(); //for ..(root's parent is itself)
-        root.sync_all()?;
-
+        let root = sfs._new_inode(root_id, sfs.new_disk_inode(DiskINode::new_dir()));
+        let init = || -> vfs::Result<()> {
+            root.init_direntry(root_id)?;
+            root.nlinks_inc()?; //for .
+            root.nlinks_inc()?; //for ..(root's parent is itself)
+            root.write_inode()
+        };
+        if let Err(err) = init() {
+            // the device failed, drop the fs without writing it again
+            sfs.hold_super_block.store(true, Ordering::Relaxed);
+            return Err(err);
+        }
+        sfs.apply_options(opts)?;
         Ok(sfs)
     }
+    /// Set what `opts` set at runtime
+    fn apply_options(&self, opts: &SfsOptions) -> vfs::Result<()> {
+        self.set_inode_cache_size(opts.inode_cache_size);
+        self.set_scratch_pool_size(opts.scratch_pool_size);
+        self.set_dir_readahead(opts.dir_readahead);
+        self.set_io_burst(opts.io_burst);
+        self.set_max_inflight_io(opts.max_inflight_io);
+        self.set_alloc_groups(opts.alloc_groups);
+        if opts.silly_rename {
+            self.set_silly_rename(true)?;
+        }
+        Ok(())
+    }
+    /// The options in effect: those recorded in the image, see
+    /// `SfsOptions::from_superblock()`, the ones it was opened or created
+    /// with, and the current runtime settings. Its name policy is `names`,
+    /// and `read_only` is set if the device is read-only too.
+    pub fn options(&self) -> SfsOptions {
+        let mut opts = SfsOptions::from_superblock(&self.super_block.read());
+        opts.read_only = self.read_only;
+        opts.rescue = self.rescue.is_some();
+        opts.names = Some(self.names.clone());
+        opts.deterministic = self.options.deterministic;
+        opts.time_provider = self.options.time_provider.clone();
+        opts.on_dirty = self.options.on_dirty;
+        opts.inode_cache_size = self.inode_cache.read().capacity;
+        opts.scratch_pool_size = self.scratch.size();
+        opts.dir_readahead = self.dir_readahead.load(Ordering::Relaxed);
+        opts.io_burst = self.io_burst();
+        opts.max_inflight_io = self.tracer.max_inflight_io();
+        opts.alloc_groups = self.alloc_groups.load(Ordering::Relaxed);
+        opts.silly_rename = self.silly_rename.load(Ordering::Relaxed);
+        opts
+    }
     /// Wrap pure SimpleFileSystem with Arc
     /// Used in constructors
-    fn wrap(self) -> Arc<Self> {
-        // Create an Arc, make a Weak from it, then put it into the struct.
-        // It's a little tricky.
-        let fs = Arc::new(self);
-        let weak = Arc::downgrade(&fs);
-        let ptr = Arc::into_raw(fs) as *mut Self;
-        unsafe {
-            (*ptr).self_ptr = weak;
-        }
-        unsafe { Arc::from_raw(ptr) }
+    fn wrap(mut self) -> Arc<Self> {
+        Arc::new_cyclic(|weak| {
+            self.self_ptr = weak.clone();
+            self
+        })
     }
 
-    /// Allocate a block, return block id
-    fn alloc_block(&self) -> Option<usize> {
-        let mut free_map = self.free_map.write();
-        let id = free_map.alloc();
-        if let Some(block_id) = id {
-            let mut super_block = self.super_block.write();
-            if super_block.unused_blocks == 0 {
-                free_map.set(block_id, true);
-                return None;
-            }
-            super_block.unused_blocks -= 1; // will not underflow
-            trace!("alloc block {:#x}", block_id);
-        } else {
-            let super_block = self.super_block.read();
-            panic!("{:?}", super_block)
-        }
-        id
+    /// The `Arc` the fs is in
+    #[allow(clippy::unwrap_used)]
+    fn this(&self) -> Arc<Self> {
+        // made by `wrap()`, and only dropped once no inode refers to it, so
+        // alive while borrowed except when dropped, which loads no inode
+        self.self_ptr.upgrade().unwrap()
     }
-    /// Free a block
+
+    /// UUID of the volume, all zero for images without one
+    pub fn uuid(&self) -> [u8; 16] {
+        self.super_block.read().uuid
+    }
+    /// Blocks after the freemap the fs never uses, empty unless reserved by
+    /// `CreateOptions::reserved_blocks`
+    pub fn reserved_range(&self) -> Range<BlockId> {
+        let data_begin = BLKN_FREEMAP + self.super_block.read().freemap_blocks as usize;
+        data_begin..self.data_blocks.start
+    }
+    /// Free blocks kept for deleting and repairing once the fs is full, 0
+    /// in images before VERSION_RECOVERY.
+    ///
+    /// Writes fail with `NoDeviceSpace` once only these are free, so
+    /// `info().bavail` leaves them out, while `info().bfree` counts them.
+    /// Unlinking and committing transactions which only unlink may take
+    /// them for the entries and indexes of dirs, as may `check_dir_indexes()`,
+    /// and give them back with what they free.
+    pub fn recovery_blocks(&self) -> usize {
+        self.recovery_blocks as usize
+    }
+    /// Label of the volume, stored in the info string of superblock
+    pub fn label(&self) -> String {
+        String::from(self.super_block.read().info.as_ref())
+    }
+    /// Whether the fs rejects all modifications
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    /// Set label of the volume. It is written back on next sync.
+    pub fn set_label(&self, label: &str) -> vfs::Result<()> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        self.remount()?;
+        self.super_block.write().info = Str32::new(label)?;
+        self.backups_stale.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Set capacity of the strong inode cache, 0 to disable it (default).
+    ///
+    /// Cached INodes hold the fs alive, `unmount()` empties and disables the
+    /// cache before the fs is dropped.
+    ///
+    /// Pinned inodes, see `INode::pin()`, are kept beyond the capacity.
+    pub fn set_inode_cache_size(&self, size: usize) {
+        self.inode_cache.write().capacity = size;
+        self.shrink_inode_cache();
+    }
+    fn shrink_inode_cache(&self) {
+        let evicted = self.inode_cache.write().shrink();
+        Self::evict_inodes(evicted);
+    }
+    /// Inodes pinned by `INode::pin()`, for diagnostics
+    pub fn pinned_inodes(&self) -> Vec<INodeId> {
+        // dropped without the lock held, as the last reference drops them
+        let inodes: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(INodeSlot::upgrade)
+            .collect();
+        inodes
+            .iter()
+            .filter(|inode| inode.is_pinned())
+            .map(|inode| inode.id)
+            .collect()
+    }
+    /// Allocate blocks near where they are read from, on by default.
+    ///
+    /// The device is divided in groups of `ALLOC_GROUP_BLOCKS` blocks. A new
+    /// inode goes in the group of its dir, the first block of a file after
+    /// its inode and each next block after the one before, so that reading
+    /// a file is sequential. Once a group is full, the emptiest other group
+    /// is used. Off, every block is the first free one, which depends only
+    /// on the blocks in use, e.g. for reproducible images.
+    pub fn set_alloc_groups(&self, enabled: bool) {
+        self.alloc_groups.store(enabled, Ordering::Relaxed);
+    }
+    /// Set the most blocks read or written between two checkpoints of
+    /// `read_at_with()` and `write_at_with()`, 256 by default, fewer if
+    /// `TaskContext::interval()` is. Zeroing is done by runs of at most as
+    /// many blocks too.
+    pub fn set_io_burst(&self, blocks: usize) {
+        self.io_burst.store(blocks.max(1), Ordering::Relaxed);
+    }
+    fn io_burst(&self) -> usize {
+        self.io_burst.load(Ordering::Relaxed)
+    }
+    /// Set the most operations in the device at once, 16 by default, so
+    /// that concurrent users of the fs do not pile up more than the device
+    /// queues. An operation counts for the blocks it reads or writes, and
+    /// one on more blocks than that has the device to itself.
+    ///
+    /// Waiting reads of metadata go before the other operations.
+    /// Prefetching, `prefetch()` and `set_dir_readahead()`, is skipped
+    /// rather than wait. See `SfsStats::io_peak` and the others.
+    pub fn set_max_inflight_io(&self, ops: usize) {
+        self.tracer.set_max_inflight_io(ops);
+    }
+    /// Set how many inodes listing a dir with metadata loads ahead, 32 by
+    /// default, 0 to disable it.
+    ///
+    /// When `get_entry_with_metadata()` needs a child not in memory, the
+    /// children of the entries after it are loaded too, adjacent inode
+    /// blocks with a single device read. The dir keeps them until its next
+    /// readahead. Inodes already in memory are left as they are, and the
+    /// strong inode cache is not used.
+    pub fn set_dir_readahead(&self, inodes: usize) {
+        self.dir_readahead.store(inodes, Ordering::Relaxed);
+    }
+    /// Set number of scratch buffers kept for directory scans, 4 by default.
+    ///
+    /// Buffers are preallocated. Concurrent scans beyond this number, or any
+    /// scan if it is 0, allocate from the global allocator instead.
+    pub fn set_scratch_pool_size(&self, size: usize) {
+        self.scratch.set_size(size);
+    }
+    /// Set whether unlinking the last name of an open file keeps it on disk
+    /// under a hidden name until it is closed, off by default.
+    ///
+    /// The entry is renamed to ".sfs-unlinked-<inode>" in the same dir, and
+    /// removed with the blocks of the file when the last reference is
+    /// dropped. Listings skip it, but `find()` of the hidden name still
+    /// works, and the dir is not empty while it is there. Dirs are removed
+    /// as usual.
+    ///
+    /// Turning it on reclaims hidden entries left by a crash, so do it
+    /// right after `open()`. Return the number of reclaimed files. Images
+    /// cleanly unmounted have none, and are not searched.
+    pub fn set_silly_rename(&self, enabled: bool) -> vfs::Result<usize> {
+        if !enabled {
+            self.silly_rename.store(false, Ordering::Relaxed);
+            return Ok(0);
+        }
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let reclaimed = match self.opened_dirty {
+            true => self.reclaim_silly_renamed()?,
+            false => 0,
+        };
+        self.silly_rename.store(true, Ordering::Relaxed);
+        Ok(reclaimed)
+    }
+    /// Whether the image was not cleanly unmounted when opened. Always true
+    /// for images older than VERSION_STATE, which do not tell.
+    pub fn opened_dirty(&self) -> bool {
+        self.opened_dirty
+    }
+    /// Sync the fs and mark the image cleanly unmounted, to call before
+    /// dropping it. Modifying the fs afterwards marks it in use again.
+    ///
+    /// Files unlinked while open keep their hidden entries of
+    /// `set_silly_rename()`, so the image stays marked in use while there
+    /// are some, for the next `open()` to reclaim them.
+    ///
+    /// The strong inode cache is emptied and disabled, see
+    /// `set_inode_cache_size()`.
+    pub fn unmount(&self) -> vfs::Result<()> {
+        self.set_inode_cache_size(0);
+        vfs::FileSystem::sync(self)?;
+        if self.read_only || self.super_block.read().version < VERSION_STATE {
+            return Ok(());
+        }
+        if !self.silly_renamed.read().is_empty() {
+            warn!("sfs: files unlinked while open are left, keep the image dirty");
+            return Ok(());
+        }
+        let mut super_block = self.super_block.write();
+        super_block.state = STATE_CLEAN;
+        self.write_super_block(&mut super_block)?;
+        self.unmounted.store(true, Ordering::SeqCst);
+        drop(super_block);
+        self.device.sync()?;
+        Ok(())
+    }
+    /// Mark the image in use on disk at once, so that a crash before
+    /// `unmount()` is seen by the next `open()`
+    fn mark_in_use(&self) -> vfs::Result<()> {
+        let mut super_block = self.super_block.write();
+        if self.read_only
+            || super_block.version < VERSION_STATE
+            || self.hold_super_block.load(Ordering::Relaxed)
+        {
+            return Ok(());
+        }
+        let was_dirty = super_block.dirty();
+        super_block.state = STATE_DIRTY;
+        self.device
+            .write_block(BLKN_SUPER, 0, &super_block.to_disk())?;
+        if !was_dirty {
+            super_block.sync();
+        }
+        drop(super_block);
+        self.device.sync()?;
+        Ok(())
+    }
+    /// Mark the image in use again if `unmount()` marked it clean, before
+    /// modifying the fs
+    fn remount(&self) -> vfs::Result<()> {
+        if !self.unmounted.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let marked = self.mark_in_use();
+        if marked.is_err() {
+            self.unmounted.store(true, Ordering::SeqCst);
+        }
+        marked
+    }
+    /// Check an image not cleanly unmounted, as `DirtyPolicy::QuickScan`
+    /// does on open: the free block count of the superblock against the
+    /// freemap, which is trusted and fixes it, then the first
+    /// `QUICK_SCAN_INODES` inodes reached from the root, which must load
+    /// and be marked used. Fail with `Corrupted` if one is not.
+    pub fn quick_scan(&self) -> vfs::Result<()> {
+        let free = self.free_map.read().count_ones() as u32;
+        let counted = self.unused_blocks.load(Ordering::Relaxed);
+        if counted != free {
+            warn!(
+                "sfs: superblock counts {} free blocks, the freemap {}",
+                counted, free
+            );
+            self.unused_blocks.store(free, Ordering::Relaxed);
+        }
+        let mut scanned = 0;
+        self.walk_inodes(|inode| {
+            if self.free_map.read()[inode.id] {
+                error!("sfs: inode {} is in a free block", inode.id);
+                return Err(FsError::Corrupted);
+            }
+            scanned += 1;
+            Ok(match scanned < QUICK_SCAN_INODES {
+                true => ControlFlow::Continue(()),
+                false => ControlFlow::Break(()),
+            })
+        })?;
+        Ok(())
+    }
+    /// Unlink hidden entries of `set_silly_rename()` not opened by now
+    fn reclaim_silly_renamed(&self) -> vfs::Result<usize> {
+        let mut leftovers = Vec::new();
+        self.walk_inodes(|inode| {
+            if inode.disk_inode.read().type_ == FileType::Dir {
+                inode.scan_direntry(|id, entry| {
+                    let inode_id = entry.id as INodeId;
+                    if id >= 2 && entry.name == *silly_name(inode_id).as_str() {
+                        leftovers.push((inode.clone(), inode_id));
+                    }
+                    None::<()>
+                })?;
+            }
+            Ok(ControlFlow::<()>::Continue(()))
+        })?;
+        let mut reclaimed = 0;
+        for (dir, inode_id) in leftovers {
+            if self.silly_renamed.read().contains_key(&inode_id) {
+                continue;
+            }
+            info!("sfs: reclaim inode {} unlinked while open", inode_id);
+            dir.unlink(&silly_name(inode_id))?;
+            reclaimed += 1;
+        }
+        Ok(reclaimed)
+    }
+    /// Visit every inode in use with its metadata, until `f` returns `Break`.
+    ///
+    /// Inodes are found by walking the directory tree from root, plus the
+    /// orphans which are unlinked but still open. On-disk blocks are never
+    /// guessed to be inodes, so data blocks can not be misreported.
+    /// Each inode is visited once even if it has multiple hard links.
+    ///
+    /// The inode is kept in memory while `f` is called on it,
+    /// so `open_inode()` on it is cheap there.
+    pub fn for_each_inode(
+        &self,
+        mut f: impl FnMut(INodeId, &Metadata) -> ControlFlow<()>,
+    ) -> vfs::Result<()> {
+        self.walk_inodes(|inode| Ok(f(inode.id, &inode.metadata()?)))?;
+        Ok(())
+    }
+    /// Open inode by id. Fail with `EntryNotFound` if `id` is not an inode in use.
+    ///
+    /// Unless it is already in memory, the directory tree is walked to find it.
+    pub fn open_inode(&self, id: INodeId) -> vfs::Result<Arc<dyn INode>> {
+        let in_memory = self.inodes.read().get(&id).and_then(INodeSlot::upgrade);
+        if let Some(inode) = in_memory {
+            return Ok(inode);
+        }
+        let found = self.walk_inodes(|inode| {
+            if inode.id == id {
+                Ok(ControlFlow::Break(inode.clone()))
+            } else {
+                Ok(ControlFlow::Continue(()))
+            }
+        })?;
+        match found {
+            Some(inode) => Ok(inode),
+            None => Err(FsError::EntryNotFound),
+        }
+    }
+    /// Check the hashed index of every dir against its entries, and rebuild
+    /// the broken ones. Return the number of rebuilt indexes.
+    pub fn check_dir_indexes(&self) -> vfs::Result<usize> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let mut rebuilt = 0;
+        self.walk_inodes(|inode| {
+            if inode.disk_inode.read().type_ == FileType::Dir && !inode.index_verify()? {
+                warn!("sfs: index of dir {} is broken, rebuild it", inode.id);
+                inode.with_recovery(|| inode.index_rebuild())?;
+                rebuilt += 1;
+            }
+            Ok(ControlFlow::<()>::Continue(()))
+        })?;
+        Ok(rebuilt)
+    }
+    /// Check the chunk table of every compressed file, and that its chunks
+    /// decompress. Return the ids of the broken ones.
+    pub fn check_chunk_tables(&self) -> vfs::Result<Vec<INodeId>> {
+        let mut broken = Vec::new();
+        self.walk_inodes(|inode| {
+            if inode.disk_inode.read().is_compressed() && !inode.verify_chunks()? {
+                warn!("sfs: chunk table of inode {} is broken", inode.id);
+                broken.push(inode.id);
+            }
+            Ok(ControlFlow::<()>::Continue(()))
+        })?;
+        Ok(broken)
+    }
+    /// Whether an inode id read from `parent`, a dir with its
+    /// `change_cookie()` then, may be stale, as the entries changed since
+    fn entry_changed(parent: &Option<(Arc<INodeImpl>, u64)>) -> bool {
+        parent
+            .as_ref()
+            .is_some_and(|(dir, cookie)| dir.change_cookie() != *cookie)
+    }
+    /// Walk all inodes in use, see `for_each_inode()`
+    fn walk_inodes<T>(
+        &self,
+        mut f: impl FnMut(&Arc<INodeImpl>) -> vfs::Result<ControlFlow<T>>,
+    ) -> vfs::Result<Option<T>> {
+        // hold orphans, so they can not be freed during the walk
+        let orphans: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(INodeSlot::upgrade)
+            .filter(|inode| inode.is_removed())
+            .collect();
+        let mut visited = BTreeSet::new();
+        // with the dir the id was read from, and its `change_cookie()` then
+        let mut stack = vec![(self.root_id, None)];
+        stack.extend(orphans.iter().map(|inode| (inode.id, None)));
+        while let Some((id, parent)) = stack.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let inode = match self.get_inode(id) {
+                Ok(inode) => inode,
+                // unlinked since, and maybe reclaimed
+                Err(_) if Self::entry_changed(&parent) => continue,
+                Err(err) => return Err(err),
+            };
+            if inode.disk_inode.read().type_ == FileType::Dir {
+                let cookie = inode.change_cookie();
+                inode.scan_direntry(|id, entry| {
+                    // skip "." and ".."
+                    if id >= 2 {
+                        stack.push((entry.id as INodeId, Some((inode.clone(), cookie))));
+                    }
+                    None::<()>
+                })?;
+            }
+            if let ControlFlow::Break(ret) = f(&inode)? {
+                return Ok(Some(ret));
+            }
+        }
+        Ok(None)
+    }
+    /// Get statistics of the fs
+    pub fn stats(&self) -> SfsStats {
+        let (scratch_hits, scratch_misses) = self.scratch.counters();
+        let io = self.tracer.io_stats();
+        SfsStats {
+            inode_table_size: self.inodes.read().len(),
+            inode_cache_size: self.inode_cache.read().inodes.len(),
+            scratch_hits,
+            scratch_misses,
+            io_in_flight: io.in_use,
+            io_peak: io.peak,
+            io_waits: io.waits,
+            io_shed: io.refused,
+        }
+    }
+
+    /// Allocate a block, return block id. It is the first free block after
+    /// `goal` in its allocation group if any, see `set_alloc_groups()`,
+    /// otherwise the first free block. The recovery reserve is left free.
+    fn alloc_block(&self, goal: Option<BlockId>) -> Option<usize> {
+        self.alloc_block_keeping(goal, self.recovery_blocks)
+    }
+    /// `alloc_block()` taking from the recovery reserve too, only to delete
+    /// or repair, see `recovery_blocks()`
+    fn alloc_block_reserved(&self, goal: Option<BlockId>) -> Option<usize> {
+        self.alloc_block_keeping(goal, 0)
+    }
+    /// `alloc_block()` unless only `keep` blocks are free
+    fn alloc_block_keeping(&self, goal: Option<BlockId>, keep: u32) -> Option<usize> {
+        let mut free_map = self.free_map.write();
+        let id = match goal.filter(|_| self.alloc_groups.load(Ordering::Relaxed)) {
+            Some(goal) => free_map.alloc_near(goal),
+            None => free_map.alloc(),
+        };
+        let unused = self.unused_blocks.load(Ordering::Relaxed);
+        if let Some(block_id) = id {
+            self.free_map_changed.write().insert(block_id / BLKBITS);
+            if !self.data_blocks.contains(&block_id) {
+                // only a corrupt freemap has free bits past the end, or
+                // among the reserved blocks
+                warn!("sfs: free block {:#x} out of the data blocks", block_id);
+                return None;
+            }
+            if unused <= keep {
+                free_map.set(block_id, true);
+                return None;
+            }
+            // will not underflow, only changed under `free_map`
+            self.unused_blocks.store(unused - 1, Ordering::Relaxed);
+            self.scrub_touch(block_id);
+            trace!("alloc block {:#x}", block_id);
+        } else if unused != 0 {
+            // the disk is full, the count is wrong
+            warn!("sfs: {} blocks counted unused in a full freemap", unused);
+            self.unused_blocks.store(0, Ordering::Relaxed);
+        }
+        id
+    }
+    /// Free a block
     fn free_block(&self, block_id: usize) {
         let mut free_map = self.free_map.write();
-        assert!(!free_map[block_id]);
+        if !self.data_blocks.contains(&block_id) {
+            // a corrupt inode, keep the freemap consistent
+            warn!("sfs: ignore freeing block {:#x} out of the fs", block_id);
+            return;
+        }
+        if free_map[block_id] {
+            // shared by two corrupt inodes
+            warn!("sfs: ignore freeing block {:#x} already free", block_id);
+            return;
+        }
         free_map.set(block_id, true);
-        self.super_block.write().unused_blocks += 1;
+        self.free_map_changed.write().insert(block_id / BLKBITS);
+        self.unused_blocks.fetch_add(1, Ordering::Relaxed);
+        self.scrub_touch(block_id);
         trace!("free block {:#x}", block_id);
     }
 
-    pub fn new_device_inode(&self, device_inode_id: usize, device_inode: Arc<DeviceINode>) {
-        self.device_inodes
-            .write()
-            .insert(device_inode_id, device_inode);
+    /// Register the device behind char/block device INodes with `rdev`,
+    /// which is the device number packed by `make_rdev()`.
+    ///
+    /// Migration: images made before `rdev` was standardized store the key
+    /// used here as is, so keep registering with the same keys for them.
+    pub fn new_device_inode(&self, rdev: usize, device_inode: Arc<DeviceINode>) {
+        self.device_inodes.write().insert(rdev, device_inode);
+    }
+
+    /// Create a new INode struct, not in self.inodes yet
+    fn make_inode(&self, id: INodeId, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
+        let rdev = disk_inode.rdev;
+        Arc::new(INodeImpl {
+            id,
+            disk_inode: RankedRwLock::new(RANK_DISK_INODE, disk_inode),
+            fs: self.this(),
+            rdev,
+            dots_stale: AtomicBool::new(false),
+            readahead: RwLock::new(Vec::new()),
+            dir_lock: RankedRwLock::new(RANK_DIR, ()),
+            change_counter: AtomicU64::new(self.change_clock.load(Ordering::SeqCst)),
+            chunk_cache: spin::Mutex::new(Default::default()),
+            recovery: AtomicBool::new(false),
+            pins: AtomicUsize::new(0),
+            cache_hint: RwLock::new(CacheHint::Normal),
+        })
     }
 
     /// Create a new INode struct, then insert it to self.inodes
     /// Private used for load or create INode
     fn _new_inode(&self, id: INodeId, disk_inode: Dirty<DiskINode>) -> Arc<INodeImpl> {
-        let device_inode_id = disk_inode.device_inode_id;
-        let inode = Arc::new(INodeImpl {
-            id,
-            disk_inode: RwLock::new(disk_inode),
-            fs: self.self_ptr.upgrade().unwrap(),
-            device_inode_id,
-        });
-        self.inodes.write().insert(id, Arc::downgrade(&inode));
+        let inode = self.make_inode(id, disk_inode);
+        let mut inodes = self.inodes.write();
+        inodes.insert(id, INodeSlot::Loaded(Arc::downgrade(&inode)));
         inode
     }
+    /// Inode `id` if in memory, or `None` with it reserved as
+    /// `INodeSlot::Loading` for the caller to load. If it is being loaded or
+    /// dropped, wait for that. Fail with `EntryNotFound` if it is being
+    /// reclaimed.
+    fn reserve_inode(&self, id: INodeId) -> vfs::Result<Option<Arc<INodeImpl>>> {
+        loop {
+            let inode = self.inodes.read().get(&id).and_then(INodeSlot::upgrade);
+            if inode.is_some() {
+                return Ok(inode);
+            }
+            let mut inodes = self.inodes.write();
+            match inodes.get(&id) {
+                None => {
+                    inodes.insert(id, INodeSlot::Loading);
+                    return Ok(None);
+                }
+                Some(INodeSlot::Dying) => return Err(FsError::EntryNotFound),
+                Some(slot) => {
+                    if let Some(inode) = slot.upgrade() {
+                        return Ok(Some(inode));
+                    }
+                }
+            }
+            drop(inodes);
+            // it does not take long, and takes no lock held by the caller
+            core::hint::spin_loop();
+        }
+    }
+    /// Fill the `INodeSlot::Loading` of `id` with the inode loaded, or free
+    /// it if loading failed
+    fn finish_loading(&self, id: INodeId, disk_inode: Option<DiskINode>) -> Option<Arc<INodeImpl>> {
+        let mut inodes = self.inodes.write();
+        if !matches!(inodes.get(&id), Some(INodeSlot::Loading)) {
+            // only taken by a new inode, so it was free when loaded
+            return None;
+        }
+        let disk_inode = match disk_inode {
+            Some(disk_inode) => disk_inode,
+            None => {
+                inodes.remove(&id);
+                return None;
+            }
+        };
+        let inode = self.make_inode(id, Dirty::new(disk_inode));
+        inodes.insert(id, INodeSlot::Loaded(Arc::downgrade(&inode)));
+        Some(inode)
+    }
+    /// Mark `inode`, dropped as removed, as `INodeSlot::Dying` while it is
+    /// reclaimed
+    fn set_dying(&self, inode: &INodeImpl) {
+        let mut inodes = self.inodes.write();
+        let own = match inodes.get(&inode.id) {
+            Some(INodeSlot::Loaded(weak)) => core::ptr::eq(weak.as_ptr(), inode),
+            None => true,
+            _ => false,
+        };
+        if own {
+            inodes.insert(inode.id, INodeSlot::Dying);
+        }
+    }
+    /// Take the entry of `inode`, done dropping, out of `inodes`: as
+    /// `INodeSlot::Dying` if `dying`, unless the id is reused meanwhile
+    fn forget_inode(&self, inode: &INodeImpl, dying: bool) {
+        let mut inodes = self.inodes.write();
+        let own = match inodes.get(&inode.id) {
+            Some(INodeSlot::Dying) => dying,
+            Some(INodeSlot::Loaded(weak)) => core::ptr::eq(weak.as_ptr(), inode),
+            _ => false,
+        };
+        if own {
+            inodes.remove(&inode.id);
+        }
+    }
 
     /// Get inode by id. Load if not in memory.
-    /// ** Must ensure it's a valid INode **
-    fn get_inode(&self, id: INodeId) -> Arc<INodeImpl> {
-        assert!(!self.free_map.read()[id]);
-
-        // In the BTreeSet and not weak.
-        if let Some(inode) = self.inodes.read().get(&id) {
-            if let Some(inode) = inode.upgrade() {
-                return inode;
+    /// Fail with `EntryNotFound` if it is being reclaimed, or with
+    /// `Corrupted` if `id`, read from disk, is not an inode in use. In
+    /// rescue mode, an inode which can not be read is quarantined instead.
+    fn get_inode(&self, id: INodeId) -> vfs::Result<Arc<INodeImpl>> {
+        let inode = match self.reserve_inode(id)? {
+            Some(inode) => inode,
+            None => match self.read_inode(id).or_else(|err| self.quarantine(id, err)) {
+                Ok(disk_inode) => self
+                    .finish_loading(id, Some(disk_inode))
+                    .ok_or(FsError::EntryNotFound)?,
+                Err(err) => {
+                    self.finish_loading(id, None);
+                    return Err(err);
+                }
+            },
+        };
+        self.cache_inode(&inode);
+        Ok(inode)
+    }
+    /// Read inode `id` from disk, checked
+    fn read_inode(&self, id: INodeId) -> vfs::Result<DiskINode> {
+        if !self.may_be_inode(id) {
+            warn!("sfs: entry points to block {:#x}, not an inode", id);
+            return Err(FsError::Corrupted);
+        }
+        let mut disk_inode = self.device.load_inode(id)?;
+        self.fixup_disk_inode(&mut disk_inode);
+        self.check_disk_inode(id, &disk_inode)?;
+        Ok(disk_inode)
+    }
+    /// Whether block `id` may be an inode in use
+    fn may_be_inode(&self, id: INodeId) -> bool {
+        (id == self.root_id || self.data_blocks.contains(&id)) && !self.free_map.read()[id]
+    }
+    /// Check inode `id` just loaded is consistent with the geometry of fs,
+    /// so that walking its blocks is bounded and stays in the fs
+    fn check_disk_inode(&self, id: INodeId, disk_inode: &DiskINode) -> vfs::Result<()> {
+        let blocks = disk_inode.blocks as usize;
+        let in_fs = |block: u32| self.data_blocks.contains(&(block as BlockId));
+        let valid = match disk_inode.type_ {
+            FileType::Invalid => false,
+            // a removed inode must not be freed by dropping it
+            _ if disk_inode.nlinks == 0 => false,
+            type_ if disk_inode.is_inline() => {
+                matches!(type_, FileType::File | FileType::SymLink)
+                    && !disk_inode.is_compressed()
+                    && blocks == 0
+                    && disk_inode.size as usize <= MAX_INLINE_SIZE
+            }
+            type_ => {
+                blocks <= MAX_NBLOCK_DOUBLE_INDIRECT.min(self.data_blocks.len())
+                    && match disk_inode.is_compressed() {
+                        true => type_ == FileType::File && blocks > 0,
+                        false => disk_inode.size as usize <= blocks * BLKSIZE,
+                    }
+                    && disk_inode.direct[..blocks.min(NDIRECT)]
+                        .iter()
+                        .all(|&block| in_fs(block))
+                    && (blocks < MAX_NBLOCK_DIRECT || in_fs(disk_inode.indirect))
+                    && (blocks < MAX_NBLOCK_INDIRECT || in_fs(disk_inode.db_indirect))
+                    && (type_ != FileType::Dir
+                        || disk_inode.size as usize % DIRENT_SIZE == 0
+                            && (disk_inode.index == 0 || in_fs(disk_inode.index)))
+            }
+        };
+        if !valid {
+            warn!("sfs: inode {} is corrupted: {:?}", id, disk_inode);
+            return Err(FsError::Corrupted);
+        }
+        Ok(())
+    }
+    /// Whether the entry named `stored` is `name` by the name policy
+    pub(crate) fn is_name(&self, stored: &Str256, name: &str) -> bool {
+        if *stored == *name {
+            return true;
+        }
+        // names not UTF-8 are only found by their bytes
+        self.names.id != NAME_POLICY_EXACT
+            && core::str::from_utf8(stored.as_bytes()).is_ok_and(|s| self.names.ops.eq(s, name))
+    }
+    /// Whether `a` and `b` are the same name by the name policy
+    pub(crate) fn same_name(&self, a: &str, b: &str) -> bool {
+        a == b || self.names.ops.eq(a, b)
+    }
+    /// Hash of `name` in the hashed index of a dir
+    pub(crate) fn name_hash(&self, name: &str) -> u32 {
+        self.names.ops.hash(name) as u32
+    }
+    /// `name_hash()` of the entry named `stored`
+    pub(crate) fn entry_hash(&self, stored: &Str256) -> u32 {
+        self.bytes_hash(stored.as_bytes())
+    }
+    /// `name_hash()` of the name of `bytes`
+    pub(crate) fn bytes_hash(&self, name: &[u8]) -> u32 {
+        match core::str::from_utf8(name) {
+            Ok(name) => self.name_hash(name),
+            // only found by its bytes
+            Err(_) => name_hash(name),
+        }
+    }
+    /// Check `block`, read from an indirect block, is in the fs
+    fn check_block_id(&self, block: u32) -> vfs::Result<BlockId> {
+        let block = block as BlockId;
+        if !self.data_blocks.contains(&block) {
+            warn!("sfs: block id {:#x} out of the fs", block);
+            return Err(FsError::Corrupted);
+        }
+        Ok(block)
+    }
+    /// Reset the fields not valid in the version of this image
+    fn fixup_disk_inode(&self, disk_inode: &mut DiskINode) {
+        let version = self.super_block.read().version;
+        if version < VERSION_FLAGS {
+            // the field is not initialized in old images
+            disk_inode.flags = 0;
+        }
+        if version < VERSION_INDEX {
+            disk_inode.index = 0;
+        }
+        if version < VERSION_INLINE {
+            disk_inode.flags &= !INODE_INLINE;
+        }
+        if version < VERSION_COMPRESSED {
+            disk_inode.flags &= !INODE_COMPRESSED;
+        }
+        if version < VERSION_OWNER {
+            disk_inode.mode = DEFAULT_MODE;
+            disk_inode.uid = 0;
+            disk_inode.gid = 0;
+        }
+        if version < VERSION_DATA_VERSION {
+            // counted from 0 in each mount
+            disk_inode.data_version = 0;
+        }
+        if version < VERSION_BTIME {
+            disk_inode.btime = Timespec { sec: 0, nsec: 0 };
+        }
+    }
+    /// Whether inode `id` is in memory
+    fn is_resident(&self, id: INodeId) -> bool {
+        let inodes = self.inodes.read();
+        inodes
+            .get(&id)
+            .is_some_and(|inode| inode.upgrade().is_some())
+    }
+    /// Load the inodes of `ids` not in memory, reading each run of adjacent
+    /// blocks at once. Those failing to load, or not read as the device was
+    /// busy, see `set_max_inflight_io()`, are left to `get_inode()`.
+    fn load_inodes(&self, mut ids: Vec<INodeId>) -> Vec<Arc<INodeImpl>> {
+        ids.retain(|&id| self.may_be_inode(id));
+        ids.sort_unstable();
+        ids.dedup();
+        {
+            // those in memory, or being loaded, dropped or reclaimed, are not
+            // read, see `reserve_inode()`
+            let mut inodes = self.inodes.write();
+            ids.retain(|&id| match inodes.contains_key(&id) {
+                true => false,
+                false => {
+                    inodes.insert(id, INodeSlot::Loading);
+                    true
+                }
+            });
+        }
+        let mut loaded = Vec::with_capacity(ids.len());
+        let mut rest = &ids[..];
+        while let Some(&first) = rest.first() {
+            let run = 1 + rest
+                .windows(2)
+                .take_while(|pair| pair[1] == pair[0] + 1)
+                .count();
+            rest = &rest[run..];
+            let mut buf = vec![0u8; run * BLKSIZE];
+            match self.tracer.try_read_at_prio(first * BLKSIZE, &mut buf) {
+                Some(Ok(len)) if len == buf.len() => {}
+                _ => {
+                    (first..first + run).for_each(|id| drop(self.finish_loading(id, None)));
+                    continue;
+                }
+            }
+            for (id, block) in (first..).zip(buf.chunks_exact(BLKSIZE)) {
+                let mut bytes = [0u8; size_of::<DiskINode>()];
+                bytes.copy_from_slice(&block[..size_of::<DiskINode>()]);
+                let disk_inode = DiskINode::from_bytes(&bytes).and_then(|mut disk_inode| {
+                    self.fixup_disk_inode(&mut disk_inode);
+                    self.check_disk_inode(id, &disk_inode).ok()?;
+                    Some(disk_inode)
+                });
+                loaded.extend(self.finish_loading(id, disk_inode));
             }
         }
-        // Load if not in set, or is weak ref.
-        let disk_inode = Dirty::new(self.device.load_struct::<DiskINode>(id).unwrap());
-        self._new_inode(id, disk_inode)
+        loaded
     }
-    /// Create a new INode file
-    fn new_inode_file(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_file());
-        Ok(self._new_inode(id, disk_inode))
+    /// Put inode to the head of the strong cache
+    fn cache_inode(&self, inode: &Arc<INodeImpl>) {
+        let evicted = {
+            let mut cache = self.inode_cache.write();
+            if cache.capacity == 0 {
+                return;
+            }
+            cache.touch(inode);
+            cache.shrink()
+        };
+        Self::evict_inodes(evicted);
     }
-    /// Create a new INode symlink
-    fn new_inode_symlink(&self) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_symlink());
-        Ok(self._new_inode(id, disk_inode))
+    /// Remove inode from the strong cache
+    fn uncache_inode(&self, id: INodeId) {
+        let evicted = self.inode_cache.write().remove(id);
+        Self::evict_inodes(evicted);
+    }
+    /// Write back evicted inodes. Called without holding the cache lock.
+    fn evict_inodes(evicted: impl IntoIterator<Item = Arc<INodeImpl>>) {
+        for inode in evicted {
+            // tried again when dropped
+            if let Err(err) = inode.write_inode() {
+                warn!(
+                    "sfs: cannot write back evicted inode {}: {:?}",
+                    inode.id, err
+                );
+            }
+        }
+    }
+    /// Create a new INode of `type_` to link in dir `parent`, near it, see
+    /// `set_alloc_groups()`. `data` is the device number packed by
+    /// `make_rdev()` of devices.
+    fn new_inode_in(
+        &self,
+        parent: INodeId,
+        type_: vfs::FileType,
+        data: usize,
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let goal = Some(parent);
+        match type_ {
+            vfs::FileType::File => self.new_inode_inline(DiskINode::new_file(), goal),
+            vfs::FileType::SymLink => self.new_inode_inline(DiskINode::new_symlink(), goal),
+            vfs::FileType::Dir => self.new_inode_dir(parent),
+            vfs::FileType::CharDevice => self.new_inode_device(FileType::CharDevice, data, goal),
+            vfs::FileType::BlockDevice => self.new_inode_device(FileType::BlockDevice, data, goal),
+            _ => Err(FsError::InvalidParam),
+        }
+    }
+    /// Create a new INode of `disk_inode`, its content stored inline while
+    /// small if the image allows
+    fn new_inode_inline(
+        &self,
+        mut disk_inode: DiskINode,
+        goal: Option<BlockId>,
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_block(goal).ok_or(FsError::NoDeviceSpace)?;
+        if self.super_block.read().version >= VERSION_INLINE {
+            disk_inode.flags |= INODE_INLINE;
+        }
+        Ok(self._new_inode(id, self.new_disk_inode(disk_inode)))
     }
     /// Create a new INode dir
     fn new_inode_dir(&self, parent: INodeId) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_dir());
+        let id = self
+            .alloc_block(Some(parent))
+            .ok_or(FsError::NoDeviceSpace)?;
+        let disk_inode = self.new_disk_inode(DiskINode::new_dir());
         let inode = self._new_inode(id, disk_inode);
         inode.init_direntry(parent)?;
         Ok(inode)
     }
-    /// Create a new INode chardevice
-    pub fn new_inode_chardevice(&self, device_inode_id: usize) -> vfs::Result<Arc<INodeImpl>> {
-        let id = self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let disk_inode = Dirty::new_dirty(DiskINode::new_chardevice(device_inode_id));
+    /// Create a new INode chardevice with packed device number `rdev`
+    pub fn new_inode_chardevice(&self, rdev: usize) -> vfs::Result<Arc<INodeImpl>> {
+        self.new_inode_device(FileType::CharDevice, rdev, None)
+    }
+    /// Create a new INode blockdevice with packed device number `rdev`
+    pub fn new_inode_blockdevice(&self, rdev: usize) -> vfs::Result<Arc<INodeImpl>> {
+        self.new_inode_device(FileType::BlockDevice, rdev, None)
+    }
+    fn new_inode_device(
+        &self,
+        type_: FileType,
+        rdev: usize,
+        goal: Option<BlockId>,
+    ) -> vfs::Result<Arc<INodeImpl>> {
+        let id = self.alloc_block(goal).ok_or(FsError::NoDeviceSpace)?;
+        let disk_inode = self.new_disk_inode(DiskINode::new_device(type_, rdev));
         let new_inode = self._new_inode(id, disk_inode);
         Ok(new_inode)
     }
-    fn flush_weak_inodes(&self) {
-        let mut inodes = self.inodes.write();
-        let remove_ids: Vec<_> = inodes
+    /// `disk_inode` of a new inode, with its times, creation time included,
+    /// read from `SfsOptions::time_provider` if any
+    fn new_disk_inode(&self, mut disk_inode: DiskINode) -> Dirty<DiskINode> {
+        if let Some(time) = &self.options.time_provider {
+            let now = time.current_time();
+            disk_inode.atime = now;
+            disk_inode.mtime = now;
+            disk_inode.ctime = now;
+            if self.super_block.read().version >= VERSION_BTIME {
+                disk_inode.btime = now;
+            }
+        }
+        Dirty::new_dirty(disk_inode)
+    }
+    /// Bring the free block count of the superblock up to date, making it
+    /// dirty only if the count differs from the one last written
+    fn reconcile_unused_blocks(&self, super_block: &mut Dirty<SuperBlock>) {
+        let unused = self.unused_blocks.load(Ordering::Relaxed);
+        if super_block.unused_blocks != unused {
+            super_block.unused_blocks = unused;
+        }
+    }
+    /// Write back the superblock, and the backups if stale, unless held by
+    /// `pack_subtree()`. Return the blocks written.
+    fn write_super_block(&self, super_block: &mut Dirty<SuperBlock>) -> vfs::Result<usize> {
+        if self.hold_super_block.load(Ordering::Relaxed) {
+            return Ok(0);
+        }
+        let generation = super_block.generation();
+        let mut written = 1;
+        fs_try!(
+            self.device
+                .write_block(BLKN_SUPER, 0, &super_block.to_disk()),
+            vfs::ErrorContext::new("sync")
+        );
+        // backups may lag behind in free block count,
+        // only rewrite them when other fields change
+        if self.backups_stale.load(Ordering::Relaxed) {
+            for &id in super_block.backup_blocks.iter().filter(|&&id| id != 0) {
+                fs_try!(
+                    self.device
+                        .write_block(id as BlockId, 0, &super_block.to_disk()),
+                    vfs::ErrorContext::new("sync")
+                );
+                written += 1;
+            }
+            self.backups_stale.store(false, Ordering::Relaxed);
+        }
+        super_block.sync_at(generation);
+        Ok(written)
+    }
+    /// Blocks `write_super_block()` would write, 0 if it is clean
+    fn super_block_cost(&self, super_block: &SuperBlock) -> usize {
+        match self.backups_stale.load(Ordering::Relaxed) {
+            true => {
+                1 + super_block
+                    .backup_blocks
+                    .iter()
+                    .filter(|&&id| id != 0)
+                    .count()
+            }
+            false => 1,
+        }
+    }
+    /// Write back the superblock, the freemap, then every inode, with both
+    /// locked by the caller. The inodes are put in `inodes`, to be dropped
+    /// once unlocked.
+    fn write_back(
+        &self,
+        free_map: &mut Dirty<BitVec<Lsb0, u8>>,
+        super_block: &mut Dirty<SuperBlock>,
+        inodes: &mut Vec<Arc<INodeImpl>>,
+    ) -> vfs::Result<()> {
+        self.reconcile_unused_blocks(super_block);
+        if super_block.dirty() {
+            self.write_super_block(super_block)?;
+        }
+        failpoint!(self, "sync_after_super_block");
+        if free_map.dirty() {
+            let generation = free_map.generation();
+            for i in 0..super_block.freemap_blocks as usize {
+                self.write_free_map_block(free_map, i)?;
+            }
+            // a change since the blocks were written is left to the next sync
+            if free_map.sync_at(generation) {
+                self.free_map_changed.write().clear();
+            }
+        }
+        failpoint!(self, "sync_after_freemap");
+        inodes.extend(self.inodes.read().values().filter_map(INodeSlot::upgrade));
+        for inode in inodes.iter() {
+            fs_try!(inode.write_inode(), vfs::ErrorContext::new("sync"));
+        }
+        Ok(())
+    }
+    /// Write back the blocks of the freemap changed since written, so that
+    /// the blocks of an inode written next are allocated on disk
+    fn write_free_map_changed(&self) -> vfs::Result<()> {
+        let mut free_map = self.free_map.write();
+        let mut changed = self.free_map_changed.write();
+        while let Some(&i) = changed.iter().next() {
+            self.write_free_map_block(&free_map, i)?;
+            changed.remove(&i);
+        }
+        free_map.sync();
+        Ok(())
+    }
+    /// Write back block `i` of the freemap
+    fn write_free_map_block(&self, free_map: &BitVec<Lsb0, u8>, i: usize) -> vfs::Result<()> {
+        let data = free_map.as_buf();
+        fs_try!(
+            self.device
+                .write_block(BLKN_FREEMAP + i, 0, &data[i * BLKSIZE..(i + 1) * BLKSIZE]),
+            vfs::ErrorContext::new("sync")
+        );
+        Ok(())
+    }
+}
+
+/// Runtime statistics of SFS
+#[derive(Debug, Default, Clone)]
+pub struct SfsStats {
+    /// number of entries in the inode table, including those being dropped
+    pub inode_table_size: usize,
+    /// number of inodes held by the strong inode cache
+    pub inode_cache_size: usize,
+    /// scratch buffers taken from the pool
+    pub scratch_hits: u64,
+    /// scratch buffers taken from the global allocator, as the pool was empty
+    pub scratch_misses: u64,
+    /// device operations under way now, by their blocks, see
+    /// `SimpleFileSystem::set_max_inflight_io()`
+    pub io_in_flight: usize,
+    /// most of `io_in_flight` ever
+    pub io_peak: usize,
+    /// device operations which waited for their turn
+    pub io_waits: u64,
+    /// prefetches skipped as the device was busy
+    pub io_shed: u64,
+}
+
+#[cfg(any(test, feature = "std"))]
+impl rcore_fs::metrics::StatSource for SimpleFileSystem {
+    fn samples(&self) -> Vec<rcore_fs::metrics::Sample> {
+        use rcore_fs::metrics::Sample;
+        let stats = self.stats();
+        let info = vfs::FileSystem::info(self);
+        vec![
+            Sample::gauge("blocks", "Blocks of the fs", info.blocks as u64),
+            Sample::gauge("free_blocks", "Free blocks of the fs", info.bfree as u64),
+            Sample::gauge(
+                "sfs_inode_table_size",
+                "Entries in the inode table, those being dropped included",
+                stats.inode_table_size as u64,
+            ),
+            Sample::gauge(
+                "sfs_inode_cache_size",
+                "Inodes held by the inode cache",
+                stats.inode_cache_size as u64,
+            ),
+            Sample::counter(
+                "sfs_scratch_hits_total",
+                "Scratch buffers taken from the pool",
+                stats.scratch_hits,
+            ),
+            Sample::counter(
+                "sfs_scratch_misses_total",
+                "Scratch buffers allocated as the pool was empty",
+                stats.scratch_misses,
+            ),
+            Sample::gauge(
+                "sfs_io_in_flight",
+                "Blocks of the device operations under way",
+                stats.io_in_flight as u64,
+            ),
+            Sample::gauge(
+                "sfs_io_peak",
+                "Most blocks of device operations ever under way at once",
+                stats.io_peak as u64,
+            ),
+            Sample::counter(
+                "sfs_io_waits_total",
+                "Device operations which waited for their turn",
+                stats.io_waits,
+            ),
+            Sample::counter(
+                "sfs_io_shed_total",
+                "Prefetches skipped as the device was busy",
+                stats.io_shed,
+            ),
+        ]
+    }
+}
+
+/// ranks of the locks of SFS, see "Lock order" of `SimpleFileSystem`
+const RANK_DIR: u8 = 1;
+const RANK_FREE_MAP: u8 = 2;
+const RANK_SUPER_BLOCK: u8 = 3;
+const RANK_INODES: u8 = 4;
+const RANK_DISK_INODE: u8 = 5;
+
+/// inodes checked by `SimpleFileSystem::quick_scan()`
+const QUICK_SCAN_INODES: usize = 64;
+
+/// default of `SimpleFileSystem::set_io_burst()`
+const DEFAULT_IO_BURST: usize = 256;
+
+/// default of `SimpleFileSystem::set_max_inflight_io()`
+const DEFAULT_MAX_INFLIGHT_IO: usize = 16;
+
+/// default of `SimpleFileSystem::set_dir_readahead()`
+const DEFAULT_DIR_READAHEAD: usize = 32;
+
+/// default of `CreateOptions::reserve_for_recovery`
+pub const DEFAULT_RECOVERY_BLOCKS: usize = 8;
+
+/// blocks of an allocation group, 8 MiB, see
+/// `SimpleFileSystem::set_alloc_groups()`
+pub const ALLOC_GROUP_BLOCKS: usize = 2048;
+
+/// LRU cache holding strong references to inodes
+#[derive(Default)]
+struct INodeCache {
+    capacity: usize,
+    /// last use of each cached inode
+    inodes: BTreeMap<INodeId, u64>,
+    /// cached inodes by last use, least recently used first
+    lru: BTreeMap<u64, Arc<INodeImpl>>,
+    /// next value of a last use
+    clock: u64,
+}
+
+impl INodeCache {
+    /// Put inode to the head of the cache
+    fn touch(&mut self, inode: &Arc<INodeImpl>) {
+        self.remove(inode.id);
+        self.clock += 1;
+        self.inodes.insert(inode.id, self.clock);
+        self.lru.insert(self.clock, inode.clone());
+    }
+    fn remove(&mut self, id: INodeId) -> Option<Arc<INodeImpl>> {
+        let used = self.inodes.remove(&id)?;
+        self.lru.remove(&used)
+    }
+    /// Pop the least recently used inodes beyond capacity, but no pinned
+    /// one
+    fn shrink(&mut self) -> Vec<Arc<INodeImpl>> {
+        let n = self.inodes.len().saturating_sub(self.capacity);
+        let used: Vec<u64> = self
+            .lru
             .iter()
-            .filter(|(_, inode)| inode.upgrade().is_none())
-            .map(|(&id, _)| id)
+            .filter(|(_, inode)| !inode.is_pinned())
+            .map(|(&used, _)| used)
+            .take(n)
             .collect();
-        for id in remove_ids.iter() {
-            inodes.remove(&id);
+        let mut evicted = Vec::with_capacity(used.len());
+        for used in used {
+            if let Some(inode) = self.lru.remove(&used) {
+                self.inodes.remove(&inode.id);
+                evicted.push(inode);
+            }
         }
+        evicted
     }
 }
 
 impl vfs::FileSystem for SimpleFileSystem {
+    /// See `unmount()`
+    fn prepare_unmount(&self) -> vfs::Result<()> {
+        self.unmount()
+    }
+
     /// Write back super block if dirty
     fn sync(&self) -> vfs::Result<()> {
+        fs_span!("sync", fs = self.instance_id);
+        // declared first to be dropped last, without locks held, as the last
+        // reference to an inode may free its blocks on drop
+        let mut inodes = Vec::new();
+        // order is important, see issue #18 and the lock order of
+        // `SimpleFileSystem`, tested by `free_map_locked_before_super_block`
+        let mut free_map = self.free_map.write();
+        let mut super_block = self.super_block.write();
+        self.write_back(&mut free_map, &mut super_block, &mut inodes)?;
+        drop(super_block);
+        drop(free_map);
+        self.device.sync()?;
+        Ok(())
+    }
+
+    /// Write back dirty inodes, then the changed blocks of the freemap, then
+    /// the superblock, each only once all before it are written. So an
+    /// inode may be on disk before the blocks it uses are marked in the
+    /// freemap, but never the other way around.
+    ///
+    /// The superblock with its backups, or an inode with its repaired "."
+    /// and "..", is written whole, even over `max_blocks` if it is the first
+    /// to write in this call, so that every call with a budget makes
+    /// progress.
+    fn sync_partial(&self, max_blocks: usize) -> vfs::Result<vfs::SyncProgress> {
+        let mut written = 0;
+        let mut remaining = 0;
+        let inodes: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(|inode| inode.upgrade())
+            .collect();
+        for inode in inodes.iter() {
+            let cost = inode.dirty_blocks();
+            if cost == 0 {
+                continue;
+            }
+            if written + cost <= max_blocks || (written == 0 && max_blocks > 0) {
+                fs_try!(inode.write_inode(), vfs::ErrorContext::new("sync_partial"));
+                written += cost;
+            } else {
+                remaining += cost;
+            }
+        }
+        // the last reference to an inode may free its blocks on drop
+        drop(inodes);
+        failpoint!(self, "sync_partial_after_inodes");
+
         // order is important, see issue #18
         let mut free_map = self.free_map.write();
         let mut super_block = self.super_block.write();
-        if super_block.dirty() {
-            self.device
-                .write_at(BLKSIZE * BLKN_SUPER, super_block.as_buf())?;
-            super_block.sync();
+        let mut changed = self.free_map_changed.write();
+        self.reconcile_unused_blocks(&mut super_block);
+        if remaining == 0 {
+            while written < max_blocks {
+                let i = match changed.iter().next() {
+                    Some(&i) => i,
+                    None => break,
+                };
+                self.write_free_map_block(&free_map, i)?;
+                changed.remove(&i);
+                written += 1;
+            }
+            if changed.is_empty() {
+                free_map.sync();
+            }
         }
-        if free_map.dirty() {
-            let data = free_map.as_buf();
-            for i in 0..super_block.freemap_blocks as usize {
-                self.device.write_at(
-                    BLKSIZE * (BLKN_FREEMAP + i),
-                    &data[i * BLKSIZE..(i + 1) * BLKSIZE],
-                )?;
+        remaining += changed.len();
+        if super_block.dirty() {
+            let cost = self.super_block_cost(&super_block);
+            let fits = written + cost <= max_blocks || (written == 0 && max_blocks > 0);
+            if remaining == 0 && fits {
+                written += self.write_super_block(&mut super_block)?;
+            } else {
+                remaining += cost;
             }
-            free_map.sync();
         }
-        self.flush_weak_inodes();
-        for inode in self.inodes.read().values() {
-            if let Some(inode) = inode.upgrade() {
-                inode.sync_all()?;
+        drop(changed);
+        drop(super_block);
+        drop(free_map);
+
+        if remaining == 0 {
+            self.device.sync()?;
+        }
+        Ok(vfs::SyncProgress {
+            written_blocks: written,
+            remaining_dirty_blocks: remaining,
+            completed: remaining == 0,
+        })
+    }
+
+    /// The dirty inodes are found among the entries of the dirs down from
+    /// `inode_id`, loading only the dirs
+    fn dirty_inodes_under(&self, inode_id: usize) -> vfs::Result<Vec<Arc<dyn vfs::INode>>> {
+        let inodes: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(|inode| inode.upgrade())
+            .collect();
+        let mut dirty: BTreeMap<INodeId, Arc<INodeImpl>> = inodes
+            .into_iter()
+            .filter(|inode| inode.dirty_blocks() > 0)
+            .map(|inode| (inode.id, inode))
+            .collect();
+        let mut found: Vec<Arc<dyn vfs::INode>> = Vec::new();
+        let mut dirs = vec![(inode_id, None)];
+        while let Some((id, parent)) = dirs.pop() {
+            if dirty.is_empty() {
+                break;
             }
+            if let Some(inode) = dirty.remove(&id) {
+                found.push(inode);
+            }
+            let dir = match self.get_inode(id) {
+                Ok(dir) => dir,
+                // unlinked since, and maybe reclaimed
+                Err(_) if Self::entry_changed(&parent) => continue,
+                Err(err) => return Err(err),
+            };
+            if dir.disk_inode.read().type_ != FileType::Dir {
+                continue;
+            }
+            let cookie = dir.change_cookie();
+            dir.scan_direntry(|i, entry| {
+                let child = entry.id as INodeId;
+                match entry.type_hint() {
+                    // "." and ".."
+                    _ if i < 2 => {}
+                    Some(FileType::Dir) | None => dirs.push((child, Some((dir.clone(), cookie)))),
+                    Some(_) => found.extend(dirty.remove(&child).map(|inode| inode as _)),
+                }
+                None::<()>
+            })?;
         }
-        self.device.sync()?;
-        Ok(())
+        Ok(found)
     }
 
+    /// Checked by `open()`, but loaded again once dropped, which may fail:
+    /// then an INode failing every operation is returned
     fn root_inode(&self) -> Arc<dyn vfs::INode> {
-        self.get_inode(BLKN_ROOT)
-        // let root = self.get_inode(BLKN_ROOT);
-        // root.create("dev", vfs::FileType::Dir, 0).expect("fail to create dev"); // what's mode?
-        // return root;
+        match self.get_inode(self.root_id) {
+            Ok(root) => root,
+            Err(err) => {
+                error!("sfs: cannot load the root inode: {:?}", err);
+                Arc::new(FailedRoot {
+                    fs: self.this(),
+                    corrupted: *err.root_cause() == FsError::Corrupted,
+                })
+            }
+        }
     }
 
     fn info(&self) -> vfs::FsInfo {
-        let sb = self.super_block.read();
+        let blocks = self.super_block.read().blocks as usize;
+        let unused = self.unused_blocks.load(Ordering::Relaxed) as usize;
         vfs::FsInfo {
             bsize: BLKSIZE,
             frsize: BLKSIZE,
-            blocks: sb.blocks as usize,
-            bfree: sb.unused_blocks as usize,
-            bavail: sb.unused_blocks as usize,
-            files: sb.blocks as usize,        // inaccurate
-            ffree: sb.unused_blocks as usize, // inaccurate
+            blocks,
+            bfree: unused,
+            // the recovery reserve is not available to writes
+            bavail: unused.saturating_sub(self.recovery_blocks as usize),
+            files: blocks, // inaccurate
+            ffree: unused, // inaccurate
             namemax: MAX_FNAME_LEN,
+            linkmax: LINK_MAX,
+        }
+    }
+
+    fn instance_id(&self) -> u64 {
+        self.instance_id
+    }
+
+    fn volume_info(&self) -> Option<vfs::VolumeInfo> {
+        Some(vfs::VolumeInfo {
+            uuid: self.uuid(),
+            label: self.label(),
+        })
+    }
+
+    fn capabilities(&self) -> vfs::FsCapabilities {
+        use vfs::FsCapabilities as Caps;
+        let caps =
+            Caps::HARDLINK | Caps::SYMLINK | Caps::DEVICE_NODES | Caps::RENAME | Caps::PREALLOC;
+        let version = self.super_block.read().version;
+        let caps = match version >= VERSION_FLAGS {
+            true => caps | Caps::INODE_FLAGS,
+            false => caps,
+        };
+        let caps = match version >= VERSION_BTIME {
+            true => caps | Caps::BIRTH_TIME,
+            false => caps,
+        };
+        match self.names.id == NAME_POLICY_ASCII_CASE_FOLD {
+            true => caps | Caps::CASE_INSENSITIVE,
+            false => caps,
         }
     }
+
+    fn name_ops(&self) -> Option<Arc<dyn NameOps>> {
+        Some(self.names.ops.clone())
+    }
 }
 
 impl Drop for SimpleFileSystem {
     /// Auto sync when drop
     fn drop(&mut self) {
-        self.sync()
-            .expect("Failed to sync when dropping the SimpleFileSystem");
+        let result = self.sync();
+        if self.hold_super_block.load(Ordering::Relaxed) {
+            // a failed pack, there is no image to keep
+            self.super_block.write().sync();
+            self.free_map.write().sync();
+            return;
+        }
+        if let Err(err) = result {
+            error!("sfs: changes are lost, cannot sync on drop: {:?}", err);
+            self.super_block.write().sync();
+            self.free_map.write().sync();
+        }
+    }
+}
+
+/// Hidden name of inode `id` unlinked while open, see `set_silly_rename()`
+fn silly_name(id: INodeId) -> String {
+    format!(".sfs-unlinked-{}", id)
+}
+
+/// Check the sizes in `super_block` are consistent, and return the range
+/// of blocks after the freemap and the reserved blocks, and the root inode
+fn check_geometry(super_block: &SuperBlock) -> vfs::Result<(Range<BlockId>, INodeId)> {
+    let blocks = super_block.blocks as usize;
+    let freemap_blocks = super_block.freemap_blocks as usize;
+    // `create()` may round the freemap up to one more block
+    let freemap_ok =
+        freemap_blocks * BLKBITS >= blocks && freemap_blocks <= blocks.div_ceil(BLKBITS) + 1;
+    let (root_id, reserved) = match super_block.version >= VERSION_LAYOUT {
+        true => (
+            super_block.root_block as usize,
+            super_block.reserved_blocks as usize,
+        ),
+        false => (BLKN_ROOT, 0),
+    };
+    let data_begin = (BLKN_FREEMAP + freemap_blocks).saturating_add(reserved);
+    if !freemap_ok
+        || data_begin >= blocks
+        || super_block.unused_blocks > super_block.blocks
+        || super_block.recovery_blocks > super_block.blocks
+    {
+        error!(
+            "sfs: superblock is corrupted: {} blocks, {} freemap blocks, {} reserved, {} unused",
+            blocks, freemap_blocks, reserved, super_block.unused_blocks
+        );
+        return Err(FsError::Corrupted);
+    }
+    if root_id != BLKN_ROOT && !(data_begin..blocks).contains(&root_id) {
+        error!(
+            "sfs: superblock is corrupted: root inode at block {}",
+            root_id
+        );
+        return Err(FsError::Corrupted);
+    }
+    Ok((data_begin..blocks, root_id))
+}
+
+/// Seed of the UUID of the `count`th fs created by this process at `now`,
+/// for images made by different processes or boots to differ
+fn uuid_seed(now: Timespec, count: u64) -> u64 {
+    let nanos = (now.sec as u64)
+        .wrapping_mul(1_000_000_000)
+        .wrapping_add(now.nsec as u64);
+    nanos ^ count.rotate_right(16)
+}
+
+/// Seed of the UUID of the `count`th fs created by this process if there
+/// is no clock, from where `device` is in memory and `space`: it differs
+/// between the fs of a process, but may repeat after a reboot
+fn uuid_seed_without_clock(device: &Arc<dyn Device>, space: usize, count: u64) -> u64 {
+    let addr = Arc::as_ptr(device) as *const u8 as usize as u64;
+    addr ^ (space as u64).rotate_left(48) ^ count.rotate_right(16)
+}
+
+/// Current time, to make a UUID from, if there is a clock
+#[cfg(any(test, feature = "std"))]
+fn system_time() -> Option<Timespec> {
+    use rcore_fs::dev::{std_impl::StdTimeProvider, TimeProvider};
+    Some(StdTimeProvider.current_time())
+}
+
+#[cfg(not(any(test, feature = "std")))]
+fn system_time() -> Option<Timespec> {
+    None
+}
+
+/// Make a version 4 UUID from `seed`
+fn uuid_from_seed(seed: u64) -> [u8; 16] {
+    // splitmix64
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    let mut uuid = [0u8; 16];
+    uuid[..8].copy_from_slice(&next().to_le_bytes());
+    uuid[8..].copy_from_slice(&next().to_le_bytes());
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    uuid
+}
+
+/// Display UUID in the canonical 8-4-4-4-12 form
+struct Uuid<'a>(&'a [u8; 16]);
+
+impl Display for Uuid<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+        for (i, b) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
     }
 }
 
 trait BitsetAlloc {
     fn alloc(&mut self) -> Option<usize>;
+    /// Allocate in the allocation group of `goal`, from `goal` on then from
+    /// the start of the group, else in the emptiest other group
+    fn alloc_near(&mut self, goal: usize) -> Option<usize>;
+    /// Allocate the first free bit in `range`
+    fn alloc_in(&mut self, range: Range<usize>) -> Option<usize>;
 }
 
 impl BitsetAlloc for BitVec<Lsb0, u8> {
     fn alloc(&mut self) -> Option<usize> {
+        self.alloc_in(0..self.len())
+    }
+    fn alloc_near(&mut self, goal: usize) -> Option<usize> {
+        let goal = goal.min(self.len() - 1);
+        let group = goal / ALLOC_GROUP_BLOCKS;
+        let len = self.len();
+        let range = |group: usize| {
+            let start = group * ALLOC_GROUP_BLOCKS;
+            start..len.min(start + ALLOC_GROUP_BLOCKS)
+        };
+        let Range { start, end } = range(group);
+        if let Some(id) = self
+            .alloc_in(goal..end)
+            .or_else(|| self.alloc_in(start..goal))
+        {
+            return Some(id);
+        }
+        // the free counts of the groups are only needed when one is full
+        let groups = len.div_ceil(ALLOC_GROUP_BLOCKS);
+        let emptiest = (0..groups)
+            .filter(|&other| other != group)
+            .map(|other| (self[range(other)].count_ones(), core::cmp::Reverse(other)))
+            .max()
+            .filter(|&(free, _)| free > 0)?;
+        self.alloc_in(range(emptiest.1 .0))
+    }
+    fn alloc_in(&mut self, range: Range<usize>) -> Option<usize> {
         // TODO: more efficient
-        let id = (0..self.len()).find(|&i| self[i]);
+        let id = range.into_iter().find(|&i| self[i]);
         if let Some(id) = id {
             self.set(id, false);
         }
@@ -1026,15 +5085,59 @@ impl AsBuf for BitVec<Lsb0, u8> {
 
 impl AsBuf for [u8; BLKSIZE] {}
 
+/// Root returned by `root_inode()` when it fails to load
+struct FailedRoot {
+    fs: Arc<SimpleFileSystem>,
+    /// `Corrupted` rather than `DeviceError`
+    corrupted: bool,
+}
+
+impl FailedRoot {
+    fn err<T>(&self) -> vfs::Result<T> {
+        match self.corrupted {
+            true => Err(FsError::Corrupted),
+            false => Err(FsError::DeviceError),
+        }
+    }
+}
+
+impl vfs::INode for FailedRoot {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> vfs::Result<usize> {
+        self.err()
+    }
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> vfs::Result<usize> {
+        self.err()
+    }
+    fn poll(&self) -> vfs::Result<vfs::PollStatus> {
+        self.err()
+    }
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        self.err()
+    }
+    fn find(&self, _name: &str) -> vfs::Result<Arc<dyn INode>> {
+        self.err()
+    }
+    fn get_entry(&self, _id: usize) -> vfs::Result<String> {
+        self.err()
+    }
+    fn fs(&self) -> vfs::Result<Arc<dyn FileSystem>> {
+        Ok(self.fs.clone())
+    }
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// `Invalid` is never loaded, see `check_disk_inode()`, and is taken as a
+/// file otherwise
 impl From<FileType> for vfs::FileType {
     fn from(t: FileType) -> Self {
         match t {
-            FileType::File => vfs::FileType::File,
+            FileType::File | FileType::Invalid => vfs::FileType::File,
             FileType::SymLink => vfs::FileType::SymLink,
             FileType::Dir => vfs::FileType::Dir,
             FileType::CharDevice => vfs::FileType::CharDevice,
             FileType::BlockDevice => vfs::FileType::BlockDevice,
-            _ => panic!("unknown file type"),
         }
     }
 }
@@ -0,0 +1,308 @@
+//! All the settings of an SFS in one place, taken by
+//! `SimpleFileSystem::open_with()` and `create_with()`, and reported by
+//! `SimpleFileSystem::options()`
+
+use super::*;
+use rcore_fs::dev::TimeProvider;
+
+/// Settings of an SFS, made with the builder methods and checked by
+/// `build()`:
+///
+/// ```ignore
+/// let opts = SfsOptions::new().read_only(true).case_insensitive(false).build()?;
+/// let sfs = SimpleFileSystem::open_with(device, &opts)?;
+/// ```
+///
+/// The default ones are those of `open()` and `create()`. Some settings are
+/// recorded in the image when it is created, see `from_superblock()`:
+/// opening an image which has another one than asked fails, the others are
+/// only used by `create_with()`.
+#[derive(Clone)]
+pub struct SfsOptions {
+    /// log2 of the block size, only `BLKSIZE_LOG2` is supported
+    pub block_size_log2: u8,
+    /// Reject all modifications even if the device takes writes, so that
+    /// the image is left untouched. Not for `create_with()`.
+    pub read_only: bool,
+    /// Salvage what can be read of a damaged image, only read-only, see
+    /// `SimpleFileSystem::open_rescue()`
+    pub rescue: bool,
+    /// Whether names ignore the case of ASCII letters, recorded in the
+    /// image. `None` takes what the image has on open, and exact names on
+    /// create. An image of a custom name policy is neither.
+    pub case_insensitive: Option<bool>,
+    /// Name policy of the image on create. On open, a custom one the image
+    /// may be made with, see `OpenOptions::names`.
+    pub names: Option<NamePolicy>,
+    /// Keep what changes between runs out of the image: the UUID is made
+    /// from seed 0 unless `uuid` is set, and inodes have zero times
+    pub deterministic: bool,
+    /// Clock the times of new inodes are read from, zero times if `None`
+    pub time_provider: Option<Arc<dyn TimeProvider>>,
+    /// What to do on open if the image was not cleanly unmounted
+    pub on_dirty: DirtyPolicy,
+    /// UUID of the volume on create, derived from the time, or the device
+    /// without a clock, and a per-process counter if `None`, see
+    /// `SimpleFileSystem::create()`
+    pub uuid: Option<[u8; 16]>,
+    /// See `CreateOptions::reserved_blocks`
+    pub reserved_blocks: usize,
+    /// See `CreateOptions::root_block`
+    pub root_block: Option<BlockId>,
+    /// See `CreateOptions::reserve_for_recovery`
+    pub reserve_for_recovery: usize,
+    /// See `SimpleFileSystem::set_inode_cache_size()`
+    pub inode_cache_size: usize,
+    /// See `SimpleFileSystem::set_scratch_pool_size()`
+    pub scratch_pool_size: usize,
+    /// See `SimpleFileSystem::set_dir_readahead()`
+    pub dir_readahead: usize,
+    /// See `SimpleFileSystem::set_io_burst()`
+    pub io_burst: usize,
+    /// See `SimpleFileSystem::set_max_inflight_io()`, not 0
+    pub max_inflight_io: usize,
+    /// See `SimpleFileSystem::set_alloc_groups()`
+    pub alloc_groups: bool,
+    /// See `SimpleFileSystem::set_silly_rename()`
+    pub silly_rename: bool,
+}
+
+impl Default for SfsOptions {
+    fn default() -> Self {
+        SfsOptions {
+            block_size_log2: BLKSIZE_LOG2,
+            read_only: false,
+            rescue: false,
+            case_insensitive: None,
+            names: None,
+            deterministic: false,
+            time_provider: None,
+            on_dirty: DirtyPolicy::Proceed,
+            uuid: None,
+            reserved_blocks: 0,
+            root_block: None,
+            reserve_for_recovery: DEFAULT_RECOVERY_BLOCKS,
+            inode_cache_size: 0,
+            scratch_pool_size: DEFAULT_SCRATCH_POOL_SIZE,
+            dir_readahead: DEFAULT_DIR_READAHEAD,
+            io_burst: DEFAULT_IO_BURST,
+            max_inflight_io: DEFAULT_MAX_INFLIGHT_IO,
+            alloc_groups: true,
+            silly_rename: false,
+        }
+    }
+}
+
+impl Debug for SfsOptions {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        f.debug_struct("SfsOptions")
+            .field("block_size_log2", &self.block_size_log2)
+            .field("read_only", &self.read_only)
+            .field("rescue", &self.rescue)
+            .field("case_insensitive", &self.case_insensitive)
+            .field("names", &self.names)
+            .field("deterministic", &self.deterministic)
+            .field("time_provider", &self.time_provider.is_some())
+            .field("on_dirty", &self.on_dirty)
+            .field("uuid", &self.uuid)
+            .field("reserved_blocks", &self.reserved_blocks)
+            .field("root_block", &self.root_block)
+            .field("reserve_for_recovery", &self.reserve_for_recovery)
+            .field("inode_cache_size", &self.inode_cache_size)
+            .field("scratch_pool_size", &self.scratch_pool_size)
+            .field("dir_readahead", &self.dir_readahead)
+            .field("io_burst", &self.io_burst)
+            .field("max_inflight_io", &self.max_inflight_io)
+            .field("alloc_groups", &self.alloc_groups)
+            .field("silly_rename", &self.silly_rename)
+            .finish()
+    }
+}
+
+impl SfsOptions {
+    /// The default options, to be changed by the methods below
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn block_size_log2(mut self, log2: u8) -> Self {
+        self.block_size_log2 = log2;
+        self
+    }
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    pub fn rescue(mut self, rescue: bool) -> Self {
+        self.rescue = rescue;
+        self
+    }
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = Some(case_insensitive);
+        self
+    }
+    pub fn names(mut self, names: NamePolicy) -> Self {
+        self.names = Some(names);
+        self
+    }
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+    pub fn time_provider(mut self, time: Arc<dyn TimeProvider>) -> Self {
+        self.time_provider = Some(time);
+        self
+    }
+    pub fn on_dirty(mut self, policy: DirtyPolicy) -> Self {
+        self.on_dirty = policy;
+        self
+    }
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+    pub fn reserved_blocks(mut self, blocks: usize) -> Self {
+        self.reserved_blocks = blocks;
+        self
+    }
+    pub fn root_block(mut self, id: BlockId) -> Self {
+        self.root_block = Some(id);
+        self
+    }
+    pub fn reserve_for_recovery(mut self, blocks: usize) -> Self {
+        self.reserve_for_recovery = blocks;
+        self
+    }
+    pub fn inode_cache_size(mut self, size: usize) -> Self {
+        self.inode_cache_size = size;
+        self
+    }
+    pub fn scratch_pool_size(mut self, size: usize) -> Self {
+        self.scratch_pool_size = size;
+        self
+    }
+    pub fn dir_readahead(mut self, inodes: usize) -> Self {
+        self.dir_readahead = inodes;
+        self
+    }
+    pub fn io_burst(mut self, blocks: usize) -> Self {
+        self.io_burst = blocks;
+        self
+    }
+    pub fn max_inflight_io(mut self, ops: usize) -> Self {
+        self.max_inflight_io = ops;
+        self
+    }
+    pub fn alloc_groups(mut self, enabled: bool) -> Self {
+        self.alloc_groups = enabled;
+        self
+    }
+    pub fn silly_rename(mut self, enabled: bool) -> Self {
+        self.silly_rename = enabled;
+        self
+    }
+    /// The options, if `validate()` finds them coherent
+    pub fn build(self) -> vfs::Result<Self> {
+        self.validate()?;
+        Ok(self)
+    }
+
+    /// Check the options make sense together, whether to open or create.
+    ///
+    /// Fail with `Unsupported` if the block size is not `BLKSIZE`, and with
+    /// `InvalidParam` if deterministic with a time provider, whose clock
+    /// makes images depend on when they are made, if `case_insensitive` is
+    /// not what a built-in `names` does, or set with a custom one, if
+    /// `max_inflight_io` is 0, or with rescue but not read-only.
+    pub fn validate(&self) -> vfs::Result<()> {
+        if self.block_size_log2 != BLKSIZE_LOG2 {
+            error!(
+                "sfs: block size {} is not supported, only {}",
+                1u64.checked_shl(self.block_size_log2 as u32).unwrap_or(0),
+                BLKSIZE
+            );
+            return Err(FsError::Unsupported);
+        }
+        if self.deterministic && self.time_provider.is_some() {
+            error!("sfs: a deterministic fs can not read times from a clock");
+            return Err(FsError::InvalidParam);
+        }
+        if self.max_inflight_io == 0 {
+            error!("sfs: max_inflight_io of 0 lets no I/O through");
+            return Err(FsError::InvalidParam);
+        }
+        if self.rescue && !self.read_only {
+            error!("sfs: rescue mode is only read-only");
+            return Err(FsError::InvalidParam);
+        }
+        if let (Some(case_insensitive), Some(names)) = (self.case_insensitive, &self.names) {
+            if case_insensitive_policy(names.id) != Some(case_insensitive) {
+                error!(
+                    "sfs: name policy {:#x} does not match case_insensitive {}",
+                    names.id, case_insensitive
+                );
+                return Err(FsError::InvalidParam);
+            }
+        }
+        Ok(())
+    }
+
+    /// The settings recorded in the image of `super_block`, the others
+    /// default. Those before its version are the ones older images have.
+    pub fn from_superblock(super_block: &SuperBlock) -> Self {
+        let mut opts = SfsOptions::default();
+        if super_block.version >= VERSION_UUID {
+            opts.uuid = Some(super_block.uuid);
+        }
+        if super_block.version >= VERSION_LAYOUT {
+            opts.reserved_blocks = super_block.reserved_blocks as usize;
+            opts.root_block = match super_block.root_block as usize {
+                BLKN_ROOT => None,
+                id => Some(id),
+            };
+        }
+        let name_policy = match super_block.version >= VERSION_NAMES {
+            true => super_block.name_policy,
+            false => NAME_POLICY_EXACT,
+        };
+        opts.case_insensitive = case_insensitive_policy(name_policy);
+        opts.names = NamePolicy::built_in(name_policy);
+        opts.reserve_for_recovery = match super_block.version >= VERSION_RECOVERY {
+            true => super_block.recovery_blocks as usize,
+            false => 0,
+        };
+        opts
+    }
+
+    /// Fail with `InvalidParam` if these options ask for another setting
+    /// than `persisted` ones recorded in an image to open
+    pub(crate) fn check_persisted(&self, persisted: &SfsOptions) -> vfs::Result<()> {
+        if let Some(case_insensitive) = self.case_insensitive {
+            if persisted.case_insensitive != Some(case_insensitive) {
+                error!(
+                    "sfs: asked for case-{} names, the image has {}",
+                    match case_insensitive {
+                        true => "insensitive",
+                        false => "sensitive",
+                    },
+                    match persisted.case_insensitive {
+                        Some(true) => "case-insensitive ones",
+                        Some(false) => "case-sensitive ones",
+                        None => "a custom name policy",
+                    }
+                );
+                return Err(FsError::InvalidParam);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the built-in name policy `id` ignores case, `None` for a custom
+/// one
+fn case_insensitive_policy(id: u32) -> Option<bool> {
+    match id {
+        NAME_POLICY_EXACT => Some(false),
+        NAME_POLICY_ASCII_CASE_FOLD => Some(true),
+        _ => None,
+    }
+}
@@ -0,0 +1,309 @@
+//! Copy of a subtree of any fs into a new SFS image sized to fit it, see
+//! `pack_subtree()`
+
+use super::*;
+
+/// How `pack_subtree()` builds the image
+#[derive(Debug, Clone, Copy)]
+pub struct PackOptions {
+    /// Free space left in the image, in percent of the blocks it needs
+    pub slack_percent: usize,
+    /// Make a reproducible image: the UUID is made from the seed, and
+    /// entries are created in name order rather than the order listed
+    pub seed: Option<u64>,
+    /// Compress the files whose blocks it shrinks by at least this percent,
+    /// see `INodeImpl::compress_from()`
+    pub compress: Option<usize>,
+    /// Leave out the inodes flagged `MetadataFlags::CORRUPT`, e.g. by
+    /// `SimpleFileSystem::open_rescue()`, instead of failing with
+    /// `Corrupted`
+    pub skip_corrupt: bool,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions {
+            slack_percent: 10,
+            seed: None,
+            compress: None,
+            skip_corrupt: false,
+        }
+    }
+}
+
+/// Make a new SFS on `device` holding a copy of the tree under dir `src`,
+/// which becomes its root.
+///
+/// The image is only as large as the tree needs, plus
+/// `opts.slack_percent`. Types, content, mode, owner, times and flags are
+/// copied, inodes with several names in the tree are linked again. With
+/// `opts.compress` files are compressed, those saving too little are
+/// stored as is. With `opts.skip_corrupt` a tree salvaged by
+/// `SimpleFileSystem::open_rescue()` is copied without its corrupt inodes.
+///
+/// The superblock and its backups are written last, once all else is, so an
+/// image left by an error is rejected by `open()`, provided `device` held no
+/// valid SFS before. Only an error writing a backup superblock may leave an
+/// image which opens.
+pub fn pack_subtree(
+    src: &Arc<dyn INode>,
+    device: Arc<dyn Device>,
+    opts: PackOptions,
+) -> vfs::Result<Arc<SimpleFileSystem>> {
+    let root_meta = src.metadata()?;
+    if root_meta.type_ != vfs::FileType::Dir {
+        return Err(FsError::NotDir);
+    }
+    let mut usage = Usage {
+        skip_corrupt: opts.skip_corrupt,
+        ..Usage::default()
+    };
+    usage.walk(src)?;
+    let blocks = usage.image_blocks() * (100 + opts.slack_percent) / 100;
+    let blocks = blocks.max(16);
+    if device.size().is_some_and(|size| size < blocks * BLKSIZE) {
+        return Err(FsError::NoDeviceSpace);
+    }
+    let sfs = match opts.seed {
+        Some(seed) => SimpleFileSystem::create_with_seed(device, blocks * BLKSIZE, seed)?,
+        None => SimpleFileSystem::create(device, blocks * BLKSIZE)?,
+    };
+    sfs.hold_super_block.store(true, Ordering::Relaxed);
+    if opts.seed.is_some() {
+        sfs.set_alloc_groups(false);
+    }
+    let root = sfs.root_inode();
+    let mut packer = Packer {
+        sorted: opts.seed.is_some(),
+        compress: opts.compress,
+        skip_corrupt: opts.skip_corrupt,
+        linked: BTreeMap::new(),
+        packed: Vec::new(),
+    };
+    packer.copy_dir(src, &root)?;
+    packer.packed.push((src.clone(), root));
+    for (src, inode) in packer.packed.iter() {
+        copy_attributes(src, inode)?;
+    }
+    // all but the superblock, which then makes the image valid
+    sfs.sync()?;
+    sfs.hold_super_block.store(false, Ordering::Relaxed);
+    // written once, as by `unmount()`
+    sfs.super_block.write().state = STATE_CLEAN;
+    sfs.unmounted.store(true, Ordering::SeqCst);
+    if let Err(err) = sfs.sync() {
+        sfs.hold_super_block.store(true, Ordering::Relaxed);
+        return Err(err);
+    }
+    Ok(sfs)
+}
+
+/// Blocks a tree takes in SFS
+#[derive(Default)]
+struct Usage {
+    /// data, index and inode blocks, but the root inode
+    blocks: usize,
+    /// inodes with several names counted so far
+    counted: BTreeSet<vfs::InodeKey>,
+    /// see `PackOptions::skip_corrupt`
+    skip_corrupt: bool,
+}
+
+impl Usage {
+    /// Count dir `dir` and the tree under it
+    fn walk(&mut self, dir: &Arc<dyn INode>) -> vfs::Result<()> {
+        let names = dir.list()?;
+        let entries = names.len();
+        self.blocks += content_blocks(entries * DIRENT_SIZE);
+        if entries >= INDEX_THRESHOLD {
+            // buckets are about half full
+            let buckets = (2 * entries.div_ceil(INDEX_BUCKET_SLOTS)).min(INDEX_MAX_BUCKETS);
+            self.blocks += 1 + buckets;
+        }
+        for name in names.iter().skip(2) {
+            let child = dir.find(name)?;
+            let meta = child.metadata()?;
+            if is_skipped(&meta, self.skip_corrupt)? {
+                continue;
+            }
+            if meta.type_ != vfs::FileType::Dir
+                && meta.nlinks > 1
+                && !self.counted.insert(child.ino_key())
+            {
+                continue;
+            }
+            // its inode
+            self.blocks += 1;
+            match meta.type_ {
+                vfs::FileType::Dir => self.walk(&child)?,
+                vfs::FileType::File | vfs::FileType::SymLink => {
+                    self.blocks += content_blocks(meta.size)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Blocks of an image holding the tree with no space left
+    fn image_blocks(&self) -> usize {
+        let mut blocks = BLKN_FREEMAP + self.blocks;
+        loop {
+            let backups = backup_super_blocks(blocks).len();
+            let total = BLKN_FREEMAP + self.blocks + blocks.div_ceil(BLKBITS) + backups;
+            if total <= blocks {
+                return blocks;
+            }
+            blocks = total;
+        }
+    }
+}
+
+/// Data blocks of `size` bytes of content, and the indirect blocks mapping
+/// them
+fn content_blocks(size: usize) -> usize {
+    let blocks = size.div_ceil(BLKSIZE);
+    let mut index = 0;
+    if blocks > NDIRECT {
+        index += 1;
+    }
+    if blocks > MAX_NBLOCK_INDIRECT {
+        index += 1 + (blocks - MAX_NBLOCK_INDIRECT).div_ceil(BLK_NENTRY);
+    }
+    blocks + index
+}
+
+struct Packer {
+    /// create entries in name order
+    sorted: bool,
+    /// see `PackOptions::compress`
+    compress: Option<usize>,
+    /// see `PackOptions::skip_corrupt`
+    skip_corrupt: bool,
+    /// copies of the inodes with several names, by their source
+    linked: BTreeMap<vfs::InodeKey, Arc<dyn INode>>,
+    /// (source, copy) of every inode, to copy attributes once all is written
+    packed: Vec<(Arc<dyn INode>, Arc<dyn INode>)>,
+}
+
+impl Packer {
+    /// Copy the entries of dir `src` into the empty dir `dir`
+    fn copy_dir(&mut self, src: &Arc<dyn INode>, dir: &Arc<dyn INode>) -> vfs::Result<()> {
+        let mut names: Vec<String> = src.list()?.into_iter().skip(2).collect();
+        if self.sorted {
+            names.sort();
+        }
+        // new inodes are created at once, then the other names linked
+        let mut created = Vec::new();
+        let mut links = Vec::new();
+        let mut seen = BTreeSet::new();
+        for name in names {
+            let child = src.find(&name)?;
+            let meta = child.metadata()?;
+            if is_skipped(&meta, self.skip_corrupt)? {
+                continue;
+            }
+            let multi = meta.type_ != vfs::FileType::Dir && meta.nlinks > 1;
+            if multi {
+                let key = child.ino_key();
+                if self.linked.contains_key(&key) || !seen.insert(key) {
+                    links.push((name, key));
+                    continue;
+                }
+            }
+            created.push((name, child, meta, multi));
+        }
+        let specs: Vec<_> = created
+            .iter()
+            .map(|(name, _, meta, _)| CreateSpec {
+                name,
+                type_: meta.type_,
+                mode: meta.mode as u32,
+                data: meta.rdev,
+            })
+            .collect();
+        let inodes = dir.create_batch(&specs)?;
+        // known before going down, where they may be linked
+        for ((_, child, _, multi), inode) in created.iter().zip(inodes.iter()) {
+            if *multi {
+                self.linked.insert(child.ino_key(), inode.clone());
+            }
+        }
+        for ((_, child, meta, _), inode) in created.into_iter().zip(inodes) {
+            match meta.type_ {
+                vfs::FileType::Dir => self.copy_dir(&child, &inode)?,
+                vfs::FileType::File if self.compress.is_some() => {
+                    let compressed = match (inode.downcast_ref::<INodeImpl>(), self.compress) {
+                        (Some(file), Some(compress)) => {
+                            file.compress_from(&*child, meta.size, compress)?
+                        }
+                        _ => false,
+                    };
+                    if !compressed {
+                        copy_content(&child, &inode, meta.size)?
+                    }
+                }
+                vfs::FileType::File | vfs::FileType::SymLink => {
+                    copy_content(&child, &inode, meta.size)?
+                }
+                _ => {}
+            }
+            self.packed.push((child, inode));
+        }
+        for (name, key) in links {
+            dir.link(&name, &self.linked[&key])?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the inode of `meta` is left out, failing with `Corrupted` if it
+/// is corrupt and not `skip_corrupt`
+fn is_skipped(meta: &Metadata, skip_corrupt: bool) -> vfs::Result<bool> {
+    if !meta.flags.contains(MetadataFlags::CORRUPT) {
+        return Ok(false);
+    }
+    match skip_corrupt {
+        true => Ok(true),
+        false => Err(FsError::Corrupted),
+    }
+}
+
+/// Copy `size` bytes of content of `src` into `inode`
+fn copy_content(src: &Arc<dyn INode>, inode: &Arc<dyn INode>, size: usize) -> vfs::Result<()> {
+    inode.resize(size)?;
+    let mut buf = vec![0u8; BLKSIZE];
+    let mut offset = 0;
+    while offset < size {
+        let chunk = (size - offset).min(BLKSIZE);
+        if src.read_at(offset, &mut buf[..chunk])? != chunk {
+            return Err(FsError::WrongFs);
+        }
+        if inode.write_at(offset, &buf[..chunk])? != chunk {
+            return Err(FsError::DeviceError);
+        }
+        offset += chunk;
+    }
+    Ok(())
+}
+
+/// Give `inode` the mode, owner, times, creation time included, and flags
+/// of `src`
+fn copy_attributes(src: &Arc<dyn INode>, inode: &Arc<dyn INode>) -> vfs::Result<()> {
+    let meta = src.metadata()?;
+    let mut new_meta = inode.metadata()?;
+    new_meta.mode = meta.mode;
+    new_meta.uid = meta.uid;
+    new_meta.gid = meta.gid;
+    new_meta.atime = meta.atime;
+    new_meta.mtime = meta.mtime;
+    new_meta.ctime = meta.ctime;
+    inode.set_metadata(&new_meta)?;
+    if let Some(inode) = inode.downcast_ref::<INodeImpl>() {
+        inode.set_btime(meta.btime);
+    }
+    match src.get_flags() {
+        Ok(flags) if flags != InodeFlags::empty() => inode.set_flags(flags),
+        _ => Ok(()),
+    }
+}
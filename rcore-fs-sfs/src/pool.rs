@@ -0,0 +1,124 @@
+//! Pool of scratch buffers, to keep hot temporary allocations off the heap
+
+use crate::structs::{BLKSIZE, DIRENT_SIZE};
+use alloc::{boxed::Box, vec::Vec};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Size of a scratch buffer: a block, plus an entry crossing its end
+pub(crate) const SCRATCH_SIZE: usize = BLKSIZE + DIRENT_SIZE;
+
+/// Default number of buffers kept by the pool
+pub(crate) const DEFAULT_SCRATCH_POOL_SIZE: usize = 4;
+
+type Scratch = Box<[u8; SCRATCH_SIZE]>;
+
+/// Freelist of scratch buffers.
+///
+/// Buffers are preallocated up to `size`. When the pool is empty, a buffer is
+/// taken from the global allocator instead, and freed on release if the pool
+/// is full.
+pub(crate) struct ScratchPool {
+    free: Mutex<Vec<Scratch>>,
+    size: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ScratchPool {
+    pub fn new(size: usize) -> Self {
+        let pool = ScratchPool {
+            free: Mutex::new(Vec::new()),
+            size: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+        pool.set_size(size);
+        pool
+    }
+
+    /// Set the number of kept buffers, 0 to always use the global allocator
+    pub fn set_size(&self, size: usize) {
+        let mut free = self.free.lock();
+        self.size.store(size, Ordering::Relaxed);
+        free.truncate(size);
+        free.shrink_to(size);
+        // release never grows the list
+        let len = free.len();
+        free.reserve_exact(size - len);
+        while free.len() < size {
+            free.push(Box::new([0; SCRATCH_SIZE]));
+        }
+    }
+
+    /// The number of kept buffers, see `set_size()`
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Take a buffer. Its content is unspecified.
+    pub fn acquire(&self) -> ScratchBuf<'_> {
+        let buf = match self.free.lock().pop() {
+            Some(buf) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Box::new([0; SCRATCH_SIZE])
+            }
+        };
+        ScratchBuf {
+            pool: self,
+            buf: Some(buf),
+        }
+    }
+
+    fn release(&self, buf: Scratch) {
+        let mut free = self.free.lock();
+        if free.len() < self.size.load(Ordering::Relaxed) {
+            free.push(buf);
+        }
+    }
+
+    /// Number of (hits, misses) since created
+    pub fn counters(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A scratch buffer, returned to the pool on drop
+pub(crate) struct ScratchBuf<'a> {
+    pool: &'a ScratchPool,
+    buf: Option<Scratch>,
+}
+
+impl Deref for ScratchBuf<'_> {
+    type Target = [u8; SCRATCH_SIZE];
+
+    #[allow(clippy::unwrap_used)]
+    fn deref(&self) -> &Self::Target {
+        // `Some` until dropped
+        self.buf.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ScratchBuf<'_> {
+    #[allow(clippy::unwrap_used)]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // `Some` until dropped
+        self.buf.as_mut().unwrap()
+    }
+}
+
+impl Drop for ScratchBuf<'_> {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.release(buf);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Recording the blocks a workload reads, e.g. a boot, and reading them
+//! ahead of the next run, see `SimpleFileSystem::start_access_trace()`
+
+use super::*;
+use rcore_fs::dev::{CacheHint, HintedDevice, Result as DevResult, WearHook};
+use rcore_fs::util::{Permit, Semaphore, SemaphoreStats};
+use spin::RwLockReadGuard;
+
+/// Most blocks an access trace records, later ones are left out
+pub const MAX_TRACE_BLOCKS: usize = 1 << 16;
+
+const MAGIC: &[u8; 4] = b"SFSP";
+const TRACE_VERSION: u32 = 1;
+
+/// Distinct blocks read while recorded, in order of first read, see
+/// `SimpleFileSystem::start_access_trace()`
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct AccessTrace {
+    blocks: Vec<BlockId>,
+}
+
+impl AccessTrace {
+    pub fn blocks(&self) -> &[BlockId] {
+        &self.blocks
+    }
+
+    /// The trace as bytes, to be stored e.g. in a file for the next boot
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + self.blocks.len() * 4);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        for &id in self.blocks.iter() {
+            bytes.extend_from_slice(&(id as u32).to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Load a trace from `to_bytes()`, failing with `InvalidParam` if
+    /// `bytes` is not one
+    pub fn from_bytes(bytes: &[u8]) -> vfs::Result<Self> {
+        let words: Vec<u32> = match bytes.get(4..) {
+            Some(rest) if &bytes[..4] == MAGIC && rest.len() % 4 == 0 => rest
+                .chunks(4)
+                .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                .collect(),
+            _ => return Err(FsError::InvalidParam),
+        };
+        match words.as_slice() {
+            [TRACE_VERSION, len, blocks @ ..] if *len as usize == blocks.len() => Ok(AccessTrace {
+                blocks: blocks.iter().map(|&id| id as BlockId).collect(),
+            }),
+            _ => Err(FsError::InvalidParam),
+        }
+    }
+}
+
+/// The device of a fs, noting the blocks read while a trace is recorded,
+/// keeping the blocks of a backup in progress before they are written, and
+/// bounding the operations in the device at once, see
+/// `SimpleFileSystem::set_max_inflight_io()`
+pub(crate) struct TracingDevice {
+    inner: Arc<dyn Device>,
+    /// a permit for each block of each operation in `inner`, but no more
+    /// than all of them for one
+    io: Semaphore,
+    /// a trace is recorded
+    tracing: AtomicBool,
+    /// blocks noted, with a set of them
+    trace: spin::Mutex<(Vec<BlockId>, BTreeSet<BlockId>)>,
+    /// see `SimpleFileSystem::backup_stream()`, held across each write so
+    /// that none is under way when a backup starts
+    backup: RwLock<Option<BackupCopies>>,
+}
+
+impl TracingDevice {
+    pub(crate) fn new(inner: Arc<dyn Device>, max_inflight_io: usize) -> Self {
+        TracingDevice {
+            inner,
+            io: Semaphore::new(max_inflight_io),
+            tracing: AtomicBool::new(false),
+            trace: spin::Mutex::new((Vec::new(), BTreeSet::new())),
+            backup: RwLock::new(None),
+        }
+    }
+
+    /// Keep the blocks of `copies` from now on, failing with `Busy` if
+    /// another backup is in progress
+    pub(crate) fn start_backup(&self, copies: BackupCopies) -> vfs::Result<()> {
+        let mut backup = self.backup.write();
+        if backup.is_some() {
+            return Err(FsError::Busy);
+        }
+        *backup = Some(copies);
+        Ok(())
+    }
+
+    /// Stop keeping blocks, return the number of them kept for writes
+    pub(crate) fn end_backup(&self) -> usize {
+        self.backup
+            .write()
+            .take()
+            .map_or(0, |copies| copies.copied_up())
+    }
+
+    /// Block `id` as it was when the backup started, see
+    /// `BackupCopies::take()`
+    pub(crate) fn backup_block(&self, id: BlockId) -> DevResult<Option<Vec<u8>>> {
+        let _io = self.io.acquire(1, false);
+        match &*self.backup.read() {
+            Some(copies) => copies.take(&*self.inner, id),
+            None => Ok(None),
+        }
+    }
+
+    /// Before writing `len` bytes from `offset`, keep the blocks the backup
+    /// still needs. The write is to be done with the guard held.
+    fn before_write(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> DevResult<RwLockReadGuard<'_, Option<BackupCopies>>> {
+        let backup = self.backup.read();
+        if let Some(copies) = &*backup {
+            copies.copy_up(&*self.inner, offset, len)?;
+        }
+        Ok(backup)
+    }
+
+    /// Permits for an operation on `len` bytes, waiting until free
+    fn acquire(&self, len: usize, prio: bool) -> Permit<'_> {
+        self.io.acquire(len.div_ceil(BLKSIZE), prio)
+    }
+
+    pub(crate) fn set_max_inflight_io(&self, max: usize) {
+        self.io.set_max(max);
+    }
+
+    pub(crate) fn max_inflight_io(&self) -> usize {
+        self.io.max()
+    }
+
+    pub(crate) fn io_stats(&self) -> SemaphoreStats {
+        self.io.stats()
+    }
+
+    /// `read_ahead()`, or `None` if the device has no free slot now or
+    /// others wait for one, for prefetching to be skipped rather than wait
+    pub(crate) fn try_read_ahead(&self, offset: usize, len: usize) -> Option<DevResult<()>> {
+        let _io = self.io.try_acquire(len.div_ceil(BLKSIZE))?;
+        Some(self.inner.read_ahead(offset, len))
+    }
+
+    /// `read_at_prio()`, or `None` as `try_read_ahead()`
+    pub(crate) fn try_read_at_prio(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Option<DevResult<usize>> {
+        let _io = self.io.try_acquire(buf.len().div_ceil(BLKSIZE))?;
+        self.note(offset, buf.len());
+        Some(self.inner.read_at_prio(offset, buf))
+    }
+
+    /// Note the blocks of `len` bytes read from `offset`
+    fn note(&self, offset: usize, len: usize) {
+        if !self.tracing.load(Ordering::Relaxed) || len == 0 {
+            return;
+        }
+        let mut trace = self.trace.lock();
+        let (blocks, seen) = &mut *trace;
+        for id in offset / BLKSIZE..=(offset + len - 1) / BLKSIZE {
+            if blocks.len() >= MAX_TRACE_BLOCKS {
+                break;
+            }
+            if seen.insert(id) {
+                blocks.push(id);
+            }
+        }
+    }
+}
+
+impl Device for TracingDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let _io = self.acquire(buf.len(), false);
+        self.note(offset, buf.len());
+        self.inner.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        let _io = self.acquire(buf.len(), false);
+        let _backup = self.before_write(offset, buf.len())?;
+        self.inner.write_at(offset, buf)
+    }
+
+    fn sync(&self) -> DevResult<()> {
+        let _io = self.io.acquire(1, false);
+        self.inner.sync()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.inner.size()
+    }
+
+    fn write_zeros(&self, offset: usize, len: usize) -> DevResult<usize> {
+        let _io = self.acquire(len, false);
+        let _backup = self.before_write(offset, len)?;
+        self.inner.write_zeros(offset, len)
+    }
+
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let _io = self.acquire(buf.len(), false);
+        self.note(offset, buf.len());
+        self.inner.read_at_direct(offset, buf)
+    }
+
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        let _io = self.acquire(buf.len(), false);
+        let _backup = self.before_write(offset, buf.len())?;
+        self.inner.write_at_direct(offset, buf)
+    }
+
+    fn read_at_prio(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let _io = self.acquire(buf.len(), true);
+        self.note(offset, buf.len());
+        self.inner.read_at_prio(offset, buf)
+    }
+
+    fn wear_hook(&self) -> Option<&dyn WearHook> {
+        self.inner.wear_hook()
+    }
+
+    /// Skipped if the device is busy, see `try_read_ahead()`
+    fn read_ahead(&self, offset: usize, len: usize) -> DevResult<()> {
+        self.try_read_ahead(offset, len).unwrap_or(Ok(()))
+    }
+
+    fn hinted(&self) -> Option<&dyn HintedDevice> {
+        self.inner.hinted().map(|_| self as &dyn HintedDevice)
+    }
+}
+
+impl HintedDevice for TracingDevice {
+    fn read_at_hinted(&self, offset: usize, buf: &mut [u8], hint: CacheHint) -> DevResult<usize> {
+        let _io = self.acquire(buf.len(), hint == CacheHint::Metadata);
+        self.note(offset, buf.len());
+        match self.inner.hinted() {
+            Some(inner) => inner.read_at_hinted(offset, buf, hint),
+            None => self.inner.read_at(offset, buf),
+        }
+    }
+
+    fn write_at_hinted(&self, offset: usize, buf: &[u8], hint: CacheHint) -> DevResult<usize> {
+        let _io = self.acquire(buf.len(), false);
+        let _backup = self.before_write(offset, buf.len())?;
+        match self.inner.hinted() {
+            Some(inner) => inner.write_at_hinted(offset, buf, hint),
+            None => self.inner.write_at(offset, buf),
+        }
+    }
+}
+
+impl SimpleFileSystem {
+    /// Start recording the distinct blocks read from the device, e.g. during
+    /// a boot, until `stop_access_trace()`. At most `MAX_TRACE_BLOCKS` are
+    /// recorded. A trace already being recorded is dropped.
+    pub fn start_access_trace(&self) {
+        let mut trace = self.tracer.trace.lock();
+        trace.0.clear();
+        trace.1.clear();
+        self.tracer.tracing.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop recording, return the blocks read since `start_access_trace()`
+    /// in order of first read
+    pub fn stop_access_trace(&self) -> AccessTrace {
+        self.tracer.tracing.store(false, Ordering::Relaxed);
+        let mut trace = self.tracer.trace.lock();
+        trace.1.clear();
+        AccessTrace {
+            blocks: core::mem::take(&mut trace.0),
+        }
+    }
+
+    /// Read ahead the first `budget` blocks of `trace` still in use, to warm
+    /// the cache of the device before the workload recorded runs again.
+    /// Adjacent blocks are read by one `Device::read_ahead()`. Blocks now
+    /// free or outside the fs are skipped, so a stale trace only reads less,
+    /// as are runs finding the device busy, see `set_max_inflight_io()`.
+    /// Return the number of blocks read ahead.
+    pub async fn prefetch(&self, trace: &AccessTrace, budget: usize) -> vfs::Result<usize> {
+        let mut blocks: Vec<BlockId> = {
+            let free_map = self.free_map.read();
+            let blocks = self.super_block.read().blocks as usize;
+            let in_use =
+                |&&id: &&BlockId| id < blocks && !free_map.get(id).is_some_and(|free| *free);
+            trace
+                .blocks
+                .iter()
+                .filter(in_use)
+                .take(budget)
+                .copied()
+                .collect()
+        };
+        blocks.sort_unstable();
+        let mut rest = &blocks[..];
+        let mut read = 0;
+        while !rest.is_empty() {
+            let mut len = 1;
+            while len < rest.len() && rest[len] == rest[0] + len {
+                len += 1;
+            }
+            if let Some(result) = self.tracer.try_read_ahead(rest[0] * BLKSIZE, len * BLKSIZE) {
+                fs_try!(
+                    result.map_err(FsError::from),
+                    vfs::ErrorContext::new("prefetch").block(rest[0])
+                );
+                read += len;
+            }
+            rest = &rest[len..];
+        }
+        Ok(read)
+    }
+}
@@ -0,0 +1,194 @@
+//! Read-only opening of a damaged image to salvage what can be read, see
+//! `SimpleFileSystem::open_rescue()`
+
+use super::*;
+
+/// Why an entry is left out of the listing of its dir in rescue mode
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum LostReason {
+    /// its inode can not be read, and is quarantined
+    BadInode,
+    /// its name is not one an entry may have
+    BadName,
+}
+
+/// An entry left out of the listing of its dir in rescue mode
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct LostEntry {
+    /// slot of the entry in the dir, as in `DirCursor::next`
+    pub slot: usize,
+    /// name bytes, up to the first NUL if any
+    pub name: Vec<u8>,
+    /// inode it points to
+    pub inode: INodeId,
+    pub reason: LostReason,
+}
+
+/// Content of a file read as zeros in rescue mode, since its block could
+/// not be read or is mapped out of the fs
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ZeroFilled {
+    pub inode: INodeId,
+    /// start of the block in the content
+    pub offset: usize,
+    /// bytes of the block in the content
+    pub len: usize,
+}
+
+/// What rescue mode left out or made up so far, see
+/// `SimpleFileSystem::rescue_report()`
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct RescueReport {
+    /// Inodes which can not be read, found as empty files flagged
+    /// `MetadataFlags::CORRUPT`
+    pub quarantined: BTreeSet<INodeId>,
+    /// Entries left out of the listing of each dir, by dir
+    pub lost: BTreeMap<INodeId, Vec<LostEntry>>,
+    /// Content read as zeros
+    pub zero_filled: BTreeSet<ZeroFilled>,
+}
+
+/// State of rescue mode in `SimpleFileSystem::rescue`
+#[derive(Default)]
+pub(crate) struct Rescue {
+    report: RescueReport,
+    /// inodes of entries found readable, not loaded again when listed
+    good: BTreeSet<INodeId>,
+}
+
+impl SimpleFileSystem {
+    /// Open the SFS on `device` to salvage what can be read from it.
+    ///
+    /// The fs is read-only, and what `open()` checks lazily does not fail:
+    /// an inode which can not be read is found as an empty file flagged
+    /// `MetadataFlags::CORRUPT`, entries with a bad name or such an inode
+    /// are left out of the listing of their dir, and content which can not
+    /// be read reads as zeros. All of it is recorded in `rescue_report()`.
+    /// The superblock and freemap must still be readable.
+    pub fn open_rescue(device: Arc<dyn Device>) -> vfs::Result<Arc<Self>> {
+        Self::open_with(device, &SfsOptions::new().read_only(true).rescue(true))
+    }
+
+    /// What was left out or zero-filled so far, `None` unless opened by
+    /// `open_rescue()`
+    pub fn rescue_report(&self) -> Option<RescueReport> {
+        self.rescue
+            .as_ref()
+            .map(|rescue| rescue.read().report.clone())
+    }
+
+    /// Stub of inode `id` which `read_inode()` failed with `err` in rescue
+    /// mode, or `err` if not in rescue mode
+    pub(crate) fn quarantine(&self, id: INodeId, err: FsError) -> vfs::Result<DiskINode> {
+        let rescue = match &self.rescue {
+            Some(rescue) => rescue,
+            None => return Err(err),
+        };
+        warn!("sfs: quarantine inode {}: {:?}", id, err);
+        rescue.write().report.quarantined.insert(id);
+        let mut stub = DiskINode::new_file();
+        // never reclaimed on drop
+        stub.nlinks = 1;
+        Ok(stub)
+    }
+
+    /// Flags of `Metadata` for inode `id`
+    pub(crate) fn metadata_flags(&self, id: INodeId) -> MetadataFlags {
+        match &self.rescue {
+            Some(rescue) if rescue.read().report.quarantined.contains(&id) => {
+                MetadataFlags::CORRUPT
+            }
+            _ => MetadataFlags::empty(),
+        }
+    }
+
+    /// Whether `entry` in slot `slot` of dir `dir` is left out of listings
+    /// in rescue mode, recording it in the report if so
+    pub(crate) fn is_lost(&self, dir: INodeId, slot: usize, entry: &DiskEntry) -> bool {
+        let rescue = match &self.rescue {
+            Some(rescue) => rescue,
+            None => return false,
+        };
+        let inode = entry.id as INodeId;
+        let reason = if !is_entry_name(&entry.name) {
+            LostReason::BadName
+        } else if rescue.read().good.contains(&inode) {
+            return false;
+        } else {
+            let loaded = self.get_inode(inode);
+            let mut rescue = rescue.write();
+            if loaded.is_ok() && !rescue.report.quarantined.contains(&inode) {
+                rescue.good.insert(inode);
+                return false;
+            }
+            LostReason::BadInode
+        };
+        let mut rescue = rescue.write();
+        let lost = rescue.report.lost.entry(dir).or_default();
+        if !lost.iter().any(|lost| lost.slot == slot) {
+            warn!("sfs: lose entry {} of dir {}: {:?}", slot, dir, reason);
+            lost.push(LostEntry {
+                slot,
+                name: entry.name.as_bytes().to_vec(),
+                inode,
+                reason,
+            });
+        }
+        true
+    }
+
+    /// Record that `len` bytes of content of inode `id` at `offset` read as
+    /// zeros
+    fn zero_filled(&self, id: INodeId, offset: usize, len: usize) {
+        if let Some(rescue) = &self.rescue {
+            rescue.write().report.zero_filled.insert(ZeroFilled {
+                inode: id,
+                offset,
+                len,
+            });
+        }
+    }
+}
+
+impl INodeImpl {
+    /// Read content like `_read_at()` in rescue mode: a block which can not
+    /// be read, or is mapped out of the fs, reads as zeros
+    pub(crate) fn _rescue_read_at(&self, offset: usize, buf: &mut [u8]) -> vfs::Result<usize> {
+        let size = self.disk_inode.read().size as usize;
+        let iter = BlockIter {
+            begin: size.min(offset),
+            end: size.min(offset + buf.len()),
+            block_size_log2: BLKSIZE_LOG2,
+        };
+        let mut buf_offset = 0;
+        for range in iter {
+            let len = range.len();
+            let part = &mut buf[buf_offset..buf_offset + len];
+            let read = self
+                .get_disk_block_id(range.block)
+                .and_then(|block| self.fs.device.read_block(block, range.begin, part));
+            if let Err(err) = read {
+                let start = range.block * BLKSIZE;
+                warn!(
+                    "sfs: zero-fill block at {} of inode {}: {:?}",
+                    start, self.id, err
+                );
+                part.fill(0);
+                self.fs
+                    .zero_filled(self.id, start, BLKSIZE.min(size - start));
+            }
+            buf_offset += len;
+        }
+        Ok(buf_offset)
+    }
+}
+
+/// Whether `name` may be the name of an entry other than "." and ".."
+fn is_entry_name(name: &Str256) -> bool {
+    let bytes = name.as_bytes();
+    name.0.contains(&0)
+        && !bytes.is_empty()
+        && !bytes.contains(&b'/')
+        && bytes != b"."
+        && bytes != b".."
+}
@@ -0,0 +1,395 @@
+//! Online check of the freemap against the blocks reachable from the tree,
+//! see `SimpleFileSystem::scrub()`
+
+use super::*;
+
+/// Rounds of reading again the dirs changed during a pass, before the pass
+/// ends anyway
+const MAX_RESCANS: usize = 8;
+
+/// How much one call of `SimpleFileSystem::scrub()` may do
+#[derive(Debug, Clone, Copy)]
+pub struct ScrubBudget {
+    /// Blocks of inodes to check, at least one inode is checked per call
+    pub max_blocks: usize,
+}
+
+/// Progress of `SimpleFileSystem::scrub()` over calls, for one fs
+#[derive(Debug, Default)]
+pub struct ScrubState {
+    /// Fix the freemap bits found definitely wrong: mark leaked blocks free
+    /// and blocks in use marked free used. Other problems are left for an
+    /// offline fsck.
+    pub repair: bool,
+    /// a pass is in progress
+    started: bool,
+    /// inodes to check, with the dir they were found in and its change
+    /// cookie then
+    queue: Vec<(INodeId, Option<(INodeId, u64)>)>,
+    visited: BTreeSet<INodeId>,
+    /// change cookie of each dir checked, when its entries were read
+    dirs: BTreeMap<INodeId, u64>,
+    /// inode using each block reached
+    reached: BTreeMap<BlockId, INodeId>,
+    /// blocks reached twice
+    shared: BTreeSet<BlockId>,
+    rescans: usize,
+    /// blocks leaked in the last pass, certain if leaked again
+    suspects: BTreeSet<BlockId>,
+    /// inodes failing in this pass, and in the last one
+    failing: BTreeSet<INodeId>,
+    failed: BTreeSet<INodeId>,
+}
+
+impl ScrubState {
+    pub fn new(repair: bool) -> Self {
+        ScrubState {
+            repair,
+            ..ScrubState::default()
+        }
+    }
+}
+
+/// Something wrong found by `SimpleFileSystem::scrub()`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ScrubProblem {
+    /// Block marked used in the freemap, reached from no inode
+    Leaked(BlockId),
+    /// Block of an inode, marked free in the freemap
+    MarkedFree(BlockId),
+    /// Block reached from two inodes, or twice from one
+    Shared(BlockId),
+    /// Inode which fails to load, or whose blocks can not be listed
+    BadInode(INodeId),
+}
+
+/// What one call of `SimpleFileSystem::scrub()` found
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ScrubFindings {
+    /// Problems certain, as what they are about did not change during the
+    /// pass
+    pub inconsistent: Vec<ScrubProblem>,
+    /// Blocks leaked and inodes failing for the first time, which may also be
+    /// blocks allocated before the pass and taken into use during it, or
+    /// inodes read while another thread drops them. They are certain if the
+    /// next pass finds them again.
+    pub recheck: Vec<ScrubProblem>,
+    /// Problems of `inconsistent` fixed by `ScrubState::repair`
+    pub repaired: Vec<ScrubProblem>,
+    /// Blocks of inodes checked by this call
+    pub checked_blocks: usize,
+    /// This call ended a pass, the next call starts another
+    pub pass_complete: bool,
+}
+
+impl SimpleFileSystem {
+    /// Check part of the fs, about `budget.max_blocks` blocks, while it stays
+    /// fully usable: the blocks of the inodes reachable from the root are
+    /// compared with the freemap once all are checked. `state` keeps the
+    /// progress of the pass between calls, and starts another pass after.
+    ///
+    /// Changes made during the pass never show as problems: blocks allocated
+    /// or freed are left out, dirs whose change cookie moved are read again
+    /// at the end, and a leak or an inode failing to load is only certain
+    /// when two passes in a row find it. Inodes have no checksums, an inode is
+    /// only checked to load. Only one scrub may run on a fs at a time.
+    pub async fn scrub(
+        &self,
+        budget: ScrubBudget,
+        state: &mut ScrubState,
+    ) -> vfs::Result<ScrubFindings> {
+        let mut findings = ScrubFindings::default();
+        if !state.started {
+            self.scrub_start(state);
+        }
+        let mut checked = 0;
+        while checked == 0 || checked < budget.max_blocks {
+            if let Some((id, found_in)) = state.queue.pop() {
+                checked += self.scrub_inode(state, id, found_in, &mut findings);
+            } else if state.rescans < MAX_RESCANS && self.scrub_rescan(state, &mut checked) {
+                state.rescans += 1;
+            } else {
+                self.scrub_finish(state, &mut findings);
+                break;
+            }
+        }
+        findings.checked_blocks = checked;
+        Ok(findings)
+    }
+
+    fn scrub_start(&self, state: &mut ScrubState) {
+        // first, so that the orphans below are noted when freed
+        self.scrub_touched.write().get_or_insert_with(BTreeSet::new);
+        state.started = true;
+        state.visited.clear();
+        state.dirs.clear();
+        state.shared.clear();
+        state.rescans = 0;
+        // backups of the superblock are owned by no inode
+        let backups = self.super_block.read().backup_blocks;
+        state.reached = backups
+            .iter()
+            .filter(|&&id| id != 0)
+            .map(|&id| (id as BlockId, 0))
+            .collect();
+        state.queue = vec![(self.root_id, None)];
+        let orphans: Vec<_> = self
+            .inodes
+            .read()
+            .values()
+            .filter_map(INodeSlot::upgrade)
+            .filter(|inode| inode.is_removed())
+            .map(|inode| (inode.id, None))
+            .collect();
+        state.queue.extend(orphans);
+    }
+
+    /// Check inode `id`, return the number of blocks checked
+    fn scrub_inode(
+        &self,
+        state: &mut ScrubState,
+        id: INodeId,
+        found_in: Option<(INodeId, u64)>,
+        findings: &mut ScrubFindings,
+    ) -> usize {
+        if !state.visited.insert(id) {
+            return 0;
+        }
+        // the entry may be gone with its inode, then its dir is read again,
+        // and an orphan may be freed
+        let moved = || {
+            let gone = match found_in {
+                Some((dir, cookie)) => self.scrub_cookie(dir) != Some(cookie),
+                None => false,
+            };
+            gone || self.scrub_touched(id)
+        };
+        let inode = match self.get_inode(id) {
+            Ok(inode) => inode,
+            Err(_) if moved() => {
+                state.visited.remove(&id);
+                return 1;
+            }
+            Err(_) => {
+                Self::scrub_failed(state, id, findings);
+                return 1;
+            }
+        };
+        let mut checked = 1;
+        if inode.disk_inode.read().type_ == FileType::Dir {
+            checked += self.scrub_entries(state, &inode);
+        }
+        let blocks = match inode.scrub_blocks() {
+            Ok(Some(blocks)) => blocks,
+            // changing all the time, what it frees is left out anyway
+            Ok(None) => return checked,
+            Err(_) if moved() => {
+                state.visited.remove(&id);
+                return checked;
+            }
+            Err(_) => {
+                Self::scrub_failed(state, id, findings);
+                return checked;
+            }
+        };
+        checked += blocks.len();
+        // freed meanwhile, the blocks listed may be anything
+        if self.scrub_touched(id) {
+            return checked;
+        }
+        for block in blocks {
+            if state.reached.insert(block, id).is_some() {
+                state.shared.insert(block);
+            }
+        }
+        checked
+    }
+
+    /// Queue the entries of `dir` not checked yet, return the number of
+    /// blocks read
+    fn scrub_entries(&self, state: &mut ScrubState, dir: &Arc<INodeImpl>) -> usize {
+        let _guard = dir.dir_lock.read();
+        let cookie = dir.change_cookie();
+        let mut children = Vec::new();
+        let scanned = dir.scan_direntry(|i, entry| {
+            // skip "." and ".."
+            if i >= 2 {
+                children.push(entry.id as INodeId);
+            }
+            None::<()>
+        });
+        // a dir which can not be read is checked as an inode
+        if scanned.is_ok() {
+            state.dirs.insert(dir.id, cookie);
+            let visited = &state.visited;
+            let children = children
+                .into_iter()
+                .filter(|child| !visited.contains(child));
+            let found_in = Some((dir.id, cookie));
+            state.queue.extend(children.map(|child| (child, found_in)));
+        }
+        dir.disk_inode.read().blocks as usize
+    }
+
+    /// Report inode `id` failing, certain if it failed in the last pass too
+    fn scrub_failed(state: &mut ScrubState, id: INodeId, findings: &mut ScrubFindings) {
+        state.failing.insert(id);
+        match state.failed.contains(&id) {
+            true => findings.inconsistent.push(ScrubProblem::BadInode(id)),
+            false => findings.recheck.push(ScrubProblem::BadInode(id)),
+        }
+    }
+
+    /// Read again the dirs changed since checked. False if none did.
+    fn scrub_rescan(&self, state: &mut ScrubState, checked: &mut usize) -> bool {
+        let dirs: Vec<_> = state
+            .dirs
+            .iter()
+            .map(|(&id, &cookie)| (id, cookie))
+            .collect();
+        let mut changed = false;
+        for (id, cookie) in dirs {
+            match self.scrub_cookie(id) {
+                Some(now) if now == cookie => {}
+                Some(_) => {
+                    if let Ok(dir) = self.get_inode(id) {
+                        *checked += self.scrub_entries(state, &dir);
+                        changed = true;
+                    }
+                }
+                // removed, its blocks are freed
+                None => {
+                    state.dirs.remove(&id);
+                }
+            }
+        }
+        changed
+    }
+
+    /// Change cookie of dir `id`, `None` if it is gone
+    fn scrub_cookie(&self, id: INodeId) -> Option<u64> {
+        let dir = self.get_inode(id).ok()?;
+        let is_dir = dir.disk_inode.read().type_ == FileType::Dir;
+        (is_dir && !dir.is_removed()).then(|| dir.change_cookie())
+    }
+
+    /// Compare the blocks reached with the freemap, and repair it
+    fn scrub_finish(&self, state: &mut ScrubState, findings: &mut ScrubFindings) {
+        // in lock order, and so that no block is allocated or freed meanwhile
+        let mut free_map = self.free_map.write();
+        let mut touched = self.scrub_touched.write();
+        let changed = touched.take().unwrap_or_default();
+        let mut suspects = BTreeSet::new();
+        for block in self.data_blocks.clone() {
+            let free = free_map[block];
+            let problem = match (free, state.reached.contains_key(&block)) {
+                (false, false) => ScrubProblem::Leaked(block),
+                (true, true) => ScrubProblem::MarkedFree(block),
+                _ => continue,
+            };
+            if changed.contains(&block) {
+                continue;
+            }
+            if !free && !state.suspects.contains(&block) {
+                suspects.insert(block);
+                findings.recheck.push(problem);
+                continue;
+            }
+            findings.inconsistent.push(problem);
+            if state.repair {
+                warn!("sfs: scrub repairs {:?}", problem);
+                free_map.set(block, !free);
+                self.free_map_changed.write().insert(block / BLKBITS);
+                match free {
+                    true => self.unused_blocks.fetch_sub(1, Ordering::Relaxed),
+                    false => self.unused_blocks.fetch_add(1, Ordering::Relaxed),
+                };
+                findings.repaired.push(problem);
+            }
+        }
+        let shared = state.shared.iter().filter(|block| !changed.contains(block));
+        findings
+            .inconsistent
+            .extend(shared.map(|&block| ScrubProblem::Shared(block)));
+        // suspects must not change until the next pass confirms them
+        if !suspects.is_empty() {
+            *touched = Some(BTreeSet::new());
+        }
+        state.suspects = suspects;
+        state.failed = core::mem::take(&mut state.failing);
+        state.started = false;
+        state.reached.clear();
+        findings.pass_complete = true;
+    }
+
+    /// Whether block `id` was allocated or freed during the pass
+    fn scrub_touched(&self, id: BlockId) -> bool {
+        let touched = self.scrub_touched.read();
+        touched
+            .as_ref()
+            .is_some_and(|touched| touched.contains(&id))
+    }
+
+    /// Note block `id` allocated or freed while a scrub runs
+    pub(crate) fn scrub_touch(&self, id: BlockId) {
+        if self.scrub_touched.read().is_none() {
+            return;
+        }
+        if let Some(touched) = self.scrub_touched.write().as_mut() {
+            touched.insert(id);
+        }
+    }
+}
+
+impl INodeImpl {
+    /// The blocks used by this inode: itself, its content with the index
+    /// blocks, and the hashed index of a dir. `None` if it keeps changing
+    /// while listed.
+    fn scrub_blocks(&self) -> vfs::Result<Option<Vec<BlockId>>> {
+        let layout = || {
+            let disk_inode = self.disk_inode.read();
+            (
+                disk_inode.blocks,
+                disk_inode.indirect,
+                disk_inode.db_indirect,
+                disk_inode.index,
+                disk_inode.is_inline(),
+            )
+        };
+        for _ in 0..3 {
+            let before = layout();
+            let blocks = self.scrub_list(before.3, before.4);
+            if layout() == before {
+                return blocks.map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn scrub_list(&self, index: u32, inline: bool) -> vfs::Result<Vec<BlockId>> {
+        let mut blocks = match inline {
+            true => Vec::new(),
+            false => self.blocks_to_free(0)?,
+        };
+        if index != 0 {
+            let root = self.fs.check_block_id(index)?;
+            let index = self.fs.device.load_struct::<DirIndexRoot>(root)?;
+            let buckets = index
+                .buckets
+                .get(..index.nbuckets as usize)
+                .ok_or(FsError::Corrupted)?;
+            blocks.extend(
+                buckets
+                    .iter()
+                    .filter(|&&bucket| bucket != 0)
+                    .map(|&b| b as BlockId),
+            );
+            blocks.push(root);
+        }
+        for &block in blocks.iter() {
+            self.fs.check_block_id(block as u32)?;
+        }
+        blocks.push(self.id);
+        Ok(blocks)
+    }
+}
@@ -3,8 +3,11 @@
 use crate::vfs;
 use alloc::str;
 
+use alloc::borrow::Cow;
+use core::cmp::Ordering;
 use core::fmt::{Debug, Error, Formatter};
-use core::mem::{size_of, size_of_val};
+use core::hash::{Hash, Hasher};
+use core::mem::{offset_of, size_of, size_of_val, ManuallyDrop, MaybeUninit};
 use core::slice;
 use rcore_fs::vfs::Timespec;
 use static_assertions::const_assert;
@@ -23,6 +26,25 @@ pub struct SuperBlock {
     pub info: Str32,
     /// number of freemap blocks
     pub freemap_blocks: u32,
+    /// on-disk format version, 0 for images made before it was added
+    pub version: u32,
+    /// volume uuid, valid since VERSION_UUID
+    pub uuid: [u8; 16],
+    /// blocks holding backup copies of the superblock, valid since VERSION_BACKUP
+    pub backup_blocks: [u32; 2],
+    /// `STATE_CLEAN` or `STATE_DIRTY`, valid since VERSION_STATE
+    pub state: u32,
+    /// block of the root inode, valid since VERSION_LAYOUT
+    pub root_block: u32,
+    /// number of blocks after the freemap never used by the fs, valid since
+    /// VERSION_LAYOUT
+    pub reserved_blocks: u32,
+    /// how names are compared, `NAME_POLICY_EXACT`, `NAME_POLICY_ASCII_CASE_FOLD`
+    /// or a custom id, valid since VERSION_NAMES
+    pub name_policy: u32,
+    /// number of free blocks only allocated to delete or repair, valid
+    /// since VERSION_RECOVERY
+    pub recovery_blocks: u32,
 }
 
 /// inode (on disk)
@@ -39,20 +61,44 @@ pub struct DiskINode {
     pub nlinks: u16,
     /// number of blocks
     pub blocks: u32,
-    /// direct blocks
+    /// direct blocks.
+    /// With `INODE_INLINE`, the content is here up to `db_indirect`.
     pub direct: [u32; NDIRECT],
     /// indirect blocks
     pub indirect: u32,
     /// double indirect blocks
     pub db_indirect: u32,
-    /// device inode id for char/block device (major, minor)
-    pub device_inode_id: usize,
+    /// device number for char/block device, packed by `make_rdev()`.
+    /// Images made before it was standardized store whatever key was
+    /// passed to `new_device_inode()` here.
+    pub rdev: usize,
     /// Time of last access
     pub atime: Timespec,
     /// Time of last modification
     pub mtime: Timespec,
     /// Time of last change
     pub ctime: Timespec,
+    /// bits of `InodeFlags`, `INODE_INLINE` and `INODE_COMPRESSED`, valid
+    /// since VERSION_FLAGS
+    pub flags: u32,
+    /// root block of the hashed index of a dir, 0 if none.
+    /// Valid since VERSION_INDEX.
+    pub index: u32,
+    /// permission bits, valid since VERSION_OWNER
+    pub mode: u16,
+    /// 0, in place of padding, so that images are reproducible
+    pub pad0: u16,
+    /// owner, valid since VERSION_OWNER
+    pub uid: u32,
+    /// group, valid since VERSION_OWNER
+    pub gid: u32,
+    /// 0, in place of padding, as `pad0`
+    pub pad1: u32,
+    /// changes of the content, see `INode::data_version()`.
+    /// Valid since VERSION_DATA_VERSION.
+    pub data_version: u64,
+    /// Time of creation, never changed after, valid since VERSION_BTIME
+    pub btime: Timespec,
 }
 
 /*
@@ -69,6 +115,27 @@ pub struct IndirectBlock {
     pub entries: [u32; BLK_NENTRY],
 }
 
+/// root block of the hashed index of a dir (on disk)
+#[repr(C)]
+pub struct DirIndexRoot {
+    /// number of buckets, a name is in bucket `hash % nbuckets` of its
+    /// `NameOps::hash()` by the name policy of the fs
+    pub nbuckets: u32,
+    /// block of each bucket, 0 if the bucket is empty
+    pub buckets: [u32; INDEX_MAX_BUCKETS],
+}
+
+/// bucket block of the hashed index of a dir (on disk)
+#[repr(C)]
+pub struct DirIndexBucket {
+    /// number of slots in use
+    pub count: u32,
+    /// non-zero if some names of the bucket did not fit
+    pub overflow: u32,
+    /// (name hash, entry id)
+    pub slots: [[u32; 2]; INDEX_BUCKET_SLOTS],
+}
+
 /// file entry (on disk)
 #[repr(C)]
 #[derive(Debug)]
@@ -85,52 +152,126 @@ pub struct Str256(pub [u8; 256]);
 #[repr(C)]
 pub struct Str32(pub [u8; 32]);
 
-impl AsRef<str> for Str256 {
-    fn as_ref(&self) -> &str {
-        let len = self.0.iter().enumerate().find(|(_, &b)| b == 0).unwrap().0;
-        str::from_utf8(&self.0[0..len]).unwrap()
-    }
-}
+/// Implement NUL terminated string helpers for `$Str` of `$N` bytes
+macro_rules! impl_str {
+    ($Str:ident, $N:expr) => {
+        impl $Str {
+            /// Make from `s`. Fail if it is too long or has a NUL byte.
+            pub fn new(s: &str) -> vfs::Result<Self> {
+                Self::from_bytes(s.as_bytes())
+            }
+            /// Make from raw bytes. Fail if it is too long or has a NUL byte.
+            pub fn from_bytes(bytes: &[u8]) -> vfs::Result<Self> {
+                if bytes.len() >= $N || bytes.contains(&0) {
+                    return Err(vfs::FsError::InvalidParam);
+                }
+                let mut ret = [0u8; $N];
+                ret[..bytes.len()].copy_from_slice(bytes);
+                Ok($Str(ret))
+            }
+            /// Bytes before the first NUL, bytes after it are ignored
+            pub fn as_bytes(&self) -> &[u8] {
+                match self.0.iter().position(|&b| b == 0) {
+                    Some(len) => &self.0[..len],
+                    None => &self.0[..],
+                }
+            }
+            /// Whether it holds a NUL terminated UTF-8 string
+            pub fn is_valid(&self) -> bool {
+                self.0.contains(&0) && str::from_utf8(self.as_bytes()).is_ok()
+            }
+        }
 
-impl AsRef<str> for Str32 {
-    fn as_ref(&self) -> &str {
-        let len = self.0.iter().enumerate().find(|(_, &b)| b == 0).unwrap().0;
-        str::from_utf8(&self.0[0..len]).unwrap()
-    }
-}
+        impl AsRef<str> for $Str {
+            /// Only the valid UTF-8 prefix is returned for corrupted bytes
+            fn as_ref(&self) -> &str {
+                let bytes = self.as_bytes();
+                match str::from_utf8(bytes) {
+                    Ok(s) => s,
+                    Err(e) => str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+                }
+            }
+        }
 
-impl Debug for Str256 {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{}", self.as_ref())
-    }
-}
+        impl Debug for $Str {
+            fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+                write!(f, "{}", self.as_ref())
+            }
+        }
 
-impl Debug for Str32 {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{}", self.as_ref())
-    }
-}
+        impl<'a> From<&'a str> for $Str {
+            /// Panic if `s` is invalid, use `new()` for names from users
+            #[allow(clippy::expect_used)]
+            fn from(s: &'a str) -> Self {
+                // only for names known to be valid, e.g. "." and ".."
+                Self::new(s).expect("invalid string")
+            }
+        }
 
-impl<'a> From<&'a str> for Str256 {
-    fn from(s: &'a str) -> Self {
-        let mut ret = [0u8; 256];
-        ret[0..s.len()].copy_from_slice(s.as_ref());
-        Str256(ret)
-    }
-}
+        impl PartialEq<str> for $Str {
+            fn eq(&self, other: &str) -> bool {
+                // check the length first: `other` must be followed by NUL
+                let len = other.len();
+                len < $N && self.0[len] == 0 && &self.0[..len] == other.as_bytes()
+            }
+        }
 
-impl<'a> From<&'a str> for Str32 {
-    fn from(s: &'a str) -> Self {
-        let mut ret = [0u8; 32];
-        ret[0..s.len()].copy_from_slice(s.as_ref());
-        Str32(ret)
-    }
+        impl PartialEq for $Str {
+            fn eq(&self, other: &Self) -> bool {
+                self.as_bytes() == other.as_bytes()
+            }
+        }
+
+        impl Eq for $Str {}
+
+        impl PartialOrd for $Str {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Ord for $Str {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.as_bytes().cmp(other.as_bytes())
+            }
+        }
+
+        impl Hash for $Str {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                self.as_bytes().hash(state)
+            }
+        }
+    };
 }
 
+impl_str!(Str256, 256);
+impl_str!(Str32, 32);
+
 impl SuperBlock {
     pub fn check(&self) -> bool {
-        self.magic == MAGIC
+        self.magic == MAGIC && self.version <= VERSION && self.info.is_valid()
+    }
+    /// Whether this is the superblock of an image written with the other
+    /// byte order, by a tool not converting to little-endian
+    pub fn is_byte_swapped(&self) -> bool {
+        self.magic == MAGIC.swap_bytes()
     }
+    /// Whether this is a valid backup copy stored in block `id`
+    pub fn check_backup(&self, id: BlockId) -> bool {
+        self.check()
+            && self.version >= VERSION_BACKUP
+            && self.backup_blocks == backup_super_blocks(self.blocks as usize)
+            && self.backup_blocks.contains(&(id as u32))
+    }
+}
+
+/// Locations of the backup superblocks in a fs of `blocks` blocks:
+/// the middle block and the last block.
+///
+/// They only depend on the size of fs, so a fs spanning the whole device
+/// can find them without the primary superblock.
+pub const fn backup_super_blocks(blocks: usize) -> [u32; 2] {
+    [(blocks / 2) as u32, (blocks - 1) as u32]
 }
 
 impl DiskINode {
@@ -143,10 +284,19 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            flags: 0,
+            index: 0,
+            mode: DEFAULT_MODE,
+            pad0: 0,
+            uid: 0,
+            gid: 0,
+            pad1: 0,
+            data_version: 0,
+            btime: Timespec { sec: 0, nsec: 0 },
         }
     }
     pub const fn new_symlink() -> Self {
@@ -158,10 +308,19 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            flags: 0,
+            index: 0,
+            mode: DEFAULT_MODE,
+            pad0: 0,
+            uid: 0,
+            gid: 0,
+            pad1: 0,
+            data_version: 0,
+            btime: Timespec { sec: 0, nsec: 0 },
         }
     }
     pub const fn new_dir() -> Self {
@@ -173,46 +332,274 @@ impl DiskINode {
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id: NODEVICE,
+            rdev: NODEVICE,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            flags: 0,
+            index: 0,
+            mode: DEFAULT_MODE,
+            pad0: 0,
+            uid: 0,
+            gid: 0,
+            pad1: 0,
+            data_version: 0,
+            btime: Timespec { sec: 0, nsec: 0 },
+        }
+    }
+    /// Read from `bytes` as stored on disk, `None` if the type is not one
+    /// of `FileType`
+    pub fn from_bytes(bytes: &[u8; size_of::<DiskINode>()]) -> Option<Self> {
+        let offset = offset_of!(DiskINode, type_);
+        let type_ = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        if type_ > FileType::BlockDevice as u16 {
+            return None;
+        }
+        let mut disk_inode = DiskINode::new_file();
+        disk_inode.as_buf_mut().copy_from_slice(bytes);
+        disk_inode.convert_le();
+        Some(disk_inode)
+    }
+    pub fn is_inline(&self) -> bool {
+        self.flags & INODE_INLINE != 0
+    }
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_COMPRESSED != 0
+    }
+    /// The content stored inline, zeros past the size
+    pub fn inline_data(&self) -> [u8; MAX_INLINE_SIZE] {
+        let mut data = [0; MAX_INLINE_SIZE];
+        let pointers = [self.indirect, self.db_indirect];
+        let words = self.direct.iter().chain(pointers.iter());
+        for (bytes, word) in data.chunks_exact_mut(ENTRY_SIZE).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+    /// Resize the content stored inline to `len`, at most `MAX_INLINE_SIZE`,
+    /// zeroing what is cut
+    pub fn resize_inline(&mut self, len: usize) {
+        debug_assert!(len <= MAX_INLINE_SIZE);
+        let len = len.min(MAX_INLINE_SIZE);
+        let size = (self.size as usize).min(MAX_INLINE_SIZE);
+        if len < size {
+            let mut data = self.inline_data();
+            data[len..size].fill(0);
+            self.set_inline_data(&data);
+        }
+        self.size = len as u32;
+    }
+    /// Store `data` inline, over the block pointers
+    pub fn set_inline_data(&mut self, data: &[u8; MAX_INLINE_SIZE]) {
+        let words = data
+            .chunks_exact(ENTRY_SIZE)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+        let pointers = self.direct.iter_mut();
+        let pointers = pointers.chain([&mut self.indirect, &mut self.db_indirect]);
+        for (pointer, word) in pointers.zip(words) {
+            *pointer = word;
         }
     }
-    pub const fn new_chardevice(device_inode_id: usize) -> Self {
+    pub const fn new_chardevice(rdev: usize) -> Self {
+        Self::new_device(FileType::CharDevice, rdev)
+    }
+    pub const fn new_device(type_: FileType, rdev: usize) -> Self {
         DiskINode {
             size: 0,
-            type_: FileType::CharDevice,
+            type_,
             nlinks: 0,
             blocks: 0,
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
-            device_inode_id,
+            rdev,
             atime: Timespec { sec: 0, nsec: 0 },
             mtime: Timespec { sec: 0, nsec: 0 },
             ctime: Timespec { sec: 0, nsec: 0 },
+            flags: 0,
+            index: 0,
+            mode: DEFAULT_MODE,
+            pad0: 0,
+            uid: 0,
+            gid: 0,
+            pad1: 0,
+            data_version: 0,
+            btime: Timespec { sec: 0, nsec: 0 },
         }
     }
 }
 
-/// Convert structs to [u8] slice
+impl DiskEntry {
+    /// Byte of `name` holding the type of the inode, after the NUL of names
+    /// shorter than `MAX_FNAME_LEN`. 0 means unknown, as in older images.
+    const TYPE_HINT: usize = MAX_FNAME_LEN;
+
+    pub fn new(id: u32, name: Str256, type_: FileType) -> Self {
+        let mut entry = DiskEntry { id, name };
+        if entry.name.as_bytes().len() < MAX_FNAME_LEN {
+            entry.name.0[Self::TYPE_HINT] = type_ as u8;
+        }
+        entry
+    }
+    /// Type of the inode if recorded, so that it need not be loaded
+    pub fn type_hint(&self) -> Option<FileType> {
+        if self.name.as_bytes().len() >= MAX_FNAME_LEN {
+            return None;
+        }
+        match self.name.0[Self::TYPE_HINT] {
+            1 => Some(FileType::File),
+            2 => Some(FileType::Dir),
+            3 => Some(FileType::SymLink),
+            4 => Some(FileType::CharDevice),
+            5 => Some(FileType::BlockDevice),
+            _ => None,
+        }
+    }
+}
+
+/// Hash of a name in the hashed index of a dir, 32-bit FNV-1a as the
+/// default `NameOps::hash()`, for names not UTF-8
+pub fn name_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Convert structs to [u8] slice.
+///
+/// Integers are little-endian on disk: the bytes read into `as_buf_mut()`
+/// are converted by `convert_le()`, and `to_disk()` gives the bytes to write.
 pub trait AsBuf {
+    /// All zero, to read into by `as_buf_mut()`. Implemented for plain data
+    /// only, for which zero is a valid value of every field.
+    fn zeroed() -> Self
+    where
+        Self: Sized,
+    {
+        unsafe { MaybeUninit::zeroed().assume_init() }
+    }
     fn as_buf(&self) -> &[u8] {
         unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of_val(self)) }
     }
     fn as_buf_mut(&mut self) -> &mut [u8] {
         unsafe { slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of_val(self)) }
     }
+    /// Convert the integers between native byte order and little-endian,
+    /// either way. Nothing to do on little-endian targets or for bytes.
+    fn convert_le(&mut self) {}
+    /// The bytes of this on disk
+    fn to_disk(&self) -> Cow<'_, [u8]>
+    where
+        Self: Sized,
+    {
+        if cfg!(target_endian = "little") {
+            return Cow::Borrowed(self.as_buf());
+        }
+        // a bitwise copy, never dropped
+        let mut copy = ManuallyDrop::new(unsafe { core::ptr::read(self) });
+        copy.convert_le();
+        Cow::Owned(copy.as_buf().to_vec())
+    }
+}
+
+/// Convert each integer of `fields` like `AsBuf::convert_le()`
+macro_rules! convert_le {
+    ($($field:expr),* $(,)?) => {
+        $($field = $field.to_le();)*
+    };
+}
+
+impl AsBuf for SuperBlock {
+    fn convert_le(&mut self) {
+        convert_le!(
+            self.magic,
+            self.blocks,
+            self.unused_blocks,
+            self.freemap_blocks,
+            self.version,
+            self.root_block,
+            self.reserved_blocks,
+            self.name_policy,
+            self.recovery_blocks,
+        );
+        for block in self.backup_blocks.iter_mut() {
+            convert_le!(*block);
+        }
+    }
 }
 
-impl AsBuf for SuperBlock {}
+impl AsBuf for DiskINode {
+    fn convert_le(&mut self) {
+        if cfg!(target_endian = "big") {
+            // an enum, swapped as bytes
+            let offset = offset_of!(DiskINode, type_);
+            self.as_buf_mut()[offset..offset + size_of::<FileType>()].reverse();
+        }
+        convert_le!(
+            self.size,
+            self.nlinks,
+            self.blocks,
+            self.indirect,
+            self.db_indirect,
+            self.rdev,
+            self.atime.sec,
+            self.atime.nsec,
+            self.mtime.sec,
+            self.mtime.nsec,
+            self.ctime.sec,
+            self.ctime.nsec,
+            self.flags,
+            self.index,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.data_version,
+            self.btime.sec,
+            self.btime.nsec,
+        );
+        for block in self.direct.iter_mut() {
+            convert_le!(*block);
+        }
+    }
+}
 
-impl AsBuf for DiskINode {}
+impl AsBuf for DiskEntry {
+    fn convert_le(&mut self) {
+        convert_le!(self.id);
+    }
+}
 
-impl AsBuf for DiskEntry {}
+impl AsBuf for IndirectBlock {
+    fn convert_le(&mut self) {
+        for entry in self.entries.iter_mut() {
+            convert_le!(*entry);
+        }
+    }
+}
 
-impl AsBuf for u32 {}
+impl AsBuf for DirIndexRoot {
+    fn convert_le(&mut self) {
+        convert_le!(self.nbuckets);
+        for bucket in self.buckets.iter_mut() {
+            convert_le!(*bucket);
+        }
+    }
+}
+
+impl AsBuf for DirIndexBucket {
+    fn convert_le(&mut self) {
+        convert_le!(self.count, self.overflow);
+        for slot in self.slots.iter_mut() {
+            convert_le!(slot[0], slot[1]);
+        }
+    }
+}
+
+impl AsBuf for u32 {
+    fn convert_le(&mut self) {
+        convert_le!(*self);
+    }
+}
 
 /*
  * Simple FS (SFS) definitions visible to ucore. This covers the on-disk format
@@ -225,24 +612,91 @@ pub const NODEVICE: usize = 100;
 
 /// magic number for sfs
 pub const MAGIC: u32 = 0x2f8dbe2b;
+/// current on-disk format version
+pub const VERSION: u32 = VERSION_BTIME;
+/// first version with the volume uuid in superblock
+pub const VERSION_UUID: u32 = 1;
+/// first version with backup copies of superblock
+pub const VERSION_BACKUP: u32 = 2;
+/// first version with inode flags
+pub const VERSION_FLAGS: u32 = 3;
+/// first version with hashed indexes of large dirs
+pub const VERSION_INDEX: u32 = 4;
+/// first version with mode, uid and gid of inodes
+pub const VERSION_OWNER: u32 = 5;
+/// first version with the content of small files inline, see `INODE_INLINE`
+pub const VERSION_INLINE: u32 = 6;
+/// first version with compressed files, see `INODE_COMPRESSED`
+pub const VERSION_COMPRESSED: u32 = 7;
+/// first version with the mount state in superblock
+pub const VERSION_STATE: u32 = 8;
+/// first version with the data version of inodes
+pub const VERSION_DATA_VERSION: u32 = 9;
+/// first version with the root block and reserved blocks in superblock
+pub const VERSION_LAYOUT: u32 = 10;
+/// first version with the name policy in superblock
+pub const VERSION_NAMES: u32 = 11;
+/// first version with the recovery reserve in superblock
+pub const VERSION_RECOVERY: u32 = 12;
+/// first version with the creation time of inodes
+pub const VERSION_BTIME: u32 = 13;
+/// mount state of an image cleanly unmounted
+pub const STATE_CLEAN: u32 = 0;
+/// mount state of an image in use, or not unmounted since it was
+pub const STATE_DIRTY: u32 = 1;
+/// names compared byte by byte, as in images before VERSION_NAMES
+pub const NAME_POLICY_EXACT: u32 = 0;
+/// names compared ignoring the case of ASCII letters
+pub const NAME_POLICY_ASCII_CASE_FOLD: u32 = 1;
+/// first id of the name policies not built in, see `NamePolicy`
+pub const NAME_POLICY_CUSTOM: u32 = 0x100;
+/// mode of inodes in images before VERSION_OWNER
+pub const DEFAULT_MODE: u16 = 0o777;
 /// size of block
 pub const BLKSIZE: usize = 1usize << BLKSIZE_LOG2;
 /// log2( size of block )
 pub const BLKSIZE_LOG2: u8 = 12;
 /// number of direct blocks in inode
 pub const NDIRECT: usize = 12;
+/// bit of `DiskINode::flags` set when the content is stored in place of the
+/// block pointers, `blocks` being 0
+pub const INODE_INLINE: u32 = 1 << 31;
+/// bit of `DiskINode::flags` set when the content of a file is stored
+/// compressed, in chunks listed by a chunk table. `size` is the size
+/// uncompressed, `blocks` the blocks holding the table and chunks.
+pub const INODE_COMPRESSED: u32 = 1 << 30;
+/// bytes of content in each chunk of a compressed file, the last one may
+/// be shorter
+pub const COMPRESS_CHUNK_SIZE: usize = 8 * BLKSIZE;
+/// magic number of the chunk table of a compressed file
+pub const CHUNK_TABLE_MAGIC: u32 = 0x4b4e_4843;
+/// max size of the content stored inline
+pub const MAX_INLINE_SIZE: usize = (NDIRECT + 2) * ENTRY_SIZE;
 /// default sfs infomation string
 pub const DEFAULT_INFO: &str = "simple file system";
 /// max length of infomation
 pub const MAX_INFO_LEN: usize = 31;
 /// max length of filename
 pub const MAX_FNAME_LEN: usize = 255;
+/// max number of links to an inode, limited by `DiskINode::nlinks`
+#[cfg(not(test))]
+pub const LINK_MAX: usize = u16::MAX as usize;
+/// max number of links to an inode, reduced to keep tests fast
+#[cfg(test)]
+pub const LINK_MAX: usize = 1024;
+/// number of entries from which a dir gets a hashed index
+pub const INDEX_THRESHOLD: usize = 1024;
+/// max number of buckets of a hashed index
+pub const INDEX_MAX_BUCKETS: usize = BLKSIZE / 4 - 1;
+/// number of slots in a bucket of a hashed index
+pub const INDEX_BUCKET_SLOTS: usize = BLKSIZE / 8 - 1;
 /// max file size in theory (48KB + 4MB + 4GB)
 /// however, the file size is stored in u32
 pub const MAX_FILE_SIZE: usize = 0xffffffff;
 /// block the superblock lives in
 pub const BLKN_SUPER: BlockId = 0;
-/// location of the root dir inode
+/// location of the root dir inode, unless moved by
+/// `CreateOptions::root_block`
 pub const BLKN_ROOT: BlockId = 1;
 /// 1st block of the freemap
 pub const BLKN_FREEMAP: BlockId = 2;
@@ -277,4 +731,6 @@ const_assert!(size_of::<SuperBlock>() <= BLKSIZE);
 const_assert!(size_of::<DiskINode>() <= BLKSIZE);
 const_assert!(size_of::<DiskEntry>() <= BLKSIZE);
 const_assert!(size_of::<IndirectBlock>() == BLKSIZE);
+const_assert!(size_of::<DirIndexRoot>() == BLKSIZE);
+const_assert!(size_of::<DirIndexBucket>() == BLKSIZE);
 const_assert!(DEFAULT_INFO.len() <= MAX_INFO_LEN);
@@ -1,27 +1,23 @@
 extern crate std;
 
 use crate::*;
-use rcore_fs::vfs::{FileSystem, FileType, Metadata, Result, Timespec};
-use std::fs::{self, OpenOptions};
+use rcore_fs::dev::{
+    BlockDevice, DevError, Device, TimedConfig, TimedDevice, WearTrackingDevice, WindowedDevice,
+};
+use rcore_fs::vfs::{
+    CreateSpec, DirCursor, DirEntrySlot, FallocateMode, FileSystem, FileType, FsCapabilities,
+    INode, InodeFlags, Metadata, MetadataFlags, Result, Timespec,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::ControlFlow;
 
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
 
-fn _open_sample_file() -> Arc<SimpleFileSystem> {
-    fs::copy("sfs.img", "test.img").expect("failed to open sfs.img");
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open("test.img")
-        .expect("failed to open test.img");
-    SimpleFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open SFS")
-}
+mod common;
 
-fn _create_new_sfs() -> Arc<SimpleFileSystem> {
-    let file = tempfile::tempfile().expect("failed to create file");
-    SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096 * 4096)
-        .expect("failed to create SFS")
-}
+use self::common::*;
 
 #[test]
 #[ignore]
@@ -54,10 +50,12 @@ fn create_file() -> Result<()> {
             nlinks: 1,
             uid: 0,
             ctime: Timespec { sec: 0, nsec: 0 },
+            btime: Timespec { sec: 0, nsec: 0 },
             gid: 0,
             blk_size: 4096,
-            dev: 0,
-            rdev: 100, // dummo why 100 here, maybe legacy data?
+            dev: sfs.instance_id() as usize,
+            rdev: 0,
+            flags: MetadataFlags::empty(),
         }
     );
 
@@ -76,7 +74,7 @@ fn resize() -> Result<()> {
     const SIZE2: usize = 0x1250;
     file1.resize(SIZE1)?;
     assert_eq!(file1.metadata()?.size, SIZE1, "wrong size after resize");
-    let mut data1: [u8; SIZE2] = unsafe { MaybeUninit::uninit().assume_init() };
+    let mut data1 = [0xffu8; SIZE2];
     let len = file1.read_at(0, data1.as_mut())?;
     assert_eq!(len, SIZE1, "wrong size returned by read_at()");
     assert_eq!(
@@ -535,6 +533,81 @@ fn nlinks() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn nlinks_limit() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let free = sfs.info().bfree;
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let file = root.create("file", FileType::File, 0o777)?;
+    file.write_at(0, b"data")?;
+    for i in 1..LINK_MAX {
+        dir.link(&format!("link{}", i), &file)?;
+    }
+    assert_eq!(file.metadata()?.nlinks, LINK_MAX);
+    let size = dir.metadata()?.size;
+    assert_eq!(dir.link("more", &file), Err(FsError::TooManyLinks));
+    assert_eq!(dir.find("more").err(), Some(FsError::EntryNotFound));
+    assert_eq!(dir.metadata()?.size, size);
+    assert_eq!(file.metadata()?.nlinks, LINK_MAX);
+
+    // ".." of subdirectories, root has "." and ".." of its own
+    for i in 3..LINK_MAX {
+        root.create(&format!("sub{}", i), FileType::Dir, 0o777)?;
+    }
+    assert_eq!(root.metadata()?.nlinks, LINK_MAX);
+    let size = root.metadata()?.size;
+    assert_eq!(
+        root.create("more", FileType::Dir, 0o777).err(),
+        Some(FsError::TooManyLinks)
+    );
+    let other = dir.create("other", FileType::Dir, 0o777)?;
+    assert_eq!(
+        dir.move_("other", &root, "other"),
+        Err(FsError::TooManyLinks)
+    );
+    let specs = [CreateSpec {
+        name: "more",
+        type_: FileType::Dir,
+        mode: 0o777,
+        data: 0,
+    }];
+    assert_eq!(root.create_batch(&specs).err(), Some(FsError::TooManyLinks));
+    assert_eq!(root.metadata()?.size, size);
+    assert_eq!(
+        dir.find("other")?.metadata()?.inode,
+        other.metadata()?.inode
+    );
+    // files are fine
+    root.create("more", FileType::File, 0o777)?;
+    root.unlink("more")?;
+    for i in 3..LINK_MAX {
+        root.unlink(&format!("sub{}", i))?;
+    }
+    dir.unlink("other")?;
+    drop(other);
+
+    // released only when the last link goes away
+    root.unlink("file")?;
+    for i in 1..LINK_MAX - 1 {
+        dir.unlink(&format!("link{}", i))?;
+    }
+    drop(file);
+    let last = format!("link{}", LINK_MAX - 1);
+    let file = dir.find(&last)?;
+    assert_eq!(file.metadata()?.nlinks, 1);
+    let mut buf = [0u8; 4];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"data");
+    drop(file);
+    assert!(sfs.info().bfree < free);
+    dir.unlink(&last)?;
+    root.unlink("dir")?;
+    drop(dir);
+    assert_eq!(sfs.info().bfree, free);
+    Ok(())
+}
+
 #[test]
 fn create_then_get_entry() -> Result<()> {
     let sfs = _create_new_sfs();
@@ -566,3 +639,6290 @@ fn create_then_get_entry() -> Result<()> {
     sfs.sync()?;
     Ok(())
 }
+
+#[test]
+fn volume_label_and_uuid() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let reopen = || {
+        let file = file.try_clone().expect("failed to clone file");
+        SimpleFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open SFS")
+    };
+
+    let sfs = SimpleFileSystem::create(
+        Arc::new(Mutex::new(file.try_clone().unwrap())),
+        32 * 4096 * 4096,
+    )?;
+    sfs.set_label("boot")?;
+    assert_eq!(
+        sfs.set_label(&"x".repeat(MAX_INFO_LEN + 1)),
+        Err(FsError::InvalidParam)
+    );
+    let uuid = sfs.uuid();
+    assert_ne!(uuid, [0; 16]);
+    drop(sfs);
+
+    let sfs = reopen();
+    assert_eq!(sfs.label(), "boot");
+    assert_eq!(sfs.uuid(), uuid);
+    sfs.set_label("data")?;
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = reopen();
+    let info = sfs.volume_info().unwrap();
+    assert_eq!(info.label, "data");
+    assert_eq!(info.uuid, uuid);
+
+    assert_ne!(_create_new_sfs().uuid(), _create_new_sfs().uuid());
+    Ok(())
+}
+
+#[test]
+fn uuid_from_clock() -> Result<()> {
+    let create = |sec: i64| -> Result<[u8; 16]> {
+        let opts = SfsOptions::new()
+            .time_provider(TestClock::new(sec, 0))
+            .build()?;
+        let device = MemDevice::new(16);
+        Ok(SimpleFileSystem::create_with(Arc::new(device), 16 * BLKSIZE, &opts)?.uuid())
+    };
+    let (uuid1, uuid2) = (create(100)?, create(100)?);
+    assert_ne!(uuid1, uuid2);
+    assert_eq!(uuid1[6] >> 4, 4);
+    assert_eq!(uuid2[8] >> 6, 0b10);
+
+    // the first fs of two processes, or of two boots
+    let first = |sec| uuid_from_seed(uuid_seed(Timespec { sec, nsec: 0 }, 0));
+    assert_ne!(first(1_700_000_000), first(1_700_000_001));
+
+    // without a clock, the fs of a process still differ
+    let device: Arc<dyn Device> = Arc::new(MemDevice::new(16));
+    let seed = |count| uuid_seed_without_clock(&device, 16 * BLKSIZE, count);
+    assert_ne!(seed(0), seed(1));
+    Ok(())
+}
+
+#[test]
+fn inode_cache_bounded() -> Result<()> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    let sfs = SimpleFileSystem::create(
+        Arc::new(Mutex::new(file.try_clone().unwrap())),
+        16 * 1024 * 4096,
+    )?;
+    let root = sfs.root_inode();
+    for i in 0..100 {
+        let dir = root.create(&format!("{}", i), FileType::Dir, 0o777)?;
+        for j in 0..100 {
+            dir.create(&format!("{}", j), FileType::File, 0o777)?;
+        }
+    }
+    sfs.sync()?;
+
+    sfs.set_inode_cache_size(64);
+    for i in 0..100 {
+        for j in 0..100 {
+            root.lookup(&format!("{}/{}", i, j))?;
+            assert!(sfs.stats().inode_cache_size <= 64);
+        }
+    }
+    assert!(sfs.stats().inode_table_size <= 64 + 1);
+    sfs.set_inode_cache_size(0);
+    assert_eq!(sfs.stats().inode_cache_size, 0);
+    assert_eq!(sfs.stats().inode_table_size, 1);
+
+    // dirty inode is written back when evicted
+    sfs.set_inode_cache_size(1);
+    let file1 = root.lookup("0/0")?;
+    let mut metadata = file1.metadata()?;
+    metadata.mtime = Timespec { sec: 42, nsec: 0 };
+    file1.set_metadata(&metadata)?;
+    root.lookup("0/1")?;
+    let id = file1.metadata()?.inode;
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file.try_clone().unwrap()));
+    let disk_inode = device.load_struct::<DiskINode>(id)?;
+    assert_eq!(disk_inode.mtime.sec, 42);
+    sfs.set_inode_cache_size(0);
+    Ok(())
+}
+
+#[test]
+fn inode_cache_emptied_by_unmount() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    for i in 0..8 {
+        root.create(&i.to_string(), FileType::File, 0o644)?;
+    }
+    sfs.set_inode_cache_size(4);
+    for i in 0..8 {
+        root.find(&i.to_string())?;
+    }
+    assert_eq!(sfs.stats().inode_cache_size, 4);
+
+    sfs.unmount()?;
+    assert_eq!(sfs.stats().inode_cache_size, 0);
+    assert_eq!(sfs.options().inode_cache_size, 0);
+    let weak = Arc::downgrade(&sfs);
+    drop(root);
+    drop(sfs);
+    assert!(weak.upgrade().is_none());
+    Ok(())
+}
+
+#[test]
+fn open_write_protected_device() -> Result<()> {
+    let device = Arc::new(MockDevice::new(1024));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    sfs.root_inode()
+        .create("file1", FileType::File, 0o777)?
+        .write_at(0, b"hello")?;
+    drop(sfs);
+
+    device.set_protected(true);
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    assert!(sfs.is_read_only());
+    let root = sfs.root_inode();
+    let file1 = root.find("file1")?;
+    let mut buf = [0u8; 5];
+    file1.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    assert_eq!(file1.write_at(0, b"world"), Err(FsError::ReadOnly));
+    assert_eq!(
+        root.create("file2", FileType::File, 0o777).err(),
+        Some(FsError::ReadOnly)
+    );
+    assert_eq!(root.unlink("file1"), Err(FsError::ReadOnly));
+    assert_eq!(sfs.set_label("x"), Err(FsError::ReadOnly));
+    Ok(())
+}
+
+#[test]
+fn write_fails_when_device_becomes_protected() -> Result<()> {
+    let device = Arc::new(MockDevice::new(1024));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    file1.write_at(0, &[1; 100])?;
+    sfs.sync()?;
+    let unused_blocks = sfs.info().bfree;
+
+    device.set_protected(true);
+    assert_eq!(file1.write_at(0x10000, &[2; 100]), Err(FsError::ReadOnly));
+    let metadata = file1.metadata()?;
+    assert_eq!(metadata.size, 100);
+    assert_eq!(metadata.blocks, 1);
+    assert_eq!(sfs.info().bfree, unused_blocks);
+
+    device.set_protected(false);
+    Ok(())
+}
+
+#[test]
+fn open_with_backup_super_block() -> Result<()> {
+    let device = Arc::new(MockDevice::new(1024));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    sfs.set_label("backup")?;
+    sfs.root_inode()
+        .create("file1", FileType::File, 0o777)?
+        .write_at(0, b"hello")?;
+    sfs.sync()?;
+    let bfree = sfs.info().bfree;
+    drop(sfs);
+    let primary = device.mem.0.lock().unwrap()[..BLKSIZE].to_vec();
+
+    device.mem.0.lock().unwrap()[..BLKSIZE].fill(0);
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    assert_eq!(sfs.label(), "backup");
+    assert_eq!(sfs.info().bfree, bfree);
+    let mut buf = [0u8; 5];
+    sfs.root_inode().find("file1")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"hello");
+    sfs.sync()?;
+    assert_eq!(device.mem.0.lock().unwrap()[..BLKSIZE], primary[..]);
+    drop(sfs);
+
+    device.mem.0.lock().unwrap()[..BLKSIZE].fill(0);
+    SimpleFileSystem::restore_superblock(device.clone())?;
+    assert_eq!(device.mem.0.lock().unwrap()[..BLKSIZE], primary[..]);
+    Ok(())
+}
+
+#[test]
+fn backup_super_blocks_never_allocated() -> Result<()> {
+    let device = Arc::new(MockDevice::new(256));
+    let sfs = SimpleFileSystem::create(device, 256 * 4096)?;
+    let backups = backup_super_blocks(256);
+    let mut allocated = 0;
+    while let Some(id) = sfs.alloc_block(None) {
+        assert!(!backups.contains(&(id as u32)));
+        allocated += 1;
+    }
+    // all blocks except superblock, root, its entries, freemap, backups
+    // and the recovery reserve
+    assert_eq!(allocated, 256 - 4 - backups.len() - DEFAULT_RECOVERY_BLOCKS);
+    Ok(())
+}
+
+/// Blocks reachable from the tree, plus the blocks reserved by the fs
+fn used_blocks(sfs: &Arc<SimpleFileSystem>) -> Result<BTreeSet<BlockId>> {
+    let mut used: BTreeSet<BlockId> = (0..BLKN_FREEMAP + 1).collect();
+    used.extend(
+        sfs.super_block
+            .read()
+            .backup_blocks
+            .iter()
+            .map(|&id| id as BlockId),
+    );
+    let mut ids = Vec::new();
+    sfs.for_each_inode(|id, _| {
+        ids.push(id);
+        ControlFlow::Continue(())
+    })?;
+    for id in ids {
+        let inode = sfs.get_inode(id)?;
+        used.insert(id);
+        let disk_inode = inode.disk_inode.read();
+        let blocks = disk_inode.blocks as usize;
+        if blocks >= MAX_NBLOCK_DIRECT {
+            used.insert(disk_inode.indirect as BlockId);
+        }
+        assert!(blocks < MAX_NBLOCK_INDIRECT, "not checked here");
+        drop(disk_inode);
+        for i in 0..blocks {
+            assert!(used.insert(inode.get_disk_block_id(i)?));
+        }
+    }
+    Ok(used)
+}
+
+#[test]
+fn dir_shrink_does_not_leak_blocks() -> Result<()> {
+    const BLOCKS: usize = 1024;
+    let device = Arc::new(MockDevice::new(BLOCKS));
+    let sfs = SimpleFileSystem::create(device, BLOCKS * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    sfs.sync()?;
+    let bfree = sfs.info().bfree;
+
+    // xorshift, so that entries are removed from everywhere in the dir
+    let mut state = 0x2545f491u32;
+    let mut rand = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+    let mut names = Vec::new();
+    for i in 0..5000 {
+        // grow up to ~400 entries, i.e. beyond the direct blocks
+        if names.len() < 400 && (names.is_empty() || rand() % 3 != 0) {
+            let name = format!("{}-{}", i, rand());
+            dir.create(&name, FileType::File, 0o777)?;
+            names.push(name);
+        } else {
+            let name = names.swap_remove(rand() as usize % names.len());
+            dir.unlink(&name)?;
+        }
+    }
+    for name in names {
+        dir.unlink(&name)?;
+    }
+    assert_eq!(dir.metadata()?.size, 2 * DIRENT_SIZE);
+    sfs.sync()?;
+    assert_eq!(sfs.info().bfree, bfree);
+
+    let used = used_blocks(&sfs)?;
+    let free_map = sfs.free_map.read();
+    for id in 0..BLOCKS {
+        assert_eq!(free_map[id], !used.contains(&id), "block {}", id);
+    }
+    Ok(())
+}
+
+#[test]
+fn inode_flags() -> Result<()> {
+    let device = Arc::new(MockDevice::new(1024));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    let root = sfs.root_inode();
+    let log = root.create("log", FileType::File, 0o777)?;
+    log.write_at(0, b"hello")?;
+    log.set_flags(InodeFlags::APPEND_ONLY)?;
+    let file = root.create("file", FileType::File, 0o777)?;
+    file.write_at(0, b"data")?;
+    file.set_flags(InodeFlags::IMMUTABLE)?;
+    assert_eq!(
+        file.set_flags(InodeFlags(1 << 8)),
+        Err(FsError::InvalidParam)
+    );
+    sfs.sync()?;
+    drop((log, file, root, sfs));
+
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let root = sfs.root_inode();
+    let log = root.find("log")?;
+    assert_eq!(log.get_flags()?, InodeFlags::APPEND_ONLY);
+    // append-only: appends and timestamps only
+    log.write_at(5, b" world")?;
+    assert_eq!(log.write_at(0, b"j"), Err(FsError::PermError));
+    assert_eq!(log.resize(0), Err(FsError::PermError));
+    assert_eq!(root.unlink("log"), Err(FsError::PermError));
+    let mut metadata = log.metadata()?;
+    metadata.mtime.sec = 42;
+    log.set_metadata(&metadata)?;
+    assert_eq!(log.metadata()?.size, 11);
+
+    // immutable: nothing
+    let file = root.find("file")?;
+    assert_eq!(file.get_flags()?, InodeFlags::IMMUTABLE);
+    assert_eq!(file.write_at(4, b"x"), Err(FsError::PermError));
+    assert_eq!(file.write_at(0, b"x"), Err(FsError::PermError));
+    assert_eq!(file.resize(0), Err(FsError::PermError));
+    assert_eq!(file.resize(100), Err(FsError::PermError));
+    assert_eq!(file.set_metadata(&metadata), Err(FsError::PermError));
+    assert_eq!(root.link("file2", &file), Err(FsError::PermError));
+    assert_eq!(root.unlink("file"), Err(FsError::PermError));
+    assert_eq!(root.move_("file", &root, "file2"), Err(FsError::PermError));
+    let other = root.create("other", FileType::File, 0o777)?;
+    assert_eq!(root.move_("other", &root, "file"), Err(FsError::PermError));
+    let mut buf = [0u8; 4];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"data");
+
+    // clear and back to normal
+    log.set_flags(InodeFlags::empty())?;
+    file.set_flags(InodeFlags::empty())?;
+    sfs.sync()?;
+    drop((log, file, other, root, sfs));
+
+    let sfs = SimpleFileSystem::open(device)?;
+    let root = sfs.root_inode();
+    let log = root.find("log")?;
+    assert_eq!(log.get_flags()?, InodeFlags::empty());
+    log.write_at(0, b"j")?;
+    log.resize(0)?;
+    let file = root.find("file")?;
+    file.write_at(0, b"x")?;
+    root.link("file2", &file)?;
+    root.move_("other", &root, "file3")?;
+    root.unlink("file")?;
+    root.unlink("file2")?;
+    root.unlink("log")?;
+    Ok(())
+}
+
+#[test]
+fn metadata_reads_are_prioritized() -> Result<()> {
+    let device = Arc::new(CountingDevice::new());
+    {
+        let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+        let dir = sfs.root_inode().create("dir", FileType::Dir, 0o777)?;
+        let file = dir.create("file", FileType::File, 0o777)?;
+        file.write_at(0, &[1; BLKSIZE])?;
+        sfs.sync()?;
+    }
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let prio_reads = || device.prio_reads.load(Ordering::SeqCst);
+
+    // inodes and entries
+    let before = prio_reads();
+    let file = sfs.root_inode().find("dir")?.find("file")?;
+    assert!(prio_reads() > before);
+
+    // data
+    let (before, reads) = (prio_reads(), device.reads.load(Ordering::SeqCst));
+    let mut buf = [0; BLKSIZE];
+    assert_eq!(file.read_at(0, &mut buf)?, BLKSIZE);
+    assert_eq!(buf, [1; BLKSIZE]);
+    assert_eq!(prio_reads(), before);
+    assert!(device.reads.load(Ordering::SeqCst) > reads);
+    Ok(())
+}
+
+#[test]
+fn create_many_files_reads_each_dir_block_once() -> Result<()> {
+    const N: usize = 2000;
+    let device = Arc::new(CountingDevice::new());
+    let sfs = SimpleFileSystem::create(device.clone(), 4096 * 4096)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    device.reads.store(0, Ordering::SeqCst);
+    for i in 0..N {
+        dir.create(&format!("file{}", i), FileType::File, 0o777)?;
+    }
+    // every create must read all existing entries, including "." and ".."
+    let min_reads: usize = (2..N + 2)
+        .map(|n| (n * DIRENT_SIZE - 1) / BLKSIZE + 1)
+        .sum();
+    let reads = device.reads.load(Ordering::SeqCst);
+    assert!(reads <= min_reads * 2, "{} reads, min {}", reads, min_reads);
+
+    assert_eq!(
+        dir.create("file42", FileType::File, 0o777).err(),
+        Some(FsError::EntryExist)
+    );
+    assert!(dir.find(&format!("file{}", N - 1)).is_ok());
+    Ok(())
+}
+
+/// Create files named `file0`.. in `dir`, by one `create_batch()` or a loop of `create()`
+fn create_files(dir: &Arc<dyn INode>, n: usize, batch: bool) -> Result<Vec<Arc<dyn INode>>> {
+    let names: Vec<_> = (0..n).map(|i| format!("file{}", i)).collect();
+    if batch {
+        let specs: Vec<_> = names
+            .iter()
+            .map(|name| CreateSpec {
+                name,
+                type_: FileType::File,
+                mode: 0o777,
+                data: 0,
+            })
+            .collect();
+        return dir.create_batch(&specs);
+    }
+    names
+        .iter()
+        .map(|name| dir.create(name, FileType::File, 0o777))
+        .collect()
+}
+
+#[test]
+fn create_batch() -> Result<()> {
+    const N: usize = 1000;
+    let mut writes = [0; 2];
+    let mut result = None;
+    for (i, &batch) in [false, true].iter().enumerate() {
+        let device = Arc::new(CountingDevice::new());
+        let sfs = SimpleFileSystem::create(device.clone(), 4096 * 4096)?;
+        let dir = sfs.root_inode().create("dir", FileType::Dir, 0o777)?;
+        device.writes.store(0, Ordering::SeqCst);
+        let inodes = create_files(&dir, N, batch)?;
+        writes[i] = device.writes.load(Ordering::SeqCst);
+        result = Some((sfs, dir, inodes));
+    }
+    let (sfs, dir, inodes) = result.unwrap();
+    // about one write per dir block instead of one per entry
+    assert!(
+        writes[1] * 5 < writes[0],
+        "batch {} writes, loop {} writes",
+        writes[1],
+        writes[0]
+    );
+    assert_eq!(inodes.len(), N);
+    for (i, inode) in inodes.iter().enumerate() {
+        let found = dir.find(&format!("file{}", i))?;
+        assert_eq!(found.metadata()?.inode, inode.metadata()?.inode);
+        assert_eq!(found.metadata()?.nlinks, 1);
+    }
+    drop(inodes);
+
+    // atomic: nothing is created if one fails
+    let bfree = sfs.info().bfree;
+    let spec = |name| CreateSpec {
+        name,
+        type_: FileType::Dir,
+        mode: 0o777,
+        data: 0,
+    };
+    assert_eq!(
+        dir.create_batch(&[spec("new1"), spec("file7"), spec("new2")])
+            .err(),
+        Some(FsError::EntryExist)
+    );
+    assert_eq!(
+        dir.create_batch(&[spec("new1"), spec("new2"), spec("new1")])
+            .err(),
+        Some(FsError::EntryExist)
+    );
+    assert_eq!(dir.metadata()?.size, (N + 2) * DIRENT_SIZE);
+    assert_eq!(dir.metadata()?.nlinks, 2);
+    assert!(dir.find("new1").is_err());
+    assert_eq!(sfs.info().bfree, bfree);
+
+    let created = dir.create_batch(&[spec("new1"), spec("new2")])?;
+    assert_eq!(dir.metadata()?.nlinks, 4);
+    assert_eq!(created[1].metadata()?.nlinks, 2);
+    assert_eq!(
+        dir.find("new2")?.find("..")?.metadata()?.inode,
+        dir.metadata()?.inode
+    );
+    Ok(())
+}
+
+#[test]
+fn for_each_inode() -> Result<()> {
+    let (sfs, root, file1) = sfs_with_file("file1")?;
+    file1.write_at(0, &[1; 5000])?;
+    let dir1 = root.create("dir1", FileType::Dir, 0o777)?;
+    let file2 = dir1.create("file2", FileType::File, 0o777)?;
+    dir1.link("link1", &file1)?;
+    let orphan = root.create("orphan", FileType::File, 0o777)?;
+    orphan.write_at(0, &[2; 100])?;
+    root.unlink("orphan")?;
+
+    let mut inodes = BTreeMap::new();
+    sfs.for_each_inode(|id, metadata| {
+        assert!(inodes.insert(id, metadata.clone()).is_none());
+        ControlFlow::Continue(())
+    })?;
+    let expected = [&root, &file1, &dir1, &file2, &orphan];
+    assert_eq!(inodes.len(), expected.len());
+    for inode in expected.iter() {
+        let metadata = inode.metadata()?;
+        assert_eq!(inodes[&metadata.inode], metadata);
+        let opened = sfs.open_inode(metadata.inode)?;
+        assert_eq!(opened.metadata()?, metadata);
+    }
+    assert_eq!(inodes[&file1.metadata()?.inode].nlinks, 2);
+    assert_eq!(inodes[&orphan.metadata()?.inode].nlinks, 0);
+
+    // stop early
+    let mut count = 0;
+    sfs.for_each_inode(|_, _| {
+        count += 1;
+        ControlFlow::Break(())
+    })?;
+    assert_eq!(count, 1);
+
+    // data blocks are not inodes
+    let data_block = file1
+        .downcast_ref::<INodeImpl>()
+        .unwrap()
+        .get_disk_block_id(0)?;
+    drop(file1);
+    assert_eq!(
+        sfs.open_inode(data_block).err(),
+        Some(FsError::EntryNotFound)
+    );
+    assert_eq!(
+        sfs.open_inode(BLKN_FREEMAP).err(),
+        Some(FsError::EntryNotFound)
+    );
+    Ok(())
+}
+
+#[test]
+fn str256_round_trip() {
+    // xorshift, so failures are reproducible
+    let mut seed = 0x2545_f491_4f6c_dd1du64;
+    let mut rand = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    let mut names = Vec::new();
+    for _ in 0..1000 {
+        let len = rand() as usize % (MAX_FNAME_LEN + 1);
+        let bytes: Vec<u8> = (0..len).map(|_| rand() as u8 % 255 + 1).collect();
+        let s = Str256::from_bytes(&bytes).unwrap();
+        assert_eq!(s.as_bytes(), &bytes[..]);
+
+        let mut with_nul = bytes.clone();
+        with_nul.insert(rand() as usize % (len + 1), 0);
+        assert_eq!(
+            Str256::from_bytes(&with_nul).err(),
+            Some(FsError::InvalidParam)
+        );
+        names.push(s);
+    }
+    assert_eq!(
+        Str256::from_bytes(&[b'x'; MAX_FNAME_LEN + 1]).err(),
+        Some(FsError::InvalidParam)
+    );
+
+    // Ord and Eq are consistent with the bytes
+    for (a, b) in names.iter().zip(names.iter().skip(1)) {
+        assert_eq!(a.cmp(b), a.as_bytes().cmp(b.as_bytes()));
+        assert_eq!(a == b, a.cmp(b) == core::cmp::Ordering::Equal);
+        assert!(a == a);
+    }
+}
+
+#[test]
+fn str256_compare_with_str() {
+    let name = "文件-1";
+    let s = Str256::new(name).unwrap();
+    assert!(s == *name);
+    assert!(s != *"文件");
+    assert!(s != *"文件-12");
+    assert_eq!(s.as_ref(), name);
+
+    // bytes after NUL are ignored
+    let mut raw = [0xffu8; 256];
+    raw[..3].copy_from_slice(b"abc");
+    raw[3] = 0;
+    let s = Str256(raw);
+    assert!(s == *"abc");
+    assert_eq!(s, Str256::new("abc").unwrap());
+
+    let longest = "x".repeat(MAX_FNAME_LEN);
+    let s = Str256::new(&longest).unwrap();
+    assert!(s == *longest);
+    assert_eq!(s.as_ref(), longest);
+    assert_eq!(Str256::new("a\0b").err(), Some(FsError::InvalidParam));
+    assert_eq!(
+        Str32::new(&"x".repeat(MAX_INFO_LEN + 1)).err(),
+        Some(FsError::InvalidParam)
+    );
+}
+
+#[test]
+fn create_invalid_name() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    assert_eq!(
+        root.create("a\0b", FileType::File, 0o777).err(),
+        Some(FsError::InvalidParam)
+    );
+    assert_eq!(
+        root.create(&"x".repeat(MAX_FNAME_LEN + 1), FileType::File, 0o777)
+            .err(),
+        Some(FsError::InvalidParam)
+    );
+    assert_eq!(root.get_entry(2).err(), Some(FsError::EntryNotFound));
+    Ok(())
+}
+
+#[test]
+fn io_adapter_copy() -> Result<()> {
+    use futures::executor::block_on;
+    use futures::io::{copy, AsyncReadExt};
+    use rcore_fs::file::{File, IoAdapter};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    let (_sfs, root, src) = sfs_with_file("src")?;
+    let dst = root.create("dst", FileType::File, 0o777)?;
+    let data: Vec<u8> = (0..1 << 20)
+        .map(|i: u32| (i * 7 + i / 4096) as u8)
+        .collect();
+    src.write_at(0, &data)?;
+
+    let mut reader = IoAdapter::new(File::new(src, true, false));
+    let mut writer = IoAdapter::new(File::new(dst.clone(), false, true));
+    let len = block_on(copy(&mut reader, &mut writer)).unwrap();
+    assert_eq!(len, data.len() as u64);
+    // EOF
+    let mut buf = [0u8; 16];
+    assert_eq!(block_on(reader.read(&mut buf)).unwrap(), 0);
+
+    let hash = |bytes: &[u8]| {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(bytes);
+        hasher.finish()
+    };
+    let mut copied = Vec::new();
+    let mut reader = IoAdapter::new(File::new(dst, true, false));
+    block_on(reader.read_to_end(&mut copied)).unwrap();
+    assert_eq!(hash(&copied), hash(&data));
+    Ok(())
+}
+
+#[test]
+fn io_adapter_seek() -> Result<()> {
+    use futures::executor::block_on;
+    use futures::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+    use rcore_fs::file::{File, IoAdapter};
+
+    let sfs = _create_new_sfs();
+    let file1 = sfs.root_inode().create("file1", FileType::File, 0o777)?;
+    let mut file = IoAdapter::new(File::new(file1, true, true));
+    block_on(async {
+        file.write_all(b"hello, world").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(file.seek(SeekFrom::Start(7)).await.unwrap(), 7);
+        let mut buf = [0u8; 5];
+        file.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"world");
+        assert_eq!(file.seek(SeekFrom::End(-12)).await.unwrap(), 0);
+        assert_eq!(file.seek(SeekFrom::Current(5)).await.unwrap(), 5);
+        file.read_exact(&mut buf[..1]).await.unwrap();
+        assert_eq!(buf[0], b',');
+        assert!(file.seek(SeekFrom::Current(-100)).await.is_err());
+        file.close().await.unwrap();
+    });
+    Ok(())
+}
+
+#[test]
+fn sync_facade() -> Result<()> {
+    use rcore_fs::sync_facade::{SyncFileSystem, SyncINode};
+
+    let device = Arc::new(MockDevice::new(1024));
+    let sfs = SimpleFileSystem::create(device, 1024 * 4096)?;
+    let dir = sfs.root_inode().create("etc", FileType::Dir, 0o777)?;
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    dir.create("config", FileType::File, 0o777)?
+        .write_at(0, &data)?;
+
+    let fs = SyncFileSystem(&*sfs);
+    assert_eq!(fs.read("/etc/config")?, data);
+    assert_eq!(fs.read("/etc/none"), Err(FsError::EntryNotFound));
+    let root = fs.root_inode();
+    let root = SyncINode(&*root);
+    assert_eq!(root.list()?, [".", "..", "etc"]);
+    assert_eq!(root.find("etc")?.metadata()?.type_, FileType::Dir);
+    assert!(root.poll()?.read);
+    Ok(())
+}
+
+/// Counts allocations of the current thread, and records the largest,
+/// so that tests running in parallel do not disturb each other
+struct CountingAlloc;
+
+std::thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    static LARGEST_ALLOCATION: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        let _ = LARGEST_ALLOCATION.try_with(|n| n.set(n.get().max(layout.size())));
+        std::alloc::System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Look up every file of `dir` many times, return the sum of found inode ids
+/// and the number of allocations made
+fn lookup_workload(dir: &Arc<dyn INode>, names: &[String]) -> Result<(usize, usize)> {
+    let before = ALLOCATIONS.with(|n| n.get());
+    let mut sum = 0;
+    for _ in 0..20 {
+        for name in names {
+            sum += dir.find(name)?.metadata()?.inode;
+        }
+        assert_eq!(dir.find("none").err(), Some(FsError::EntryNotFound));
+    }
+    Ok((sum, ALLOCATIONS.with(|n| n.get()) - before))
+}
+
+#[test]
+fn scratch_pool_saves_allocations() -> Result<()> {
+    let device = Arc::new(MockDevice::new(1024));
+    let sfs = SimpleFileSystem::create(device, 1024 * 4096)?;
+    let dir = sfs.root_inode().create("dir", FileType::Dir, 0o777)?;
+    // hold the files, so that lookups do not load them again
+    let _files = create_files(&dir, 100, true)?;
+    let names: Vec<_> = (0..100).map(|i| format!("file{}", i)).collect();
+
+    sfs.set_scratch_pool_size(0);
+    let start = sfs.stats();
+    let (base_sum, base_allocs) = lookup_workload(&dir, &names)?;
+    let base_stats = sfs.stats();
+    assert_eq!(base_stats.scratch_hits, start.scratch_hits);
+    assert_eq!(base_stats.scratch_misses, start.scratch_misses + 20 * 101);
+
+    sfs.set_scratch_pool_size(4);
+    let (sum, allocs) = lookup_workload(&dir, &names)?;
+    let stats = sfs.stats();
+    assert_eq!(sum, base_sum);
+    assert!(
+        allocs * 10 <= base_allocs,
+        "{} allocations with pool, {} without",
+        allocs,
+        base_allocs
+    );
+    assert_eq!(stats.scratch_misses, base_stats.scratch_misses);
+    assert_eq!(stats.scratch_hits, base_stats.scratch_hits + 20 * 101);
+    Ok(())
+}
+
+#[test]
+fn metadata_through_block_cache() -> Result<()> {
+    use rcore_fs::dev::block_cache::BlockCache;
+
+    const BLOCKS: usize = 256;
+    let mem = MemDevice::new(BLOCKS);
+    // small enough to evict blocks all the time
+    let cache = Arc::new(BlockAlignedWrites(BlockCache::new(mem.clone(), 8)));
+    let raw = |id: BlockId| {
+        let mut buf = vec![0; BLKSIZE];
+        BlockDevice::read_at(&mem, id, &mut buf).unwrap();
+        buf
+    };
+    let cached = |id: BlockId| {
+        let mut buf = vec![0; BLKSIZE];
+        cache.read_at(id * BLKSIZE, &mut buf).unwrap();
+        buf
+    };
+
+    let mut sfs = SimpleFileSystem::create(cache.clone(), BLOCKS * BLKSIZE)?;
+    for cycle in 0..3 {
+        let dir = sfs
+            .root_inode()
+            .create(&format!("d{}", cycle), FileType::Dir, 0o777)?;
+        for i in 0..10 {
+            dir.create(&format!("f{}", i), FileType::File, 0o777)?
+                .write_at(0, &[cycle as u8; 5000])?;
+        }
+        sfs.set_label(&format!("cycle {}", cycle))?;
+        sfs.sync()?;
+
+        let super_block = sfs.super_block.read().as_buf().to_vec();
+        let free_map = sfs.free_map.read().as_buf().to_vec();
+        let bfree = sfs.info().bfree;
+        for (name, read) in [
+            ("cache", &cached as &dyn Fn(BlockId) -> Vec<u8>),
+            ("raw", &raw),
+        ] {
+            assert_eq!(
+                read(BLKN_SUPER)[..super_block.len()],
+                super_block[..],
+                "{}",
+                name
+            );
+            assert_eq!(read(BLKN_FREEMAP)[..], free_map[..BLKSIZE], "{}", name);
+        }
+        drop(sfs);
+
+        // reopen through the cache
+        sfs = SimpleFileSystem::open(cache.clone())?;
+        assert_eq!(sfs.label(), format!("cycle {}", cycle));
+        assert_eq!(sfs.info().bfree, bfree);
+        let mut buf = [0u8; 5000];
+        sfs.root_inode()
+            .lookup(&format!("d{}/f9", cycle))?
+            .read_at(0, &mut buf)?;
+        assert!(buf.iter().all(|&b| b == cycle as u8));
+    }
+    drop(sfs);
+
+    // and from the device alone
+    BlockDevice::sync(&cache.0).unwrap();
+    let sfs = SimpleFileSystem::open(Arc::new(mem))?;
+    assert_eq!(sfs.label(), "cycle 2");
+    assert_eq!(sfs.root_inode().list()?, [".", "..", "d0", "d1", "d2"]);
+    Ok(())
+}
+
+#[test]
+fn error_context_of_lookup() -> Result<()> {
+    let (device, sfs) = mock_sfs(1024)?;
+    let root = sfs.root_inode();
+    let dir = root
+        .create("a", FileType::Dir, 0o777)?
+        .create("b", FileType::Dir, 0o777)?;
+    dir.create("c", FileType::File, 0o777)?;
+    let dir_id = dir.metadata()?.inode;
+    let block = sfs.get_inode(dir_id)?.get_disk_block_id(0)?;
+    sfs.sync()?;
+
+    device.bad_block.store(block, Ordering::SeqCst);
+    let err = root.lookup("a/b/c").err().unwrap();
+    assert_eq!(err, FsError::DeviceError);
+    assert_eq!(err.root_cause(), &FsError::DeviceError);
+    let context = err.context().unwrap();
+    assert_eq!(context.op, "find");
+    assert_eq!(context.inode, Some(dir_id));
+    assert_eq!(context.name.as_deref(), Some("c"));
+    assert_eq!(context.block, Some(block));
+
+    device.bad_block.store(usize::MAX, Ordering::SeqCst);
+    root.lookup("a/b/c")?;
+    Ok(())
+}
+
+#[test]
+fn move_dir_updates_dotdot() -> Result<()> {
+    let file = tempfile::tempfile().unwrap();
+    let device = Arc::new(Mutex::new(file.try_clone().unwrap()));
+    let sfs = SimpleFileSystem::create(device, 1024 * 4096)?;
+    let root = sfs.root_inode();
+    let a = root.create("a", FileType::Dir, 0o777)?;
+    let b = root.create("b", FileType::Dir, 0o777)?;
+    let x = a.create("x", FileType::Dir, 0o777)?;
+    a.move_("x", &b, "y")?;
+    let b_id = b.metadata()?.inode;
+    assert_eq!(x.find("..")?.metadata()?.inode, b_id);
+    assert_eq!(root.lookup("b/y/..")?.metadata()?.inode, b_id);
+    assert_eq!(x.get_entry(1)?, "..");
+    assert_eq!(b.move_("y", &b, "."), Err(FsError::IsDir));
+    sfs.sync()?;
+    drop((root, a, b, x));
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(Arc::new(Mutex::new(file)))?;
+    let root = sfs.root_inode();
+    assert_eq!(root.lookup("b/y/..")?.metadata()?.inode, b_id);
+    Ok(())
+}
+
+#[test]
+fn corrupted_dotdot_repaired() -> Result<()> {
+    let file = tempfile::tempfile().unwrap();
+    let device = Arc::new(Mutex::new(file));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    dir.create("file", FileType::File, 0o777)?;
+    sfs.sync()?;
+
+    // rename ".." to "zz" on disk
+    let dir_id = dir.metadata()?.inode;
+    let block = sfs.get_inode(dir_id)?.get_disk_block_id(0)?;
+    let name_offset = block * BLKSIZE + DIRENT_SIZE + ENTRY_SIZE;
+    device.write_at(name_offset, b"zz\0").unwrap();
+
+    assert_eq!(dir.find("..")?.metadata()?.inode, BLKN_ROOT);
+    assert_eq!(root.lookup("dir/../dir/file").map(|_| ()), Ok(()));
+    assert_eq!(dir.find("zz").err(), Some(FsError::EntryNotFound));
+    assert_eq!(dir.list()?, [".", "..", "file"]);
+    assert_eq!(dir.get_entry_with_metadata(1)?.0.inode, BLKN_ROOT);
+
+    sfs.sync()?;
+    let mut name = [0u8; 3];
+    device.read_at(name_offset, &mut name).unwrap();
+    assert_eq!(&name, b"..\0");
+    Ok(())
+}
+
+#[test]
+fn open_hook_unrestricted() -> Result<()> {
+    use rcore_fs::file::File;
+
+    let (_sfs, root, file) = sfs_with_file("file")?;
+    let _exclusive = File::open(file.clone(), true, true, true)?;
+    let _shared = File::open(file.clone(), true, false, false)?;
+    let _again = File::open(file, true, true, true)?;
+    // no device behind it
+    let tty = root.create2(
+        "tty",
+        FileType::CharDevice,
+        0o666,
+        rcore_fs::vfs::make_rdev(4, 1),
+    )?;
+    let _tty = File::open(tty.clone(), true, true, true)?;
+    assert!(File::open(tty, true, true, true).is_ok());
+    Ok(())
+}
+
+/// Path -> (metadata without dev and inode number, content) of every inode
+fn compare_tree(root: &Arc<dyn INode>) -> Result<BTreeMap<String, (Metadata, Vec<u8>)>> {
+    fn walk(
+        dir: &Arc<dyn INode>,
+        path: &str,
+        tree: &mut BTreeMap<String, (Metadata, Vec<u8>)>,
+    ) -> Result<()> {
+        for name in dir.list()?.into_iter().skip(2) {
+            let inode = dir.find(&name)?;
+            let path = std::format!("{}/{}", path, name);
+            let mut meta = inode.metadata()?;
+            meta.dev = 0;
+            meta.inode = 0;
+            let mut content = Vec::new();
+            match meta.type_ {
+                FileType::Dir => walk(&inode, &path, tree)?,
+                FileType::File | FileType::SymLink => {
+                    content.resize(meta.size, 0);
+                    assert_eq!(inode.read_at(0, &mut content)?, meta.size);
+                }
+                _ => {}
+            }
+            tree.insert(path, (meta, content));
+        }
+        Ok(())
+    }
+    let mut tree = BTreeMap::new();
+    walk(root, "", &mut tree)?;
+    Ok(tree)
+}
+
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    let sub = dir.create("sub", FileType::Dir, 0o755)?;
+    let big = dir.create("big", FileType::File, 0o644)?;
+    let data: Vec<u8> = (0..3 * BLKSIZE + 123).map(|i| (i * 7) as u8).collect();
+    big.write_at(0, &data)?;
+    let sparse = sub.create("sparse", FileType::File, 0o644)?;
+    sparse.resize(2 * BLKSIZE)?;
+    sparse.write_at(BLKSIZE + 5, b"tail")?;
+    root.create("empty", FileType::File, 0o644)?;
+    let link = root.create("link", FileType::SymLink, 0o777)?;
+    link.write_at(0, b"dir/big")?;
+    root.create2(
+        "null",
+        FileType::CharDevice,
+        0o666,
+        rcore_fs::vfs::make_rdev(1, 3),
+    )?;
+    sub.link("big2", &big)?;
+    root.link("big3", &big)?;
+    for (i, inode) in [&big, &sparse, &sub, &link].iter().enumerate() {
+        let mut meta = inode.metadata()?;
+        meta.mtime = Timespec {
+            sec: 1000 + i as i64,
+            nsec: 7,
+        };
+        meta.atime = meta.mtime;
+        inode.set_metadata(&meta)?;
+    }
+
+    let mut stream = Vec::new();
+    let exported = export_stream(&sfs, &mut stream)?;
+    assert_eq!(
+        exported,
+        ExportSummary {
+            files: 3,
+            dirs: 3,
+            symlinks: 1,
+            devices: 1,
+            links: 2,
+            bytes: (data.len() + 2 * BLKSIZE) as u64,
+        }
+    );
+
+    // a smaller image, with another layout of the freemap and inodes
+    let file = tempfile::tempfile().expect("failed to create file");
+    let copy = SimpleFileSystem::create(Arc::new(Mutex::new(file)), 4 * 4096 * 4096)?;
+    let imported = import_stream(&copy, &mut &stream[..])?;
+    assert_eq!(imported, exported);
+    assert_eq!(
+        compare_tree(&copy.root_inode())?,
+        compare_tree(&sfs.root_inode())?
+    );
+
+    // hard links stay links
+    let root = copy.root_inode();
+    let id = root.lookup("dir/big")?.metadata()?.inode;
+    assert_eq!(root.lookup("dir/sub/big2")?.metadata()?.inode, id);
+    assert_eq!(root.lookup("big3")?.metadata()?.inode, id);
+    assert_eq!(root.lookup("big3")?.metadata()?.nlinks, 3);
+
+    // importing twice conflicts with the existing entries
+    assert_eq!(
+        import_stream(&copy, &mut &stream[..]).map(|_| ()),
+        Err(FsError::EntryExist)
+    );
+    Ok(())
+}
+
+#[test]
+fn unaligned_device_size() -> Result<()> {
+    let size = 16 * BLKSIZE + 100;
+    let device = StrictDevice::new(vec![0; size]);
+    let sfs = SimpleFileSystem::create(device.clone(), size)?;
+    assert_eq!(sfs.info().blocks, 16);
+    let root = sfs.root_inode();
+    let mut files = 0;
+    // fill the fs, each file takes an inode and a data block
+    loop {
+        let file = match root.create(&files.to_string(), FileType::File, 0o644) {
+            Ok(file) => file,
+            Err(FsError::NoDeviceSpace) => break,
+            Err(err) => return Err(err),
+        };
+        files += 1;
+        match file.write_at(0, &[0xaa; BLKSIZE]) {
+            Ok(_) => {}
+            Err(FsError::NoDeviceSpace) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    assert!(files > 0);
+    assert_eq!(sfs.info().bavail, 0);
+    assert_eq!(sfs.info().bfree, sfs.recovery_blocks());
+    sfs.sync()?;
+    assert!(!device.out_of_range.load(Ordering::SeqCst));
+    Ok(())
+}
+
+#[test]
+fn truncated_device_read_only() -> Result<()> {
+    let size = 64 * BLKSIZE;
+    let device = StrictDevice::new(vec![0; size]);
+    let sfs = SimpleFileSystem::create(device.clone(), size)?;
+    sfs.root_inode()
+        .create("file", FileType::File, 0o644)?
+        .write_at(0, b"data")?;
+    sfs.sync()?;
+    drop(sfs);
+
+    let mut data = device.data.lock().unwrap().clone();
+    data.truncate(48 * BLKSIZE);
+    let sfs = SimpleFileSystem::open(StrictDevice::new(data))?;
+    assert!(sfs.is_read_only());
+    let root = sfs.root_inode();
+    let mut buf = [0; 4];
+    root.find("file")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"data");
+    assert_eq!(
+        root.create("new", FileType::File, 0o644).map(|_| ()),
+        Err(FsError::ReadOnly)
+    );
+    Ok(())
+}
+
+#[test]
+fn cancel_resize() -> Result<()> {
+    use rcore_fs::vfs::{CancelToken, TaskContext};
+
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o644)?;
+    file.write_at(0, b"head")?;
+    let bfree = sfs.info().bfree;
+    let token = CancelToken::new();
+    let yields = Arc::new(AtomicUsize::new(0));
+    let ctx = {
+        let (token, yields) = (token.clone(), yields.clone());
+        TaskContext::new()
+            .with_token(token.clone())
+            .with_interval(1000)
+            .with_yield(move || {
+                // cancel halfway through 10k blocks
+                if yields.fetch_add(1, Ordering::SeqCst) + 1 == 5 {
+                    token.cancel();
+                }
+            })
+    };
+    assert_eq!(
+        file.resize_with(10000 * BLKSIZE, &ctx),
+        Err(FsError::Interrupted)
+    );
+    assert_eq!(yields.load(Ordering::SeqCst), 5);
+    // rolled back, with all blocks freed
+    assert_eq!(file.metadata()?.size, 4);
+    assert_eq!(sfs.info().bfree, bfree);
+    let mut buf = [0; 4];
+    file.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"head");
+
+    let yields = Arc::new(AtomicUsize::new(0));
+    let counter = yields.clone();
+    let ctx = TaskContext::new().with_interval(1000).with_yield(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    file.resize_with(10000 * BLKSIZE, &ctx)?;
+    assert_eq!(yields.load(Ordering::SeqCst), 10);
+    assert_eq!(file.metadata()?.size, 10000 * BLKSIZE);
+
+    // shrinking keeps the size reached
+    let token = CancelToken::new();
+    token.cancel();
+    let ctx = TaskContext::new().with_token(token);
+    assert_eq!(file.resize_with(0, &ctx), Err(FsError::Interrupted));
+    assert_eq!(file.metadata()?.size, 10000 * BLKSIZE);
+    file.resize_with(0, &TaskContext::new())?;
+    // "head" was inline, taking no block
+    assert_eq!(sfs.info().bfree, bfree);
+    Ok(())
+}
+
+#[test]
+fn io_in_bursts() -> Result<()> {
+    use rcore_fs::vfs::TaskContext;
+
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o644)?;
+    let data: Vec<u8> = (0..10000 * BLKSIZE).map(|i| (i * 7 / 5) as u8).collect();
+    let yields = Arc::new(AtomicUsize::new(0));
+    let counter = yields.clone();
+    let ctx = TaskContext::new().with_yield(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    assert_eq!(file.write_at_with(0, &data, &ctx)?, data.len());
+    // 256 blocks per burst
+    assert_eq!(yields.swap(0, Ordering::SeqCst), 40);
+    let mut buf = vec![0; data.len()];
+    assert_eq!(file.read_at_with(0, &mut buf, &ctx)?, data.len());
+    assert_eq!(yields.swap(0, Ordering::SeqCst), 40);
+    assert!(buf == data);
+
+    // the fewest blocks of the fs and the context
+    sfs.set_io_burst(1000);
+    let counter = yields.clone();
+    let ctx = TaskContext::new().with_interval(5000).with_yield(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    assert_eq!(
+        file.read_at_with(BLKSIZE / 2, &mut buf, &ctx)?,
+        data.len() - BLKSIZE / 2
+    );
+    assert_eq!(yields.load(Ordering::SeqCst), 10);
+    assert!(buf[..data.len() - BLKSIZE / 2] == data[BLKSIZE / 2..]);
+    Ok(())
+}
+
+#[test]
+fn io_cancelled_after_first_burst() -> Result<()> {
+    use rcore_fs::file::File;
+    use rcore_fs::vfs::{CancelToken, TaskContext};
+
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o644)?;
+    let data: Vec<u8> = (0..2000 * BLKSIZE).map(|i| (i * 3 / 7) as u8).collect();
+    // cancelled at the checkpoint after `n` ones
+    let cancel_after = |n| {
+        let token = CancelToken::new();
+        let yields = AtomicUsize::new(0);
+        let ctx = TaskContext::new().with_token(token.clone());
+        ctx.with_yield(move || {
+            if yields.fetch_add(1, Ordering::SeqCst) == n {
+                token.cancel();
+            }
+        })
+    };
+    let burst = 256 * BLKSIZE;
+    assert_eq!(file.write_at_with(0, &data, &cancel_after(1))?, burst);
+    assert_eq!(file.metadata()?.size, burst);
+    let mut buf = vec![0; data.len()];
+    assert_eq!(file.read_at(0, &mut buf)?, burst);
+    assert!(buf[..burst] == data[..burst]);
+
+    file.write_at(0, &data)?;
+    buf.fill(0);
+    assert_eq!(file.read_at_with(0, &mut buf, &cancel_after(1))?, burst);
+    assert!(buf[..burst] == data[..burst] && buf[burst..].iter().all(|&b| b == 0));
+    // nothing done at all
+    let token = CancelToken::new();
+    token.cancel();
+    let ctx = TaskContext::new().with_token(token);
+    assert_eq!(
+        file.read_at_with(0, &mut buf, &ctx),
+        Err(FsError::Interrupted)
+    );
+
+    // through a file, reporting progress by pieces of 100 blocks, each
+    // starting with a checkpoint
+    let file = sfs.root_inode().create("handle", FileType::File, 0o644)?;
+    let mut handle = File::new(file.clone(), true, true);
+    handle.set_task_context(cancel_after(2));
+    let progress = Arc::new(Mutex::new(Vec::new()));
+    let reported = progress.clone();
+    handle.set_progress(100 * BLKSIZE, move |done, total| {
+        reported.lock().unwrap().push((done, total));
+    });
+    assert_eq!(handle.write(&data)?, 200 * BLKSIZE);
+    assert_eq!(
+        *progress.lock().unwrap(),
+        [(100 * BLKSIZE, data.len()), (200 * BLKSIZE, data.len())]
+    );
+    assert_eq!(file.metadata()?.size, 200 * BLKSIZE);
+    Ok(())
+}
+
+#[test]
+fn partial_metadata_of_entries() -> Result<()> {
+    use rcore_fs::vfs::{MetadataMask, PartialMetadata};
+
+    let device = Arc::new(CountingDevice::new());
+    let sfs = SimpleFileSystem::create(device.clone(), 4096 * 4096)?;
+    let dir = sfs.root_inode().create("dir", FileType::Dir, 0o777)?;
+    for i in 0..20 {
+        let file = dir.create(&format!("file{}", i), FileType::File, 0o777)?;
+        file.write_at(0, &vec![1; i * 100])?;
+    }
+    dir.create("sub", FileType::Dir, 0o777)?;
+    dir.create("link", FileType::SymLink, 0o777)?;
+    dir.create2("tty", FileType::CharDevice, 0o666, 0x401)?;
+    dir.move_("file0", &dir, "renamed")?;
+    dir.link("file1_again", &dir.find("file1")?)?;
+    // no room for the type after the longest name
+    let long_name = "x".repeat(MAX_FNAME_LEN);
+    let long_id = dir
+        .create(&long_name, FileType::File, 0o777)?
+        .metadata()?
+        .inode;
+    let count = dir.list()?.len();
+    sfs.sync()?;
+    drop(dir);
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let dir = sfs.root_inode().find("dir")?;
+    let ids: BTreeSet<usize> = (2..count)
+        .map(|i| Ok(dir.get_entry_with_metadata(i)?.0.inode))
+        .collect::<Result<_>>()?;
+    drop(dir);
+    drop(sfs);
+    let inode_loads = |device: &CountingDevice| {
+        let offsets = std::mem::take(&mut *device.read_offsets.lock().unwrap());
+        offsets
+            .into_iter()
+            .filter(|&offset| offset % BLKSIZE == 0 && ids.contains(&(offset / BLKSIZE)))
+            .map(|offset| offset / BLKSIZE)
+            .collect::<Vec<_>>()
+    };
+
+    for &mask in &[
+        MetadataMask::TYPE,
+        MetadataMask::TYPE | MetadataMask::INODE,
+        MetadataMask::SIZE,
+        MetadataMask::ALL,
+    ] {
+        let sfs = SimpleFileSystem::open(device.clone())?;
+        // count the loads of each entry alone
+        sfs.set_dir_readahead(0);
+        let dir = sfs.root_inode().find("dir")?;
+        inode_loads(&device);
+        let entries: Vec<_> = (0..count)
+            .map(|i| dir.get_entry_with_metadata_partial(i, mask))
+            .collect::<Result<_>>()?;
+        let loads = inode_loads(&device);
+        if mask.contains(MetadataMask::SIZE) {
+            assert_eq!(loads.into_iter().collect::<BTreeSet<_>>(), ids);
+        } else {
+            assert_eq!(loads, [long_id], "{:?}", mask);
+        }
+        for (i, (partial, name)) in entries.into_iter().enumerate() {
+            let (metadata, full_name) = dir.get_entry_with_metadata(i)?;
+            assert_eq!(name, full_name);
+            assert_eq!(partial, PartialMetadata::from_metadata(&metadata, mask));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn removed_dir() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let other = root.create("other", FileType::Dir, 0o777)?;
+    other.create("file", FileType::File, 0o777)?;
+    let file = root.create("file", FileType::File, 0o777)?;
+    root.unlink("dir")?;
+
+    let meta = dir.metadata()?;
+    assert_eq!((meta.type_, meta.nlinks), (FileType::Dir, 0));
+    // only "." is left
+    assert_eq!(dir.list()?, ["."]);
+    assert_eq!(dir.get_entry(1), Err(FsError::EntryNotFound));
+    assert_eq!(dir.get_entry_with_metadata(0)?.0.inode, meta.inode);
+    assert_eq!(dir.find(".")?.metadata()?.inode, meta.inode);
+    assert_eq!(dir.find("..").err(), Some(FsError::DirRemoved));
+    assert_eq!(dir.find("file").err(), Some(FsError::DirRemoved));
+    // as for a live dir
+    assert_eq!(dir.read_at(0, &mut [0; 4]), Err(FsError::IsDir));
+    assert_eq!(dir.resize(0), Err(FsError::IsDir));
+    // no new entries
+    assert_eq!(
+        dir.create("new", FileType::File, 0o777).err(),
+        Some(FsError::DirRemoved)
+    );
+    let specs = [CreateSpec {
+        name: "new",
+        type_: FileType::File,
+        mode: 0o777,
+        data: 0,
+    }];
+    assert_eq!(dir.create_batch(&specs).err(), Some(FsError::DirRemoved));
+    assert_eq!(dir.link("new", &file), Err(FsError::DirRemoved));
+    assert_eq!(dir.unlink("new"), Err(FsError::DirRemoved));
+    assert_eq!(dir.move_("new", &root, "new"), Err(FsError::DirRemoved));
+    assert_eq!(other.move_("file", &dir, "file"), Err(FsError::DirRemoved));
+    assert_eq!(root.move_("file", &dir, "file"), Err(FsError::DirRemoved));
+    // nothing moved
+    assert!(other.find("file").is_ok());
+    assert!(root.find("file").is_ok());
+    Ok(())
+}
+
+/// Make `n` entries `link0`.. in `dir`, as hard links to files made in `root`
+fn link_many(root: &Arc<dyn INode>, dir: &Arc<dyn INode>, n: usize) -> Result<()> {
+    let mut file = None;
+    for i in 0..n {
+        if i % (LINK_MAX - 1) == 0 {
+            let name = format!("file{}", i / (LINK_MAX - 1));
+            file = Some(root.create(&name, FileType::File, 0o777)?);
+        }
+        dir.link(&format!("link{}", i), file.as_ref().unwrap())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn dir_index_bounded_lookup() -> Result<()> {
+    const N: usize = 50_000;
+    let device = Arc::new(CountingDevice::new());
+    {
+        let sfs = SimpleFileSystem::create(device.clone(), 8192 * BLKSIZE)?;
+        let root = sfs.root_inode();
+        let dir = root.create("dir", FileType::Dir, 0o777)?;
+        link_many(&root, &dir, N)?;
+        sfs.sync()?;
+    }
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let dir = sfs.root_inode().find("dir")?;
+    assert!(dir.metadata()?.size > 3000 * BLKSIZE);
+    let lookup = |name: &str| {
+        device.reads.store(0, Ordering::SeqCst);
+        let found = dir.find(name).map(|inode| inode.metadata().unwrap().inode);
+        (found, device.reads.load(Ordering::SeqCst))
+    };
+    for name in &["link0", "link25000", "link49999", "missing"] {
+        let (found, reads) = lookup(name);
+        assert_eq!(found.is_ok(), *name != "missing");
+        // the bucket, the entry which may cross a block, and the inode
+        assert!(reads <= 4, "{} reads for {}", reads, name);
+    }
+    assert_eq!(sfs.check_dir_indexes()?, 0);
+
+    // a broken index is not trusted, and is rebuilt
+    let index = dir
+        .downcast_ref::<INodeImpl>()
+        .unwrap()
+        .disk_inode
+        .read()
+        .index;
+    device.write_at(index as usize * BLKSIZE, &[0; 4]).unwrap();
+    let (found, reads) = lookup("link25000");
+    assert!(found.is_ok());
+    assert!(reads > 1000);
+    assert_eq!(lookup("missing").0.err(), Some(FsError::EntryNotFound));
+    assert_eq!(sfs.check_dir_indexes()?, 1);
+    assert_eq!(sfs.check_dir_indexes()?, 0);
+    assert!(lookup("link25000").1 <= 4);
+    Ok(())
+}
+
+#[test]
+fn dir_index_consistency() -> Result<()> {
+    const N: usize = 3000;
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let other = root.create("other", FileType::Dir, 0o777)?;
+    let free = sfs.info().bfree;
+    link_many(&root, &dir, N)?;
+    let index = || {
+        dir.downcast_ref::<INodeImpl>()
+            .unwrap()
+            .disk_inode
+            .read()
+            .index
+    };
+    assert_ne!(index(), 0);
+
+    let mut names: BTreeSet<String> = (0..N).map(|i| format!("link{}", i)).collect();
+    for i in (0..N).step_by(3) {
+        let name = format!("link{}", i);
+        dir.unlink(&name)?;
+        names.remove(&name);
+    }
+    for i in (1..N).step_by(3) {
+        let (old, new) = (format!("link{}", i), format!("renamed{}", i));
+        dir.move_(&old, &dir, &new)?;
+        names.remove(&old);
+        names.insert(new);
+    }
+    for i in (2..N).step_by(30) {
+        let (old, new) = (format!("link{}", i), format!("back{}", i));
+        dir.move_(&old, &other, &old)?;
+        other.move_(&old, &dir, &new)?;
+        names.remove(&old);
+        names.insert(new);
+    }
+
+    assert_eq!(sfs.check_dir_indexes()?, 0);
+    for name in names.iter() {
+        dir.find(name)?;
+    }
+    for name in &["link0", "link1", "link2", "renamed0"] {
+        assert_eq!(dir.find(name).err(), Some(FsError::EntryNotFound));
+    }
+    let listed: BTreeSet<String> = dir.list()?.into_iter().skip(2).collect();
+    assert_eq!(listed, names);
+
+    // dropped once the dir is small again, and no block leaks
+    for name in names.iter() {
+        dir.unlink(name)?;
+    }
+    assert_eq!(index(), 0);
+    for i in 0..(N - 1) / (LINK_MAX - 1) + 1 {
+        root.unlink(&format!("file{}", i))?;
+    }
+    assert_eq!(sfs.info().bfree, free);
+    Ok(())
+}
+
+#[test]
+fn clean_by_write_zeros() -> Result<()> {
+    let device = Arc::new(ZeroCountingDevice {
+        inner: Mutex::new(tempfile::tempfile().expect("failed to create file")),
+        zeros: Mutex::new(Vec::new()),
+    });
+    let sfs = SimpleFileSystem::create(device.clone(), 4096 * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let other = root.create("other", FileType::File, 0o777)?;
+    other.write_at(0, &[1; BLKSIZE])?;
+    // with the indirect block, so that new blocks are contiguous
+    let file = root.create("file", FileType::File, 0o777)?;
+    file.resize(13 * BLKSIZE)?;
+    file.write_at(13 * BLKSIZE - 4, b"data")?;
+    // leave garbage in the blocks to be reused
+    let garbage = root.create("garbage", FileType::File, 0o777)?;
+    garbage.write_at(0, &[0xff; 100 * BLKSIZE])?;
+    drop(garbage);
+    root.unlink("garbage")?;
+
+    device.zeros.lock().unwrap().clear();
+    file.resize(113 * BLKSIZE)?;
+    let zeros = device.zeros.lock().unwrap().clone();
+    assert_eq!(zeros.len(), 1, "{:?}", zeros);
+    assert_eq!(zeros[0].1, 100 * BLKSIZE);
+
+    let mut buf = vec![0xffu8; 101 * BLKSIZE];
+    assert_eq!(file.read_at(12 * BLKSIZE, &mut buf)?, buf.len());
+    assert_eq!(&buf[BLKSIZE - 4..BLKSIZE], b"data");
+    assert!(buf[BLKSIZE..].iter().all(|&b| b == 0));
+    let mut buf = vec![0u8; BLKSIZE];
+    other.read_at(0, &mut buf)?;
+    assert!(buf.iter().all(|&b| b == 1));
+    Ok(())
+}
+
+#[test]
+fn short_io_on_device_error() -> Result<()> {
+    let device = Arc::new(PoisonDevice {
+        inner: Mutex::new(tempfile::tempfile().expect("failed to create file")),
+        armed: AtomicBool::new(false),
+    });
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * BLKSIZE)?;
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    let mut data = vec![1; 2 * BLKSIZE];
+    data.resize(4 * BLKSIZE, POISON);
+    file.write_at(0, &data)?;
+    sfs.sync()?;
+    let bfree = sfs.info().bfree;
+    device.armed.store(true, Ordering::SeqCst);
+
+    // the bytes before the failing block
+    let mut buf = vec![0; 4 * BLKSIZE];
+    assert_eq!(file.read_at(100, &mut buf)?, 2 * BLKSIZE - 100);
+    assert_eq!(buf[..2 * BLKSIZE - 100], data[100..2 * BLKSIZE]);
+    // the error if nothing was read
+    assert_eq!(
+        file.read_at(2 * BLKSIZE, &mut buf),
+        Err(FsError::DeviceError)
+    );
+
+    let mut data = vec![2; BLKSIZE - 100];
+    data.resize(2 * BLKSIZE, POISON);
+    assert_eq!(file.write_at(100, &data)?, BLKSIZE - 100);
+    assert_eq!(
+        file.write_at(BLKSIZE, &data[BLKSIZE..]),
+        Err(FsError::DeviceError)
+    );
+    let mut buf = vec![0; BLKSIZE];
+    assert_eq!(file.read_at(0, &mut buf)?, BLKSIZE);
+    assert_eq!(buf[..100], [1; 100]);
+    assert_eq!(buf[100..], data[..BLKSIZE - 100]);
+
+    // the size only grows as far as written
+    let mut data = vec![3; 100];
+    data.resize(BLKSIZE + 100, POISON);
+    assert_eq!(file.write_at(4 * BLKSIZE - 100, &data)?, 100);
+    assert_eq!(file.metadata()?.size, 4 * BLKSIZE);
+    assert_eq!(sfs.info().bfree, bfree);
+    device.armed.store(false, Ordering::SeqCst);
+    Ok(())
+}
+
+#[test]
+fn create_with_context() -> Result<()> {
+    use rcore_fs::vfs::CreateContext;
+
+    let file = tempfile::tempfile().expect("failed to create file");
+    let reopen = || {
+        let file = file.try_clone().expect("failed to clone file");
+        SimpleFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open SFS")
+    };
+    let sfs = SimpleFileSystem::create(
+        Arc::new(Mutex::new(file.try_clone().unwrap())),
+        1024 * BLKSIZE,
+    )?;
+    let root = sfs.root_inode();
+    let ctx = |uid, gid, umask| CreateContext { uid, gid, umask };
+    let user = ctx(1000, 100, 0o022);
+    root.create3("file", FileType::File, 0o666, 0, &user)?;
+    let dir = root.create3("dir", FileType::Dir, 0o777, 0, &user)?;
+    dir.create3("file", FileType::File, 0o4755, 0, &ctx(7, 7, 0))?;
+    let shared = root.create3("shared", FileType::Dir, 0o2775, 0, &ctx(1, 50, 0o002))?;
+    shared.create3("file", FileType::File, 0o666, 0, &ctx(1000, 100, 0o077))?;
+    let sub = shared.create3("sub", FileType::Dir, 0o777, 0, &ctx(1000, 100, 0o077))?;
+    sub.create3("dir", FileType::Dir, 0o750, 0, &ctx(2000, 200, 0))?;
+    // without a context
+    shared.create("plain", FileType::File, 0o640)?;
+    root.create("plain", FileType::File, 0o777)?;
+    // chmod and chown
+    let chowned = root.create3("chowned", FileType::File, 0o644, 0, &user)?;
+    let mut metadata = chowned.metadata()?;
+    metadata.mode = 0o600;
+    metadata.uid = 3;
+    metadata.gid = 4;
+    chowned.set_metadata(&metadata)?;
+
+    let expected = [
+        ("file", 0o644, 1000, 100),
+        ("dir", 0o755, 1000, 100),
+        ("dir/file", 0o4755, 7, 7),
+        ("shared", 0o2775, 1, 50),
+        ("shared/file", 0o600, 1000, 50),
+        ("shared/sub", 0o2700, 1000, 50),
+        ("shared/sub/dir", 0o2750, 2000, 50),
+        ("shared/plain", 0o640, 0, 50),
+        ("plain", 0o777, 0, 0),
+        ("chowned", 0o600, 3, 4),
+    ];
+    let check = |root: Arc<dyn INode>| -> Result<()> {
+        for &(path, mode, uid, gid) in expected.iter() {
+            let metadata = root.lookup(path)?.metadata()?;
+            assert_eq!(
+                (metadata.mode, metadata.uid, metadata.gid),
+                (mode, uid, gid),
+                "{}",
+                path
+            );
+        }
+        Ok(())
+    };
+    check(root.clone())?;
+    drop((root, dir, shared, sub, chowned));
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = reopen();
+    check(sfs.root_inode())?;
+    drop(sfs);
+
+    // images before VERSION_OWNER have no owners
+    let device: Arc<dyn Device> = Arc::new(Mutex::new(file.try_clone().unwrap()));
+    let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    super_block.version = VERSION_INDEX;
+    device.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+    let sfs = reopen();
+    let root = sfs.root_inode();
+    for &(path, ..) in expected.iter() {
+        let metadata = root.lookup(path)?.metadata()?;
+        assert_eq!((metadata.mode, metadata.uid, metadata.gid), (0o777, 0, 0));
+    }
+    let file = root.create3("new", FileType::File, 0o600, 0, &user)?;
+    let metadata = file.metadata()?;
+    assert_eq!((metadata.mode, metadata.uid, metadata.gid), (0o777, 0, 0));
+    Ok(())
+}
+
+#[test]
+fn direct_io_through_block_cache() -> Result<()> {
+    use rcore_fs::dev::block_cache::{BlockCache, CacheStats};
+    use rcore_fs::file::File;
+
+    const BLOCKS: usize = 256;
+    let mem = MemDevice::new(BLOCKS);
+    let cache = Arc::new(BlockCache::new(mem.clone(), 32));
+    let on_media = |byte: u8| {
+        mem.0
+            .lock()
+            .unwrap()
+            .chunks(BLKSIZE)
+            .filter(|block| block.iter().all(|&b| b == byte))
+            .count()
+    };
+    let sfs = SimpleFileSystem::create(cache.clone(), BLOCKS * BLKSIZE)?;
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    file.write_at(0, &[1; 2 * BLKSIZE])?;
+    assert_eq!(on_media(1), 0);
+
+    // dirty blocks are written back first
+    let stats = cache.stats();
+    let mut buf = vec![0; 2 * BLKSIZE];
+    assert_eq!(file.read_at_direct(0, &mut buf)?, 2 * BLKSIZE);
+    assert!(buf.iter().all(|&b| b == 1));
+    assert_eq!(on_media(1), 2);
+    assert_eq!(cache.stats(), stats);
+
+    // the cached copy is dropped
+    assert_eq!(file.write_at_direct(0, &[2; BLKSIZE])?, BLKSIZE);
+    assert_eq!(on_media(2), 1);
+    let stats = CacheStats {
+        invalidations: stats.invalidations + 1,
+        ..stats
+    };
+    assert_eq!(cache.stats(), stats);
+    let mut buf = vec![0; BLKSIZE];
+    file.read_at(0, &mut buf)?;
+    assert!(buf.iter().all(|&b| b == 2));
+    file.read_at(BLKSIZE, &mut buf)?;
+    assert!(buf.iter().all(|&b| b == 1));
+    let stats = CacheStats {
+        hits: stats.hits + 1,
+        misses: stats.misses + 1,
+        ..stats
+    };
+    assert_eq!(cache.stats(), stats);
+
+    // the tail of the file is read as a whole block
+    file.resize(2 * BLKSIZE + 10)?;
+    let mut buf = vec![0xff; 3 * BLKSIZE];
+    assert_eq!(file.read_at_direct(BLKSIZE, &mut buf)?, BLKSIZE + 10);
+    assert!(buf[..BLKSIZE].iter().all(|&b| b == 1));
+    assert!(buf[BLKSIZE..BLKSIZE + 10].iter().all(|&b| b == 0));
+
+    // only aligned I/O
+    let mut buf = vec![0; BLKSIZE];
+    assert_eq!(
+        file.read_at_direct(100, &mut buf),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(
+        file.read_at_direct(0, &mut buf[..100]),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(
+        file.write_at_direct(0, &buf[..100]),
+        Err(FsError::InvalidParam)
+    );
+
+    // by a file handle
+    let mut handle = File::new(file.clone(), true, true);
+    handle.set_direct(true);
+    assert_eq!(handle.write(&[3; BLKSIZE])?, BLKSIZE);
+    assert_eq!(on_media(3), 1);
+    assert_eq!(handle.write(&[3; 100]), Err(FsError::InvalidParam));
+    Ok(())
+}
+
+/// Hits on a file of 8 blocks read again after streaming a file of 256
+/// blocks through a cache of 64, opened with `hint`
+fn hot_hits_after_stream(hint: CacheHint) -> Result<u64> {
+    use rcore_fs::dev::block_cache::BlockCache;
+    use rcore_fs::file::File;
+
+    const BLOCKS: usize = 512;
+    let mem = MemDevice::new(BLOCKS);
+    let cache = Arc::new(BlockCache::new(mem, 64));
+    let sfs = SimpleFileSystem::create(cache.clone(), BLOCKS * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let hot = root.create("hot", FileType::File, 0o777)?;
+    hot.write_at(0, &[1; 8 * BLKSIZE])?;
+    let big = root.create("big", FileType::File, 0o777)?;
+    big.write_at(0, &vec![2; 256 * BLKSIZE])?;
+    sfs.sync()?;
+
+    let mut buf = vec![0; 8 * BLKSIZE];
+    assert_eq!(hot.read_at(0, &mut buf)?, buf.len());
+    let mut file = File::new(big, true, false);
+    file.set_cache_hint(hint);
+    let mut chunk = vec![0; 16 * BLKSIZE];
+    for _ in 0..16 {
+        assert_eq!(file.read(&mut chunk)?, chunk.len());
+        assert!(chunk.iter().all(|&b| b == 2));
+    }
+    let before = cache.stats().hits;
+    assert_eq!(hot.read_at(0, &mut buf)?, buf.len());
+    assert!(buf.iter().all(|&b| b == 1));
+    Ok(cache.stats().hits - before)
+}
+
+#[test]
+fn cache_hint_drop_after_use() -> Result<()> {
+    assert_eq!(hot_hits_after_stream(CacheHint::Normal)?, 0);
+    assert_eq!(hot_hits_after_stream(CacheHint::DropAfterUse)?, 8);
+
+    // ignored by a device without hints
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o777)?;
+    file.set_cache_hint(CacheHint::DropAfterUse);
+    file.write_at(100, &[3; 3 * BLKSIZE])?;
+    let mut buf = vec![0; 3 * BLKSIZE];
+    assert_eq!(file.read_at(100, &mut buf)?, buf.len());
+    assert!(buf.iter().all(|&b| b == 3));
+    Ok(())
+}
+
+#[test]
+fn inode_flags_capability_of_old_images() -> Result<()> {
+    let file = tempfile::tempfile().unwrap();
+    let open = || -> Arc<dyn Device> { Arc::new(Mutex::new(file.try_clone().unwrap())) };
+    let sfs = SimpleFileSystem::create(open(), 1024 * 4096)?;
+    assert!(sfs.capabilities().contains(FsCapabilities::INODE_FLAGS));
+    sfs.root_inode().create("file", FileType::File, 0o777)?;
+    drop(sfs);
+
+    let device = open();
+    let mut super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    super_block.version = VERSION_FLAGS - 1;
+    device.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+    let sfs = SimpleFileSystem::open(device)?;
+    assert!(!sfs.capabilities().contains(FsCapabilities::INODE_FLAGS));
+    let file = sfs.root_inode().find("file")?;
+    assert_eq!(
+        file.set_flags(InodeFlags::APPEND_ONLY),
+        Err(FsError::Unsupported)
+    );
+    // still meaningless for a dir
+    assert_eq!(sfs.root_inode().resize(0), Err(FsError::IsDir));
+    Ok(())
+}
+
+#[test]
+fn sync_partial() -> Result<()> {
+    const BLOCKS: usize = 2048;
+    const FILES: usize = 240;
+    let content = |i: usize| vec![i as u8; 2 * BLKSIZE];
+    // synced files, each then given 2 blocks of data, kept open as inodes
+    // sync on drop
+    type DirtySfs = (MockDevice, Arc<SimpleFileSystem>, Vec<Arc<dyn INode>>);
+    let dirty_sfs = || -> Result<DirtySfs> {
+        let device = MockDevice::new(BLOCKS);
+        let sfs =
+            SimpleFileSystem::create_with_seed(Arc::new(device.clone()), BLOCKS * BLKSIZE, 1)?;
+        let root = sfs.root_inode();
+        let files = (0..FILES)
+            .map(|i| root.create(&format!("f{}", i), FileType::File, 0o644))
+            .collect::<Result<Vec<_>>>()?;
+        sfs.sync()?;
+        for (i, file) in files.iter().enumerate() {
+            file.write_at(0, &content(i))?;
+        }
+        Ok((device, sfs, files))
+    };
+    // files with all their data on a copy of the image, failing if any
+    // has only part of it
+    let synced_files = |image: Vec<u8>| -> Result<usize> {
+        let sfs = SimpleFileSystem::open(Arc::new(MemDevice::with_image(image)))?;
+        let mut synced = 0;
+        for i in 0..FILES {
+            let file = sfs.root_inode().find(&format!("f{}", i))?;
+            match file.metadata()?.size {
+                0 => {}
+                size => {
+                    assert_eq!(size, 2 * BLKSIZE);
+                    let mut buf = vec![0; size];
+                    file.read_at(0, &mut buf)?;
+                    assert_eq!(buf, content(i));
+                    synced += 1;
+                }
+            }
+        }
+        Ok(synced)
+    };
+    // what an image holds, apart from the padding of its structs
+    let on_disk = |image: &[u8]| -> Result<(Vec<u8>, usize, Vec<Metadata>)> {
+        let free_map = image[BLKN_FREEMAP * BLKSIZE..(BLKN_FREEMAP + 1) * BLKSIZE].to_vec();
+        let device = MemDevice(Arc::new(Mutex::new(image.to_vec())));
+        let sfs = SimpleFileSystem::open(Arc::new(device))?;
+        let metadata = (0..FILES)
+            .map(|i| {
+                let mut metadata = sfs.root_inode().find(&format!("f{}", i))?.metadata()?;
+                // differs between instances
+                metadata.dev = 0;
+                Ok(metadata)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok((free_map, sfs.info().bfree, metadata))
+    };
+
+    let (device, sfs, _files) = dirty_sfs()?;
+    let writes = device.writes.load(Ordering::SeqCst);
+    // a budget of 0 only reports
+    let progress = sfs.sync_partial(0)?;
+    assert_eq!(device.writes.load(Ordering::SeqCst), writes);
+    assert_eq!(progress.written_blocks, 0);
+    assert!(progress.remaining_dirty_blocks > FILES);
+    let mut remaining = progress.remaining_dirty_blocks;
+    let mut synced = 0;
+    let mut calls = 0;
+    loop {
+        let writes = device.writes.load(Ordering::SeqCst);
+        let progress = sfs.sync_partial(64)?;
+        let written = device.writes.load(Ordering::SeqCst) - writes;
+        assert!(written <= 64);
+        assert_eq!(progress.written_blocks, written);
+        assert!(progress.remaining_dirty_blocks < remaining);
+        remaining = progress.remaining_dirty_blocks;
+        calls += 1;
+
+        let image = device.mem.image();
+        let now = synced_files(image)?;
+        assert!(now >= synced);
+        synced = now;
+        if progress.completed {
+            assert_eq!(remaining, 0);
+            break;
+        }
+    }
+    assert!(calls > 1);
+    assert_eq!(synced, FILES);
+    let progress = sfs.sync_partial(64)?;
+    assert_eq!(progress.written_blocks, 0);
+    assert!(progress.completed);
+
+    let (full_device, full_sfs, _full_files) = dirty_sfs()?;
+    full_sfs.sync()?;
+    let full_image = full_device.mem.image();
+    let image = device.mem.image();
+    assert!(on_disk(&full_image)? == on_disk(&image)?);
+    Ok(())
+}
+
+#[test]
+fn silly_rename() -> Result<()> {
+    const BLOCKS: usize = 256;
+    let (mem, sfs) = mem_sfs(BLOCKS)?;
+    assert_eq!(sfs.set_silly_rename(true)?, 0);
+    let root = sfs.root_inode();
+    let free = sfs.info().bfree;
+    let data = vec![7u8; 3 * BLKSIZE];
+
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &data)?;
+    let hidden = format!(".sfs-unlinked-{}", file.metadata()?.inode);
+    root.create("other", FileType::File, 0o644)?;
+    root.unlink("file")?;
+    assert_eq!(root.list()?, [".", "..", "other"]);
+    assert!(matches!(root.find("file"), Err(FsError::EntryNotFound)));
+    assert_eq!(
+        root.find(&hidden)?.metadata()?.inode,
+        file.metadata()?.inode
+    );
+    let mut buf = vec![0; data.len()];
+    assert_eq!(file.read_at(0, &mut buf)?, data.len());
+    assert_eq!(buf, data);
+    assert_eq!(file.metadata()?.nlinks, 1);
+
+    // closing it removes the hidden entry and the blocks
+    drop(file);
+    assert!(matches!(root.find(&hidden), Err(FsError::EntryNotFound)));
+    assert_eq!(root.list()?, [".", "..", "other"]);
+    root.unlink("other")?;
+    assert_eq!(sfs.info().bfree, free);
+
+    // a file not open is unlinked at once
+    root.create("closed", FileType::File, 0o644)?;
+    root.unlink("closed")?;
+    assert_eq!(root.list()?, [".", ".."]);
+    assert_eq!(sfs.info().bfree, free);
+
+    // crash before closing
+    let file = root.create("crash", FileType::File, 0o644)?;
+    file.write_at(0, &data)?;
+    let hidden = format!(".sfs-unlinked-{}", file.metadata()?.inode);
+    root.unlink("crash")?;
+    sfs.sync()?;
+    let image = mem.image();
+
+    let sfs = SimpleFileSystem::open(Arc::new(MemDevice::with_image(image)))?;
+    let root = sfs.root_inode();
+    assert_eq!(root.list()?, [".", "..", hidden.as_str()]);
+    assert_eq!(sfs.info().bfree, free - 4);
+    assert_eq!(sfs.set_silly_rename(true)?, 1);
+    assert_eq!(root.list()?, [".", ".."]);
+    assert!(matches!(root.find(&hidden), Err(FsError::EntryNotFound)));
+    assert_eq!(sfs.info().bfree, free);
+    Ok(())
+}
+
+/// Replace the numbers after "inode " by their order of appearance
+fn remap_inode_ids(dump: &str) -> String {
+    let mut ids = Vec::new();
+    let mut out = String::new();
+    let mut rest = dump;
+    while let Some(pos) = rest.find("inode ") {
+        out.push_str(&rest[..pos + 6]);
+        rest = &rest[pos + 6..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if len == 0 {
+            continue;
+        }
+        let id = &rest[..len];
+        let index = match ids.iter().position(|&known| known == id) {
+            Some(index) => index,
+            None => {
+                ids.push(id);
+                ids.len() - 1
+            }
+        };
+        out.push_str(&format!("#{}", index));
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Build a small tree to dump on a fresh fs
+fn dump_sample(mem: &MemDevice) -> Result<Arc<SimpleFileSystem>> {
+    let sfs = SimpleFileSystem::create_with_seed(Arc::new(mem.clone()), 256 * BLKSIZE, 0)?;
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &[1; 2 * BLKSIZE + 1])?;
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    dir.create("empty", FileType::Dir, 0o755)?;
+    let link = dir.create("link", FileType::SymLink, 0o777)?;
+    link.write_at(0, b"../file")?;
+    dir.link("hard", &file)?;
+    let big = dir.create("big", FileType::File, 0o644)?;
+    big.resize((NDIRECT + 2) * BLKSIZE)?;
+    Ok(sfs)
+}
+
+#[test]
+fn dump_tree() -> Result<()> {
+    let mem = MemDevice::new(256);
+    let sfs = dump_sample(&mem)?;
+    let mut dump = String::new();
+    sfs.dump_tree(&mut dump, DumpOpts::default())?;
+    assert_eq!(
+        remap_inode_ids(&dump),
+        r#"superblock: valid, version 13, label "simple file system"
+  blocks 256, unused 225, freemap blocks 1
+  recovery blocks 8
+options: read-write, inode cache 0, scratch buffers 4, dir readahead 32, io burst 256
+  alloc groups true, silly rename false, deterministic false, clock false
+freemap: 225 free blocks in 2 runs
+  runs of 64-127: 2
+/ (inode #0, Dir, size 1040, nlinks 3, blocks 1)
+  dir/ (inode #1, Dir, size 1560, nlinks 3, blocks 1)
+    big (inode #2, File, size 57344, nlinks 1, blocks 14)
+    empty/ (inode #3, Dir, size 520, nlinks 2, blocks 1)
+    hard (inode #4, File, size 8193, nlinks 2, blocks 3)
+    link (inode #5, SymLink, size 7, nlinks 1, blocks 0)
+  file (inode #4, File, size 8193, nlinks 2, blocks 3)
+"#
+    );
+
+    let mut dump = String::new();
+    sfs.dump_tree(&mut dump, DumpOpts { block_map: true })?;
+    let lines: Vec<_> = dump.lines().map(str::trim_start).collect();
+    assert_eq!(lines.iter().filter(|l| l.starts_with("data: ")).count(), 7);
+    let big = lines.iter().position(|l| l.starts_with("big ")).unwrap();
+    assert!(lines[big + 1].starts_with("indirect: "));
+    let link = lines.iter().position(|l| l.starts_with("link ")).unwrap();
+    assert_eq!(lines[link + 1], "data: inline");
+    // 14 blocks in a single run
+    let data: Vec<usize> = lines[big + 2]["data: ".len()..]
+        .split('-')
+        .map(|id| id.parse().unwrap())
+        .collect();
+    assert_eq!(data[1] - data[0] + 1, 14);
+    assert!(!dump.contains('!'));
+    Ok(())
+}
+
+#[test]
+fn dump_tree_reports_corruption_inline() -> Result<()> {
+    let mem = MemDevice::new(256);
+    let sfs = dump_sample(&mem)?;
+    let dir = sfs.root_inode().find("dir")?;
+    let dir_block = sfs
+        .get_inode(dir.metadata()?.inode)?
+        .disk_inode
+        .read()
+        .direct[0] as usize;
+    drop(dir);
+    drop(sfs);
+
+    // point "empty" to a free block
+    {
+        let mut image = mem.0.lock().unwrap();
+        let block = &mut image[dir_block * BLKSIZE..(dir_block + 1) * BLKSIZE];
+        let entry = (2..BLKSIZE / DIRENT_SIZE)
+            .map(|i| i * DIRENT_SIZE)
+            .find(|&pos| block[pos + 4..pos + 10] == *b"empty\0")
+            .unwrap();
+        block[entry..entry + 4].copy_from_slice(&200u32.to_ne_bytes());
+    }
+    let sfs = SimpleFileSystem::open(Arc::new(mem))?;
+    let mut dump = String::new();
+    sfs.dump_tree(&mut dump, DumpOpts { block_map: true })?;
+    assert!(dump.contains("\n    empty (inode 200) ! marked free\n"));
+    // the rest is still there
+    assert!(dump.contains("\n    hard (inode "));
+    assert!(dump.contains("\n    link (inode "));
+    assert!(dump.contains("\n  file (inode "));
+    Ok(())
+}
+
+#[test]
+fn transaction_crash_prefixes() -> Result<()> {
+    const BLOCKS: usize = 256;
+    let (device, sfs) = mock_sfs(BLOCKS)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    // entries over more than one block
+    for i in 0..20 {
+        dir.create(&format!("keep{}", i), FileType::File, 0o644)?;
+    }
+    dir.create("old", FileType::File, 0o644)?
+        .write_at(0, b"old")?;
+    sfs.sync()?;
+    let before = device.mem.image();
+    device.logging.store(true, Ordering::SeqCst);
+
+    let mut txn = sfs.transaction();
+    for name in ["a", "b", "c"] {
+        txn.create2(&dir, name, FileType::File, 0o644, 0)?;
+        txn.write_at(&dir, name, 0, name.repeat(5000).as_bytes())?;
+    }
+    txn.move_(&dir, "old", &dir, "new")?;
+    txn.commit()?;
+    device.logging.store(false, Ordering::SeqCst);
+    let log = core::mem::take(&mut *device.log.lock().unwrap());
+    let after = device.mem.image();
+
+    // files of "dir" with their content
+    let files = |image: Vec<u8>| -> Result<BTreeMap<String, Vec<u8>>> {
+        let sfs = SimpleFileSystem::open(Arc::new(MemDevice::with_image(image)))?;
+        let dir = sfs.root_inode().find("dir")?;
+        let mut files = BTreeMap::new();
+        for name in dir.list()?.into_iter().skip(2) {
+            let file = dir.find(&name)?;
+            let mut buf = vec![0; file.metadata()?.size];
+            file.read_at(0, &mut buf)?;
+            files.insert(name, buf);
+        }
+        Ok(files)
+    };
+    let old = files(before.clone())?;
+    let new = files(after)?;
+    assert!(old.contains_key("old") && !old.contains_key("a"));
+    assert_eq!(new["a"], b"a".repeat(5000));
+    assert_eq!(new["new"], b"old");
+    assert_eq!(new.len(), old.len() + 3);
+
+    // a crash after any write shows all or none of the changes
+    let mut switched = false;
+    for len in 0..=log.len() {
+        let mut image = before.clone();
+        for (id, data) in log[..len].iter() {
+            image[id * BLKSIZE..(id + 1) * BLKSIZE].copy_from_slice(data);
+        }
+        let files = files(image)?;
+        if files == new {
+            switched = true;
+        } else {
+            assert!(!switched, "back to old after {} writes", len);
+            assert_eq!(files, old, "partial after {} writes", len);
+        }
+    }
+    assert!(switched);
+    Ok(())
+}
+
+#[test]
+fn atomic_replace_crash_prefixes() -> Result<()> {
+    use rcore_fs::file::{File, SyncPolicy};
+
+    const BLOCKS: usize = 256;
+    let (device, sfs) = mock_sfs(BLOCKS)?;
+    let dir = sfs.root_inode().create("dir", FileType::Dir, 0o755)?;
+    for i in 0..20 {
+        dir.create(&format!("keep{}", i), FileType::File, 0o644)?;
+    }
+    let old = b"old".repeat(5000);
+    dir.create("target", FileType::File, 0o644)?
+        .write_at(0, &old)?;
+    sfs.sync()?;
+    let before = device.mem.image();
+    device.logging.store(true, Ordering::SeqCst);
+
+    // write a temporary file synced on close, move it over, sync the dir
+    let new = b"new!".repeat(5000);
+    let tmp = dir.create("tmp", FileType::File, 0o644)?;
+    let mut file = File::new(tmp, false, true);
+    file.set_sync_on_close(SyncPolicy::All);
+    assert_eq!(file.write(&new)?, new.len());
+    file.close()?;
+    dir.move_("tmp", &dir, "target")?;
+    dir.sync_all()?;
+    device.logging.store(false, Ordering::SeqCst);
+    let log = core::mem::take(&mut *device.log.lock().unwrap());
+
+    let target = |image: Vec<u8>| -> Result<Vec<u8>> {
+        let sfs = SimpleFileSystem::open(Arc::new(MemDevice::with_image(image)))?;
+        let file = sfs.root_inode().find("dir")?.find("target")?;
+        let mut buf = vec![0; file.metadata()?.size];
+        assert_eq!(file.read_at(0, &mut buf)?, buf.len());
+        Ok(buf)
+    };
+    // a crash after any write leaves the whole old or new content
+    let mut switched = false;
+    for len in 0..=log.len() {
+        let mut image = before.clone();
+        for (id, data) in log[..len].iter() {
+            image[id * BLKSIZE..(id + 1) * BLKSIZE].copy_from_slice(data);
+        }
+        let content = target(image)?;
+        if content == new {
+            switched = true;
+        } else {
+            assert!(!switched, "back to old after {} writes", len);
+            assert!(content == old, "partial after {} writes", len);
+        }
+    }
+    assert!(switched);
+    Ok(())
+}
+
+#[test]
+fn transaction_abort() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    root.create("old", FileType::File, 0o644)?;
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    sfs.sync()?;
+    let free = sfs.info().bfree;
+
+    // dropped without commit
+    let mut txn = sfs.transaction();
+    txn.create2(&root, "a", FileType::File, 0o644, 0)?;
+    txn.write_at(&root, "a", 0, &[1; 3 * BLKSIZE])?;
+    txn.create2(&dir, "sub", FileType::Dir, 0o755, 0)?;
+    txn.unlink(&root, "old")?;
+    drop(txn);
+    assert_eq!(root.list()?, [".", "..", "old", "dir"]);
+    assert_eq!(dir.list()?, [".", ".."]);
+    assert_eq!(sfs.info().bfree, free);
+
+    // a failing commit changes nothing either
+    let mut txn = sfs.transaction();
+    txn.create2(&root, "a", FileType::File, 0o644, 0)?;
+    txn.write_at(&root, "a", 0, &[1; 3 * BLKSIZE])?;
+    txn.create2(&dir, "sub", FileType::Dir, 0o755, 0)?;
+    txn.move_(&root, "old", &dir, "moved")?;
+    // only files created by the transaction can be written
+    txn.write_at(&dir, "moved", 0, b"data")?;
+    assert_eq!(txn.commit(), Err(FsError::InvalidParam));
+    assert_eq!(root.list()?, [".", "..", "old", "dir"]);
+    assert_eq!(dir.list()?, [".", ".."]);
+    assert_eq!(dir.metadata()?.nlinks, 2);
+    assert_eq!(sfs.info().bfree, free);
+
+    // the dirs are free again
+    root.create("b", FileType::File, 0o644)?;
+    dir.create("c", FileType::File, 0o644)?;
+    Ok(())
+}
+
+#[test]
+fn transaction_holds_dirs() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    let other = root.create("other", FileType::Dir, 0o755)?;
+    let file = dir.create("file", FileType::File, 0o644)?;
+
+    let mut txn = sfs.transaction();
+    txn.create2(&dir, "new", FileType::File, 0o644, 0)?;
+    txn.write_at(&dir, "new", 0, b"new")?;
+    assert_eq!(
+        txn.write_at(&dir, "new", 3, &vec![0; TXN_MAX_WRITE]).err(),
+        Some(FsError::InvalidParam)
+    );
+    // changes by other means fail until the guard is dropped
+    assert_eq!(
+        dir.create("new", FileType::File, 0o644).err(),
+        Some(FsError::Busy)
+    );
+    assert_eq!(dir.unlink("file"), Err(FsError::Busy));
+    assert_eq!(dir.link("link", &file), Err(FsError::Busy));
+    assert_eq!(other.move_("x", &dir, "x"), Err(FsError::Busy));
+    assert_eq!(sfs.transaction().unlink(&dir, "file"), Err(FsError::Busy));
+    // reads and other dirs are not held
+    assert_eq!(dir.find("file")?.metadata()?.inode, file.metadata()?.inode);
+    other.create("x", FileType::File, 0o644)?;
+    let mut second = sfs.transaction();
+    second.move_(&other, "x", &root, "y")?;
+    second.commit()?;
+
+    txn.commit()?;
+    assert_eq!(dir.list()?, [".", "..", "file", "new"]);
+    let mut buf = [0; 4];
+    assert_eq!(dir.find("new")?.read_at(0, &mut buf)?, 3);
+    assert_eq!(&buf[..3], b"new");
+    assert_eq!(root.list()?, [".", "..", "dir", "other", "y"]);
+    dir.unlink("file")?;
+    Ok(())
+}
+
+#[test]
+fn dir_readahead() -> Result<()> {
+    const BLOCKS: usize = 1024;
+    const FILES: usize = 200;
+    let device = Arc::new(WatchedReads {
+        mem: MemDevice::new(BLOCKS),
+        watched: Mutex::new(BTreeSet::new()),
+        reads: AtomicUsize::new(0),
+    });
+    let sfs = SimpleFileSystem::create(device.clone(), BLOCKS * BLKSIZE)?;
+    let dir = sfs.root_inode().create("dir", FileType::Dir, 0o755)?;
+    let mut inodes = BTreeSet::new();
+    for i in 0..FILES {
+        let file = dir.create(&format!("f{}", i), FileType::File, 0o644)?;
+        inodes.insert(file.metadata()?.inode);
+    }
+    sfs.sync()?;
+    *device.watched.lock().unwrap() = inodes;
+    // reads of the inodes of the files
+    let walk = || -> Result<(Vec<(Metadata, String)>, usize)> {
+        device.reads.store(0, Ordering::SeqCst);
+        let entries = (0..FILES + 2)
+            .map(|id| dir.get_entry_with_metadata(id))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((entries, device.reads.load(Ordering::SeqCst)))
+    };
+
+    sfs.set_dir_readahead(0);
+    let (entries, reads) = walk()?;
+    assert_eq!(reads, FILES);
+    sfs.set_dir_readahead(32);
+    let (listed, reads) = walk()?;
+    assert_eq!(listed, entries);
+    // inode blocks are adjacent but where the dir grew by a block
+    let runs = dir.metadata()?.blocks;
+    assert!(reads <= runs + FILES.div_ceil(32), "{} reads", reads);
+
+    // a dirty inode in memory is neither read nor replaced
+    let file = dir.find("f5")?;
+    let mut metadata = file.metadata()?;
+    metadata.mtime = Timespec { sec: 42, nsec: 0 };
+    file.set_metadata(&metadata)?;
+    let (listed, _) = walk()?;
+    assert_eq!(listed[7], (metadata, String::from("f5")));
+    assert_eq!(dir.find("f5")?.metadata()?.mtime.sec, 42);
+    assert_eq!(listed[8..], entries[8..]);
+
+    // files unlinked while loaded ahead are freed at once
+    drop(file);
+    assert_eq!(dir.get_entry_with_metadata(8)?.1, "f6");
+    let free = sfs.info().bfree;
+    dir.unlink("f6")?;
+    assert_eq!(sfs.info().bfree, free + 1);
+    Ok(())
+}
+
+#[test]
+fn super_block_written_only_if_changed() -> Result<()> {
+    let (device, sfs) = yielding_sfs(256)?;
+    let writes = || device.super_block_writes.swap(0, Ordering::SeqCst);
+    writes();
+    let free = sfs.info().bfree;
+    let blocks: Vec<_> = (0..10).map(|_| sfs.alloc_block(None).unwrap()).collect();
+    assert_eq!(sfs.info().bfree, free - 10);
+    for &id in blocks.iter() {
+        sfs.free_block(id);
+    }
+    sfs.sync()?;
+    assert_eq!(writes(), 0);
+
+    // the same through files
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.resize(4 * BLKSIZE)?;
+    drop(file);
+    root.unlink("file")?;
+    sfs.sync()?;
+    assert_eq!(writes(), 0);
+    assert_eq!(sfs.info().bfree, free);
+
+    // a changed count is written once
+    let id = sfs.alloc_block(None).unwrap();
+    sfs.sync()?;
+    sfs.sync()?;
+    assert_eq!(writes(), 1);
+    sfs.free_block(id);
+    Ok(())
+}
+
+#[test]
+fn concurrent_alloc_and_free() -> Result<()> {
+    const THREADS: usize = 4;
+    let (device, sfs) = yielding_sfs(1024)?;
+    let root = sfs.root_inode();
+    let (done, finished) = std::sync::mpsc::channel();
+    for t in 0..THREADS {
+        let sfs = sfs.clone();
+        let file = root.create(&format!("f{}", t), FileType::File, 0o644)?;
+        let done = done.clone();
+        std::thread::spawn(move || {
+            let result = (|| -> Result<()> {
+                for round in 0..20 {
+                    let blocks: Vec<_> = (0..8).filter_map(|_| sfs.alloc_block(None)).collect();
+                    // with the inode locked in between
+                    file.resize((round % 4) * NDIRECT * BLKSIZE)?;
+                    if round % 5 == t {
+                        sfs.sync()?;
+                    }
+                    for id in blocks {
+                        sfs.free_block(id);
+                    }
+                }
+                Ok(())
+            })();
+            done.send(result).unwrap();
+        });
+    }
+    for _ in 0..THREADS {
+        let result = finished.recv_timeout(std::time::Duration::from_secs(60));
+        result.expect("deadlock")?;
+    }
+
+    let free = sfs.free_map.read().count_ones();
+    assert_eq!(sfs.info().bfree, free);
+    drop(root);
+    sfs.sync()?;
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(Arc::new(device))?;
+    assert_eq!(sfs.super_block.read().unused_blocks as usize, free);
+    assert_eq!(sfs.free_map.read().count_ones(), free);
+    Ok(())
+}
+
+#[test]
+fn wear_tracking() -> Result<()> {
+    const BLOCKS: usize = 512;
+    const FILES: usize = 16;
+    let mem = MemDevice::new(BLOCKS);
+    let device = Arc::new(WearTrackingDevice::new(Arc::new(mem), BLKSIZE_LOG2));
+    let sfs = SimpleFileSystem::create(device.clone(), BLOCKS * BLKSIZE)?;
+    sfs.sync()?;
+    device.reset();
+    // files of 2 blocks written by 1 KiB, synced one by one
+    let dir = sfs.root_inode().create("dir", FileType::Dir, 0o755)?;
+    for i in 0..FILES {
+        let file = dir.create(&format!("f{}", i), FileType::File, 0o644)?;
+        for offset in (0..2 * BLKSIZE).step_by(1024) {
+            file.write_at(offset, &[i as u8; 1024])?;
+        }
+        sfs.sync()?;
+    }
+
+    let top = device.top_n(4);
+    assert!(
+        top.iter().any(|&(chunk, _)| chunk == BLKN_SUPER),
+        "{:?}",
+        top
+    );
+    let all = device.top_n(BLOCKS);
+    let writes: u64 = all.iter().map(|&(_, count)| count).sum();
+    assert_eq!(device.histogram(7).iter().sum::<usize>() as u64, writes);
+    // the data, partly zeroed first as the files grow, then the inodes,
+    // entries, freemap and superblock written on each sync
+    let (logical, physical) = device.write_amplification();
+    assert_eq!(logical, (FILES * 2 * BLKSIZE) as u64);
+    assert!(physical > logical, "{} {}", logical, physical);
+    assert!(physical < logical * 2, "{} {}", logical, physical);
+    Ok(())
+}
+
+#[test]
+fn pack_subtree_into_image() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    root.create("outside", FileType::File, 0o644)?;
+    let out = root.create("out", FileType::Dir, 0o750)?;
+    let bin = out.create("bin", FileType::Dir, 0o755)?;
+    let tool = bin.create("tool", FileType::File, 0o755)?;
+    let data: Vec<u8> = (0..300 * BLKSIZE + 5).map(|i| (i * 13) as u8).collect();
+    tool.write_at(0, &data)?;
+    out.link("tool", &tool)?;
+    bin.link("tool2", &tool)?;
+    let link = out.create("latest", FileType::SymLink, 0o777)?;
+    link.write_at(0, b"bin/tool")?;
+    let mut meta = tool.metadata()?;
+    meta.uid = 1000;
+    meta.mtime = Timespec { sec: 42, nsec: 1 };
+    tool.set_metadata(&meta)?;
+
+    let new_device = || Arc::new(MockDevice::new(2048));
+    let opts = PackOptions {
+        slack_percent: 20,
+        seed: Some(7),
+        compress: None,
+        skip_corrupt: false,
+    };
+    let device = new_device();
+    let packed = pack_subtree(&out, device.clone(), opts)?;
+    let writes = device.writes.load(Ordering::SeqCst);
+    let info = packed.info();
+    drop(packed);
+
+    let packed = SimpleFileSystem::open(device.clone())?;
+    let root = packed.root_inode();
+    assert_eq!(compare_tree(&root)?, compare_tree(&out)?);
+    let id = root.lookup("bin/tool")?.metadata()?.inode;
+    assert_eq!(root.lookup("bin/tool2")?.metadata()?.inode, id);
+    assert_eq!(root.lookup("tool")?.metadata()?.inode, id);
+    assert_eq!(root.metadata()?.mode, 0o750);
+    // sized to the tree
+    let used = info.blocks - info.bfree;
+    assert!(info.blocks * 100 <= (used + 1) * 120, "{:?}", info);
+    assert!(info.blocks * 100 >= (used - 1) * 120, "{:?}", info);
+
+    // reproducible with the same seed
+    drop(root);
+    packed.unmount()?;
+    drop(packed);
+    let again = new_device();
+    drop(pack_subtree(&out, again.clone(), opts)?);
+    assert!(again.mem.image() == device.mem.image());
+
+    // an error leaves no valid image, even once the fs is dropped, up to
+    // the last write before the superblock and its 2 backups
+    for fail_after in [1, writes / 2, writes - 3] {
+        let device = new_device();
+        device.writes_left.store(fail_after, Ordering::SeqCst);
+        assert!(pack_subtree(&out, device.clone(), opts).is_err());
+        device.writes_left.store(usize::MAX, Ordering::SeqCst);
+        assert!(SimpleFileSystem::open(device).is_err());
+    }
+    Ok(())
+}
+
+#[test]
+fn type_errors() -> Result<()> {
+    let sfs = SimpleFileSystem::create(Arc::new(MemDevice::new(64)), 64 * BLKSIZE)?;
+    let root = sfs.root_inode();
+    root.create("file", FileType::File, 0o644)?
+        .write_at(0, b"data")?;
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    dir.create("empty", FileType::File, 0o644)?;
+    root.create("link", FileType::SymLink, 0o777)?
+        .write_at(0, b"file")?;
+    root.create2(
+        "tty",
+        FileType::CharDevice,
+        0o666,
+        rcore_fs::vfs::make_rdev(4, 1),
+    )?;
+    rcore_fs::conformance::check_type_errors(&root);
+    assert_eq!(root.list()?, [".", "..", "file", "dir", "link", "tty"]);
+    Ok(())
+}
+
+/// Run `a` and `b` on two threads started at once, panicking if they do not
+/// finish, e.g. on a deadlock
+fn run_together<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send + 'static,
+    B: FnOnce() -> RB + Send + 'static,
+    RA: Send + 'static,
+    RB: Send + 'static,
+{
+    let barrier = Arc::new(std::sync::Barrier::new(2));
+    let (done_a, finished_a) = std::sync::mpsc::channel();
+    let (done_b, finished_b) = std::sync::mpsc::channel();
+    let barrier_b = barrier.clone();
+    std::thread::spawn(move || {
+        barrier.wait();
+        done_a.send(a()).unwrap();
+    });
+    std::thread::spawn(move || {
+        barrier_b.wait();
+        done_b.send(b()).unwrap();
+    });
+    let timeout = std::time::Duration::from_secs(60);
+    (
+        finished_a.recv_timeout(timeout).expect("deadlock"),
+        finished_b.recv_timeout(timeout).expect("deadlock"),
+    )
+}
+
+#[test]
+fn concurrent_dir_changes() -> Result<()> {
+    const ROUNDS: usize = 20;
+    let (_, sfs) = yielding_sfs(1024)?;
+    let root = sfs.root_inode();
+    let free = sfs.info().bfree;
+
+    // creating the same name: one wins, no entry is doubled
+    for round in 0..ROUNDS {
+        let name = format!("f{}", round);
+        let (dir_a, dir_b) = (root.clone(), root.clone());
+        let (name_a, name_b) = (name.clone(), name.clone());
+        let (a, b) = run_together(
+            move || dir_a.create(&name_a, FileType::File, 0o644).map(|_| ()),
+            move || dir_b.create(&name_b, FileType::File, 0o644).map(|_| ()),
+        );
+        let mut results = [a, b];
+        results.sort_by_key(|result| result.is_err());
+        assert_eq!(results, [Ok(()), Err(FsError::EntryExist)]);
+    }
+    let names = root.list()?;
+    assert_eq!(names.len(), 2 + ROUNDS);
+    assert_eq!(names.iter().collect::<BTreeSet<_>>().len(), names.len());
+    for round in 0..ROUNDS {
+        root.unlink(&format!("f{}", round))?;
+    }
+    assert_eq!(root.list()?, [".", ".."]);
+
+    // unlinking and creating the same name: the name is there at most once
+    for _ in 0..ROUNDS {
+        root.create("x", FileType::File, 0o644)?;
+        let (dir_a, dir_b) = (root.clone(), root.clone());
+        let (unlinked, created) = run_together(
+            move || dir_a.unlink("x"),
+            move || dir_b.create("x", FileType::File, 0o644).map(|_| ()),
+        );
+        unlinked?;
+        match created {
+            Ok(()) => {
+                assert_eq!(root.list()?, [".", "..", "x"]);
+                root.unlink("x")?;
+            }
+            Err(err) => assert_eq!(err, FsError::EntryExist),
+        }
+        assert_eq!(root.list()?, [".", ".."]);
+    }
+
+    // moving across dirs both ways at once
+    let a = root.create("a", FileType::Dir, 0o755)?;
+    let b = root.create("b", FileType::Dir, 0o755)?;
+    for round in 0..ROUNDS {
+        a.create(&format!("a{}", round), FileType::File, 0o644)?;
+        b.create(&format!("b{}", round), FileType::File, 0o644)?;
+    }
+    let (from_a, to_b, from_b, to_a) = (a.clone(), b.clone(), b.clone(), a.clone());
+    let (moved_a, moved_b) = run_together(
+        move || -> Result<()> {
+            for round in 0..ROUNDS {
+                let name = format!("a{}", round);
+                from_a.move_(&name, &to_b, &name)?;
+            }
+            Ok(())
+        },
+        move || -> Result<()> {
+            for round in 0..ROUNDS {
+                let name = format!("b{}", round);
+                from_b.move_(&name, &to_a, &name)?;
+            }
+            Ok(())
+        },
+    );
+    moved_a?;
+    moved_b?;
+    assert_eq!(a.list()?.len(), 2 + ROUNDS);
+    assert!(a.list()?.iter().skip(2).all(|name| name.starts_with('b')));
+    assert!(b.list()?.iter().skip(2).all(|name| name.starts_with('a')));
+    for round in 0..ROUNDS {
+        a.unlink(&format!("b{}", round))?;
+        b.unlink(&format!("a{}", round))?;
+    }
+    drop((a, b));
+    root.unlink("a")?;
+    root.unlink("b")?;
+
+    // no inode or block leaked
+    assert_eq!(sfs.info().bfree, free);
+    Ok(())
+}
+
+#[test]
+fn concurrent_find_or_create() -> Result<()> {
+    const ROUNDS: usize = 20;
+    let (_, sfs) = yielding_sfs(1024)?;
+    let root = sfs.root_inode();
+    for round in 0..ROUNDS {
+        let name = format!("f{}", round);
+        let (dir_a, dir_b) = (root.clone(), root.clone());
+        let (name_a, name_b) = (name.clone(), name.clone());
+        let (a, b) = run_together(
+            move || dir_a.find_or_create(&name_a, FileType::File, 0o644),
+            move || dir_b.find_or_create(&name_b, FileType::File, 0o644),
+        );
+        let ((a, created_a), (b, created_b)) = (a?, b?);
+        assert!(created_a != created_b, "round {}", round);
+        assert_eq!(a.ino_key(), b.ino_key());
+        assert_eq!(root.find(&name)?.ino_key(), a.ino_key());
+    }
+    assert_eq!(root.list()?.len(), ROUNDS + 2);
+    Ok(())
+}
+
+#[test]
+fn find_or_create() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let (file, created) = root.find_or_create("file", FileType::File, 0o644)?;
+    assert!(created);
+    let (found, created) = root.find_or_create("file", FileType::File, 0o600)?;
+    assert!(!created);
+    assert_eq!(found.ino_key(), file.ino_key());
+    assert_eq!(found.metadata()?.mode, 0o644);
+
+    // never the file in place of a dir, nor the other way
+    let err = root.find_or_create("file", FileType::Dir, 0o755).err();
+    assert_eq!(err, Some(FsError::EntryExist));
+    let (dir, created) = root.find_or_create("dir", FileType::Dir, 0o755)?;
+    assert!(created);
+    assert_eq!(dir.metadata()?.nlinks, 2);
+    let err = root.find_or_create("dir", FileType::File, 0o644).err();
+    assert_eq!(err, Some(FsError::EntryExist));
+    let (dot, created) = dir.find_or_create(".", FileType::Dir, 0o755)?;
+    assert!(!created);
+    assert_eq!(dot.ino_key(), dir.ino_key());
+
+    // finding works where creating does not
+    let err = file.find_or_create("x", FileType::File, 0o644).err();
+    assert_eq!(err, Some(FsError::NotDir));
+    dir.create("in", FileType::File, 0o644)?;
+    dir.set_flags(InodeFlags::IMMUTABLE)?;
+    let (_, created) = dir.find_or_create("in", FileType::File, 0o644)?;
+    assert!(!created);
+    let err = dir.find_or_create("x", FileType::File, 0o644).err();
+    assert_eq!(err, Some(FsError::PermError));
+    Ok(())
+}
+
+#[test]
+fn fallocate_keep_size() -> Result<()> {
+    const BLOCKS: usize = 1108;
+    let (mem, sfs) = mem_sfs(BLOCKS)?;
+    let root = sfs.root_inode();
+    let file = root.create("download", FileType::File, 0o644)?;
+    let other = root.create("other", FileType::File, 0o644)?;
+    let free = sfs.info().bfree;
+    assert_eq!(free, 1100);
+
+    // reserved blocks are used, but not part of the content
+    file.fallocate(0, 1000 * BLKSIZE, FallocateMode::KEEP_SIZE)?;
+    let meta = file.metadata()?;
+    assert_eq!((meta.size, meta.blocks), (0, 1000));
+    // with the indirect block
+    assert_eq!(sfs.info().bfree, free - 1001);
+    assert_eq!(file.read_at(0, &mut [0; 16])?, 0);
+    assert_eq!(
+        other.fallocate(0, 200 * BLKSIZE, FallocateMode::KEEP_SIZE),
+        Err(FsError::NoDeviceSpace)
+    );
+    assert_eq!(other.metadata()?.blocks, 0);
+    assert_eq!(sfs.info().bfree, free - 1001);
+
+    // writes into the reservation take no block, and find zeros around
+    file.write_at(10 * BLKSIZE, &[1; 2 * BLKSIZE])?;
+    file.write_at(500 * BLKSIZE + 7, b"data")?;
+    assert_eq!(sfs.info().bfree, free - 1001);
+    let meta = file.metadata()?;
+    assert_eq!((meta.size, meta.blocks), (500 * BLKSIZE + 11, 1000));
+    let mut buf = vec![1; BLKSIZE];
+    file.read_at(300 * BLKSIZE, &mut buf)?;
+    assert!(buf.iter().all(|&b| b == 0));
+    assert_eq!(file.read_at(500 * BLKSIZE + 7, &mut buf)?, 4);
+    assert_eq!(&buf[..4], b"data");
+    // growing within the reservation keeps it
+    file.resize(600 * BLKSIZE)?;
+    assert_eq!(file.metadata()?.blocks, 1000);
+
+    // kept across remounts, until truncated
+    drop((root, file, other));
+    sfs.sync()?;
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(Arc::new(mem))?;
+    assert_eq!(sfs.info().bfree, free - 1001);
+    let root = sfs.root_inode();
+    let file = root.find("download")?;
+    assert_eq!(file.metadata()?.blocks, 1000);
+    file.resize(0)?;
+    assert_eq!(file.metadata()?.blocks, 0);
+    assert_eq!(sfs.info().bfree, free);
+    assert_eq!(sfs.free_map.read().count_ones(), free);
+
+    // a hole punched to the end frees the reservation past the size
+    file.write_at(0, b"head")?;
+    file.fallocate(0, 100 * BLKSIZE, FallocateMode::KEEP_SIZE)?;
+    assert_eq!(sfs.info().bfree, free - 101);
+    let punch = FallocateMode::KEEP_SIZE | FallocateMode::PUNCH_HOLE;
+    file.fallocate(2, 100 * BLKSIZE, punch)?;
+    let meta = file.metadata()?;
+    assert_eq!((meta.size, meta.blocks), (4, 1));
+    assert_eq!(sfs.info().bfree, free - 1);
+    assert_eq!(file.read_at(0, &mut buf)?, 4);
+    assert_eq!(&buf[..4], b"he\0\0");
+
+    // without KEEP_SIZE the file grows over the range
+    file.fallocate(BLKSIZE, BLKSIZE, FallocateMode::empty())?;
+    let meta = file.metadata()?;
+    assert_eq!((meta.size, meta.blocks), (2 * BLKSIZE, 2));
+
+    assert_eq!(
+        file.fallocate(0, 1, FallocateMode::PUNCH_HOLE),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(
+        file.fallocate(0, 0, FallocateMode::KEEP_SIZE),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(
+        root.fallocate(0, 1, FallocateMode::KEEP_SIZE),
+        Err(FsError::IsDir)
+    );
+    assert!(sfs.capabilities().contains(FsCapabilities::PREALLOC));
+    Ok(())
+}
+
+#[test]
+fn inline_small_files() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let free = sfs.info().bfree;
+    for i in 0..1000 {
+        let file = root.create(&format!("f{}", i), FileType::File, 0o644)?;
+        file.write_at(0, &[i as u8; 50])?;
+        assert_eq!(file.metadata()?.blocks, 0);
+    }
+    // the inodes and the entries of the dir, no data blocks
+    let used = free - sfs.info().bfree;
+    let dir_blocks = (1002 * DIRENT_SIZE).div_ceil(BLKSIZE);
+    assert_eq!(used, 1000 + dir_blocks);
+    let mut buf = [0; 64];
+    let file = root.find("f7")?;
+    assert_eq!(file.read_at(0, &mut buf)?, 50);
+    assert_eq!(&buf[..50], &[7; 50]);
+    assert_eq!(file.read_at(40, &mut buf)?, 10);
+    assert_eq!(file.read_at(60, &mut buf)?, 0);
+    Ok(())
+}
+
+#[test]
+fn inline_grows_into_blocks() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let file = sfs.root_inode().create("file", FileType::File, 0o644)?;
+    let data: Vec<u8> = (0..MAX_INLINE_SIZE as u8).collect();
+    file.write_at(0, &data)?;
+    assert_eq!(file.metadata()?.blocks, 0);
+    // shrinking inline zeroes what is cut
+    file.resize(10)?;
+    file.resize(MAX_INLINE_SIZE)?;
+    let mut buf = vec![0; 2 * BLKSIZE];
+    assert_eq!(file.read_at(0, &mut buf)?, MAX_INLINE_SIZE);
+    assert_eq!(&buf[..10], &data[..10]);
+    assert!(buf[10..MAX_INLINE_SIZE].iter().all(|&b| b == 0));
+    file.write_at(0, &data)?;
+
+    let free = sfs.info().bfree;
+    // one byte over the boundary
+    file.write_at(MAX_INLINE_SIZE - 2, b"xyz")?;
+    let meta = file.metadata()?;
+    assert_eq!((meta.size, meta.blocks), (MAX_INLINE_SIZE + 1, 1));
+    assert_eq!(sfs.info().bfree, free - 1);
+    assert_eq!(file.read_at(0, &mut buf)?, MAX_INLINE_SIZE + 1);
+    assert_eq!(&buf[..MAX_INLINE_SIZE - 2], &data[..MAX_INLINE_SIZE - 2]);
+    assert_eq!(&buf[MAX_INLINE_SIZE - 2..MAX_INLINE_SIZE + 1], b"xyz");
+
+    // small again, it stays in blocks
+    file.resize(4)?;
+    assert_eq!(file.metadata()?.blocks, 1);
+    assert_eq!(file.read_at(0, &mut buf)?, 4);
+    assert_eq!(&buf[..4], &data[..4]);
+
+    // the inline bit is not a flag of the file
+    let other = sfs.root_inode().create("other", FileType::File, 0o644)?;
+    other.write_at(0, b"abc")?;
+    assert_eq!(other.get_flags()?, InodeFlags::empty());
+    other.set_flags(InodeFlags::APPEND_ONLY)?;
+    assert_eq!(other.get_flags()?, InodeFlags::APPEND_ONLY);
+    assert_eq!(other.read_at(0, &mut buf)?, 3);
+    assert_eq!(&buf[..3], b"abc");
+    // reserving blocks moves the content there
+    other.fallocate(0, BLKSIZE, FallocateMode::KEEP_SIZE)?;
+    let meta = other.metadata()?;
+    assert_eq!((meta.size, meta.blocks), (3, 1));
+    assert_eq!(other.read_at(0, &mut buf)?, 3);
+    assert_eq!(&buf[..3], b"abc");
+    Ok(())
+}
+
+#[test]
+fn inline_round_trip() -> Result<()> {
+    let (mem, sfs) = mem_sfs(256)?;
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, b"small content")?;
+    let link = root.create("link", FileType::SymLink, 0o777)?;
+    link.write_at(0, b"../some/where")?;
+    drop((file, link, root));
+    sfs.sync()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open(Arc::new(mem))?;
+    let root = sfs.root_inode();
+    let mut buf = [0; 64];
+    let file = root.find("file")?;
+    assert_eq!(file.metadata()?.blocks, 0);
+    assert_eq!(file.read_at(0, &mut buf)?, 13);
+    assert_eq!(&buf[..13], b"small content");
+    let link = root.find("link")?;
+    assert_eq!(link.metadata()?.blocks, 0);
+    assert_eq!(link.read_at(0, &mut buf)?, 13);
+    assert_eq!(&buf[..13], b"../some/where");
+    Ok(())
+}
+
+#[test]
+fn byte_swapped_image() -> Result<()> {
+    use core::mem::offset_of;
+
+    let (mem, sfs) = mem_sfs(64)?;
+    sfs.root_inode().create("file", FileType::File, 0o644)?;
+    sfs.sync()?;
+    drop(sfs);
+    // as written by a tool not converting the superblock integers
+    let swap = |mem: &MemDevice| {
+        let mut image = mem.0.lock().unwrap();
+        for &block in [0, 32, 63].iter() {
+            let offset = block * BLKSIZE;
+            let fields = [
+                offset_of!(SuperBlock, magic),
+                offset_of!(SuperBlock, blocks),
+                offset_of!(SuperBlock, unused_blocks),
+                offset_of!(SuperBlock, freemap_blocks),
+                offset_of!(SuperBlock, version),
+            ];
+            for field in fields.iter() {
+                image[offset + field..offset + field + 4].reverse();
+            }
+        }
+    };
+    swap(&mem);
+    let device: Arc<dyn Device> = Arc::new(mem.clone());
+    assert!(device
+        .load_struct::<SuperBlock>(BLKN_SUPER)?
+        .is_byte_swapped());
+    assert_eq!(
+        SimpleFileSystem::open(device.clone()).err(),
+        Some(FsError::WrongFs)
+    );
+    assert_eq!(
+        SimpleFileSystem::restore_superblock(device).err(),
+        Some(FsError::WrongFs)
+    );
+
+    swap(&mem);
+    let sfs = SimpleFileSystem::open(Arc::new(mem))?;
+    sfs.root_inode().find("file")?;
+    Ok(())
+}
+
+/// Check the bytes at `offset` of `disk` are `le`
+fn assert_bytes_at(disk: &[u8], offset: usize, le: &[u8]) {
+    assert_eq!(&disk[offset..offset + le.len()], le, "at offset {}", offset);
+}
+
+#[test]
+fn on_disk_byte_order() {
+    use core::mem::offset_of;
+
+    let mut sb = SuperBlock::zeroed();
+    sb.magic = MAGIC;
+    sb.blocks = 0x0102_0304;
+    sb.unused_blocks = 0x0506_0708;
+    sb.freemap_blocks = 0x090a_0b0c;
+    sb.version = 0x0d0e_0f10;
+    sb.backup_blocks = [0x1112_1314, 0x1516_1718];
+    let disk = sb.to_disk().into_owned();
+    assert_bytes_at(
+        &disk,
+        offset_of!(SuperBlock, magic),
+        &[0x2b, 0xbe, 0x8d, 0x2f],
+    );
+    assert_bytes_at(&disk, offset_of!(SuperBlock, blocks), &[4, 3, 2, 1]);
+    assert_bytes_at(&disk, offset_of!(SuperBlock, unused_blocks), &[8, 7, 6, 5]);
+    assert_bytes_at(
+        &disk,
+        offset_of!(SuperBlock, freemap_blocks),
+        &[0xc, 0xb, 0xa, 9],
+    );
+    assert_bytes_at(
+        &disk,
+        offset_of!(SuperBlock, version),
+        &[0x10, 0xf, 0xe, 0xd],
+    );
+    assert_bytes_at(
+        &disk,
+        offset_of!(SuperBlock, backup_blocks),
+        &[0x14, 0x13, 0x12, 0x11, 0x18, 0x17, 0x16, 0x15],
+    );
+    let mut back = SuperBlock::zeroed();
+    back.as_buf_mut().copy_from_slice(&disk);
+    back.convert_le();
+    assert!(back.magic == MAGIC && back.blocks == sb.blocks);
+    assert!(back.unused_blocks == sb.unused_blocks && back.version == sb.version);
+    assert!(back.freemap_blocks == sb.freemap_blocks && back.backup_blocks == sb.backup_blocks);
+
+    let mut inode = DiskINode::new_symlink();
+    inode.size = 0x0102_0304;
+    inode.nlinks = 0x0506;
+    inode.blocks = 0x0708_090a;
+    inode.direct[0] = 0x1112_1314;
+    inode.direct[NDIRECT - 1] = 0x1516_1718;
+    inode.indirect = 0x191a_1b1c;
+    inode.db_indirect = 0x1d1e_1f20;
+    inode.rdev = 0x2122_2324;
+    inode.atime = Timespec {
+        sec: 0x3132_3334_3536_3738,
+        nsec: 0x393a_3b3c,
+    };
+    inode.mtime = Timespec {
+        sec: 0x4142_4344_4546_4748,
+        nsec: 0x494a_4b4c,
+    };
+    inode.ctime = Timespec {
+        sec: 0x5152_5354_5556_5758,
+        nsec: 0x595a_5b5c,
+    };
+    inode.btime = Timespec {
+        sec: 0x0102_0304_0506_0708,
+        nsec: 0x090a_0b0c,
+    };
+    inode.flags = 0x6162_6364;
+    inode.index = 0x6566_6768;
+    inode.mode = 0x696a;
+    inode.uid = 0x7172_7374;
+    inode.gid = 0x7576_7778;
+    let disk = inode.to_disk().into_owned();
+    let at = |offset, le: &[u8]| assert_bytes_at(&disk, offset, le);
+    at(offset_of!(DiskINode, size), &[4, 3, 2, 1]);
+    at(offset_of!(DiskINode, type_), &[3, 0]);
+    at(offset_of!(DiskINode, nlinks), &[6, 5]);
+    at(offset_of!(DiskINode, blocks), &[0xa, 9, 8, 7]);
+    at(offset_of!(DiskINode, direct), &[0x14, 0x13, 0x12, 0x11]);
+    at(
+        offset_of!(DiskINode, direct) + ENTRY_SIZE * (NDIRECT - 1),
+        &[0x18, 0x17, 0x16, 0x15],
+    );
+    at(offset_of!(DiskINode, indirect), &[0x1c, 0x1b, 0x1a, 0x19]);
+    at(
+        offset_of!(DiskINode, db_indirect),
+        &[0x20, 0x1f, 0x1e, 0x1d],
+    );
+    // low bytes first, whatever the width
+    at(offset_of!(DiskINode, rdev), &[0x24, 0x23, 0x22, 0x21]);
+    for (offset, base) in [
+        (offset_of!(DiskINode, atime), 0x30),
+        (offset_of!(DiskINode, mtime), 0x40),
+        (offset_of!(DiskINode, ctime), 0x50),
+        (offset_of!(DiskINode, btime), 0),
+    ]
+    .iter()
+    {
+        let sec: Vec<u8> = (1..=8).rev().map(|i| base + i).collect();
+        at(offset + offset_of!(Timespec, sec), &sec);
+        let nsec: Vec<u8> = (9..=0xc).rev().map(|i| base + i).collect();
+        at(offset + offset_of!(Timespec, nsec), &nsec);
+    }
+    at(offset_of!(DiskINode, flags), &[0x64, 0x63, 0x62, 0x61]);
+    at(offset_of!(DiskINode, index), &[0x68, 0x67, 0x66, 0x65]);
+    at(offset_of!(DiskINode, mode), &[0x6a, 0x69]);
+    at(offset_of!(DiskINode, uid), &[0x74, 0x73, 0x72, 0x71]);
+    at(offset_of!(DiskINode, gid), &[0x78, 0x77, 0x76, 0x75]);
+    let mut back = DiskINode::new_file();
+    back.as_buf_mut().copy_from_slice(&disk);
+    back.convert_le();
+    assert_eq!(back.to_disk().into_owned(), disk);
+    assert_eq!(back.type_, structs::FileType::SymLink);
+    assert_eq!(
+        (back.size, back.nlinks, back.blocks),
+        (0x0102_0304, 0x0506, 0x0708_090a)
+    );
+    assert_eq!(
+        (back.direct, back.indirect, back.db_indirect),
+        (inode.direct, inode.indirect, inode.db_indirect)
+    );
+    assert_eq!(
+        (back.rdev, back.atime, back.mtime, back.ctime, back.btime),
+        (
+            inode.rdev,
+            inode.atime,
+            inode.mtime,
+            inode.ctime,
+            inode.btime
+        )
+    );
+    assert_eq!(
+        (back.flags, back.index, back.mode, back.uid, back.gid),
+        (inode.flags, inode.index, inode.mode, inode.uid, inode.gid)
+    );
+
+    let entry = DiskEntry {
+        id: 0x0102_0304,
+        name: Str256::from("name"),
+    };
+    let disk = entry.to_disk().into_owned();
+    assert_bytes_at(&disk, offset_of!(DiskEntry, id), &[4, 3, 2, 1]);
+    assert_bytes_at(&disk, offset_of!(DiskEntry, name), b"name\0");
+
+    let mut table = IndirectBlock::zeroed();
+    table.entries[0] = 0x0102_0304;
+    table.entries[BLK_NENTRY - 1] = 0x0506_0708;
+    let disk = table.to_disk().into_owned();
+    assert_bytes_at(&disk, 0, &[4, 3, 2, 1]);
+    assert_bytes_at(&disk, BLKSIZE - ENTRY_SIZE, &[8, 7, 6, 5]);
+
+    let mut root = DirIndexRoot::zeroed();
+    root.nbuckets = 0x0102_0304;
+    root.buckets[1] = 0x0506_0708;
+    let disk = root.to_disk().into_owned();
+    assert_bytes_at(&disk, offset_of!(DirIndexRoot, nbuckets), &[4, 3, 2, 1]);
+    assert_bytes_at(
+        &disk,
+        offset_of!(DirIndexRoot, buckets) + ENTRY_SIZE,
+        &[8, 7, 6, 5],
+    );
+
+    let mut bucket = DirIndexBucket::zeroed();
+    bucket.count = 0x0102_0304;
+    bucket.overflow = 0x0506_0708;
+    bucket.slots[0] = [0x090a_0b0c, 0x0d0e_0f10];
+    let disk = bucket.to_disk().into_owned();
+    assert_bytes_at(&disk, offset_of!(DirIndexBucket, count), &[4, 3, 2, 1]);
+    assert_bytes_at(&disk, offset_of!(DirIndexBucket, overflow), &[8, 7, 6, 5]);
+    assert_bytes_at(
+        &disk,
+        offset_of!(DirIndexBucket, slots),
+        &[0xc, 0xb, 0xa, 9, 0x10, 0xf, 0xe, 0xd],
+    );
+    let mut back = DirIndexBucket::zeroed();
+    back.as_buf_mut().copy_from_slice(&disk);
+    back.convert_le();
+    assert_eq!(
+        (back.count, back.overflow, back.slots[0]),
+        (bucket.count, bucket.overflow, bucket.slots[0])
+    );
+}
+
+/// Reproducible random numbers for fuzzing, xorshift64*
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % n
+    }
+}
+
+/// Device read requests allowed to open and walk a fuzzed image
+const FUZZ_READ_BUDGET: usize = 100_000;
+/// Largest allocation allowed to open and walk a fuzzed image
+const FUZZ_ALLOC_BUDGET: usize = 1 << 20;
+
+/// Visit every inode under `dir` once, listing the dirs and reading the
+/// content of the others
+fn fuzz_walk(dir: &Arc<dyn INode>, visited: &mut BTreeSet<usize>) -> Result<()> {
+    for name in dir.list()?.into_iter().skip(2) {
+        let inode = match dir.find(&name) {
+            // a corrupt name, cut at its first invalid byte by `list()`
+            Err(FsError::EntryNotFound) => continue,
+            result => result?,
+        };
+        let metadata = inode.metadata()?;
+        if !visited.insert(metadata.inode) {
+            continue;
+        }
+        match metadata.type_ {
+            FileType::Dir => fuzz_walk(&inode, visited)?,
+            FileType::File | FileType::SymLink => {
+                let mut buf = [0; BLKSIZE];
+                for offset in (0..metadata.size).step_by(BLKSIZE).take(64) {
+                    inode.read_at(offset, &mut buf)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fuzz_corrupt_images() -> Result<()> {
+    let mem = MemDevice::new(256);
+    let sfs = dump_sample(&mem)?;
+    sfs.sync()?;
+    drop(sfs);
+    let image = mem.image();
+    let used: Vec<_> = image
+        .chunks_exact(BLKSIZE)
+        .enumerate()
+        .filter(|(_, block)| block.iter().any(|&byte| byte != 0))
+        .map(|(id, _)| id)
+        .collect();
+    let mut rng = Rng(0x5eed);
+    for iteration in 0..500 {
+        let mut fuzzed = image.clone();
+        for _ in 0..1 + rng.below(4) {
+            // sizes and block ids are mostly at the head of a block
+            let offset = match rng.below(2) {
+                0 => rng.below(128),
+                _ => rng.below(BLKSIZE),
+            };
+            fuzzed[used[rng.below(used.len())] * BLKSIZE + offset] = rng.below(256) as u8;
+        }
+        let device = MockDevice::over(MemDevice::with_image(fuzzed));
+        device.read_budget.store(FUZZ_READ_BUDGET, Ordering::SeqCst);
+        LARGEST_ALLOCATION.with(|n| n.set(0));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| -> Result<()> {
+            let sfs = SimpleFileSystem::open(Arc::new(device))?;
+            match fuzz_walk(&sfs.root_inode(), &mut BTreeSet::new()) {
+                // blocks past the end of the device can not be read
+                Err(FsError::DeviceError) if sfs.is_read_only() => Ok(()),
+                result => result,
+            }
+        }));
+        let largest = LARGEST_ALLOCATION.with(|n| n.get());
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => assert!(
+                matches!(err.root_cause(), FsError::Corrupted | FsError::WrongFs),
+                "iteration {}: {:?}",
+                iteration,
+                err
+            ),
+            Err(_) => panic!("iteration {} panicked", iteration),
+        }
+        assert!(
+            largest <= FUZZ_ALLOC_BUDGET,
+            "iteration {}: allocated {} bytes at once",
+            iteration,
+            largest
+        );
+    }
+    Ok(())
+}
+
+/// Problems of an image found by `fsck()`
+#[derive(Debug, Default)]
+struct Fsck {
+    /// blocks marked used, but used by no inode reachable from the root
+    leaked: usize,
+    /// blocks used by a reachable inode, but marked free
+    unmarked: usize,
+    /// what marking blocks used or free does not repair: inodes that do not
+    /// load, blocks used twice, wrong link counts
+    errors: Vec<String>,
+}
+
+/// Check the inodes reachable from the root of `image` against each other
+/// and the freemap
+fn fsck(image: Vec<u8>) -> Fsck {
+    let mut report = Fsck::default();
+    let device = Arc::new(MemDevice::with_image(image));
+    let sfs = match SimpleFileSystem::open(device) {
+        Ok(sfs) => sfs,
+        Err(err) => {
+            report.errors.push(format!("open: {:?}", err));
+            return report;
+        }
+    };
+    // entries naming each inode, "." and ".." included, and its nlinks
+    let mut links = BTreeMap::<INodeId, (usize, usize)>::new();
+    // inodes on disk before the freemap marks them used are loaded as
+    // after a repair, and compared against the freemap on disk at the end
+    let free_map = sfs.free_map.read().clone();
+    for block in sfs.data_blocks.clone() {
+        sfs.free_map.write().set(block, false);
+    }
+    // backups of the superblock are owned by no inode
+    let backups = sfs.super_block.read().backup_blocks;
+    let mut owners: BTreeMap<BlockId, INodeId> = backups
+        .iter()
+        .filter(|&&id| id != 0)
+        .map(|&id| (id as BlockId, 0))
+        .collect();
+    let mut queue = vec![sfs.root_id];
+    let mut seen = BTreeSet::from([sfs.root_id]);
+    while let Some(id) = queue.pop() {
+        let inode = match sfs.get_inode(id) {
+            Ok(inode) => inode,
+            Err(err) => {
+                report.errors.push(format!("inode {}: {:?}", id, err));
+                continue;
+            }
+        };
+        let (type_, size, nlinks) = {
+            let disk_inode = inode.disk_inode.read();
+            (disk_inode.type_, disk_inode.size, disk_inode.nlinks)
+        };
+        links.entry(id).or_default().1 = nlinks as usize;
+        let blocks = match inode.blocks_to_free(0) {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                report.errors.push(format!("blocks of {}: {:?}", id, err));
+                continue;
+            }
+        };
+        for block in blocks.into_iter().chain(Some(id)) {
+            if let Some(other) = owners.insert(block, id) {
+                let error = format!("block {} used by {} and {}", block, other, id);
+                report.errors.push(error);
+            }
+        }
+        if inode.disk_inode.read().is_compressed() && !inode.verify_chunks().unwrap_or(false) {
+            report.errors.push(format!("chunk table of {}", id));
+        }
+        if type_ != structs::FileType::Dir {
+            continue;
+        }
+        for i in 0..size as usize / DIRENT_SIZE {
+            let entry = match inode.read_direntry(i) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    report
+                        .errors
+                        .push(format!("entry {} of {}: {:?}", i, id, err));
+                    continue;
+                }
+            };
+            let child = entry.id as INodeId;
+            links.entry(child).or_default().0 += 1;
+            if seen.insert(child) {
+                queue.push(child);
+            }
+        }
+    }
+    for (id, (entries, nlinks)) in links {
+        if entries != nlinks {
+            let error = format!("inode {} has {} links, {} entries", id, nlinks, entries);
+            report.errors.push(error);
+        }
+    }
+    for block in sfs.data_blocks.clone() {
+        match (owners.contains_key(&block), free_map[block]) {
+            (true, true) => report.unmarked += 1,
+            (false, false) => report.leaked += 1,
+            _ => {}
+        }
+    }
+    report
+}
+
+/// Type, links and content of each path
+type Tree = BTreeMap<String, (FileType, usize, Vec<u8>)>;
+
+/// The files under `dir`
+fn tree(dir: &Arc<dyn INode>) -> Result<Tree> {
+    let mut tree = BTreeMap::new();
+    let mut queue = vec![(String::new(), dir.clone())];
+    while let Some((path, dir)) = queue.pop() {
+        for name in dir.list()?.into_iter().skip(2) {
+            let inode = dir.find(&name)?;
+            let metadata = inode.metadata()?;
+            let path = format!("{}/{}", path, name);
+            let mut content = vec![];
+            match metadata.type_ {
+                FileType::Dir => queue.push((path.clone(), inode)),
+                _ => {
+                    content.resize(metadata.size, 0);
+                    inode.read_at(0, &mut content)?;
+                }
+            }
+            tree.insert(path, (metadata.type_, metadata.nlinks, content));
+        }
+    }
+    Ok(tree)
+}
+
+/// How an operation stopped at a failpoint leaves the fs
+#[derive(Debug, Clone, Copy)]
+enum Expect {
+    /// It fails with `DeviceError` and changes nothing, and a retry works
+    RolledBack,
+    /// The image at the crash opens, and a fsck only has to mark blocks
+    /// used or free
+    Repairable,
+    /// Like `Repairable`, with the tree as before the operation and
+    /// leaked blocks only
+    LeaksOnly,
+}
+
+/// An operation going through failpoint `point`, on an fs made by `setup`
+struct FailCase {
+    point: &'static str,
+    expect: Expect,
+    setup: fn(&Arc<dyn INode>) -> Result<()>,
+    op: fn(&Arc<SimpleFileSystem>) -> Result<()>,
+}
+
+fn file_of_blocks(root: &Arc<dyn INode>, name: &str, blocks: usize) -> Result<()> {
+    let file = root.create(name, FileType::File, 0o644)?;
+    file.write_at(0, &vec![0x5a; blocks * BLKSIZE])?;
+    Ok(())
+}
+
+fn resize_f(sfs: &Arc<SimpleFileSystem>, blocks: usize) -> Result<()> {
+    sfs.root_inode().find("f")?.resize(blocks * BLKSIZE)
+}
+
+/// Grow a new file and shrink "f", then sync
+fn churn(sfs: &Arc<SimpleFileSystem>) -> Result<()> {
+    let root = sfs.root_inode();
+    file_of_blocks(&root, "new", 3)?;
+    root.find("f")?.resize(BLKSIZE)?;
+    sfs.sync()
+}
+
+fn fail_cases() -> Vec<FailCase> {
+    use Expect::*;
+    let none = |_: &Arc<dyn INode>| Ok(());
+    let f = |root: &Arc<dyn INode>| file_of_blocks(root, "f", 10);
+    vec![
+        FailCase {
+            point: "create_after_alloc_inode",
+            expect: RolledBack,
+            setup: none,
+            op: |sfs| {
+                sfs.root_inode()
+                    .create("new", FileType::File, 0o644)
+                    .map(drop)
+            },
+        },
+        FailCase {
+            point: "create_after_dirent",
+            expect: RolledBack,
+            setup: |root| file_of_blocks(root, "a", 0),
+            op: |sfs| {
+                sfs.root_inode()
+                    .create("new", FileType::Dir, 0o755)
+                    .map(drop)
+            },
+        },
+        FailCase {
+            point: "symlink_after_target",
+            expect: RolledBack,
+            setup: |root| file_of_blocks(root, "a", 0),
+            op: |sfs| {
+                // past the inline size, so that it takes a block
+                let target = "a/".repeat(BLKSIZE);
+                sfs.root_inode().symlink("link", &target).map(drop)
+            },
+        },
+        FailCase {
+            point: "create_batch_after_resize",
+            expect: RolledBack,
+            setup: none,
+            op: |sfs| {
+                let spec = |name, type_| CreateSpec {
+                    name,
+                    type_,
+                    mode: 0o644,
+                    data: 0,
+                };
+                let specs = [spec("a", FileType::File), spec("b", FileType::Dir)];
+                sfs.root_inode().create_batch(&specs).map(drop)
+            },
+        },
+        FailCase {
+            point: "insert_dirent_after_grow",
+            expect: RolledBack,
+            setup: |root| file_of_blocks(root, "a", 0),
+            op: |sfs| {
+                sfs.root_inode()
+                    .create("new", FileType::File, 0o644)
+                    .map(drop)
+            },
+        },
+        FailCase {
+            point: "init_dirent_after_grow",
+            expect: RolledBack,
+            setup: none,
+            op: |sfs| {
+                sfs.root_inode()
+                    .create("new", FileType::Dir, 0o755)
+                    .map(drop)
+            },
+        },
+        FailCase {
+            point: "remove_dirent_before_swap",
+            expect: RolledBack,
+            setup: |root| {
+                for name in ["a", "b", "c"] {
+                    file_of_blocks(root, name, 1)?;
+                }
+                Ok(())
+            },
+            op: |sfs| sfs.root_inode().unlink("a"),
+        },
+        FailCase {
+            point: "link_after_dirent",
+            expect: RolledBack,
+            setup: |root| file_of_blocks(root, "a", 1),
+            op: |sfs| {
+                let root = sfs.root_inode();
+                root.link("hard", &root.find("a")?)
+            },
+        },
+        FailCase {
+            point: "unlink_after_nlinks_dec",
+            expect: RolledBack,
+            setup: |root| {
+                root.create("sub", FileType::Dir, 0o755)?;
+                file_of_blocks(root, "a", 1)
+            },
+            op: |sfs| sfs.root_inode().unlink("sub"),
+        },
+        FailCase {
+            point: "move_after_append",
+            expect: RolledBack,
+            setup: |root| {
+                let a = root.create("a", FileType::Dir, 0o755)?;
+                file_of_blocks(&a, "f", 1)?;
+                file_of_blocks(&a, "g", 0)?;
+                root.create("b", FileType::Dir, 0o755).map(drop)
+            },
+            op: |sfs| {
+                let root = sfs.root_inode();
+                root.find("a")?.move_("f", &root.find("b")?, "moved")
+            },
+        },
+        FailCase {
+            point: "move_after_replace",
+            expect: RolledBack,
+            setup: |root| {
+                file_of_blocks(root, "f", 1)?;
+                file_of_blocks(root, "g", 2)?;
+                file_of_blocks(root, "h", 0)
+            },
+            op: |sfs| {
+                let root = sfs.root_inode();
+                root.move_("f", &root, "g")
+            },
+        },
+        FailCase {
+            point: "resize_grow_after_indirect",
+            expect: RolledBack,
+            setup: |root| file_of_blocks(root, "f", 3),
+            op: |sfs| resize_f(sfs, MAX_NBLOCK_DIRECT + 8),
+        },
+        FailCase {
+            point: "resize_grow_after_alloc",
+            expect: RolledBack,
+            setup: |root| file_of_blocks(root, "f", 1),
+            op: |sfs| resize_f(sfs, 5),
+        },
+        FailCase {
+            point: "resize_shrink_before_free",
+            expect: RolledBack,
+            setup: |root| file_of_blocks(root, "f", MAX_NBLOCK_DIRECT + 8),
+            op: |sfs| resize_f(sfs, 1),
+        },
+        FailCase {
+            point: "uninline_after_grow",
+            expect: RolledBack,
+            setup: |root| {
+                let file = root.create("f", FileType::File, 0o644)?;
+                file.write_at(0, b"inline").map(drop)
+            },
+            op: |sfs| resize_f(sfs, 2),
+        },
+        FailCase {
+            point: "write_after_grow",
+            expect: RolledBack,
+            setup: |root| file_of_blocks(root, "f", 1),
+            op: |sfs| {
+                let file = sfs.root_inode().find("f")?;
+                file.write_at(100, &[7; 3 * BLKSIZE]).map(drop)
+            },
+        },
+        FailCase {
+            point: "txn_commit_before_switch",
+            expect: LeaksOnly,
+            setup: |root| root.create("d", FileType::Dir, 0o755).map(drop),
+            op: |sfs| {
+                let root = sfs.root_inode();
+                let mut txn = sfs.transaction();
+                txn.create2(&root, "t", FileType::File, 0o644, 0)?;
+                txn.write_at(&root, "t", 0, &[1; 2 * BLKSIZE])?;
+                txn.create2(&root.find("d")?, "u", FileType::File, 0o644, 0)?;
+                txn.commit()
+            },
+        },
+        FailCase {
+            point: "sync_after_super_block",
+            expect: Repairable,
+            setup: f,
+            op: churn,
+        },
+        FailCase {
+            point: "sync_after_freemap",
+            expect: Repairable,
+            setup: f,
+            op: churn,
+        },
+        FailCase {
+            point: "sync_partial_after_inodes",
+            expect: Repairable,
+            setup: f,
+            op: |sfs| {
+                let root = sfs.root_inode();
+                file_of_blocks(&root, "new", 3)?;
+                root.find("f")?.resize(BLKSIZE)?;
+                sfs.sync_partial(usize::MAX).map(drop)
+            },
+        },
+        FailCase {
+            point: "drop_after_resize0",
+            expect: Repairable,
+            setup: f,
+            op: |sfs| {
+                let root = sfs.root_inode();
+                let file = root.find("f")?;
+                root.unlink("f")?;
+                sfs.sync()?;
+                drop(file);
+                Ok(())
+            },
+        },
+        FailCase {
+            point: "find_after_entry",
+            expect: RolledBack,
+            setup: f,
+            op: |sfs| sfs.root_inode().find("f").map(drop),
+        },
+    ]
+}
+
+#[test]
+fn failpoints() -> Result<()> {
+    const BLOCKS: usize = 256;
+    let cases = fail_cases();
+    for &point in failpoint::POINTS {
+        let case = cases.iter().find(|case| case.point == point);
+        let case = case.unwrap_or_else(|| panic!("no case for failpoint {}", point));
+        let (device, sfs) = mock_sfs(BLOCKS)?;
+        let root = sfs.root_inode();
+        (case.setup)(&root)?;
+        sfs.sync()?;
+        let image = || device.mem.image();
+        let report = fsck(image());
+        assert!(report.errors.is_empty(), "{}: {:?}", point, report);
+        assert_eq!(
+            report.leaked + report.unmarked,
+            0,
+            "{}: {:?}",
+            point,
+            report
+        );
+        let before = tree(&root)?;
+        let free = sfs.info().bfree;
+
+        let action = match case.expect {
+            Expect::RolledBack => failpoint::FailAction::Error,
+            _ => failpoint::FailAction::Panic,
+        };
+        failpoint::arm(&sfs, point, 1, action);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| (case.op)(&sfs)));
+        assert!(failpoint::hits(&sfs, point) >= 1, "{} not hit", point);
+        failpoint::disarm_all(&sfs);
+        match case.expect {
+            Expect::RolledBack => {
+                let result = result.unwrap_or_else(|_| panic!("{} panicked", point));
+                assert_eq!(result, Err(FsError::DeviceError), "{}", point);
+                assert_eq!(tree(&root)?, before, "{}", point);
+                assert_eq!(sfs.info().bfree, free, "{}", point);
+                sfs.sync()?;
+                let report = fsck(image());
+                assert!(report.errors.is_empty(), "{}: {:?}", point, report);
+                assert_eq!(
+                    report.leaked + report.unmarked,
+                    0,
+                    "{}: {:?}",
+                    point,
+                    report
+                );
+                // and a retry works
+                (case.op)(&sfs)?;
+                sfs.sync()?;
+                let report = fsck(image());
+                assert!(report.errors.is_empty(), "{}: {:?}", point, report);
+            }
+            Expect::Repairable | Expect::LeaksOnly => {
+                assert!(result.is_err(), "{} did not crash", point);
+                let crashed = image();
+                let report = fsck(crashed.clone());
+                assert!(report.errors.is_empty(), "{}: {:?}", point, report);
+                if let Expect::LeaksOnly = case.expect {
+                    assert_eq!(report.unmarked, 0, "{}: {:?}", point, report);
+                    let device = MemDevice::with_image(crashed);
+                    let reopened = SimpleFileSystem::open(Arc::new(device))?;
+                    assert_eq!(tree(&reopened.root_inode())?, before, "{}", point);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn failed_dirent_write_leaves_dir_as_before() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    for name in ["a", "b", "c"] {
+        dir.create(name, FileType::File, 0o644)?;
+    }
+    let size = dir.metadata()?.size;
+    let names = dir.list()?;
+    let free = sfs.info().bfree;
+
+    failpoint::arm(
+        &sfs,
+        "insert_dirent_after_grow",
+        1,
+        failpoint::FailAction::Error,
+    );
+    let created = dir.create("new", FileType::File, 0o644).map(drop);
+    failpoint::disarm_all(&sfs);
+    assert_eq!(created, Err(FsError::DeviceError));
+    assert_eq!(dir.metadata()?.size, size);
+    assert_eq!(dir.list()?, names);
+    assert_eq!(dir.find("new").err(), Some(FsError::EntryNotFound));
+    // the inode of "new" is freed
+    assert_eq!(sfs.info().bfree, free);
+    // and the next entry takes the slot
+    dir.create("d", FileType::File, 0o644)?;
+    assert_eq!(dir.metadata()?.size, size + DIRENT_SIZE);
+    assert_eq!(dir.get_entry(names.len())?, "d");
+
+    let names = dir.list()?;
+    failpoint::arm(
+        &sfs,
+        "remove_dirent_before_swap",
+        1,
+        failpoint::FailAction::Error,
+    );
+    assert_eq!(dir.unlink("a"), Err(FsError::DeviceError));
+    failpoint::disarm_all(&sfs);
+    assert_eq!(dir.list()?, names);
+    assert_eq!(dir.find("a")?.metadata()?.nlinks, 1);
+    dir.unlink("a")?;
+    let mut left = dir.list()?;
+    left.sort();
+    assert_eq!(left, [".", "..", "b", "c", "d"]);
+    Ok(())
+}
+
+#[test]
+fn change_cookie_seen_through_other_inodes() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    assert!(dir.find("x").is_err());
+    let cookie = dir.change_cookie();
+
+    // changed through the inode from the fs, not the one found
+    let other = sfs.get_inode(dir.metadata()?.inode)?;
+    other.create("x", FileType::File, 0o644)?;
+    assert!(dir.change_cookie() > cookie);
+    dir.find("x")?;
+    Ok(())
+}
+
+#[test]
+fn change_cookie_grows_with_each_change() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dirs = [
+        root.create("a", FileType::Dir, 0o777)?,
+        root.create("b", FileType::Dir, 0o777)?,
+    ];
+    // xorshift, so that the changes are mixed
+    let mut state = 0x2545f491u32;
+    let mut rand = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+    let mut names: Vec<(usize, String)> = Vec::new();
+    for i in 0..300 {
+        let cookies = [dirs[0].change_cookie(), dirs[1].change_cookie()];
+        let d = rand() as usize % 2;
+        let dir = &dirs[d];
+        // dirs changed by the step
+        let mut changed = vec![d];
+        match rand() % 6 {
+            0 | 1 => {
+                let name = format!("f{}", i);
+                dir.create(&name, FileType::File, 0o644)?;
+                names.push((d, name));
+            }
+            2 if !names.is_empty() => {
+                let (d, name) = names.swap_remove(rand() as usize % names.len());
+                dirs[d].unlink(&name)?;
+                changed = vec![d];
+            }
+            3 if !names.is_empty() => {
+                let index = rand() as usize % names.len();
+                let (from, name) = names[index].clone();
+                let renamed = format!("r{}", i);
+                dirs[from].move_(&name, dir, &renamed)?;
+                names[index] = (d, renamed);
+                changed = vec![from, d];
+            }
+            4 if !names.is_empty() => {
+                let (from, name) = names[rand() as usize % names.len()].clone();
+                let name_link = format!("l{}", i);
+                dir.link(&name_link, &dirs[from].find(&name)?)?;
+                names.push((d, name_link));
+            }
+            _ => {
+                let mut txn = sfs.transaction();
+                let name = format!("t{}", i);
+                txn.create2(dir, &name, FileType::File, 0o644, 0)?;
+                txn.commit()?;
+                names.push((d, name));
+            }
+        }
+        for d in 0..2 {
+            match changed.contains(&d) {
+                true => assert!(dirs[d].change_cookie() > cookies[d], "step {}", i),
+                false => assert_eq!(dirs[d].change_cookie(), cookies[d], "step {}", i),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Blocks of the content of `file`, in order
+fn file_blocks(file: &Arc<dyn INode>) -> Result<Vec<BlockId>> {
+    let inode = file.downcast_ref::<INodeImpl>().unwrap();
+    let blocks = inode.disk_inode.read().blocks as usize;
+    (0..blocks).map(|i| inode.get_disk_block_id(i)).collect()
+}
+
+#[test]
+fn alloc_groups_keep_files_together() -> Result<()> {
+    const BLOCKS: usize = 4 * ALLOC_GROUP_BLOCKS;
+    let (_, sfs) = mem_sfs(BLOCKS)?;
+    let root = sfs.root_inode();
+    let dirs = [
+        root.create("a", FileType::Dir, 0o777)?,
+        root.create("b", FileType::Dir, 0o777)?,
+    ];
+    let mut together = 0;
+    for i in 0..50 {
+        let dir = &dirs[i % 2];
+        let file = dir.create(&format!("f{}", i), FileType::File, 0o644)?;
+        // written in pieces, 4500 blocks in all to fill more than 2 groups
+        for piece in 0..6 + i / 2 {
+            file.write_at(piece * 5 * BLKSIZE, &[i as u8; 5 * BLKSIZE])?;
+        }
+        let blocks = file_blocks(&file)?;
+        let gaps: usize = blocks.windows(2).map(|w| w[1].abs_diff(w[0])).sum();
+        let average = gaps as f64 / (blocks.len() - 1) as f64;
+        assert!(average < 1.5, "file {}: {:?}", i, blocks);
+        let group = file.metadata()?.inode / ALLOC_GROUP_BLOCKS;
+        if blocks.iter().all(|&id| id / ALLOC_GROUP_BLOCKS == group) {
+            together += 1;
+        }
+    }
+    assert!(together * 10 > 50 * 9, "{} of 50", together);
+    Ok(())
+}
+
+#[test]
+fn alloc_groups_spill_to_emptiest_group() -> Result<()> {
+    const BLOCKS: usize = 4 * ALLOC_GROUP_BLOCKS;
+    let (_, sfs) = mem_sfs(BLOCKS)?;
+    let root = sfs.root_inode();
+    // group 2 is the emptiest after group 0
+    for group in [1, 3] {
+        for _ in 0..100 {
+            let id = sfs.alloc_block(Some(group * ALLOC_GROUP_BLOCKS)).unwrap();
+            assert_eq!(id / ALLOC_GROUP_BLOCKS, group);
+        }
+    }
+    sfs.sync()?;
+    let free = sfs.info().bfree;
+
+    let file = root.create("big", FileType::File, 0o644)?;
+    file.resize(ALLOC_GROUP_BLOCKS * BLKSIZE)?;
+    let blocks = file_blocks(&file)?;
+    let spilled: Vec<_> = blocks
+        .iter()
+        .skip_while(|&&id| id < ALLOC_GROUP_BLOCKS)
+        .collect();
+    assert!(!spilled.is_empty());
+    assert!(spilled.iter().all(|&&id| id / ALLOC_GROUP_BLOCKS == 2));
+    assert!(spilled.windows(2).all(|w| *w[1] == *w[0] + 1));
+    assert_eq!(sfs.info().bfree, sfs.free_map.read().count_ones());
+
+    // the rest of the device is still used, then it is full
+    let rest = root.create("rest", FileType::File, 0o644)?;
+    let len = (sfs.info().bfree - 64) * BLKSIZE;
+    rest.resize(len)?;
+    assert_eq!(rest.resize(BLOCKS * BLKSIZE), Err(FsError::NoDeviceSpace));
+    assert_eq!(rest.metadata()?.size, len);
+    assert_eq!(sfs.info().bfree, sfs.free_map.read().count_ones());
+    drop((file, rest));
+    root.unlink("big")?;
+    root.unlink("rest")?;
+    sfs.sync()?;
+    assert_eq!(sfs.info().bfree, free);
+    Ok(())
+}
+
+#[test]
+fn lz4_round_trip() {
+    let mut rng = Rng(5);
+    let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog "
+        .iter()
+        .cycle()
+        .take(COMPRESS_CHUNK_SIZE)
+        .cloned()
+        .collect();
+    let random: Vec<u8> = (0..COMPRESS_CHUNK_SIZE)
+        .map(|_| rng.below(256) as u8)
+        .collect();
+    let mixed: Vec<u8> = (0..COMPRESS_CHUNK_SIZE)
+        .map(|i| match (i / 300) % 2 {
+            0 => text[i],
+            _ => random[i],
+        })
+        .collect();
+    let inputs = [
+        &b""[..],
+        b"a",
+        b"abcdabcdabcdabcd",
+        &[0; 13],
+        &[7; COMPRESS_CHUNK_SIZE],
+        &text,
+        &random,
+        &mixed,
+    ];
+    for input in inputs {
+        let mut packed = Vec::new();
+        compress::lz4_compress(input, &mut packed);
+        let mut out = vec![0; input.len()];
+        assert_eq!(compress::lz4_decompress(&packed, &mut out), Some(()));
+        assert!(out == input);
+        // the size must be exact
+        let mut longer = vec![0; input.len() + 1];
+        assert_eq!(compress::lz4_decompress(&packed, &mut longer), None);
+        if !input.is_empty() {
+            assert_eq!(compress::lz4_decompress(&packed, &mut out[1..]), None);
+        }
+    }
+    let mut packed = Vec::new();
+    compress::lz4_compress(&mixed, &mut packed);
+    assert!(packed.len() < mixed.len() * 3 / 4);
+    let mut out = vec![0; mixed.len()];
+    // malformed input fails without reading or writing out of bounds
+    for len in 0..packed.len() {
+        assert_eq!(compress::lz4_decompress(&packed[..len], &mut out), None);
+    }
+    for _ in 0..1000 {
+        let mut broken = packed.clone();
+        let i = rng.below(broken.len());
+        broken[i] = rng.below(256) as u8;
+        let _ = compress::lz4_decompress(&broken, &mut out);
+    }
+}
+
+#[test]
+fn pack_compressed_files() -> Result<()> {
+    let mut rng = Rng(11);
+    let sfs = _create_new_sfs();
+    let src = sfs.root_inode().create("src", FileType::Dir, 0o755)?;
+    let text: Vec<u8> = (0..300 * BLKSIZE + 77)
+        .map(|i| b"locale data, font glyphs "[i % 25] ^ (i / 4096) as u8)
+        .collect();
+    let random: Vec<u8> = (0..25 * BLKSIZE + 3)
+        .map(|_| rng.below(256) as u8)
+        .collect();
+    // compressible but for one chunk
+    let mut mixed = text[..5 * COMPRESS_CHUNK_SIZE].to_vec();
+    mixed[COMPRESS_CHUNK_SIZE..2 * COMPRESS_CHUNK_SIZE]
+        .copy_from_slice(&random[..COMPRESS_CHUNK_SIZE]);
+    let files = [
+        ("text", &text[..]),
+        ("random", &random[..]),
+        ("mixed", &mixed[..]),
+        ("small", &text[..100]),
+        ("block", &text[..BLKSIZE]),
+    ];
+    for (name, data) in files {
+        src.create(name, FileType::File, 0o644)?.write_at(0, data)?;
+    }
+    src.find("text")?.set_flags(InodeFlags::IMMUTABLE)?;
+    let device = MemDevice::new(2048);
+    let opts = PackOptions {
+        compress: Some(10),
+        ..Default::default()
+    };
+    let packed = pack_subtree(&src, Arc::new(device.clone()), opts)?;
+    drop(packed);
+
+    let packed = SimpleFileSystem::open(Arc::new(device.clone()))?;
+    let root = packed.root_inode();
+    let is_compressed = |name: &str| -> Result<bool> {
+        let file = root.find(name)?;
+        let file = file.downcast_ref::<INodeImpl>().unwrap();
+        let compressed = file.disk_inode.read().is_compressed();
+        Ok(compressed)
+    };
+    for (name, data) in files {
+        let file = root.find(name)?;
+        let meta = file.metadata()?;
+        assert_eq!(meta.size, data.len(), "{}", name);
+        let raw_blocks = data.len().div_ceil(BLKSIZE);
+        match name {
+            "text" | "mixed" => {
+                assert!(is_compressed(name)?, "{}", name);
+                assert!(meta.blocks < raw_blocks * 9 / 10, "{}: {:?}", name, meta);
+            }
+            _ => {
+                assert!(!is_compressed(name)?, "{}", name);
+                assert_eq!(meta.blocks, raw_blocks, "{}", name);
+            }
+        }
+        let mut content = vec![0; data.len() + 10];
+        assert_eq!(file.read_at(0, &mut content)?, data.len());
+        assert!(&content[..data.len()] == data, "{}", name);
+        for _ in 0..200 {
+            let offset = rng.below(data.len() + 10);
+            let len = rng.below(3 * COMPRESS_CHUNK_SIZE);
+            let mut buf = vec![0; len];
+            let read = file.read_at(offset, &mut buf)?;
+            let expected = &data[offset.min(data.len())..(offset + len).min(data.len())];
+            assert!(&buf[..read] == expected, "{} at {} + {}", name, offset, len);
+        }
+    }
+    let text_file = root.find("text")?;
+    assert_eq!(text_file.get_flags()?, InodeFlags::IMMUTABLE);
+    let mut buf = vec![0; 4 * BLKSIZE];
+    assert_eq!(text_file.read_at_direct(BLKSIZE, &mut buf)?, buf.len());
+    assert!(buf == text[BLKSIZE..5 * BLKSIZE]);
+
+    // read-only
+    let mixed_file = root.find("mixed")?;
+    assert_eq!(mixed_file.write_at(0, b"x"), Err(FsError::Unsupported));
+    assert_eq!(
+        mixed_file.write_at_direct(0, &[0; BLKSIZE]),
+        Err(FsError::Unsupported)
+    );
+    assert_eq!(mixed_file.resize(0), Err(FsError::Unsupported));
+    assert_eq!(
+        mixed_file.fallocate(0, BLKSIZE, FallocateMode::empty()),
+        Err(FsError::Unsupported)
+    );
+    assert_eq!(packed.check_chunk_tables()?, vec![]);
+    let id = mixed_file.metadata()?.inode;
+    let first_block = mixed_file
+        .downcast_ref::<INodeImpl>()
+        .unwrap()
+        .get_disk_block_id(0)?;
+    drop((root, text_file, mixed_file));
+    drop(packed);
+    let report = fsck(device.image());
+    assert_eq!((report.leaked, report.unmarked), (0, 0));
+    assert_eq!(report.errors, Vec::<String>::new());
+
+    // a chunk pointing past the blocks of the file
+    let entry = first_block * BLKSIZE + 16 + 8 * 4;
+    device.0.lock().unwrap()[entry..entry + 4].copy_from_slice(&1000u32.to_le_bytes());
+    let report = fsck(device.image());
+    assert_eq!(report.errors, vec![format!("chunk table of {}", id)]);
+    let packed = SimpleFileSystem::open(Arc::new(device.clone()))?;
+    assert_eq!(packed.check_chunk_tables()?, vec![id]);
+    let mixed_file = packed.root_inode().find("mixed")?;
+    let mut buf = vec![0; 10];
+    assert_eq!(
+        mixed_file.read_at(4 * COMPRESS_CHUNK_SIZE, &mut buf),
+        Err(FsError::Corrupted)
+    );
+    assert_eq!(mixed_file.read_at(0, &mut buf)?, 10);
+    Ok(())
+}
+
+#[test]
+fn metrics_of_sfs() -> Result<()> {
+    use rcore_fs::metrics::Registry;
+    let sfs = _create_new_sfs();
+    sfs.root_inode()
+        .create("file", FileType::File, 0o644)?
+        .resize(10 * BLKSIZE)?;
+    let registry = Registry::new();
+    registry.register("root", sfs.clone());
+    let text = registry.render();
+    let info = sfs.info();
+    let stats = sfs.stats();
+    for line in [
+        "# TYPE rcore_fs_free_blocks gauge".into(),
+        format!("rcore_fs_free_blocks{{fs=\"root\"}} {}", info.bfree),
+        format!("rcore_fs_blocks{{fs=\"root\"}} {}", info.blocks),
+        "# TYPE rcore_fs_sfs_scratch_hits_total counter".into(),
+        format!(
+            "rcore_fs_sfs_scratch_hits_total{{fs=\"root\"}} {}",
+            stats.scratch_hits
+        ),
+    ] {
+        assert!(text.lines().any(|l| l == line), "{}", line);
+    }
+    Ok(())
+}
+
+#[test]
+fn rename_onto_own_link() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    let file = root.create("a", FileType::File, 0o777)?;
+    root.link("b", &file)?;
+    dir.link("c", &file)?;
+    let counts = || -> Result<_> { Ok((root.metadata()?.size, dir.metadata()?.size)) };
+    let before = counts()?;
+
+    // same dir, across dirs both ways, and onto the same name
+    root.move_("a", &root, "b")?;
+    root.move_("b", &dir, "c")?;
+    dir.move_("c", &root, "a")?;
+    root.move_("a", &root, "a")?;
+    for (parent, name) in [(&root, "a"), (&root, "b"), (&dir, "c")] {
+        assert_eq!(parent.find(name)?.ino_key(), file.ino_key());
+    }
+    assert_eq!(file.metadata()?.nlinks, 3);
+    assert_eq!(counts()?, before);
+
+    // a different file is still replaced
+    let other = root.create("d", FileType::File, 0o777)?;
+    root.move_("d", &root, "b")?;
+    assert_eq!(root.find("b")?.ino_key(), other.ino_key());
+    assert!(root.find("d").is_err());
+    Ok(())
+}
+
+/// Mount state on disk of the image in `device`
+fn mount_state(device: &MemDevice) -> u32 {
+    let device: &dyn Device = device;
+    device.load_struct::<SuperBlock>(BLKN_SUPER).unwrap().state
+}
+
+#[test]
+fn unmount_marks_clean() -> Result<()> {
+    let (device, sfs) = mem_sfs(1024)?;
+    sfs.root_inode()
+        .create("file", FileType::File, 0o644)?
+        .write_at(0, b"data")?;
+    sfs.sync()?;
+    assert_eq!(mount_state(&device), STATE_DIRTY);
+    sfs.unmount()?;
+    assert_eq!(mount_state(&device), STATE_CLEAN);
+    drop(sfs);
+    assert_eq!(mount_state(&device), STATE_CLEAN);
+
+    let sfs = SimpleFileSystem::open(Arc::new(device.clone()))?;
+    assert!(!sfs.opened_dirty());
+    // marked in use as soon as opened
+    assert_eq!(mount_state(&device), STATE_DIRTY);
+    sfs.unmount()?;
+    assert_eq!(mount_state(&device), STATE_CLEAN);
+    // and again when modified after unmount
+    sfs.root_inode().find("file")?.write_at(4, b"more")?;
+    assert_eq!(mount_state(&device), STATE_DIRTY);
+    Ok(())
+}
+
+#[test]
+fn crash_leaves_dirty() -> Result<()> {
+    let (device, sfs) = mem_sfs(1024)?;
+    let file = sfs.root_inode().create("file", FileType::File, 0o644)?;
+    file.write_at(0, b"data")?;
+    sfs.sync()?;
+    let file_id = file.metadata()?.inode;
+    let bfree = sfs.info().bfree;
+    // crash: the image as it is, without unmount
+    let image = device.image();
+    drop(file);
+    drop(sfs);
+    let open = |image: &Vec<u8>, on_dirty| {
+        let device = MemDevice(Arc::new(Mutex::new(image.clone())));
+        let sfs = SimpleFileSystem::open_with_options(
+            Arc::new(device.clone()),
+            crate::OpenOptions {
+                on_dirty,
+                names: None,
+            },
+        );
+        (sfs, device)
+    };
+
+    let (sfs, device) = open(&image, DirtyPolicy::RequireFsck);
+    assert_eq!(sfs.err(), Some(FsError::Corrupted));
+    assert!(*device.0.lock().unwrap() == image);
+    let (sfs, _) = open(&image, DirtyPolicy::Proceed);
+    assert!(sfs?.opened_dirty());
+    let (sfs, _) = open(&image, DirtyPolicy::QuickScan);
+    assert!(sfs?.root_inode().find("file").is_ok());
+
+    // the free block count is fixed from the freemap
+    let mut counted_wrong = image.clone();
+    counted_wrong[8..12].copy_from_slice(&(bfree as u32 - 1).to_le_bytes());
+    let (sfs, _) = open(&counted_wrong, DirtyPolicy::Proceed);
+    assert_eq!(sfs?.info().bfree, bfree - 1);
+    let (sfs, _) = open(&counted_wrong, DirtyPolicy::QuickScan);
+    assert_eq!(sfs?.info().bfree, bfree);
+
+    // an inode in a block marked free fails the scan
+    let mut inode_free = image.clone();
+    inode_free[BLKN_FREEMAP * BLKSIZE + file_id / 8] |= 1 << (file_id % 8);
+    let (sfs, device) = open(&inode_free, DirtyPolicy::QuickScan);
+    assert_eq!(sfs.err(), Some(FsError::Corrupted));
+    assert!(*device.0.lock().unwrap() == inode_free);
+    let (sfs, _) = open(&inode_free, DirtyPolicy::Proceed);
+    assert!(sfs.is_ok());
+    Ok(())
+}
+
+#[test]
+fn open_read_only_keeps_mount_state() -> Result<()> {
+    let device = Arc::new(MockDevice::new(1024));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    sfs.unmount()?;
+    drop(sfs);
+    device.set_protected(true);
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    assert!(sfs.is_read_only() && !sfs.opened_dirty());
+    sfs.unmount()?;
+    drop(sfs);
+    device.set_protected(false);
+    assert!(!SimpleFileSystem::open(device)?.opened_dirty());
+    Ok(())
+}
+
+#[test]
+fn symlink_at_once() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let link = root.symlink("link", "target")?;
+    let mut buf = [0; 16];
+    assert_eq!(link.read_at(0, &mut buf)?, 6);
+    assert_eq!(&buf[..6], b"target");
+    assert_eq!(link.metadata()?.type_, FileType::SymLink);
+    assert!(Arc::ptr_eq(&root.find("link")?, &link));
+
+    assert_eq!(root.symlink("empty", "").err(), Some(FsError::InvalidParam));
+    assert_eq!(root.find("empty").err(), Some(FsError::EntryNotFound));
+    assert_eq!(root.symlink("link", "x").err(), Some(FsError::EntryExist));
+    assert_eq!(link.symlink("x", "y").err(), Some(FsError::NotDir));
+    Ok(())
+}
+
+#[test]
+fn empty_symlink_is_corrupted() -> Result<()> {
+    let (device, sfs) = mem_sfs(256)?;
+    // as left by a crash between creating and writing it
+    sfs.root_inode().create("link", FileType::SymLink, 0o777)?;
+    sfs.root_inode().create("file", FileType::File, 0o644)?;
+    sfs.unmount()?;
+    drop(sfs);
+
+    let root = SimpleFileSystem::open(Arc::new(device))?.root_inode();
+    assert_eq!(root.lookup("link")?.metadata()?.size, 0);
+    assert_eq!(
+        root.lookup_follow("link", 1).err(),
+        Some(FsError::Corrupted)
+    );
+    assert_eq!(
+        root.lookup_follow("link/file", 1).err(),
+        Some(FsError::Corrupted)
+    );
+    Ok(())
+}
+
+#[test]
+fn symlink_in_path() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    let file = dir.create("x", FileType::File, 0o644)?;
+    let link = root.symlink("link", "dir")?;
+
+    // a symlink is no dir, unless followed
+    assert_eq!(link.find("x").err(), Some(FsError::NotDir));
+    assert_eq!(link.find(".").err(), Some(FsError::NotDir));
+    assert_eq!(root.lookup("link/x").err(), Some(FsError::NotDir));
+    assert!(Arc::ptr_eq(&root.lookup("link")?, &link));
+    assert!(Arc::ptr_eq(&root.lookup_follow("link/x", 1)?, &file));
+    Ok(())
+}
+
+#[test]
+fn data_version_counts_content_changes() -> Result<()> {
+    let (device, sfs) = mem_sfs(256)?;
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    assert_eq!(file.data_version(), 0);
+
+    // (change, expected version after it)
+    let steps: [(&dyn Fn() -> Result<()>, u64); 12] = [
+        (&|| file.write_at(0, b"data").map(drop), 1),
+        (&|| file.write_at(2, b"").map(drop), 1),
+        (&|| file.read_at(0, &mut [0; 4]).map(drop), 1),
+        (&|| file.set_metadata(&file.metadata()?), 1),
+        (&|| file.resize(4), 1),
+        (&|| file.resize(2 * BLKSIZE), 2),
+        (&|| file.resize(3), 3),
+        (&|| file.write_at(BLKSIZE, &[1; BLKSIZE]).map(drop), 4),
+        (
+            &|| file.fallocate(0, 4 * BLKSIZE, FallocateMode::empty()),
+            5,
+        ),
+        (
+            &|| file.fallocate(0, 8 * BLKSIZE, FallocateMode::KEEP_SIZE),
+            5,
+        ),
+        (
+            &|| file.fallocate(0, 1, FallocateMode::KEEP_SIZE | FallocateMode::PUNCH_HOLE),
+            6,
+        ),
+        (&|| file.resize_with(BLKSIZE, &vfs::TaskContext::new()), 7),
+    ];
+    for (i, (step, version)) in steps.iter().enumerate() {
+        step()?;
+        assert_eq!(file.data_version(), *version, "step {}", i);
+    }
+    assert_eq!(root.find("file")?.data_version(), 7);
+    assert_eq!(root.lookup("./file")?.data_version(), 7);
+
+    // kept on disk, and growing on from there
+    drop((file, root));
+    sfs.unmount()?;
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(Arc::new(device.clone()))?;
+    let file = sfs.root_inode().find("file")?;
+    assert_eq!(file.data_version(), 7);
+    file.write_at(0, b"more")?;
+    assert_eq!(file.data_version(), 8);
+    drop(file);
+    sfs.unmount()?;
+    drop(sfs);
+
+    // images before VERSION_DATA_VERSION count from 0 in each mount
+    let dev: Arc<dyn Device> = Arc::new(device.clone());
+    let mut super_block = dev.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    super_block.version = VERSION_STATE;
+    dev.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+    let sfs = SimpleFileSystem::open(Arc::new(device))?;
+    let file = sfs.root_inode().find("file")?;
+    assert_eq!(file.data_version(), 0);
+    file.write_at(0, b"again")?;
+    assert_eq!(file.data_version(), 1);
+    Ok(())
+}
+
+#[test]
+fn diff_images_reports_each_change() -> Result<()> {
+    use futures::executor::block_on;
+    use rcore_fs::vfs::MetadataMask;
+
+    let image = |change: bool| -> Result<MemDevice> {
+        let (device, sfs) = mem_sfs(256)?;
+        let root = sfs.root_inode();
+        root.create("same", FileType::File, 0o644)?
+            .write_at(0, b"same")?;
+        root.create("chmod", FileType::File, 0o644)?;
+        let content = root.create("content", FileType::File, 0o644)?;
+        content.write_at(0, b"hello")?;
+        let old = root.create("old", FileType::Dir, 0o755)?;
+        old.create("file", FileType::File, 0o644)?;
+        let linked = root.create("linked", FileType::File, 0o644)?;
+        linked.write_at(0, b"linked")?;
+        if change {
+            root.create("new", FileType::File, 0o644)?;
+            old.unlink("file")?;
+            root.unlink("old")?;
+            content.write_at(0, b"HELLO")?;
+            let chmod = root.find("chmod")?;
+            let mut meta = chmod.metadata()?;
+            meta.mode = 0o600;
+            chmod.set_metadata(&meta)?;
+            root.link("hardlink", &linked)?;
+        }
+        drop((root, old, content, linked));
+        sfs.unmount()?;
+        Ok(device)
+    };
+    let (a, b) = (image(false)?, image(true)?);
+    let opts = DiffOpts { content_hash: true };
+    let diff = block_on(diff_images(Arc::new(a.clone()), Arc::new(b.clone()), opts))?;
+
+    let free = |device: &MemDevice| -> Result<u32> {
+        let sfs = SimpleFileSystem::open(Arc::new(device.clone()))?;
+        Ok(sfs.info().bfree as u32)
+    };
+    let (free_a, free_b) = (free(&a)?, free(&b)?);
+    let expected = SuperBlockDiff {
+        unused_blocks: Some((free_a, free_b)).filter(|_| free_a != free_b),
+        ..SuperBlockDiff::default()
+    };
+    assert_eq!(diff.super_block, expected);
+    let entry = |path: &str, type_, link_of: Option<&str>| DiffEntry {
+        path: String::from(path),
+        type_,
+        link_of: link_of.map(String::from),
+    };
+    assert_eq!(
+        diff.added,
+        [
+            entry("/hardlink", FileType::File, Some("/linked")),
+            entry("/new", FileType::File, None),
+        ]
+    );
+    assert_eq!(diff.removed, [entry("/old", FileType::Dir, None)]);
+    let modified: Vec<_> = diff
+        .modified
+        .iter()
+        .map(|m| (m.path.as_str(), m.changed, m.content_changed))
+        .collect();
+    assert_eq!(
+        modified,
+        [
+            ("/chmod", MetadataMask::MODE, Some(false)),
+            ("/content", MetadataMask::empty(), Some(true)),
+        ]
+    );
+    let printed = diff.to_string();
+    assert!(printed.contains("+ /hardlink (file, link of /linked)\n"));
+    assert!(printed.contains("M /chmod: mode 644 -> 600, content unchanged\n"));
+    assert!(diff
+        .to_json()
+        .contains(r#""removed":[{"path":"/old","type":"dir","link_of":null}]"#));
+
+    // without hashes, a change keeping the size and mtime is not seen
+    let diff = block_on(diff_images(
+        Arc::new(a.clone()),
+        Arc::new(b),
+        DiffOpts::default(),
+    ))?;
+    assert_eq!(diff.modified.len(), 1);
+    assert_eq!(diff.modified[0].path, "/chmod");
+    // nothing changed
+    let diff = block_on(diff_images(Arc::new(a.clone()), Arc::new(a), opts))?;
+    assert!(diff.is_empty(), "{}", diff);
+    Ok(())
+}
+
+/// Flip the freemap bit of `block`, keeping the free count in line as a
+/// corrupt image would
+fn flip_free_bit(sfs: &SimpleFileSystem, block: BlockId) {
+    let mut free_map = sfs.free_map.write();
+    let free = free_map[block];
+    free_map.set(block, !free);
+    match free {
+        true => sfs.unused_blocks.fetch_sub(1, Ordering::Relaxed),
+        false => sfs.unused_blocks.fetch_add(1, Ordering::Relaxed),
+    };
+}
+
+#[test]
+fn scrub_while_changing() -> Result<()> {
+    use futures::executor::block_on;
+
+    let (_, sfs) = yielding_sfs(1024)?;
+    let root = sfs.root_inode();
+    // the workload takes the first free blocks, freed by "pad" below "keep",
+    // so it never takes a block of "keep" marked free
+    let pad = root.create("pad", FileType::File, 0o644)?;
+    pad.resize(128 * BLKSIZE)?;
+    let keep = root.create("keep", FileType::File, 0o644)?;
+    keep.write_at(0, &[7; 4 * BLKSIZE])?;
+    drop(pad);
+    root.unlink("pad")?;
+    let work = root.create("work", FileType::Dir, 0o755)?;
+    let sub = work.create("sub", FileType::Dir, 0o755)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let workload = {
+        let stop = stop.clone();
+        std::thread::spawn(move || -> Result<usize> {
+            let mut rounds = 0;
+            while !stop.load(Ordering::SeqCst) {
+                for i in 0..4 {
+                    let file = work.create(&format!("{}-{}", rounds, i), FileType::File, 0o644)?;
+                    file.write_at(0, &[i as u8; 2 * BLKSIZE])?;
+                }
+                work.move_(&format!("{}-0", rounds), &sub, "moved")?;
+                for i in 1..4 {
+                    work.unlink(&format!("{}-{}", rounds, i))?;
+                }
+                sub.unlink("moved")?;
+                rounds += 1;
+            }
+            Ok(rounds)
+        })
+    };
+    // one pass, a few blocks at a time
+    let pass = |state: &mut ScrubState| -> Result<ScrubFindings> {
+        let mut all = ScrubFindings::default();
+        loop {
+            let budget = ScrubBudget { max_blocks: 4 };
+            let findings = block_on(sfs.scrub(budget, state))?;
+            all.inconsistent.extend(findings.inconsistent);
+            all.recheck.extend(findings.recheck);
+            all.repaired.extend(findings.repaired);
+            if findings.pass_complete {
+                return Ok(all);
+            }
+        }
+    };
+
+    let mut state = ScrubState::new(false);
+    for _ in 0..3 {
+        assert_eq!(pass(&mut state)?.inconsistent, []);
+    }
+
+    let leaked = sfs
+        .data_blocks
+        .clone()
+        .rev()
+        .find(|&b| sfs.free_map.read()[b]);
+    let leaked = leaked.unwrap();
+    flip_free_bit(&sfs, leaked);
+    let keep_id = keep.metadata()?.inode;
+    let marked_free = sfs.get_inode(keep_id)?.get_disk_block_id(3)?;
+    flip_free_bit(&sfs, marked_free);
+    let mut state = ScrubState::new(true);
+    // a leak is certain once found twice
+    let first = pass(&mut state)?;
+    assert_eq!(first.inconsistent, [ScrubProblem::MarkedFree(marked_free)]);
+    assert_eq!(first.repaired, first.inconsistent);
+    assert!(first.recheck.contains(&ScrubProblem::Leaked(leaked)));
+    let second = pass(&mut state)?;
+    assert_eq!(second.inconsistent, [ScrubProblem::Leaked(leaked)]);
+    assert_eq!(second.repaired, second.inconsistent);
+
+    stop.store(true, Ordering::SeqCst);
+    assert!(workload.join().unwrap()? > 0);
+    let last = pass(&mut state)?;
+    assert_eq!((last.inconsistent, last.recheck), (vec![], vec![]));
+    assert!(!sfs.free_map.read()[marked_free] && sfs.free_map.read()[leaked]);
+    let free = sfs.free_map.read().count_ones() as u32;
+    assert_eq!(sfs.unused_blocks.load(Ordering::Relaxed), free);
+    let mut buf = [0; 4 * BLKSIZE];
+    assert_eq!(keep.read_at(0, &mut buf)?, buf.len());
+    assert!(buf.iter().all(|&b| b == 7));
+    Ok(())
+}
+
+#[test]
+fn next_entries_in_windows() -> Result<()> {
+    const N: usize = 50_000;
+    let (_, sfs) = mem_sfs(8192)?;
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    link_many(&root, &dir, N)?;
+    LARGEST_ALLOCATION.with(|n| n.set(0));
+    let listing = dir.list()?;
+    assert_eq!(listing.len(), N + 2);
+    assert!(LARGEST_ALLOCATION.with(|n| n.get()) > N * std::mem::size_of::<String>());
+
+    let mut slots = vec![DirEntrySlot::default(); 128];
+    LARGEST_ALLOCATION.with(|n| n.set(0));
+    let before = ALLOCATIONS.with(|n| n.get());
+    let mut cursor = DirCursor::default();
+    let (mut listed, mut windows) = (0, 0);
+    loop {
+        let filled = dir.next_entries(&mut cursor, &mut slots)?;
+        for slot in &slots[..filled] {
+            assert_eq!(slot.name, listing[listed]);
+            let type_ = if listed < 2 {
+                FileType::Dir
+            } else {
+                FileType::File
+            };
+            assert_eq!(slot.type_, type_);
+            listed += 1;
+            assert_eq!(slot.next, listed);
+        }
+        windows += 1;
+        if filled < slots.len() {
+            break;
+        }
+    }
+    let allocations = ALLOCATIONS.with(|n| n.get()) - before;
+    assert_eq!(listed, listing.len());
+    // none grows with the dir: the names of the slots, then buffers of a
+    // block at most freed at once, for the blocks read
+    assert!(
+        allocations < N / 4,
+        "{} allocations for {} windows",
+        allocations,
+        windows
+    );
+    assert!(LARGEST_ALLOCATION.with(|n| n.get()) <= BLKSIZE);
+
+    // a change between windows is reported, and the cursor may go on anyway
+    let mut cursor = DirCursor::default();
+    assert_eq!(dir.next_entries(&mut cursor, &mut slots)?, slots.len());
+    dir.create("new", FileType::File, 0o644)?;
+    let next = cursor.next;
+    assert_eq!(
+        dir.next_entries(&mut cursor, &mut slots),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(cursor.next, next);
+    let mut cursor = DirCursor::at(next);
+    assert_eq!(dir.next_entries(&mut cursor, &mut slots)?, slots.len());
+    assert_eq!(slots[0].name, listing[next]);
+    // appended at the end
+    let mut cursor = DirCursor::at(N + 2);
+    assert_eq!(dir.next_entries(&mut cursor, &mut slots)?, 1);
+    assert_eq!(slots[0].name, "new");
+    Ok(())
+}
+
+#[test]
+fn find_racing_reclaim() -> Result<()> {
+    let (_device, sfs) = yielding_sfs(256)?;
+    let root = sfs.root_inode();
+    let find_paused = |name: &'static str| {
+        failpoint::arm(&sfs, "find_after_entry", 1, failpoint::FailAction::Pause);
+        let root = root.clone();
+        let finder = std::thread::spawn(move || root.find(name).map(|inode| inode.metadata()));
+        while failpoint::hits(&sfs, "find_after_entry") == 0 {
+            std::thread::yield_now();
+        }
+        finder
+    };
+
+    // reclaimed and the block reused by another before the inode is loaded
+    let id = root.create("a", FileType::File, 0o644)?.metadata()?.inode;
+    let finder = find_paused("a");
+    root.unlink("a")?;
+    let b = root.create("b", FileType::File, 0o644)?;
+    assert_eq!(b.metadata()?.inode, id);
+    b.write_at(0, b"b")?;
+    failpoint::resume(&sfs, "find_after_entry");
+    let found = finder.join().unwrap();
+    assert_eq!(found.err(), Some(FsError::EntryNotFound));
+    failpoint::disarm_all(&sfs);
+
+    // loaded while being reclaimed
+    let id = root.create("c", FileType::File, 0o644)?.metadata()?.inode;
+    let finder = find_paused("c");
+    failpoint::arm(&sfs, "drop_after_resize0", 1, failpoint::FailAction::Pause);
+    let unlinker = {
+        let root = root.clone();
+        std::thread::spawn(move || root.unlink("c"))
+    };
+    while failpoint::hits(&sfs, "drop_after_resize0") == 0 {
+        std::thread::yield_now();
+    }
+    assert_eq!(sfs.get_inode(id).err(), Some(FsError::EntryNotFound));
+    failpoint::resume(&sfs, "find_after_entry");
+    let found = finder.join().unwrap();
+    assert_eq!(found.err(), Some(FsError::EntryNotFound));
+    failpoint::resume(&sfs, "drop_after_resize0");
+    unlinker.join().unwrap()?;
+    failpoint::disarm_all(&sfs);
+    assert_eq!(sfs.get_inode(id).err(), Some(FsError::Corrupted));
+    Ok(())
+}
+
+#[test]
+fn create_unlink_find_stress() -> Result<()> {
+    const NAMES: usize = 4;
+    let (device, sfs) = yielding_sfs(256)?;
+    let root = sfs.root_inode();
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            let root = root.clone();
+            std::thread::spawn(move || {
+                let mut rng = worker as u64 + 1;
+                let mut next = || {
+                    rng ^= rng << 13;
+                    rng ^= rng >> 7;
+                    rng ^= rng << 17;
+                    rng as usize
+                };
+                for round in 0..300 {
+                    let name = format!("{}", next() % NAMES);
+                    let result = match next() % 4 {
+                        0 => root.unlink(&name),
+                        1 => root.create(&name, FileType::File, 0o644).and_then(|file| {
+                            // each file is marked with the name it was created as
+                            let marker = format!("{}:{}:{}", name, worker, round);
+                            file.write_at(0, marker.as_bytes()).map(drop)
+                        }),
+                        2 => (0..NAMES + 2)
+                            .try_for_each(|id| root.get_entry_with_metadata(id).map(drop)),
+                        _ => root.find(&name).and_then(|file| {
+                            let mut buf = [0u8; 32];
+                            let len = file.read_at(0, &mut buf)?;
+                            let content = std::str::from_utf8(&buf[..len]).unwrap();
+                            // not yet written, or of that name
+                            assert!(
+                                len == 0 || content.split(':').next() == Some(name.as_str()),
+                                "found {:?} as {}",
+                                content,
+                                name
+                            );
+                            Ok(())
+                        }),
+                    };
+                    match result {
+                        Ok(()) | Err(FsError::EntryNotFound) | Err(FsError::EntryExist) => {}
+                        Err(err) => panic!("{}: {:?}", name, err),
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    sfs.sync()?;
+    let report = fsck(device.mem.image());
+    assert!(report.errors.is_empty(), "{:?}", report);
+    assert_eq!(report.leaked + report.unmarked, 0, "{:?}", report);
+    Ok(())
+}
+
+#[test]
+fn reserved_blocks_never_used() -> Result<()> {
+    const BLOCKS: usize = 256;
+    // right after the freemap, of one block
+    let reserved = BLKN_FREEMAP + 1..BLKN_FREEMAP + 1 + 64;
+    let device = Arc::new(MockDevice::new(BLOCKS));
+    *device.fence.lock().unwrap() = reserved.clone();
+    let opts = CreateOptions {
+        reserved_blocks: 64,
+        root_block: None,
+        ..CreateOptions::default()
+    };
+    let sfs =
+        SimpleFileSystem::create_with_options(device.clone(), BLOCKS * BLKSIZE, [1; 16], opts)?;
+    assert_eq!(sfs.reserved_range(), reserved);
+    let root = sfs.root_inode();
+    for i in 0.. {
+        let file = match root.create(&format!("f{}", i), FileType::File, 0o644) {
+            Ok(file) => file,
+            Err(FsError::NoDeviceSpace) => break,
+            Err(err) => return Err(err),
+        };
+        match file.write_at(0, &[i as u8; 3 * BLKSIZE]) {
+            Ok(_) | Err(FsError::NoDeviceSpace) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    assert_eq!(sfs.info().bavail, 0);
+    assert_eq!(sfs.info().bfree, sfs.recovery_blocks());
+    drop(root);
+    sfs.sync()?;
+    drop(sfs);
+    assert!(!device.crossed.load(Ordering::SeqCst));
+    let report = fsck(device.mem.image());
+    assert!(report.errors.is_empty(), "{:?}", report);
+    assert_eq!(report.leaked + report.unmarked, 0, "{:?}", report);
+
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    assert_eq!(sfs.reserved_range(), reserved);
+    sfs.root_inode().unlink("f0")?;
+    sfs.root_inode()
+        .create("again", FileType::File, 0o644)?
+        .write_at(0, &[1; BLKSIZE])?;
+    sfs.sync()?;
+    assert!(!device.crossed.load(Ordering::SeqCst));
+    Ok(())
+}
+
+#[test]
+fn root_at_chosen_block() -> Result<()> {
+    const BLOCKS: usize = 256;
+    let mem = MemDevice::new(BLOCKS);
+    let opts = CreateOptions {
+        reserved_blocks: 8,
+        root_block: Some(100),
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(
+        Arc::new(mem.clone()),
+        BLOCKS * BLKSIZE,
+        [2; 16],
+        opts,
+    )?;
+    let root = sfs.root_inode();
+    assert_eq!(root.metadata()?.inode, 100);
+    root.create("boot", FileType::Dir, 0o755)?
+        .create("kernel", FileType::File, 0o644)?
+        .write_at(0, b"kernel")?;
+    drop(root);
+    sfs.sync()?;
+    drop(sfs);
+
+    // all a bootloader needs to find it
+    let device: Arc<dyn Device> = Arc::new(mem.clone());
+    let super_block = device.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    assert_eq!(super_block.root_block, 100);
+    let sfs = SimpleFileSystem::open(device)?;
+    let root = sfs.root_inode();
+    assert_eq!(root.metadata()?.inode, 100);
+    assert_eq!(root.find("..")?.metadata()?.inode, 100);
+    let boot = root.find("boot")?;
+    assert_eq!(boot.find("..")?.metadata()?.inode, 100);
+    let mut buf = [0; 6];
+    boot.find("kernel")?.read_at(0, &mut buf)?;
+    assert_eq!(&buf, b"kernel");
+    assert!(sfs.reserved_range().len() == 8);
+    drop((root, boot, sfs));
+    let report = fsck(mem.image());
+    assert!(report.errors.is_empty(), "{:?}", report);
+    assert_eq!(report.leaked + report.unmarked, 0, "{:?}", report);
+
+    // not in the reserved blocks, the metadata or a backup superblock
+    let data_begin = BLKN_FREEMAP + 1 + 8;
+    for root_block in [BLKN_SUPER, BLKN_FREEMAP, data_begin - 1, BLOCKS / 2, BLOCKS] {
+        let opts = CreateOptions {
+            reserved_blocks: 8,
+            root_block: Some(root_block),
+            ..CreateOptions::default()
+        };
+        let mem = MemDevice::new(BLOCKS);
+        let result =
+            SimpleFileSystem::create_with_options(Arc::new(mem), BLOCKS * BLKSIZE, [3; 16], opts);
+        assert_eq!(result.err(), Some(FsError::InvalidParam), "{}", root_block);
+    }
+    // nor reaching the backup superblock in the middle
+    let opts = CreateOptions {
+        reserved_blocks: BLOCKS / 2,
+        root_block: None,
+        ..CreateOptions::default()
+    };
+    let mem = MemDevice::new(BLOCKS);
+    let result =
+        SimpleFileSystem::create_with_options(Arc::new(mem), BLOCKS * BLKSIZE, [4; 16], opts);
+    assert_eq!(result.err(), Some(FsError::InvalidParam));
+    Ok(())
+}
+
+#[test]
+fn old_layout_image() -> Result<()> {
+    let (device, sfs) = mem_sfs(256)?;
+    assert!(sfs.reserved_range().is_empty());
+    sfs.root_inode().create("file", FileType::File, 0o644)?;
+    sfs.unmount()?;
+    drop(sfs);
+
+    // the default layout is that of images before VERSION_LAYOUT, which
+    // have anything where its fields are
+    let dev: Arc<dyn Device> = Arc::new(device.clone());
+    let mut super_block = dev.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    assert_eq!(super_block.root_block, BLKN_ROOT as u32);
+    assert_eq!(super_block.reserved_blocks, 0);
+    super_block.version = VERSION_DATA_VERSION;
+    super_block.root_block = 0xdead;
+    super_block.reserved_blocks = 0xbeef;
+    dev.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+    let sfs = SimpleFileSystem::open(Arc::new(device))?;
+    assert!(sfs.reserved_range().is_empty());
+    let root = sfs.root_inode();
+    assert_eq!(root.metadata()?.inode, BLKN_ROOT);
+    root.find("file")?;
+    root.create("new", FileType::File, 0o644)?;
+    Ok(())
+}
+
+#[test]
+fn prefetch_access_trace() -> Result<()> {
+    use futures::executor::block_on;
+    use rcore_fs::dev::block_cache::BlockCache;
+    const BLOCKS: usize = 1024;
+    let (mem, sfs) = mem_sfs(BLOCKS)?;
+    let root = sfs.root_inode();
+    for d in 0..4 {
+        let dir = root.create(&format!("etc{}", d), FileType::Dir, 0o755)?;
+        for f in 0..10 {
+            let content = format!("{}-{}", d, f).repeat(100 * (f + 1));
+            dir.create(&format!("conf{}", f), FileType::File, 0o644)?
+                .write_at(0, content.as_bytes())?;
+        }
+    }
+    drop(root);
+    sfs.unmount()?;
+    drop(sfs);
+
+    // open with a cold cache, return the count of reads since then
+    let device = MockDevice::over(mem);
+    let boot = || -> Result<Arc<SimpleFileSystem>> {
+        let sfs = SimpleFileSystem::open(Arc::new(BlockCache::new(device.clone(), 512)))?;
+        device.reads.store(0, Ordering::SeqCst);
+        Ok(sfs)
+    };
+    let workload = |sfs: &SimpleFileSystem| -> Result<Vec<Vec<u8>>> {
+        let root = sfs.root_inode();
+        let mut contents = Vec::new();
+        for d in 0..4 {
+            let dir = root.find(&format!("etc{}", d))?;
+            for f in 0..10 {
+                if let Ok(file) = dir.find(&format!("conf{}", f)) {
+                    let mut content = vec![0; file.metadata()?.size];
+                    file.read_at(0, &mut content)?;
+                    contents.push(content);
+                }
+            }
+        }
+        Ok(contents)
+    };
+
+    let sfs = boot()?;
+    sfs.start_access_trace();
+    let expected = workload(&sfs)?;
+    let trace = sfs.stop_access_trace();
+    let cold_reads = device.reads.load(Ordering::SeqCst);
+    // with those cached already, e.g. the root inode
+    assert!(trace.blocks().len() >= cold_reads);
+    drop(sfs);
+    let bytes = trace.to_bytes();
+    assert_eq!(AccessTrace::from_bytes(&bytes)?, trace);
+    assert_eq!(
+        AccessTrace::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(FsError::InvalidParam)
+    );
+    assert_eq!(AccessTrace::from_bytes(b"SFSX"), Err(FsError::InvalidParam));
+
+    // the adjacent blocks are read together, then the workload reads nothing
+    let sfs = boot()?;
+    let trace = AccessTrace::from_bytes(&bytes)?;
+    let prefetched = block_on(sfs.prefetch(&trace, usize::MAX))?;
+    assert_eq!(prefetched, trace.blocks().len());
+    let prefetch_reads = device.reads.swap(0, Ordering::SeqCst);
+    assert!(
+        prefetch_reads * 4 < cold_reads,
+        "{} {}",
+        prefetch_reads,
+        cold_reads
+    );
+    assert_eq!(workload(&sfs)?, expected);
+    assert_eq!(device.reads.load(Ordering::SeqCst), 0);
+
+    // with a budget, the blocks read first
+    let sfs = boot()?;
+    assert_eq!(block_on(sfs.prefetch(&trace, 5))?, 5);
+
+    // a stale trace skips the blocks freed since
+    let dir = sfs.root_inode().find("etc2")?;
+    for f in 0..10 {
+        dir.unlink(&format!("conf{}", f))?;
+    }
+    drop(dir);
+    sfs.unmount()?;
+    drop(sfs);
+    let sfs = boot()?;
+    let prefetched = block_on(sfs.prefetch(&trace, usize::MAX))?;
+    assert!(prefetched < trace.blocks().len());
+    device.reads.store(0, Ordering::SeqCst);
+    let contents = workload(&sfs)?;
+    assert_eq!(contents.len(), 30);
+    assert_eq!(device.reads.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+#[test]
+fn broken_device_or_image_returns_errors() -> Result<()> {
+    let data = Arc::new(Mutex::new(vec![0; 1024 * BLKSIZE]));
+    let device = Arc::new(MemDevice(data.clone()));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * BLKSIZE)?;
+    sfs.root_inode().create("file", FileType::File, 0o644)?;
+    sfs.sync()?;
+
+    // a root corrupted after the mount
+    data.lock().unwrap()[BLKN_ROOT * BLKSIZE..][..BLKSIZE].fill(0xff);
+    let root = sfs.root_inode();
+    assert_eq!(root.metadata().err(), Some(FsError::Corrupted));
+    assert_eq!(root.find("file").err(), Some(FsError::Corrupted));
+    assert_eq!(root.get_entry(0), Err(FsError::Corrupted));
+    assert_eq!(root.read_at(0, &mut [0; 4]), Err(FsError::Corrupted));
+    // more space than the device has, or too little for a fs
+    let small = Arc::new(MemDevice::new(8));
+    assert_eq!(
+        SimpleFileSystem::create(small.clone(), 16 * BLKSIZE).err(),
+        Some(FsError::InvalidParam)
+    );
+    assert_eq!(
+        SimpleFileSystem::create(small, 8 * BLKSIZE).err(),
+        Some(FsError::InvalidParam)
+    );
+    assert!(Arc::ptr_eq(
+        &root.fs()?,
+        &(sfs.clone() as Arc<dyn FileSystem>)
+    ));
+    drop((root, sfs));
+
+    // inodes dropped while the device fails to write are lost, not a panic
+    let device = Arc::new(MockDevice::new(1024));
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * 4096)?;
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    file.write_at(0, &[1; 100])?;
+    let unlinked = root.create("unlinked", FileType::File, 0o644)?;
+    unlinked.write_at(0, &[1; 3 * BLKSIZE])?;
+    root.unlink("unlinked")?;
+    sfs.sync()?;
+    file.resize(200)?;
+    device.set_protected(true);
+    drop((file, unlinked));
+    device.set_protected(false);
+    // as last synced
+    assert_eq!(root.find("file")?.metadata()?.size, 100);
+    Ok(())
+}
+
+#[test]
+fn two_fs_in_windows() -> Result<()> {
+    const WINDOW: usize = 256 * BLKSIZE;
+    let data = Arc::new(Mutex::new(vec![0; 2 * WINDOW]));
+    let device: Arc<dyn Device> = Arc::new(MemDevice(data.clone()));
+    let basic = |sfs: Arc<SimpleFileSystem>, fill: u8| -> Result<()> {
+        let root = sfs.root_inode();
+        for i in 0..20 {
+            let file = root.create(&format!("file{}", i), FileType::File, 0o644)?;
+            file.write_at(0, &vec![fill; (i + 1) * 1000])?;
+        }
+        let dir = root.create("dir", FileType::Dir, 0o755)?;
+        dir.create("empty", FileType::File, 0o644)?;
+        root.create("link", FileType::SymLink, 0o777)?
+            .write_at(0, b"file0")?;
+        rcore_fs::conformance::check_type_errors(&root);
+        root.unlink("file1")?;
+        sfs.unmount()
+    };
+    let a = SimpleFileSystem::create_in_window(device.clone(), 0, WINDOW)?;
+    let b = SimpleFileSystem::create_in_window(device.clone(), WINDOW, WINDOW)?;
+    let (a, b) = run_together(move || basic(a, 0xaa), move || basic(b, 0xbb));
+    a?;
+    b?;
+    let check = |offset: usize, fill: u8| -> Result<()> {
+        let sfs = SimpleFileSystem::open_in_window(device.clone(), offset, WINDOW)?;
+        assert!(!sfs.opened_dirty());
+        let root = sfs.root_inode();
+        assert_eq!(root.find("file1").err(), Some(FsError::EntryNotFound));
+        let mut buf = vec![0; 20_000];
+        assert_eq!(root.find("file19")?.read_at(0, &mut buf)?, 20_000);
+        assert!(buf.iter().all(|&b| b == fill));
+        sfs.unmount()
+    };
+    check(0, 0xaa)?;
+    check(WINDOW, 0xbb)?;
+
+    // a window of another size or at another offset is refused untouched
+    let image = data.lock().unwrap().clone();
+    assert_eq!(
+        SimpleFileSystem::open_in_window(device.clone(), 0, WINDOW - BLKSIZE).err(),
+        Some(FsError::InvalidParam)
+    );
+    assert_eq!(
+        SimpleFileSystem::open_in_window(device.clone(), 0, 2 * WINDOW).err(),
+        Some(FsError::InvalidParam)
+    );
+    assert!(SimpleFileSystem::open_in_window(device.clone(), BLKSIZE, WINDOW).is_err());
+    assert!(*data.lock().unwrap() == image);
+
+    // the neighbor of a broken fs is still clean
+    data.lock().unwrap()[..WINDOW].fill(0xff);
+    assert!(SimpleFileSystem::open_in_window(device.clone(), 0, WINDOW).is_err());
+    check(WINDOW, 0xbb)?;
+
+    // I/O out of the window never reaches the neighbor
+    let window = WindowedDevice::new(device.clone(), 0, WINDOW);
+    assert_eq!(
+        window.write_at(WINDOW - 1, &[0; 2]),
+        Err(DevError::OutOfRange)
+    );
+    assert_eq!(window.write_zeros(WINDOW, 1), Err(DevError::OutOfRange));
+    let mut buf = [0; 2];
+    assert_eq!(window.read_at(WINDOW - 1, &mut buf), Ok(1));
+    assert!(data.lock().unwrap()[WINDOW..] == image[WINDOW..]);
+    Ok(())
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "lock of rank 2 taken while holding rank 3")]
+fn free_map_locked_before_super_block() {
+    // `sync()` writes the superblock with the freemap locked, see issue #18
+    let sfs = _create_new_sfs();
+    let _super_block = sfs.super_block.write();
+    let _free_map = sfs.free_map.write();
+}
+
+#[test]
+fn sync_races_allocation() -> Result<()> {
+    const BLOCKS: usize = 512;
+    for round in 0..3u64 {
+        // the I/O of the threads interleaves, the operations vary by round
+        let (device, sfs) = yielding_sfs(BLOCKS)?;
+        let root = sfs.root_inode();
+        let done = Arc::new(AtomicBool::new(false));
+        let syncer = {
+            let (sfs, done) = (sfs.clone(), done.clone());
+            move || -> Result<()> {
+                while !done.load(Ordering::SeqCst) {
+                    sfs.sync()?;
+                    std::thread::yield_now();
+                }
+                Ok(())
+            }
+        };
+        let mutator = {
+            let (root, done) = (root.clone(), done.clone());
+            move || -> Result<()> {
+                let mut seed = 0x9e37_79b9_7f4a_7c15 ^ round;
+                for _ in 0..60 {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    let name = format!("f{}", seed % 8);
+                    let len = (seed >> 8) as usize % (8 * BLKSIZE);
+                    match root.find(&name) {
+                        Ok(_) if seed & 0x10 == 0 => root.unlink(&name)?,
+                        Ok(file) => file.resize(len)?,
+                        Err(_) => {
+                            let file = root.create(&name, FileType::File, 0o644)?;
+                            file.write_at(0, &vec![1; len])?;
+                        }
+                    }
+                }
+                done.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        };
+        let (synced, mutated) = run_together(syncer, mutator);
+        synced?;
+        mutated?;
+
+        // the freemap and superblock on the device are the ones in memory
+        sfs.sync()?;
+        let image = device.mem.image();
+        let free_map = sfs.free_map.read();
+        let on_disk = &image[BLKN_FREEMAP * BLKSIZE..][..free_map.as_buf().len()];
+        assert!(on_disk == free_map.as_buf(), "round {}", round);
+        let super_block = sfs.super_block.read();
+        let disk = super_block.to_disk();
+        assert!(image[BLKN_SUPER * BLKSIZE..][..disk.len()] == disk[..]);
+        assert_eq!(
+            super_block.unused_blocks,
+            sfs.unused_blocks.load(Ordering::Relaxed)
+        );
+        drop((free_map, super_block));
+        // and agree with each other
+        let copy = SimpleFileSystem::open(Arc::new(MemDevice::with_image(image)))?;
+        copy.quick_scan()?;
+        assert_eq!(copy.info().bfree, sfs.info().bfree);
+    }
+    Ok(())
+}
+
+/// Names equal once their trailing dots are dropped, as in Windows
+struct TrailingDots;
+
+impl NameOps for TrailingDots {
+    fn normalize<'a>(&self, name: &'a str) -> std::borrow::Cow<'a, str> {
+        std::borrow::Cow::Borrowed(name.trim_end_matches('.'))
+    }
+}
+
+fn trailing_dots() -> NamePolicy {
+    NamePolicy {
+        id: NAME_POLICY_CUSTOM,
+        ops: Arc::new(TrailingDots),
+    }
+}
+
+#[test]
+fn custom_name_policy() -> Result<()> {
+    const BLOCKS: usize = 1024;
+    let device = MemDevice::new(BLOCKS);
+    let opts = CreateOptions {
+        names: Some(trailing_dots()),
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(
+        Arc::new(device.clone()),
+        BLOCKS * BLKSIZE,
+        [1; 16],
+        opts,
+    )?;
+    let root = sfs.root_inode();
+    let file = root.create("foo.", FileType::File, 0o644)?;
+    let id = file.metadata()?.inode;
+    assert_eq!(root.find("foo")?.metadata()?.inode, id);
+    assert_eq!(root.find("foo...")?.metadata()?.inode, id);
+    assert_eq!(root.get_entry(2)?, "foo.");
+    for name in ["foo", "foo.."] {
+        let created = root.create(name, FileType::File, 0o644);
+        assert_eq!(created.err(), Some(FsError::EntryExist));
+        assert_eq!(root.link(name, &file).err(), Some(FsError::EntryExist));
+    }
+    let spec = |name| CreateSpec {
+        name,
+        type_: FileType::File,
+        mode: 0o644,
+        data: 0,
+    };
+    let batch = root.create_batch(&[spec("bar"), spec("bar.")]);
+    assert_eq!(batch.err(), Some(FsError::EntryExist));
+
+    // renamed to another spelling of its name, or replacing it
+    root.move_("foo", &root, "foo..")?;
+    assert_eq!(root.get_entry(2)?, "foo..");
+    let bar = root.create("bar", FileType::File, 0o644)?;
+    root.move_("bar.", &root, "foo")?;
+    assert_eq!(root.find("foo.")?.metadata()?.inode, bar.metadata()?.inode);
+    let mut txn = sfs.transaction();
+    txn.move_(&root, "foo", &root, "foo.")?;
+    txn.commit()?;
+    assert_eq!(root.get_entry(2)?, "foo.");
+    assert_eq!(root.find("foo")?.metadata()?.inode, bar.metadata()?.inode);
+
+    // through the hashed index of a large dir too
+    let dir = root.create("dir", FileType::Dir, 0o777)?;
+    link_many(&root, &dir, INDEX_THRESHOLD + 100)?;
+    assert!(dir.downcast_ref::<INodeImpl>().unwrap().has_index());
+    for i in (0..INDEX_THRESHOLD + 100).step_by(7) {
+        dir.find(&format!("link{}.", i))?;
+    }
+    dir.move_("link0.", &dir, "link0..")?;
+    dir.unlink("link1.")?;
+    assert_eq!(dir.find("link1").err(), Some(FsError::EntryNotFound));
+    assert_eq!(sfs.check_dir_indexes()?, 0);
+    sfs.unmount()?;
+    drop((root, file, bar, dir, sfs));
+
+    // the image only opens with its policy
+    let open = |names| {
+        let opts = crate::OpenOptions {
+            names,
+            ..crate::OpenOptions::default()
+        };
+        SimpleFileSystem::open_with_options(Arc::new(device.clone()), opts)
+    };
+    assert_eq!(open(None).err(), Some(FsError::WrongFs));
+    let other = NamePolicy {
+        id: NAME_POLICY_CUSTOM + 1,
+        ..trailing_dots()
+    };
+    assert_eq!(open(Some(other)).err(), Some(FsError::WrongFs));
+    let sfs = open(Some(trailing_dots()))?;
+    sfs.root_inode().find("dir")?.find("link0")?;
+    sfs.quick_scan()?;
+
+    // ids of the built-in policies are reserved
+    let opts = CreateOptions {
+        names: Some(NamePolicy {
+            id: NAME_POLICY_CUSTOM - 1,
+            ..trailing_dots()
+        }),
+        ..CreateOptions::default()
+    };
+    let mem = MemDevice::new(BLOCKS);
+    let result =
+        SimpleFileSystem::create_with_options(Arc::new(mem), BLOCKS * BLKSIZE, [2; 16], opts);
+    assert_eq!(result.err(), Some(FsError::InvalidParam));
+    Ok(())
+}
+
+#[test]
+fn ascii_case_fold_policy() -> Result<()> {
+    const BLOCKS: usize = 64;
+    let device = MemDevice::new(BLOCKS);
+    let opts = CreateOptions {
+        names: Some(NamePolicy::ascii_case_fold()),
+        ..CreateOptions::default()
+    };
+    let sfs = SimpleFileSystem::create_with_options(
+        Arc::new(device.clone()),
+        BLOCKS * BLKSIZE,
+        [1; 16],
+        opts,
+    )?;
+    assert!(sfs
+        .capabilities()
+        .contains(FsCapabilities::CASE_INSENSITIVE));
+    let root = sfs.root_inode();
+    let id = root
+        .create("Readme", FileType::File, 0o644)?
+        .metadata()?
+        .inode;
+    assert_eq!(root.find("README")?.metadata()?.inode, id);
+    assert_eq!(
+        root.create("readme", FileType::File, 0o644).err(),
+        Some(FsError::EntryExist)
+    );
+    // only ASCII letters are folded
+    root.create("É", FileType::File, 0o644)?;
+    assert_eq!(root.find("é").err(), Some(FsError::EntryNotFound));
+    sfs.unmount()?;
+    drop((root, sfs));
+
+    // a built-in policy needs no option to open
+    let sfs = SimpleFileSystem::open(Arc::new(device))?;
+    assert_eq!(sfs.root_inode().find("readme")?.metadata()?.inode, id);
+    Ok(())
+}
+
+#[test]
+fn exact_name_policy_is_the_default() -> Result<()> {
+    const BLOCKS: usize = 1024;
+    let image = |names| -> Result<Vec<u8>> {
+        let device = MemDevice::new(BLOCKS);
+        let opts = CreateOptions {
+            names,
+            ..CreateOptions::default()
+        };
+        let sfs = SimpleFileSystem::create_with_options(
+            Arc::new(device.clone()),
+            BLOCKS * BLKSIZE,
+            [1; 16],
+            opts,
+        )?;
+        assert!(!sfs
+            .capabilities()
+            .contains(FsCapabilities::CASE_INSENSITIVE));
+        let root = sfs.root_inode();
+        let dir = root.create("dir", FileType::Dir, 0o777)?;
+        link_many(&root, &dir, INDEX_THRESHOLD + 100)?;
+        rcore_fs::conformance::check_type_errors(&root);
+        root.create("foo", FileType::File, 0o644)?;
+        root.create("foo.", FileType::File, 0o644)?;
+        root.create("FOO", FileType::File, 0o644)?;
+        root.move_("foo.", &root, "foo")?;
+        assert_eq!(root.find("foo.").err(), Some(FsError::EntryNotFound));
+        dir.move_("link0", &dir, "LINK0")?;
+        dir.unlink("link1")?;
+        assert_eq!(dir.find("link0").err(), Some(FsError::EntryNotFound));
+        assert_eq!(sfs.check_dir_indexes()?, 0);
+        sfs.unmount()?;
+        drop((root, dir, sfs));
+        let mut image = device.image();
+        // the padding after `nsec` of the times of inodes is left as is, as
+        // is that before `rdev`
+        for block in image.chunks_mut(BLKSIZE) {
+            let times = [
+                std::mem::offset_of!(DiskINode, atime),
+                std::mem::offset_of!(DiskINode, mtime),
+                std::mem::offset_of!(DiskINode, ctime),
+                std::mem::offset_of!(DiskINode, btime),
+            ];
+            for time in times {
+                block[time + 12..time + 16].fill(0);
+            }
+            let rdev = std::mem::offset_of!(DiskINode, rdev);
+            block[std::mem::offset_of!(DiskINode, db_indirect) + 4..rdev].fill(0);
+        }
+        Ok(image)
+    };
+    assert!(image(None)? == image(Some(NamePolicy::exact()))?);
+    Ok(())
+}
+
+#[test]
+fn hung_device_times_out() -> Result<()> {
+    const BLOCKS: usize = 256;
+    let hanging = Arc::new(HangingDevice {
+        mem: MemDevice::new(BLOCKS),
+        requests: AtomicUsize::new(0),
+        hang: AtomicUsize::new(0),
+    });
+    let timer = Arc::new(VirtualTimer::default());
+    let config = TimedConfig {
+        deadline: std::time::Duration::from_millis(100),
+        poison_after: Some(3),
+    };
+    let device = Arc::new(TimedDevice::new(hanging.clone(), timer.clone(), config));
+    let sfs = SimpleFileSystem::create(device.clone(), BLOCKS * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let a = root.create("a", FileType::File, 0o644)?;
+    let b = root.create("b", FileType::File, 0o644)?;
+    a.write_at(0, &[1; BLKSIZE])?;
+    b.write_at(0, &[2; BLKSIZE])?;
+    sfs.sync()?;
+
+    // a hung read fails at the deadline
+    let mut buf = [0; BLKSIZE];
+    hanging.hang.store(1, Ordering::SeqCst);
+    let err = a.read_at(0, &mut buf).unwrap_err();
+    assert_eq!(err.root_cause(), &FsError::DeviceError);
+    assert_eq!(timer.now.load(Ordering::SeqCst), 100);
+    assert_eq!(device.timeouts(), 1);
+
+    // no lock is left behind, on other inodes or this one
+    assert_eq!(b.read_at(0, &mut buf)?, BLKSIZE);
+    assert_eq!(buf, [2; BLKSIZE]);
+    assert_eq!(a.read_at(0, &mut buf)?, BLKSIZE);
+    assert_eq!(buf, [1; BLKSIZE]);
+    a.write_at(BLKSIZE, &[3; BLKSIZE])?;
+    root.create("c", FileType::File, 0o644)?;
+    sfs.sync()?;
+
+    // timeouts in a row poison the device, then nothing waits for it
+    hanging.hang.store(usize::MAX, Ordering::SeqCst);
+    for _ in 0..3 {
+        assert!(b.read_at(0, &mut buf).is_err());
+    }
+    assert!(device.is_poisoned());
+    hanging.hang.store(0, Ordering::SeqCst);
+    let (requests, now) = (
+        hanging.requests.load(Ordering::SeqCst),
+        timer.now.load(Ordering::SeqCst),
+    );
+    assert!(b.read_at(0, &mut buf).is_err());
+    assert!(root.find("c").is_err());
+    assert_eq!(hanging.requests.load(Ordering::SeqCst), requests);
+    assert_eq!(timer.now.load(Ordering::SeqCst), now);
+
+    device.clear_poison();
+    assert_eq!(b.read_at(0, &mut buf)?, BLKSIZE);
+    assert_eq!(buf, [2; BLKSIZE]);
+    sfs.quick_scan()?;
+    Ok(())
+}
+
+#[test]
+fn names_not_utf8() -> Result<()> {
+    const BLOCKS: usize = 256;
+    const RAW: &[u8] = b"bad\xff\xfe-\xe2\x82";
+    let (device, sfs) = mem_sfs(BLOCKS)?;
+    let root = sfs.root_inode();
+    let file = root.create("raw", FileType::File, 0o644)?;
+    file.write_at(0, b"data")?;
+    root.create("keep", FileType::File, 0o644)?;
+    // as written by another tool
+    let dir = root.downcast_ref::<INodeImpl>().unwrap();
+    let (id, entry_id) = dir.get_file_inode_and_entry_id("raw")?.unwrap();
+    let entry = DiskEntry::new(id as u32, Str256::from_bytes(RAW)?, structs::FileType::File);
+    dir.write_direntry(entry_id, &entry)?;
+    sfs.sync()?;
+    drop((file, root, sfs));
+
+    let sfs = SimpleFileSystem::open(Arc::new(device))?;
+    let root = sfs.root_inode();
+    let escaped = "bad%FF%FE-%E2%82";
+    assert_eq!(escape_name(RAW), escaped);
+    assert!(root.list()?.iter().any(|name| name == escaped));
+    let index = (0..)
+        .find(|&i| root.get_entry(i).unwrap() == escaped)
+        .unwrap();
+    assert_eq!(root.get_entry_bytes(index)?, RAW);
+    assert_eq!(root.get_entry_bytes(0)?, b".");
+    let mut slots = vec![DirEntrySlot::default(); 8];
+    let filled = root.next_entries(&mut DirCursor::default(), &mut slots)?;
+    let slot = slots[..filled].iter().find(|s| s.name == escaped).unwrap();
+    assert_eq!(slot.name_bytes(), RAW);
+    assert_eq!(slot.inode, id);
+    let keep = slots[..filled].iter().find(|s| s.name == "keep").unwrap();
+    assert_eq!(keep.raw_name, None);
+
+    // the escaped name is not the entry, its bytes are
+    assert_eq!(root.find(escaped).err(), Some(FsError::EntryNotFound));
+    let file = root.find_bytes(RAW)?;
+    let mut buf = [0; 4];
+    assert_eq!(file.read_at(0, &mut buf)?, 4);
+    assert_eq!(&buf, b"data");
+    assert_eq!(root.find_bytes(b"keep")?.metadata()?.type_, FileType::File);
+    assert_eq!(
+        root.find_bytes(b"bad\xff").err(),
+        Some(FsError::EntryNotFound)
+    );
+
+    root.unlink_bytes(RAW)?;
+    assert_eq!(root.find_bytes(RAW).err(), Some(FsError::EntryNotFound));
+    assert!(!root.list()?.iter().any(|name| name == escaped));
+    assert_eq!(file.metadata()?.nlinks, 0);
+    root.unlink_bytes(b"keep")?;
+    drop(file);
+    sfs.quick_scan()?;
+    Ok(())
+}
+
+#[test]
+fn recovery_reserve_on_full_fs() -> Result<()> {
+    const BLOCKS: usize = 128;
+    let device = Arc::new(MemDevice::new(BLOCKS));
+    let sfs = SimpleFileSystem::create(device.clone(), BLOCKS * BLKSIZE)?;
+    assert_eq!(sfs.recovery_blocks(), DEFAULT_RECOVERY_BLOCKS);
+    let reserve = |sfs: &SimpleFileSystem| {
+        let info = sfs.info();
+        assert_eq!(info.bfree - info.bavail, sfs.recovery_blocks());
+        info.bfree
+    };
+    let free = reserve(&sfs);
+    let root = sfs.root_inode();
+    let big = root.create("big", FileType::File, 0o644)?;
+    big.write_at(0, &[1; 16 * BLKSIZE])?;
+    reserve(&sfs);
+    // fill the rest of the fs, one block per file
+    let mut files = 0;
+    loop {
+        let file = match root.create(&files.to_string(), FileType::File, 0o644) {
+            Ok(file) => file,
+            Err(FsError::NoDeviceSpace) => break,
+            Err(err) => return Err(err),
+        };
+        files += 1;
+        match file.write_at(0, &[2; BLKSIZE]) {
+            Ok(_) => {}
+            Err(FsError::NoDeviceSpace) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    assert_eq!(sfs.info().bavail, 0);
+    assert_eq!(reserve(&sfs), DEFAULT_RECOVERY_BLOCKS);
+    let file = root.find("0")?;
+    assert_eq!(
+        file.write_at(BLKSIZE, &[3; 4 * BLKSIZE]).err(),
+        Some(FsError::NoDeviceSpace)
+    );
+    assert_eq!(reserve(&sfs), DEFAULT_RECOVERY_BLOCKS);
+    drop(file);
+
+    // a transaction creating an entry may not take the reserve, one only
+    // unlinking may
+    let mut txn = sfs.transaction();
+    txn.create2(&root, "new", FileType::File, 0o644, 0)?;
+    txn.unlink(&root, "1")?;
+    assert_eq!(txn.commit().err(), Some(FsError::NoDeviceSpace));
+    assert!(root.find("1").is_ok());
+    assert_eq!(reserve(&sfs), DEFAULT_RECOVERY_BLOCKS);
+    let mut txn = sfs.transaction();
+    txn.unlink(&root, "1")?;
+    txn.unlink(&root, "2")?;
+    txn.commit()?;
+    assert_eq!(root.find("1").err(), Some(FsError::EntryNotFound));
+    // the blocks of both files are back, the shadow dir is freed
+    assert!(sfs.info().bavail > 0);
+    reserve(&sfs);
+
+    drop(big);
+    root.unlink("big")?;
+    assert!(sfs.info().bavail >= 16);
+    reserve(&sfs);
+    let file = root.create("after", FileType::File, 0o644)?;
+    file.write_at(0, &[4; 8 * BLKSIZE])?;
+    drop(file);
+    for i in 3..files {
+        root.unlink(&i.to_string())?;
+    }
+    root.unlink("0")?;
+    root.unlink("after")?;
+    assert_eq!(reserve(&sfs), free);
+    sfs.sync()?;
+    drop((root, sfs));
+
+    let sfs = SimpleFileSystem::open(device)?;
+    assert_eq!(sfs.recovery_blocks(), DEFAULT_RECOVERY_BLOCKS);
+    assert_eq!(reserve(&sfs), free);
+    sfs.quick_scan()?;
+    Ok(())
+}
+
+#[test]
+fn io_contract() -> Result<()> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let data: Vec<u8> = (0..3 * BLKSIZE + 100).map(|i| i as u8).collect();
+    root.create("file", FileType::File, 0o644)?
+        .write_at(0, &data)?;
+    root.create("small", FileType::File, 0o644)?
+        .write_at(0, b"data")?;
+    root.create("dir", FileType::Dir, 0o755)?
+        .create("empty", FileType::File, 0o644)?;
+    root.create("link", FileType::SymLink, 0o777)?
+        .write_at(0, b"file")?;
+    rcore_fs::conformance::check_io(&root);
+    assert_eq!(root.list()?, [".", "..", "file", "small", "dir", "link"]);
+    let file = root.find("file")?;
+    assert_eq!(file.metadata()?.size, data.len());
+    let mut buf = vec![0; data.len()];
+    assert_eq!(file.read_at(0, &mut buf)?, data.len());
+    assert_eq!(buf, data);
+    sfs.quick_scan()?;
+    Ok(())
+}
+
+#[test]
+fn pinned_inode() -> Result<()> {
+    let (sfs, root, file) = sfs_with_file("swap")?;
+    file.write_at(0, &[1; 4 * BLKSIZE])?;
+    for i in 0..8 {
+        root.create(&i.to_string(), FileType::File, 0o644)?;
+    }
+    let id = file.metadata()?.inode;
+    let blocks = file_blocks(&file)?;
+    let cached = |sfs: &SimpleFileSystem| sfs.inode_cache.read().inodes.contains_key(&id);
+
+    sfs.set_inode_cache_size(4);
+    let pin = file.pin()?;
+    let pin2 = file.pin()?;
+    drop(file);
+    assert_eq!(sfs.pinned_inodes(), [id]);
+    let mut dump = String::new();
+    sfs.dump_tree(&mut dump, DumpOpts::default())?;
+    assert!(dump.contains(&format!(
+        "swap (inode {}, File, size 16384, nlinks 1, blocks 4) pinned 2",
+        id
+    )));
+
+    // never evicted from the strong cache
+    assert!(cached(&sfs));
+    for i in 0..8 {
+        root.find(&i.to_string())?;
+        assert!(cached(&sfs));
+    }
+    assert_eq!(sfs.stats().inode_cache_size, 4);
+    sfs.set_inode_cache_size(0);
+    assert!(cached(&sfs));
+    assert_eq!(sfs.stats().inode_cache_size, 1);
+
+    // only its last link is kept
+    root.link("link", &root.find("swap")?)?;
+    root.unlink("swap")?;
+    assert_eq!(root.unlink("link"), Err(FsError::Busy));
+    assert_eq!(root.move_("0", &root, "link"), Err(FsError::Busy));
+    let mut txn = sfs.transaction();
+    txn.unlink(&root, "link")?;
+    assert_eq!(txn.commit().err(), Some(FsError::Busy));
+    let file = root.find("link")?;
+    assert_eq!(file_blocks(&file)?, blocks);
+    drop(file);
+
+    drop(pin);
+    assert_eq!(sfs.pinned_inodes(), [id]);
+    assert_eq!(root.unlink("link"), Err(FsError::Busy));
+    drop(pin2);
+    assert!(sfs.pinned_inodes().is_empty());
+    assert!(!cached(&sfs));
+    root.unlink("link")?;
+    assert_eq!(sfs.stats().inode_cache_size, 0);
+    assert_eq!(root.find("link").err(), Some(FsError::EntryNotFound));
+    sfs.quick_scan()?;
+    Ok(())
+}
+
+#[test]
+fn backup_stream_while_writing() -> Result<()> {
+    use futures::executor::block_on;
+    use std::time::{Duration, Instant};
+
+    const BLOCKS: usize = 1024;
+    const FILES: usize = 8;
+    let (_, sfs) = mem_sfs(BLOCKS)?;
+    let root = sfs.root_inode();
+    for i in 0..FILES {
+        let file = root.create(&format!("f{}", i), FileType::File, 0o644)?;
+        file.write_at(0, &[i as u8; 16 * BLKSIZE])?;
+    }
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    dir.create("a", FileType::File, 0o644)?
+        .write_at(0, b"before")?;
+
+    // overwrite, create and unlink from the consistency point on, noting
+    // the longest a write takes
+    let begun = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let (root, dir) = (root.clone(), dir.clone());
+        let (begun, done) = (begun.clone(), done.clone());
+        std::thread::spawn(move || -> Result<Duration> {
+            while !begun.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+            let mut stall = Duration::ZERO;
+            let mut timed = |f: &mut dyn FnMut() -> Result<()>| -> Result<()> {
+                let start = Instant::now();
+                f()?;
+                stall = stall.max(start.elapsed());
+                Ok(())
+            };
+            for i in (0..FILES).rev() {
+                timed(&mut || {
+                    root.find(&format!("f{}", i))?
+                        .write_at(0, &[0xee; 16 * BLKSIZE])?;
+                    Ok(())
+                })?;
+            }
+            timed(&mut || dir.unlink("a"))?;
+            let mut n = 0;
+            while !done.load(Ordering::SeqCst) {
+                timed(&mut || {
+                    let file = root.create(&format!("new{}", n), FileType::File, 0o644)?;
+                    file.write_at(0, &[n as u8; BLKSIZE])?;
+                    Ok(())
+                })?;
+                n += 1;
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            Ok(stall)
+        })
+    };
+
+    let mut sink = ImageSink {
+        image: Vec::new(),
+        delay: Duration::from_millis(2),
+        begun,
+        begun_at: None,
+        last_id: None,
+    };
+    let start = Instant::now();
+    let summary = block_on(sfs.backup_stream(&mut sink))?;
+    let quiesce = sink.begun_at.unwrap() - start;
+    let total = start.elapsed();
+    done.store(true, Ordering::SeqCst);
+    let stall = writer.join().unwrap()?;
+    assert!(summary.streamed > FILES * 16);
+    assert!(summary.copied_up > 0);
+    let freemap_blocks = sfs.super_block.read().freemap_blocks as usize;
+    assert_eq!(summary.blocks, 1 + freemap_blocks + summary.streamed);
+    // writers only wait for the copy of a few blocks, not for the backup
+    assert!(stall < total / 4, "stall {:?} of {:?}", stall, total);
+    assert!(quiesce < total / 4, "quiesce {:?} of {:?}", quiesce, total);
+
+    // the live fs has the new writes
+    let mut buf = vec![0; 16 * BLKSIZE];
+    root.find("f0")?.read_at(0, &mut buf)?;
+    assert_eq!(buf, [0xee; 16 * BLKSIZE]);
+    root.find("new0")?;
+    assert_eq!(dir.find("a").err(), Some(FsError::EntryNotFound));
+
+    // the image has the state before
+    let image = MemDevice(Arc::new(Mutex::new(std::mem::take(&mut sink.image))));
+    let backup = SimpleFileSystem::open(Arc::new(image))?;
+    assert!(!backup.opened_dirty());
+    backup.quick_scan()?;
+    let root = backup.root_inode();
+    for i in 0..FILES {
+        root.find(&format!("f{}", i))?.read_at(0, &mut buf)?;
+        assert_eq!(buf, [i as u8; 16 * BLKSIZE]);
+    }
+    let mut a = [0u8; 6];
+    root.find("dir")?.find("a")?.read_at(0, &mut a)?;
+    assert_eq!(&a, b"before");
+    assert_eq!(root.find("new0").err(), Some(FsError::EntryNotFound));
+
+    // the next backup is of the fs now
+    sink.last_id = None;
+    sink.delay = Duration::ZERO;
+    assert_eq!(block_on(sfs.backup_stream(&mut sink))?.copied_up, 0);
+    let image = MemDevice::with_image(sink.image);
+    let backup = SimpleFileSystem::open(Arc::new(image))?;
+    backup.root_inode().find("new0")?;
+    Ok(())
+}
+
+#[test]
+fn sfs_options_round_trip() -> Result<()> {
+    const BLOCKS: usize = 64;
+    let now = Timespec { sec: 1234, nsec: 5 };
+    let opts = SfsOptions::new()
+        .block_size_log2(BLKSIZE_LOG2)
+        .case_insensitive(true)
+        .names(NamePolicy::ascii_case_fold())
+        .time_provider(TestClock::new(now.sec, now.nsec))
+        .on_dirty(DirtyPolicy::QuickScan)
+        .uuid([7; 16])
+        .reserved_blocks(2)
+        .root_block(BLKN_FREEMAP + 1 + 2)
+        .reserve_for_recovery(3)
+        .inode_cache_size(5)
+        .scratch_pool_size(1)
+        .dir_readahead(6)
+        .io_burst(7)
+        .alloc_groups(false)
+        .silly_rename(true)
+        .build()?;
+    assert_eq!(opts.block_size_log2, BLKSIZE_LOG2);
+    assert!(!opts.read_only && !opts.deterministic);
+    assert_eq!(opts.case_insensitive, Some(true));
+    assert_eq!(opts.names.as_ref().unwrap().id, NAME_POLICY_ASCII_CASE_FOLD);
+    assert_eq!(opts.on_dirty, DirtyPolicy::QuickScan);
+    assert_eq!(opts.uuid, Some([7; 16]));
+    assert_eq!(opts.reserved_blocks, 2);
+    assert_eq!(opts.root_block, Some(BLKN_FREEMAP + 3));
+    assert_eq!(opts.reserve_for_recovery, 3);
+    assert_eq!(opts.inode_cache_size, 5);
+    assert_eq!(opts.scratch_pool_size, 1);
+    assert_eq!(opts.dir_readahead, 6);
+    assert_eq!(opts.io_burst, 7);
+    assert!(!opts.alloc_groups && opts.silly_rename);
+    let flags = SfsOptions::new().read_only(true).deterministic(true);
+    assert!(flags.read_only && flags.deterministic);
+
+    // the fs made with them reports them back
+    let device = MemDevice::new(BLOCKS);
+    let sfs = SimpleFileSystem::create_with(Arc::new(device.clone()), BLOCKS * BLKSIZE, &opts)?;
+    assert_eq!(format!("{:?}", sfs.options()), format!("{:?}", opts));
+    let file = sfs.root_inode().create("File", FileType::File, 0o644)?;
+    let metadata = file.metadata()?;
+    assert_eq!(
+        (metadata.atime, metadata.mtime, metadata.ctime),
+        (now, now, now)
+    );
+    sfs.root_inode().find("FILE")?;
+    sfs.set_io_burst(9);
+    assert_eq!(sfs.options().io_burst, 9);
+    drop(file);
+    sfs.unmount()?;
+    drop(sfs);
+
+    // and the image the ones recorded in it
+    let dev: Arc<dyn Device> = Arc::new(device.clone());
+    let super_block = dev.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    let persisted = SfsOptions::from_superblock(&super_block);
+    assert_eq!(persisted.case_insensitive, Some(true));
+    assert_eq!(persisted.uuid, Some([7; 16]));
+    assert_eq!(persisted.reserved_blocks, 2);
+    assert_eq!(persisted.root_block, Some(BLKN_FREEMAP + 3));
+    assert_eq!(persisted.reserve_for_recovery, 3);
+    let sfs = SimpleFileSystem::open_with(Arc::new(device), &persisted)?;
+    let opts = sfs.options();
+    assert_eq!(opts.uuid, Some([7; 16]));
+    assert_eq!(opts.root_block, Some(BLKN_FREEMAP + 3));
+    assert_eq!(opts.io_burst, DEFAULT_IO_BURST);
+    assert!(opts.time_provider.is_none());
+    Ok(())
+}
+
+#[test]
+fn sfs_options_invalid() -> Result<()> {
+    const BLOCKS: usize = 64;
+    let invalid = [
+        (SfsOptions::new().block_size_log2(13), FsError::Unsupported),
+        (
+            SfsOptions::new()
+                .deterministic(true)
+                .time_provider(TestClock::new(1, 0)),
+            FsError::InvalidParam,
+        ),
+        (
+            SfsOptions::new()
+                .case_insensitive(false)
+                .names(NamePolicy::ascii_case_fold()),
+            FsError::InvalidParam,
+        ),
+    ];
+    let device = MemDevice::new(BLOCKS);
+    SimpleFileSystem::create(Arc::new(device.clone()), BLOCKS * BLKSIZE)?.unmount()?;
+    for (opts, err) in invalid.iter() {
+        assert_eq!(opts.clone().build().err().as_ref(), Some(err));
+        let open = SimpleFileSystem::open_with(Arc::new(device.clone()), opts);
+        assert_eq!(open.err().as_ref(), Some(err));
+        let mem = MemDevice::new(BLOCKS);
+        let create = SimpleFileSystem::create_with(Arc::new(mem), BLOCKS * BLKSIZE, opts);
+        assert_eq!(create.err().as_ref(), Some(err));
+    }
+
+    // coherent, but not to create
+    let opts = SfsOptions::new().read_only(true).build()?;
+    let mem = MemDevice::new(BLOCKS);
+    let create = SimpleFileSystem::create_with(Arc::new(mem), BLOCKS * BLKSIZE, &opts);
+    assert_eq!(create.err(), Some(FsError::InvalidParam));
+    Ok(())
+}
+
+#[test]
+fn sfs_options_persisted_conflict() -> Result<()> {
+    const BLOCKS: usize = 64;
+    let device = MemDevice::new(BLOCKS);
+    let opts = SfsOptions::new().case_insensitive(true).build()?;
+    let sfs = SimpleFileSystem::create_with(Arc::new(device.clone()), BLOCKS * BLKSIZE, &opts)?;
+    assert_eq!(sfs.options().names.unwrap().id, NAME_POLICY_ASCII_CASE_FOLD);
+    sfs.root_inode().create("a", FileType::File, 0o644)?;
+    sfs.unmount()?;
+    drop(sfs);
+    let image = device.image();
+
+    let open = |opts: SfsOptions| SimpleFileSystem::open_with(Arc::new(device.clone()), &opts);
+    let conflict = open(SfsOptions::new().case_insensitive(false));
+    assert_eq!(conflict.err(), Some(FsError::InvalidParam));
+    assert_eq!(*device.0.lock().unwrap(), image);
+
+    // read-only leaves the image untouched
+    let sfs = open(SfsOptions::new().case_insensitive(true).read_only(true))?;
+    assert!(sfs.is_read_only() && sfs.options().read_only);
+    assert_eq!(
+        sfs.root_inode().create("b", FileType::File, 0o644).err(),
+        Some(FsError::ReadOnly)
+    );
+    sfs.root_inode().find("A")?;
+    drop(sfs);
+    assert_eq!(*device.0.lock().unwrap(), image);
+    // what the image has if not asked
+    let sfs = open(SfsOptions::new())?;
+    assert_eq!(sfs.options().case_insensitive, Some(true));
+    Ok(())
+}
+
+#[test]
+fn sfs_options_defaults() -> Result<()> {
+    const BLOCKS: usize = 256;
+    let new_device = || MemDevice::new(BLOCKS);
+    let old = SimpleFileSystem::create(Arc::new(new_device()), BLOCKS * BLKSIZE)?;
+    let opts = SfsOptions::new().build()?;
+    let sfs = SimpleFileSystem::create_with(Arc::new(new_device()), BLOCKS * BLKSIZE, &opts)?;
+    let without_uuid = |opts: SfsOptions| format!("{:?}", SfsOptions { uuid: None, ..opts });
+    assert_eq!(without_uuid(sfs.options()), without_uuid(old.options()));
+    let defaults = SfsOptions::from_superblock(&sfs.super_block.read());
+    assert_eq!(defaults.reserved_blocks, 0);
+    assert_eq!(defaults.root_block, None);
+    assert_eq!(defaults.reserve_for_recovery, DEFAULT_RECOVERY_BLOCKS);
+    let root = sfs.root_inode();
+    root.create("dir", FileType::Dir, 0o755)?
+        .create("file", FileType::File, 0o644)?
+        .write_at(0, b"data")?;
+    assert_eq!(
+        root.find("dir")?.metadata()?.mtime,
+        Timespec { sec: 0, nsec: 0 }
+    );
+    rcore_fs::conformance::check_type_errors(&root);
+    rcore_fs::conformance::check_io(&root);
+    sfs.quick_scan()?;
+
+    // deterministic images have the same UUID and times every time
+    let make = || -> Result<([u8; 16], Metadata)> {
+        let opts = SfsOptions::new().deterministic(true);
+        let sfs = SimpleFileSystem::create_with(Arc::new(new_device()), BLOCKS * BLKSIZE, &opts)?;
+        let file = sfs.root_inode().create("file", FileType::File, 0o644)?;
+        Ok((sfs.uuid(), file.metadata()?))
+    };
+    let (a, b) = (make()?, make()?);
+    assert_eq!(a.0, b.0);
+    assert_eq!(
+        (a.1.atime, a.1.mtime, a.1.ctime),
+        (b.1.atime, b.1.mtime, b.1.ctime)
+    );
+    Ok(())
+}
+
+#[test]
+fn max_inflight_io() -> Result<()> {
+    use futures::executor::block_on;
+    const BLOCKS: usize = 2048;
+    const THREADS: usize = 3;
+    let device = Arc::new(InFlight {
+        mem: Some(MemDevice::new(BLOCKS)),
+        ..InFlight::default()
+    });
+    let opts = SfsOptions::new().max_inflight_io(0);
+    assert_eq!(opts.build().err(), Some(FsError::InvalidParam));
+    let opts = SfsOptions::new().max_inflight_io(4);
+    let sfs = SimpleFileSystem::create_with(device.clone(), BLOCKS * BLKSIZE, &opts)?;
+    assert_eq!(sfs.options().max_inflight_io, 4);
+    let root = sfs.root_inode();
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    create_files(&dir, 32, false)?;
+    let big = root.create("big", FileType::File, 0o644)?;
+    big.write_at(0, &vec![7; 8 * BLKSIZE])?;
+    let list = |dir: &Arc<dyn INode>| -> Result<()> {
+        (0..34).try_for_each(|id| dir.get_entry_with_metadata(id).map(drop))
+    };
+    sfs.start_access_trace();
+    list(&dir)?;
+    let trace = sfs.stop_access_trace();
+
+    // listings loading ahead, prefetches and bulk writes from many threads
+    let workload = |sfs: &Arc<SimpleFileSystem>| {
+        let threads: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let sfs = sfs.clone();
+                let trace = trace.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    let root = sfs.root_inode();
+                    let dir = root.find("dir")?;
+                    let file = root.create(&format!("t{}", t), FileType::File, 0o644)?;
+                    for i in 0..2 {
+                        list(&dir)?;
+                        block_on(sfs.prefetch(&trace, usize::MAX))?;
+                        file.write_at(i * 3 * BLKSIZE, &vec![t as u8; 3 * BLKSIZE])?;
+                        file.sync_all()?;
+                    }
+                    root.unlink(&format!("t{}", t))
+                })
+            })
+            .collect();
+        threads
+            .into_iter()
+            .try_for_each(|thread| thread.join().unwrap())
+    };
+
+    // one at a time
+    sfs.set_max_inflight_io(1);
+    rcore_fs::conformance::check_type_errors(&root);
+    rcore_fs::conformance::check_io(&root);
+    device.peak.store(0, Ordering::SeqCst);
+    workload(&sfs)?;
+    assert_eq!(device.peak.load(Ordering::SeqCst), 1);
+
+    sfs.set_max_inflight_io(4);
+    workload(&sfs)?;
+    let stats = sfs.stats();
+    assert_eq!(stats.io_in_flight, 0);
+    assert!(stats.io_peak <= 4, "{:?}", stats);
+    assert!(device.peak.load(Ordering::SeqCst) <= 4);
+
+    // prefetching is skipped while the device is busy, not waited for
+    sfs.set_max_inflight_io(1);
+    let shed = sfs.stats().io_shed;
+    device.stall.store(true, Ordering::SeqCst);
+    let busy = {
+        let big = big.clone();
+        std::thread::spawn(move || big.read_at(0, &mut [0; BLKSIZE]))
+    };
+    while device.now.load(Ordering::SeqCst) == 0 {
+        std::thread::yield_now();
+    }
+    assert_eq!(block_on(sfs.prefetch(&trace, usize::MAX))?, 0);
+    assert!(sfs.stats().io_shed > shed);
+    device.stall.store(false, Ordering::SeqCst);
+    assert_eq!(busy.join().unwrap()?, BLKSIZE);
+    assert_eq!(
+        block_on(sfs.prefetch(&trace, usize::MAX))?,
+        trace.blocks().len()
+    );
+    sfs.quick_scan()?;
+    Ok(())
+}
+
+#[test]
+fn btime_is_set_once() -> Result<()> {
+    use rcore_fs::vfs::MetadataMask;
+
+    const BLOCKS: usize = 64;
+    let sec = |sec: i64| Timespec { sec, nsec: 0 };
+    let clock = TestClock::new(100, 0);
+    let opts = SfsOptions::new().time_provider(clock.clone()).build()?;
+    let device = MemDevice::new(BLOCKS);
+    let sfs = SimpleFileSystem::create_with(Arc::new(device.clone()), BLOCKS * BLKSIZE, &opts)?;
+    assert!(sfs.capabilities().contains(FsCapabilities::BIRTH_TIME));
+    let root = sfs.root_inode();
+    let file = root.create("file", FileType::File, 0o644)?;
+    assert_eq!(file.metadata()?.btime, sec(100));
+
+    // modified later, by a writer setting mtime as the VFS above does
+    clock.set(105);
+    file.write_at(0, b"hello")?;
+    let mut meta = file.metadata()?;
+    meta.mtime = sec(105);
+    meta.btime = sec(1);
+    file.set_metadata(&meta)?;
+    let meta = file.metadata()?;
+    assert_eq!(meta.btime, sec(100));
+    assert!(meta.btime < meta.mtime);
+    let partial = file.metadata_partial(MetadataMask::TIMES)?;
+    assert_eq!(partial.btime, Some(sec(100)));
+    assert_eq!(file.metadata_partial(MetadataMask::SIZE)?.btime, None);
+    rcore_fs::conformance::check_btime(&root);
+
+    // kept on the image
+    drop((file, root));
+    sfs.unmount()?;
+    drop(sfs);
+    let sfs = SimpleFileSystem::open(Arc::new(device.clone()))?;
+    let file = sfs.root_inode().find("file")?;
+    assert_eq!(file.metadata()?.btime, sec(100));
+
+    // and through export and import
+    let mut stream = Vec::new();
+    export_stream(&sfs, &mut stream)?;
+    let copy =
+        SimpleFileSystem::create_with(Arc::new(MemDevice::new(BLOCKS)), BLOCKS * BLKSIZE, &opts)?;
+    import_stream(&copy, &mut stream.as_slice())?;
+    let imported = copy.root_inode().find("file")?;
+    assert_eq!(imported.metadata()?.btime, sec(100));
+    drop(imported);
+    drop(copy);
+
+    // streams of version 1 have none
+    let mut v1 = b"SFSX".to_vec();
+    v1.extend_from_slice(&1u32.to_le_bytes());
+    for (kind, path, data) in [(2u8, "", &b""[..]), (1, "old", b"abc")].iter() {
+        v1.push(*kind);
+        v1.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        v1.extend_from_slice(path.as_bytes());
+        v1.extend_from_slice(&[0o644u32.to_le_bytes(), [0; 4], [0; 4]].concat());
+        for _ in 0..3 {
+            v1.extend_from_slice(&7i64.to_le_bytes());
+            v1.extend_from_slice(&0i32.to_le_bytes());
+        }
+        v1.extend_from_slice(&0u64.to_le_bytes());
+        v1.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        v1.extend_from_slice(data);
+    }
+    v1.push(0);
+    let copy =
+        SimpleFileSystem::create_with(Arc::new(MemDevice::new(BLOCKS)), BLOCKS * BLKSIZE, &opts)?;
+    import_stream(&copy, &mut v1.as_slice())?;
+    let old = copy.root_inode().find("old")?.metadata()?;
+    assert_eq!((old.mtime, old.btime, old.size), (sec(7), sec(0), 3));
+    drop(copy);
+    drop(file);
+    sfs.unmount()?;
+    drop(sfs);
+
+    // images before VERSION_BTIME report zero, even for new inodes
+    let dev: Arc<dyn Device> = Arc::new(device.clone());
+    let mut super_block = dev.load_struct::<SuperBlock>(BLKN_SUPER)?;
+    super_block.version = VERSION_BTIME - 1;
+    dev.write_block(BLKN_SUPER, 0, super_block.as_buf())?;
+    let sfs = SimpleFileSystem::open_with(Arc::new(device), &opts)?;
+    assert!(!sfs.capabilities().contains(FsCapabilities::BIRTH_TIME));
+    let root = sfs.root_inode();
+    assert_eq!(root.find("file")?.metadata()?.btime, sec(0));
+    let new = root.create("new", FileType::File, 0o644)?;
+    assert_eq!(new.metadata()?.mtime, sec(105));
+    assert_eq!(new.metadata()?.btime, sec(0));
+    rcore_fs::conformance::check_btime(&root);
+    Ok(())
+}
+
+#[test]
+fn rescue_salvages_readable_tree() -> Result<()> {
+    use rcore_fs::vfs::MetadataFlags;
+
+    let device = Arc::new(MockDevice::new(1024));
+    let content = |seed: usize, len: usize| -> Vec<u8> {
+        (0..len).map(|i| (seed * 31 + i * 7) as u8).collect()
+    };
+    let sfs = SimpleFileSystem::create(device.clone(), 1024 * BLKSIZE)?;
+    let root = sfs.root_inode();
+    let mut expected = BTreeMap::new();
+    root.create("keep", FileType::File, 0o644)?
+        .write_at(0, &content(0, 300))?;
+    expected.insert(String::from("keep"), content(0, 300));
+    let big = root.create("big", FileType::File, 0o644)?;
+    big.write_at(0, &content(1, 3 * BLKSIZE + 10))?;
+    let dir = root.create("dir", FileType::Dir, 0o755)?;
+    for i in 0..40 {
+        let name = format!("f{}", i);
+        dir.create(&name, FileType::File, 0o644)?
+            .write_at(0, &content(i + 2, 100 + i))?;
+        // the entries of slots 16 to 31 are in or start in block 1
+        if !(14..30).contains(&i) {
+            expected.insert(format!("dir/{}", name), content(i + 2, 100 + i));
+        }
+    }
+    let other = root.create("other", FileType::Dir, 0o755)?;
+    let bad = other.create("bad", FileType::File, 0o644)?;
+    bad.write_at(0, &content(100, 500))?;
+    other
+        .create("good", FileType::File, 0o644)?
+        .write_at(0, &content(101, 500))?;
+    expected.insert(String::from("other/good"), content(101, 500));
+    let ids = |inode: &Arc<dyn INode>| inode.metadata().map(|meta| meta.inode);
+    let (big_id, dir_id, other_id, bad_id) = (ids(&big)?, ids(&dir)?, ids(&other)?, ids(&bad)?);
+    let big_block = sfs.get_inode(big_id)?.get_disk_block_id(1)?;
+    let dir_block = sfs.get_inode(dir_id)?.get_disk_block_id(1)?;
+    drop((root, big, dir, other, bad));
+    sfs.unmount()?;
+    drop(sfs);
+
+    // a garbage inode, a garbage block of entries, an unreadable data block
+    Device::write_at(&device.mem, bad_id * BLKSIZE, &[0xff; 64])?;
+    Device::write_at(&device.mem, dir_block * BLKSIZE, &[0xff; BLKSIZE])?;
+    device.bad_block.store(big_block, Ordering::SeqCst);
+
+    // open() takes the image as before, but fails on what is damaged
+    let sfs = SimpleFileSystem::open(device.clone())?;
+    let root = sfs.root_inode();
+    assert_eq!(sfs.rescue_report(), None);
+    assert_eq!(root.lookup("other/bad").err(), Some(FsError::Corrupted));
+    let mut buf = vec![0; BLKSIZE];
+    assert!(root.find("big")?.read_at(BLKSIZE, &mut buf).is_err());
+    let out = MemDevice::new(2048);
+    let packed = pack_subtree(&root, Arc::new(out), PackOptions::default());
+    assert!(packed.is_err());
+    drop(root);
+    sfs.unmount()?;
+    drop(sfs);
+
+    let sfs = SimpleFileSystem::open_rescue(device.clone())?;
+    assert!(sfs.is_read_only() && sfs.options().rescue);
+    let root = sfs.root_inode();
+    let stub = root.lookup("other/bad")?.metadata()?;
+    assert!(stub.flags.contains(MetadataFlags::CORRUPT));
+    assert_eq!((stub.type_, stub.size), (FileType::File, 0));
+    assert_eq!(root.find("other")?.list()?, vec![".", "..", "good"]);
+    assert!(!root
+        .find("keep")?
+        .metadata()?
+        .flags
+        .contains(MetadataFlags::CORRUPT));
+    assert_eq!(
+        root.create("new", FileType::File, 0o644).err(),
+        Some(FsError::ReadOnly)
+    );
+
+    let out = MemDevice::new(2048);
+    let opts = PackOptions {
+        skip_corrupt: true,
+        // the tree is too small for the default slack to cover the reserve
+        slack_percent: 50,
+        ..Default::default()
+    };
+    let copy = pack_subtree(&root, Arc::new(out), opts)?;
+    let copy_root = copy.root_inode();
+    let mut big = content(1, 3 * BLKSIZE + 10);
+    big[BLKSIZE..2 * BLKSIZE].fill(0);
+    expected.insert(String::from("big"), big);
+    let copied: BTreeMap<String, Vec<u8>> = compare_tree(&copy_root)?
+        .into_iter()
+        .filter(|(_, (meta, _))| meta.type_ == FileType::File)
+        .map(|(path, (_, content))| (path[1..].to_string(), content))
+        .collect();
+    assert!(copied == expected);
+
+    let report = sfs.rescue_report().unwrap();
+    assert_eq!(
+        report.quarantined,
+        [bad_id, 0xffff_ffff].iter().copied().collect()
+    );
+    assert_eq!(
+        report.lost[&other_id],
+        vec![LostEntry {
+            slot: 2,
+            name: b"bad".to_vec(),
+            inode: bad_id,
+            reason: LostReason::BadInode,
+        }]
+    );
+    let lost: Vec<_> = report.lost[&dir_id]
+        .iter()
+        .map(|lost| (lost.slot, lost.reason))
+        .collect();
+    let mut expected_lost: Vec<_> = (16..31).map(|slot| (slot, LostReason::BadName)).collect();
+    // its name ends in block 2, but its inode id is garbage
+    expected_lost.push((31, LostReason::BadInode));
+    assert_eq!(lost, expected_lost);
+    assert_eq!(report.lost.len(), 2);
+    assert_eq!(
+        report.zero_filled,
+        [ZeroFilled {
+            inode: big_id,
+            offset: BLKSIZE,
+            len: BLKSIZE,
+        }]
+        .iter()
+        .copied()
+        .collect()
+    );
+    let root_id = root.metadata()?.inode;
+    drop((root, copy_root, copy));
+    drop(sfs);
+
+    // open() refuses an image with a garbage root, which rescue stubs
+    Device::write_at(&device.mem, root_id * BLKSIZE, &[0xff; 64])?;
+    assert_eq!(
+        SimpleFileSystem::open(device.clone()).err(),
+        Some(FsError::Corrupted)
+    );
+    let sfs = SimpleFileSystem::open_rescue(device)?;
+    let root = sfs.root_inode().metadata()?;
+    assert!(root.flags.contains(MetadataFlags::CORRUPT));
+    assert!(sfs.rescue_report().unwrap().quarantined.contains(&root_id));
+
+    assert_eq!(
+        SfsOptions::new().rescue(true).build().err(),
+        Some(FsError::InvalidParam)
+    );
+    Ok(())
+}
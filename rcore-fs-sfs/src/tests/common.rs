@@ -0,0 +1,578 @@
+//! Devices, clocks and setups shared by the tests
+
+use crate::*;
+use rcore_fs::dev::timed::BoxFuture;
+use rcore_fs::dev::{AsyncDevice, BlockDevice, DevError, Device, Result as DevResult, Timer};
+use rcore_fs::vfs::{FileSystem, FileType, INode, Result, Timespec};
+use std::collections::BTreeSet;
+use std::fs::{self, OpenOptions};
+use std::ops::{Deref, Range};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+
+pub fn _open_sample_file() -> Arc<SimpleFileSystem> {
+    fs::copy("sfs.img", "test.img").expect("failed to open sfs.img");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("test.img")
+        .expect("failed to open test.img");
+    SimpleFileSystem::open(Arc::new(Mutex::new(file))).expect("failed to open SFS")
+}
+
+pub fn _create_new_sfs() -> Arc<SimpleFileSystem> {
+    let file = tempfile::tempfile().expect("failed to create file");
+    SimpleFileSystem::create(Arc::new(Mutex::new(file)), 32 * 4096 * 4096)
+        .expect("failed to create SFS")
+}
+
+/// An SFS, its root and a file in it
+pub type SfsWithFile = (Arc<SimpleFileSystem>, Arc<dyn INode>, Arc<dyn INode>);
+
+/// A new SFS with a file `name` in its root
+pub fn sfs_with_file(name: &str) -> Result<SfsWithFile> {
+    let sfs = _create_new_sfs();
+    let root = sfs.root_inode();
+    let file = root.create(name, FileType::File, 0o644)?;
+    Ok((sfs, root, file))
+}
+
+/// A new SFS of `blocks` blocks in memory, returned with its device
+pub fn mem_sfs(blocks: usize) -> Result<(MemDevice, Arc<SimpleFileSystem>)> {
+    let mem = MemDevice::new(blocks);
+    let sfs = SimpleFileSystem::create(Arc::new(mem.clone()), blocks * BLKSIZE)?;
+    Ok((mem, sfs))
+}
+
+/// A new SFS of `blocks` blocks on a `MockDevice`, synced so that the
+/// counts of the device start from a clean fs
+pub fn mock_sfs(blocks: usize) -> Result<(MockDevice, Arc<SimpleFileSystem>)> {
+    let device = MockDevice::new(blocks);
+    let sfs = SimpleFileSystem::create(Arc::new(device.clone()), blocks * BLKSIZE)?;
+    sfs.sync()?;
+    Ok((device, sfs))
+}
+
+/// `mock_sfs()` letting other threads run on each access to the device
+pub fn yielding_sfs(blocks: usize) -> Result<(MockDevice, Arc<SimpleFileSystem>)> {
+    let (device, sfs) = mock_sfs(blocks)?;
+    device.yielding.store(true, Ordering::SeqCst);
+    Ok((device, sfs))
+}
+
+/// Block device in memory with SFS block size, clones share the content
+#[derive(Clone)]
+pub struct MemDevice(pub Arc<Mutex<Vec<u8>>>);
+
+impl MemDevice {
+    /// A zeroed device of `blocks` blocks
+    pub fn new(blocks: usize) -> Self {
+        Self::with_image(vec![0; blocks * BLKSIZE])
+    }
+    pub fn with_image(image: Vec<u8>) -> Self {
+        MemDevice(Arc::new(Mutex::new(image)))
+    }
+    /// A copy of the content
+    pub fn image(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl BlockDevice for MemDevice {
+    const BLOCK_SIZE_LOG2: u8 = BLKSIZE_LOG2;
+    fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> DevResult<()> {
+        let data = self.0.lock().unwrap();
+        let begin = block_id * BLKSIZE;
+        if begin + BLKSIZE > data.len() {
+            return Err(DevError::OutOfRange);
+        }
+        buf[..BLKSIZE].copy_from_slice(&data[begin..begin + BLKSIZE]);
+        Ok(())
+    }
+    fn write_at(&self, block_id: BlockId, buf: &[u8]) -> DevResult<()> {
+        let mut data = self.0.lock().unwrap();
+        let begin = block_id * BLKSIZE;
+        if begin + BLKSIZE > data.len() {
+            return Err(DevError::OutOfRange);
+        }
+        data[begin..begin + BLKSIZE].copy_from_slice(&buf[..BLKSIZE]);
+        Ok(())
+    }
+    fn sync(&self) -> DevResult<()> {
+        Ok(())
+    }
+    fn size(&self) -> Option<usize> {
+        Some(self.0.lock().unwrap().len())
+    }
+}
+
+/// `MemDevice` counting its I/O and failing it as the test asks, by
+/// setting the fields of `Mock`. Clones share the content and the fields.
+#[derive(Clone)]
+pub struct MockDevice(Arc<Mock>);
+
+/// What a `MockDevice` counts and does, nothing unusual by default
+pub struct Mock {
+    pub mem: MemDevice,
+    /// read requests, a batch of blocks as one
+    pub reads: AtomicUsize,
+    /// blocks written
+    pub writes: AtomicUsize,
+    pub super_block_writes: AtomicUsize,
+    /// reject writes and report the device read-only, like a
+    /// write-protected card
+    pub protected: AtomicBool,
+    /// let other threads run on each access
+    pub yielding: AtomicBool,
+    /// record the blocks written in `log` while set
+    pub logging: AtomicBool,
+    pub log: Mutex<Vec<(BlockId, Vec<u8>)>>,
+    /// the block whose reads fail, `usize::MAX` for none
+    pub bad_block: AtomicUsize,
+    /// blocks which can be written before all writes fail
+    pub writes_left: AtomicUsize,
+    /// blocks whose writes fail, setting `crossed`
+    pub fence: Mutex<Range<BlockId>>,
+    pub crossed: AtomicBool,
+    /// read requests after which to panic, e.g. looping over corrupt data
+    pub read_budget: AtomicUsize,
+}
+
+impl MockDevice {
+    /// A zeroed device of `blocks` blocks
+    pub fn new(blocks: usize) -> Self {
+        Self::over(MemDevice::new(blocks))
+    }
+    pub fn over(mem: MemDevice) -> Self {
+        MockDevice(Arc::new(Mock {
+            mem,
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            super_block_writes: AtomicUsize::new(0),
+            protected: AtomicBool::new(false),
+            yielding: AtomicBool::new(false),
+            logging: AtomicBool::new(false),
+            log: Mutex::new(Vec::new()),
+            bad_block: AtomicUsize::new(usize::MAX),
+            writes_left: AtomicUsize::new(usize::MAX),
+            fence: Mutex::new(0..0),
+            crossed: AtomicBool::new(false),
+            read_budget: AtomicUsize::new(usize::MAX),
+        }))
+    }
+    pub fn set_protected(&self, protected: bool) {
+        self.protected.store(protected, Ordering::SeqCst);
+    }
+    fn count_read(&self) {
+        if self.reads.fetch_add(1, Ordering::SeqCst) >= self.read_budget.load(Ordering::SeqCst) {
+            panic!("too many reads, looping over corrupt data?");
+        }
+        if self.yielding.load(Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+    }
+    fn read_block(&self, block_id: BlockId, buf: &mut [u8]) -> DevResult<()> {
+        if block_id == self.bad_block.load(Ordering::SeqCst) {
+            return Err(DevError::IoError);
+        }
+        BlockDevice::read_at(&self.mem, block_id, buf)
+    }
+}
+
+impl Deref for MockDevice {
+    type Target = Mock;
+    fn deref(&self) -> &Mock {
+        &self.0
+    }
+}
+
+impl BlockDevice for MockDevice {
+    const BLOCK_SIZE_LOG2: u8 = BLKSIZE_LOG2;
+    fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> DevResult<()> {
+        self.count_read();
+        self.read_block(block_id, buf)
+    }
+    fn read_blocks(&self, block_id: BlockId, buf: &mut [u8]) -> DevResult<()> {
+        self.count_read();
+        for (i, block) in buf.chunks_mut(BLKSIZE).enumerate() {
+            self.read_block(block_id + i, block)?;
+        }
+        Ok(())
+    }
+    /// Writes made while the thread panics are dropped, so that the content
+    /// is the one at the crash of a `FailAction::Panic` failpoint
+    fn write_at(&self, block_id: BlockId, buf: &[u8]) -> DevResult<()> {
+        if std::thread::panicking() {
+            return Ok(());
+        }
+        if self.yielding.load(Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+        if self.protected.load(Ordering::SeqCst) {
+            return Err(DevError::WriteProtected);
+        }
+        if self.fence.lock().unwrap().contains(&block_id) {
+            self.crossed.store(true, Ordering::SeqCst);
+            return Err(DevError::WriteProtected);
+        }
+        let left = self
+            .writes_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if left.is_err() {
+            return Err(DevError::IoError);
+        }
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        if block_id == BLKN_SUPER {
+            self.super_block_writes.fetch_add(1, Ordering::SeqCst);
+        }
+        if self.logging.load(Ordering::SeqCst) {
+            let data = buf[..BLKSIZE].to_vec();
+            self.log.lock().unwrap().push((block_id, data));
+        }
+        BlockDevice::write_at(&self.mem, block_id, buf)
+    }
+    fn sync(&self) -> DevResult<()> {
+        Ok(())
+    }
+    fn is_read_only(&self) -> bool {
+        self.protected.load(Ordering::SeqCst)
+    }
+    fn size(&self) -> Option<usize> {
+        BlockDevice::size(&self.mem)
+    }
+}
+
+/// Clock reading the time the test sets
+pub struct TestClock(Mutex<Timespec>);
+
+impl TestClock {
+    pub fn new(sec: i64, nsec: i32) -> Arc<Self> {
+        Arc::new(TestClock(Mutex::new(Timespec { sec, nsec })))
+    }
+    /// Move the time to `sec` whole seconds
+    pub fn set(&self, sec: i64) {
+        *self.0.lock().unwrap() = Timespec { sec, nsec: 0 };
+    }
+}
+
+impl rcore_fs::dev::TimeProvider for TestClock {
+    fn current_time(&self) -> Timespec {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Device counting reads of file content, i.e. except block id entries, and all writes
+pub struct CountingDevice {
+    pub inner: Mutex<fs::File>,
+    pub reads: AtomicUsize,
+    pub writes: AtomicUsize,
+    /// offsets of all reads
+    pub read_offsets: Mutex<Vec<usize>>,
+    /// reads by `read_at_prio()`, also counted as reads
+    pub prio_reads: AtomicUsize,
+}
+
+impl CountingDevice {
+    pub fn new() -> Self {
+        CountingDevice {
+            inner: Mutex::new(tempfile::tempfile().expect("failed to create file")),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+            read_offsets: Mutex::new(Vec::new()),
+            prio_reads: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Device for CountingDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        if buf.len() != ENTRY_SIZE {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+        }
+        self.read_offsets.lock().unwrap().push(offset);
+        self.inner.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.write_at(offset, buf)
+    }
+    fn sync(&self) -> DevResult<()> {
+        self.inner.sync()
+    }
+    fn read_at_prio(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        self.prio_reads.fetch_add(1, Ordering::SeqCst);
+        self.read_at(offset, buf)
+    }
+}
+
+/// Check that every write stays inside a single block,
+/// i.e. each one is served by a single entry of the cache below
+pub struct BlockAlignedWrites<T>(pub T);
+
+impl<T: Device> Device for BlockAlignedWrites<T> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        self.0.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        assert!(
+            offset % BLKSIZE + buf.len() <= BLKSIZE,
+            "write at {:#x} with {} bytes crosses the block border",
+            offset,
+            buf.len()
+        );
+        self.0.write_at(offset, buf)
+    }
+    fn sync(&self) -> DevResult<()> {
+        self.0.sync()
+    }
+}
+
+/// Device in memory of a fixed size, recording any access past its end
+pub struct StrictDevice {
+    pub data: Mutex<Vec<u8>>,
+    pub out_of_range: AtomicBool,
+}
+
+impl StrictDevice {
+    pub fn new(data: Vec<u8>) -> Arc<Self> {
+        Arc::new(StrictDevice {
+            data: Mutex::new(data),
+            out_of_range: AtomicBool::new(false),
+        })
+    }
+    fn check(&self, offset: usize, len: usize, size: usize) -> DevResult<()> {
+        if offset + len > size {
+            self.out_of_range.store(true, Ordering::SeqCst);
+            return Err(DevError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+impl Device for StrictDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let data = self.data.lock().unwrap();
+        self.check(offset, buf.len(), data.len())?;
+        buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        Ok(buf.len())
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        let mut data = self.data.lock().unwrap();
+        self.check(offset, buf.len(), data.len())?;
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn sync(&self) -> DevResult<()> {
+        Ok(())
+    }
+    fn size(&self) -> Option<usize> {
+        Some(self.data.lock().unwrap().len())
+    }
+}
+
+/// Records the calls of `write_zeros()`
+pub struct ZeroCountingDevice {
+    pub inner: Mutex<fs::File>,
+    pub zeros: Mutex<Vec<(usize, usize)>>,
+}
+
+impl Device for ZeroCountingDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        self.inner.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        self.inner.write_at(offset, buf)
+    }
+    fn sync(&self) -> DevResult<()> {
+        self.inner.sync()
+    }
+    fn write_zeros(&self, offset: usize, len: usize) -> DevResult<usize> {
+        self.zeros.lock().unwrap().push((offset, len));
+        self.inner.write_zeros(offset, len)
+    }
+}
+
+/// Fails the I/O of data starting with `POISON` once armed
+pub struct PoisonDevice {
+    pub inner: Mutex<fs::File>,
+    pub armed: AtomicBool,
+}
+
+pub const POISON: u8 = 0xee;
+
+impl PoisonDevice {
+    fn check(&self, buf: &[u8]) -> DevResult<()> {
+        match self.armed.load(Ordering::SeqCst) && buf.first() == Some(&POISON) {
+            true => Err(DevError::IoError),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Device for PoisonDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let len = self.inner.read_at(offset, buf)?;
+        self.check(buf)?;
+        Ok(len)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        self.check(buf)?;
+        self.inner.write_at(offset, buf)
+    }
+    fn sync(&self) -> DevResult<()> {
+        self.inner.sync()
+    }
+}
+
+/// `MemDevice` counting the reads touching the blocks in `watched`
+pub struct WatchedReads {
+    pub mem: MemDevice,
+    pub watched: Mutex<BTreeSet<BlockId>>,
+    pub reads: AtomicUsize,
+}
+
+impl Device for WatchedReads {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        let blocks = offset / BLKSIZE..(offset + buf.len()).div_ceil(BLKSIZE);
+        if self.watched.lock().unwrap().range(blocks).next().is_some() {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+        }
+        Device::read_at(&self.mem, offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        Device::write_at(&self.mem, offset, buf)
+    }
+    fn sync(&self) -> DevResult<()> {
+        Device::sync(&self.mem)
+    }
+}
+
+/// Timer whose time moves a millisecond forward each time a sleep is polled
+#[derive(Default)]
+pub struct VirtualTimer {
+    pub now: Arc<AtomicUsize>,
+}
+
+impl Timer for VirtualTimer {
+    fn sleep(&self, duration: std::time::Duration) -> BoxFuture<'static, ()> {
+        let now = self.now.clone();
+        let end = now.load(Ordering::SeqCst) + duration.as_millis() as usize;
+        Box::pin(std::future::poll_fn(move |_| {
+            match now.fetch_add(1, Ordering::SeqCst) + 1 >= end {
+                true => std::task::Poll::Ready(()),
+                false => std::task::Poll::Pending,
+            }
+        }))
+    }
+}
+
+/// `MemDevice` completing requests at once, but never the next `hang` ones
+pub struct HangingDevice {
+    pub mem: MemDevice,
+    pub requests: AtomicUsize,
+    pub hang: AtomicUsize,
+}
+
+impl HangingDevice {
+    fn request<T: Send + 'static>(&self, result: DevResult<T>) -> BoxFuture<'static, DevResult<T>> {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        let hang = self
+            .hang
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        match hang {
+            Ok(_) => Box::pin(std::future::pending()),
+            Err(_) => Box::pin(std::future::ready(result)),
+        }
+    }
+}
+
+impl AsyncDevice for HangingDevice {
+    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> BoxFuture<'a, DevResult<usize>> {
+        self.request(Device::read_at(&self.mem, offset, buf))
+    }
+    fn write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> BoxFuture<'a, DevResult<usize>> {
+        self.request(Device::write_at(&self.mem, offset, buf))
+    }
+    fn sync(&self) -> BoxFuture<'_, DevResult<()>> {
+        self.request(Ok(()))
+    }
+}
+
+/// Sink of `backup_stream()` rebuilding the image in memory, slowly
+pub struct ImageSink {
+    pub image: Vec<u8>,
+    /// time taken by each block
+    pub delay: std::time::Duration,
+    /// set once `begin()` is called, at the consistency point
+    pub begun: Arc<AtomicBool>,
+    pub begun_at: Option<std::time::Instant>,
+    pub last_id: Option<BlockId>,
+}
+
+impl AsyncBlockSink for ImageSink {
+    fn begin<'a>(
+        &'a mut self,
+        blocks: usize,
+        super_block: &'a [u8],
+        free_map: &'a [u8],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.image = vec![0; blocks * BLKSIZE];
+            self.image[..BLKSIZE].copy_from_slice(super_block);
+            let at = BLKN_FREEMAP * BLKSIZE;
+            self.image[at..at + free_map.len()].copy_from_slice(free_map);
+            self.begun_at = Some(std::time::Instant::now());
+            self.begun.store(true, Ordering::SeqCst);
+            Ok(())
+        })
+    }
+
+    fn write_block<'a>(&'a mut self, id: BlockId, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            assert!(self.last_id < Some(id), "block {} out of order", id);
+            self.last_id = Some(id);
+            std::thread::sleep(self.delay);
+            self.image[id * BLKSIZE..(id + 1) * BLKSIZE].copy_from_slice(data);
+            Ok(())
+        })
+    }
+}
+
+/// `MemDevice` recording the most operations in it at once, which wait in
+/// it while `stall` is set
+#[derive(Default)]
+pub struct InFlight {
+    pub mem: Option<MemDevice>,
+    pub now: AtomicUsize,
+    pub peak: AtomicUsize,
+    pub stall: std::sync::atomic::AtomicBool,
+}
+
+impl InFlight {
+    fn op<T>(&self, f: impl FnOnce(&MemDevice) -> T) -> T {
+        let now = self.now.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(now, Ordering::SeqCst);
+        while self.stall.load(Ordering::SeqCst) {
+            std::thread::yield_now();
+        }
+        let result = f(self.mem.as_ref().unwrap());
+        self.now.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+}
+
+impl Device for InFlight {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> DevResult<usize> {
+        self.op(|mem| Device::read_at(mem, offset, buf))
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> DevResult<usize> {
+        self.op(|mem| Device::write_at(mem, offset, buf))
+    }
+    fn sync(&self) -> DevResult<()> {
+        self.op(Device::sync)
+    }
+    fn read_ahead(&self, _offset: usize, _len: usize) -> DevResult<()> {
+        self.op(|_| Ok(()))
+    }
+}
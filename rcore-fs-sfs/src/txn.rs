@@ -0,0 +1,578 @@
+//! Changes to several entries published together, see
+//! `SimpleFileSystem::transaction()`
+
+use super::*;
+use alloc::collections::btree_map::Entry;
+use spin::RwLockReadGuard;
+
+/// Most bytes `TxnGuard::write_at()` records in one transaction
+pub const TXN_MAX_WRITE: usize = 64 * 1024;
+
+impl SimpleFileSystem {
+    /// Start recording changes to be published together by
+    /// `TxnGuard::commit()`. Dropping the guard forgets them.
+    ///
+    /// SFS has no journal, so nothing is changed, not even allocated, before
+    /// the commit. It writes the new inodes and the new entries of each
+    /// changed dir to blocks nothing points to yet, then switches the dir to
+    /// them with a single write of its inode. A crash shows the entries of a
+    /// dir all as before or all as after, with at worst some blocks leaked.
+    /// Changed dirs are switched one after another, dirs gaining moved
+    /// entries first, so a crash in between may show a moved entry under
+    /// both names but never lose it.
+    ///
+    /// A dir is held by the transaction from the first change recorded in it
+    /// until the guard is dropped. Meanwhile creating, linking, unlinking or
+    /// moving entries in it by other means fails with `Busy` instead of
+    /// blocking, and so does recording changes in it by another transaction.
+    pub fn transaction(&self) -> TxnGuard {
+        TxnGuard {
+            fs: self.this(),
+            ops: Vec::new(),
+            dirs: BTreeSet::new(),
+            recorded: 0,
+        }
+    }
+
+    /// Fail with `Busy` if a transaction holds any of `dirs`. Keep the guard
+    /// while changing them, so that no transaction takes them meanwhile.
+    pub(crate) fn hold_against_txn(
+        &self,
+        dirs: &[INodeId],
+    ) -> vfs::Result<RwLockReadGuard<'_, BTreeSet<INodeId>>> {
+        let held = self.txn_dirs.read();
+        if dirs.iter().any(|id| held.contains(id)) {
+            return Err(FsError::Busy);
+        }
+        Ok(held)
+    }
+}
+
+/// A change recorded by `TxnGuard`
+enum TxnOp {
+    Create {
+        dir: Arc<INodeImpl>,
+        name: String,
+        type_: vfs::FileType,
+        mode: u32,
+        data: usize,
+    },
+    Unlink {
+        dir: Arc<INodeImpl>,
+        name: String,
+    },
+    Move {
+        dir: Arc<INodeImpl>,
+        old_name: String,
+        target: Arc<INodeImpl>,
+        new_name: String,
+    },
+    Write {
+        dir: Arc<INodeImpl>,
+        name: String,
+        offset: usize,
+        data: Vec<u8>,
+    },
+}
+
+/// Changes recorded by `SimpleFileSystem::transaction()`
+pub struct TxnGuard {
+    fs: Arc<SimpleFileSystem>,
+    ops: Vec<TxnOp>,
+    /// dirs held until the guard is dropped
+    dirs: BTreeSet<INodeId>,
+    /// bytes recorded by `write_at()`
+    recorded: usize,
+}
+
+impl TxnGuard {
+    /// Record `dir.create2(name, type_, mode, data)`
+    pub fn create2(
+        &mut self,
+        dir: &Arc<dyn INode>,
+        name: &str,
+        type_: vfs::FileType,
+        mode: u32,
+        data: usize,
+    ) -> vfs::Result<()> {
+        Str256::new(name)?;
+        let dir = self.hold(dir)?;
+        self.ops.push(TxnOp::Create {
+            dir,
+            name: String::from(name),
+            type_,
+            mode,
+            data,
+        });
+        Ok(())
+    }
+
+    /// Record `dir.unlink(name)`
+    pub fn unlink(&mut self, dir: &Arc<dyn INode>, name: &str) -> vfs::Result<()> {
+        let dir = self.hold(dir)?;
+        self.ops.push(TxnOp::Unlink {
+            dir,
+            name: String::from(name),
+        });
+        Ok(())
+    }
+
+    /// Record `dir.move_(old_name, target, new_name)`. A dir can only be
+    /// renamed in the dir it is in, moving it to another dir fails the
+    /// commit with `InvalidParam`.
+    pub fn move_(
+        &mut self,
+        dir: &Arc<dyn INode>,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+    ) -> vfs::Result<()> {
+        Str256::new(new_name)?;
+        let dir = self.hold(dir)?;
+        let target = self.hold(target)?;
+        self.ops.push(TxnOp::Move {
+            dir,
+            old_name: String::from(old_name),
+            target,
+            new_name: String::from(new_name),
+        });
+        Ok(())
+    }
+
+    /// Record writing `buf` at `offset` of `name` in `dir`. Only files and
+    /// symlinks created by this transaction can be written, others fail the
+    /// commit with `InvalidParam`. Fail with `InvalidParam` if more than
+    /// `TXN_MAX_WRITE` bytes would be recorded in total.
+    pub fn write_at(
+        &mut self,
+        dir: &Arc<dyn INode>,
+        name: &str,
+        offset: usize,
+        buf: &[u8],
+    ) -> vfs::Result<usize> {
+        if self.recorded + buf.len() > TXN_MAX_WRITE {
+            return Err(FsError::InvalidParam);
+        }
+        let dir = self.hold(dir)?;
+        self.recorded += buf.len();
+        self.ops.push(TxnOp::Write {
+            dir,
+            name: String::from(name),
+            offset,
+            data: buf.to_vec(),
+        });
+        Ok(buf.len())
+    }
+
+    /// Apply the recorded changes in order, see
+    /// `SimpleFileSystem::transaction()`.
+    ///
+    /// Each change is checked as by the `INode` method it stands for, and if
+    /// any fails nothing is changed. An I/O error while switching the dirs
+    /// is returned as is, the dirs switched by then keep their new entries.
+    pub fn commit(mut self) -> vfs::Result<()> {
+        let ops = core::mem::take(&mut self.ops);
+        let mut plan = Plan {
+            fs: self.fs.clone(),
+            dirs: BTreeMap::new(),
+            created: BTreeMap::new(),
+            unlinked: Vec::new(),
+            recovery: ops.iter().all(|op| matches!(op, TxnOp::Unlink { .. })),
+        };
+        let shadows = ops
+            .iter()
+            .try_for_each(|op| plan.apply(op))
+            .and_then(|()| plan.prepare());
+        let shadows = match shadows {
+            Ok(shadows) => shadows,
+            Err(err) => {
+                plan.discard();
+                return Err(err);
+            }
+        };
+        for (id, shadow) in shadows {
+            failpoint!(self.fs, "txn_commit_before_switch");
+            plan.dirs[&id].switch(shadow)?;
+        }
+        plan.finish()
+    }
+
+    /// The dir `inode` of this fs, held by this transaction
+    fn hold(&mut self, inode: &Arc<dyn INode>) -> vfs::Result<Arc<INodeImpl>> {
+        let dir = inode
+            .downcast_ref::<INodeImpl>()
+            .ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&dir.fs, &self.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        dir.check_writable()?;
+        if dir.disk_inode.read().type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if !self.dirs.contains(&dir.id) {
+            if !self.fs.txn_dirs.write().insert(dir.id) {
+                return Err(FsError::Busy);
+            }
+            self.dirs.insert(dir.id);
+        }
+        self.fs.get_inode(dir.id)
+    }
+}
+
+impl Drop for TxnGuard {
+    fn drop(&mut self) {
+        let mut held = self.fs.txn_dirs.write();
+        for id in self.dirs.iter() {
+            held.remove(id);
+        }
+    }
+}
+
+/// A dir as it will be after the changes checked so far
+struct DirPlan {
+    dir: Arc<INodeImpl>,
+    /// entries after "." and ".."
+    entries: Vec<DiskEntry>,
+    nlinks: u16,
+    /// gets entries moved from another dir
+    gains: bool,
+}
+
+impl DirPlan {
+    fn position(&self, name: &str) -> Option<usize> {
+        let fs = &self.dir.fs;
+        self.entries
+            .iter()
+            .position(|entry| fs.is_name(&entry.name, name))
+    }
+
+    /// Write the new entries to a scratch inode whose content is the new
+    /// content of the dir, from the recovery reserve too if `recovery`
+    fn build(&self, recovery: bool) -> vfs::Result<Arc<INodeImpl>> {
+        let fs = &self.dir.fs;
+        let id = match recovery {
+            true => fs.alloc_block_reserved(Some(self.dir.id)),
+            false => fs.alloc_block(Some(self.dir.id)),
+        };
+        let id = id.ok_or(FsError::NoDeviceSpace)?;
+        // freed on drop, nlinks is 0
+        let shadow = fs._new_inode(id, fs.new_disk_inode(DiskINode::new_dir()));
+        // only this transaction writes it
+        shadow.recovery.store(recovery, Ordering::Relaxed);
+        let dots = [
+            DiskEntry::new(self.dir.id as u32, Str256::from("."), FileType::Dir),
+            DiskEntry::new(
+                self.dir.parent_id()? as u32,
+                Str256::from(".."),
+                FileType::Dir,
+            ),
+        ];
+        let mut buf = Vec::with_capacity((self.entries.len() + 2) * DIRENT_SIZE);
+        for entry in dots.iter().chain(self.entries.iter()) {
+            buf.extend_from_slice(&entry.to_disk());
+        }
+        shadow._resize(buf.len())?;
+        if shadow._write_at(0, &buf)? != buf.len() {
+            return Err(FsError::DeviceError);
+        }
+        Ok(shadow)
+    }
+
+    /// Give the dir the content of `shadow`, publishing it with one write of
+    /// the inode. The old content is freed with `shadow`.
+    fn switch(&self, shadow: Arc<INodeImpl>) -> vfs::Result<()> {
+        let dir = &self.dir;
+        let mut disk_inode = dir.disk_inode.write();
+        let mut shadow_inode = shadow.disk_inode.write();
+        swap_content(&mut disk_inode, &mut shadow_inode);
+        let nlinks = core::mem::replace(&mut disk_inode.nlinks, self.nlinks);
+        let result = dir
+            .fs
+            .device
+            .write_block(dir.id, 0, &disk_inode.to_disk())
+            .and_then(|()| Ok(dir.fs.device.sync()?));
+        if let Err(err) = result {
+            swap_content(&mut disk_inode, &mut shadow_inode);
+            disk_inode.nlinks = nlinks;
+            return Err(err);
+        }
+        disk_inode.sync();
+        drop(disk_inode);
+        dir.entries_changed();
+        dir.readahead.write().clear();
+        drop(shadow_inode);
+        drop(shadow);
+        dir.index_build_if_large();
+        Ok(())
+    }
+}
+
+/// Swap the blocks and size of two inodes
+fn swap_content(a: &mut DiskINode, b: &mut DiskINode) {
+    core::mem::swap(&mut a.size, &mut b.size);
+    core::mem::swap(&mut a.blocks, &mut b.blocks);
+    core::mem::swap(&mut a.direct, &mut b.direct);
+    core::mem::swap(&mut a.indirect, &mut b.indirect);
+    core::mem::swap(&mut a.db_indirect, &mut b.db_indirect);
+    core::mem::swap(&mut a.index, &mut b.index);
+}
+
+/// The changes of a transaction being committed
+struct Plan {
+    fs: Arc<SimpleFileSystem>,
+    dirs: BTreeMap<INodeId, DirPlan>,
+    /// inodes created, nothing on disk points to them before their dir is
+    /// switched
+    created: BTreeMap<INodeId, Arc<INodeImpl>>,
+    /// existing inodes to unlink once the dirs are switched, once per link
+    unlinked: Vec<INodeId>,
+    /// only unlinks, so the recovery reserve may be taken for the new
+    /// entries, see `SimpleFileSystem::recovery_blocks()`
+    recovery: bool,
+}
+
+impl Plan {
+    fn dir(&mut self, dir: &Arc<INodeImpl>) -> vfs::Result<&mut DirPlan> {
+        match self.dirs.entry(dir.id) {
+            Entry::Occupied(plan) => Ok(plan.into_mut()),
+            Entry::Vacant(slot) => {
+                if dir.is_removed() {
+                    return Err(FsError::DirRemoved);
+                }
+                let mut entries = Vec::new();
+                dir.scan_direntry(|id, entry| {
+                    if id >= 2 {
+                        entries.push(DiskEntry {
+                            id: entry.id,
+                            name: Str256(entry.name.0),
+                        });
+                    }
+                    None::<()>
+                })?;
+                let nlinks = dir.disk_inode.read().nlinks;
+                Ok(slot.insert(DirPlan {
+                    dir: dir.clone(),
+                    entries,
+                    nlinks,
+                    gains: false,
+                }))
+            }
+        }
+    }
+
+    /// Inode of entry `name` of `dir`
+    fn find(&mut self, dir: &Arc<INodeImpl>, name: &str) -> vfs::Result<(usize, Arc<INodeImpl>)> {
+        let plan = self.dir(dir)?;
+        let pos = plan.position(name).ok_or(FsError::EntryNotFound)?;
+        let id = plan.entries[pos].id as INodeId;
+        Ok((pos, self.fs.get_inode(id)?))
+    }
+
+    fn apply(&mut self, op: &TxnOp) -> vfs::Result<()> {
+        match op {
+            TxnOp::Create {
+                dir,
+                name,
+                type_,
+                mode,
+                data,
+            } => self.create(dir, name, *type_, *mode, *data),
+            TxnOp::Unlink { dir, name } => self.unlink(dir, name),
+            TxnOp::Move {
+                dir,
+                old_name,
+                target,
+                new_name,
+            } => self.move_(dir, old_name, target, new_name),
+            TxnOp::Write {
+                dir,
+                name,
+                offset,
+                data,
+            } => {
+                let (_, inode) = self.find(dir, name)?;
+                if !self.created.contains_key(&inode.id) {
+                    return Err(FsError::InvalidParam);
+                }
+                if inode.write_at(*offset, data)? != data.len() {
+                    return Err(FsError::DeviceError);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn create(
+        &mut self,
+        dir: &Arc<INodeImpl>,
+        name: &str,
+        type_: vfs::FileType,
+        mode: u32,
+        data: usize,
+    ) -> vfs::Result<()> {
+        dir.check_flags(InodeFlags::IMMUTABLE)?;
+        let plan = self.dir(dir)?;
+        if name == "." || name == ".." || plan.position(name).is_some() {
+            return Err(FsError::EntryExist);
+        }
+        if type_ == vfs::FileType::Dir && plan.nlinks as usize + 1 > LINK_MAX {
+            return Err(FsError::TooManyLinks);
+        }
+        let inode = self.fs.new_inode_in(dir.id, type_, data)?;
+        dir.init_owner(&inode, type_, mode, &CreateContext::default());
+        inode.nlinks_inc()?;
+        let plan = self.dir(dir)?;
+        if type_ == vfs::FileType::Dir {
+            inode.nlinks_inc()?; //for .
+            plan.nlinks += 1; //for ..
+        }
+        let inode_type = inode.disk_inode.read().type_;
+        plan.entries.push(DiskEntry::new(
+            inode.id as u32,
+            Str256::new(name)?,
+            inode_type,
+        ));
+        self.created.insert(inode.id, inode);
+        Ok(())
+    }
+
+    fn unlink(&mut self, dir: &Arc<INodeImpl>, name: &str) -> vfs::Result<()> {
+        dir.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
+        if name == "." || name == ".." {
+            return Err(FsError::IsDir);
+        }
+        let (pos, inode) = self.find(dir, name)?;
+        inode.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
+        inode.check_unpinned_unlink()?;
+        let is_dir = inode.disk_inode.read().type_ == FileType::Dir;
+        if is_dir {
+            let children = match self.dirs.get(&inode.id) {
+                Some(plan) => plan.entries.len(),
+                None => (inode.disk_inode.read().size as usize / DIRENT_SIZE).saturating_sub(2),
+            };
+            if children > 0 {
+                return Err(FsError::DirNotEmpty);
+            }
+        }
+        let plan = self.dir(dir)?;
+        plan.entries.remove(pos);
+        if is_dir {
+            plan.nlinks -= 1; //for ..
+        }
+        self.unlink_inode(&inode)
+    }
+
+    fn move_(
+        &mut self,
+        dir: &Arc<INodeImpl>,
+        old_name: &str,
+        target: &Arc<INodeImpl>,
+        new_name: &str,
+    ) -> vfs::Result<()> {
+        dir.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
+        target.check_flags(InodeFlags::IMMUTABLE)?;
+        for name in [old_name, new_name] {
+            if name == "." || name == ".." {
+                return Err(FsError::IsDir);
+            }
+        }
+        let (_, source) = self.find(dir, old_name)?;
+        source.check_flags(InodeFlags::IMMUTABLE)?;
+        let source_type = source.disk_inode.read().type_;
+        let same_dir = dir.id == target.id;
+        if !same_dir && source_type == FileType::Dir {
+            return Err(FsError::InvalidParam);
+        }
+        if same_dir && old_name == new_name {
+            return Ok(());
+        }
+        // another spelling of the same name only changes the entry
+        let respell = same_dir && dir.fs.same_name(old_name, new_name);
+        if let (false, Some(pos)) = (respell, self.dir(target)?.position(new_name)) {
+            // the replaced one is unlinked
+            let (_, replaced) = self.find(target, new_name)?;
+            replaced.check_flags(InodeFlags::IMMUTABLE | InodeFlags::APPEND_ONLY)?;
+            replaced.check_unpinned_unlink()?;
+            if replaced.disk_inode.read().type_ == FileType::Dir {
+                return Err(FsError::IsDir);
+            }
+            self.dir(target)?.entries.remove(pos);
+            self.unlink_inode(&replaced)?;
+        }
+        let entry = DiskEntry::new(source.id as u32, Str256::new(new_name)?, source_type);
+        let plan = self.dir(dir)?;
+        let pos = plan.position(old_name).ok_or(FsError::EntryNotFound)?;
+        if same_dir {
+            plan.entries[pos] = entry;
+        } else {
+            plan.entries.remove(pos);
+            let plan = self.dir(target)?;
+            plan.entries.push(entry);
+            plan.gains = true;
+        }
+        Ok(())
+    }
+
+    /// Drop a link to `inode`, at once if it is created by this transaction
+    fn unlink_inode(&mut self, inode: &INodeImpl) -> vfs::Result<()> {
+        if !self.created.contains_key(&inode.id) {
+            self.unlinked.push(inode.id);
+            return Ok(());
+        }
+        inode.nlinks_dec()?;
+        if inode.disk_inode.read().type_ == FileType::Dir {
+            inode.nlinks_dec()?; //for .
+        }
+        Ok(())
+    }
+
+    /// Write everything the changed dirs will point to, return the scratch
+    /// inodes holding their new content in the order to switch them
+    fn prepare(&self) -> vfs::Result<Vec<(INodeId, Arc<INodeImpl>)>> {
+        let mut order: Vec<_> = self.dirs.values().collect();
+        order.sort_by_key(|plan| !plan.gains);
+        let mut shadows = Vec::with_capacity(order.len());
+        for plan in order {
+            shadows.push((plan.dir.id, plan.build(self.recovery)?));
+        }
+        // the new blocks are marked used on disk before anything points to
+        // them, the old ones are freed only after
+        self.fs.sync()?;
+        Ok(shadows)
+    }
+
+    /// Forget the changes, the created inodes are freed
+    fn discard(&mut self) {
+        for inode in self.created.values() {
+            inode.disk_inode.write().nlinks = 0;
+        }
+        self.release_created();
+    }
+
+    /// Drop the links of the unlinked inodes once the dirs are switched
+    fn finish(mut self) -> vfs::Result<()> {
+        for id in core::mem::take(&mut self.unlinked) {
+            let inode = self.fs.get_inode(id)?;
+            inode.nlinks_dec()?;
+            if inode.disk_inode.read().type_ == FileType::Dir {
+                inode.nlinks_dec()?; //for .
+            }
+            if inode.is_removed() {
+                // let it be freed as soon as the last user drops it
+                self.fs.uncache_inode(id);
+            }
+        }
+        self.release_created();
+        self.fs.sync()
+    }
+
+    /// Let created inodes without links be freed when dropped
+    fn release_created(&mut self) {
+        let created = core::mem::take(&mut self.created);
+        for (id, inode) in created {
+            if inode.is_removed() {
+                self.fs.uncache_inode(id);
+            }
+        }
+    }
+}
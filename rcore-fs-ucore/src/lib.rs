@@ -503,7 +503,10 @@ static INODE_OPS: INodeOps = {
     }
     extern fn tryseek(inode: &mut INode, pos: i32) -> ErrorCode {
         println!("inode.tryseek({:?}) at {:?}", pos, inode);
-        let fs = inode.fs();
+        let fs = match inode.fs() {
+            Ok(fs) => fs,
+            Err(_) => return ErrorCode::Unimplemented,
+        };
         if pos < 0 || pos as usize >= fs.info().max_file_size {
             return ErrorCode::Invalid;
         }
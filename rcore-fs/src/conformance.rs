@@ -0,0 +1,246 @@
+//! Checks of the behavior all file systems share, for their tests
+//!
+//! Each check panics at the first INode differing, naming the operation.
+
+// only run by tests, where a panic is how a check fails
+#![allow(clippy::unwrap_used, clippy::expect_used, clippy::panic)]
+
+use crate::vfs::{FileType, FsCapabilities, FsError, INode, Result, Timespec};
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+
+/// Name of the entry `check_type_errors()` tries to make
+const PROBE_NAME: &str = "conformance-probe";
+
+/// Check the errors by file type documented on `INode`, for `dir` and all
+/// the tree under it. The tree is left as it was.
+pub fn check_type_errors(dir: &Arc<dyn INode>) {
+    let hardlink = dir
+        .fs()
+        .expect("fs")
+        .capabilities()
+        .contains(FsCapabilities::HARDLINK);
+    let mut dirs: Vec<Arc<dyn INode>> = Vec::new();
+    let mut others = Vec::new();
+    collect(dir, &mut dirs, &mut others);
+    for inode in dirs.iter() {
+        check_dir(inode);
+    }
+    for inode in others.iter() {
+        check_non_dir(inode, dir);
+    }
+    if hardlink {
+        for other in dirs.iter() {
+            expect("link to a dir", dir.link(PROBE_NAME, other), FsError::IsDir);
+        }
+    }
+}
+
+/// Check the return of `read_at()` and `write_at()` documented on `INode`
+/// for the files, symlinks and devices of the tree under `dir`, and for a
+/// file made in `dir` if the fs can make one. Devices are only written
+/// nothing. The tree is left as it was.
+pub fn check_io(dir: &Arc<dyn INode>) {
+    let mut dirs = Vec::new();
+    let mut others = Vec::new();
+    collect(dir, &mut dirs, &mut others);
+    for inode in others.iter() {
+        match inode.metadata().expect("metadata").type_ {
+            FileType::File | FileType::SymLink => check_content(inode),
+            FileType::CharDevice | FileType::BlockDevice => check_device(inode),
+            _ => {}
+        }
+    }
+    if let Ok(file) = dir.create(PROBE_NAME, FileType::File, 0o644) {
+        check_file_io(&file);
+        dir.unlink(PROBE_NAME).expect("unlink");
+    }
+}
+
+/// Check `Metadata::btime` of `dir` and the tree under it: zero unless the
+/// fs has `FsCapabilities::BIRTH_TIME`, else kept by `set_metadata()` and
+/// writes of a file made in `dir`. The tree is left as it was.
+pub fn check_btime(dir: &Arc<dyn INode>) {
+    let zero = Timespec { sec: 0, nsec: 0 };
+    if !dir
+        .fs()
+        .expect("fs")
+        .capabilities()
+        .contains(FsCapabilities::BIRTH_TIME)
+    {
+        let mut dirs = Vec::new();
+        let mut others = Vec::new();
+        collect(dir, &mut dirs, &mut others);
+        for inode in dirs.iter().chain(others.iter()) {
+            let btime = inode.metadata().expect("metadata").btime;
+            assert_eq!(btime, zero, "btime without BIRTH_TIME");
+        }
+        return;
+    }
+    let file = match dir.create(PROBE_NAME, FileType::File, 0o644) {
+        Ok(file) => file,
+        Err(_) => return,
+    };
+    let btime = file.metadata().expect("metadata").btime;
+    let mut meta = file.metadata().expect("metadata");
+    meta.btime = Timespec {
+        sec: btime.sec + 1000,
+        nsec: 0,
+    };
+    // may fail, e.g. if times can not be set
+    if file.set_metadata(&meta).is_ok() {
+        let after = file.metadata().expect("metadata").btime;
+        assert_eq!(after, btime, "btime after set_metadata");
+    }
+    file.write_at(0, b"btime").expect("write_at");
+    let after = file.metadata().expect("metadata");
+    assert_eq!(after.btime, btime, "btime after write_at");
+    assert!(after.btime <= after.mtime, "btime after mtime");
+    dir.unlink(PROBE_NAME).expect("unlink");
+}
+
+/// Byte of `read()` left as is where nothing is read
+const UNTOUCHED: u8 = 0xee;
+
+/// The bytes read by `read_at(offset, len)` of `inode`, checking at most
+/// `len` are read and the buffer past them is not touched
+fn read(inode: &Arc<dyn INode>, offset: usize, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![UNTOUCHED; len];
+    let n = inode.read_at(offset, &mut buf)?;
+    assert!(n <= len, "read_at of {} bytes read {}", len, n);
+    assert!(
+        buf[n..].iter().all(|&b| b == UNTOUCHED),
+        "read_at of {} bytes touched the buffer past the {} read",
+        len,
+        n
+    );
+    buf.truncate(n);
+    Ok(buf)
+}
+
+/// Check `write_at()` of nothing at `offset` transfers nothing and changes
+/// no size
+fn check_empty_write(inode: &Arc<dyn INode>, offset: usize) {
+    let size = inode.metadata().expect("metadata").size;
+    // may fail, e.g. if not writable
+    if let Ok(n) = inode.write_at(offset, &[]) {
+        assert_eq!(n, 0, "write_at of nothing at {}", offset);
+    }
+    let after = inode.metadata().expect("metadata").size;
+    assert_eq!(after, size, "size after write_at of nothing at {}", offset);
+}
+
+/// Check reads of the content of `inode`, a file or symlink, end exactly at
+/// its size
+fn check_content(inode: &Arc<dyn INode>) {
+    let size = inode.metadata().expect("metadata").size;
+    let mut content = Vec::new();
+    loop {
+        let data = read(inode, content.len(), 1000).expect("read_at");
+        if data.is_empty() {
+            break;
+        }
+        content.extend(data);
+    }
+    assert_eq!(content.len(), size, "bytes read until read_at gave 0");
+    let read = |offset, len| read(inode, offset, len).expect("read_at");
+    assert_eq!(read(size, 10), [], "read_at at the size");
+    assert_eq!(read(size + 10, 10), [], "read_at past the size");
+    assert_eq!(read(0, 0), [], "read_at of nothing");
+    if size > 0 {
+        let data = read(size - 1, 10);
+        assert_eq!(data, content[size - 1..], "read_at straddling the size");
+    }
+    check_empty_write(inode, 0);
+    check_empty_write(inode, size + 10);
+}
+
+/// Check the return of `read_at()` of `inode`, a device, and of
+/// `write_at()` of nothing
+fn check_device(inode: &Arc<dyn INode>) {
+    // may fail, e.g. if not readable, but never read more than asked
+    for len in [0, 1, 100] {
+        if let Ok(data) = read(inode, 0, len) {
+            assert!(len > 0 || data.is_empty(), "read_at of nothing");
+        }
+    }
+    check_empty_write(inode, 0);
+}
+
+/// Check reads and writes of `file`, an empty file, are byte-exact
+fn check_file_io(file: &Arc<dyn INode>) {
+    check_empty_write(file, 0);
+    check_empty_write(file, 100);
+    let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+    assert_eq!(file.write_at(0, &data).expect("write_at"), data.len());
+    assert_eq!(file.metadata().expect("metadata").size, data.len());
+    check_content(file);
+    let read = |offset, len| read(file, offset, len).expect("read_at");
+    assert_eq!(
+        read(4000, 2000),
+        data[4000..],
+        "read_at straddling the size"
+    );
+
+    // a hole reads as zeros
+    assert_eq!(file.write_at(6000, &data[..10]).expect("write_at"), 10);
+    assert_eq!(file.metadata().expect("metadata").size, 6010);
+    let read_back = read(4990, 1100);
+    assert_eq!(read_back.len(), 1020, "read_at over a hole");
+    assert_eq!(read_back[..10], data[4990..]);
+    assert!(read_back[10..1010].iter().all(|&b| b == 0), "hole read");
+    assert_eq!(read_back[1010..], data[..10]);
+    check_content(file);
+}
+
+/// Push the INodes of the tree under dir `dir`, including it
+fn collect(dir: &Arc<dyn INode>, dirs: &mut Vec<Arc<dyn INode>>, others: &mut Vec<Arc<dyn INode>>) {
+    dirs.push(dir.clone());
+    let names: Vec<String> = dir.list().expect("list").into_iter().skip(2).collect();
+    for name in names {
+        let inode = dir.find(&name).expect("find");
+        match inode.metadata().expect("metadata").type_ {
+            FileType::Dir => collect(&inode, dirs, others),
+            _ => others.push(inode),
+        }
+    }
+}
+
+/// Check the operations on file content fail on `dir`
+fn check_dir(dir: &Arc<dyn INode>) {
+    expect("read_at", dir.read_at(0, &mut [0; 1]), FsError::IsDir);
+    expect("write_at", dir.write_at(0, &[0; 1]), FsError::IsDir);
+    expect("resize", dir.resize(0), FsError::IsDir);
+}
+
+/// Check the operations on entries fail on `inode`, not a dir. `dir` is
+/// the one to give as the other INode.
+fn check_non_dir(inode: &Arc<dyn INode>, dir: &Arc<dyn INode>) {
+    expect(
+        "create",
+        inode.create(PROBE_NAME, FileType::File, 0o644),
+        FsError::NotDir,
+    );
+    expect(
+        "symlink",
+        inode.symlink(PROBE_NAME, PROBE_NAME),
+        FsError::NotDir,
+    );
+    expect("link", inode.link(PROBE_NAME, inode), FsError::NotDir);
+    expect("unlink", inode.unlink(PROBE_NAME), FsError::NotDir);
+    expect(
+        "move_",
+        inode.move_(PROBE_NAME, dir, PROBE_NAME),
+        FsError::NotDir,
+    );
+    expect("find", inode.find(PROBE_NAME), FsError::NotDir);
+    expect("find .", inode.find("."), FsError::NotDir);
+    expect("get_entry", inode.get_entry(0), FsError::NotDir);
+}
+
+/// Check `result` of `op` is the error `expected`
+fn expect<T>(op: &str, result: Result<T>, expected: FsError) {
+    match result {
+        Ok(_) => panic!("{} succeeded instead of failing with {:?}", op, expected),
+        Err(err) => assert_eq!(err.root_cause(), &expected, "error of {}", op),
+    }
+}
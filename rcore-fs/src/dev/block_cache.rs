@@ -1,17 +1,74 @@
 //! A naive LRU cache layer for `BlockDevice`
+//!
+//! Dirty blocks are written back on `sync()` and on eviction, adjacent ones
+//! together by `BlockDevice::write_blocks()`. Blocks being written back are
+//! not evicted or written around until done, and stay dirty if written to
+//! meanwhile.
+//!
+//! Through `HintedDevice`, blocks used with `CacheHint::DropAfterUse` are
+//! evicted first, and not cached at all when read whole. Blocks used with
+//! `PinHot` are not evicted, up to `with_max_pinned()` of them, and
+//! `Metadata` blocks reaching the end of the LRU are given a second chance.
 use super::*;
-use alloc::{vec, vec::Vec};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::{Mutex, MutexGuard};
 
+/// Default max bytes of a write of adjacent blocks, see `with_coalescing()`
+pub const DEFAULT_MAX_WRITE: usize = 1 << 20;
+
+/// Default part of the buffers that may be pinned, see `with_max_pinned()`
+pub const DEFAULT_PINNED_FRACTION: usize = 4;
+
 pub struct BlockCache<T: BlockDevice> {
     device: T,
     bufs: Vec<Mutex<Buf>>,
     lru: Mutex<LRU>,
+    /// max bytes of a write of adjacent blocks
+    max_write: usize,
+    /// max bytes to copy adjacent blocks to for a write
+    write_buffer: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    invalidations: AtomicU64,
+    coalesced_writes: AtomicU64,
+    coalesced_blocks: AtomicU64,
+    bypassed: AtomicU64,
+}
+
+/// Counters of a `BlockCache` since created
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct CacheStats {
+    /// Lookups of blocks found in the cache
+    pub hits: u64,
+    /// Lookups of blocks not in the cache
+    pub misses: u64,
+    /// Cached blocks dropped as the device was written around the cache
+    pub invalidations: u64,
+    /// Writes of more than one block
+    pub coalesced_writes: u64,
+    /// Blocks written by them
+    pub coalesced_blocks: u64,
+    /// Whole blocks read with `CacheHint::DropAfterUse` from the device
+    /// without caching them, counted as misses too
+    pub bypassed: u64,
 }
 
 struct Buf {
     status: BufStatus,
     data: Vec<u8>,
+    /// Bumped on each write to the block
+    version: u64,
+    /// Being written back, see the module doc
+    flushing: bool,
+}
+
+impl Buf {
+    fn set_dirty(&mut self, block_id: BlockId) {
+        self.status = BufStatus::Dirty(block_id);
+        self.version = self.version.wrapping_add(1);
+    }
 }
 
 enum BufStatus {
@@ -30,46 +87,308 @@ impl<T: BlockDevice> BlockCache<T> {
             Mutex::new(Buf {
                 status: BufStatus::Unused,
                 data: vec![0; 1 << T::BLOCK_SIZE_LOG2 as usize],
+                version: 0,
+                flushing: false,
             })
         });
-        let lru = Mutex::new(LRU::new(capacity));
-        BlockCache { device, bufs, lru }
+        let lru = Mutex::new(LRU::new(capacity, capacity / DEFAULT_PINNED_FRACTION));
+        BlockCache {
+            device,
+            bufs,
+            lru,
+            max_write: DEFAULT_MAX_WRITE,
+            write_buffer: DEFAULT_MAX_WRITE,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+            coalesced_writes: AtomicU64::new(0),
+            coalesced_blocks: AtomicU64::new(0),
+            bypassed: AtomicU64::new(0),
+        }
+    }
+
+    /// Keep at most `blocks` buffers from eviction for `CacheHint::PinHot`,
+    /// a quarter of them by default. At least two buffers stay evictable.
+    pub fn with_max_pinned(mut self, blocks: usize) -> Self {
+        let lru = self.lru.get_mut();
+        lru.max_pinned = blocks.min(lru.prev.len().saturating_sub(2));
+        self
+    }
+
+    /// Buffers kept from eviction for `CacheHint::PinHot`
+    pub fn pinned(&self) -> usize {
+        self.lru.lock().pinned_count
+    }
+
+    /// Write adjacent dirty blocks by requests of at most `max_write` bytes,
+    /// copied to a buffer first. Larger than `write_buffer` bytes, they are
+    /// written one by one instead. Both are `DEFAULT_MAX_WRITE` by default.
+    pub fn with_coalescing(mut self, max_write: usize, write_buffer: usize) -> Self {
+        self.max_write = max_write;
+        self.write_buffer = write_buffer;
+        self
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            coalesced_writes: self.coalesced_writes.load(Ordering::Relaxed),
+            coalesced_blocks: self.coalesced_blocks.load(Ordering::Relaxed),
+            bypassed: self.bypassed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Cache a clean copy of block `block_id` read elsewhere, e.g. ahead of
+    /// use. Nothing is done if it is cached already, its copy may be newer.
+    pub fn warm(&self, block_id: BlockId, data: &[u8]) {
+        // as if never read ahead if a dirty block can not be evicted for it
+        let Ok(mut buf) = self.get_buf(block_id, CacheHint::Normal) else {
+            return;
+        };
+        if let BufStatus::Unused = buf.status {
+            let len = 1 << T::BLOCK_SIZE_LOG2 as usize;
+            buf.data.copy_from_slice(&data[..len]);
+            buf.status = BufStatus::Valid(block_id);
+        }
+    }
+
+    /// Lock buffer `i` once it is not being written back
+    fn lock_idle(&self, i: usize) -> MutexGuard<'_, Buf> {
+        loop {
+            let buf = self.bufs[i].lock();
+            if !buf.flushing {
+                return buf;
+            }
+            drop(buf);
+            spin_loop();
+        }
+    }
+
+    /// Most blocks written by one request
+    fn max_write_blocks(&self) -> usize {
+        (self.max_write >> T::BLOCK_SIZE_LOG2).max(1)
     }
 
-    /// Get a buffer for `block_id` with any status
-    fn get_buf(&self, block_id: BlockId) -> MutexGuard<Buf> {
-        let (i, buf) = self._get_buf(block_id);
-        self.lru.lock().visit(i);
-        buf
+    /// Get a buffer for `block_id` with any status, used as `hint` says
+    fn get_buf(&self, block_id: BlockId, hint: CacheHint) -> Result<MutexGuard<'_, Buf>> {
+        let (i, buf) = self._get_buf(block_id)?;
+        self.lru.lock().visit(i, hint);
+        Ok(buf)
     }
 
-    fn _get_buf(&self, block_id: BlockId) -> (usize, MutexGuard<Buf>) {
+    fn _get_buf(&self, block_id: BlockId) -> Result<(usize, MutexGuard<'_, Buf>)> {
         for (i, buf) in self.bufs.iter().enumerate() {
             if let Some(lock) = buf.try_lock() {
                 match lock.status {
-                    BufStatus::Valid(id) if id == block_id => return (i, lock),
-                    BufStatus::Dirty(id) if id == block_id => return (i, lock),
+                    BufStatus::Valid(id) | BufStatus::Dirty(id) if id == block_id => {
+                        self.hits.fetch_add(1, Ordering::Relaxed);
+                        crate::fs_event!(block = block_id, "cache hit");
+                        return Ok((i, lock));
+                    }
                     _ => {}
                 }
             }
         }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        crate::fs_event!(block = block_id, "cache miss");
         self.get_unused()
     }
 
-    /// Get an unused buffer
-    fn get_unused(&self) -> (usize, MutexGuard<Buf>) {
+    /// The buffer of `block_id` if cached, waiting for it if locked
+    fn cached(&self, block_id: BlockId) -> Option<MutexGuard<'_, Buf>> {
+        self.cached_at(block_id).map(|(_, buf)| buf)
+    }
+
+    /// `cached()` with the index of the buffer
+    fn cached_at(&self, block_id: BlockId) -> Option<(usize, MutexGuard<'_, Buf>)> {
+        (0..self.bufs.len())
+            .map(|i| (i, self.lock_idle(i)))
+            .find(|(_, buf)| match buf.status {
+                BufStatus::Valid(id) | BufStatus::Dirty(id) => id == block_id,
+                BufStatus::Unused => false,
+            })
+    }
+
+    /// Drop the cached copy in `buf`
+    fn invalidate(&self, buf: &mut Buf) {
+        buf.status = BufStatus::Unused;
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get an unused buffer, evicting the least recently used block. Fails
+    /// if it is dirty and can not be written back, then it stays dirty.
+    fn get_unused(&self) -> Result<(usize, MutexGuard<'_, Buf>)> {
         for (i, buf) in self.bufs.iter().enumerate() {
             if let Some(lock) = buf.try_lock() {
                 if let BufStatus::Unused = lock.status {
-                    return (i, lock);
+                    self.lru.lock().unpin(i);
+                    return Ok((i, lock));
                 }
             }
         }
         let victim_id = self.lru.lock().victim();
-        let mut victim = self.bufs[victim_id].lock();
-        self.write_back(&mut victim).expect("failed to write back");
+        let mut victim = self.lock_idle(victim_id);
+        if let BufStatus::Dirty(block_id) = victim.status {
+            // write back the dirty blocks next to it by the same request
+            victim.flushing = true;
+            drop(victim);
+            let mut blocks = self.take_adjacent(block_id);
+            blocks.push((block_id, victim_id));
+            self.flush(blocks)?;
+            victim = self.lock_idle(victim_id);
+            // in case it is written to meanwhile
+            self.write_back(&mut victim)?;
+        }
         victim.status = BufStatus::Unused;
-        (victim_id, victim)
+        self.lru.lock().unpin(victim_id);
+        Ok((victim_id, victim))
+    }
+
+    /// Mark as being written back the dirty blocks adjacent to `block_id`,
+    /// as many as fit in one request with it
+    fn take_adjacent(&self, block_id: BlockId) -> Vec<(BlockId, usize)> {
+        let max_blocks = self
+            .max_write_blocks()
+            .min(self.write_buffer >> T::BLOCK_SIZE_LOG2);
+        if max_blocks <= 1 {
+            return Vec::new();
+        }
+        let mut dirty = BTreeMap::new();
+        for (i, buf) in self.bufs.iter().enumerate() {
+            if let Some(buf) = buf.try_lock() {
+                match buf.status {
+                    BufStatus::Dirty(id) if !buf.flushing && id.abs_diff(block_id) < max_blocks => {
+                        dirty.insert(id, i);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let adjacent = |id: BlockId| dirty.get(&id).map(|&i| (id, i));
+        let below = (0..block_id).rev().map_while(adjacent);
+        let above = (block_id + 1..).map_while(adjacent);
+        let mut blocks = Vec::new();
+        for (id, i) in below.chain(above).take(max_blocks - 1) {
+            if let Some(mut buf) = self.bufs[i].try_lock() {
+                if matches!(buf.status, BufStatus::Dirty(now) if now == id) && !buf.flushing {
+                    buf.flushing = true;
+                    blocks.push((id, i));
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Write back `blocks` marked as being written back, adjacent ones by
+    /// one request, then unmark them
+    fn flush(&self, mut blocks: Vec<(BlockId, usize)>) -> Result<()> {
+        crate::fs_span!("cache_flush", blocks = blocks.len());
+        blocks.sort_unstable();
+        let max_blocks = self.max_write_blocks();
+        let mut result = Ok(());
+        let mut rest = &blocks[..];
+        while !rest.is_empty() {
+            let mut len = 1;
+            while len < rest.len().min(max_blocks) && rest[len].0 == rest[0].0 + len {
+                len += 1;
+            }
+            let (run, next) = rest.split_at(len);
+            rest = next;
+            result = match result {
+                Ok(()) => self.write_run(run),
+                Err(err) => {
+                    for &(_, i) in run {
+                        self.bufs[i].lock().flushing = false;
+                    }
+                    Err(err)
+                }
+            };
+        }
+        result
+    }
+
+    /// Write back a run of adjacent blocks, then unmark them, and mark clean
+    /// the ones not written to meanwhile
+    fn write_run(&self, run: &[(BlockId, usize)]) -> Result<()> {
+        let len = run.len() << T::BLOCK_SIZE_LOG2;
+        if run.len() == 1 || len > self.write_buffer {
+            let mut result = Ok(());
+            for &(_, i) in run {
+                let mut buf = self.bufs[i].lock();
+                buf.flushing = false;
+                if result.is_ok() {
+                    result = self.write_back(&mut buf);
+                }
+            }
+            return result;
+        }
+        // as the blocks are now
+        let mut data = Vec::with_capacity(len);
+        let mut versions = Vec::with_capacity(run.len());
+        for &(_, i) in run {
+            let buf = self.bufs[i].lock();
+            data.extend_from_slice(&buf.data);
+            versions.push(buf.version);
+        }
+        let result = self.device.write_blocks(run[0].0, &data);
+        if result.is_ok() {
+            self.coalesced_writes.fetch_add(1, Ordering::Relaxed);
+            self.coalesced_blocks
+                .fetch_add(run.len() as u64, Ordering::Relaxed);
+        }
+        for (&(block_id, i), version) in run.iter().zip(versions) {
+            let mut buf = self.bufs[i].lock();
+            buf.flushing = false;
+            if result.is_ok() && buf.version == version {
+                buf.status = BufStatus::Valid(block_id);
+            }
+        }
+        result
+    }
+
+    /// Get the buffer of `block_id`, reading it from device if not cached
+    fn get_valid_buf(&self, block_id: BlockId, hint: CacheHint) -> Result<MutexGuard<'_, Buf>> {
+        let mut buf = self.get_buf(block_id, hint)?;
+        if let BufStatus::Unused = buf.status {
+            // read from device
+            self.device.read_at(block_id, &mut buf.data)?;
+            buf.status = BufStatus::Valid(block_id);
+        }
+        Ok(buf)
+    }
+
+    /// Read block `block_id` whole for `CacheHint::DropAfterUse`: from its
+    /// buffer if cached, else from the device without caching it
+    fn read_uncached(&self, block_id: BlockId, buffer: &mut [u8]) -> Result<()> {
+        if let Some((i, buf)) = self.cached_at(block_id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.lru.lock().visit(i, CacheHint::DropAfterUse);
+            buffer.copy_from_slice(&buf.data);
+            return Ok(());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.bypassed.fetch_add(1, Ordering::Relaxed);
+        self.device.read_at(block_id, buffer)
+    }
+
+    /// Write `buffer` from `offset` of block `block_id` into its buffer,
+    /// used as `hint` says
+    fn write_cached(
+        &self,
+        block_id: BlockId,
+        offset: usize,
+        buffer: &[u8],
+        hint: CacheHint,
+    ) -> Result<()> {
+        let mut buf = match buffer.len() == 1 << T::BLOCK_SIZE_LOG2 as usize {
+            true => self.get_buf(block_id, hint)?,
+            false => self.get_valid_buf(block_id, hint)?,
+        };
+        buf.data[offset..offset + buffer.len()].copy_from_slice(buffer);
+        buf.set_dirty(block_id);
+        Ok(())
     }
 
     /// Write back data if buffer is dirty
@@ -83,8 +402,10 @@ impl<T: BlockDevice> BlockCache<T> {
 }
 
 impl<T: BlockDevice> Drop for BlockCache<T> {
+    /// Write back the dirty blocks, those failing to are lost: sync first to
+    /// see the error
     fn drop(&mut self) {
-        BlockDevice::sync(self).expect("failed to sync");
+        let _ = BlockDevice::sync(self);
     }
 }
 
@@ -92,61 +413,246 @@ impl<T: BlockDevice> BlockDevice for BlockCache<T> {
     const BLOCK_SIZE_LOG2: u8 = T::BLOCK_SIZE_LOG2;
 
     fn read_at(&self, block_id: BlockId, buffer: &mut [u8]) -> Result<()> {
-        let mut buf = self.get_buf(block_id);
-        match buf.status {
-            BufStatus::Unused => {
-                // read from device
-                self.device.read_at(block_id, &mut buf.data)?;
-                buf.status = BufStatus::Valid(block_id);
-            }
-            _ => {}
-        }
+        let buf = self.get_valid_buf(block_id, CacheHint::Normal)?;
         let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
         buffer[..len].copy_from_slice(&buf.data);
         Ok(())
     }
 
     fn write_at(&self, block_id: BlockId, buffer: &[u8]) -> Result<()> {
-        let mut buf = self.get_buf(block_id);
-        buf.status = BufStatus::Dirty(block_id);
         let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
-        buf.data.copy_from_slice(&buffer[..len]);
+        self.write_cached(block_id, 0, &buffer[..len], CacheHint::Normal)
+    }
+
+    /// Read inside the cached block
+    fn read_partial(&self, block_id: BlockId, offset: usize, buffer: &mut [u8]) -> Result<()> {
+        let buf = self.get_valid_buf(block_id, CacheHint::Normal)?;
+        buffer.copy_from_slice(&buf.data[offset..offset + buffer.len()]);
+        Ok(())
+    }
+
+    /// Modify the cached block in place, it is written back as a whole later
+    fn write_partial(&self, block_id: BlockId, offset: usize, buffer: &[u8]) -> Result<()> {
+        self.write_cached(block_id, offset, buffer, CacheHint::Normal)
+    }
+
+    /// Write back the cached block if dirty, then read from the device
+    fn read_direct(&self, block_id: BlockId, buffer: &mut [u8]) -> Result<()> {
+        if let Some(mut buf) = self.cached(block_id) {
+            self.write_back(&mut buf)?;
+        }
+        self.device.read_direct(block_id, buffer)
+    }
+
+    /// Write to the device, then drop the cached block
+    fn write_direct(&self, block_id: BlockId, buffer: &[u8]) -> Result<()> {
+        match self.cached(block_id) {
+            Some(mut buf) => {
+                // hold the buffer, so that it is not written back meanwhile
+                self.device.write_direct(block_id, buffer)?;
+                self.invalidate(&mut buf);
+                Ok(())
+            }
+            None => self.device.write_direct(block_id, buffer),
+        }
+    }
+
+    /// Read the blocks not cached, adjacent ones by one `read_blocks()` of at
+    /// most `max_write` bytes, and cache them by `warm()`
+    fn read_ahead(&self, block_id: BlockId, count: usize) -> Result<()> {
+        let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
+        let max_blocks = self.max_write_blocks();
+        let mut data = Vec::new();
+        let mut id = block_id;
+        while id < block_id + count {
+            if self.cached(id).is_some() {
+                id += 1;
+                continue;
+            }
+            let mut end = id + 1;
+            while end < block_id + count && end - id < max_blocks && self.cached(end).is_none() {
+                end += 1;
+            }
+            data.resize((end - id) * len, 0);
+            self.device.read_blocks(id, &mut data)?;
+            for (i, block) in data.chunks(len).enumerate() {
+                self.warm(id + i, block);
+            }
+            id = end;
+        }
         Ok(())
     }
 
+    /// Drop the cached blocks in the range, then zero them on the device
+    fn write_zeroes(&self, block_id: BlockId, count: usize) -> Result<()> {
+        let blocks = block_id..block_id + count;
+        for i in 0..self.bufs.len() {
+            let mut buf = self.lock_idle(i);
+            match buf.status {
+                BufStatus::Valid(id) | BufStatus::Dirty(id) if blocks.contains(&id) => {
+                    self.invalidate(&mut buf);
+                }
+                _ => {}
+            }
+        }
+        self.device.write_zeroes(block_id, count)
+    }
+
     fn sync(&self) -> Result<()> {
-        for buf in self.bufs.iter() {
-            self.write_back(&mut buf.lock())?;
+        let mut dirty = Vec::new();
+        for i in 0..self.bufs.len() {
+            let mut buf = self.lock_idle(i);
+            if let BufStatus::Dirty(block_id) = buf.status {
+                buf.flushing = true;
+                dirty.push((block_id, i));
+            }
         }
+        self.flush(dirty)?;
         self.device.sync()?;
         Ok(())
     }
+
+    fn is_read_only(&self) -> bool {
+        self.device.is_read_only()
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.device.size()
+    }
+
+    fn hinted(&self) -> Option<&dyn HintedDevice> {
+        Some(self)
+    }
+}
+
+/// The end of an I/O through blocks like `Device::read_at()` of a
+/// `BlockDevice`, after `done` bytes and `res` on the next block: `None` to
+/// go on
+fn end_short(done: usize, res: Result<()>) -> Option<Result<usize>> {
+    match res {
+        Ok(()) => None,
+        Err(DevError::OutOfRange) => Some(Ok(done)),
+        Err(_) if done > 0 => Some(Ok(done)),
+        Err(err) => Some(Err(err)),
+    }
+}
+
+impl<T: BlockDevice> HintedDevice for BlockCache<T> {
+    fn read_at_hinted(&self, offset: usize, buf: &mut [u8], hint: CacheHint) -> Result<usize> {
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: T::BLOCK_SIZE_LOG2,
+        };
+        for range in iter {
+            let done = range.origin_begin() - offset;
+            let buf = &mut buf[done..range.origin_end() - offset];
+            let res = match (range.is_full(), hint) {
+                (true, CacheHint::DropAfterUse) => self.read_uncached(range.block, buf),
+                _ => self.get_valid_buf(range.block, hint).map(|cached| {
+                    buf.copy_from_slice(&cached.data[range.begin..range.end]);
+                }),
+            };
+            if let Some(result) = end_short(done, res) {
+                return result;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn write_at_hinted(&self, offset: usize, buf: &[u8], hint: CacheHint) -> Result<usize> {
+        let iter = BlockIter {
+            begin: offset,
+            end: offset + buf.len(),
+            block_size_log2: T::BLOCK_SIZE_LOG2,
+        };
+        for range in iter {
+            let done = range.origin_begin() - offset;
+            let buf = &buf[done..range.origin_end() - offset];
+            let res = self.write_cached(range.block, range.begin, buf, hint);
+            if let Some(result) = end_short(done, res) {
+                return result;
+            }
+        }
+        Ok(buf.len())
+    }
 }
 
 /// Doubly circular linked list LRU manager
 struct LRU {
     prev: Vec<usize>,
     next: Vec<usize>,
+    /// kept from eviction by `CacheHint::PinHot`
+    pinned: Vec<bool>,
+    pinned_count: usize,
+    max_pinned: usize,
+    /// used as `CacheHint::Metadata` since last reaching the tail
+    second_chance: Vec<bool>,
 }
 
 impl LRU {
-    fn new(size: usize) -> Self {
+    fn new(size: usize, max_pinned: usize) -> Self {
         LRU {
             prev: (size - 1..size).chain(0..size - 1).collect(),
             next: (1..size).chain(0..1).collect(),
+            pinned: vec![false; size],
+            pinned_count: 0,
+            max_pinned: max_pinned.min(size.saturating_sub(2)),
+            second_chance: vec![false; size],
         }
     }
-    /// Visit element `id`, move it to head.
-    fn visit(&mut self, id: usize) {
+    /// Visit element `id` as `hint` says: move it to head, or to tail for
+    /// `DropAfterUse`.
+    fn visit(&mut self, id: usize, hint: CacheHint) {
         if id == 0 || id >= self.prev.len() {
             return;
         }
         self._list_remove(id);
-        self._list_insert_head(id);
+        match hint {
+            CacheHint::DropAfterUse => self._list_insert_tail(id),
+            _ => self._list_insert_head(id),
+        }
+        match hint {
+            CacheHint::PinHot if !self.pinned[id] && self.pinned_count < self.max_pinned => {
+                self.pinned[id] = true;
+                self.pinned_count += 1;
+            }
+            CacheHint::Metadata => self.second_chance[id] = true,
+            _ => {}
+        }
     }
-    /// Get a victim at tail.
-    fn victim(&self) -> usize {
-        self.prev[0]
+    /// Forget the hints of element `id`, which gets another block
+    fn unpin(&mut self, id: usize) {
+        if self.pinned[id] {
+            self.pinned[id] = false;
+            self.pinned_count -= 1;
+        }
+        self.second_chance[id] = false;
+    }
+    /// Get a victim at tail, skipping pinned elements, and moving to head
+    /// once those with a second chance.
+    fn victim(&mut self) -> usize {
+        let mut id = self.prev[0];
+        while id != 0 {
+            let prev = self.prev[id];
+            if self.second_chance[id] && !self.pinned[id] {
+                self.second_chance[id] = false;
+                self._list_remove(id);
+                self._list_insert_head(id);
+            } else if !self.pinned[id] {
+                return id;
+            }
+            id = prev;
+        }
+        // all had a second chance, used up now
+        let mut id = self.prev[0];
+        while id != 0 && self.pinned[id] {
+            id = self.prev[id];
+        }
+        match id {
+            0 => self.prev[0],
+            id => id,
+        }
     }
     fn _list_remove(&mut self, id: usize) {
         let prev = self.prev[id];
@@ -161,4 +667,319 @@ impl LRU {
         self.next[0] = id;
         self.prev[head] = id;
     }
+    fn _list_insert_tail(&mut self, id: usize) {
+        let tail = self.prev[0];
+        self.next[id] = 0;
+        self.prev[id] = tail;
+        self.prev[0] = id;
+        self.next[tail] = id;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    const BLOCK: usize = 512;
+
+    /// Device in memory recording its reads and writes as (first block,
+    /// blocks), writes of several blocks wait while `gate` is closed, and
+    /// writes fail while `broken`
+    #[derive(Default)]
+    struct Recorder {
+        data: Mutex<Vec<u8>>,
+        reads: Mutex<Vec<(BlockId, usize)>>,
+        writes: Mutex<Vec<(BlockId, usize)>>,
+        gate: AtomicBool,
+        waiting: AtomicBool,
+        broken: AtomicBool,
+    }
+
+    impl Recorder {
+        fn new(blocks: usize) -> Arc<Self> {
+            let recorder = Recorder::default();
+            *recorder.data.lock() = vec![0; blocks * BLOCK];
+            Arc::new(recorder)
+        }
+
+        fn block(&self, block_id: BlockId) -> Vec<u8> {
+            self.data.lock()[block_id * BLOCK..(block_id + 1) * BLOCK].to_vec()
+        }
+
+        fn take_reads(&self) -> Vec<(BlockId, usize)> {
+            core::mem::take(&mut *self.reads.lock())
+        }
+
+        fn take_writes(&self) -> Vec<(BlockId, usize)> {
+            core::mem::take(&mut *self.writes.lock())
+        }
+    }
+
+    impl BlockDevice for Arc<Recorder> {
+        const BLOCK_SIZE_LOG2: u8 = 9;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            self.read_blocks(block_id, &mut buf[..BLOCK])
+        }
+        fn read_blocks(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            self.reads.lock().push((block_id, buf.len() / BLOCK));
+            buf.copy_from_slice(&self.data.lock()[block_id * BLOCK..][..buf.len()]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            self.write_blocks(block_id, &buf[..BLOCK])
+        }
+        fn write_blocks(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            if self.broken.load(Ordering::SeqCst) {
+                return Err(DevError::IoError);
+            }
+            if buf.len() > BLOCK {
+                self.waiting.store(true, Ordering::SeqCst);
+                while self.gate.load(Ordering::SeqCst) {
+                    thread::yield_now();
+                }
+                self.waiting.store(false, Ordering::SeqCst);
+            }
+            self.writes.lock().push((block_id, buf.len() / BLOCK));
+            self.data.lock()[block_id * BLOCK..][..buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Dirty blocks 10..266 and a few others
+    fn dirty(cache: &BlockCache<Arc<Recorder>>) -> Vec<BlockId> {
+        let blocks: Vec<_> = (10..266).chain([300, 302, 400]).collect();
+        for &id in blocks.iter() {
+            BlockDevice::write_at(cache, id, &[id as u8; BLOCK]).unwrap();
+        }
+        blocks
+    }
+
+    #[test]
+    fn coalesced_sync() {
+        let recorder = Recorder::new(512);
+        let cache = BlockCache::new(recorder.clone(), 300);
+        let blocks = dirty(&cache);
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(
+            recorder.take_writes(),
+            [(10, 256), (300, 1), (302, 1), (400, 1)]
+        );
+        for &id in blocks.iter() {
+            assert_eq!(recorder.block(id), [id as u8; BLOCK]);
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.coalesced_writes, stats.coalesced_blocks), (1, 256));
+        // all clean
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(recorder.take_writes(), []);
+
+        // split by the max size of a request
+        let cache = BlockCache::new(recorder.clone(), 300).with_coalescing(100 * BLOCK, 1 << 20);
+        dirty(&cache);
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(
+            recorder.take_writes(),
+            [
+                (10, 100),
+                (110, 100),
+                (210, 56),
+                (300, 1),
+                (302, 1),
+                (400, 1)
+            ]
+        );
+
+        // one by one over the write buffer
+        let cache = BlockCache::new(recorder.clone(), 300).with_coalescing(1 << 20, 64 * BLOCK);
+        let blocks = dirty(&cache);
+        BlockDevice::sync(&cache).unwrap();
+        let writes: Vec<_> = blocks.iter().map(|&id| (id, 1)).collect();
+        assert_eq!(recorder.take_writes(), writes);
+        assert_eq!(cache.stats().coalesced_writes, 0);
+    }
+
+    #[test]
+    fn coalesced_eviction() {
+        let recorder = Recorder::new(512);
+        let cache = BlockCache::new(recorder.clone(), 8);
+        for id in 20..28 {
+            BlockDevice::write_at(&cache, id, &[id as u8; BLOCK]).unwrap();
+        }
+        let mut buf = [0; BLOCK];
+        BlockDevice::read_at(&cache, 100, &mut buf).unwrap();
+        assert_eq!(recorder.take_writes(), [(20, 8)]);
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(recorder.take_writes(), []);
+    }
+
+    #[test]
+    fn failed_eviction() {
+        let recorder = Recorder::new(512);
+        let cache = BlockCache::new(recorder.clone(), 8);
+        for id in 20..28 {
+            BlockDevice::write_at(&cache, id, &[id as u8; BLOCK]).unwrap();
+        }
+        recorder.broken.store(true, Ordering::SeqCst);
+        let mut buf = [0; BLOCK];
+        assert_eq!(
+            BlockDevice::read_at(&cache, 100, &mut buf),
+            Err(DevError::IoError)
+        );
+        assert_eq!(
+            BlockDevice::write_at(&cache, 100, &[0xff; BLOCK]),
+            Err(DevError::IoError)
+        );
+        // the victims are still dirty, and written once the device is back
+        recorder.broken.store(false, Ordering::SeqCst);
+        BlockDevice::sync(&cache).unwrap();
+        for id in 20..28 {
+            assert_eq!(recorder.block(id), [id as u8; BLOCK]);
+        }
+    }
+
+    #[test]
+    fn written_during_sync() {
+        let recorder = Recorder::new(512);
+        let cache = Arc::new(BlockCache::new(recorder.clone(), 300));
+        dirty(&cache);
+        recorder.gate.store(true, Ordering::SeqCst);
+        let sync = {
+            let cache = cache.clone();
+            thread::spawn(move || BlockDevice::sync(&*cache))
+        };
+        while !recorder.waiting.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+        BlockDevice::write_at(&*cache, 20, &[0xff; BLOCK]).unwrap();
+        recorder.gate.store(false, Ordering::SeqCst);
+        sync.join().unwrap().unwrap();
+        // written as it was when the request was made, and dirty again
+        assert_eq!(recorder.take_writes()[0], (10, 256));
+        assert_eq!(recorder.block(20), [20; BLOCK]);
+        BlockDevice::sync(&*cache).unwrap();
+        assert_eq!(recorder.take_writes(), [(20, 1)]);
+        assert_eq!(recorder.block(20), [0xff; BLOCK]);
+    }
+
+    #[test]
+    fn read_ahead() {
+        let recorder = Recorder::new(512);
+        for id in 0..512 {
+            recorder.data.lock()[id * BLOCK..][..BLOCK].fill(id as u8);
+        }
+        let cache = BlockCache::new(recorder.clone(), 300).with_coalescing(100 * BLOCK, 1 << 20);
+        let mut buf = [0; BLOCK];
+        BlockDevice::read_at(&cache, 20, &mut buf).unwrap();
+        BlockDevice::write_at(&cache, 30, &[0xff; BLOCK]).unwrap();
+        recorder.take_reads();
+        // around the cached blocks, split by the max size of a request
+        Device::read_ahead(&cache, 10 * BLOCK + 7, 150 * BLOCK).unwrap();
+        assert_eq!(
+            recorder.take_reads(),
+            [(10, 10), (21, 9), (31, 100), (131, 30)]
+        );
+        for id in 10..161 {
+            BlockDevice::read_at(&cache, id, &mut buf).unwrap();
+            let expected = if id == 30 { 0xff } else { id as u8 };
+            assert_eq!(buf, [expected; BLOCK]);
+        }
+        assert_eq!(recorder.take_reads(), []);
+
+        // a block cached meanwhile is kept
+        cache.warm(30, &[0; BLOCK]);
+        BlockDevice::read_at(&cache, 30, &mut buf).unwrap();
+        assert_eq!(buf, [0xff; BLOCK]);
+    }
+
+    /// Hits on blocks 0..16 read again after streaming 64 blocks through a
+    /// cache of 32 with `hint`
+    fn hot_hits(hint: CacheHint) -> u64 {
+        let recorder = Recorder::new(512);
+        let cache = BlockCache::new(recorder, 32);
+        let mut buf = [0; BLOCK];
+        // the first buffer is never evicted
+        BlockDevice::read_at(&cache, 500, &mut buf).unwrap();
+        for id in 0..16 {
+            BlockDevice::read_at(&cache, id, &mut buf).unwrap();
+        }
+        let mut stream = [0; 8 * BLOCK];
+        for offset in (100 * BLOCK..164 * BLOCK).step_by(stream.len()) {
+            assert_eq!(
+                cache.read_at_hinted(offset, &mut stream, hint),
+                Ok(stream.len())
+            );
+        }
+        let before = cache.stats().hits;
+        for id in 0..16 {
+            BlockDevice::read_at(&cache, id, &mut buf).unwrap();
+        }
+        cache.stats().hits - before
+    }
+
+    #[test]
+    fn drop_after_use_keeps_hot_set() {
+        assert_eq!(hot_hits(CacheHint::Normal), 0);
+        assert_eq!(hot_hits(CacheHint::DropAfterUse), 16);
+
+        // read whole, not cached; in part, cached but evicted first
+        let recorder = Recorder::new(512);
+        let cache = BlockCache::new(recorder.clone(), 32);
+        let mut buf = [0; 2 * BLOCK];
+        cache
+            .read_at_hinted(BLOCK / 2, &mut buf[..BLOCK], CacheHint::DropAfterUse)
+            .unwrap();
+        assert_eq!(cache.stats().bypassed, 0);
+        cache
+            .read_at_hinted(10 * BLOCK, &mut buf, CacheHint::DropAfterUse)
+            .unwrap();
+        assert_eq!(cache.stats().bypassed, 2);
+        recorder.take_reads();
+        cache
+            .read_at_hinted(0, &mut buf, CacheHint::DropAfterUse)
+            .unwrap();
+        assert_eq!(recorder.take_reads(), []);
+    }
+
+    #[test]
+    fn pin_hot_survives_thrashing() {
+        let recorder = Recorder::new(512);
+        let cache = BlockCache::new(recorder.clone(), 32).with_max_pinned(4);
+        let mut buf = [0; BLOCK];
+        // the first buffer is never evicted
+        BlockDevice::read_at(&cache, 500, &mut buf).unwrap();
+        // only the first 4 are pinned
+        for id in 0..6 {
+            cache
+                .read_at_hinted(id * BLOCK, &mut buf, CacheHint::PinHot)
+                .unwrap();
+        }
+        assert_eq!(cache.pinned(), 4);
+        for id in 100..300 {
+            BlockDevice::read_at(&cache, id, &mut buf).unwrap();
+        }
+        recorder.take_reads();
+        for id in 0..6 {
+            BlockDevice::read_at(&cache, id, &mut buf).unwrap();
+        }
+        assert_eq!(recorder.take_reads(), [(4, 1), (5, 1)]);
+
+        // a dirty pinned block is written back on sync, and stays cached
+        cache
+            .write_at_hinted(0, &[0xff; BLOCK], CacheHint::PinHot)
+            .unwrap();
+        BlockDevice::sync(&cache).unwrap();
+        assert_eq!(recorder.block(0), [0xff; BLOCK]);
+        for id in 100..300 {
+            BlockDevice::read_at(&cache, id, &mut buf).unwrap();
+        }
+        recorder.take_reads();
+        BlockDevice::read_at(&cache, 0, &mut buf).unwrap();
+        assert_eq!(recorder.take_reads(), []);
+    }
 }
@@ -1,8 +1,42 @@
 use crate::util::*;
 use crate::vfs::Timespec;
+use alloc::vec::Vec;
 
 pub mod block_cache;
 pub mod std_impl;
+pub mod throttle;
+pub mod timed;
+pub mod wear;
+pub mod window;
+
+pub use self::throttle::{ThrottleConfig, ThrottleStats, ThrottledDevice, LATENCY_BUCKETS_US};
+pub use self::timed::{AsyncDevice, TimedConfig, TimedDevice, Timer};
+pub use self::wear::{WearHook, WearTrackingDevice};
+pub use self::window::WindowedDevice;
+
+/// How the cache of a device may keep the blocks of a request, see
+/// `Device::hinted()`
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum CacheHint {
+    /// Kept like any other
+    #[default]
+    Normal,
+    /// Used once, e.g. by streaming: evicted first, and whole blocks read
+    /// are not cached at all
+    DropAfterUse,
+    /// Used again and again: kept from eviction, up to a bound of the cache
+    PinHot,
+    /// Metadata of a fs: evicted only after going once through the cache
+    /// unused
+    Metadata,
+}
+
+/// Reads and writes with a `CacheHint`, returning like `Device::read_at()`
+/// and `write_at()`, see `Device::hinted()`
+pub trait HintedDevice: Send + Sync {
+    fn read_at_hinted(&self, offset: usize, buf: &mut [u8], hint: CacheHint) -> Result<usize>;
+    fn write_at_hinted(&self, offset: usize, buf: &[u8], hint: CacheHint) -> Result<usize>;
+}
 
 /// A current time provider
 pub trait TimeProvider: Send + Sync {
@@ -10,98 +44,346 @@ pub trait TimeProvider: Send + Sync {
 }
 
 /// Interface for FS to read & write
+///
+/// Reads and writes return like those of `vfs::INode`: `Ok(n)` short of
+/// `buf.len()` transferred exactly `n` bytes, `Ok(0)` from a read is the
+/// end of media, and an error transferred nothing.
 pub trait Device: Send + Sync {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
     fn sync(&self) -> Result<()>;
+    /// Whether the device rejects all writes, e.g. a write-protected SD card
+    fn is_read_only(&self) -> bool {
+        false
+    }
+    /// Size in bytes, `None` if unknown or growing on write like an image file
+    fn size(&self) -> Option<usize> {
+        None
+    }
+    /// Fill `len` bytes from `offset` with zeros, return the number of bytes
+    /// written, short if an error follows some progress. Devices with a native write-zeroes or discard should override
+    /// it, by default zeros are written a chunk at a time.
+    fn write_zeros(&self, offset: usize, len: usize) -> Result<usize> {
+        static ZEROS: [u8; ZEROS_CHUNK] = [0; ZEROS_CHUNK];
+        let mut written = 0;
+        while written < len {
+            let chunk = (len - written).min(ZEROS_CHUNK);
+            let n = match self.write_at(offset + written, &ZEROS[..chunk]) {
+                Ok(n) => n,
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err),
+            };
+            written += n;
+            if n < chunk {
+                break;
+            }
+        }
+        Ok(written)
+    }
+    /// Read like `read_at()`, bypassing any cache and seeing data written
+    /// through the cache. By default it is `read_at()`.
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.read_at(offset, buf)
+    }
+    /// Write like `write_at()`, bypassing any cache and dropping the cached
+    /// copies. By default it is `write_at()`.
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.write_at(offset, buf)
+    }
+    /// Read like `read_at()` for latency-sensitive metadata, which devices
+    /// scheduling I/O may serve before bulk data. By default it is `read_at()`.
+    fn read_at_prio(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.read_at(offset, buf)
+    }
+    /// Where a fs notes the file data it writes, if the device tracks wear
+    /// like `WearTrackingDevice`. By default `None`.
+    fn wear_hook(&self) -> Option<&dyn WearHook> {
+        None
+    }
+    /// Read `len` bytes from `offset` into the cache of the device ahead of
+    /// use, by as few requests as it can. Devices without a cache have
+    /// nothing to do, which is the default.
+    fn read_ahead(&self, _offset: usize, _len: usize) -> Result<()> {
+        Ok(())
+    }
+    /// Reads and writes taking a `CacheHint`, if the device has a cache
+    /// using them like `BlockCache`. By default `None`, hints are dropped.
+    fn hinted(&self) -> Option<&dyn HintedDevice> {
+        None
+    }
 }
 
+/// Size of the zero buffer of the default `Device::write_zeros()`
+const ZEROS_CHUNK: usize = 4096;
+
 /// Device which can only R/W in blocks
 pub trait BlockDevice: Send + Sync {
     const BLOCK_SIZE_LOG2: u8;
     fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()>;
     fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()>;
     fn sync(&self) -> Result<()>;
+    /// Whether the device rejects all writes, e.g. a write-protected SD card
+    fn is_read_only(&self) -> bool {
+        false
+    }
+    /// Size in bytes, `None` if unknown or growing on write like an image file
+    fn size(&self) -> Option<usize> {
+        None
+    }
+    /// Read `buf.len()` bytes from `offset` inside block `block_id`
+    fn read_partial(&self, block_id: BlockId, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let mut local = [0u8; 1 << 10];
+        let mut heap = Vec::new();
+        let block_buf = block_buf::<Self>(&mut local, &mut heap);
+        // Read to local buf first
+        self.read_at(block_id, block_buf)?;
+        // Copy to target buf then
+        buf.copy_from_slice(&block_buf[offset..offset + buf.len()]);
+        Ok(())
+    }
+    /// Write `buf` to `offset` inside block `block_id`, keeping the rest of block
+    fn write_partial(&self, block_id: BlockId, offset: usize, buf: &[u8]) -> Result<()> {
+        let mut local = [0u8; 1 << 10];
+        let mut heap = Vec::new();
+        let block_buf = block_buf::<Self>(&mut local, &mut heap);
+        // Read to local buf first
+        self.read_at(block_id, block_buf)?;
+        // Write to local buf
+        block_buf[offset..offset + buf.len()].copy_from_slice(buf);
+        // Write back to target buf
+        self.write_at(block_id, block_buf)
+    }
+    /// Read block `block_id` bypassing any cache, by default `read_at()`
+    fn read_direct(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+        self.read_at(block_id, buf)
+    }
+    /// Write block `block_id` bypassing any cache, by default `write_at()`
+    fn write_direct(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+        self.write_at(block_id, buf)
+    }
+    /// Write whole blocks from `block_id` by one request, `buf.len()` is a
+    /// multiple of the block size. By default they are written one by one.
+    fn write_blocks(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+        let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
+        for (i, block) in buf.chunks(len).enumerate() {
+            self.write_at(block_id + i, block)?;
+        }
+        Ok(())
+    }
+    /// Read whole blocks from `block_id` by one request, `buf.len()` is a
+    /// multiple of the block size. By default they are read one by one.
+    fn read_blocks(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+        let len = 1 << Self::BLOCK_SIZE_LOG2 as usize;
+        for (i, block) in buf.chunks_mut(len).enumerate() {
+            self.read_at(block_id + i, block)?;
+        }
+        Ok(())
+    }
+    /// Read `count` blocks from `block_id` into the cache of the device ahead
+    /// of use, see `Device::read_ahead()`. By default nothing is done.
+    fn read_ahead(&self, _block_id: BlockId, _count: usize) -> Result<()> {
+        Ok(())
+    }
+    /// Zero `count` whole blocks from `block_id`, e.g. by a native
+    /// write-zeroes command. By default they are written one by one.
+    fn write_zeroes(&self, block_id: BlockId, count: usize) -> Result<()> {
+        let mut local = [0u8; 1 << 10];
+        let mut heap = Vec::new();
+        let zeros = block_buf::<Self>(&mut local, &mut heap);
+        for id in block_id..block_id + count {
+            self.write_at(id, zeros)?;
+        }
+        Ok(())
+    }
+    /// See `Device::hinted()`, by default `None`
+    fn hinted(&self) -> Option<&dyn HintedDevice> {
+        None
+    }
+}
+
+/// A buffer of one block, on stack if small enough
+fn block_buf<'a, T: BlockDevice + ?Sized>(
+    local: &'a mut [u8; 1 << 10],
+    heap: &'a mut Vec<u8>,
+) -> &'a mut [u8] {
+    let len = 1 << T::BLOCK_SIZE_LOG2 as usize;
+    if len <= local.len() {
+        &mut local[..len]
+    } else {
+        heap.resize(len, 0);
+        heap
+    }
 }
 
 /// The error type for device.
-#[derive(Debug, PartialEq, Eq)]
-pub struct DevError;
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DevError {
+    /// Generic I/O failure
+    IoError,
+    /// Access beyond the end of the media
+    OutOfRange,
+    /// The media is write-protected
+    WriteProtected,
+    /// The device did not complete the request in time, see `TimedDevice`
+    TimedOut,
+}
 
 /// A specialized `Result` type for device.
 pub type Result<T> = core::result::Result<T, DevError>;
 
 pub type BlockId = usize;
 
-macro_rules! try0 {
-    ($len:expr, $res:expr) => {
-        if $res.is_err() {
-            return Ok($len);
+/// End the I/O short with the `$done` bytes transferred so far if `$res` is
+/// an error, the error itself is returned only if nothing was transferred
+macro_rules! try_short {
+    ($done:expr, $res:expr) => {
+        match $res {
+            Ok(()) => {}
+            Err(_) if $done > 0 => return Ok($done),
+            Err(err) => return Err(err),
         }
     };
 }
 
 /// Helper functions to R/W BlockDevice in bytes
+///
+/// An error on a block ends the I/O short, returning the bytes of the blocks
+/// before it. It is returned only if it is on the first block, except that
+/// reading from past the end of media returns `Ok(0)`.
 impl<T: BlockDevice> Device for T {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
-        let iter = BlockIter {
-            begin: offset,
-            end: offset + buf.len(),
-            block_size_log2: Self::BLOCK_SIZE_LOG2,
-        };
-
-        // For each block
-        for range in iter {
-            let len = range.origin_begin() - offset;
-            let buf = &mut buf[range.origin_begin() - offset..range.origin_end() - offset];
-            if range.is_full() {
-                // Read to target buf directly
-                try0!(len, BlockDevice::read_at(self, range.block, buf));
-            } else {
-                use core::mem::MaybeUninit;
-                let mut block_buf: [u8; 1 << 10] = unsafe { MaybeUninit::uninit().assume_init() };
-                assert!(Self::BLOCK_SIZE_LOG2 <= 10);
-                // Read to local buf first
-                try0!(len, BlockDevice::read_at(self, range.block, &mut block_buf));
-                // Copy to target buf then
-                buf.copy_from_slice(&mut block_buf[range.begin..range.end]);
-            }
-        }
-        Ok(buf.len())
+        read_blocks(self, offset, buf, false)
     }
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        write_blocks(self, offset, buf, false)
+    }
+
+    /// Whole blocks go to `read_direct()`, partial ones to `read_partial()`
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        read_blocks(self, offset, buf, true)
+    }
+
+    /// Whole blocks go to `write_direct()`, partial ones to `write_partial()`
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        write_blocks(self, offset, buf, true)
+    }
+
+    /// Whole blocks in the range are zeroed by one `write_zeroes()`, none of
+    /// them counted as written if it fails
+    fn write_zeros(&self, offset: usize, len: usize) -> Result<usize> {
         let iter = BlockIter {
             begin: offset,
-            end: offset + buf.len(),
+            end: offset + len,
             block_size_log2: Self::BLOCK_SIZE_LOG2,
         };
-
-        // For each block
+        let mut local = [0u8; 1 << 10];
+        let mut heap = Vec::new();
+        let zeros = block_buf::<Self>(&mut local, &mut heap);
+        // (bytes before it, first block, number of blocks) of whole blocks
+        // not zeroed yet
+        let mut run = (0, 0, 0);
         for range in iter {
-            let len = range.origin_begin() - offset;
-            let buf = &buf[range.origin_begin() - offset..range.origin_end() - offset];
+            let done = range.origin_begin() - offset;
             if range.is_full() {
-                // Write to target buf directly
-                try0!(len, BlockDevice::write_at(self, range.block, buf));
-            } else {
-                use core::mem::MaybeUninit;
-                let mut block_buf: [u8; 1 << 10] = unsafe { MaybeUninit::uninit().assume_init() };
-                assert!(Self::BLOCK_SIZE_LOG2 <= 10);
-                // Read to local buf first
-                try0!(len, BlockDevice::read_at(self, range.block, &mut block_buf));
-                // Write to local buf
-                block_buf[range.begin..range.end].copy_from_slice(buf);
-                // Write back to target buf
-                try0!(len, BlockDevice::write_at(self, range.block, &block_buf));
+                if run.2 == 0 {
+                    run = (done, range.block, 0);
+                }
+                run.2 += 1;
+                continue;
             }
+            if run.2 != 0 {
+                try_short!(run.0, self.write_zeroes(run.1, run.2));
+                run.2 = 0;
+            }
+            let res = self.write_partial(range.block, range.begin, &zeros[..range.len()]);
+            try_short!(done, res);
+        }
+        if run.2 != 0 {
+            try_short!(run.0, self.write_zeroes(run.1, run.2));
         }
-        Ok(buf.len())
+        Ok(len)
     }
 
     fn sync(&self) -> Result<()> {
         BlockDevice::sync(self)
     }
+
+    fn is_read_only(&self) -> bool {
+        BlockDevice::is_read_only(self)
+    }
+
+    fn size(&self) -> Option<usize> {
+        BlockDevice::size(self)
+    }
+
+    /// The blocks overlapping the range go to one `BlockDevice::read_ahead()`
+    fn read_ahead(&self, offset: usize, len: usize) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let first = offset >> Self::BLOCK_SIZE_LOG2;
+        let last = (offset + len - 1) >> Self::BLOCK_SIZE_LOG2;
+        BlockDevice::read_ahead(self, first, last - first + 1)
+    }
+
+    fn hinted(&self) -> Option<&dyn HintedDevice> {
+        BlockDevice::hinted(self)
+    }
+}
+
+/// `Device::read_at()` of a `BlockDevice`, by `read_direct()` if `direct`
+fn read_blocks<T: BlockDevice>(
+    dev: &T,
+    offset: usize,
+    buf: &mut [u8],
+    direct: bool,
+) -> Result<usize> {
+    let iter = BlockIter {
+        begin: offset,
+        end: offset + buf.len(),
+        block_size_log2: T::BLOCK_SIZE_LOG2,
+    };
+
+    // For each block
+    for range in iter {
+        let done = range.origin_begin() - offset;
+        let buf = &mut buf[done..range.origin_end() - offset];
+        let res = match (range.is_full(), direct) {
+            // Read to target buf directly
+            (true, false) => BlockDevice::read_at(dev, range.block, buf),
+            (true, true) => dev.read_direct(range.block, buf),
+            _ => dev.read_partial(range.block, range.begin, buf),
+        };
+        if res == Err(DevError::OutOfRange) {
+            return Ok(done);
+        }
+        try_short!(done, res);
+    }
+    Ok(buf.len())
+}
+
+/// `Device::write_at()` of a `BlockDevice`, by `write_direct()` if `direct`
+fn write_blocks<T: BlockDevice>(dev: &T, offset: usize, buf: &[u8], direct: bool) -> Result<usize> {
+    let iter = BlockIter {
+        begin: offset,
+        end: offset + buf.len(),
+        block_size_log2: T::BLOCK_SIZE_LOG2,
+    };
+
+    // For each block
+    for range in iter {
+        let done = range.origin_begin() - offset;
+        let buf = &buf[done..range.origin_end() - offset];
+        let res = match (range.is_full(), direct) {
+            // Write to target buf directly
+            (true, false) => BlockDevice::write_at(dev, range.block, buf),
+            (true, true) => dev.write_direct(range.block, buf),
+            _ => dev.write_partial(range.block, range.begin, buf),
+        };
+        try_short!(done, res);
+    }
+    Ok(buf.len())
 }
 
 #[cfg(test)]
@@ -113,7 +395,7 @@ mod test {
         const BLOCK_SIZE_LOG2: u8 = 2;
         fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevError::OutOfRange);
             }
             let begin = block_id << 2;
             buf[..4].copy_from_slice(&mut self.lock().unwrap()[begin..begin + 4]);
@@ -121,7 +403,7 @@ mod test {
         }
         fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevError::OutOfRange);
             }
             let begin = block_id << 2;
             self.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
@@ -132,6 +414,57 @@ mod test {
         }
     }
 
+    /// Records the calls of `write_zeroes()`
+    struct ZeroesDevice {
+        data: Mutex<[u8; 64]>,
+        calls: Mutex<Vec<(BlockId, usize)>>,
+    }
+
+    impl BlockDevice for ZeroesDevice {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            let begin = block_id << 2;
+            buf[..4].copy_from_slice(&self.data.lock().unwrap()[begin..begin + 4]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            let begin = block_id << 2;
+            self.data.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+        fn write_zeroes(&self, block_id: BlockId, count: usize) -> Result<()> {
+            self.calls.lock().unwrap().push((block_id, count));
+            let begin = block_id << 2;
+            self.data.lock().unwrap()[begin..begin + count * 4].fill(0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_zeros() {
+        // without a fast path
+        let buf: Mutex<[u8; 16]> = Mutex::new([1; 16]);
+        assert_eq!(Device::write_zeros(&buf, 3, 6), Ok(6));
+        assert_eq!(
+            *buf.lock().unwrap(),
+            [1, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1]
+        );
+
+        // whole blocks are zeroed by one call
+        let dev = ZeroesDevice {
+            data: Mutex::new([1; 64]),
+            calls: Mutex::new(Vec::new()),
+        };
+        assert_eq!(Device::write_zeros(&dev, 2, 40), Ok(40));
+        assert_eq!(*dev.calls.lock().unwrap(), [(1, 9)]);
+        let data = dev.data.lock().unwrap();
+        assert!(data[2..42].iter().all(|&b| b == 0));
+        assert!(data[..2].iter().chain(&data[42..]).all(|&b| b == 1));
+    }
+
     #[test]
     fn read() {
         let buf: Mutex<[u8; 16]> =
@@ -177,10 +510,79 @@ mod test {
 
         // all outside
         let ret = Device::write_at(&buf, 16, &res);
-        assert_eq!(ret, Ok(0));
+        assert_eq!(ret, Err(DevError::OutOfRange));
         assert_eq!(
             *buf.lock().unwrap(),
             [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 3, 4, 5, 6, 7]
         );
     }
+
+    /// 4 byte blocks, failing on block `bad`
+    struct FailingDevice {
+        data: Mutex<[u8; 32]>,
+        bad: BlockId,
+    }
+
+    impl BlockDevice for FailingDevice {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            if block_id == self.bad {
+                return Err(DevError::IoError);
+            }
+            let begin = block_id << 2;
+            buf[..4].copy_from_slice(&self.data.lock().unwrap()[begin..begin + 4]);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
+            if block_id == self.bad {
+                return Err(DevError::IoError);
+            }
+            let begin = block_id << 2;
+            self.data.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
+            Ok(())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_on_error() {
+        let dev = FailingDevice {
+            data: Mutex::new([7; 32]),
+            bad: 3,
+        };
+        let mut buf = [0u8; 16];
+        // the bytes of blocks 0 to 2 from offset 1
+        assert_eq!(Device::read_at(&dev, 1, &mut buf), Ok(11));
+        assert_eq!(Device::read_at(&dev, 11, &mut buf), Ok(1));
+        assert_eq!(Device::read_at(&dev, 8, &mut buf), Ok(4));
+        assert_eq!(Device::read_at(&dev, 12, &mut buf), Err(DevError::IoError));
+        assert_eq!(
+            Device::read_at(&dev, 13, &mut buf[..2]),
+            Err(DevError::IoError)
+        );
+        // nothing to transfer
+        assert_eq!(Device::read_at(&dev, 12, &mut buf[..0]), Ok(0));
+
+        assert_eq!(Device::write_at(&dev, 6, &[1; 16]), Ok(6));
+        assert_eq!(Device::write_at(&dev, 12, &[1; 16]), Err(DevError::IoError));
+        assert_eq!(
+            dev.data.lock().unwrap()[..16],
+            [7, 7, 7, 7, 7, 7, 1, 1, 1, 1, 1, 1, 7, 7, 7, 7]
+        );
+
+        // a run of whole blocks ends at the bad block
+        assert_eq!(Device::write_zeros(&dev, 2, 20), Ok(2));
+        assert_eq!(Device::write_zeros(&dev, 14, 20), Err(DevError::IoError));
+        let dev = FailingDevice {
+            data: Mutex::new([7; 32]),
+            bad: 5,
+        };
+        assert_eq!(Device::write_zeros(&dev, 2, 20), Ok(18));
+        assert_eq!(
+            dev.data.lock().unwrap()[..20],
+            [7, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
 }
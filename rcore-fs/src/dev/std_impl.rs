@@ -2,7 +2,7 @@
 
 use std::fs::File;
 use std::io::{Error, Read, Seek, SeekFrom, Write};
-use std::sync::Mutex;
+use std::sync::{Mutex, PoisonError};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::*;
@@ -10,7 +10,7 @@ use super::*;
 impl Device for Mutex<File> {
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
         let offset = offset as u64;
-        let mut file = self.lock().unwrap();
+        let mut file = self.lock().unwrap_or_else(PoisonError::into_inner);
         file.seek(SeekFrom::Start(offset))?;
         let len = file.read(buf)?;
         Ok(len)
@@ -18,14 +18,14 @@ impl Device for Mutex<File> {
 
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
         let offset = offset as u64;
-        let mut file = self.lock().unwrap();
+        let mut file = self.lock().unwrap_or_else(PoisonError::into_inner);
         file.seek(SeekFrom::Start(offset))?;
         let len = file.write(buf)?;
         Ok(len)
     }
 
     fn sync(&self) -> Result<()> {
-        let file = self.lock().unwrap();
+        let file = self.lock().unwrap_or_else(PoisonError::into_inner);
         file.sync_all()?;
         Ok(())
     }
@@ -35,7 +35,10 @@ pub struct StdTimeProvider;
 
 impl TimeProvider for StdTimeProvider {
     fn current_time(&self) -> Timespec {
-        let duration = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        // a clock set before 1970 reads as 1970
+        let duration = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
         Timespec {
             sec: duration.as_secs() as i64,
             nsec: duration.subsec_nanos() as i32,
@@ -45,6 +48,6 @@ impl TimeProvider for StdTimeProvider {
 
 impl From<Error> for DevError {
     fn from(_: Error) -> Self {
-        DevError
+        DevError::IoError
     }
 }
@@ -0,0 +1,462 @@
+//! Bandwidth and concurrency limits of a `Device` shared by several file
+//! systems, e.g. partitions of one disk
+//!
+//! `ThrottledDevice` lets at most `ThrottleConfig::max_bytes` through in each
+//! window of time, splitting larger I/O across windows, and lets at most
+//! `max_in_flight` operations into the inner device at once. I/O over the
+//! limits waits rather than fails, spinning like `spin::Mutex` and reading
+//! the `TimeProvider` until the next window.
+//!
+//! Reads by `read_at_prio()` are latency-sensitive: they take the next free
+//! slot before queued bulk I/O and never wait for the next window, though
+//! their bytes are charged to the current one.
+
+use super::*;
+use crate::util::Semaphore;
+use alloc::sync::Arc;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+/// Limits of a `ThrottledDevice`
+#[derive(Clone)]
+pub struct ThrottleConfig {
+    /// Clock the windows are aligned to
+    pub time: Arc<dyn TimeProvider>,
+    /// Length of a window, not zero
+    pub window: Duration,
+    /// Bytes per window, not zero, `None` for no limit
+    pub max_bytes: Option<usize>,
+    /// Operations in the inner device at once, not zero, `None` for no limit
+    pub max_in_flight: Option<usize>,
+}
+
+impl ThrottleConfig {
+    /// No limits, with windows of one second by `time`
+    pub fn new(time: Arc<dyn TimeProvider>) -> Self {
+        ThrottleConfig {
+            time,
+            window: Duration::from_secs(1),
+            max_bytes: None,
+            max_in_flight: None,
+        }
+    }
+}
+
+/// Upper bounds in microseconds of the buckets of `ThrottleStats::latency`,
+/// a last bucket counts the longer transfers
+pub const LATENCY_BUCKETS_US: [u64; 7] = [10, 100, 1_000, 10_000, 100_000, 1_000_000, 10_000_000];
+
+/// Counters of a `ThrottledDevice` since created, and its queue now
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct ThrottleStats {
+    /// Times I/O waited for the next window as the bytes of one ran out
+    pub window_waits: u64,
+    /// Operations waiting for a slot in the inner device
+    pub queued: usize,
+    /// Operations in the inner device
+    pub in_flight: usize,
+    /// Transfers by the time they took, waits included, in the buckets of
+    /// `LATENCY_BUCKETS_US`
+    pub latency: [u64; LATENCY_BUCKETS_US.len() + 1],
+    /// Time all transfers took, in microseconds
+    pub latency_us: u64,
+}
+
+/// A `Device` limiting the I/O into `inner`, see the module doc
+pub struct ThrottledDevice {
+    inner: Arc<dyn Device>,
+    config: ThrottleConfig,
+    /// (index of the current window, bytes charged to it)
+    budget: Mutex<(u64, usize)>,
+    slots: Semaphore,
+    window_waits: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS_US.len() + 1],
+    latency_us: AtomicU64,
+}
+
+impl ThrottledDevice {
+    /// A zero limit or window of `config` is taken as the smallest one
+    pub fn new(inner: Arc<dyn Device>, mut config: ThrottleConfig) -> Self {
+        config.window = config.window.max(Duration::from_nanos(1));
+        config.max_bytes = config.max_bytes.map(|max| max.max(1));
+        config.max_in_flight = config.max_in_flight.map(|max| max.max(1));
+        ThrottledDevice {
+            inner,
+            slots: Semaphore::new(config.max_in_flight.unwrap_or(usize::MAX)),
+            config,
+            budget: Mutex::new((0, 0)),
+            window_waits: AtomicU64::new(0),
+            latency: Default::default(),
+            latency_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn stats(&self) -> ThrottleStats {
+        let slots = self.slots.stats();
+        ThrottleStats {
+            window_waits: self.window_waits.load(Ordering::Relaxed),
+            queued: slots.waiting,
+            in_flight: slots.in_use,
+            latency: core::array::from_fn(|i| self.latency[i].load(Ordering::Relaxed)),
+            latency_us: self.latency_us.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Nanoseconds by the clock of the config
+    fn now(&self) -> u64 {
+        let now = self.config.time.current_time();
+        now.sec as u64 * 1_000_000_000 + now.nsec as u64
+    }
+
+    /// Index of the window now
+    fn window(&self) -> u64 {
+        self.now() / self.config.window.as_nanos() as u64
+    }
+
+    /// Charge up to `len` bytes to the window, waiting for the next one if
+    /// this one is used up. Return the bytes charged.
+    fn charge(&self, len: usize, prio: bool) -> usize {
+        let max = match self.config.max_bytes {
+            Some(max) => max,
+            None => return len,
+        };
+        let mut waited = false;
+        loop {
+            let window = self.window();
+            let mut budget = self.budget.lock();
+            if window > budget.0 {
+                *budget = (window, 0);
+            }
+            if prio {
+                budget.1 += len;
+                return len;
+            }
+            if budget.1 < max {
+                let len = len.min(max - budget.1);
+                budget.1 += len;
+                return len;
+            }
+            drop(budget);
+            if !waited {
+                waited = true;
+                self.window_waits.fetch_add(1, Ordering::Relaxed);
+            }
+            spin_loop();
+        }
+    }
+
+    /// Transfer `len` bytes by `op(done, len)` in chunks within the limits,
+    /// ending short if an error follows some progress. Its latency is
+    /// counted.
+    fn throttle<F>(&self, len: usize, prio: bool, op: F) -> Result<usize>
+    where
+        F: FnMut(usize, usize) -> Result<usize>,
+    {
+        let start = self.now();
+        let result = self.transfer(len, prio, op);
+        let took = self.now().saturating_sub(start) / 1000;
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| took <= bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_us.fetch_add(took, Ordering::Relaxed);
+        result
+    }
+
+    /// The transfer of `throttle()`
+    fn transfer<F>(&self, len: usize, prio: bool, mut op: F) -> Result<usize>
+    where
+        F: FnMut(usize, usize) -> Result<usize>,
+    {
+        let mut done = 0;
+        while done < len {
+            let chunk = self.charge(len - done, prio);
+            let res = {
+                let _slot = self.slots.acquire(1, prio);
+                op(done, chunk)
+            };
+            let n = match res {
+                Ok(n) => n,
+                Err(_) if done > 0 => break,
+                Err(err) => return Err(err),
+            };
+            done += n;
+            if n < chunk {
+                break;
+            }
+        }
+        Ok(done)
+    }
+}
+
+impl Device for ThrottledDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.throttle(buf.len(), false, |done, len| {
+            self.inner
+                .read_at(offset + done, &mut buf[done..done + len])
+        })
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.throttle(buf.len(), false, |done, len| {
+            self.inner.write_at(offset + done, &buf[done..done + len])
+        })
+    }
+
+    fn sync(&self) -> Result<()> {
+        let _slot = self.slots.acquire(1, false);
+        self.inner.sync()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.inner.size()
+    }
+
+    fn write_zeros(&self, offset: usize, len: usize) -> Result<usize> {
+        self.throttle(len, false, |done, len| {
+            self.inner.write_zeros(offset + done, len)
+        })
+    }
+
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.throttle(buf.len(), false, |done, len| {
+            self.inner
+                .read_at_direct(offset + done, &mut buf[done..done + len])
+        })
+    }
+
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.throttle(buf.len(), false, |done, len| {
+            self.inner
+                .write_at_direct(offset + done, &buf[done..done + len])
+        })
+    }
+
+    fn read_at_prio(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.throttle(buf.len(), true, |done, len| {
+            self.inner
+                .read_at_prio(offset + done, &mut buf[done..done + len])
+        })
+    }
+
+    fn wear_hook(&self) -> Option<&dyn WearHook> {
+        self.inner.wear_hook()
+    }
+
+    fn read_ahead(&self, offset: usize, len: usize) -> Result<()> {
+        self.inner.read_ahead(offset, len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::thread;
+    use std::vec::Vec;
+
+    /// Clock moving `step` nanoseconds forward each time it is read
+    struct VirtualClock {
+        now: AtomicU64,
+        step: u64,
+    }
+
+    impl TimeProvider for VirtualClock {
+        fn current_time(&self) -> Timespec {
+            let now = self.now.fetch_add(self.step, Ordering::SeqCst);
+            Timespec {
+                sec: (now / 1_000_000_000) as i64,
+                nsec: (now % 1_000_000_000) as i32,
+            }
+        }
+    }
+
+    fn clock(step: u64) -> Arc<VirtualClock> {
+        Arc::new(VirtualClock {
+            now: AtomicU64::new(0),
+            step,
+        })
+    }
+
+    /// Device recording its operations and how many ran at once, writes
+    /// wait while `gate` is closed
+    #[derive(Default)]
+    struct Recorder {
+        ops: std::sync::Mutex<Vec<(&'static str, usize)>>,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+        gate: AtomicBool,
+    }
+
+    impl Recorder {
+        fn record(&self, op: &'static str, len: usize) {
+            self.ops.lock().unwrap().push((op, len));
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+            while self.gate.load(Ordering::SeqCst) {
+                thread::yield_now();
+            }
+            // let others in if the throttle would
+            for _ in 0..10 {
+                thread::yield_now();
+            }
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    impl Device for Recorder {
+        fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+            self.record("read", buf.len());
+            Ok(buf.len())
+        }
+
+        fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+            self.record("write", buf.len());
+            Ok(buf.len())
+        }
+
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn read_at_prio(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+            self.record("read_prio", buf.len());
+            Ok(buf.len())
+        }
+    }
+
+    #[test]
+    fn bandwidth() {
+        const MB: usize = 1 << 20;
+        let clock = clock(1_000_000);
+        let recorder = Arc::new(Recorder::default());
+        let mut config = ThrottleConfig::new(clock.clone());
+        config.max_bytes = Some(MB);
+        let dev = ThrottledDevice::new(recorder.clone(), config);
+
+        assert_eq!(dev.write_at(0, &vec![0; 2 * MB]), Ok(2 * MB));
+        assert_eq!(
+            *recorder.ops.lock().unwrap(),
+            [("write", MB), ("write", MB)]
+        );
+        assert_eq!(dev.stats().window_waits, 1);
+        // in the second window of 1 s
+        assert_eq!(clock.now.load(Ordering::SeqCst) / 1_000_000_000, 1);
+
+        // prioritized reads go over the limit, later I/O waits for it
+        let mut buf = [0; 4096];
+        assert_eq!(dev.read_at_prio(0, &mut buf), Ok(4096));
+        assert_eq!(dev.stats().window_waits, 1);
+        assert_eq!(dev.read_at(0, &mut buf[..1]), Ok(1));
+        assert_eq!(dev.stats().window_waits, 2);
+        assert_eq!(clock.now.load(Ordering::SeqCst) / 1_000_000_000, 2);
+    }
+
+    #[test]
+    fn in_flight_cap() {
+        let recorder = Arc::new(Recorder::default());
+        let mut config = ThrottleConfig::new(clock(0));
+        config.max_in_flight = Some(2);
+        let dev = Arc::new(ThrottledDevice::new(recorder.clone(), config));
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let dev = dev.clone();
+                thread::spawn(move || {
+                    for j in 0..20 {
+                        assert_eq!(dev.write_at((i * 20 + j) * 512, &[0; 512]), Ok(512));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(recorder.ops.lock().unwrap().len(), 160);
+        assert!(recorder.max_in_flight.load(Ordering::SeqCst) <= 2);
+        let stats = ThrottleStats {
+            latency: [160, 0, 0, 0, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        assert_eq!(dev.stats(), stats);
+    }
+
+    #[test]
+    fn latency() {
+        let recorder = Arc::new(Recorder::default());
+        // the clock is read before and after each transfer
+        let dev = ThrottledDevice::new(recorder.clone(), ThrottleConfig::new(clock(20_000)));
+        assert_eq!(dev.write_at(0, &[0; 512]), Ok(512));
+        assert_eq!(dev.stats().latency, [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(dev.stats().latency_us, 20);
+        let dev = ThrottledDevice::new(recorder, ThrottleConfig::new(clock(2_000_000)));
+        assert_eq!(dev.write_at(0, &[0; 512]), Ok(512));
+        assert_eq!(dev.read_at(0, &mut [0; 512]), Ok(512));
+        assert_eq!(dev.stats().latency, [0, 0, 0, 2, 0, 0, 0, 0]);
+        assert_eq!(dev.stats().latency_us, 4000);
+    }
+
+    #[test]
+    fn zero_config() {
+        let recorder = Arc::new(Recorder::default());
+        let mut config = ThrottleConfig::new(clock(1));
+        config.window = Duration::from_secs(0);
+        config.max_bytes = Some(0);
+        config.max_in_flight = Some(0);
+        let dev = ThrottledDevice::new(recorder.clone(), config);
+        // a byte per window of a nanosecond
+        assert_eq!(dev.write_at(0, &[0; 3]), Ok(3));
+        assert_eq!(
+            *recorder.ops.lock().unwrap(),
+            [("write", 1), ("write", 1), ("write", 1)]
+        );
+    }
+
+    #[test]
+    fn prio_reads_first() {
+        let recorder = Arc::new(Recorder::default());
+        let mut config = ThrottleConfig::new(clock(0));
+        config.max_in_flight = Some(1);
+        let dev = Arc::new(ThrottledDevice::new(recorder.clone(), config));
+        let wait_for = |cond: &dyn Fn(ThrottleStats) -> bool| {
+            while !cond(dev.stats()) {
+                thread::yield_now();
+            }
+        };
+        let spawn = |prio: bool| {
+            let dev = dev.clone();
+            thread::spawn(move || {
+                let mut buf = [0; 512];
+                match prio {
+                    true => dev.read_at_prio(0, &mut buf),
+                    false => dev.write_at(0, &buf),
+                }
+            })
+        };
+
+        // a bulk write in the device, two queued behind it
+        recorder.gate.store(true, Ordering::SeqCst);
+        let mut threads = vec![spawn(false)];
+        wait_for(&|stats| stats.in_flight == 1);
+        threads.push(spawn(false));
+        threads.push(spawn(false));
+        wait_for(&|stats| stats.queued == 2);
+        threads.push(spawn(true));
+        wait_for(&|stats| stats.queued == 3);
+
+        recorder.gate.store(false, Ordering::SeqCst);
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), Ok(512));
+        }
+        let ops: Vec<_> = recorder.ops.lock().unwrap().iter().map(|op| op.0).collect();
+        assert_eq!(ops, ["write", "read_prio", "write", "write"]);
+        assert_eq!(recorder.max_in_flight.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,263 @@
+//! Deadlines for the I/O of a device which may never complete it, e.g. on a
+//! lost interrupt or a hung SD card
+//!
+//! A `Device` call can not be given up once made, so a driver completing its
+//! requests later implements `AsyncDevice` instead. `TimedDevice` makes a
+//! `Device` of it: each request is polled, spinning like `spin::Mutex`,
+//! against a sleep of `TimedConfig::deadline` from the `Timer` of the
+//! kernel, and fails with `DevError::TimedOut` once the sleep ends first.
+//! The fs then sees an error like any other and unwinds, releasing its locks,
+//! so the next operations go on.
+//!
+//! After `TimedConfig::poison_after` timeouts in a row, the device is taken
+//! as gone: every request fails at once with `TimedOut`, without waiting
+//! for another deadline, until `clear_poison()`.
+
+use super::*;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::hint::spin_loop;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use core::time::Duration;
+
+/// A future of the I/O of an `AsyncDevice`, or of a `Timer`
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A device completing its requests later, e.g. by interrupt.
+///
+/// Dropping the future of a request cancels it: the driver must not touch
+/// its buffer afterwards.
+pub trait AsyncDevice: Send + Sync {
+    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize>>;
+    fn write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> BoxFuture<'a, Result<usize>>;
+    fn sync(&self) -> BoxFuture<'_, Result<()>>;
+    /// See `Device::is_read_only()`
+    fn is_read_only(&self) -> bool {
+        false
+    }
+    /// See `Device::size()`
+    fn size(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The timer of the kernel, for `TimedDevice`
+pub trait Timer: Send + Sync {
+    /// A future ready once `duration` has passed
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Deadlines of a `TimedDevice`
+#[derive(Debug, Clone, Copy)]
+pub struct TimedConfig {
+    /// Time each request has to complete
+    pub deadline: Duration,
+    /// Timeouts in a row poisoning the device, `None` to never poison it
+    pub poison_after: Option<usize>,
+}
+
+/// An `AsyncDevice` whose requests fail after a deadline, see the module doc
+pub struct TimedDevice {
+    inner: Arc<dyn AsyncDevice>,
+    timer: Arc<dyn Timer>,
+    config: TimedConfig,
+    /// Timeouts since the last request completed
+    in_a_row: AtomicUsize,
+    poisoned: AtomicBool,
+    timeouts: AtomicU64,
+}
+
+impl TimedDevice {
+    pub fn new(inner: Arc<dyn AsyncDevice>, timer: Arc<dyn Timer>, config: TimedConfig) -> Self {
+        TimedDevice {
+            inner,
+            timer,
+            config,
+            in_a_row: AtomicUsize::new(0),
+            poisoned: AtomicBool::new(false),
+            timeouts: AtomicU64::new(0),
+        }
+    }
+
+    /// Requests timed out since created
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    /// Whether requests fail at once, see `TimedConfig::poison_after`
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// Let requests through again, e.g. once the device is reset
+    pub fn clear_poison(&self) {
+        self.in_a_row.store(0, Ordering::Relaxed);
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    /// Poll the request made by `request` until it completes or the
+    /// deadline passes. None is made once poisoned.
+    fn run<'a, T>(&self, request: impl FnOnce() -> BoxFuture<'a, Result<T>>) -> Result<T> {
+        if self.is_poisoned() {
+            return Err(DevError::TimedOut);
+        }
+        let mut request = request();
+        let mut sleep = self.timer.sleep(self.config.deadline);
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(result) = request.as_mut().poll(&mut cx) {
+                self.in_a_row.store(0, Ordering::Relaxed);
+                return result;
+            }
+            if sleep.as_mut().poll(&mut cx).is_ready() {
+                break;
+            }
+            spin_loop();
+        }
+        // cancel it before the buffer is given back
+        drop(request);
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+        let in_a_row = self.in_a_row.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.config.poison_after.is_some_and(|max| in_a_row >= max) {
+            self.poisoned.store(true, Ordering::Relaxed);
+        }
+        Err(DevError::TimedOut)
+    }
+}
+
+/// A waker doing nothing, the futures being polled in a loop anyway
+fn noop_waker() -> Waker {
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RAW, |_| {}, |_| {}, |_| {});
+    const RAW: RawWaker = RawWaker::new(core::ptr::null(), &VTABLE);
+    // SAFETY: the functions of the vtable touch no data
+    unsafe { Waker::from_raw(RAW) }
+}
+
+impl Device for TimedDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.run(|| self.inner.read_at(offset, buf))
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.run(|| self.inner.write_at(offset, buf))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.run(|| self.inner.sync())
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.inner.size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+    use std::vec;
+    use std::vec::Vec;
+
+    /// Timer whose time moves a millisecond forward each time a sleep is
+    /// polled
+    #[derive(Default)]
+    struct VirtualTimer {
+        now: Arc<AtomicU64>,
+    }
+
+    impl Timer for VirtualTimer {
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            let now = self.now.clone();
+            let end = now.load(Ordering::SeqCst) + duration.as_millis() as u64;
+            Box::pin(core::future::poll_fn(move |_| {
+                match now.fetch_add(1, Ordering::SeqCst) + 1 >= end {
+                    true => Poll::Ready(()),
+                    false => Poll::Pending,
+                }
+            }))
+        }
+    }
+
+    /// Device in memory whose requests numbered in `hang`, counted from 1,
+    /// never complete
+    struct Mem {
+        data: Mutex<Vec<u8>>,
+        requests: AtomicUsize,
+        hang: Mutex<Vec<usize>>,
+    }
+
+    impl Mem {
+        /// A request ready at once, or never
+        fn request<T: Send + 'static>(&self, result: Result<T>) -> BoxFuture<'static, Result<T>> {
+            let n = self.requests.fetch_add(1, Ordering::SeqCst) + 1;
+            match self.hang.lock().unwrap().contains(&n) {
+                true => Box::pin(core::future::pending()),
+                false => Box::pin(core::future::ready(result)),
+            }
+        }
+    }
+
+    impl AsyncDevice for Mem {
+        fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> BoxFuture<'a, Result<usize>> {
+            let data = self.data.lock().unwrap();
+            buf.copy_from_slice(&data[offset..offset + buf.len()]);
+            self.request(Ok(buf.len()))
+        }
+        fn write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> BoxFuture<'a, Result<usize>> {
+            let mut data = self.data.lock().unwrap();
+            data[offset..offset + buf.len()].copy_from_slice(buf);
+            self.request(Ok(buf.len()))
+        }
+        fn sync(&self) -> BoxFuture<'_, Result<()>> {
+            self.request(Ok(()))
+        }
+    }
+
+    #[test]
+    fn deadline_and_poison() {
+        let mem = Arc::new(Mem {
+            data: Mutex::new(vec![0; 64]),
+            requests: AtomicUsize::new(0),
+            hang: Mutex::new(vec![5, 7, 8, 9]),
+        });
+        let timer = Arc::new(VirtualTimer::default());
+        let config = TimedConfig {
+            deadline: Duration::from_millis(100),
+            poison_after: Some(3),
+        };
+        let dev = TimedDevice::new(mem.clone(), timer.clone(), config);
+        let mut buf = [0; 4];
+        for i in 0..4 {
+            assert_eq!(dev.write_at(i * 4, &[i as u8; 4]), Ok(4));
+        }
+        // the 5th request hangs, given up at the deadline
+        assert_eq!(dev.read_at(0, &mut buf), Err(DevError::TimedOut));
+        assert_eq!(timer.now.load(Ordering::SeqCst), 100);
+        assert_eq!(dev.read_at(4, &mut buf), Ok(4));
+        assert_eq!(buf, [1; 4]);
+        assert!(!dev.is_poisoned());
+
+        // 3 in a row poison it, then no request is made
+        assert_eq!(dev.sync(), Err(DevError::TimedOut));
+        assert_eq!(dev.sync(), Err(DevError::TimedOut));
+        assert!(!dev.is_poisoned());
+        assert_eq!(dev.sync(), Err(DevError::TimedOut));
+        assert!(dev.is_poisoned());
+        assert_eq!(dev.read_at(4, &mut buf), Err(DevError::TimedOut));
+        assert_eq!(mem.requests.load(Ordering::SeqCst), 9);
+        assert_eq!(dev.timeouts(), 4);
+        assert_eq!(timer.now.load(Ordering::SeqCst), 400);
+
+        dev.clear_poison();
+        assert_eq!(dev.read_at(8, &mut buf), Ok(4));
+        assert_eq!(buf, [2; 4]);
+    }
+}
@@ -0,0 +1,246 @@
+//! Write counts of each part of a `Device`, to find the blocks a fs wears
+//! out on flash, e.g. the superblock or a hot directory
+//!
+//! `WearTrackingDevice` counts the writes touching each chunk of
+//! `1 << granularity_log2` bytes, and the bytes written into the inner
+//! device. A fs finding it by `Device::wear_hook()` notes the bytes of file
+//! data it is asked to write, so `write_amplification()` shows what its
+//! metadata costs on top.
+
+use super::*;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Told by a fs what it is asked to write, see `Device::wear_hook()`
+pub trait WearHook: Send + Sync {
+    /// `len` bytes of file data are written
+    fn note_logical_write(&self, len: usize);
+}
+
+/// A `Device` counting the writes into `inner`, see the module doc
+pub struct WearTrackingDevice {
+    inner: Arc<dyn Device>,
+    granularity_log2: u8,
+    /// writes touching each chunk
+    counts: Vec<AtomicU64>,
+    /// bytes noted by `note_logical_write()`
+    logical: AtomicU64,
+    /// bytes written into `inner`
+    physical: AtomicU64,
+}
+
+impl WearTrackingDevice {
+    /// Count writes by chunks of `1 << granularity_log2` bytes. If the size
+    /// of `inner` is unknown, only the bytes written are counted.
+    pub fn new(inner: Arc<dyn Device>, granularity_log2: u8) -> Self {
+        let size = inner.size().unwrap_or(0);
+        let granularity_log2 = granularity_log2.min(usize::BITS as u8 - 1);
+        let chunks = size.div_ceil(1 << granularity_log2);
+        WearTrackingDevice {
+            inner,
+            granularity_log2,
+            counts: (0..chunks).map(|_| AtomicU64::new(0)).collect(),
+            logical: AtomicU64::new(0),
+            physical: AtomicU64::new(0),
+        }
+    }
+
+    /// The `n` most written chunks as (index, writes), most written first,
+    /// leaving out chunks never written
+    pub fn top_n(&self, n: usize) -> Vec<(usize, u64)> {
+        let mut top: Vec<_> = self
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .enumerate()
+            .filter(|&(_, count)| count != 0)
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    /// Writes of the chunks split in `buckets` runs of equal length from
+    /// the start of the device, the last one maybe shorter. Each write is
+    /// counted once for every chunk it touches. Empty for no buckets.
+    pub fn histogram(&self, buckets: usize) -> Vec<usize> {
+        if buckets == 0 {
+            return Vec::new();
+        }
+        let per_bucket = self.counts.len().div_ceil(buckets).max(1);
+        let mut histogram = alloc::vec![0; buckets];
+        for (i, count) in self.counts.iter().enumerate() {
+            histogram[i / per_bucket] += count.load(Ordering::Relaxed) as usize;
+        }
+        histogram
+    }
+
+    /// Forget the counts so far
+    pub fn reset(&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.logical.store(0, Ordering::Relaxed);
+        self.physical.store(0, Ordering::Relaxed);
+    }
+
+    /// (bytes of file data noted by the fs, bytes written into the device)
+    pub fn write_amplification(&self) -> (u64, u64) {
+        (
+            self.logical.load(Ordering::Relaxed),
+            self.physical.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Count a write of `len` bytes at `offset` done by the inner device
+    fn count(&self, offset: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        self.physical.fetch_add(len as u64, Ordering::Relaxed);
+        let first = offset >> self.granularity_log2;
+        let last = (offset + len - 1) >> self.granularity_log2;
+        for count in self.counts.iter().take(last + 1).skip(first) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count the bytes written by `res` at `offset`
+    fn counted(&self, offset: usize, res: Result<usize>) -> Result<usize> {
+        if let Ok(len) = res {
+            self.count(offset, len);
+        }
+        res
+    }
+}
+
+impl WearHook for WearTrackingDevice {
+    fn note_logical_write(&self, len: usize) {
+        self.logical.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+impl Device for WearTrackingDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.counted(offset, self.inner.write_at(offset, buf))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn size(&self) -> Option<usize> {
+        self.inner.size()
+    }
+
+    fn write_zeros(&self, offset: usize, len: usize) -> Result<usize> {
+        self.counted(offset, self.inner.write_zeros(offset, len))
+    }
+
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read_at_direct(offset, buf)
+    }
+
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.counted(offset, self.inner.write_at_direct(offset, buf))
+    }
+
+    fn read_at_prio(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read_at_prio(offset, buf)
+    }
+
+    fn wear_hook(&self) -> Option<&dyn WearHook> {
+        Some(self)
+    }
+
+    fn read_ahead(&self, offset: usize, len: usize) -> Result<()> {
+        self.inner.read_ahead(offset, len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Device in memory of `Vec::len()` bytes
+    struct Mem(Mutex<Vec<u8>>);
+
+    impl Device for Mem {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+        fn size(&self) -> Option<usize> {
+            Some(self.0.lock().unwrap().len())
+        }
+    }
+
+    #[test]
+    fn count_chunks() {
+        let device = WearTrackingDevice::new(Arc::new(Mem(Mutex::new(vec![0; 1000]))), 8);
+        // chunks 0, 0-1, 3 (end of the device)
+        device.write_at(10, &[1; 10]).unwrap();
+        device.write_at(200, &[1; 100]).unwrap();
+        device.write_zeros(990, 20).unwrap();
+        assert_eq!(device.top_n(10), vec![(0, 2), (1, 1), (3, 1)]);
+        assert_eq!(device.top_n(1), vec![(0, 2)]);
+        assert_eq!(device.histogram(2), vec![3, 1]);
+        assert_eq!(device.histogram(8), vec![2, 1, 0, 1, 0, 0, 0, 0]);
+        device.note_logical_write(10);
+        assert_eq!(device.write_amplification(), (10, 120));
+        // short writes count what is written
+        assert_eq!(device.write_at(995, &[1; 10]), Ok(5));
+        assert_eq!(device.write_amplification(), (10, 125));
+
+        device.reset();
+        assert_eq!(device.top_n(10), vec![]);
+        assert_eq!(device.write_amplification(), (0, 0));
+        assert!(device.wear_hook().is_some());
+    }
+
+    /// `Mem` of unknown size
+    struct Unsized(Mem);
+
+    impl Device for Unsized {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            self.0.read_at(offset, buf)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            self.0.write_at(offset, buf)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn unknown_size() {
+        let mem = Mem(Mutex::new(vec![0; 1000]));
+        let device = WearTrackingDevice::new(Arc::new(Unsized(mem)), u8::MAX);
+        assert_eq!(device.write_at(10, &[1; 10]), Ok(10));
+        assert_eq!(device.write_amplification(), (0, 10));
+        assert_eq!(device.top_n(10), vec![]);
+        assert_eq!(device.histogram(2), vec![0, 0]);
+        assert_eq!(device.histogram(0), vec![]);
+    }
+}
@@ -0,0 +1,202 @@
+//! A fixed part of a `Device`, to put several file systems on one device
+//! split at known offsets, e.g. a flash chip without a partition table
+//!
+//! `WindowedDevice` shows the `len` bytes of the inner device from `offset`
+//! as a device of its own. Reads stop at the end of the window, writes
+//! reaching past it fail as a whole, so a fs never touches the bytes of its
+//! neighbors. Windows keep no state but their bounds, several of them on
+//! one device are as safe to use at once as the device itself.
+
+use super::*;
+use alloc::sync::Arc;
+use core::ops::Range;
+
+/// The bytes `offset..offset + len` of `inner`, see the module doc
+pub struct WindowedDevice {
+    inner: Arc<dyn Device>,
+    offset: usize,
+    len: usize,
+}
+
+impl WindowedDevice {
+    /// The `len` bytes of `inner` from `offset`. The window is not checked
+    /// against the size of `inner`, I/O past its end fails there.
+    pub fn new(inner: Arc<dyn Device>, offset: usize, len: usize) -> Self {
+        // a window past the end of the address space ends there
+        let len = len.min(usize::MAX - offset);
+        WindowedDevice { inner, offset, len }
+    }
+
+    /// The bytes of the inner device in the window
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.len
+    }
+
+    /// Offset in the inner device of `len` bytes from `offset` in the window,
+    /// `OutOfRange` unless all of them are in the window
+    fn translate(&self, offset: usize, len: usize) -> Result<usize> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.len => Ok(self.offset + offset),
+            _ => Err(DevError::OutOfRange),
+        }
+    }
+
+    /// Length of the part in the window of `len` bytes from `offset`
+    fn clamp(&self, offset: usize, len: usize) -> usize {
+        len.min(self.len.saturating_sub(offset))
+    }
+}
+
+impl Device for WindowedDevice {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        match len {
+            0 => Ok(0),
+            _ => self.inner.read_at(self.offset + offset, &mut buf[..len]),
+        }
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let offset = self.translate(offset, buf.len())?;
+        self.inner.write_at(offset, buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn size(&self) -> Option<usize> {
+        Some(self.len)
+    }
+
+    fn write_zeros(&self, offset: usize, len: usize) -> Result<usize> {
+        let offset = self.translate(offset, len)?;
+        self.inner.write_zeros(offset, len)
+    }
+
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        match len {
+            0 => Ok(0),
+            _ => self
+                .inner
+                .read_at_direct(self.offset + offset, &mut buf[..len]),
+        }
+    }
+
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let offset = self.translate(offset, buf.len())?;
+        self.inner.write_at_direct(offset, buf)
+    }
+
+    fn read_at_prio(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        match len {
+            0 => Ok(0),
+            _ => self
+                .inner
+                .read_at_prio(self.offset + offset, &mut buf[..len]),
+        }
+    }
+
+    fn wear_hook(&self) -> Option<&dyn WearHook> {
+        self.inner.wear_hook()
+    }
+
+    fn read_ahead(&self, offset: usize, len: usize) -> Result<()> {
+        let len = self.clamp(offset, len);
+        match len {
+            0 => Ok(()),
+            _ => self.inner.read_ahead(self.offset + offset, len),
+        }
+    }
+
+    fn hinted(&self) -> Option<&dyn HintedDevice> {
+        self.inner.hinted().map(|_| self as &dyn HintedDevice)
+    }
+}
+
+impl HintedDevice for WindowedDevice {
+    fn read_at_hinted(&self, offset: usize, buf: &mut [u8], hint: CacheHint) -> Result<usize> {
+        let len = self.clamp(offset, buf.len());
+        match (len, self.inner.hinted()) {
+            (0, _) => Ok(0),
+            (_, Some(inner)) => inner.read_at_hinted(self.offset + offset, &mut buf[..len], hint),
+            (_, None) => self.inner.read_at(self.offset + offset, &mut buf[..len]),
+        }
+    }
+
+    fn write_at_hinted(&self, offset: usize, buf: &[u8], hint: CacheHint) -> Result<usize> {
+        let offset = self.translate(offset, buf.len())?;
+        match self.inner.hinted() {
+            Some(inner) => inner.write_at_hinted(offset, buf, hint),
+            None => self.inner.write_at(offset, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Device in memory of `Vec::len()` bytes
+    struct Mem(Mutex<Vec<u8>>);
+
+    impl Device for Mem {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+            let data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+            let mut data = self.0.lock().unwrap();
+            let len = buf.len().min(data.len().saturating_sub(offset));
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+        fn size(&self) -> Option<usize> {
+            Some(self.0.lock().unwrap().len())
+        }
+    }
+
+    #[test]
+    fn bounds() {
+        let mem = Arc::new(Mem(Mutex::new(vec![0; 100])));
+        let window = WindowedDevice::new(mem.clone(), 10, 20);
+        assert_eq!(window.range(), 10..30);
+        assert_eq!(window.size(), Some(20));
+        assert_eq!(window.write_at(5, &[1; 15]), Ok(15));
+        assert_eq!(window.write_at(5, &[2; 16]), Err(DevError::OutOfRange));
+        assert_eq!(window.write_at(usize::MAX, &[2]), Err(DevError::OutOfRange));
+        assert_eq!(window.write_zeros(19, 2), Err(DevError::OutOfRange));
+        assert_eq!(window.write_at_direct(20, &[2]), Err(DevError::OutOfRange));
+        assert_eq!(window.write_zeros(19, 1), Ok(1));
+
+        // reads end at the window
+        let mut buf = [0xff; 30];
+        assert_eq!(window.read_at(0, &mut buf), Ok(20));
+        assert_eq!(buf[..5], [0; 5]);
+        assert_eq!(buf[5..19], [1; 14]);
+        assert_eq!(
+            buf[19..],
+            [0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(window.read_at_prio(15, &mut buf), Ok(5));
+        assert_eq!(window.read_at_direct(20, &mut buf), Ok(0));
+        assert_eq!(window.read_at(usize::MAX, &mut buf), Ok(0));
+        assert_eq!(window.read_ahead(10, 100), Ok(()));
+
+        // nothing outside written
+        let data = mem.0.lock().unwrap();
+        assert!(data[..15].iter().chain(&data[30..]).all(|&b| b == 0));
+    }
+}
@@ -3,9 +3,15 @@ use core::ops::{Deref, DerefMut};
 
 /// Dirty wraps a value of type T with functions similiar to that of a Read/Write
 /// lock but simply sets a dirty flag on write(), reset on read()
+///
+/// A writer back that does not hold the value from capturing it to being
+/// written notes `generation()` when capturing, and clears the flag by
+/// `sync_at()`, which keeps it dirty if the value changed meanwhile.
 pub struct Dirty<T> {
     value: T,
     dirty: bool,
+    /// bumped by every write
+    generation: u64,
 }
 
 impl<T> Dirty<T> {
@@ -14,6 +20,7 @@ impl<T> Dirty<T> {
         Dirty {
             value: val,
             dirty: false,
+            generation: 0,
         }
     }
 
@@ -22,6 +29,7 @@ impl<T> Dirty<T> {
         Dirty {
             value: val,
             dirty: true,
+            generation: 0,
         }
     }
 
@@ -35,6 +43,20 @@ impl<T> Dirty<T> {
     pub fn sync(&mut self) {
         self.dirty = false;
     }
+
+    /// Changes of the value so far, to be given to `sync_at()`
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Reset dirty if the value is the one of `generation`, e.g. as captured
+    /// to be written back. Return whether it is clean.
+    pub fn sync_at(&mut self, generation: u64) -> bool {
+        if self.generation == generation {
+            self.dirty = false;
+        }
+        !self.dirty
+    }
 }
 
 impl<T> Deref for Dirty<T> {
@@ -50,6 +72,7 @@ impl<T> DerefMut for Dirty<T> {
     /// Writable value return, sets the dirty flag
     fn deref_mut(&mut self) -> &mut T {
         self.dirty = true;
+        self.generation = self.generation.wrapping_add(1);
         &mut self.value
     }
 }
@@ -57,7 +80,7 @@ impl<T> DerefMut for Dirty<T> {
 impl<T> Drop for Dirty<T> {
     /// Guard it is not dirty when dropping
     fn drop(&mut self) {
-        assert!(!self.dirty, "data dirty when dropping");
+        debug_assert!(!self.dirty, "data dirty when dropping");
     }
 }
 
@@ -67,3 +90,29 @@ impl<T: Debug> Debug for Dirty<T> {
         write!(f, "[{}] {:?}", tag, self.value)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sync_at() {
+        let mut value = Dirty::new_dirty(1);
+        let generation = value.generation();
+        assert!(value.sync_at(generation));
+        assert!(!value.dirty());
+
+        // changed after being captured
+        *value = 2;
+        let generation = value.generation();
+        *value = 3;
+        assert!(!value.sync_at(generation));
+        assert!(value.dirty());
+        assert!(value.sync_at(value.generation()));
+
+        // a stale generation leaves a clean value clean
+        *value = 4;
+        value.sync();
+        assert!(value.sync_at(generation));
+    }
+}
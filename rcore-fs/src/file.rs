@@ -1,11 +1,38 @@
-use crate::vfs::{INode, Metadata, Result};
-use alloc::{string::String, sync::Arc};
+use crate::dev::CacheHint;
+use crate::vfs::{FsError, INode, Metadata, OpenGuard, Result, TaskContext};
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::ops::Range;
+
+/// Callback of `File::set_progress()`, with the bytes done and asked
+type Progress = Box<dyn Fn(usize, usize) + Send + Sync>;
+
+/// What a `File` syncs when closed, see `File::set_sync_on_close()`
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum SyncPolicy {
+    /// Nothing
+    #[default]
+    None,
+    /// The content, by `INode::sync_data()`
+    Data,
+    /// The content and metadata, by `INode::sync_all()`
+    All,
+}
 
 pub struct File {
     inode: Arc<dyn INode>,
     offset: usize,
     readable: bool,
     writable: bool,
+    /// Read and write by `INode::read_at_direct()` and `write_at_direct()`
+    direct: bool,
+    /// Read and write by `INode::read_at_with()` and `write_at_with()`
+    ctx: Option<TaskContext>,
+    /// Piece size and callback of `set_progress()`
+    progress: Option<(usize, Progress)>,
+    /// Synced by `close()` or on drop
+    sync_on_close: SyncPolicy,
+    /// Held while opened, from `INode::open_hook()`
+    _guard: OpenGuard,
 }
 
 impl File {
@@ -15,19 +42,160 @@ impl File {
             offset: 0,
             readable,
             writable,
+            direct: false,
+            ctx: None,
+            progress: None,
+            sync_on_close: SyncPolicy::None,
+            _guard: OpenGuard::default(),
         }
     }
 
+    /// Open `inode` through its `open_hook()`, `exclusive` to be the only
+    /// opener. Fail with `Busy` if the INode is not available.
+    pub fn open(
+        inode: Arc<dyn INode>,
+        readable: bool,
+        writable: bool,
+        exclusive: bool,
+    ) -> Result<Self> {
+        let guard = inode.open_hook(exclusive)?;
+        Ok(File {
+            inode,
+            offset: 0,
+            readable,
+            writable,
+            direct: false,
+            ctx: None,
+            progress: None,
+            sync_on_close: SyncPolicy::None,
+            _guard: guard,
+        })
+    }
+
+    /// Bypass the caches of the fs and device like `O_DIRECT`, the fs may
+    /// require reads and writes to be aligned
+    pub fn set_direct(&mut self, direct: bool) {
+        self.direct = direct;
+    }
+
+    /// Sync as `policy` says when closed, before the INode is released.
+    /// `close()` returns whether it failed, a drop ignores it.
+    pub fn set_sync_on_close(&mut self, policy: SyncPolicy) {
+        self.sync_on_close = policy;
+    }
+
+    /// Close the file, syncing it as `set_sync_on_close()` says
+    pub fn close(mut self) -> Result<()> {
+        self.sync_for_close()
+    }
+
+    /// Sync as `set_sync_on_close()` says, once
+    fn sync_for_close(&mut self) -> Result<()> {
+        match core::mem::take(&mut self.sync_on_close) {
+            SyncPolicy::None => Ok(()),
+            SyncPolicy::Data => self.inode.sync_data(),
+            SyncPolicy::All => self.inode.sync_all(),
+        }
+    }
+
+    /// Hint how the blocks of the file are to be cached, see
+    /// `INode::set_cache_hint()`. Kept by the INode, so it holds for all the
+    /// files opened on it.
+    pub fn set_cache_hint(&self, hint: CacheHint) {
+        self.inode.set_cache_hint(hint);
+    }
+
+    /// Read and write through `ctx`, so that long transfers call its
+    /// checkpoint, and end short once it is cancelled. Not for direct I/O.
+    pub fn set_task_context(&mut self, ctx: TaskContext) {
+        self.ctx = Some(ctx);
+    }
+
+    /// Transfer buffers in pieces of `piece` bytes, calling `progress` with
+    /// the bytes done and the bytes asked after each one. With direct I/O,
+    /// `piece` should be aligned as the fs requires.
+    pub fn set_progress(
+        &mut self,
+        piece: usize,
+        progress: impl Fn(usize, usize) + Send + Sync + 'static,
+    ) {
+        self.progress = Some((piece.max(1), Box::new(progress)));
+    }
+
+    fn read_at(&self, buf: &mut [u8]) -> Result<usize> {
+        let total = buf.len();
+        self.in_pieces(total, |offset, range| match (self.direct, &self.ctx) {
+            (true, _) => self.inode.read_at_direct(offset, &mut buf[range]),
+            (false, Some(ctx)) => self.inode.read_at_with(offset, &mut buf[range], ctx),
+            (false, None) => self.inode.read_at(offset, &mut buf[range]),
+        })
+    }
+
+    fn write_at(&self, buf: &[u8]) -> Result<usize> {
+        self.in_pieces(buf.len(), |offset, range| match (self.direct, &self.ctx) {
+            (true, _) => self.inode.write_at_direct(offset, &buf[range]),
+            (false, Some(ctx)) => self.inode.write_at_with(offset, &buf[range], ctx),
+            (false, None) => self.inode.write_at(offset, &buf[range]),
+        })
+    }
+
+    /// Transfer `total` bytes from `self.offset` with `io` on each piece of
+    /// `set_progress()`, or all at once. Stop at the first short piece, or
+    /// error after some progress.
+    fn in_pieces(
+        &self,
+        total: usize,
+        mut io: impl FnMut(usize, Range<usize>) -> Result<usize>,
+    ) -> Result<usize> {
+        let (piece, progress) = match &self.progress {
+            Some((piece, progress)) => (*piece, progress),
+            None => return io(self.offset, 0..total),
+        };
+        let mut done = 0;
+        loop {
+            let end = total.min(done + piece);
+            let len = match io(self.offset + done, done..end) {
+                Ok(len) => len,
+                Err(_) if done > 0 => return Ok(done),
+                Err(err) => return Err(err),
+            };
+            done += len;
+            progress(done, total);
+            if done == total || done < end {
+                return Ok(done);
+            }
+        }
+    }
+
+    /// Read and write from `offset` on
+    pub fn seek(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    pub fn readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Fails with `PermError` if the file is not opened for reading
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        assert!(self.readable);
-        let len = self.inode.read_at(self.offset, buf)?;
+        if !self.readable {
+            return Err(FsError::PermError);
+        }
+        let len = self.read_at(buf)?;
         self.offset += len;
         Ok(len)
     }
 
+    /// Fails with `PermError` if the file is not opened for writing
     pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        assert!(self.writable);
-        let len = self.inode.write_at(self.offset, buf)?;
+        if !self.writable {
+            return Err(FsError::PermError);
+        }
+        let len = self.write_at(buf)?;
         self.offset += len;
         Ok(len)
     }
@@ -40,3 +208,117 @@ impl File {
         self.inode.get_entry(id)
     }
 }
+
+impl Drop for File {
+    fn drop(&mut self) {
+        // nowhere to report it, see `close()`
+        let _ = self.sync_for_close();
+    }
+}
+
+/// Adapter implementing `futures-io` traits over `File`
+///
+/// INode operations are synchronous, so every poll completes at once
+/// and there is no pending operation to keep between polls.
+#[cfg(feature = "futures-io")]
+pub struct IoAdapter {
+    file: File,
+}
+
+#[cfg(feature = "futures-io")]
+mod io_adapter {
+    use super::*;
+    use alloc::format;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+    use futures_io::{AsyncRead, AsyncSeek, AsyncWrite, Error, ErrorKind, SeekFrom};
+
+    impl IoAdapter {
+        pub fn new(file: File) -> Self {
+            IoAdapter { file }
+        }
+
+        pub fn into_inner(self) -> File {
+            self.file
+        }
+    }
+
+    fn io_error(err: FsError) -> Error {
+        let kind = match err.root_cause() {
+            FsError::EntryNotFound | FsError::DirRemoved => ErrorKind::NotFound,
+            FsError::EntryExist => ErrorKind::AlreadyExists,
+            FsError::InvalidParam => ErrorKind::InvalidInput,
+            FsError::Again => ErrorKind::WouldBlock,
+            FsError::Interrupted => ErrorKind::Interrupted,
+            FsError::NotSupported | FsError::Unsupported => ErrorKind::Unsupported,
+            FsError::ReadOnly | FsError::PermError => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Other,
+        };
+        Error::new(kind, format!("{:?}", err))
+    }
+
+    impl AsyncRead for IoAdapter {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<futures_io::Result<usize>> {
+            let file = &mut self.get_mut().file;
+            if !file.readable {
+                return Poll::Ready(Err(ErrorKind::PermissionDenied.into()));
+            }
+            // read_at returns 0 at EOF
+            let len = file.read_at(buf).map_err(io_error)?;
+            file.offset += len;
+            Poll::Ready(Ok(len))
+        }
+    }
+
+    impl AsyncWrite for IoAdapter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<futures_io::Result<usize>> {
+            let file = &mut self.get_mut().file;
+            if !file.writable {
+                return Poll::Ready(Err(ErrorKind::PermissionDenied.into()));
+            }
+            let len = file.write_at(buf).map_err(io_error)?;
+            file.offset += len;
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<futures_io::Result<()>> {
+            Poll::Ready(self.file.inode.sync_data().map_err(io_error))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<futures_io::Result<()>> {
+            Poll::Ready(self.file.inode.sync_all().map_err(io_error))
+        }
+    }
+
+    impl AsyncSeek for IoAdapter {
+        fn poll_seek(
+            self: Pin<&mut Self>,
+            _cx: &mut Context,
+            pos: SeekFrom,
+        ) -> Poll<futures_io::Result<u64>> {
+            let file = &mut self.get_mut().file;
+            let (base, delta) = match pos {
+                SeekFrom::Start(offset) => (0, offset as i64),
+                SeekFrom::End(delta) => {
+                    let size = file.inode.metadata().map_err(io_error)?.size;
+                    (size, delta)
+                }
+                SeekFrom::Current(delta) => (file.offset, delta),
+            };
+            let offset = (base as i64)
+                .checked_add(delta)
+                .filter(|&offset| offset >= 0)
+                .ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+            file.offset = offset as usize;
+            Poll::Ready(Ok(offset as u64))
+        }
+    }
+}
@@ -1,13 +1,154 @@
 #![cfg_attr(not(any(test, feature = "std")), no_std)]
-#![feature(async_closure)]
+// a bug of a fs must not take the kernel down, see `vfs::FsError`
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::panic,
+        clippy::unimplemented,
+        clippy::unreachable,
+        clippy::todo
+    )
+)]
 
 extern crate alloc;
 
+#[cfg(feature = "conformance")]
+pub mod conformance;
 pub mod dev;
 pub mod dirty;
 pub mod file;
+#[cfg(any(test, feature = "std"))]
+pub mod metrics;
+#[cfg(feature = "sync-facade")]
+pub mod sync_facade;
 pub mod util;
 pub mod vfs;
 
 #[cfg(any(test, feature = "std"))]
 mod std;
+
+/// Unwrap the result of `$expr` like `?`, attaching the `vfs::ErrorContext`
+/// built by `$context` to an error.
+///
+/// Without the `error-context` feature, `$context` is not even evaluated.
+#[cfg(feature = "error-context")]
+#[macro_export]
+macro_rules! fs_try {
+    ($expr:expr, $context:expr) => {
+        $expr.map_err(|err: $crate::vfs::FsError| err.with_context($context))?
+    };
+}
+
+/// Unwrap the result of `$expr` like `?`, attaching the `vfs::ErrorContext`
+/// built by `$context` to an error.
+///
+/// Without the `error-context` feature, `$context` is not even evaluated.
+#[cfg(not(feature = "error-context"))]
+#[macro_export]
+macro_rules! fs_try {
+    ($expr:expr, $context:expr) => {
+        $expr?
+    };
+}
+
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub mod __private {
+    pub use alloc::boxed::Box;
+    pub use tracing;
+}
+
+/// Enter a `tracing` span at debug level named `$name` with the fields
+/// given as to `tracing::span!`, until the end of the scope.
+///
+/// Without the `tracing` feature, this is nothing, the fields are not even
+/// evaluated.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! fs_span {
+    ($name:expr $(, $($fields:tt)*)?) => {
+        let _span = $crate::__private::tracing::debug_span!($name $(, $($fields)*)?).entered();
+    };
+}
+
+/// Enter a `tracing` span at debug level named `$name` with the fields
+/// given as to `tracing::span!`, until the end of the scope.
+///
+/// Without the `tracing` feature, this is nothing, the fields are not even
+/// evaluated.
+#[cfg(not(feature = "tracing"))]
+#[macro_export]
+macro_rules! fs_span {
+    ($($args:tt)*) => {};
+}
+
+/// Record a `tracing` event at trace level, given as to `tracing::event!`.
+///
+/// Without the `tracing` feature, this is nothing.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! fs_event {
+    ($($args:tt)*) => {
+        $crate::__private::tracing::trace!($($args)*)
+    };
+}
+
+/// Record a `tracing` event at trace level, given as to `tracing::event!`.
+///
+/// Without the `tracing` feature, this is nothing.
+#[cfg(not(feature = "tracing"))]
+#[macro_export]
+macro_rules! fs_event {
+    ($($args:tt)*) => {};
+}
+
+/// The boxed future `$future` boxed again in a span like that of
+/// `fs_span!`, entered each time it is polled rather than held across its
+/// await points.
+///
+/// Without the `tracing` feature, this is `$future` itself.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! fs_instrument {
+    ($future:expr, $name:expr $(, $($fields:tt)*)?) => {
+        $crate::__private::Box::pin($crate::__private::tracing::Instrument::instrument(
+            $future,
+            $crate::__private::tracing::debug_span!($name $(, $($fields)*)?),
+        ))
+    };
+}
+
+/// The boxed future `$future` boxed again in a span like that of
+/// `fs_span!`, entered each time it is polled rather than held across its
+/// await points.
+///
+/// Without the `tracing` feature, this is `$future` itself.
+#[cfg(not(feature = "tracing"))]
+#[macro_export]
+macro_rules! fs_instrument {
+    ($future:expr, $($args:tt)*) => {
+        $future
+    };
+}
+
+#[cfg(all(test, not(feature = "tracing")))]
+mod test {
+    use alloc::boxed::Box;
+    use core::cell::Cell;
+    use core::future::{ready, Ready};
+    use core::pin::Pin;
+
+    #[test]
+    fn tracing_compiled_out() {
+        let evaluated = Cell::new(false);
+        fs_span!("span", field = evaluated.replace(true));
+        fs_event!(field = evaluated.replace(true), "event");
+        // not even wrapped
+        let future: Pin<Box<Ready<()>>> =
+            fs_instrument!(Box::pin(ready(())), "span", field = evaluated.replace(true));
+        drop(future);
+        assert!(!evaluated.get());
+    }
+}
@@ -0,0 +1,413 @@
+//! Counters of file systems and devices in the Prometheus text format, for
+//! debugging on the host
+//!
+//! Sources of counters are registered by name with `register()`, and
+//! `render()` lists all of them, each sample labelled with `fs="<name>"`.
+//! Metric names get the prefix `rcore_fs_`, counters end with `_total`.
+
+use crate::dev::block_cache::BlockCache;
+use crate::dev::{BlockDevice, ThrottledDevice, LATENCY_BUCKETS_US};
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use spin::Mutex;
+
+/// Whether a sample only goes up, or may go down
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+/// A counter or gauge of a `StatSource`
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// Name without the prefix, e.g. `cache_hits_total`
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub value: u64,
+}
+
+impl Sample {
+    pub fn counter(name: &'static str, help: &'static str, value: u64) -> Self {
+        Sample {
+            name,
+            help,
+            kind: MetricKind::Counter,
+            value,
+        }
+    }
+
+    pub fn gauge(name: &'static str, help: &'static str, value: u64) -> Self {
+        Sample {
+            name,
+            help,
+            kind: MetricKind::Gauge,
+            value,
+        }
+    }
+}
+
+/// A histogram of a `StatSource`
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    /// Name without the prefix, e.g. `throttle_latency_microseconds`
+    pub name: &'static str,
+    pub help: &'static str,
+    /// Upper bound of each bucket but the last, in increasing order
+    pub bounds: Vec<u64>,
+    /// Observations in each bucket, not cumulated. The last bucket counts
+    /// those over all bounds.
+    pub counts: Vec<u64>,
+    /// Sum of all observations
+    pub sum: u64,
+}
+
+/// Anything with counters to render
+pub trait StatSource: Send + Sync {
+    fn samples(&self) -> Vec<Sample>;
+
+    fn histograms(&self) -> Vec<Histogram> {
+        Vec::new()
+    }
+}
+
+/// Sources of counters by name
+pub struct Registry {
+    sources: Mutex<BTreeMap<String, Arc<dyn StatSource>>>,
+}
+
+impl Registry {
+    pub const fn new() -> Self {
+        Registry {
+            sources: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add `source` as `name`, replacing the one of that name if any
+    pub fn register(&self, name: &str, source: Arc<dyn StatSource>) {
+        self.sources.lock().insert(String::from(name), source);
+    }
+
+    /// Remove the source `name`, false if there is none
+    pub fn unregister(&self, name: &str) -> bool {
+        self.sources.lock().remove(name).is_some()
+    }
+
+    /// The samples of all sources in the Prometheus text format, by metric
+    /// then by source name. Counters are read one by one, not at once.
+    pub fn render(&self) -> String {
+        // sources are read unlocked, so they may register others meanwhile
+        let sources: Vec<_> = self
+            .sources
+            .lock()
+            .iter()
+            .map(|(name, source)| (escape_label(name), source.clone()))
+            .collect();
+        let mut families = BTreeMap::<String, Family>::new();
+        for (fs, source) in sources.iter() {
+            for sample in source.samples() {
+                let name = format!("rcore_fs_{}", sample.name);
+                let type_ = match sample.kind {
+                    MetricKind::Counter => "counter",
+                    MetricKind::Gauge => "gauge",
+                };
+                let family = families
+                    .entry(name.clone())
+                    .or_insert_with(|| Family::new(sample.help, type_));
+                let line = format!("{}{{fs=\"{}\"}} {}", name, fs, sample.value);
+                family.lines.push(line);
+            }
+            for histogram in source.histograms() {
+                let name = format!("rcore_fs_{}", histogram.name);
+                let family = families
+                    .entry(name.clone())
+                    .or_insert_with(|| Family::new(histogram.help, "histogram"));
+                let mut total = 0;
+                for (i, count) in histogram.counts.iter().enumerate() {
+                    total += count;
+                    let le = match histogram.bounds.get(i) {
+                        Some(bound) => bound.to_string(),
+                        None => String::from("+Inf"),
+                    };
+                    let line = format!("{}_bucket{{fs=\"{}\",le=\"{}\"}} {}", name, fs, le, total);
+                    family.lines.push(line);
+                }
+                let sum = format!("{}_sum{{fs=\"{}\"}} {}", name, fs, histogram.sum);
+                let count = format!("{}_count{{fs=\"{}\"}} {}", name, fs, total);
+                family.lines.extend([sum, count]);
+            }
+        }
+        let mut out = String::new();
+        for (name, family) in families {
+            let help = family.help.replace('\\', "\\\\").replace('\n', "\\n");
+            out.push_str(&format!("# HELP {} {}\n", name, help));
+            out.push_str(&format!("# TYPE {} {}\n", name, family.type_));
+            for line in family.lines {
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+        out
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Samples of one metric from all sources
+struct Family {
+    help: &'static str,
+    type_: &'static str,
+    lines: Vec<String>,
+}
+
+impl Family {
+    fn new(help: &'static str, type_: &'static str) -> Self {
+        Family {
+            help,
+            type_,
+            lines: Vec::new(),
+        }
+    }
+}
+
+/// `name` as a label value
+fn escape_label(name: &str) -> String {
+    name.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The registry of `register()` and `render()`
+static REGISTRY: Registry = Registry::new();
+
+/// Add `source` as `name` to the global registry, see `Registry::register()`
+pub fn register(name: &str, source: Arc<dyn StatSource>) {
+    REGISTRY.register(name, source)
+}
+
+/// Remove the source `name` from the global registry
+pub fn unregister(name: &str) -> bool {
+    REGISTRY.unregister(name)
+}
+
+/// The samples of the global registry, see `Registry::render()`
+pub fn render() -> String {
+    REGISTRY.render()
+}
+
+impl<T: BlockDevice> StatSource for BlockCache<T> {
+    fn samples(&self) -> Vec<Sample> {
+        let stats = self.stats();
+        vec![
+            Sample::counter(
+                "cache_hits_total",
+                "Lookups of blocks found in the cache",
+                stats.hits,
+            ),
+            Sample::counter(
+                "cache_misses_total",
+                "Lookups of blocks read from the device",
+                stats.misses,
+            ),
+            Sample::counter(
+                "cache_invalidations_total",
+                "Cached blocks dropped as the device was written around the cache",
+                stats.invalidations,
+            ),
+            Sample::counter(
+                "cache_coalesced_writes_total",
+                "Writes of more than one block",
+                stats.coalesced_writes,
+            ),
+            Sample::counter(
+                "cache_coalesced_blocks_total",
+                "Blocks written by writes of more than one block",
+                stats.coalesced_blocks,
+            ),
+        ]
+    }
+}
+
+impl StatSource for ThrottledDevice {
+    fn samples(&self) -> Vec<Sample> {
+        let stats = self.stats();
+        vec![
+            Sample::counter(
+                "throttle_window_waits_total",
+                "Times I/O waited for the next window",
+                stats.window_waits,
+            ),
+            Sample::gauge(
+                "throttle_queued",
+                "Operations waiting for the device",
+                stats.queued as u64,
+            ),
+            Sample::gauge(
+                "throttle_in_flight",
+                "Operations in the device",
+                stats.in_flight as u64,
+            ),
+        ]
+    }
+
+    fn histograms(&self) -> Vec<Histogram> {
+        let stats = self.stats();
+        vec![Histogram {
+            name: "throttle_latency_microseconds",
+            help: "Time taken by transfers, waits included",
+            bounds: LATENCY_BUCKETS_US.to_vec(),
+            counts: stats.latency.to_vec(),
+            sum: stats.latency_us,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+
+    struct Fixed {
+        hits: AtomicU64,
+        free: u64,
+        latency: Vec<u64>,
+    }
+
+    impl StatSource for Fixed {
+        fn samples(&self) -> Vec<Sample> {
+            vec![
+                Sample::counter("hits_total", "Hits", self.hits.load(Ordering::SeqCst)),
+                Sample::gauge("free_blocks", "Free blocks", self.free),
+            ]
+        }
+
+        fn histograms(&self) -> Vec<Histogram> {
+            if self.latency.is_empty() {
+                return Vec::new();
+            }
+            vec![Histogram {
+                name: "latency_microseconds",
+                help: "Latency",
+                bounds: vec![10, 100],
+                counts: self.latency.clone(),
+                sum: 1234,
+            }]
+        }
+    }
+
+    fn fixed(hits: u64, free: u64, latency: &[u64]) -> Arc<Fixed> {
+        Arc::new(Fixed {
+            hits: AtomicU64::new(hits),
+            free,
+            latency: latency.to_vec(),
+        })
+    }
+
+    #[test]
+    fn render_registry() {
+        let registry = Registry::new();
+        registry.register("sd\"0\"", fixed(3, 100, &[]));
+        registry.register("root", fixed(42, 7, &[1, 2, 3]));
+        assert_eq!(
+            registry.render(),
+            "# HELP rcore_fs_free_blocks Free blocks\n\
+             # TYPE rcore_fs_free_blocks gauge\n\
+             rcore_fs_free_blocks{fs=\"root\"} 7\n\
+             rcore_fs_free_blocks{fs=\"sd\\\"0\\\"\"} 100\n\
+             # HELP rcore_fs_hits_total Hits\n\
+             # TYPE rcore_fs_hits_total counter\n\
+             rcore_fs_hits_total{fs=\"root\"} 42\n\
+             rcore_fs_hits_total{fs=\"sd\\\"0\\\"\"} 3\n\
+             # HELP rcore_fs_latency_microseconds Latency\n\
+             # TYPE rcore_fs_latency_microseconds histogram\n\
+             rcore_fs_latency_microseconds_bucket{fs=\"root\",le=\"10\"} 1\n\
+             rcore_fs_latency_microseconds_bucket{fs=\"root\",le=\"100\"} 3\n\
+             rcore_fs_latency_microseconds_bucket{fs=\"root\",le=\"+Inf\"} 6\n\
+             rcore_fs_latency_microseconds_sum{fs=\"root\"} 1234\n\
+             rcore_fs_latency_microseconds_count{fs=\"root\"} 6\n"
+        );
+
+        assert!(registry.unregister("root"));
+        assert!(!registry.unregister("root"));
+        assert_eq!(
+            registry.render(),
+            "# HELP rcore_fs_free_blocks Free blocks\n\
+             # TYPE rcore_fs_free_blocks gauge\n\
+             rcore_fs_free_blocks{fs=\"sd\\\"0\\\"\"} 100\n\
+             # HELP rcore_fs_hits_total Hits\n\
+             # TYPE rcore_fs_hits_total counter\n\
+             rcore_fs_hits_total{fs=\"sd\\\"0\\\"\"} 3\n"
+        );
+        registry.unregister("sd\"0\"");
+        assert_eq!(registry.render(), "");
+    }
+
+    #[test]
+    fn render_while_counting() {
+        let registry = Arc::new(Registry::new());
+        let source = fixed(0, 0, &[]);
+        registry.register("busy", source.clone());
+        let counter = thread::spawn(move || {
+            for _ in 0..100_000 {
+                source.hits.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        // sources come and go meanwhile too
+        let churn = {
+            let registry = registry.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    registry.register("other", fixed(i, 0, &[1]));
+                    registry.unregister("other");
+                }
+            })
+        };
+        let mut last = 0;
+        while last < 100_000 {
+            let text = registry.render();
+            let line = text
+                .lines()
+                .find(|line| line.starts_with("rcore_fs_hits_total{fs=\"busy\"}"))
+                .unwrap();
+            let hits: u64 = line.rsplit(' ').next().unwrap().parse().unwrap();
+            assert!(hits >= last);
+            last = hits;
+        }
+        counter.join().unwrap();
+        churn.join().unwrap();
+    }
+
+    #[test]
+    fn throttled_device() {
+        use crate::dev::std_impl::StdTimeProvider;
+        use crate::dev::ThrottleConfig;
+
+        let file = std::sync::Mutex::new(tempfile::tempfile().unwrap());
+        let dev = Arc::new(ThrottledDevice::new(
+            Arc::new(file),
+            ThrottleConfig::new(Arc::new(StdTimeProvider)),
+        ));
+        crate::dev::Device::write_at(&*dev, 0, &[1; 512]).unwrap();
+        register("throttled", dev);
+        let text = render();
+        assert!(text.contains("# TYPE rcore_fs_throttle_latency_microseconds histogram\n"));
+        assert!(text.contains(
+            "rcore_fs_throttle_latency_microseconds_bucket{fs=\"throttled\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(text.contains("rcore_fs_throttle_in_flight{fs=\"throttled\"} 0\n"));
+        assert!(unregister("throttled"));
+        assert!(!render().contains("throttled"));
+    }
+}
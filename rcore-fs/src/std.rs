@@ -30,6 +30,21 @@ impl From<std::io::Error> for FsError {
     }
 }
 
+/// Time of creation of `m`, zero if the host does not record it
+fn created(m: &std::fs::Metadata) -> Timespec {
+    let since_epoch = m
+        .created()
+        .ok()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok());
+    match since_epoch {
+        Some(time) => Timespec {
+            sec: time.as_secs() as i64,
+            nsec: time.subsec_nanos() as i32,
+        },
+        None => Timespec { sec: 0, nsec: 0 },
+    }
+}
+
 #[cfg(unix)]
 impl From<std::fs::Metadata> for Metadata {
     fn from(m: std::fs::Metadata) -> Self {
@@ -51,20 +66,23 @@ impl From<std::fs::Metadata> for Metadata {
                 sec: m.ctime(),
                 nsec: m.ctime_nsec() as i32,
             },
+            btime: created(&m),
             type_: match (m.mode() & 0xf000) as _ {
                 libc::S_IFCHR => FileType::CharDevice,
                 libc::S_IFBLK => FileType::BlockDevice,
                 libc::S_IFDIR => FileType::Dir,
-                libc::S_IFREG => FileType::File,
                 libc::S_IFLNK => FileType::SymLink,
                 libc::S_IFSOCK => FileType::Socket,
-                _ => unimplemented!("unknown file type"),
+                libc::S_IFIFO => FileType::NamedPipe,
+                // S_IFREG, the only type left
+                _ => FileType::File,
             },
             mode: m.mode() as u16 & 0o777,
             nlinks: m.nlink() as usize,
             uid: m.uid() as usize,
             gid: m.gid() as usize,
             rdev: m.rdev() as usize,
+            flags: MetadataFlags::empty(),
         }
     }
 }
@@ -99,16 +117,16 @@ impl From<std::fs::Metadata> for Metadata {
                     nsec: mtime.nanoseconds() as i32,
                 }
             },
+            btime: created(&m),
             type_: {
                 let attr = m.file_attributes() as DWORD;
-                if (attr & winnt::FILE_ATTRIBUTE_NORMAL) != 0 {
-                    FileType::File
-                } else if (attr & winnt::FILE_ATTRIBUTE_DIRECTORY) != 0 {
+                // a file may have any other attributes, `NORMAL` only alone
+                if (attr & winnt::FILE_ATTRIBUTE_DIRECTORY) != 0 {
                     FileType::Dir
                 } else if (attr & winnt::FILE_ATTRIBUTE_REPARSE_POINT) != 0 {
                     FileType::SymLink
                 } else {
-                    unimplemented!("unknown file type")
+                    FileType::File
                 }
             },
             mode: 0,
@@ -116,6 +134,7 @@ impl From<std::fs::Metadata> for Metadata {
             uid: 0,
             gid: 0,
             rdev: 0,
+            flags: MetadataFlags::empty(),
         }
     }
 }
@@ -0,0 +1,139 @@
+//! Synchronous facade of the VFS, for contexts without an executor
+//!
+//! Only `INode::async_poll()` returns a future, the wrappers here poll it
+//! once with a no-op waker and fail with `FsError::Again` instead of waiting
+//! if it is not ready. This never happens with file systems over devices
+//! completing every request at once (e.g. in-memory devices, or `BlockCache`
+//! over a synchronous block device), which is the supported configuration.
+
+use crate::vfs::{FileSystem, FsError, INode, Metadata, PollStatus, Result};
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// Max number of symlinks followed by path lookups
+const MAX_FOLLOW: usize = 8;
+
+/// Poll `future` once, fail with `FsError::Again` if it is not ready
+pub fn poll_once<T, F>(future: Pin<&mut F>) -> Result<T>
+where
+    F: Future<Output = Result<T>> + ?Sized,
+{
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    match future.poll(&mut cx) {
+        Poll::Ready(ret) => ret,
+        Poll::Pending => Err(FsError::Again),
+    }
+}
+
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+}
+
+/// Synchronous wrapper of `INode`
+#[derive(Clone, Copy)]
+pub struct SyncINode<'a>(pub &'a dyn INode);
+
+impl<'a> SyncINode<'a> {
+    pub fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.0.read_at(offset, buf)
+    }
+
+    pub fn metadata(&self) -> Result<Metadata> {
+        self.0.metadata()
+    }
+
+    pub fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        self.0.find(name)
+    }
+
+    pub fn get_entry(&self, id: usize) -> Result<String> {
+        self.0.get_entry(id)
+    }
+
+    pub fn list(&self) -> Result<Vec<String>> {
+        self.0.list()
+    }
+
+    /// Poll the events by `async_poll()`
+    pub fn poll(&self) -> Result<PollStatus> {
+        poll_once(self.0.async_poll().as_mut())
+    }
+
+    /// Read the whole file at `path` relative to this INode, following symlinks
+    pub fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let inode = self.0.lookup_follow(path, MAX_FOLLOW)?;
+        let mut buf = vec![0; inode.metadata()?.size];
+        let mut len = 0;
+        while len < buf.len() {
+            match inode.read_at(len, &mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        buf.truncate(len);
+        Ok(buf)
+    }
+}
+
+/// Synchronous wrapper of `FileSystem`
+#[derive(Clone, Copy)]
+pub struct SyncFileSystem<'a>(pub &'a dyn FileSystem);
+
+impl<'a> SyncFileSystem<'a> {
+    pub fn root_inode(&self) -> Arc<dyn INode> {
+        self.0.root_inode()
+    }
+
+    /// Read the whole file at absolute `path`
+    pub fn read(&self, path: &str) -> Result<Vec<u8>> {
+        SyncINode(&*self.root_inode()).read(path.trim_start_matches('/'))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vfs::FsError;
+    use core::any::Any;
+    use core::future::{pending, ready};
+
+    /// INode whose `async_poll()` is never ready
+    struct PendingINode;
+
+    impl INode for PendingINode {
+        fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+            Err(FsError::NotSupported)
+        }
+        fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+            Err(FsError::NotSupported)
+        }
+        fn poll(&self) -> Result<PollStatus> {
+            Err(FsError::NotSupported)
+        }
+        fn async_poll<'a>(
+            &'a self,
+        ) -> Pin<alloc::boxed::Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>>
+        {
+            alloc::boxed::Box::pin(pending())
+        }
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn pending_is_error() {
+        assert_eq!(SyncINode(&PendingINode).poll().err(), Some(FsError::Again));
+        let mut future = ready(Ok(1));
+        assert_eq!(poll_once(Pin::new(&mut future)), Ok(1));
+    }
+}
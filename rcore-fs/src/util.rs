@@ -1,10 +1,20 @@
+use core::hint::spin_loop;
+use core::ops::{Deref, DerefMut};
+
 /// Given a range and iterate sub-range for each block
+///
+/// The ranges tile `[begin, end)` in order, one for each block it touches,
+/// and none is empty: nothing is yielded if `begin >= end`, and a range
+/// ending on a block boundary has `end` equal to the block size rather than
+/// an empty range following it in the next block. `block_size_log2` must be
+/// less than the bits of `usize`, 0 making every byte a block.
 pub struct BlockIter {
     pub begin: usize,
     pub end: usize,
     pub block_size_log2: u8,
 }
 
+/// `[begin, end)` inside `block`, with `begin < end <= block size`
 #[derive(Debug, Eq, PartialEq)]
 pub struct BlockRange {
     pub block: usize,
@@ -17,12 +27,15 @@ impl BlockRange {
     pub fn len(&self) -> usize {
         self.end - self.begin
     }
+    /// Whether the range covers the whole block
     pub fn is_full(&self) -> bool {
         self.len() == (1usize << self.block_size_log2)
     }
+    /// `begin` in the range the `BlockIter` was given
     pub fn origin_begin(&self) -> usize {
         (self.block << self.block_size_log2) + self.begin
     }
+    /// `end` in the range the `BlockIter` was given
     pub fn origin_end(&self) -> usize {
         (self.block << self.block_size_log2) + self.end
     }
@@ -54,6 +67,260 @@ impl Iterator for BlockIter {
     }
 }
 
+/// A `spin::RwLock` with a rank in the lock order of its owner: a thread
+/// holding locks may only take one of the same or a higher rank.
+///
+/// Debug builds with `std` track the ranks each thread holds and panic when
+/// a lock is taken out of order, before blocking on it, as that may deadlock
+/// with a thread taking the same locks in order. Otherwise it is a plain
+/// `spin::RwLock`.
+pub struct RankedRwLock<T> {
+    rank: u8,
+    lock: spin::RwLock<T>,
+}
+
+impl<T> RankedRwLock<T> {
+    pub const fn new(rank: u8, value: T) -> Self {
+        RankedRwLock {
+            rank,
+            lock: spin::RwLock::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RankedGuard<spin::RwLockReadGuard<'_, T>> {
+        lock_rank::acquire(self.rank);
+        RankedGuard {
+            rank: self.rank,
+            guard: self.lock.read(),
+        }
+    }
+
+    pub fn write(&self) -> RankedGuard<spin::RwLockWriteGuard<'_, T>> {
+        lock_rank::acquire(self.rank);
+        RankedGuard {
+            rank: self.rank,
+            guard: self.lock.write(),
+        }
+    }
+
+    /// Never blocks, so it may be taken out of order
+    pub fn try_read(&self) -> Option<RankedGuard<spin::RwLockReadGuard<'_, T>>> {
+        let guard = self.lock.try_read()?;
+        lock_rank::hold(self.rank);
+        Some(RankedGuard {
+            rank: self.rank,
+            guard,
+        })
+    }
+}
+
+/// A guard of `RankedRwLock`
+pub struct RankedGuard<G> {
+    rank: u8,
+    guard: G,
+}
+
+impl<G: Deref> Deref for RankedGuard<G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &G::Target {
+        &self.guard
+    }
+}
+
+impl<G: DerefMut> DerefMut for RankedGuard<G> {
+    fn deref_mut(&mut self) -> &mut G::Target {
+        &mut self.guard
+    }
+}
+
+impl<G> Drop for RankedGuard<G> {
+    fn drop(&mut self) {
+        lock_rank::release(self.rank);
+    }
+}
+
+#[cfg(all(debug_assertions, any(test, feature = "std")))]
+mod lock_rank {
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    ::std::thread_local! {
+        /// ranks of the locks held by this thread
+        static HELD: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub fn acquire(rank: u8) {
+        let highest = HELD.with(|held| held.borrow().iter().copied().max());
+        if let Some(highest) = highest {
+            assert!(
+                rank >= highest,
+                "lock of rank {} taken while holding rank {}",
+                rank,
+                highest
+            );
+        }
+        hold(rank);
+    }
+
+    pub fn hold(rank: u8) {
+        HELD.with(|held| held.borrow_mut().push(rank));
+    }
+
+    pub fn release(rank: u8) {
+        // a thread being torn down has nothing to check any more
+        let _ = HELD.try_with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(pos) = held.iter().rposition(|&held| held == rank) {
+                held.remove(pos);
+            }
+        });
+    }
+}
+
+#[cfg(not(all(debug_assertions, any(test, feature = "std"))))]
+mod lock_rank {
+    pub fn acquire(_rank: u8) {}
+    pub fn hold(_rank: u8) {}
+    pub fn release(_rank: u8) {}
+}
+
+/// A counting semaphore, spinning like `spin::Mutex` while no permit is
+/// free, e.g. to bound the operations in a device at once.
+///
+/// High-priority waiters go before the others, and `try_acquire()` never
+/// takes permits while anyone waits, so work which may as well be skipped
+/// does not delay the rest.
+pub struct Semaphore {
+    state: spin::Mutex<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    max: usize,
+    used: usize,
+    /// high-priority waiters
+    waiting_prio: usize,
+    stats: SemaphoreStats,
+}
+
+/// Counters of a `Semaphore` since made, and its permits now
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct SemaphoreStats {
+    /// Permits taken now
+    pub in_use: usize,
+    /// Most permits ever taken at once
+    pub peak: usize,
+    /// Acquisitions waiting now
+    pub waiting: usize,
+    /// Acquisitions which waited
+    pub waits: u64,
+    /// Failed `try_acquire()`
+    pub refused: u64,
+}
+
+/// Permits taken from a `Semaphore`, given back on drop
+pub struct Permit<'a> {
+    semaphore: &'a Semaphore,
+    count: usize,
+}
+
+impl SemaphoreState {
+    /// Take `count` permits if free, and no high-priority waiter is left
+    /// unless `prio`
+    fn take(&mut self, count: usize, prio: bool) -> bool {
+        // more than `max` only if alone, as `max` may be lowered meanwhile
+        let free = self.used == 0 || self.used + count <= self.max;
+        if !free || (!prio && self.waiting_prio > 0) {
+            return false;
+        }
+        self.used += count;
+        self.stats.peak = self.stats.peak.max(self.used);
+        true
+    }
+}
+
+impl Semaphore {
+    /// `max` permits, at least one
+    pub fn new(max: usize) -> Self {
+        Semaphore {
+            state: spin::Mutex::new(SemaphoreState {
+                max: max.max(1),
+                used: 0,
+                waiting_prio: 0,
+                stats: SemaphoreStats::default(),
+            }),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.state.lock().max
+    }
+
+    /// Set the number of permits, at least one. Those taken beyond it are
+    /// kept until given back.
+    pub fn set_max(&self, max: usize) {
+        self.state.lock().max = max.max(1);
+    }
+
+    /// Take `count` permits, at most `max()`, waiting until they are free.
+    /// High-priority waiters go before the others.
+    pub fn acquire(&self, count: usize, prio: bool) -> Permit<'_> {
+        let mut state = self.state.lock();
+        let count = count.clamp(1, state.max);
+        if !state.take(count, prio) {
+            state.stats.waiting += 1;
+            state.stats.waits += 1;
+            state.waiting_prio += prio as usize;
+            drop(state);
+            loop {
+                spin_loop();
+                let mut state = self.state.lock();
+                // a waiter of high priority does not wait for itself
+                state.waiting_prio -= prio as usize;
+                let taken = state.take(count, prio);
+                if taken {
+                    state.stats.waiting -= 1;
+                    break;
+                }
+                state.waiting_prio += prio as usize;
+            }
+        }
+        Permit {
+            semaphore: self,
+            count,
+        }
+    }
+
+    /// Take `count` permits, at most `max()`, if free now and nobody
+    /// waits, e.g. for work which may as well be skipped
+    pub fn try_acquire(&self, count: usize) -> Option<Permit<'_>> {
+        let mut state = self.state.lock();
+        let count = count.clamp(1, state.max);
+        if state.stats.waiting > 0 || !state.take(count, false) {
+            state.stats.refused += 1;
+            return None;
+        }
+        Some(Permit {
+            semaphore: self,
+            count,
+        })
+    }
+
+    pub fn stats(&self) -> SemaphoreStats {
+        let state = self.state.lock();
+        SemaphoreStats {
+            in_use: state.used,
+            ..state.stats
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.state.lock().used -= self.count;
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -94,4 +361,132 @@ mod test {
         );
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn block_iter_edges() {
+        let ranges = |begin, end, block_size_log2| {
+            BlockIter {
+                begin,
+                end,
+                block_size_log2,
+            }
+            .map(|r| (r.block, r.begin, r.end))
+            .collect::<Vec<_>>()
+        };
+        // empty or reversed
+        assert_eq!(ranges(0, 0, 12), []);
+        assert_eq!(ranges(0x1000, 0x1000, 12), []);
+        assert_eq!(ranges(0x1001, 0x1000, 12), []);
+        // ending on a block boundary
+        assert_eq!(ranges(0, 0x1000, 12), [(0, 0, 0x1000)]);
+        assert_eq!(
+            ranges(0x800, 0x2000, 12),
+            [(0, 0x800, 0x1000), (1, 0, 0x1000)]
+        );
+        assert_eq!(ranges(0x1000, 0x1001, 12), [(1, 0, 1)]);
+        assert_eq!(ranges(0xfff, 0x1001, 12), [(0, 0xfff, 0x1000), (1, 0, 1)]);
+        // one byte blocks
+        assert_eq!(ranges(3, 6, 0), [(3, 0, 1), (4, 0, 1), (5, 0, 1)]);
+        // one block covering all
+        let log2 = usize::BITS as u8 - 1;
+        assert_eq!(ranges(5, 9, log2), [(0, 5, 9)]);
+    }
+
+    #[test]
+    fn block_iter_tiles_range() {
+        // xorshift, so failures are reproducible
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut rand = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize
+        };
+        for _ in 0..10000 {
+            let block_size_log2 = (rand() % 13) as u8;
+            let begin = rand() % 0x10000;
+            let end = begin + rand() % 0x4000;
+            let block_size = 1usize << block_size_log2;
+            let mut next = begin;
+            for range in (BlockIter {
+                begin,
+                end,
+                block_size_log2,
+            }) {
+                assert_eq!(range.block_size_log2, block_size_log2);
+                assert!(range.begin < range.end && range.end <= block_size);
+                assert_eq!(range.origin_begin(), next);
+                assert_eq!(range.origin_end(), next + range.len());
+                assert_eq!(range.origin_begin() >> block_size_log2, range.block);
+                assert_eq!(range.is_full(), range.begin == 0 && range.end == block_size);
+                next = range.origin_end();
+            }
+            assert_eq!(next, begin.max(end));
+        }
+    }
+
+    #[test]
+    fn semaphore_prio_first() {
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::vec::Vec;
+
+        let semaphore = Arc::new(Semaphore::new(2));
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let held = semaphore.acquire(5, false);
+        assert_eq!(semaphore.stats().in_use, 2);
+        assert!(semaphore.try_acquire(1).is_none());
+        let mut threads = Vec::new();
+        for (i, prio) in [(0, false), (1, false), (2, true)] {
+            let (shared, order) = (semaphore.clone(), order.clone());
+            threads.push(thread::spawn(move || {
+                let _permit = shared.acquire(1, prio);
+                order.lock().unwrap().push(i);
+            }));
+            while semaphore.stats().waiting != threads.len() {
+                thread::yield_now();
+            }
+        }
+        // the prioritized one first, even with two permits free
+        drop(held);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let order = order.lock().unwrap();
+        assert_eq!(order[0], 2);
+        assert_eq!(order.len(), 3);
+        assert!(semaphore.try_acquire(2).is_some());
+        let stats = SemaphoreStats {
+            in_use: 0,
+            peak: 2,
+            waiting: 0,
+            waits: 3,
+            refused: 1,
+        };
+        assert_eq!(semaphore.stats(), stats);
+    }
+
+    #[test]
+    fn ranked_lock_order() {
+        let low = RankedRwLock::new(1, 0);
+        let high = RankedRwLock::new(2, 0);
+        {
+            let _low = low.read();
+            let _again = low.read();
+            *high.write() += 1;
+        }
+        // released, so taking them again in any order is fine
+        let _high = high.read();
+        assert!(low.try_read().is_some());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "lock of rank 1 taken while holding rank 2")]
+    fn ranked_lock_out_of_order() {
+        let low = RankedRwLock::new(1, 0);
+        let high = RankedRwLock::new(2, 0);
+        let _high = high.read();
+        let _low = low.read();
+    }
 }
@@ -1,20 +1,84 @@
-use crate::dev::DevError;
-use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+pub mod ioctl;
+pub mod scoped;
+
+use crate::dev::{CacheHint, DevError};
+use alloc::{borrow::Cow, boxed::Box, string::String, sync::Arc, vec::Vec};
 use core::any::Any;
 use core::fmt;
 use core::future::Future;
 use core::pin::Pin;
 use core::result;
 use core::str;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 
 /// Abstract file system object such as file or directory.
+///
+/// # Errors by file type
+///
+/// An operation meaningless for the type of the INode fails the same way in
+/// every fs, and through every wrapper:
+///
+/// - `read_at()`, `write_at()` and `resize()` on a dir: `IsDir`
+/// - `create()`, `symlink()`, `link()`, `unlink()`, `move_()`, `find()` and
+///   `get_entry()` on anything but a dir, a symlink too: `NotDir`
+/// - `link()` to a dir, in a fs with `FsCapabilities::HARDLINK`: `IsDir`
+///
+/// `conformance::check_type_errors()` checks them.
+///
+/// # Return of reads and writes
+///
+/// `read_at()` and `write_at()`, and their `_direct` and `_with` forms,
+/// return the same way in every fs, and through every wrapper:
+///
+/// - `Ok(n)` with `n` below `buf.len()`: exactly the first `n` bytes were
+///   transferred. The rest of `buf` is left as is, unless an error ended
+///   the I/O short, which going on from `offset + n` returns.
+/// - `Ok(0)` from a read of a non-empty `buf`: end of file, never "try
+///   again". A read straddling the end returns exactly the bytes before it.
+/// - `Err(_)`: nothing was transferred. Only `Again` means trying again
+///   without any progress may succeed.
+/// - A write of nothing returns `Ok(0)` or fails, and changes nothing, not
+///   even the size when past the end.
+///
+/// `conformance::check_io()` checks them.
 pub trait INode: Any + Sync + Send {
-    /// Read bytes at `offset` into `buf`, return the number of bytes read.
+    /// Read bytes at `offset` into `buf`, return the number of bytes read,
+    /// see the trait doc.
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
 
-    /// Write bytes at `offset` from `buf`, return the number of bytes written.
+    /// Write bytes at `offset` from `buf`, return the number of bytes
+    /// written, see the trait doc.
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
 
+    /// Read bypassing the caches of the fs and device, for `O_DIRECT`.
+    /// The fs may require `offset` and `buf.len()` to be aligned, failing
+    /// with `InvalidParam` otherwise. By default it is `read_at()`.
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.read_at(offset, buf)
+    }
+
+    /// Write bypassing the caches of the fs and device, see
+    /// `read_at_direct()`. By default it is `write_at()`.
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.write_at(offset, buf)
+    }
+
+    /// Read like `read_at()`, calling `ctx.checkpoint()` on the way. When
+    /// interrupted, return the bytes read so far, or `FsError::Interrupted`
+    /// if none.
+    fn read_at_with(&self, offset: usize, buf: &mut [u8], ctx: &TaskContext) -> Result<usize> {
+        ctx.checkpoint()?;
+        self.read_at(offset, buf)
+    }
+
+    /// Write like `write_at()`, calling `ctx.checkpoint()` on the way. When
+    /// interrupted, return the bytes written so far, or
+    /// `FsError::Interrupted` if none.
+    fn write_at_with(&self, offset: usize, buf: &[u8], ctx: &TaskContext) -> Result<usize> {
+        ctx.checkpoint()?;
+        self.write_at(offset, buf)
+    }
+
     /// Poll the events, return a bitmap of events.
     fn poll(&self) -> Result<PollStatus>;
 
@@ -22,8 +86,7 @@ pub trait INode: Any + Sync + Send {
     fn async_poll<'a>(
         &'a self,
     ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
-        let f = async move || self.poll();
-        Box::pin(f())
+        Box::pin(async move { self.poll() })
     }
 
     /// Get metadata of the INode
@@ -31,7 +94,14 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
-    /// Set metadata of the INode
+    /// Get the fields of `mask` only, which may be cheaper than `metadata()`.
+    /// Fields not supported are `None`.
+    fn metadata_partial(&self, mask: MetadataMask) -> Result<PartialMetadata> {
+        Ok(PartialMetadata::from_metadata(&self.metadata()?, mask))
+    }
+
+    /// Set metadata of the INode. `btime` is ignored, it is only set when
+    /// the inode is made.
     fn set_metadata(&self, _metadata: &Metadata) -> Result<()> {
         Err(FsError::NotSupported)
     }
@@ -46,11 +116,44 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// Sync this INode and, for a dir, those below it, with the file
+    /// systems mounted there, but not the rest of the fs. By default only
+    /// `sync_all()`.
+    fn sync_subtree(&self) -> Result<()> {
+        self.sync_all()
+    }
+
     /// Resize the file
     fn resize(&self, _len: usize) -> Result<()> {
         Err(FsError::NotSupported)
     }
 
+    /// Resize the file, calling `ctx.checkpoint()` on the way.
+    /// On `FsError::Interrupted`, see the fs for the state left.
+    fn resize_with(&self, len: usize, ctx: &TaskContext) -> Result<()> {
+        ctx.checkpoint()?;
+        self.resize(len)
+    }
+
+    /// Allocate space for `len` bytes at `offset`, growing the file over
+    /// them unless `mode` has `KEEP_SIZE`, or free it with `PUNCH_HOLE`, see
+    /// `FallocateMode`. `Unsupported` if the fs lacks
+    /// `FsCapabilities::PREALLOC`.
+    fn fallocate(&self, _offset: usize, _len: usize, _mode: FallocateMode) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Get the inode flags, e.g. append-only
+    fn get_flags(&self) -> Result<InodeFlags> {
+        Ok(InodeFlags::empty())
+    }
+
+    /// Set the inode flags, `Unsupported` if the fs lacks
+    /// `FsCapabilities::INODE_FLAGS`
+    fn set_flags(&self, _flags: InodeFlags) -> Result<()> {
+        Err(FsError::Unsupported)
+    }
+
     /// Create a new INode in the directory
     fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn INode>> {
         self.create2(name, type_, mode, 0)
@@ -67,9 +170,86 @@ pub trait INode: Any + Sync + Send {
         self.create(name, type_, mode)
     }
 
-    /// Create a hard link `name` to `other`
+    /// Create a new INode in the directory on behalf of `ctx`, which gives
+    /// its owner and umask. File systems without ownership ignore `ctx`.
+    fn create3(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+        _ctx: &CreateContext,
+    ) -> Result<Arc<dyn INode>> {
+        self.create2(name, type_, mode, data)
+    }
+
+    /// Create new INodes in the directory at once
+    ///
+    /// File systems overriding it create all of them or none. The default
+    /// one calls `create2()` one by one and stops at the first error.
+    fn create_batch(&self, entries: &[CreateSpec]) -> Result<Vec<Arc<dyn INode>>> {
+        entries
+            .iter()
+            .map(|entry| self.create2(entry.name, entry.type_, entry.mode, entry.data))
+            .collect()
+    }
+
+    /// Find the INode `name` in the directory, or create it if not exist,
+    /// as `open()` with `O_CREAT`. Also return whether it was created.
+    /// An existing INode of another type than `type_` is `EntryExist`.
+    ///
+    /// File systems overriding it look up and create under the lock of the
+    /// directory, so that one of concurrent callers creates and all get the
+    /// same INode. The default one retries `find()` and `create()` a few
+    /// times against other callers, then fails with `Again`.
+    fn find_or_create(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+    ) -> Result<(Arc<dyn INode>, bool)> {
+        const RETRIES: usize = 8;
+        for _ in 0..RETRIES {
+            match self.find(name) {
+                Ok(inode) if inode.metadata()?.type_ == type_ => return Ok((inode, false)),
+                Ok(_) => return Err(FsError::EntryExist),
+                Err(err) if *err.root_cause() == FsError::EntryNotFound => {}
+                Err(err) => return Err(err),
+            }
+            match self.create(name, type_, mode) {
+                Ok(inode) => return Ok((inode, true)),
+                // created by another caller meanwhile
+                Err(err) if *err.root_cause() == FsError::EntryExist => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Err(FsError::Again)
+    }
+
+    /// Create a symlink `name` to `target`, `InvalidParam` if `target` is
+    /// empty.
+    ///
+    /// File systems overriding it write `target` before the entry shows, so
+    /// that no empty symlink is ever seen. The default one calls `create()`
+    /// then `write_at()`, and unlinks it again if the write fails.
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>> {
+        if target.is_empty() {
+            return Err(FsError::InvalidParam);
+        }
+        let inode = self.create(name, FileType::SymLink, 0o777)?;
+        match inode.write_at(0, target.as_bytes()) {
+            Ok(len) if len == target.len() => Ok(inode),
+            result => {
+                let _ = self.unlink(name);
+                Err(result.err().unwrap_or(FsError::NoDeviceSpace))
+            }
+        }
+    }
+
+    /// Create a hard link `name` to `other`, `Unsupported` if the fs lacks
+    /// `FsCapabilities::HARDLINK`
     fn link(&self, _name: &str, _other: &Arc<dyn INode>) -> Result<()> {
-        Err(FsError::NotSupported)
+        Err(FsError::Unsupported)
     }
 
     /// Delete a hard link `name`
@@ -77,8 +257,14 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
+    /// `unlink()` by the bytes of the name, see `find_bytes()`
+    fn unlink_bytes(&self, name: &[u8]) -> Result<()> {
+        self.unlink(str::from_utf8(name).map_err(|_| FsError::InvalidName)?)
+    }
+
     /// Move INode `self/old_name` to `target/new_name`.
     /// If `target` equals `self`, do rename.
+    /// If both names are links to the same INode, do nothing, as in POSIX.
     fn move_(&self, _old_name: &str, _target: &Arc<dyn INode>, _new_name: &str) -> Result<()> {
         Err(FsError::NotSupported)
     }
@@ -88,11 +274,40 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
-    /// Get the name of directory entry
+    /// `find()` by the bytes of the name, also reaching names on disk which
+    /// are not UTF-8. By default, such names are `InvalidName`.
+    fn find_bytes(&self, name: &[u8]) -> Result<Arc<dyn INode>> {
+        self.find(str::from_utf8(name).map_err(|_| FsError::InvalidName)?)
+    }
+
+    /// A counter of the changes of the entries of this dir, growing with
+    /// each successful create, link, unlink or rename in it. Caches of
+    /// lookups or listings record it, and drop what they found once it has
+    /// changed. 0 if the fs does not count them.
+    fn change_cookie(&self) -> u64 {
+        0
+    }
+
+    /// A counter of the changes of the content of this file, growing with
+    /// each successful write, resize or fallocate that changes it, but not
+    /// with reads or changes of metadata only. It never goes back while the
+    /// fs is mounted. Caches of the content record it, like the cookie of
+    /// `change_cookie()`. 0 if the fs does not count them.
+    fn data_version(&self) -> u64 {
+        0
+    }
+
+    /// Get the name of directory entry. A name which is not UTF-8 is
+    /// escaped by `escape_name()`.
     fn get_entry(&self, _id: usize) -> Result<String> {
         Err(FsError::NotSupported)
     }
 
+    /// The exact bytes of the name of directory entry, for `find_bytes()`
+    fn get_entry_bytes(&self, id: usize) -> Result<Vec<u8>> {
+        Ok(self.get_entry(id)?.into_bytes())
+    }
+
     /// Get the name of directory entry with metadata
     fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
         // a default and slow implementation
@@ -101,6 +316,56 @@ pub trait INode: Any + Sync + Send {
         Ok((entry.metadata()?, name))
     }
 
+    /// Get the name of directory entry with the fields of `mask`
+    fn get_entry_with_metadata_partial(
+        &self,
+        id: usize,
+        mask: MetadataMask,
+    ) -> Result<(PartialMetadata, String)> {
+        let name = self.get_entry(id)?;
+        let entry = self.find(&name)?;
+        Ok((entry.metadata_partial(mask)?, name))
+    }
+
+    /// Fill `out` with the entries from `cursor` on, with their type, and
+    /// move it past them. Return how many, fewer than `out.len()` only at
+    /// the end.
+    ///
+    /// Memory use is bounded by `out`, whose names keep their buffers from
+    /// call to call. Fails with `InvalidParam` if the dir has changed since
+    /// the first call with `cursor`, by `change_cookie()`: the entries may
+    /// have moved, and going on may skip or repeat some of them. Restart
+    /// with a new cursor, or go on with `DirCursor::at()` accepting that.
+    fn next_entries(&self, cursor: &mut DirCursor, out: &mut [DirEntrySlot]) -> Result<usize> {
+        // a default and slow implementation
+        let cookie = self.change_cookie();
+        if *cursor.cookie.get_or_insert(cookie) != cookie {
+            return Err(FsError::InvalidParam);
+        }
+        let mask = MetadataMask::INODE | MetadataMask::TYPE;
+        let mut next = cursor.next;
+        let mut filled = 0;
+        for slot in out.iter_mut() {
+            let (metadata, name) = match self.get_entry_with_metadata_partial(next, mask) {
+                Ok(entry) => entry,
+                Err(err) if *err.root_cause() == FsError::EntryNotFound => break,
+                Err(err) => return Err(err),
+            };
+            next += 1;
+            slot.inode = metadata.inode.ok_or(FsError::NotSupported)?;
+            slot.type_ = metadata.type_.ok_or(FsError::NotSupported)?;
+            slot.name = name;
+            slot.raw_name = None;
+            slot.next = next;
+            filled += 1;
+        }
+        if self.change_cookie() != cookie {
+            return Err(FsError::InvalidParam);
+        }
+        cursor.next = next;
+        Ok(filled)
+    }
+
     /// Control device
     fn io_control(&self, _cmd: u32, _data: usize) -> Result<usize> {
         Err(FsError::NotSupported)
@@ -111,9 +376,62 @@ pub trait INode: Any + Sync + Send {
         Err(FsError::NotSupported)
     }
 
-    /// Get the file system of the INode
-    fn fs(&self) -> Arc<dyn FileSystem> {
-        unimplemented!();
+    /// Called on open, `exclusive` to be the only opener.
+    /// Keep the guard while opened, see `OpenTracker`.
+    fn open_hook(&self, _exclusive: bool) -> Result<OpenGuard> {
+        Ok(OpenGuard::default())
+    }
+
+    /// Keep the INode in memory, its blocks where they are and its last link
+    /// until the guard is dropped, e.g. for a swap file or a buffer of DMA.
+    /// Unlinking the last link of a pinned INode fails with `Busy`. Pins add
+    /// up: it is released once every guard is dropped.
+    fn pin(&self) -> Result<PinGuard> {
+        Err(FsError::NotSupported)
+    }
+
+    /// How the blocks of the file are to be cached from now on, for all its
+    /// opened files, e.g. `DropAfterUse` for a file read once. Only a hint,
+    /// ignored by default.
+    fn set_cache_hint(&self, _hint: CacheHint) {}
+
+    /// Dir to resolve absolute paths from in `lookup_follow()`
+    fn lookup_root(&self) -> Result<Arc<dyn INode>> {
+        Ok(self.fs()?.root_inode())
+    }
+
+    /// Get the file system of the INode, `NotSupported` by default, for
+    /// INodes in no file system, e.g. the devices of a `DevFS`. Every INode
+    /// of a file system overrides it.
+    fn fs(&self) -> Result<Arc<dyn FileSystem>> {
+        Err(FsError::NotSupported)
+    }
+
+    /// Info of the file system the content of this INode is on, what
+    /// statvfs of its path reports. That of `fs()` by default, overridden
+    /// where another fs may be mounted over it.
+    fn fs_info(&self) -> Result<FsInfo> {
+        Ok(self.fs()?.info())
+    }
+
+    /// Identity of the file, the same through all wrappers of its INode.
+    ///
+    /// By default the one of the wrapped INode, else the address of this
+    /// INode with fs 0, which only holds while it is alive and only if there
+    /// is a single INode object for the file.
+    fn ino_key(&self) -> InodeKey {
+        match self.wrapped() {
+            Some(inner) => inner.ino_key(),
+            None => InodeKey {
+                fs: 0,
+                inode: self.as_any_ref() as *const dyn Any as *const () as usize,
+            },
+        }
+    }
+
+    /// The INode this one wraps, e.g. to change how it is found or seen
+    fn wrapped(&self) -> Option<&Arc<dyn INode>> {
+        None
     }
 
     /// This is used to implement dynamics cast.
@@ -127,6 +445,16 @@ impl dyn INode {
         self.as_any_ref().downcast_ref::<T>()
     }
 
+    /// The innermost INode under all wrappers, the one of the file system
+    /// to downcast to
+    pub fn unwrap_inner(&self) -> &dyn INode {
+        let mut inode = self;
+        while let Some(inner) = inode.wrapped() {
+            inode = inner.as_ref();
+        }
+        inode
+    }
+
     /// Get all directory entries as a Vec
     pub fn list(&self) -> Result<Vec<String>> {
         let info = self.metadata()?;
@@ -146,6 +474,9 @@ impl dyn INode {
     }
 
     /// Lookup path from current INode, and follow symlinks at most `follow_times` times
+    ///
+    /// A symlink not followed is no dir: a path going through it is
+    /// `NotDir`. An empty symlink is `Corrupted`.
     pub fn lookup_follow(&self, path: &str, follow_times: usize) -> Result<Arc<dyn INode>> {
         if self.metadata()?.type_ != FileType::Dir {
             return Err(FsError::NotDir);
@@ -153,7 +484,7 @@ impl dyn INode {
 
         // handle absolute path
         let (mut result, mut rest_path) = if let Some(rest) = path.strip_prefix('/') {
-            (self.fs().root_inode(), String::from(rest))
+            (self.lookup_root()?, String::from(rest))
         } else {
             (self.find(".")?, String::from(path))
         };
@@ -181,6 +512,9 @@ impl dyn INode {
             if inode.metadata()?.type_ == FileType::SymLink && follow_times > 0 {
                 let mut content = [0u8; 256];
                 let len = inode.read_at(0, &mut content)?;
+                if len == 0 {
+                    return Err(FsError::Corrupted);
+                }
                 let link_path =
                     String::from(str::from_utf8(&content[..len]).map_err(|_| FsError::NotDir)?);
                 // result remains unchanged
@@ -201,6 +535,220 @@ pub enum IOCTLError {
     NotCharDevice = 25,  // ENOTTY
 }
 
+/// Advisory tracking of the openers of an INode, for `INode::open_hook()`.
+///
+/// Any number of shared opens, or a single exclusive one, are allowed at
+/// the same time. Others fail with `FsError::Busy`.
+#[derive(Debug, Default)]
+pub struct OpenTracker {
+    /// Number of shared openers, or `EXCLUSIVE`
+    openers: Arc<AtomicUsize>,
+}
+
+impl OpenTracker {
+    const EXCLUSIVE: usize = usize::MAX;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open once, until the guard is dropped
+    pub fn open(&self, exclusive: bool) -> Result<OpenGuard> {
+        let result = if exclusive {
+            self.openers
+                .compare_exchange(0, Self::EXCLUSIVE, Ordering::Acquire, Ordering::Relaxed)
+        } else {
+            self.openers
+                .fetch_update(Ordering::Acquire, Ordering::Relaxed, |n| {
+                    // the last shared slot would look exclusive
+                    if n >= Self::EXCLUSIVE - 1 {
+                        None
+                    } else {
+                        Some(n + 1)
+                    }
+                })
+        };
+        result.map_err(|_| FsError::Busy)?;
+        Ok(OpenGuard {
+            openers: Some(self.openers.clone()),
+            exclusive,
+            number: None,
+            release: None,
+        })
+    }
+
+    /// Number of openers, `usize::MAX` if opened exclusively
+    pub fn openers(&self) -> usize {
+        self.openers.load(Ordering::Relaxed)
+    }
+}
+
+/// An open of an INode, released on drop
+#[derive(Default)]
+pub struct OpenGuard {
+    /// `None` if not tracked
+    openers: Option<Arc<AtomicUsize>>,
+    exclusive: bool,
+    /// see `numbered()`
+    number: Option<usize>,
+    /// run on drop
+    release: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl OpenGuard {
+    /// An open given `number` by the INode, e.g. the pseudo-terminal it made,
+    /// running `release` once closed
+    pub fn numbered(number: usize, release: impl FnOnce() + Send + Sync + 'static) -> Self {
+        OpenGuard {
+            openers: None,
+            exclusive: false,
+            number: Some(number),
+            release: Some(Box::new(release)),
+        }
+    }
+
+    /// The number given by the INode opened, see `numbered()`
+    pub fn number(&self) -> Option<usize> {
+        self.number
+    }
+}
+
+impl fmt::Debug for OpenGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OpenGuard")
+            .field("openers", &self.openers)
+            .field("exclusive", &self.exclusive)
+            .field("number", &self.number)
+            .finish()
+    }
+}
+
+impl Drop for OpenGuard {
+    fn drop(&mut self) {
+        if let Some(openers) = &self.openers {
+            if self.exclusive {
+                openers.store(0, Ordering::Release);
+            } else {
+                openers.fetch_sub(1, Ordering::Release);
+            }
+        }
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+/// A pin of an INode, released on drop, see `INode::pin()`
+pub struct PinGuard {
+    /// run on drop
+    release: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl PinGuard {
+    /// A pin running `release` once dropped
+    pub fn new(release: impl FnOnce() + Send + Sync + 'static) -> Self {
+        PinGuard {
+            release: Some(Box::new(release)),
+        }
+    }
+}
+
+impl fmt::Debug for PinGuard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PinGuard").finish_non_exhaustive()
+    }
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+/// Flag to ask a long-running operation to stop, shared by clones
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make the operations watching the token fail with `FsError::Interrupted`
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+/// Options of a long-running operation like `INode::resize_with()`.
+///
+/// The operation calls `checkpoint()` every `interval` blocks, which runs the
+/// yield hook so that a cooperative executor can schedule other tasks, then
+/// stops the operation if the token is cancelled.
+pub struct TaskContext {
+    token: Option<CancelToken>,
+    yield_now: Option<Box<dyn Fn() + Send + Sync>>,
+    interval: usize,
+}
+
+impl Default for TaskContext {
+    fn default() -> Self {
+        TaskContext {
+            token: None,
+            yield_now: None,
+            interval: Self::DEFAULT_INTERVAL,
+        }
+    }
+}
+
+impl TaskContext {
+    /// Default number of blocks between checkpoints
+    pub const DEFAULT_INTERVAL: usize = 256;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_token(mut self, token: CancelToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    pub fn with_yield(mut self, yield_now: impl Fn() + Send + Sync + 'static) -> Self {
+        self.yield_now = Some(Box::new(yield_now));
+        self
+    }
+
+    /// Set the number of blocks between checkpoints, at least 1
+    pub fn with_interval(mut self, interval: usize) -> Self {
+        self.interval = interval.max(1);
+        self
+    }
+
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    /// Yield, then fail with `FsError::Interrupted` if cancelled
+    pub fn checkpoint(&self) -> Result<()> {
+        if let Some(yield_now) = &self.yield_now {
+            yield_now();
+        }
+        match &self.token {
+            Some(token) if token.is_cancelled() => Err(FsError::Interrupted),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct PollStatus {
     pub read: bool,
@@ -227,8 +775,14 @@ pub struct MMapArea {
 /// Ref: [http://pubs.opengroup.org/onlinepubs/009604499/basedefs/sys/stat.h.html]
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub struct Metadata {
-    /// Device ID
-    pub dev: usize, // (major << 8) | minor
+    /// Id of the file system holding the INode, so `(dev, inode)` tells
+    /// files apart across file systems.
+    ///
+    /// Most file systems report `FileSystem::instance_id()`, which is only
+    /// stable while the fs is alive. Use `FileSystem::volume_info()` to
+    /// recognize a volume across mounts. Host-backed ones report the host
+    /// device number.
+    pub dev: usize,
     /// Inode number
     pub inode: usize,
     /// Size in bytes
@@ -247,6 +801,9 @@ pub struct Metadata {
     pub mtime: Timespec,
     /// Time of last change
     pub ctime: Timespec,
+    /// Time of creation, zero if the fs does not record it, see
+    /// `FsCapabilities::BIRTH_TIME`. Never changed by `set_metadata()`.
+    pub btime: Timespec,
     /// Type of file
     pub type_: FileType,
     /// Permission
@@ -260,9 +817,13 @@ pub struct Metadata {
     pub uid: usize,
     /// Group ID
     pub gid: usize,
-    /// Raw device id
+    /// Raw device id of char/block device, 0 for others.
+    /// Always packed by `make_rdev()`, use `unpack_rdev()` to get (major, minor).
     /// e.g. /dev/null: makedev(0x1, 0x3)
     pub rdev: usize, // (major << 8) | minor
+    /// What the fs knows of the state of the INode, empty for most.
+    /// Ignored by `set_metadata()`.
+    pub flags: MetadataFlags,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
@@ -303,15 +864,446 @@ pub struct FsInfo {
     pub ffree: usize,
     /// Maximum filename length
     pub namemax: usize,
+    /// Maximum number of links to an INode, 0 if unknown
+    pub linkmax: usize,
+}
+
+/// An INode to create by `INode::create_batch()`
+#[derive(Debug, Clone)]
+pub struct CreateSpec<'a> {
+    pub name: &'a str,
+    pub type_: FileType,
+    pub mode: u32,
+    /// Same as the `data` of `INode::create2()`
+    pub data: usize,
+}
+
+/// Where a listing by `INode::next_entries()` goes on, kept with the open
+/// dir between calls. `DirCursor::default()` starts at the first entry.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct DirCursor {
+    /// Position of the next entry, `DirEntrySlot::next` of the last one
+    pub next: usize,
+    /// `change_cookie()` of the dir at the first call, `None` before
+    pub cookie: Option<u64>,
+}
+
+impl DirCursor {
+    /// Go on from position `next` of another listing, e.g. the offset sent
+    /// back by a client
+    pub fn at(next: usize) -> Self {
+        DirCursor { next, cookie: None }
+    }
+}
+
+/// Entry filled by `INode::next_entries()`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirEntrySlot {
+    pub inode: usize,
+    pub type_: FileType,
+    /// Escaped by `escape_name()` if not UTF-8
+    pub name: String,
+    /// The bytes of the name if not UTF-8, reset by whoever fills `name`
+    pub raw_name: Option<Vec<u8>>,
+    /// Position after this entry, see `DirCursor`
+    pub next: usize,
+}
+
+impl DirEntrySlot {
+    /// The exact bytes of the name, for `INode::find_bytes()`
+    pub fn name_bytes(&self) -> &[u8] {
+        self.raw_name.as_deref().unwrap_or(self.name.as_bytes())
+    }
+}
+
+impl Default for DirEntrySlot {
+    fn default() -> Self {
+        DirEntrySlot {
+            inode: 0,
+            type_: FileType::File,
+            name: String::new(),
+            raw_name: None,
+            next: 0,
+        }
+    }
+}
+
+/// The name of `bytes` for the `&str` API: UTF-8 as is, each byte of an
+/// invalid sequence as `%XX` otherwise.
+///
+/// This is lossy, an escaped name is not told from a name with the same
+/// `%XX` in it, so such an entry is only reached by its bytes, see
+/// `INode::get_entry_bytes()` and `INode::find_bytes()`.
+pub fn escape_name(bytes: &[u8]) -> Cow<'_, str> {
+    use core::fmt::Write;
+    let mut rest = match str::from_utf8(bytes) {
+        Ok(name) => return Cow::Borrowed(name),
+        Err(_) => bytes,
+    };
+    let mut name = String::new();
+    loop {
+        match str::from_utf8(rest) {
+            Ok(valid) => {
+                name.push_str(valid);
+                break;
+            }
+            Err(err) => {
+                let (valid, after) = rest.split_at(err.valid_up_to());
+                name.push_str(str::from_utf8(valid).unwrap_or_default());
+                let (invalid, after) = after.split_at(err.error_len().unwrap_or(after.len()));
+                for byte in invalid {
+                    let _ = write!(name, "%{:02X}", byte);
+                }
+                rest = after;
+            }
+        }
+    }
+    Cow::Owned(name)
+}
+
+/// Set-group-ID bit of `Metadata::mode`
+pub const MODE_SETGID: u16 = 0o2000;
+
+/// Who creates an INode by `INode::create3()`
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct CreateContext {
+    pub uid: usize,
+    /// Group of the new INode, unless the dir is setgid
+    pub gid: usize,
+    /// Permission bits cleared from the mode asked
+    pub umask: u16,
+}
+
+impl CreateContext {
+    /// (mode, uid, gid) of a new INode of `type_` asked with `mode` in a dir
+    /// of `dir_mode` and `dir_gid`, by the POSIX rules: in a setgid dir it
+    /// takes the group of the dir, and is setgid too if a dir.
+    pub fn apply(
+        &self,
+        type_: FileType,
+        mode: u32,
+        dir_mode: u16,
+        dir_gid: usize,
+    ) -> (u16, usize, usize) {
+        let mut mode = mode as u16 & 0o7777 & !self.umask;
+        let mut gid = self.gid;
+        if dir_mode & MODE_SETGID != 0 {
+            gid = dir_gid;
+            if type_ == FileType::Dir {
+                mode |= MODE_SETGID;
+            }
+        }
+        (mode, self.uid, gid)
+    }
+}
+
+/// Fields of `Metadata` asked to `INode::metadata_partial()`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct MetadataMask(pub u32);
+
+impl MetadataMask {
+    pub const INODE: MetadataMask = MetadataMask(1 << 0);
+    pub const TYPE: MetadataMask = MetadataMask(1 << 1);
+    /// `size` and `blocks`
+    pub const SIZE: MetadataMask = MetadataMask(1 << 2);
+    /// `atime`, `mtime`, `ctime` and `btime`
+    pub const TIMES: MetadataMask = MetadataMask(1 << 3);
+    pub const MODE: MetadataMask = MetadataMask(1 << 4);
+    pub const NLINKS: MetadataMask = MetadataMask(1 << 5);
+    /// `uid` and `gid`
+    pub const OWNER: MetadataMask = MetadataMask(1 << 6);
+    pub const RDEV: MetadataMask = MetadataMask(1 << 7);
+    pub const ALL: MetadataMask = MetadataMask(0xff);
+
+    pub const fn empty() -> Self {
+        MetadataMask(0)
+    }
+
+    pub fn contains(&self, other: MetadataMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(&self, other: MetadataMask) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl core::ops::BitOr for MetadataMask {
+    type Output = MetadataMask;
+
+    fn bitor(self, rhs: MetadataMask) -> MetadataMask {
+        MetadataMask(self.0 | rhs.0)
+    }
+}
+
+/// Result of `INode::metadata_partial()`, `None` for fields not asked for
+/// or not supported
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct PartialMetadata {
+    pub inode: Option<usize>,
+    pub type_: Option<FileType>,
+    pub size: Option<usize>,
+    pub blocks: Option<usize>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+    pub ctime: Option<Timespec>,
+    pub btime: Option<Timespec>,
+    pub mode: Option<u16>,
+    pub nlinks: Option<usize>,
+    pub uid: Option<usize>,
+    pub gid: Option<usize>,
+    pub rdev: Option<usize>,
+}
+
+impl PartialMetadata {
+    /// The fields of `mask` in `metadata`
+    pub fn from_metadata(metadata: &Metadata, mask: MetadataMask) -> Self {
+        let has = |field| mask.contains(field);
+        PartialMetadata {
+            inode: has(MetadataMask::INODE).then_some(metadata.inode),
+            type_: has(MetadataMask::TYPE).then_some(metadata.type_),
+            size: has(MetadataMask::SIZE).then_some(metadata.size),
+            blocks: has(MetadataMask::SIZE).then_some(metadata.blocks),
+            atime: has(MetadataMask::TIMES).then_some(metadata.atime),
+            mtime: has(MetadataMask::TIMES).then_some(metadata.mtime),
+            ctime: has(MetadataMask::TIMES).then_some(metadata.ctime),
+            btime: has(MetadataMask::TIMES).then_some(metadata.btime),
+            mode: has(MetadataMask::MODE).then_some(metadata.mode),
+            nlinks: has(MetadataMask::NLINKS).then_some(metadata.nlinks),
+            uid: has(MetadataMask::OWNER).then_some(metadata.uid),
+            gid: has(MetadataMask::OWNER).then_some(metadata.gid),
+            rdev: has(MetadataMask::RDEV).then_some(metadata.rdev),
+        }
+    }
+}
+
+/// Inode flags, like `chattr` attributes
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct InodeFlags(pub u32);
+
+impl InodeFlags {
+    /// Content can only be appended, and the INode can not be unlinked
+    pub const APPEND_ONLY: InodeFlags = InodeFlags(1 << 0);
+    /// Neither content nor metadata can be changed, and the INode can not be unlinked
+    pub const IMMUTABLE: InodeFlags = InodeFlags(1 << 1);
+    pub const ALL: InodeFlags = InodeFlags(0x3);
+
+    pub const fn empty() -> Self {
+        InodeFlags(0)
+    }
+
+    pub fn contains(&self, other: InodeFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(&self, other: InodeFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl core::ops::BitOr for InodeFlags {
+    type Output = InodeFlags;
+
+    fn bitor(self, rhs: InodeFlags) -> InodeFlags {
+        InodeFlags(self.0 | rhs.0)
+    }
+}
+
+/// State of an INode reported in `Metadata::flags`
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct MetadataFlags(pub u32);
+
+impl MetadataFlags {
+    /// The INode could not be read, it stands for it as an empty file, see
+    /// SFS rescue mode
+    pub const CORRUPT: MetadataFlags = MetadataFlags(1 << 0);
+
+    pub const fn empty() -> Self {
+        MetadataFlags(0)
+    }
+
+    pub fn contains(&self, other: MetadataFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for MetadataFlags {
+    type Output = MetadataFlags;
+
+    fn bitor(self, rhs: MetadataFlags) -> MetadataFlags {
+        MetadataFlags(self.0 | rhs.0)
+    }
+}
+
+/// Mode of `INode::fallocate()`, like the flags of `fallocate(2)`
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct FallocateMode(pub u32);
+
+impl FallocateMode {
+    /// Leave the size as is, space allocated past the end is reserved for
+    /// later writes, and freed by shrinking the file
+    pub const KEEP_SIZE: FallocateMode = FallocateMode(1 << 0);
+    /// Free the space instead, reading zeros from the range after. Only
+    /// with `KEEP_SIZE`.
+    pub const PUNCH_HOLE: FallocateMode = FallocateMode(1 << 1);
+    pub const ALL: FallocateMode = FallocateMode(0x3);
+
+    pub const fn empty() -> Self {
+        FallocateMode(0)
+    }
+
+    pub fn contains(&self, other: FallocateMode) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for FallocateMode {
+    type Output = FallocateMode;
+
+    fn bitor(self, rhs: FallocateMode) -> FallocateMode {
+        FallocateMode(self.0 | rhs.0)
+    }
+}
+
+/// Features of a file system, see `FileSystem::capabilities()`
+///
+/// Operations of a feature the fs lacks fail with `FsError::Unsupported`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct FsCapabilities(pub u32);
+
+impl FsCapabilities {
+    /// `link()` makes hard links to files
+    pub const HARDLINK: FsCapabilities = FsCapabilities(1 << 0);
+    /// `create()` makes symlinks, their target written as content
+    pub const SYMLINK: FsCapabilities = FsCapabilities(1 << 1);
+    /// Holes left by `resize()` take no space
+    pub const SPARSE: FsCapabilities = FsCapabilities(1 << 2);
+    /// `create2()` makes char and block devices, `data` as their `rdev`
+    pub const DEVICE_NODES: FsCapabilities = FsCapabilities(1 << 3);
+    /// `move_()` renames entries
+    pub const RENAME: FsCapabilities = FsCapabilities(1 << 4);
+    /// `set_flags()` sets `InodeFlags`
+    pub const INODE_FLAGS: FsCapabilities = FsCapabilities(1 << 5);
+    /// Names are found ignoring case
+    pub const CASE_INSENSITIVE: FsCapabilities = FsCapabilities(1 << 6);
+    /// `fallocate()` allocates space ahead
+    pub const PREALLOC: FsCapabilities = FsCapabilities(1 << 7);
+    /// `Metadata::btime` is when the inode was made
+    pub const BIRTH_TIME: FsCapabilities = FsCapabilities(1 << 8);
+
+    pub const fn empty() -> Self {
+        FsCapabilities(0)
+    }
+
+    pub fn contains(&self, other: FsCapabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for FsCapabilities {
+    type Output = FsCapabilities;
+
+    fn bitor(self, rhs: FsCapabilities) -> FsCapabilities {
+        FsCapabilities(self.0 | rhs.0)
+    }
+}
+
+/// How a fs compares the names in a dir, see `FileSystem::name_ops()`
+///
+/// Two names are the same entry if their `normalize()` forms are equal,
+/// the name is kept as given. "." and ".." are never passed. The default
+/// `eq()` and `hash()` work on `normalize()`, an override must agree with
+/// it. A fs may keep `hash()` on disk, it must never change for a name.
+pub trait NameOps: Send + Sync {
+    /// The form of `name` compared with others
+    fn normalize<'a>(&self, name: &'a str) -> Cow<'a, str>;
+
+    /// Whether `a` and `b` name the same entry
+    fn eq(&self, a: &str, b: &str) -> bool {
+        self.normalize(a) == self.normalize(b)
+    }
+
+    /// Hash of `name`, equal for names which are `eq()`. By default the
+    /// 32-bit FNV-1a of the normalized name.
+    fn hash(&self, name: &str) -> u64 {
+        let hash = self
+            .normalize(name)
+            .bytes()
+            .fold(0x811c_9dc5u32, |hash, byte| {
+                (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+            });
+        hash as u64
+    }
+}
+
+/// Names are compared byte by byte, as in most fs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExactNameOps;
+
+impl NameOps for ExactNameOps {
+    fn normalize<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(name)
+    }
+
+    fn eq(&self, a: &str, b: &str) -> bool {
+        a == b
+    }
+}
+
+/// Names are compared ignoring the case of ASCII letters, others are
+/// compared exactly
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsciiCaseFoldOps;
+
+impl NameOps for AsciiCaseFoldOps {
+    fn normalize<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            true => Cow::Owned(name.to_ascii_lowercase()),
+            false => Cow::Borrowed(name),
+        }
+    }
+
+    fn eq(&self, a: &str, b: &str) -> bool {
+        a.eq_ignore_ascii_case(b)
+    }
+}
+
+/// Identity of a file among all file systems, see `INode::ino_key()`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct InodeKey {
+    /// `FileSystem::instance_id()` of its fs
+    pub fs: u64,
+    /// Inode number in the fs
+    pub inode: usize,
+}
+
+/// Whether `a` and `b` are INodes of the same file
+pub fn is_same_inode(a: &Arc<dyn INode>, b: &Arc<dyn INode>) -> bool {
+    a.ino_key() == b.ino_key()
+}
+
+/// A new id for `FileSystem::instance_id()`, never 0
+pub fn new_instance_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Identity of a file system volume, used to tell images apart
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct VolumeInfo {
+    /// Volume UUID, all zero if the volume has none
+    pub uuid: [u8; 16],
+    /// Volume label
+    pub label: String,
 }
 
 // Note: IOError/NoMemory always lead to a panic since it's hard to recover from it.
 //       We also panic when we can not parse the fs on disk normally
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug)]
 pub enum FsError {
-    NotSupported,  // E_UNIMP, or E_INVAL
-    NotFile,       // E_ISDIR
-    IsDir,         // E_ISDIR, used only in link
+    NotSupported,  // E_UNIMP, or E_INVAL, when the operation is meaningless for the INode
+    NotFile,       // E_ISDIR, when the INode is neither a file nor a dir, e.g. resize of a device
+    IsDir,         // E_ISDIR, for file operations on a dir, see `INode`
     NotDir,        // E_NOTDIR
     EntryNotFound, // E_NOENT
     EntryExist,    // E_EXIST
@@ -324,10 +1316,135 @@ pub enum FsError {
     DeviceError,
     IOCTLError,
     NoDevice,
-    Again,       // E_AGAIN, when no data is available, never happens in fs
-    SymLoop,     // E_LOOP
-    Busy,        // E_BUSY
-    Interrupted, // E_INTR
+    Again,        // E_AGAIN, when no data is available, never happens in fs
+    SymLoop,      // E_LOOP
+    Busy,         // E_BUSY
+    Interrupted,  // E_INTR
+    ReadOnly,     // E_ROFS
+    PermError,    // E_PERM, e.g. when the INode is immutable
+    TooManyLinks, // E_MLINK
+    Unsupported,  // E_OPNOTSUPP, when the file system lacks the feature, see FsCapabilities
+    Corrupted,    // E_IO, when a value on disk is out of range, e.g. a block id past the end
+    InvalidName,  // E_ILSEQ, when a name given by its bytes is not UTF-8, see `INode::find_bytes()`
+    /// Some of several file systems failed to sync, the others were
+    /// synced: `FileSystem::instance_id()` of each failed one, with its error
+    PartialSync(Vec<(u64, FsError)>),
+    /// An error with where it happened, attached by `fs_try!()` with the
+    /// `error-context` feature. Match on `root_cause()` to see through it.
+    WithContext(Box<(ErrorContext, FsError)>),
+}
+
+impl FsError {
+    /// The error without context
+    pub fn root_cause(&self) -> &FsError {
+        match self {
+            FsError::WithContext(inner) => &inner.1,
+            _ => self,
+        }
+    }
+
+    /// The Linux errno of the error, for protocols passing errors by number
+    pub fn to_errno(&self) -> i32 {
+        match self.root_cause() {
+            FsError::NotSupported => 38,                       // ENOSYS
+            FsError::NotFile | FsError::IsDir => 21,           // EISDIR
+            FsError::NotDir => 20,                             // ENOTDIR
+            FsError::EntryNotFound | FsError::DirRemoved => 2, // ENOENT
+            FsError::EntryExist => 17,                         // EEXIST
+            FsError::NotSameFs => 18,                          // EXDEV
+            FsError::InvalidParam | FsError::WrongFs => 22,    // EINVAL
+            FsError::NoDeviceSpace => 28,                      // ENOSPC
+            FsError::DirNotEmpty => 39,                        // ENOTEMPTY
+            FsError::IOCTLError => 25,                         // ENOTTY
+            FsError::NoDevice => 19,                           // ENODEV
+            FsError::Again => 11,                              // EAGAIN
+            FsError::SymLoop => 40,                            // ELOOP
+            FsError::Busy => 16,                               // EBUSY
+            FsError::Interrupted => 4,                         // EINTR
+            FsError::ReadOnly => 30,                           // EROFS
+            FsError::PermError => 1,                           // EPERM
+            FsError::TooManyLinks => 31,                       // EMLINK
+            FsError::Unsupported => 95,                        // EOPNOTSUPP
+            FsError::InvalidName => 84,                        // EILSEQ
+            FsError::DeviceError | FsError::Corrupted | FsError::PartialSync(_) => 5, // EIO
+            FsError::WithContext(inner) => inner.1.to_errno(),
+        }
+    }
+
+    /// Where the error happened, if known
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            FsError::WithContext(inner) => Some(&inner.0),
+            _ => None,
+        }
+    }
+
+    /// Attach `context`, as the caller of the operation that failed.
+    ///
+    /// `op` is replaced, since the outermost operation is the one to report.
+    /// The other fields are kept if already set by a deeper call.
+    pub fn with_context(self, context: ErrorContext) -> Self {
+        match self {
+            FsError::WithContext(mut inner) => {
+                let inner_context = &mut inner.0;
+                inner_context.op = context.op;
+                inner_context.inode = inner_context.inode.or(context.inode);
+                inner_context.name = inner_context.name.take().or(context.name);
+                inner_context.block = inner_context.block.or(context.block);
+                FsError::WithContext(inner)
+            }
+            err => FsError::WithContext(Box::new((context, err))),
+        }
+    }
+}
+
+/// Errors are equal if their root causes are, whatever the context
+impl PartialEq for FsError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self.root_cause(), other.root_cause()) {
+            (FsError::PartialSync(a), FsError::PartialSync(b)) => a == b,
+            (a, b) => core::mem::discriminant(a) == core::mem::discriminant(b),
+        }
+    }
+}
+
+impl Eq for FsError {}
+
+/// Where an error happened: the operation, and what it worked on
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ErrorContext {
+    /// Name of the operation, e.g. "find"
+    pub op: &'static str,
+    /// INode the operation worked on
+    pub inode: Option<usize>,
+    /// Name of the entry the operation worked on
+    pub name: Option<String>,
+    /// Block of the device the operation worked on
+    pub block: Option<usize>,
+}
+
+impl ErrorContext {
+    pub fn new(op: &'static str) -> Self {
+        ErrorContext {
+            op,
+            ..Self::default()
+        }
+    }
+
+    pub fn inode(mut self, inode: usize) -> Self {
+        self.inode = Some(inode);
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    pub fn block(mut self, block: usize) -> Self {
+        self.block = Some(block);
+        self
+    }
 }
 
 impl fmt::Display for FsError {
@@ -337,8 +1454,11 @@ impl fmt::Display for FsError {
 }
 
 impl From<DevError> for FsError {
-    fn from(_: DevError) -> Self {
-        FsError::DeviceError
+    fn from(err: DevError) -> Self {
+        match err {
+            DevError::WriteProtected => FsError::ReadOnly,
+            _ => FsError::DeviceError,
+        }
     }
 }
 
@@ -354,8 +1474,94 @@ pub trait FileSystem: Sync + Send {
 
     /// Get the file system information
     fn info(&self) -> FsInfo;
+
+    /// Id of this file system, unique in the program, taken from
+    /// `new_instance_id()` when it is made. Usually reported as
+    /// `Metadata::dev` of its INodes.
+    fn instance_id(&self) -> u64;
+
+    /// Get the UUID and label of the volume, if the file system has them
+    fn volume_info(&self) -> Option<VolumeInfo> {
+        None
+    }
+
+    /// Get the features of the file system, none by default
+    fn capabilities(&self) -> FsCapabilities {
+        FsCapabilities::empty()
+    }
+
+    /// How names are compared, `None` if byte by byte as `ExactNameOps`,
+    /// for layers keeping names of their own, e.g. caches of lookups
+    fn name_ops(&self) -> Option<Arc<dyn NameOps>> {
+        None
+    }
+
+    /// Sync part of the dirty data, writing about `max_blocks` blocks, so
+    /// that a large sync can be spread over several calls. Stopping between
+    /// calls leaves the storage as consistent as before.
+    ///
+    /// By default it is a whole `sync()`.
+    fn sync_partial(&self, _max_blocks: usize) -> Result<SyncProgress> {
+        self.sync()?;
+        Ok(SyncProgress {
+            written_blocks: 0,
+            remaining_dirty_blocks: 0,
+            completed: true,
+        })
+    }
+
+    /// INodes in memory with changes not written yet, among dir `inode_id`
+    /// and those below it, for `INode::sync_subtree()` to sync only them.
+    /// `Unsupported` if the fs can not tell them.
+    fn dirty_inodes_under(&self, _inode_id: usize) -> Result<Vec<Arc<dyn INode>>> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Get ready to be unmounted, e.g. sync and mark the storage cleanly
+    /// unmounted. Called by `MountFS::umount()`, does nothing by default.
+    fn prepare_unmount(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Result of `FileSystem::sync_partial()`
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct SyncProgress {
+    /// Blocks written by the call, 0 if unknown
+    pub written_blocks: usize,
+    /// Dirty blocks left to write, an estimate
+    pub remaining_dirty_blocks: usize,
+    /// Whether everything is synced, including the device
+    pub completed: bool,
 }
 
+/// Pack device number (`major`, `minor`) into `Metadata::rdev`
 pub fn make_rdev(major: usize, minor: usize) -> usize {
     ((major & 0xfff) << 8) | (minor & 0xff)
 }
+
+/// Unpack `Metadata::rdev` into (major, minor)
+pub fn unpack_rdev(rdev: usize) -> (usize, usize) {
+    ((rdev >> 8) & 0xfff, rdev & 0xff)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn errors_equal_by_root_cause() {
+        let err = FsError::EntryNotFound.with_context(ErrorContext::new("find").name("a"));
+        assert_eq!(err, FsError::EntryNotFound);
+        assert_eq!(err.context().map(|context| context.op), Some("find"));
+        assert_ne!(err, FsError::NotDir);
+        assert_eq!(err.to_errno(), 2);
+
+        let sync = |err: FsError| FsError::PartialSync(vec![(1, err)]);
+        assert_eq!(
+            sync(FsError::DeviceError),
+            sync(FsError::DeviceError.with_context(ErrorContext::new("sync")))
+        );
+        assert_ne!(sync(FsError::DeviceError), sync(FsError::ReadOnly));
+    }
+}
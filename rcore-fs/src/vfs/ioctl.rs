@@ -0,0 +1,248 @@
+//! Encoding and dispatch of `INode::io_control` commands
+//!
+//! Commands are packed like Linux `_IOC()`: number, type, argument size and
+//! direction. `IoctlHandler` maps commands to closures and moves the
+//! argument struct in and out of the caller's memory with an `IoctlMemory`
+//! provided by the kernel, so backends never touch `data` as a pointer.
+
+use super::{FsError, Result};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+
+const NR_BITS: u32 = 8;
+const TYPE_BITS: u32 = 8;
+const SIZE_BITS: u32 = 14;
+
+const NR_SHIFT: u32 = 0;
+const TYPE_SHIFT: u32 = NR_SHIFT + NR_BITS;
+const SIZE_SHIFT: u32 = TYPE_SHIFT + TYPE_BITS;
+const DIR_SHIFT: u32 = SIZE_SHIFT + SIZE_BITS;
+
+/// Direction: no argument is transferred
+pub const IOC_NONE: u32 = 0;
+/// Direction: the argument is written by the caller, read by the device
+pub const IOC_WRITE: u32 = 1;
+/// Direction: the argument is read by the caller, written by the device
+pub const IOC_READ: u32 = 2;
+
+/// Pack a command from direction, type, number and argument size
+pub const fn ioc(dir: u32, type_: u8, nr: u8, size: usize) -> u32 {
+    (dir << DIR_SHIFT)
+        | (((size as u32) & ((1 << SIZE_BITS) - 1)) << SIZE_SHIFT)
+        | ((type_ as u32) << TYPE_SHIFT)
+        | ((nr as u32) << NR_SHIFT)
+}
+
+/// Command without argument
+pub const fn io(type_: u8, nr: u8) -> u32 {
+    ioc(IOC_NONE, type_, nr, 0)
+}
+
+/// Command returning a `size` bytes struct to the caller
+pub const fn ior(type_: u8, nr: u8, size: usize) -> u32 {
+    ioc(IOC_READ, type_, nr, size)
+}
+
+/// Command taking a `size` bytes struct from the caller
+pub const fn iow(type_: u8, nr: u8, size: usize) -> u32 {
+    ioc(IOC_WRITE, type_, nr, size)
+}
+
+/// Command updating a `size` bytes struct of the caller in place
+pub const fn iowr(type_: u8, nr: u8, size: usize) -> u32 {
+    ioc(IOC_READ | IOC_WRITE, type_, nr, size)
+}
+
+/// Direction of `cmd`
+pub const fn ioc_dir(cmd: u32) -> u32 {
+    cmd >> DIR_SHIFT
+}
+
+/// Type of `cmd`
+pub const fn ioc_type(cmd: u32) -> u8 {
+    (cmd >> TYPE_SHIFT) as u8
+}
+
+/// Number of `cmd`
+pub const fn ioc_nr(cmd: u32) -> u8 {
+    (cmd >> NR_SHIFT) as u8
+}
+
+/// Argument size of `cmd`
+pub const fn ioc_size(cmd: u32) -> usize {
+    ((cmd >> SIZE_SHIFT) & ((1 << SIZE_BITS) - 1)) as usize
+}
+
+// Standard commands, with the numbers of Linux. They predate the `_IOC()`
+// encoding, so the argument size is given at registration.
+
+/// Get the number of bytes ready to read, as an `i32`
+pub const FIONREAD: u32 = 0x541b;
+/// Set or clear non-blocking mode, from an `i32`
+pub const FIONBIO: u32 = 0x5421;
+/// Get the window size, as a `WinSize`
+pub const TIOCGWINSZ: u32 = 0x5413;
+
+/// Argument of `TIOCGWINSZ`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct WinSize {
+    pub row: u16,
+    pub col: u16,
+    pub xpixel: u16,
+    pub ypixel: u16,
+}
+
+impl WinSize {
+    /// Native-endian bytes of the struct, as seen by the caller
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut buf = [0; 8];
+        buf[0..2].copy_from_slice(&self.row.to_ne_bytes());
+        buf[2..4].copy_from_slice(&self.col.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.xpixel.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.ypixel.to_ne_bytes());
+        buf
+    }
+}
+
+/// Access to the memory `data` of `io_control()` points into, implemented by
+/// the kernel. Errors are returned by `io_control()` as is.
+pub trait IoctlMemory: Send + Sync {
+    /// Copy `buf.len()` bytes at `addr` into `buf`
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<()>;
+
+    /// Copy `buf` to `addr`
+    fn write(&self, addr: usize, buf: &[u8]) -> Result<()>;
+}
+
+type Handler = Box<dyn Fn(usize, Option<&dyn IoctlMemory>) -> Result<usize> + Send + Sync>;
+
+/// Table of commands handled by an INode.
+///
+/// `dispatch()` fails with `FsError::IOCTLError` for an unknown command, and
+/// with `FsError::InvalidParam` for a command known with another direction
+/// or size. INodes without any command should keep `FsError::NotSupported`.
+#[derive(Default)]
+pub struct IoctlHandler {
+    commands: BTreeMap<u32, Handler>,
+    memory: Option<Arc<dyn IoctlMemory>>,
+}
+
+impl IoctlHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Access the argument structs through `memory`.
+    /// Without it, commands with an argument struct fail with `IOCTLError`.
+    pub fn with_memory(mut self, memory: Arc<dyn IoctlMemory>) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+
+    /// Handle `cmd` by `f(data)`, with `data` as a plain value
+    pub fn on(self, cmd: u32, f: impl Fn(usize) -> Result<usize> + Send + Sync + 'static) -> Self {
+        self.insert(cmd, IOC_NONE, 0, Box::new(move |data, _| f(data)))
+    }
+
+    /// Handle `cmd` by writing the `N` bytes returned by `f()` at `data`
+    pub fn on_read<const N: usize>(
+        self,
+        cmd: u32,
+        f: impl Fn() -> Result<[u8; N]> + Send + Sync + 'static,
+    ) -> Self {
+        let handler = move |data, memory: Option<&dyn IoctlMemory>| {
+            let memory = memory.ok_or(FsError::IOCTLError)?;
+            memory.write(data, &f()?)?;
+            Ok(0)
+        };
+        self.insert(cmd, IOC_READ, N, Box::new(handler))
+    }
+
+    /// Handle `cmd` by `f()` on the `N` bytes at `data`
+    pub fn on_write<const N: usize>(
+        self,
+        cmd: u32,
+        f: impl Fn(&[u8; N]) -> Result<usize> + Send + Sync + 'static,
+    ) -> Self {
+        let handler = move |data, memory: Option<&dyn IoctlMemory>| {
+            let memory = memory.ok_or(FsError::IOCTLError)?;
+            let mut buf = [0; N];
+            memory.read(data, &mut buf)?;
+            f(&buf)
+        };
+        self.insert(cmd, IOC_WRITE, N, Box::new(handler))
+    }
+
+    /// Handle `cmd` by `f()` updating the `N` bytes at `data`
+    pub fn on_read_write<const N: usize>(
+        self,
+        cmd: u32,
+        f: impl Fn(&mut [u8; N]) -> Result<usize> + Send + Sync + 'static,
+    ) -> Self {
+        let handler = move |data, memory: Option<&dyn IoctlMemory>| {
+            let memory = memory.ok_or(FsError::IOCTLError)?;
+            let mut buf = [0; N];
+            memory.read(data, &mut buf)?;
+            let ret = f(&mut buf)?;
+            memory.write(data, &buf)?;
+            Ok(ret)
+        };
+        self.insert(cmd, IOC_READ | IOC_WRITE, N, Box::new(handler))
+    }
+
+    fn insert(mut self, cmd: u32, dir: u32, size: usize, handler: Handler) -> Self {
+        // legacy commands carry neither direction nor size
+        if ioc_dir(cmd) != IOC_NONE || ioc_size(cmd) != 0 {
+            debug_assert_eq!(ioc_dir(cmd), dir, "direction of ioctl {:#x}", cmd);
+            debug_assert_eq!(ioc_size(cmd), size, "size of ioctl {:#x}", cmd);
+        }
+        let replaced = self.commands.insert(cmd, handler);
+        debug_assert!(replaced.is_none(), "ioctl {:#x} registered twice", cmd);
+        self
+    }
+
+    /// Run the handler of `cmd`
+    pub fn dispatch(&self, cmd: u32, data: usize) -> Result<usize> {
+        match self.commands.get(&cmd) {
+            Some(handler) => handler(data, self.memory.as_deref()),
+            None if self.is_mismatched(cmd) => Err(FsError::InvalidParam),
+            None => Err(FsError::IOCTLError),
+        }
+    }
+
+    /// Whether `cmd` has the type and number of a registered command, but
+    /// another direction or size
+    fn is_mismatched(&self, cmd: u32) -> bool {
+        self.commands.keys().any(|&known| {
+            ioc_type(known) == ioc_type(cmd)
+                && ioc_nr(known) == ioc_nr(cmd)
+                && (ioc_dir(known) != IOC_NONE || ioc_size(known) != 0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        // same values as the Linux macros
+        assert_eq!(io(b'T', 0x01), 0x5401);
+        assert_eq!(ior(b'T', 0x30, 4), 0x8004_5430);
+        assert_eq!(iow(b'T', 0x31, 4), 0x4004_5431);
+        assert_eq!(iowr(b'U', 0x02, 16), 0xc010_5502);
+        let cmd = iowr(b'x', 7, 1000);
+        assert_eq!(ioc_dir(cmd), IOC_READ | IOC_WRITE);
+        assert_eq!(ioc_type(cmd), b'x');
+        assert_eq!(ioc_nr(cmd), 7);
+        assert_eq!(ioc_size(cmd), 1000);
+        assert_eq!((ioc_dir(FIONREAD), ioc_size(FIONREAD)), (IOC_NONE, 0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn registered_size_mismatch() {
+        IoctlHandler::new().on_read(ior(b'x', 1, 8), || Ok([0u8; 4]));
+    }
+}
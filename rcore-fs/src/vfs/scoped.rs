@@ -0,0 +1,402 @@
+//! Views of a directory tree that can not reach outside of it, like
+//! `openat2()` with `RESOLVE_BENEATH`
+//!
+//! `ScopedDir::new()` wraps a dir as the root of a scope. Every INode found
+//! or created from it is wrapped in the same scope, so the confinement holds
+//! however deep the lookup goes:
+//!
+//! - ".." is answered from the path walked down, never from the fs, and
+//!   fails with `PermError` at the root of the scope.
+//! - `fs()` is a view whose root is the root of the scope, and absolute
+//!   symlink targets followed by `lookup_follow()` start there, or fail with
+//!   `PermError` if the scope rejects them.
+//! - `move_()` and `link()` only accept INodes of the same scope.
+
+use super::*;
+use alloc::sync::Weak;
+
+/// How a scope resolves absolute symlink targets and paths
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AbsolutePaths {
+    /// From the root of the scope, as in a chroot
+    Beneath,
+    /// Fail with `PermError`
+    Reject,
+}
+
+struct Scope {
+    root: Arc<dyn INode>,
+    absolute: AbsolutePaths,
+}
+
+/// An INode seen through a scope, see the module doc
+pub struct ScopedDir {
+    inode: Arc<dyn INode>,
+    scope: Arc<Scope>,
+    /// Dir it was found in, `None` for the root of the scope
+    parent: Option<Arc<ScopedDir>>,
+    /// Weak reference to self
+    self_ref: Weak<ScopedDir>,
+}
+
+impl ScopedDir {
+    /// Make `root` the root of a new scope, absolute symlink targets start
+    /// from it
+    pub fn new(root: Arc<dyn INode>) -> Arc<Self> {
+        Self::with_absolute_paths(root, AbsolutePaths::Beneath)
+    }
+
+    /// Make `root` the root of a new scope, resolving absolute symlink
+    /// targets as `absolute`
+    pub fn with_absolute_paths(root: Arc<dyn INode>, absolute: AbsolutePaths) -> Arc<Self> {
+        let scope = Arc::new(Scope {
+            root: root.clone(),
+            absolute,
+        });
+        Self::wrap(root, scope, None)
+    }
+
+    fn wrap(inode: Arc<dyn INode>, scope: Arc<Scope>, parent: Option<Arc<ScopedDir>>) -> Arc<Self> {
+        Arc::new_cyclic(|self_ref| ScopedDir {
+            inode,
+            scope,
+            parent,
+            self_ref: self_ref.clone(),
+        })
+    }
+
+    /// The `Arc` this dir is in
+    #[allow(clippy::unwrap_used)]
+    fn this(&self) -> Arc<ScopedDir> {
+        // only made by `wrap()` in an `Arc`, alive while `self` is borrowed
+        self.self_ref.upgrade().unwrap()
+    }
+
+    /// Wrap `child` of this dir in the scope
+    fn child(&self, child: Arc<dyn INode>) -> Arc<dyn INode> {
+        Self::wrap(child, self.scope.clone(), Some(self.this()))
+    }
+
+    /// Number of dirs between the root of the scope and this INode
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut dir = &self.parent;
+        while let Some(parent) = dir {
+            depth += 1;
+            dir = &parent.parent;
+        }
+        depth
+    }
+
+    /// The INode wrapped by `other` if it is in the same scope
+    fn unwrap_same_scope<'a>(&self, other: &'a Arc<dyn INode>) -> Result<&'a Arc<dyn INode>> {
+        match other.downcast_ref::<ScopedDir>() {
+            Some(other) if Arc::ptr_eq(&other.scope, &self.scope) => Ok(&other.inode),
+            _ => Err(FsError::PermError),
+        }
+    }
+}
+
+impl INode for ScopedDir {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inode.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.inode.write_at(offset, buf)
+    }
+
+    fn read_at_direct(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.inode.read_at_direct(offset, buf)
+    }
+
+    fn write_at_direct(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.inode.write_at_direct(offset, buf)
+    }
+
+    fn read_at_with(&self, offset: usize, buf: &mut [u8], ctx: &TaskContext) -> Result<usize> {
+        self.inode.read_at_with(offset, buf, ctx)
+    }
+
+    fn write_at_with(&self, offset: usize, buf: &[u8], ctx: &TaskContext) -> Result<usize> {
+        self.inode.write_at_with(offset, buf, ctx)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        self.inode.poll()
+    }
+
+    fn async_poll<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = Result<PollStatus>> + Send + Sync + 'a>> {
+        self.inode.async_poll()
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        self.inode.metadata()
+    }
+
+    fn metadata_partial(&self, mask: MetadataMask) -> Result<PartialMetadata> {
+        self.inode.metadata_partial(mask)
+    }
+
+    fn set_metadata(&self, metadata: &Metadata) -> Result<()> {
+        self.inode.set_metadata(metadata)
+    }
+
+    fn sync_all(&self) -> Result<()> {
+        self.inode.sync_all()
+    }
+
+    fn sync_data(&self) -> Result<()> {
+        self.inode.sync_data()
+    }
+
+    fn resize(&self, len: usize) -> Result<()> {
+        self.inode.resize(len)
+    }
+
+    fn resize_with(&self, len: usize, ctx: &TaskContext) -> Result<()> {
+        self.inode.resize_with(len, ctx)
+    }
+
+    fn fallocate(&self, offset: usize, len: usize, mode: FallocateMode) -> Result<()> {
+        self.inode.fallocate(offset, len, mode)
+    }
+
+    fn get_flags(&self) -> Result<InodeFlags> {
+        self.inode.get_flags()
+    }
+
+    fn set_flags(&self, flags: InodeFlags) -> Result<()> {
+        self.inode.set_flags(flags)
+    }
+
+    fn create2(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+    ) -> Result<Arc<dyn INode>> {
+        Ok(self.child(self.inode.create2(name, type_, mode, data)?))
+    }
+
+    fn create3(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+        data: usize,
+        ctx: &CreateContext,
+    ) -> Result<Arc<dyn INode>> {
+        Ok(self.child(self.inode.create3(name, type_, mode, data, ctx)?))
+    }
+
+    fn create_batch(&self, entries: &[CreateSpec]) -> Result<Vec<Arc<dyn INode>>> {
+        let inodes = self.inode.create_batch(entries)?;
+        Ok(inodes.into_iter().map(|inode| self.child(inode)).collect())
+    }
+
+    fn find_or_create(
+        &self,
+        name: &str,
+        type_: FileType,
+        mode: u32,
+    ) -> Result<(Arc<dyn INode>, bool)> {
+        match name {
+            "." | ".." => {
+                let dir = self.find(name)?;
+                match dir.metadata()?.type_ == type_ {
+                    true => Ok((dir, false)),
+                    false => Err(FsError::EntryExist),
+                }
+            }
+            _ => {
+                let (inode, created) = self.inode.find_or_create(name, type_, mode)?;
+                Ok((self.child(inode), created))
+            }
+        }
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>> {
+        Ok(self.child(self.inode.symlink(name, target)?))
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn INode>) -> Result<()> {
+        self.inode.link(name, self.unwrap_same_scope(other)?)
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.inode.unlink(name)
+    }
+
+    fn unlink_bytes(&self, name: &[u8]) -> Result<()> {
+        self.inode.unlink_bytes(name)
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn INode>, new_name: &str) -> Result<()> {
+        let target = self.unwrap_same_scope(target)?;
+        self.inode.move_(old_name, target, new_name)
+    }
+
+    fn find(&self, name: &str) -> Result<Arc<dyn INode>> {
+        match name {
+            "." | ".." => {
+                // fail as the fs does, e.g. if this is not a dir
+                self.inode.find(name)?;
+                let dir = match name {
+                    "." => Some(self.this()),
+                    _ => self.parent.clone(),
+                };
+                Ok(dir.ok_or(FsError::PermError)?)
+            }
+            _ => Ok(self.child(self.inode.find(name)?)),
+        }
+    }
+
+    /// Names not UTF-8 are never dots
+    fn find_bytes(&self, name: &[u8]) -> Result<Arc<dyn INode>> {
+        match str::from_utf8(name) {
+            Ok(name) => self.find(name),
+            Err(_) => Ok(self.child(self.inode.find_bytes(name)?)),
+        }
+    }
+
+    fn change_cookie(&self) -> u64 {
+        self.inode.change_cookie()
+    }
+
+    fn data_version(&self) -> u64 {
+        self.inode.data_version()
+    }
+
+    fn get_entry(&self, id: usize) -> Result<String> {
+        self.inode.get_entry(id)
+    }
+
+    fn get_entry_bytes(&self, id: usize) -> Result<Vec<u8>> {
+        self.inode.get_entry_bytes(id)
+    }
+
+    fn get_entry_with_metadata(&self, id: usize) -> Result<(Metadata, String)> {
+        let (metadata, name) = self.inode.get_entry_with_metadata(id)?;
+        match self.parent.is_none() && name == ".." {
+            // do not show what is outside
+            true => Ok((self.metadata()?, name)),
+            false => Ok((metadata, name)),
+        }
+    }
+
+    fn get_entry_with_metadata_partial(
+        &self,
+        id: usize,
+        mask: MetadataMask,
+    ) -> Result<(PartialMetadata, String)> {
+        let (metadata, name) = self.inode.get_entry_with_metadata_partial(id, mask)?;
+        match self.parent.is_none() && name == ".." {
+            true => Ok((self.metadata_partial(mask)?, name)),
+            false => Ok((metadata, name)),
+        }
+    }
+
+    fn next_entries(&self, cursor: &mut DirCursor, out: &mut [DirEntrySlot]) -> Result<usize> {
+        let filled = self.inode.next_entries(cursor, out)?;
+        if self.parent.is_none() {
+            for slot in out[..filled].iter_mut().filter(|slot| slot.name == "..") {
+                slot.inode = self.metadata()?.inode;
+            }
+        }
+        Ok(filled)
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        self.inode.io_control(cmd, data)
+    }
+
+    fn mmap(&self, area: MMapArea) -> Result<()> {
+        self.inode.mmap(area)
+    }
+
+    fn open_hook(&self, exclusive: bool) -> Result<OpenGuard> {
+        self.inode.open_hook(exclusive)
+    }
+
+    fn pin(&self) -> Result<PinGuard> {
+        self.inode.pin()
+    }
+
+    fn set_cache_hint(&self, hint: CacheHint) {
+        self.inode.set_cache_hint(hint)
+    }
+
+    fn lookup_root(&self) -> Result<Arc<dyn INode>> {
+        match self.scope.absolute {
+            AbsolutePaths::Beneath => Ok(self.fs()?.root_inode()),
+            AbsolutePaths::Reject => Err(FsError::PermError),
+        }
+    }
+
+    fn wrapped(&self) -> Option<&Arc<dyn INode>> {
+        Some(&self.inode)
+    }
+
+    fn fs(&self) -> Result<Arc<dyn FileSystem>> {
+        Ok(Arc::new(ScopedFs {
+            inner: self.inode.fs()?,
+            scope: self.scope.clone(),
+        }))
+    }
+
+    fn fs_info(&self) -> Result<FsInfo> {
+        self.inode.fs_info()
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// The fs of a scope, its root is the root of the scope
+struct ScopedFs {
+    inner: Arc<dyn FileSystem>,
+    scope: Arc<Scope>,
+}
+
+impl FileSystem for ScopedFs {
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+
+    fn root_inode(&self) -> Arc<dyn INode> {
+        ScopedDir::wrap(self.scope.root.clone(), self.scope.clone(), None)
+    }
+
+    fn info(&self) -> FsInfo {
+        self.inner.info()
+    }
+
+    fn instance_id(&self) -> u64 {
+        self.inner.instance_id()
+    }
+
+    fn volume_info(&self) -> Option<VolumeInfo> {
+        self.inner.volume_info()
+    }
+
+    fn capabilities(&self) -> FsCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn name_ops(&self) -> Option<Arc<dyn NameOps>> {
+        self.inner.name_ops()
+    }
+
+    fn sync_partial(&self, max_blocks: usize) -> Result<SyncProgress> {
+        self.inner.sync_partial(max_blocks)
+    }
+
+    fn prepare_unmount(&self) -> Result<()> {
+        self.inner.prepare_unmount()
+    }
+}